@@ -0,0 +1,51 @@
+//! Time encrypting a large file's chunks on one worker against several
+//!
+//! Run with `cargo bench --bench crypto_pool`. 256 MB goes through in 1 MB
+//! chunks, all in memory, each result taken in order as the sender takes
+//! them, so the difference is the encryption alone.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use zap::crypto::{Cipher, CryptoPool};
+
+const TOTAL: usize = 256 * 1024 * 1024;
+const CHUNK: usize = 1024 * 1024;
+
+async fn run(cipher: &Arc<Cipher>, chunk: &[u8], workers: usize) -> Duration {
+    let start = Instant::now();
+    let mut pool = CryptoPool::new(cipher.clone(), workers);
+    let mut expected = 0;
+    let mut take = |(index, sealed): (usize, anyhow::Result<Vec<u8>>)| {
+        assert_eq!(index, expected, "out of order");
+        std::hint::black_box(sealed.unwrap());
+        expected += 1;
+    };
+    for index in 0..TOTAL / CHUNK {
+        pool.encrypt(index, chunk.to_vec());
+        while pool.is_full() {
+            take(pool.next().await.unwrap());
+        }
+    }
+    while let Some(sealed) = pool.next().await {
+        take(sealed);
+    }
+    start.elapsed()
+}
+
+fn report(workers: usize, elapsed: Duration, baseline: Duration) {
+    let mb_per_sec = TOTAL as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64();
+    println!("{} workers {:>6} ms {:>7.1} MB/s {:>6.2}x", workers, elapsed.as_millis(), mb_per_sec, speedup);
+}
+
+#[tokio::main]
+async fn main() {
+    let cipher = Arc::new(Cipher::from_password("alpha-bravo-charlie").unwrap());
+    let chunk: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+    
+    println!("{} cores", std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let single = run(&cipher, &chunk, 1).await;
+    report(1, single, single);
+    report(4, run(&cipher, &chunk, 4).await, single);
+}
@@ -0,0 +1,53 @@
+//! Time checksumming a folder of 5000 files on more and more threads
+//!
+//! Run with `cargo bench --bench hash_tree`. The files sit in 50 folders and
+//! range from nothing to 64 KB, about 160 MB in all, so the work is
+//! split between opening files and hashing them. The first run warms the page
+//! cache, so every timed run reads from memory.
+
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use zap::transfer::hash_tree;
+
+const FOLDERS: usize = 50;
+const FILES_PER_FOLDER: usize = 100;
+
+fn synthetic_tree() -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let block: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    for folder in 0..FOLDERS {
+        let folder_path = dir.path().join(format!("folder{:02}", folder));
+        std::fs::create_dir(&folder_path).unwrap();
+        for file in 0..FILES_PER_FOLDER {
+            let len = (folder * FILES_PER_FOLDER + file) * 53 % block.len();
+            std::fs::write(folder_path.join(format!("file{:03}.bin", file)), &block[..len]).unwrap();
+        }
+    }
+    dir
+}
+
+async fn run(dir: &TempDir, threads: usize) -> Duration {
+    let start = Instant::now();
+    let manifest = hash_tree(dir.path(), &[], threads).await.unwrap().collect(|_, _| {}).await.unwrap();
+    assert_eq!(manifest.len(), FOLDERS * FILES_PER_FOLDER);
+    start.elapsed()
+}
+
+fn report(threads: usize, elapsed: Duration, baseline: Duration) {
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64();
+    println!("{:>2} threads {:>6} ms {:>6.2}x", threads, elapsed.as_millis(), speedup);
+}
+
+#[tokio::main]
+async fn main() {
+    let dir = synthetic_tree();
+    run(&dir, 0).await;
+    
+    println!("{} cores", std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let single = run(&dir, 1).await;
+    report(1, single, single);
+    for threads in [2, 4, 8, 16] {
+        report(threads, run(&dir, threads).await, single);
+    }
+}
@@ -0,0 +1,61 @@
+//! Compare plain and read-ahead chunking when every disk read is slow
+//!
+//! Run with `cargo bench --bench readahead`. Each read and each "send" sleeps
+//! for the same time, roughly a seek on a spinning disk, so the read-ahead
+//! chunker should approach half the time by overlapping the two.
+
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use zap::transfer::FileChunker;
+
+const FILE_SIZE: usize = 32 * 1024 * 1024;
+const LATENCY: Duration = Duration::from_millis(2);
+
+/// A reader that waits before every read, like a disk that has to seek
+struct SlowReader<R>(R);
+
+impl<R: Read> Read for SlowReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        std::thread::sleep(LATENCY);
+        self.0.read(buf)
+    }
+}
+
+fn slow_file(data: &[u8]) -> FileChunker<SlowReader<io::Cursor<Vec<u8>>>> {
+    FileChunker::from_reader(SlowReader(io::Cursor::new(data.to_vec())), data.len() as u64)
+}
+
+/// Stand-in for encrypting and sending a chunk
+async fn send(chunk: &[u8]) {
+    std::hint::black_box(chunk);
+    tokio::time::sleep(LATENCY).await;
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+    let mb_per_sec = FILE_SIZE as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64();
+    println!("{:<14} {:>6} ms {:>7.1} MB/s {:>6.2}x", name, elapsed.as_millis(), mb_per_sec, speedup);
+}
+
+#[tokio::main]
+async fn main() {
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    
+    let start = Instant::now();
+    let mut chunker = slow_file(&data);
+    while let Some(chunk) = chunker.next_chunk().unwrap() {
+        send(&chunk).await;
+    }
+    let plain = start.elapsed();
+    report("FileChunker", plain, plain);
+    
+    for capacity in [1, 4, 16] {
+        let start = Instant::now();
+        let mut chunker = slow_file(&data).with_readahead(capacity);
+        while let Some(chunk) = chunker.next_chunk().await.unwrap() {
+            send(&chunk).await;
+        }
+        report(&format!("readahead {}", capacity), start.elapsed(), plain);
+    }
+}
@@ -0,0 +1,69 @@
+//! Compare one lock around every relay room with the sharded room map
+//!
+//! Run with `cargo bench --bench relay_rooms >/dev/null`; the relay and its
+//! clients log to stdout, the results go to stderr. Each run starts a relay on
+//! loopback and pushes 1000 pairs through it at once. Every frame a pair
+//! forwards looks its room up, so with a single lock the pairs queue behind
+//! each other; the difference only shows with several cores.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpListener;
+use zap::relay::{self, RelayConfig, RelayConnection, RelayState, RelayUrl, Role, RoomMap, MAX_RELAY_FRAME_SIZE, ROOM_SHARDS};
+
+const PAIRS: usize = 1000;
+const FRAMES: usize = 64;
+const FRAME_SIZE: usize = 4096;
+
+async fn run(shards: usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relays = vec![RelayUrl::from(listener.local_addr().unwrap())];
+    let state = Arc::new(RelayState::default().with_rooms(RoomMap::with_shards(shards, PAIRS)));
+    let server = tokio::spawn(relay::serve(listener, RelayConfig::default(), state));
+    
+    let start = Instant::now();
+    let pairs: Vec<_> = (0..PAIRS).map(|i| {
+        let relays = relays.clone();
+        tokio::spawn(async move {
+            let code = format!("bench-pair-{}", i);
+            let (sender, receiver) = tokio::join!(
+                RelayConnection::connect(&relays, &code, Role::Sender, MAX_RELAY_FRAME_SIZE),
+                RelayConnection::connect(&relays, &code, Role::Receiver, MAX_RELAY_FRAME_SIZE),
+            );
+            let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+            let send = async {
+                let frame = vec![0u8; FRAME_SIZE];
+                for _ in 0..FRAMES {
+                    sender.send(&frame).await.unwrap();
+                }
+            };
+            let receive = async {
+                for _ in 0..FRAMES {
+                    receiver.receive().await.unwrap();
+                }
+            };
+            tokio::join!(send, receive);
+        })
+    }).collect();
+    for pair in pairs {
+        pair.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    server.abort();
+    elapsed
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+    let frames_per_sec = (PAIRS * FRAMES) as f64 / elapsed.as_secs_f64();
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64();
+    eprintln!("{:<12} {:>6} ms {:>9.0} frames/s {:>6.2}x", name, elapsed.as_millis(), frames_per_sec, speedup);
+}
+
+#[tokio::main]
+async fn main() {
+    let single = run(1).await;
+    let sharded = run(ROOM_SHARDS).await;
+    report("single lock", single, single);
+    report("sharded", sharded, single);
+}
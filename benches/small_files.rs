@@ -0,0 +1,160 @@
+//! Send a folder of 10,000 small files packed into a tar stream, and one file at a time
+//!
+//! Run with `cargo bench --bench small_files`. Both go over loopback TCP,
+//! encrypted, with Nagle's algorithm off so the naive path's small messages
+//! aren't held back waiting for delayed acks. The naive path sends each file as its own Metadata, Chunk and
+//! Complete, and the receiver syncs it to disk and acks it before the next
+//! one starts; the packed path is what a folder send does, the archive in
+//! shared chunks followed by every file's checksum, checked before unpacking.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use zap::crypto::Cipher;
+use zap::network::Connection;
+use zap::protocol::Message;
+use zap::transfer::{self, ArchivePiece, ArchiveStream, CHUNK_SIZE};
+use zap::transport::Transport;
+
+const FOLDERS: usize = 100;
+const FILES_PER_FOLDER: usize = 100;
+
+fn synthetic_tree() -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let block: Vec<u8> = (0..8 * 1024).map(|i| (i % 251) as u8).collect();
+    for folder in 0..FOLDERS {
+        let folder_path = dir.path().join(format!("folder{:02}", folder));
+        std::fs::create_dir(&folder_path).unwrap();
+        for file in 0..FILES_PER_FOLDER {
+            let len = (folder * FILES_PER_FOLDER + file) * 53 % block.len();
+            std::fs::write(folder_path.join(format!("file{:03}.bin", file)), &block[..len]).unwrap();
+        }
+    }
+    dir
+}
+
+async fn pair() -> (Transport, Transport) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sender = TcpStream::connect(addr).await.unwrap();
+    let (receiver, peer) = listener.accept().await.unwrap();
+    for stream in [&sender, &receiver] {
+        stream.set_nodelay(true).unwrap();
+    }
+    let local_port = sender.local_addr().unwrap().port();
+    (
+        Transport::Direct(Connection::new(sender, addr, local_port)),
+        Transport::Direct(Connection::new(receiver, peer, addr.port())),
+    )
+}
+
+async fn send(conn: &mut Transport, cipher: &Cipher, message: &Message) {
+    conn.send(&cipher.encrypt(&message.to_bytes().unwrap()).unwrap()).await.unwrap();
+}
+
+async fn receive(conn: &mut Transport, cipher: &Cipher) -> Message {
+    Message::from_bytes(&cipher.decrypt(&conn.receive().await.unwrap()).unwrap()).unwrap()
+}
+
+async fn naive(source: &Path, output: &Path) -> Duration {
+    let (mut sender, mut receiver) = pair().await;
+    let cipher = Cipher::from_password("small-files").unwrap();
+    let files: Vec<_> = walkdir::WalkDir::new(source)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().strip_prefix(source).unwrap().to_path_buf())
+        .collect();
+    let count = files.len();
+    
+    let start = Instant::now();
+    let output = output.to_path_buf();
+    let receiving = tokio::spawn(async move {
+        let cipher = Cipher::from_password("small-files").unwrap();
+        for _ in 0..count {
+            let Message::Metadata { filename, .. } = receive(&mut receiver, &cipher).await else { panic!("expected Metadata") };
+            let Message::Chunk { data, .. } = receive(&mut receiver, &cipher).await else { panic!("expected Chunk") };
+            assert!(matches!(receive(&mut receiver, &cipher).await, Message::Complete));
+            let path = output.join(filename);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let file = std::fs::File::create(&path).unwrap();
+            std::io::Write::write_all(&mut &file, &data).unwrap();
+            file.sync_all().unwrap();
+            receiver.send(&Message::Ack.to_bytes().unwrap()).await.unwrap();
+        }
+    });
+    for name in files {
+        let data = std::fs::read(source.join(&name)).unwrap();
+        let filename = name.to_string_lossy().into_owned();
+        let metadata = Message::Metadata { filename, size: data.len() as u64, is_directory: false, checksum: transfer::NO_CHECKSUM.to_string() };
+        send(&mut sender, &cipher, &metadata).await;
+        send(&mut sender, &cipher, &Message::Chunk { index: 0, data }).await;
+        send(&mut sender, &cipher, &Message::Complete).await;
+        assert!(matches!(Message::from_bytes(&sender.receive().await.unwrap()).unwrap(), Message::Ack));
+    }
+    receiving.await.unwrap();
+    start.elapsed()
+}
+
+async fn packed(source: &Path, output: &Path) -> Duration {
+    let (mut sender, mut receiver) = pair().await;
+    let cipher = Cipher::from_password("small-files").unwrap();
+    
+    let start = Instant::now();
+    let output = output.to_path_buf();
+    let receiving = tokio::spawn(async move {
+        let cipher = Cipher::from_password("small-files").unwrap();
+        let archive_path = output.with_extension("tar");
+        let mut archive = std::fs::File::create(&archive_path).unwrap();
+        let mut checksums = Vec::new();
+        loop {
+            match receive(&mut receiver, &cipher).await {
+                Message::Chunk { data, .. } => std::io::Write::write_all(&mut archive, &data).unwrap(),
+                Message::FileChecksums { files } => checksums = files,
+                Message::Complete => break,
+                _ => panic!("unexpected message"),
+            }
+        }
+        archive.sync_all().unwrap();
+        transfer::check_file_checksums(&archive_path, &checksums).unwrap();
+        transfer::extract_tar_archive(&archive_path, &output).unwrap();
+        receiver.send(&Message::Ack.to_bytes().unwrap()).await.unwrap();
+        checksums.len()
+    });
+    let mut archive = ArchiveStream::tar(source, &[], CHUNK_SIZE);
+    let mut index = 0;
+    while let Some(piece) = archive.next().await.unwrap() {
+        match piece {
+            ArchivePiece::Data(data) => {
+                send(&mut sender, &cipher, &Message::Chunk { index, data }).await;
+                index += 1;
+            }
+            ArchivePiece::Checksums(files) => send(&mut sender, &cipher, &Message::FileChecksums { files }).await,
+            ArchivePiece::Progress { .. } => {}
+        }
+    }
+    send(&mut sender, &cipher, &Message::Complete).await;
+    assert!(matches!(Message::from_bytes(&sender.receive().await.unwrap()).unwrap(), Message::Ack));
+    assert_eq!(receiving.await.unwrap(), FOLDERS * FILES_PER_FOLDER);
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+    let files_per_sec = (FOLDERS * FILES_PER_FOLDER) as f64 / elapsed.as_secs_f64();
+    let speedup = baseline.as_secs_f64() / elapsed.as_secs_f64();
+    println!("{:<8} {:>7} ms {:>9.0} files/s {:>6.2}x", name, elapsed.as_millis(), files_per_sec, speedup);
+}
+
+#[tokio::main]
+async fn main() {
+    let source = synthetic_tree();
+    let output = TempDir::new().unwrap();
+    
+    let naive_time = naive(source.path(), &output.path().join("naive")).await;
+    report("naive", naive_time, naive_time);
+    let packed_time = packed(source.path(), &output.path().join("packed")).await;
+    report("packed", packed_time, naive_time);
+}
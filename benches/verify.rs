@@ -0,0 +1,65 @@
+//! Time writing a received file with no checksum, one hashed as it's written, and one read back after
+//!
+//! Run with `cargo bench --bench verify`. 128 MB is written in 1 MB chunks
+//! to a temporary file; the hash stage works alongside the writes, so with
+//! a core to spare it should cost little over writing alone, while reading
+//! back costs a second pass over the file.
+
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use zap::crypto::ChecksumAlgorithm;
+use zap::transfer::{checksum_file, FileWriter, HashStage};
+
+const TOTAL: usize = 128 * 1024 * 1024;
+const CHUNK: usize = 1024 * 1024;
+
+enum Check {
+    None,
+    Streamed(ChecksumAlgorithm),
+    ReadBack(ChecksumAlgorithm),
+}
+
+async fn run(dir: &TempDir, chunk: &[u8], check: Check) -> Duration {
+    let path = dir.path().join("received.bin");
+    let start = Instant::now();
+    let mut writer = FileWriter::new(&path, TOTAL as u64).unwrap();
+    let mut stage = match check {
+        Check::Streamed(algorithm) => Some(HashStage::start(algorithm, &path, 0)),
+        _ => None,
+    };
+    for _ in 0..TOTAL / CHUNK {
+        writer.write_chunk(chunk).unwrap();
+        if let Some(stage) = &mut stage {
+            stage.push(chunk.to_vec()).await.unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    if let Some(stage) = stage {
+        std::hint::black_box(stage.finish().await.unwrap());
+    }
+    if let Check::ReadBack(algorithm) = check {
+        std::hint::black_box(checksum_file(&path, algorithm, |_, _| {}).await.unwrap());
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+    let mb_per_sec = TOTAL as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    let overhead = elapsed.as_secs_f64() / baseline.as_secs_f64();
+    println!("{:<18} {:>6} ms {:>7.1} MB/s {:>6.2}x", name, elapsed.as_millis(), mb_per_sec, overhead);
+}
+
+#[tokio::main]
+async fn main() {
+    let dir = TempDir::new().unwrap();
+    let chunk: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+    
+    println!("{} cores", std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let plain = run(&dir, &chunk, Check::None).await;
+    report("no checksum", plain, plain);
+    report("streamed SHA-256", run(&dir, &chunk, Check::Streamed(ChecksumAlgorithm::Sha256)).await, plain);
+    report("streamed BLAKE3", run(&dir, &chunk, Check::Streamed(ChecksumAlgorithm::Blake3)).await, plain);
+    report("read back SHA-256", run(&dir, &chunk, Check::ReadBack(ChecksumAlgorithm::Sha256)).await, plain);
+    report("read back BLAKE3", run(&dir, &chunk, Check::ReadBack(ChecksumAlgorithm::Blake3)).await, plain);
+}
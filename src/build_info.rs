@@ -0,0 +1,87 @@
+use anyhow::Result;
+use serde::Serialize;
+
+/// Build details embedded at compile time by `build.rs`
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_timestamp: &'static str,
+    pub rustc_version: &'static str,
+    pub target: &'static str,
+    pub features: Vec<&'static str>,
+    pub tls_backend: &'static str,
+}
+
+impl BuildInfo {
+    /// Get the info for the running binary
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("VERGEN_GIT_SHA").unwrap_or("unknown"),
+            build_timestamp: option_env!("VERGEN_BUILD_TIMESTAMP").unwrap_or("unknown"),
+            rustc_version: option_env!("VERGEN_RUSTC_SEMVER").unwrap_or("unknown"),
+            target: option_env!("VERGEN_CARGO_TARGET_TRIPLE").unwrap_or("unknown"),
+            features: option_env!("VERGEN_CARGO_FEATURES")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            // tokio-tungstenite is built with native-tls (OpenSSL on Linux)
+            tls_backend: "native-tls",
+        }
+    }
+    
+    /// Format as human-readable lines, or as a JSON object
+    pub fn render(&self, json: bool) -> Result<String> {
+        if json {
+            return Ok(serde_json::to_string_pretty(self)?);
+        }
+        
+        let features = if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join(", ")
+        };
+        
+        Ok(format!(
+            "zap {}\ncommit:    {}\nbuilt:     {}\nrustc:     {}\ntarget:    {}\nfeatures:  {}\ntls:       {}",
+            self.version,
+            self.git_sha,
+            self.build_timestamp,
+            self.rustc_version,
+            self.target,
+            features,
+            self.tls_backend,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Commands};
+    use clap::Parser;
+    
+    fn run(args: &[&str]) -> String {
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Version { json } => BuildInfo::current().render(json).unwrap(),
+            other => panic!("expected version command, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_version_contains_crate_version() {
+        let output = run(&["zap", "version"]);
+        assert!(output.contains(env!("CARGO_PKG_VERSION")));
+    }
+    
+    #[test]
+    fn test_version_json() {
+        let output = run(&["zap", "version", "--json"]);
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["version"], env!("CARGO_PKG_VERSION"));
+        assert!(value["git_sha"].is_string());
+    }
+}
@@ -1,5 +1,20 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+use clap::{Command, CommandFactory, FromArgMatches, Parser, Subcommand};
+use ipnet::IpNet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::network::{parse_cidr, Endpoint};
+use crate::protocol::{KEY_EXCHANGE_V2, KEY_EXCHANGE_V3};
+use crate::relay::http::parse_path_prefix;
+use crate::relay::{
+    RelayUrl, DEFAULT_MAILBOX_MAX_BYTES, DEFAULT_PRE_REGISTER_FRAMES, DEFAULT_PRE_REGISTER_FRAME_SIZE, DEFAULT_QUEUE_DEPTH, DEFAULT_REGISTER_TIMEOUT,
+    DEFAULT_ROOM_IN_FLIGHT_BYTES, DEFAULT_SESSION_TIMEOUT, MAX_RELAY_FRAME_SIZE,
+};
+use crate::selftest::DEFAULT_SELFTEST_SIZE;
+use crate::session::schedule::parse_start_time;
+use crate::transfer::{ArchiveFormat, ChecksumChoice, ConflictStrategy, DEFAULT_MEMORY_LIMIT, DEFAULT_READAHEAD, MIN_MEMORY_LIMIT};
 
 #[derive(Parser, Debug)]
 #[command(name = "zap")]
@@ -20,15 +35,91 @@ pub struct Cli {
     /// Verbose output
     #[arg(long, short = 'v', global = true)]
     pub verbose: bool,
+    
+    /// Once matched through a relay, try connecting to the peer directly (both sides must ask)
+    #[arg(long, global = true)]
+    pub try_direct: bool,
+    
+    /// Mix a post-quantum (ML-KEM-768) secret into the session key when the other side offers it too
+    #[arg(long, global = true)]
+    pub pq: bool,
+    
+    /// Wrap a direct connection in TLS too, checking the peer's certificate against the code (both sides must ask)
+    #[arg(long, global = true)]
+    pub direct_tls: bool,
+    
+    /// Like --pq, but refuse to go on with a peer that can't (--min-protocol 3)
+    #[arg(long, global = true)]
+    pub require_pq: bool,
+    
+    /// Refuse any key exchange weaker than SPAKE2, such as a mailbox transfer's (--min-protocol 2)
+    #[arg(long, global = true)]
+    pub require_pake: bool,
+    
+    /// Weakest key exchange to go on with: 1 code alone (mailbox), 2 SPAKE2, 3 SPAKE2 with ML-KEM-768
+    #[arg(long, global = true, value_name = "N", env = "ZAP_MIN_PROTOCOL", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    pub min_protocol: u8,
+    
+    /// Threads checksumming files for a folder sync (default: one per core)
+    #[arg(long, global = true, default_value_t = 0, hide_default_value = true)]
+    pub hash_threads: usize,
+    
+    /// Give up when a direct connection receives nothing for this many seconds
+    #[arg(long, global = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_timeout: Option<u64>,
+    
+    /// Give up when a direct connection can't send anything for this many seconds
+    #[arg(long, global = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub write_timeout: Option<u64>,
+    
+    /// Try a host's addresses one at a time instead of racing IPv4 against IPv6
+    #[arg(long, global = true)]
+    pub no_happy_eyeballs: bool,
+    
+    /// Keep the computer from sleeping while a transfer runs (default: on when run from a terminal)
+    #[arg(long, global = true, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    pub inhibit_sleep: Option<bool>,
+    
+    /// Log every message on direct connections to stderr as JSON (debug builds only)
+    #[arg(long, global = true)]
+    pub debug_protocol: bool,
+    
+    /// Once a transfer is done, show how long each phase of it took
+    #[arg(long, global = true)]
+    pub stats: bool,
+    
+    /// Ask questions in the language of this JSON message catalog, and take its answers to them
+    #[arg(long, global = true, value_name = "FILE", env = "ZAP_MESSAGES")]
+    pub messages: Option<PathBuf>,
+    
+    /// Read settings from this file instead of ~/.config/zap/config.toml; it has to exist
+    #[arg(long, global = true, value_name = "FILE", conflicts_with = "no_config")]
+    pub config: Option<PathBuf>,
+    
+    /// Ignore the settings file, going by the command line and ZAP_* variables alone
+    #[arg(long, global = true)]
+    pub no_config: bool,
+    
+    /// Keep the history of files sent and received, which verify --from-history reads, in this file instead ("off" keeps none)
+    #[arg(long, global = true, value_name = "FILE", env = "ZAP_HISTORY")]
+    pub history: Option<PathBuf>,
+    
+    /// Seed the random number generator, to reproduce a bug report's codes
+    #[arg(long, global = true, hide = true)]
+    pub seed: Option<u64>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Send a file or directory
     Send {
-        /// File or directory to send (or read from stdin if omitted)
+        /// File or directory to send
         path: Option<PathBuf>,
         
+        /// Send stdin instead of a file, copying it to stdout like `tee`
+        #[arg(long, conflicts_with_all = ["path", "mailbox"])]
+        stdin_passthrough: bool,
+        
         /// Custom code instead of generating one
         #[arg(long, short = 'c')]
         code: Option<String>,
@@ -37,9 +128,85 @@ pub enum Commands {
         #[arg(long, short = 'w', default_value = "3")]
         words: usize,
         
-        /// Use relay server (format: host:port)
+        /// Generate the code from a custom wordlist (file path or HTTPS URL)
         #[arg(long)]
-        relay: Option<String>,
+        wordlist: Option<String>,
+        
+        /// Use relay server (host, host:port or ws(s):// URL; comma-separate several for failover)
+        #[arg(long, env = "ZAP_RELAYS", value_delimiter = ',')]
+        relay: Option<Vec<RelayUrl>>,
+        
+        /// Largest WebSocket frame to send through the relay, in bytes; bigger messages are split (default: 2 MB)
+        #[arg(long, default_value_t = MAX_RELAY_FRAME_SIZE, hide_default_value = true, requires = "relay")]
+        relay_max_frame_size: usize,
+        
+        /// Leave the file in the relay's mailbox for a receiver that isn't online yet
+        #[arg(long, requires = "relay")]
+        mailbox: bool,
+        
+        /// How long the relay should keep a mailbox upload (e.g. 30m, 24h, 3days)
+        #[arg(long, default_value = "24h", value_parser = humantime::parse_duration)]
+        mailbox_ttl: Duration,
+        
+        /// Chunks to read from disk ahead of the network, for slow disks
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_READAHEAD, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        readahead: usize,
+        
+        /// How to pack a directory: tar, or zip for receivers who want to open it elsewhere
+        #[arg(long, default_value_t = ArchiveFormat::Tar)]
+        format: ArchiveFormat,
+        
+        /// Connect now but start sending at this time: local like 02:00, or RFC 3339
+        #[arg(long, value_name = "TIME", value_parser = parse_start_time, conflicts_with = "mailbox")]
+        at: Option<SystemTime>,
+        
+        /// Connect now but start sending once network traffic drops below this many Mbps
+        #[arg(long, value_name = "MBPS", conflicts_with_all = ["at", "mailbox"])]
+        when_idle: Option<f64>,
+        
+        /// Measure the connection for a few seconds first, show how long the transfer would take and ask whether to go ahead
+        #[arg(long, conflicts_with = "mailbox")]
+        estimate: bool,
+        
+        /// Go ahead after --estimate, or delete with --sync --delete, without asking
+        #[arg(long, short = 'y')]
+        yes: bool,
+        
+        /// Only let receivers in this address range connect, e.g. 192.168.1.0/24 (repeatable)
+        #[arg(long, value_name = "CIDR", value_parser = parse_cidr, conflicts_with = "relay")]
+        allow: Vec<IpNet>,
+        
+        /// Only let receivers on private, link-local or loopback addresses connect
+        #[arg(long, conflicts_with = "relay")]
+        lan_only: bool,
+        
+        /// Have the receiver confirm the file arrived intact, saving its receipt as <file>.zap-receipt.json
+        #[arg(long, conflicts_with_all = ["stdin_passthrough", "mailbox"])]
+        receipt: bool,
+        
+        /// Make the receiver's copy of a folder match this one, sending only the files that differ
+        #[arg(long, conflicts_with_all = ["stdin_passthrough", "mailbox", "receipt", "format"])]
+        sync: bool,
+        
+        /// With --sync, delete files only the receiver has (asks first, unless --yes)
+        #[arg(long, requires = "sync")]
+        delete: bool,
+        
+        /// With --sync, read every file again instead of trusting the checksums cached from the last sync for files whose size and modification time haven't changed
+        #[arg(long, requires = "sync")]
+        no_manifest_cache: bool,
+        
+        /// Leave files and folders that can't be read out of a directory, with a warning, instead of refusing to send it
+        #[arg(long, conflicts_with_all = ["stdin_passthrough", "sync"])]
+        skip_unreadable: bool,
+        
+        /// Threads encrypting a file's chunks (default: one fewer than the cores, up to 4)
+        #[arg(long, value_name = "N", default_value_t = 0, hide_default_value = true)]
+        crypto_threads: usize,
+        
+        /// Print the code, then each event (progress, hints, ...) as a line of JSON on stdout, for scripts
+        #[arg(long, conflicts_with = "stdin_passthrough")]
+        json: bool,
     },
     
     /// Receive a file or directory
@@ -51,13 +218,111 @@ pub enum Commands {
         #[arg(long, short = 'o')]
         output: Option<PathBuf>,
         
-        /// Resume a previous transfer
+        /// Sender's address for a direct transfer: host, host:port or [IPv6]:port (asked for interactively if omitted, except with --json)
+        #[arg(long, conflicts_with = "relay")]
+        host: Option<Endpoint>,
+        
+        /// Pick up a cancelled or interrupted transfer of the same file where it stopped
         #[arg(long, short = 'r')]
         resume: bool,
         
-        /// Use relay server (format: host:port)
+        /// Use relay server (host, host:port or ws(s):// URL; comma-separate several for failover)
+        #[arg(long, env = "ZAP_RELAYS", value_delimiter = ',')]
+        relay: Option<Vec<RelayUrl>>,
+        
+        /// Largest WebSocket frame to send through the relay, in bytes; bigger messages are split (default: 2 MB)
+        #[arg(long, default_value_t = MAX_RELAY_FRAME_SIZE, hide_default_value = true, requires = "relay")]
+        relay_max_frame_size: usize,
+        
+        /// Take a file the sender left in the relay's mailbox (send --mailbox) if the sender isn't there
+        #[arg(long, requires = "relay")]
+        mailbox: bool,
+        
+        /// When a received directory has files that already exist: skip, overwrite, rename, newer or ask
+        #[arg(long, default_value_t = ConflictStrategy::Overwrite)]
+        conflict: ConflictStrategy,
+        
+        /// Only accept files with these extensions, checked against their contents too (e.g. jpg,png,pdf)
+        #[arg(long, env = "ZAP_ACCEPT_TYPES")]
+        accept_types: Option<String>,
+        
+        /// Accept files that look like programs without asking first
+        #[arg(long)]
+        allow_executables: bool,
+        
+        /// Take the offered file without asking first
+        #[arg(long)]
+        auto_accept: bool,
+        
+        /// Decline files bigger than this many bytes
+        #[arg(long, value_name = "BYTES")]
+        reject_larger_than: Option<u64>,
+        
+        /// Most memory incoming data may take at once, in bytes (default: 64 MB, at least 4 MB); the sender sends smaller chunks to fit
+        #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MEMORY_LIMIT, hide_default_value = true, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(MIN_MEMORY_LIMIT as u64..))]
+        memory_limit: usize,
+        
+        /// Keep temporary files (staged folders, resume records) here instead of next to the output
+        #[arg(long, env = "ZAP_TMP_DIR")]
+        tmp_dir: Option<PathBuf>,
+        
+        /// Fail when a received file's modification time, permissions or hard link can't be set, instead of warning
+        #[arg(long)]
+        strict_metadata: bool,
+        
+        /// Leave out the files of a folder that can't be written, out of space or otherwise, and list them, instead of stopping
+        #[arg(long)]
+        keep_going: bool,
+        
+        /// How to check what arrives for receipts and checkpoints: blake3 (SHA-256 for senders without it), sha256, or none to skip both
+        #[arg(long, default_value_t = ChecksumChoice::Blake3)]
+        checksum: ChecksumChoice,
+        
+        /// Check a file for its receipt by reading it back once it's all arrived, instead of hashing it on the way in
+        #[arg(long)]
+        verify_after: bool,
+        
+        /// Sign receipts with this identity key (make one with `zap receipt keygen`)
+        #[arg(long, value_name = "KEY_FILE", env = "ZAP_IDENTITY")]
+        identity: Option<PathBuf>,
+        
+        /// When the output file already exists, only fetch the parts of it that changed
         #[arg(long)]
-        relay: Option<String>,
+        delta: bool,
+        
+        /// Take a folder the sender is syncing, fetching only the files that differ from the output folder
+        #[arg(long)]
+        sync: bool,
+        
+        /// Stream what arrives into this shell command's stdin instead of saving it
+        #[arg(long, value_name = "COMMAND", conflicts_with_all = ["output", "resume", "delta", "sync"])]
+        pipe_to: Option<String>,
+        
+        /// Print each event (progress, hints, ...), then where the file went, as a line of JSON on stdout, for scripts
+        #[arg(long)]
+        json: bool,
+    },
+    
+    /// Show what a sender is offering without taking it; the sender keeps waiting for a receiver
+    Peek {
+        /// Transfer code from sender
+        code: String,
+        
+        /// Sender's address for a direct transfer: host, host:port or [IPv6]:port
+        #[arg(long, conflicts_with = "relay")]
+        host: Option<Endpoint>,
+        
+        /// Use relay server (host, host:port or ws(s):// URL; comma-separate several for failover)
+        #[arg(long, env = "ZAP_RELAYS", value_delimiter = ',')]
+        relay: Option<Vec<RelayUrl>>,
+        
+        /// Largest WebSocket frame to send through the relay, in bytes; bigger messages are split (default: 2 MB)
+        #[arg(long, default_value_t = MAX_RELAY_FRAME_SIZE, hide_default_value = true, requires = "relay")]
+        relay_max_frame_size: usize,
+        
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
     },
     
     /// Run a relay server for NAT-to-NAT transfers
@@ -65,6 +330,182 @@ pub enum Commands {
         /// Port to listen on
         #[arg(long, short = 'p', default_value = "7777")]
         port: u16,
+        
+        /// Largest WebSocket frame the relay accepts, in bytes; clients sending bigger ones are disconnected
+        #[arg(long, default_value_t = MAX_RELAY_FRAME_SIZE)]
+        relay_max_frame_size: usize,
+        
+        /// Frames buffered per client before the relay pauses reading from its peer
+        #[arg(long, default_value_t = DEFAULT_QUEUE_DEPTH)]
+        relay_queue_depth: usize,
+        
+        /// Bytes one room may have queued towards a client before its sender waits
+        #[arg(long, default_value_t = DEFAULT_ROOM_IN_FLIGHT_BYTES)]
+        room_in_flight_bytes: usize,
+        
+        /// Limit each room to this many bytes per second in each direction
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        per_room_rate: Option<u64>,
+        
+        /// Serve the admin endpoint on a Unix socket
+        #[arg(long)]
+        admin_socket: Option<PathBuf>,
+        
+        /// Serve the admin endpoint on a loopback TCP address (e.g. 127.0.0.1:7778)
+        #[arg(long)]
+        admin_addr: Option<SocketAddr>,
+        
+        /// Store uploads for receivers that aren't online yet
+        #[arg(long)]
+        allow_mailbox: bool,
+        
+        /// Directory holding mailbox uploads
+        #[arg(long, default_value = "zap-mailbox")]
+        mailbox_dir: PathBuf,
+        
+        /// Disk space all mailbox uploads may use together, in bytes
+        #[arg(long, default_value_t = DEFAULT_MAILBOX_MAX_BYTES)]
+        mailbox_max_bytes: u64,
+        
+        /// Only accept clients matching a rule in this file (IP, CIDR or hash:<prefix> per line; reloaded on change)
+        #[arg(long)]
+        allowlist: Option<PathBuf>,
+        
+        /// Refuse clients matching a rule in this file (IP, CIDR or hash:<prefix> per line; reloaded on change)
+        #[arg(long)]
+        denylist: Option<PathBuf>,
+        
+        /// Rooms to make space for up front, for relays expecting many concurrent transfers
+        #[arg(long, default_value_t = 0)]
+        capacity: usize,
+        
+        /// Close rooms that have waited this many seconds for their second peer
+        #[arg(long, default_value_t = DEFAULT_SESSION_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+        session_timeout: u64,
+        
+        /// Disconnect clients that haven't registered this many seconds after connecting
+        #[arg(long, default_value_t = DEFAULT_REGISTER_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+        register_timeout: u64,
+        
+        /// Disconnect clients that send a frame larger than this, in bytes, before registering
+        #[arg(long, default_value_t = DEFAULT_PRE_REGISTER_FRAME_SIZE)]
+        pre_register_frame_size: usize,
+        
+        /// Disconnect clients that send more than this many other frames, like pings, before registering
+        #[arg(long, default_value_t = DEFAULT_PRE_REGISTER_FRAMES)]
+        pre_register_frames: usize,
+        
+        /// Only accept WebSocket upgrades for this path (e.g. /zap behind a reverse proxy); any path otherwise
+        #[arg(long, value_parser = parse_path_prefix)]
+        path: Option<String>,
+        
+        /// Take client addresses from X-Forwarded-For, for logs and allow/deny lists; only behind a proxy that sets it
+        #[arg(long)]
+        trust_proxy: bool,
+    },
+    
+    /// Send a test file to yourself over every transport and report what worked
+    Selftest {
+        /// Size of the test file in bytes
+        #[arg(long, default_value_t = DEFAULT_SELFTEST_SIZE)]
+        size: u64,
+        
+        /// Also go through a relay started inside this process
+        #[arg(long)]
+        with_relay: bool,
+    },
+    
+    /// Make an identity key for signing receipts, or check a file against a receipt
+    Receipt {
+        #[command(subcommand)]
+        action: ReceiptAction,
+    },
+    
+    /// Check a received file against the checksum it should have, without changing anything
+    Verify {
+        /// The file to check
+        path: PathBuf,
+        
+        /// Expected SHA-256, in hex, or BLAKE3 as blake3:<hex>
+        #[arg(long, required_unless_present_any = ["receipt", "from_history"], conflicts_with_all = ["receipt", "from_history"])]
+        checksum: Option<String>,
+        
+        /// A receipt for the file (<file>.zap-receipt.json); its signature is checked too
+        #[arg(long, conflicts_with = "from_history")]
+        receipt: Option<PathBuf>,
+        
+        /// The checksum the file had when zap last sent or received it, from the history
+        #[arg(long)]
+        from_history: bool,
+    },
+    
+    /// Remove temporary files left behind by abandoned transfers
+    Clean {
+        /// Directory to search, including subdirectories
+        #[arg(default_value = ".", env = "ZAP_TMP_DIR")]
+        dir: PathBuf,
+        
+        /// List what would be removed without removing it
+        #[arg(long)]
+        dry_run: bool,
+        
+        /// Leave anything younger than this alone (e.g. 30m, 24h, 3days)
+        #[arg(long, default_value = "24h", value_parser = humantime::parse_duration)]
+        older_than: Duration,
+    },
+    
+    /// Read or change the settings file, which stands in for the ZAP_* environment variables
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    
+    /// Print detailed build information
+    Version {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ReceiptAction {
+    /// Make a new identity key and print its public half for senders to check against
+    Keygen {
+        /// Where to write the key; an existing file is never overwritten
+        key_file: PathBuf,
+    },
+    
+    /// Check that a file is the one a receipt was given for, and that its signature holds
+    Verify {
+        /// The file that was sent
+        file: PathBuf,
+        
+        /// Its receipt (<file>.zap-receipt.json)
+        receipt: PathBuf,
+        
+        /// Only accept a receipt signed by this public key (hex)
+        #[arg(long)]
+        key: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Save a setting to the file, checking the key and value first
+    Set {
+        key: String,
+        value: String,
+    },
+    
+    /// Print a setting in effect, or with no key, every setting and where it came from
+    Get {
+        key: Option<String>,
+    },
+    
+    /// Remove a setting from the file
+    Unset {
+        key: String,
     },
 }
 
@@ -72,4 +513,298 @@ impl Cli {
     pub fn parse_args() -> Self {
         Self::parse()
     }
+    
+    /// The key exchange floor `--min-protocol` sets, raised by `--require-pake` and `--require-pq`
+    pub fn min_protocol(&self) -> u8 {
+        let mut floor = self.min_protocol;
+        if self.require_pake {
+            floor = floor.max(KEY_EXCHANGE_V2);
+        }
+        if self.require_pq {
+            floor = floor.max(KEY_EXCHANGE_V3);
+        }
+        floor
+    }
+    
+    /// Parse the command line with `defaults`, by environment variable, for the options that read one
+    ///
+    /// A value on the command line or in the variable itself still wins, so
+    /// the settings file can fill in options without touching the environment.
+    pub fn parse_with_defaults(defaults: &[(&str, String)]) -> Self {
+        Self::try_parse_with_defaults(std::env::args_os(), defaults).unwrap_or_else(|e| e.exit())
+    }
+    
+    pub fn try_parse_with_defaults<I, T>(args: I, defaults: &[(&str, String)]) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = with_env_defaults(Self::command(), defaults).try_get_matches_from(args)?;
+        Self::from_arg_matches(&matches)
+    }
+}
+
+/// `command`, and its subcommands, with `defaults` for the arguments reading those environment variables
+fn with_env_defaults(mut command: Command, defaults: &[(&str, String)]) -> Command {
+    let found: Vec<(clap::Id, String)> = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let env = arg.get_env()?;
+            let (_, value) = defaults.iter().find(|(name, _)| env == std::ffi::OsStr::new(name))?;
+            Some((arg.get_id().clone(), value.clone()))
+        })
+        .collect();
+    for (id, value) in found {
+        command = command.mut_arg(id, |arg| arg.default_value(value));
+    }
+    let names: Vec<String> = command.get_subcommands().map(|sub| sub.get_name().to_string()).collect();
+    for name in names {
+        command = command.mut_subcommand(name, |sub| with_env_defaults(sub, defaults));
+    }
+    command
+}
+
+impl Commands {
+    /// Whether stdout is for lines of JSON, so anything that goes wrong has to be one too
+    pub fn json(&self) -> bool {
+        match self {
+            Commands::Send { json, .. } | Commands::Receive { json, .. } | Commands::Peek { json, .. } | Commands::Version { json } => *json,
+            _ => false,
+        }
+    }
+    
+    /// Checks clap can't make on its own, run once the arguments are parsed
+    ///
+    /// Mistakes that can't work are errors; ones with an obvious fix are
+    /// fixed in place and returned as warnings for the caller to show.
+    /// `port` is the global `--port`.
+    pub fn post_validate(&mut self, port: Option<u16>) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
+        match self {
+            Commands::Send { path: Some(path), code, .. } => {
+                if !path.exists() {
+                    return Err(match suggest_path(path) {
+                        Some(suggestion) => anyhow!("File not found: {}. Did you mean {}?", path.display(), suggestion.display()),
+                        None => anyhow!("File not found: {}", path.display()),
+                    });
+                }
+                if let Some(code) = code {
+                    lowercase_code(code, &mut warnings);
+                }
+            }
+            Commands::Send { code: Some(code), .. } => lowercase_code(code, &mut warnings),
+            // Nothing may be asked on stdin when a script reads the output
+            Commands::Receive { json: true, host: None, relay: None, .. } => {
+                return Err(anyhow!("receive --json can't ask for the sender's address; pass --host or --relay"));
+            }
+            Commands::Receive { code, host, .. } | Commands::Peek { code, host, .. } => {
+                lowercase_code(code, &mut warnings);
+                warnings.extend(host.as_ref().and_then(Endpoint::note));
+            }
+            Commands::Relay { port, .. } => privileged_port(Some(*port), &mut warnings),
+            _ => {}
+        }
+        if !matches!(self, Commands::Relay { .. }) {
+            privileged_port(port, &mut warnings);
+        }
+        Ok(warnings)
+    }
+}
+
+/// Codes are typed by people, so `Alpha-Bravo` means `alpha-bravo`
+///
+/// Both ends lowercase, or a custom code with capitals would never match.
+fn lowercase_code(code: &mut String, warnings: &mut Vec<String>) {
+    let lower = code.to_lowercase();
+    if lower != *code {
+        warnings.push(format!("Transfer codes are lowercase, using {}", lower));
+        *code = lower;
+    }
+}
+
+#[cfg(unix)]
+fn privileged_port(port: Option<u16>, warnings: &mut Vec<String>) {
+    if let Some(port @ 1..=1023) = port {
+        warnings.push(format!("Port {} is below 1024 and usually needs root", port));
+    }
+}
+
+#[cfg(not(unix))]
+fn privileged_port(_port: Option<u16>, _warnings: &mut Vec<String>) {}
+
+/// The closest name to a missing path's among its would-be siblings
+fn suggest_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let entries = std::fs::read_dir(parent.unwrap_or(Path::new("."))).ok()?;
+    let (_, best) = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .map(|candidate| (strsim::jaro_winkler(&name, &candidate), candidate))
+        .filter(|(score, _)| *score >= 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))?;
+    Some(match parent {
+        Some(parent) => parent.join(best),
+        None => PathBuf::from(best),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    fn validate(args: &[&str]) -> Result<(Cli, Vec<String>)> {
+        let mut cli = Cli::try_parse_from(args)?;
+        let warnings = cli.command.post_validate(cli.port)?;
+        Ok((cli, warnings))
+    }
+    
+    #[test]
+    fn test_missing_path_suggests_a_sibling() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("holiday-photos.zip"), b"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+        
+        let typo = dir.path().join("holiday-photo.zip");
+        let err = validate(&["zap", "send", typo.to_str().unwrap()]).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("File not found: {}.", typo.display())), "{}", err);
+        assert!(err.ends_with(&format!("Did you mean {}?", dir.path().join("holiday-photos.zip").display())), "{}", err);
+        
+        // Nothing close enough to suggest
+        let unrelated = dir.path().join("quarterly-report.pdf");
+        let err = validate(&["zap", "send", unrelated.to_str().unwrap()]).unwrap_err().to_string();
+        assert_eq!(err, format!("File not found: {}", unrelated.display()));
+        
+        assert!(validate(&["zap", "send", dir.path().join("notes.txt").to_str().unwrap()]).is_ok());
+    }
+    
+    #[test]
+    fn test_codes_are_lowercased() {
+        let (cli, warnings) = validate(&["zap", "receive", "Alpha-BRAVO-charlie"]).unwrap();
+        assert!(matches!(cli.command, Commands::Receive { ref code, .. } if code == "alpha-bravo-charlie"));
+        assert_eq!(warnings, ["Transfer codes are lowercase, using alpha-bravo-charlie"]);
+        
+        // The sender's custom code too, so the two still match
+        let (cli, warnings) = validate(&["zap", "send", "--stdin-passthrough", "--code", "MyCode"]).unwrap();
+        assert!(matches!(cli.command, Commands::Send { code: Some(ref code), .. } if code == "mycode"));
+        assert_eq!(warnings.len(), 1);
+        
+        assert!(validate(&["zap", "receive", "alpha-bravo-charlie"]).unwrap().1.is_empty());
+    }
+    
+    #[test]
+    fn test_relay_checked_while_parsing() {
+        let (cli, _) = validate(&["zap", "receive", "alpha-bravo", "--relay", "wss://a.example/zap/, b.example:7777"]).unwrap();
+        let Commands::Receive { relay: Some(relays), .. } = cli.command else {
+            panic!("expected a relay list");
+        };
+        assert_eq!(relays.iter().map(ToString::to_string).collect::<Vec<_>>(), ["wss://a.example/zap", "ws://b.example:7777"]);
+        
+        let err = validate(&["zap", "send", "--stdin-passthrough", "--relay", "https://relay.example.com"]).unwrap_err().to_string();
+        assert!(err.contains("Relays speak WebSocket, not https://: try wss://relay.example.com"), "{}", err);
+        assert!(validate(&["zap", "peek", "alpha-bravo", "--relay", "relay.example.com:99999"]).is_err());
+    }
+    
+    #[test]
+    fn test_json_receive_needs_an_address() {
+        let err = validate(&["zap", "receive", "alpha-bravo", "--json"]).unwrap_err().to_string();
+        assert_eq!(err, "receive --json can't ask for the sender's address; pass --host or --relay");
+        assert!(validate(&["zap", "receive", "alpha-bravo", "--json", "--host", "192.168.1.20"]).is_ok());
+        assert!(validate(&["zap", "receive", "alpha-bravo", "--json", "--relay", "relay.example.com"]).is_ok());
+        assert!(validate(&["zap", "receive", "alpha-bravo"]).is_ok());
+    }
+    
+    #[test]
+    fn test_relay_frame_size_only_for_relay_commands() {
+        assert!(Cli::try_parse_from(["zap", "send", "file.txt", "--relay", "relay.example.com", "--relay-max-frame-size", "65536"]).is_ok());
+        assert!(Cli::try_parse_from(["zap", "relay", "--relay-max-frame-size", "65536"]).is_ok());
+        // Nothing to split on a direct connection
+        assert!(Cli::try_parse_from(["zap", "receive", "alpha-bravo", "--relay-max-frame-size", "65536"]).is_err());
+        assert!(Cli::try_parse_from(["zap", "selftest", "--relay-max-frame-size", "65536"]).is_err());
+    }
+    
+    #[test]
+    fn test_settings_are_defaults() {
+        let defaults = [
+            ("ZAP_RELAYS", "a.example:7777,b.example:7777".to_string()),
+            ("ZAP_TMP_DIR", "/scratch".to_string()),
+        ];
+        let parse = |args: &[&str]| Cli::try_parse_with_defaults(args, &defaults).unwrap().command;
+        let Commands::Receive { relay: Some(relays), tmp_dir, .. } = parse(&["zap", "receive", "alpha-bravo"]) else {
+            panic!("expected the relays from the settings");
+        };
+        assert_eq!(relays.len(), 2);
+        assert_eq!(tmp_dir.as_deref(), Some(Path::new("/scratch")));
+        assert!(matches!(parse(&["zap", "clean"]), Commands::Clean { dir, .. } if dir == Path::new("/scratch")));
+        
+        // The command line still wins
+        let Commands::Receive { relay: Some(relays), .. } = parse(&["zap", "receive", "alpha-bravo", "--relay", "c.example"]) else {
+            panic!("expected the relay given");
+        };
+        assert_eq!(relays.iter().map(ToString::to_string).collect::<Vec<_>>(), ["ws://c.example"]);
+        // And a bad value is caught like one typed in
+        let bad = [("ZAP_RELAYS", "https://relay.example.com".to_string())];
+        assert!(Cli::try_parse_with_defaults(["zap", "receive", "alpha-bravo"], &bad).is_err());
+    }
+    
+    #[test]
+    fn test_config_flags() {
+        let (cli, _) = validate(&["zap", "--config", "/etc/zap.toml", "config", "get"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some(Path::new("/etc/zap.toml")));
+        let (cli, _) = validate(&["zap", "receive", "alpha-bravo", "--no-config"]).unwrap();
+        assert!(cli.no_config && cli.config.is_none());
+        assert!(validate(&["zap", "config", "get", "--config", "a.toml", "--no-config"]).is_err());
+    }
+    
+    #[test]
+    fn test_min_protocol() {
+        let floor = |args: &[&str]| Cli::try_parse_from(args).map(|cli| cli.min_protocol());
+        assert_eq!(floor(&["zap", "receive", "alpha-bravo"]).unwrap(), 1);
+        assert_eq!(floor(&["zap", "receive", "alpha-bravo", "--require-pake"]).unwrap(), 2);
+        assert_eq!(floor(&["zap", "send", "a.txt", "--min-protocol", "2", "--require-pq"]).unwrap(), 3);
+        // The aliases only ever raise the floor
+        assert_eq!(floor(&["zap", "send", "a.txt", "--min-protocol", "3", "--require-pake"]).unwrap(), 3);
+        assert!(floor(&["zap", "send", "a.txt", "--min-protocol", "4"]).is_err());
+        assert!(floor(&["zap", "send", "a.txt", "--min-protocol", "0"]).is_err());
+    }
+    
+    #[test]
+    fn test_verify_needs_exactly_one_source() {
+        assert!(validate(&["zap", "verify", "photo.jpg", "--checksum", "ab12"]).is_ok());
+        assert!(validate(&["zap", "verify", "photo.jpg", "--receipt", "photo.jpg.zap-receipt.json"]).is_ok());
+        assert!(validate(&["zap", "verify", "photo.jpg"]).is_err());
+        assert!(validate(&["zap", "verify", "photo.jpg", "--checksum", "ab12", "--receipt", "r.json"]).is_err());
+        assert!(validate(&["zap", "verify", "photo.jpg", "--from-history"]).is_ok());
+        assert!(validate(&["zap", "verify", "photo.jpg", "--from-history", "--checksum", "ab12"]).is_err());
+        assert!(validate(&["zap", "verify", "photo.jpg", "--from-history", "--receipt", "r.json"]).is_err());
+    }
+    
+    #[test]
+    fn test_host_checked_while_parsing() {
+        let (cli, warnings) = validate(&["zap", "receive", "alpha-bravo", "--host", "[::1]:9000"]).unwrap();
+        let Commands::Receive { host: Some(host), .. } = cli.command else {
+            panic!("expected a host");
+        };
+        assert_eq!(host.target(9999), ("::1", 9000));
+        assert!(warnings.is_empty());
+        
+        let (_, warnings) = validate(&["zap", "peek", "alpha-bravo", "--host", "http://laptop.local/"]).unwrap();
+        assert_eq!(warnings, ["Ignoring http:// in front of laptop.local; zap connects to it directly"]);
+        
+        let err = validate(&["zap", "receive", "alpha-bravo", "--host", "laptop.local:99999"]).unwrap_err().to_string();
+        assert!(err.contains("Invalid port \"99999\" (must be 1-65535)"), "{}", err);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_privileged_port_warns() {
+        let (_, warnings) = validate(&["zap", "receive", "alpha-bravo", "--port", "80"]).unwrap();
+        assert_eq!(warnings, ["Port 80 is below 1024 and usually needs root"]);
+        let (_, warnings) = validate(&["zap", "relay", "--port", "443"]).unwrap();
+        assert_eq!(warnings, ["Port 443 is below 1024 and usually needs root"]);
+        
+        assert!(validate(&["zap", "receive", "alpha-bravo", "--port", "9999"]).unwrap().1.is_empty());
+        assert!(validate(&["zap", "relay"]).unwrap().1.is_empty());
+    }
 }
@@ -0,0 +1,405 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item};
+
+use crate::fsutil;
+use crate::relay::RelayUrl;
+
+/// Names another settings file to use instead of the usual one
+pub const CONFIG_ENV: &str = "ZAP_CONFIG";
+
+/// A setting the file can hold, standing in for the environment variable the option already reads
+#[derive(Debug)]
+pub struct Key {
+    pub name: &'static str,
+    pub env: &'static str,
+    pub about: &'static str,
+    check: fn(&str) -> Result<()>,
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Key {}
+
+/// Every key the settings file knows; anything else in it is a mistake
+pub const KEYS: &[Key] = &[
+    Key {
+        name: "relays",
+        env: "ZAP_RELAYS",
+        about: "Relay servers for send, receive and peek, comma-separated",
+        check: check_relays,
+    },
+    Key {
+        name: "accept_types",
+        env: "ZAP_ACCEPT_TYPES",
+        about: "Extensions receive accepts, comma-separated",
+        check: check_not_empty,
+    },
+    Key {
+        name: "tmp_dir",
+        env: "ZAP_TMP_DIR",
+        about: "Where receive keeps temporary files, and where clean looks",
+        check: check_not_empty,
+    },
+    Key {
+        name: "identity",
+        env: "ZAP_IDENTITY",
+        about: "Identity key file that signs receipts",
+        check: check_not_empty,
+    },
+    Key {
+        name: "messages",
+        env: "ZAP_MESSAGES",
+        about: "JSON message catalog that questions are asked from",
+        check: check_not_empty,
+    },
+    Key {
+        name: "history",
+        env: "ZAP_HISTORY",
+        about: "File the history of sent and received files is kept in, or off",
+        check: check_not_empty,
+    },
+    Key {
+        name: "min_protocol",
+        env: "ZAP_MIN_PROTOCOL",
+        about: "Weakest key exchange to go on with: 1 (mailbox), 2 (SPAKE2) or 3 (with ML-KEM-768)",
+        check: check_min_protocol,
+    },
+];
+
+fn check_relays(value: &str) -> Result<()> {
+    for relay in value.split(',') {
+        relay.trim().parse::<RelayUrl>()?;
+    }
+    Ok(())
+}
+
+fn check_min_protocol(value: &str) -> Result<()> {
+    match value.trim().parse::<u8>() {
+        Ok(1..=3) => Ok(()),
+        _ => Err(anyhow!("Give 1, 2 or 3")),
+    }
+}
+
+fn check_not_empty(value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        return Err(anyhow!("Give a value, or unset it instead"));
+    }
+    Ok(())
+}
+
+/// The key called `name`, or an error suggesting the nearest one
+pub fn find_key(name: &str) -> Result<&'static Key> {
+    if let Some(key) = KEYS.iter().find(|key| key.name == name) {
+        return Ok(key);
+    }
+    Err(match suggest_key(name) {
+        Some(key) => anyhow!("Unknown setting {:?}. Did you mean {}?", name, key.name),
+        None => anyhow!("Unknown setting {:?}; zap knows {}", name, known()),
+    })
+}
+
+fn suggest_key(name: &str) -> Option<&'static Key> {
+    let name = name.to_lowercase().replace('-', "_");
+    KEYS.iter()
+        .map(|key| (strsim::jaro_winkler(&name, key.name), key))
+        .filter(|(score, _)| *score >= 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, key)| key)
+}
+
+fn known() -> String {
+    KEYS.iter().map(|key| key.name).collect::<Vec<_>>().join(", ")
+}
+
+/// Where the settings file is: `ZAP_CONFIG`, or `zap/config.toml` in the user's config directory
+pub fn config_path() -> Option<PathBuf> {
+    match std::env::var_os(CONFIG_ENV) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(dirs::config_dir()?.join("zap").join("config.toml")),
+    }
+}
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Its environment variable, which beats the file
+    Env(&'static str),
+    /// The settings file
+    File(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Env(name) => write!(f, "from {}", name),
+            Source::File(path) => write!(f, "from {}", path.display()),
+        }
+    }
+}
+
+/// One key's value in effect, and where it came from; neither when it isn't set anywhere
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub key: &'static Key,
+    pub value: Option<String>,
+    pub source: Option<Source>,
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.value, &self.source) {
+            (Some(value), Some(source)) => write!(f, "{} = {:?}  # {}", self.key.name, value, source),
+            _ => write!(f, "# {} isn't set: {}", self.key.name, self.key.about),
+        }
+    }
+}
+
+/// The settings file, as written, so it can be changed without losing its comments or layout
+///
+/// The options each key stands for already read an environment variable;
+/// the file's values become their defaults (see `file_defaults`), so a
+/// variable that's set still wins. Keys are plain strings; `relays` may be an array of them too.
+#[derive(Debug, Clone)]
+pub struct ZapConfig {
+    path: PathBuf,
+    doc: DocumentMut,
+}
+
+impl ZapConfig {
+    /// The file `--config` names, which has to be there, or else the usual one, which needn't be
+    pub fn load(explicit: Option<&Path>) -> Result<Self> {
+        match explicit {
+            Some(path) => match std::fs::metadata(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(anyhow!("Settings file {} doesn't exist", path.display())),
+                _ => Self::read(path),
+            },
+            None => {
+                let path = config_path().ok_or_else(|| anyhow!("No config directory here; set {} or pass --config", CONFIG_ENV))?;
+                Self::read(&path)
+            }
+        }
+    }
+    
+    /// Read the file at `path`; a missing one is empty
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+        };
+        let doc = text.parse::<DocumentMut>().with_context(|| format!("{} isn't valid TOML", path.display()))?;
+        Ok(Self { path: path.to_path_buf(), doc })
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    /// What the file says for `key`, if anything usable
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.doc.get(key)? {
+            Item::Value(value) => match value.as_array() {
+                Some(array) => {
+                    let items: Option<Vec<&str>> = array.iter().map(|item| item.as_str()).collect();
+                    items.map(|items| items.join(","))
+                }
+                None => value.as_str().map(str::to_string),
+            },
+            _ => None,
+        }
+    }
+    
+    /// Set `key` to `value` once both check out; a comment above the key stays where it is
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let key = find_key(key)?;
+        (key.check)(value).with_context(|| format!("Invalid value for {}", key.name))?;
+        self.doc[key.name] = toml_edit::value(value);
+        Ok(())
+    }
+    
+    /// Remove `key`, returning whether the file had it
+    pub fn unset(&mut self, key: &str) -> Result<bool> {
+        let key = find_key(key)?;
+        Ok(self.doc.remove(key.name).is_some())
+    }
+    
+    /// What's wrong with the file as written: keys zap doesn't know, and values it can't use
+    ///
+    /// Either is left out of what `effective` reports, so it's worth saying.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (name, _) in self.doc.iter() {
+            match find_key(name) {
+                Err(e) => warnings.push(format!("{}: {}", self.path.display(), e)),
+                Ok(_) if self.get(name).is_none() => {
+                    warnings.push(format!("{}: {} should be a string, ignoring it", self.path.display(), name));
+                }
+                Ok(_) => {}
+            }
+        }
+        warnings
+    }
+    
+    /// Every key's value in effect, given what's in the environment (looked up with `env`)
+    pub fn effective(&self, env: impl Fn(&str) -> Option<String>) -> Vec<Setting> {
+        KEYS.iter()
+            .map(|key| {
+                let (value, source) = match (env(key.env), self.get(key.name)) {
+                    (Some(value), _) => (Some(value), Some(Source::Env(key.env))),
+                    (None, Some(value)) => (Some(value), Some(Source::File(self.path.clone()))),
+                    (None, None) => (None, None),
+                };
+                Setting { key, value, source }
+            })
+            .collect()
+    }
+    
+    /// Write the file back, private to the user, replacing the old one in one go
+    ///
+    /// Relay addresses can carry credentials, so the file is kept like zap's own.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fsutil::create_private_dir(dir)?;
+        }
+        let mut part = self.path.as_os_str().to_os_string();
+        part.push(".part");
+        fsutil::write_private(Path::new(&part), self.doc.to_string().as_bytes())?;
+        std::fs::rename(&part, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Every key's value in effect with no settings file, as with `--no-config`
+pub fn environment_only(env: impl Fn(&str) -> Option<String>) -> Vec<Setting> {
+    KEYS.iter()
+        .map(|key| {
+            let value = env(key.env);
+            let source = value.as_ref().map(|_| Source::Env(key.env));
+            Setting { key, value, source }
+        })
+        .collect()
+}
+
+/// The `settings` that came from the file, by the environment variable each stands in for
+///
+/// Handed to `Cli::parse_with_defaults`, which makes them the defaults of
+/// the options reading those variables; the environment itself is left alone.
+pub fn file_defaults(settings: &[Setting]) -> Vec<(&'static str, String)> {
+    settings
+        .iter()
+        .filter_map(|setting| match (&setting.value, &setting.source) {
+            (Some(value), Some(Source::File(_))) => Some((setting.key.env, value.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_set_get_unset_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("zap/config.toml");
+        let mut config = ZapConfig::read(&path).unwrap();
+        assert_eq!(config.get("relays"), None);
+        
+        config.set("relays", "relay.example.com:7777,wss://b.example/zap").unwrap();
+        config.set("tmp_dir", "/var/tmp/zap").unwrap();
+        config.save().unwrap();
+        
+        // Comments someone wrote by hand survive a change
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, text.replace("tmp_dir", "# where zap stages files\ntmp_dir")).unwrap();
+        let mut config = ZapConfig::read(&path).unwrap();
+        assert_eq!(config.get("relays").as_deref(), Some("relay.example.com:7777,wss://b.example/zap"));
+        config.set("tmp_dir", "/scratch").unwrap();
+        assert!(config.unset("relays").unwrap());
+        assert!(!config.unset("relays").unwrap());
+        config.save().unwrap();
+        
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "# where zap stages files\ntmp_dir = \"/scratch\"\n");
+        assert!(!dir.path().join("zap/config.toml.part").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        
+        assert!(config.set("relays", "https://relay.example.com").is_err());
+        assert!(config.set("accept_types", " ").is_err());
+        assert!(config.set("min_protocol", "4").is_err());
+        config.set("min_protocol", "2").unwrap();
+    }
+    
+    #[test]
+    fn test_unknown_keys_suggest_the_nearest() {
+        let err = find_key("relay").unwrap_err().to_string();
+        assert_eq!(err, "Unknown setting \"relay\". Did you mean relays?");
+        assert_eq!(find_key("tmp-dir").unwrap_err().to_string(), "Unknown setting \"tmp-dir\". Did you mean tmp_dir?");
+        let err = find_key("colour").unwrap_err().to_string();
+        assert!(err.ends_with("zap knows relays, accept_types, tmp_dir, identity, messages, history, min_protocol"), "{}", err);
+        
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "acept_types = \"jpg\"\nidentity = 7\nrelays = [\"a.example\", \"b.example\"]\n").unwrap();
+        let config = ZapConfig::read(&path).unwrap();
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert_eq!(warnings[0], format!("{}: Unknown setting \"acept_types\". Did you mean accept_types?", path.display()));
+        assert_eq!(warnings[1], format!("{}: identity should be a string, ignoring it", path.display()));
+        assert_eq!(config.get("relays").as_deref(), Some("a.example,b.example"));
+    }
+    
+    #[test]
+    fn test_effective_settings_say_where_they_came_from() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "relays = \"file.example\"\ntmp_dir = \"/scratch\"\n").unwrap();
+        let config = ZapConfig::read(&path).unwrap();
+        
+        let env = |name: &str| (name == "ZAP_RELAYS").then(|| "env.example".to_string());
+        let settings = config.effective(env);
+        assert_eq!(settings.len(), KEYS.len());
+        let lines: Vec<String> = settings.iter().map(ToString::to_string).collect();
+        assert_eq!(lines[0], "relays = \"env.example\"  # from ZAP_RELAYS");
+        assert_eq!(lines[2], format!("tmp_dir = \"/scratch\"  # from {}", path.display()));
+        assert_eq!(lines[3], "# identity isn't set: Identity key file that signs receipts");
+        
+        // Only what came from the file is handed on; the environment already has the rest
+        assert_eq!(settings[2].source, Some(Source::File(path.clone())));
+        assert_eq!(settings[0].source, Some(Source::Env("ZAP_RELAYS")));
+        assert_eq!(file_defaults(&settings), [("ZAP_TMP_DIR", "/scratch".to_string())]);
+    }
+    
+    #[test]
+    fn test_explicit_path() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ci.toml");
+        std::fs::write(&path, "relays = \"ci.example\"\n").unwrap();
+        let config = ZapConfig::load(Some(&path)).unwrap();
+        assert_eq!(config.path(), path);
+        assert_eq!(config.get("relays").as_deref(), Some("ci.example"));
+        
+        // One that was asked for by name has to be there, rather than quietly read as empty
+        let missing = dir.path().join("missing.toml");
+        let err = ZapConfig::load(Some(&missing)).unwrap_err().to_string();
+        assert_eq!(err, format!("Settings file {} doesn't exist", missing.display()));
+        
+        // Without a file, only the environment counts
+        let env = |name: &str| (name == "ZAP_TMP_DIR").then(|| "/env".to_string());
+        let settings = environment_only(env);
+        assert_eq!(settings.len(), KEYS.len());
+        assert_eq!(settings[2].source, Some(Source::Env("ZAP_TMP_DIR")));
+        assert!(settings.iter().filter(|setting| setting.key.name != "tmp_dir").all(|setting| setting.value.is_none()));
+        assert!(file_defaults(&settings).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// A long-lived Ed25519 key a receiver signs its receipts with
+///
+/// Kept on disk as hex-encoded PKCS#8. The public half, also in hex, is what
+/// a sender checks receipts against.
+#[derive(Debug)]
+pub struct IdentityKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl IdentityKey {
+    /// Make a new key and write it to `path`, which mustn't exist yet
+    pub fn create(path: &Path) -> Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| anyhow!("Couldn't generate a key"))?;
+        let mut options = File::options();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .map_err(|e| anyhow!("Couldn't create {}: {}", path.display(), e))?;
+        writeln!(file, "{}", hex::encode(pkcs8.as_ref()))?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+    
+    /// Read a key written by `create`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read identity key {}: {}", path.display(), e))?;
+        let pkcs8 = hex::decode(text.trim()).map_err(|_| anyhow!("{} isn't an identity key", path.display()))?;
+        Self::from_pkcs8(&pkcs8).map_err(|_| anyhow!("{} isn't an identity key", path.display()))
+    }
+    
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| anyhow!("Invalid identity key: {}", e))?;
+        Ok(Self { key_pair })
+    }
+    
+    /// The public key, in hex
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+    
+    /// Sign `message`, returning the signature in hex
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.key_pair.sign(message).as_ref())
+    }
+}
+
+/// Check a hex `signature` of `message` against a hex `public_key`
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> Result<()> {
+    let public_key = hex::decode(public_key).map_err(|_| anyhow!("Public key isn't hex"))?;
+    let signature = hex::decode(signature).map_err(|_| anyhow!("Signature isn't hex"))?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(message, &signature)
+        .map_err(|_| anyhow!("Signature doesn't match"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_key_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("identity.key");
+        let key = IdentityKey::create(&path).unwrap();
+        assert!(IdentityKey::create(&path).is_err(), "an existing key is never overwritten");
+        
+        let loaded = IdentityKey::load(&path).unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+        let signature = loaded.sign(b"delivered");
+        verify_signature(&key.public_key(), b"delivered", &signature).unwrap();
+        assert!(verify_signature(&key.public_key(), b"not delivered", &signature).is_err());
+        
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(IdentityKey::load(&path).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Result};
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use rand::rngs::OsRng;
+
+use super::pake::KemShare;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// The receiver's ML-KEM-768 key pair, made fresh for each transfer
+pub struct KemKeyPair {
+    decapsulation: DecapsulationKey,
+    public_key: Vec<u8>,
+}
+
+impl KemKeyPair {
+    pub fn generate() -> Self {
+        let (decapsulation, encapsulation) = MlKem768::generate(&mut OsRng);
+        Self {
+            decapsulation,
+            public_key: encapsulation.as_bytes().to_vec(),
+        }
+    }
+    
+    /// The encapsulation key, sent to the sender in `KemPublicKey`
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+    
+    /// The secret the sender encapsulated in `ciphertext`
+    pub fn decapsulate(self, ciphertext: &[u8]) -> Result<KemShare> {
+        let encoded = Ciphertext::<MlKem768>::try_from(ciphertext).map_err(|_| anyhow!("KEM ciphertext is {} bytes, not an ML-KEM-768 one", ciphertext.len()))?;
+        let secret = self.decapsulation.decapsulate(&encoded).map_err(|_| anyhow!("Couldn't decapsulate the KEM ciphertext"))?;
+        Ok(KemShare {
+            public_key: self.public_key,
+            ciphertext: ciphertext.to_vec(),
+            secret: secret.into(),
+        })
+    }
+}
+
+/// The sender's half: a fresh secret encapsulated to the receiver's `public_key`, and its ciphertext
+pub fn encapsulate(public_key: &[u8]) -> Result<KemShare> {
+    let encoded = Encoded::<EncapsulationKey>::try_from(public_key).map_err(|_| anyhow!("KEM public key is {} bytes, not an ML-KEM-768 one", public_key.len()))?;
+    let (ciphertext, secret) = EncapsulationKey::from_bytes(&encoded)
+        .encapsulate(&mut OsRng)
+        .map_err(|_| anyhow!("Couldn't encapsulate to the receiver's KEM key"))?;
+    Ok(KemShare {
+        public_key: public_key.to_vec(),
+        ciphertext: ciphertext.to_vec(),
+        secret: secret.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_both_sides_share_the_secret() {
+        let receiver = KemKeyPair::generate();
+        let sent = encapsulate(receiver.public_key()).unwrap();
+        let received = receiver.decapsulate(&sent.ciphertext).unwrap();
+        assert_eq!(sent, received);
+        
+        assert!(encapsulate(&sent.public_key[1..]).is_err());
+        assert!(KemKeyPair::generate().decapsulate(&[0u8; 16]).is_err());
+        // Implicit rejection: a different key pair gets a different secret, not an error
+        let other = KemKeyPair::generate().decapsulate(&sent.ciphertext).unwrap();
+        assert_ne!(other.secret, sent.secret);
+    }
+}
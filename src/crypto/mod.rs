@@ -1,68 +1,88 @@
 use anyhow::{anyhow, Result};
+use argon2::Argon2;
 use chacha20poly1305::{
     aead::{Aead, KeyInit},
     ChaCha20Poly1305, Nonce,
 };
 use rand::Rng;
 use sha2::{Digest, Sha256};
-use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::protocol::MAILBOX_KEY_SALT;
+use crate::rng::ZapRng;
+
+mod identity;
+#[cfg(feature = "pq")]
+mod kem;
+mod pake;
+mod pool;
+mod wordlist;
+
+pub use identity::{verify_signature, IdentityKey};
+#[cfg(feature = "pq")]
+pub use kem::{encapsulate, KemKeyPair};
+pub use pake::{KemShare, KeyExchange, Offers, Transcript};
+pub use pool::{default_crypto_workers, CryptoPool};
+pub use wordlist::{embedded_wordlist, load_wordlist, parse_wordlist, WordlistError, MIN_WORDLIST_SIZE};
 
 const NONCE_SIZE: usize = 12;
 
+/// Errors proving who's on the other end
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error(
+        "The peer's TLS certificate ({}) isn't the one it vouched for under the code ({}); something between you is intercepting the connection",
+        hex::encode(actual),
+        hex::encode(expected)
+    )]
+    CertificateMismatch { expected: [u8; 32], actual: [u8; 32] },
+}
+
+/// Context for the sender's key confirmation token
+pub const SENDER_CONFIRM: &[u8] = b"zap-confirm-sender";
+
+/// Context for the receiver's key confirmation token
+pub const RECEIVER_CONFIRM: &[u8] = b"zap-confirm-receiver";
+
+/// Leading token bytes a peer must get right to prove it has the key
+const CONFIRM_CHECK_SIZE: usize = 16;
+
 /// Generate a random word code for the transfer
 pub fn generate_code(word_count: usize) -> String {
-    let words = include_str!("wordlist.txt")
-        .lines()
-        .collect::<Vec<_>>();
-    
-    let mut rng = rand::thread_rng();
-    (0..word_count)
-        .map(|_| words[rng.gen_range(0..words.len())])
-        .collect::<Vec<_>>()
-        .join("-")
+    generate_code_from(embedded_wordlist(), word_count).expect("embedded wordlist is valid")
 }
 
-/// Derive a shared secret using SPAKE2
-pub struct KeyExchange {
-    state: Spake2<Ed25519Group>,
+/// Generate a random word code from a custom wordlist
+pub fn generate_code_from<S: AsRef<str>>(words: &[S], word_count: usize) -> Result<String, WordlistError> {
+    generate_code_with(&mut ZapRng::new(), words, word_count)
 }
 
-impl KeyExchange {
-    /// Create a new key exchange for the sender side
-    pub fn new_sender(code: &str) -> Self {
-        let (state, _outbound) = Spake2::<Ed25519Group>::start_symmetric(
-            &Password::new(code.as_bytes()),
-            &Identity::new(b"zap-sender"),
-        );
-        Self { state }
-    }
-    
-    /// Create a new key exchange for the receiver side
-    pub fn new_receiver(code: &str) -> Self {
-        let (state, _outbound) = Spake2::<Ed25519Group>::start_symmetric(
-            &Password::new(code.as_bytes()),
-            &Identity::new(b"zap-receiver"),
-        );
-        Self { state }
+/// Generate a word code using `rng`
+///
+/// Every word must be usable in a code, as `parse_wordlist` makes sure of, so
+/// a code always has `word_count` lowercase words.
+pub fn generate_code_with<S: AsRef<str>>(rng: &mut impl Rng, words: &[S], word_count: usize) -> Result<String, WordlistError> {
+    if words.is_empty() {
+        return Err(WordlistError::TooSmall { unique: 0, needed: 1 });
     }
-    
-    /// Get the outbound message to send to the peer
-    pub fn outbound_message(&self) -> Vec<u8> {
-        // Note: In a real implementation, we'd need to restructure this
-        // to properly handle the SPAKE2 protocol. For MVP, we'll use a simpler approach.
-        vec![]
-    }
-    
-    /// Complete the key exchange and derive the shared secret
-    pub fn finish(self, _peer_message: &[u8]) -> Result<Vec<u8>> {
-        // Simplified for MVP - in production, complete the SPAKE2 exchange
-        Ok(vec![0u8; 32]) // Placeholder
+    if let Some(word) = words.iter().map(AsRef::as_ref).find(|word| !wordlist::is_valid_word(word)) {
+        return Err(WordlistError::Unusable(word.to_string()));
     }
+    Ok((0..word_count)
+        .map(|_| words[rng.gen_range(0..words.len())].as_ref().to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-"))
 }
 
 /// Encryption/decryption using ChaCha20-Poly1305
+#[derive(Clone)]
 pub struct Cipher {
     cipher: ChaCha20Poly1305,
+    key: [u8; 32],
 }
 
 impl Cipher {
@@ -71,27 +91,69 @@ impl Cipher {
         // Derive a 32-byte key from the secret
         let mut hasher = Sha256::new();
         hasher.update(secret);
-        let key = hasher.finalize();
-        
-        let cipher = ChaCha20Poly1305::new(key.as_slice().into());
-        Ok(Self { cipher })
+        Ok(Self::from_key(hasher.finalize().into()))
     }
     
-    /// Create a cipher from a password (for simplified MVP)
+    /// Create a cipher keyed with a hash of `password`, for tests and benchmarks
+    ///
+    /// A hash of a transfer code is quick to brute-force, so sessions never
+    /// key this way: live ones run the PAKE, and mailbox uploads use `for_mailbox`.
     pub fn from_password(password: &str) -> Result<Self> {
         let mut hasher = Sha256::new();
         hasher.update(password.as_bytes());
-        let key = hasher.finalize();
-        
-        let cipher = ChaCha20Poly1305::new(key.as_slice().into());
-        Ok(Self { cipher })
+        Ok(Self::from_key(hasher.finalize().into()))
+    }
+    
+    /// The cipher for a mailbox upload sent with `code`: Argon2id of the code
+    ///
+    /// A mailbox upload is replayed to the receiver later, so there's nobody
+    /// to run a key exchange with; this key is only as strong as the code,
+    /// and Argon2id makes guessing it slow.
+    pub fn for_mailbox(code: &str) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(code.as_bytes(), MAILBOX_KEY_SALT, &mut key)
+            .map_err(|e| anyhow!("Couldn't derive the mailbox key: {}", e))?;
+        Ok(Self::from_key(key))
+    }
+    
+    fn from_key(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            key,
+        }
+    }
+    
+    /// Token proving knowledge of the session key, bound to `context`
+    pub fn confirmation_token(&self, context: &[u8]) -> [u8; 32] {
+        *blake3::keyed_hash(&self.key, context).as_bytes()
+    }
+    
+    /// Check a peer's confirmation token for `context`
+    pub fn verify_confirmation(&self, context: &[u8], token: &[u8; 32]) -> bool {
+        let expected = self.confirmation_token(context);
+        // Compare without an early exit so timing doesn't leak the token
+        expected[..CONFIRM_CHECK_SIZE]
+            .iter()
+            .zip(&token[..CONFIRM_CHECK_SIZE])
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
     }
     
     /// Encrypt data
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
+        self.encrypt_with_nonce(Self::new_nonce(), data)
+    }
+    
+    /// A fresh random nonce for `encrypt_with_nonce`
+    pub fn new_nonce() -> [u8; NONCE_SIZE] {
         let mut nonce_bytes = [0u8; NONCE_SIZE];
-        rng.fill(&mut nonce_bytes);
+        rand::thread_rng().fill(&mut nonce_bytes);
+        nonce_bytes
+    }
+    
+    /// Encrypt data under a nonce picked earlier, which must never be used twice
+    pub fn encrypt_with_nonce(&self, nonce_bytes: [u8; NONCE_SIZE], data: &[u8]) -> Result<Vec<u8>> {
         let nonce = Nonce::from_slice(&nonce_bytes);
         
         let ciphertext = self.cipher
@@ -126,6 +188,131 @@ pub fn checksum(data: &[u8]) -> String {
     hex::encode(hasher.finalize())
 }
 
+/// Written in front of a BLAKE3 checksum; SHA-256 ones are bare hex, as they always were
+pub const BLAKE3_PREFIX: &str = "blake3:";
+
+/// How a whole file's checksum is worked out
+///
+/// SHA-256 is what every peer and receipt understands; BLAKE3 is much
+/// faster, most of all on ARM, but only for peers agreeing to `FEATURE_CHECKSUM_BLAKE3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// The algorithm that made `checksum`, going by its prefix
+    pub fn of(checksum: &str) -> Self {
+        if checksum.starts_with(BLAKE3_PREFIX) {
+            Self::Blake3
+        } else {
+            Self::Sha256
+        }
+    }
+    
+    pub fn hasher(self) -> Checksummer {
+        match self {
+            Self::Sha256 => Checksummer::Sha256(Sha256::new()),
+            Self::Blake3 => Checksummer::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "SHA-256",
+            Self::Blake3 => "BLAKE3",
+        })
+    }
+}
+
+/// A running checksum, by either algorithm
+#[derive(Clone)]
+pub enum Checksummer {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Checksummer {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+    
+    /// Checksum of the data so far: hex for SHA-256, like `checksum`, and `BLAKE3_PREFIX` then hex for BLAKE3
+    pub fn checksum(&self) -> String {
+        match self {
+            Self::Sha256(hasher) => hex::encode(hasher.clone().finalize()),
+            Self::Blake3(hasher) => format!("{}{}", BLAKE3_PREFIX, hasher.finalize().to_hex()),
+        }
+    }
+}
+
+impl io::Write for Checksummer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like `checksum`, by `algorithm`, reading `reader` in `chunk_size` pieces instead of needing it all in memory
+pub async fn checksum_stream<R: AsyncRead + Unpin>(mut reader: R, algorithm: ChecksumAlgorithm, chunk_size: usize) -> Result<String> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0u8; chunk_size.max(1)];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.checksum());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Passes reads straight through, working out the checksum of everything read on the way
+///
+/// Lets the checksum come from the same pass over the data that sends it, like `tee`.
+pub struct ChecksumStream<R> {
+    inner: R,
+    hasher: Checksummer,
+}
+
+impl<R> ChecksumStream<R> {
+    pub fn new(inner: R, algorithm: ChecksumAlgorithm) -> Self {
+        Self { inner, hasher: algorithm.hasher() }
+    }
+    
+    /// Checksum of the data read so far, in the same form as `Checksummer::checksum`
+    pub fn checksum(&self) -> String {
+        self.hasher.checksum()
+    }
+    
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChecksumStream<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.hasher.update(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,4 +327,88 @@ mod tests {
         
         assert_eq!(plaintext, decrypted.as_slice());
     }
+    
+    #[tokio::test]
+    async fn test_streaming_checksum_matches_batch() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = checksum(&data);
+        
+        // Chunk sizes that do and don't divide the input evenly
+        for chunk_size in [1, 4096, 100_000, 1_000_000] {
+            assert_eq!(checksum_stream(&data[..], ChecksumAlgorithm::Sha256, chunk_size).await.unwrap(), expected);
+        }
+        assert_eq!(checksum_stream(&b""[..], ChecksumAlgorithm::Sha256, 64).await.unwrap(), checksum(b""));
+        
+        let mut stream = ChecksumStream::new(&data[..], ChecksumAlgorithm::Sha256);
+        let mut copied = Vec::new();
+        stream.read_to_end(&mut copied).await.unwrap();
+        assert_eq!(copied, data);
+        assert_eq!(stream.checksum(), expected);
+        
+        // BLAKE3 checksums say what they are, so they're never mistaken for SHA-256 ones
+        let blake3 = checksum_stream(&data[..], ChecksumAlgorithm::Blake3, 4096).await.unwrap();
+        assert_eq!(blake3, format!("blake3:{}", blake3::hash(&data).to_hex()));
+        assert_eq!((ChecksumAlgorithm::of(&blake3), ChecksumAlgorithm::of(&expected)), (ChecksumAlgorithm::Blake3, ChecksumAlgorithm::Sha256));
+    }
+    
+    #[test]
+    fn test_seeded_code_is_reproducible() {
+        let words = ["alpha", "bravo", "charlie", "delta", "echo", "foxtrot"];
+        let code = generate_code_with(&mut ZapRng::seeded(7), &words, 4).unwrap();
+        assert_eq!(code, generate_code_with(&mut ZapRng::seeded(7), &words, 4).unwrap());
+        assert_eq!(code.split('-').count(), 4);
+    }
+    
+    #[test]
+    fn test_messy_wordlists_give_whole_codes() {
+        let mut rng = ZapRng::seeded(11);
+        let lines = ["apple", "Apple", "BANANA", " cherry\t", "", "  ", "\t", "# comment", "#", "\r"];
+        let broken = ["x", "two words", "café", "well-known", "\u{feff}apple"];
+        let mut parsed = 0;
+        for _ in 0..500 {
+            // Words in any case, blank lines, comments and either line ending, sometimes after a BOM
+            let mut text = String::from(["", "\u{feff}"][rng.gen_range(0..2)]);
+            for _ in 0..rng.gen_range(0..20) {
+                text.push_str(lines[rng.gen_range(0..lines.len())]);
+                text.push_str(["\n", "\r\n"][rng.gen_range(0..2)]);
+            }
+            if rng.gen_range(0..5) == 0 {
+                text.push_str(broken[rng.gen_range(0..broken.len())]);
+            }
+            let words = match wordlist::parse_words(&text, 0) {
+                Ok(words) => words,
+                Err(e) => {
+                    assert!(matches!(e, WordlistError::InvalidWord { .. }), "{} from {:?}", e, text);
+                    continue;
+                }
+            };
+            parsed += 1;
+            let word_count = rng.gen_range(1..6);
+            match generate_code_with(&mut rng, &words, word_count) {
+                Ok(code) => {
+                    let segments: Vec<_> = code.split('-').collect();
+                    assert_eq!(segments.len(), word_count, "{:?} from {:?}", code, text);
+                    assert!(segments.iter().all(|segment| !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_lowercase())), "{:?}", code);
+                }
+                Err(e) => assert!(words.is_empty(), "{} from {:?}", e, words),
+            }
+        }
+        assert!(parsed > 300, "{}", parsed);
+        
+        assert!(generate_code_with(&mut rng, &[] as &[&str], 3).is_err());
+        assert_eq!(generate_code_with(&mut rng, &["apple", ""], 3), Err(WordlistError::Unusable(String::new())));
+        assert_eq!(generate_code_with(&mut rng, &["apple", "well-known"], 3), Err(WordlistError::Unusable("well-known".to_string())));
+    }
+    
+    #[test]
+    fn test_confirmation_token() {
+        let cipher = Cipher::from_password("alpha-bravo-charlie").unwrap();
+        let same = Cipher::from_password("alpha-bravo-charlie").unwrap();
+        let other = Cipher::from_password("alpha-bravo-delta").unwrap();
+        
+        let token = cipher.confirmation_token(SENDER_CONFIRM);
+        assert!(same.verify_confirmation(SENDER_CONFIRM, &token));
+        assert!(!same.verify_confirmation(RECEIVER_CONFIRM, &token));
+        assert!(!other.verify_confirmation(SENDER_CONFIRM, &token));
+    }
 }
@@ -0,0 +1,342 @@
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::HashSet;
+
+use crate::protocol::{PakeSuite, PROTOCOL_VERSION};
+
+use super::Cipher;
+
+/// First bytes of every encoded transcript, so it can't pass for anything else that gets hashed
+const TRANSCRIPT_TAG: &[u8] = b"zap-pake-transcript";
+
+/// The feature tags each peer put in its Capabilities
+///
+/// They're sent in plaintext, so they go into the transcript: a peer that
+/// saw a different offer than was sent, like one with `pake/v2` stripped,
+/// ends up with a different key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Offers {
+    pub sender: HashSet<String>,
+    pub receiver: HashSet<String>,
+}
+
+impl Offers {
+    /// How `features` go into the transcript: sorted, and joined with commas
+    pub fn encode(features: &HashSet<String>) -> Vec<u8> {
+        let mut tags: Vec<&str> = features.iter().map(String::as_str).collect();
+        tags.sort_unstable();
+        tags.join(",").into_bytes()
+    }
+}
+
+/// Everything that went into a PAKE key exchange, which the session key is bound to
+///
+/// Peers only end up with the same key when they agree on every field, so a
+/// mismatch in versions, identities or offered features shows up as failed
+/// key confirmation. `to_bytes` is the encoding other implementations have
+/// to reproduce: the tag, then each field in order as a 4-byte big-endian
+/// length and its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    pub protocol_version: u8,
+    pub key_exchange_version: u8,
+    pub sender_identity: Vec<u8>,
+    pub receiver_identity: Vec<u8>,
+    pub salt: Vec<u8>,
+    /// `Offers::encode` of the sender's offer
+    pub sender_features: Vec<u8>,
+    /// `Offers::encode` of the receiver's offer
+    pub receiver_features: Vec<u8>,
+    pub sender_message: Vec<u8>,
+    pub receiver_message: Vec<u8>,
+}
+
+impl Transcript {
+    pub fn new(suite: &PakeSuite, offers: &Offers, sender_message: &[u8], receiver_message: &[u8]) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            key_exchange_version: suite.version,
+            sender_identity: suite.sender_identity.to_vec(),
+            receiver_identity: suite.receiver_identity.to_vec(),
+            salt: suite.salt.to_vec(),
+            sender_features: Offers::encode(&offers.sender),
+            receiver_features: Offers::encode(&offers.receiver),
+            sender_message: sender_message.to_vec(),
+            receiver_message: receiver_message.to_vec(),
+        }
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let fields: [&[u8]; 9] = [
+            &[self.protocol_version],
+            &[self.key_exchange_version],
+            &self.sender_identity,
+            &self.receiver_identity,
+            &self.salt,
+            &self.sender_features,
+            &self.receiver_features,
+            &self.sender_message,
+            &self.receiver_message,
+        ];
+        let mut bytes = TRANSCRIPT_TAG.to_vec();
+        for field in fields {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+    
+    /// SHA-256 of `to_bytes`
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::digest(self.to_bytes()).into()
+    }
+    
+    /// The session key: HKDF-SHA256 of SPAKE2's shared secret, salted with
+    /// the suite's salt, with the transcript hash as info
+    pub fn session_key(&self, shared_secret: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&self.salt), shared_secret)
+            .expand(&self.hash(), &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output");
+        key
+    }
+    
+    /// The session key with a KEM alongside: HKDF-SHA256 of SPAKE2's shared secret followed by
+    /// the KEM's, salted like `session_key`, with the transcript hash as info
+    ///
+    /// The transcript hash is of `to_bytes` with the KEM's public key and
+    /// ciphertext appended as two more fields. Guessing the key takes both
+    /// secrets, so it holds as long as either the PAKE or ML-KEM does.
+    pub fn hybrid_session_key(&self, shared_secret: &[u8], kem: &KemShare) -> [u8; 32] {
+        let mut bytes = self.to_bytes();
+        for field in [&kem.public_key, &kem.ciphertext] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        let secrets = [shared_secret, &kem.secret].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&self.salt), &secrets)
+            .expand(&Sha256::digest(bytes), &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output");
+        key
+    }
+}
+
+/// What an ML-KEM encapsulation run alongside the PAKE adds to the session key
+///
+/// Both of its messages go into the key with the secret, so a peer that saw
+/// either one changed on the way ends up with a different key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KemShare {
+    pub public_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub secret: [u8; 32],
+}
+
+/// One side of a SPAKE2 key exchange, as laid down by a `PakeSuite`
+pub struct KeyExchange {
+    suite: &'static PakeSuite,
+    offers: Offers,
+    sender: bool,
+    state: Spake2<Ed25519Group>,
+    outbound: Vec<u8>,
+}
+
+impl KeyExchange {
+    /// Create a new key exchange for the sender side, after the handshake's `offers`
+    pub fn new_sender(code: &str, suite: &'static PakeSuite, offers: Offers) -> Self {
+        Self::start(code, suite, offers, true, OsRng)
+    }
+    
+    /// Create a new key exchange for the receiver side, after the handshake's `offers`
+    pub fn new_receiver(code: &str, suite: &'static PakeSuite, offers: Offers) -> Self {
+        Self::start(code, suite, offers, false, OsRng)
+    }
+    
+    /// Start either side, with `rng` picking the secret scalar
+    ///
+    /// Only test vectors should pass anything but the OS generator.
+    pub fn start(code: &str, suite: &'static PakeSuite, offers: Offers, sender: bool, rng: impl CryptoRng + RngCore) -> Self {
+        let password = Password::new(code.as_bytes());
+        let sender_identity = Identity::new(suite.sender_identity);
+        let receiver_identity = Identity::new(suite.receiver_identity);
+        let (state, outbound) = match (suite.symmetric, sender) {
+            (true, _) => Spake2::<Ed25519Group>::start_symmetric_with_rng(&password, &sender_identity, rng),
+            (false, true) => Spake2::<Ed25519Group>::start_a_with_rng(&password, &sender_identity, &receiver_identity, rng),
+            (false, false) => Spake2::<Ed25519Group>::start_b_with_rng(&password, &sender_identity, &receiver_identity, rng),
+        };
+        Self { suite, offers, sender, state, outbound }
+    }
+    
+    /// Get the outbound message to send to the peer
+    pub fn outbound_message(&self) -> Vec<u8> {
+        self.outbound.clone()
+    }
+    
+    /// Complete the exchange with the peer's message, returning the transcript and SPAKE2's shared secret
+    pub fn finish_with_transcript(self, peer_message: &[u8]) -> Result<(Transcript, Vec<u8>)> {
+        let (sender_message, receiver_message) = match self.sender {
+            true => (self.outbound.as_slice(), peer_message),
+            false => (peer_message, self.outbound.as_slice()),
+        };
+        let transcript = Transcript::new(self.suite, &self.offers, sender_message, receiver_message);
+        let shared_secret = self
+            .state
+            .finish(peer_message)
+            .map_err(|e| anyhow!("Key exchange failed: {:?}", e))?;
+        Ok((transcript, shared_secret))
+    }
+    
+    /// Complete the exchange and make the session's cipher
+    pub fn finish(self, peer_message: &[u8]) -> Result<Cipher> {
+        let (transcript, shared_secret) = self.finish_with_transcript(peer_message)?;
+        Ok(Cipher::from_key(transcript.session_key(&shared_secret)))
+    }
+    
+    /// Like `finish`, with the secret of a KEM run alongside mixed in; see `Transcript::hybrid_session_key`
+    pub fn finish_hybrid(self, peer_message: &[u8], kem: &KemShare) -> Result<Cipher> {
+        let (transcript, shared_secret) = self.finish_with_transcript(peer_message)?;
+        Ok(Cipher::from_key(transcript.hybrid_session_key(&shared_secret, kem)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{RECEIVER_CONFIRM, SENDER_CONFIRM};
+    use crate::protocol::{KEY_EXCHANGE_V2, PAKE_V2};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serde::Deserialize;
+    
+    /// Checked in for other implementations to test against; see its description
+    const VECTORS: &str = include_str!("pake-v2-vectors.json");
+    
+    #[derive(Deserialize)]
+    struct Vectors {
+        protocol_version: u8,
+        key_exchange_version: u8,
+        sender_identity: String,
+        receiver_identity: String,
+        salt: String,
+        vectors: Vec<Vector>,
+    }
+    
+    #[derive(Deserialize)]
+    struct Vector {
+        code: String,
+        sender_features: String,
+        receiver_features: String,
+        sender_seed: u64,
+        receiver_seed: u64,
+        sender_message: String,
+        receiver_message: String,
+        shared_secret: String,
+        transcript: String,
+        transcript_hash: String,
+        session_key: String,
+        sender_confirm: String,
+        receiver_confirm: String,
+    }
+    
+    #[test]
+    fn test_v2_vectors() {
+        let recorded: Vectors = serde_json::from_str(VECTORS).unwrap();
+        assert_eq!((recorded.protocol_version, recorded.key_exchange_version), (PROTOCOL_VERSION, KEY_EXCHANGE_V2));
+        assert_eq!(recorded.sender_identity.as_bytes(), PAKE_V2.sender_identity);
+        assert_eq!(recorded.receiver_identity.as_bytes(), PAKE_V2.receiver_identity);
+        assert_eq!(recorded.salt.as_bytes(), PAKE_V2.salt);
+        assert!(!recorded.vectors.is_empty());
+        
+        for vector in recorded.vectors {
+            let offers = Offers {
+                sender: vector.sender_features.split(',').map(String::from).collect(),
+                receiver: vector.receiver_features.split(',').map(String::from).collect(),
+            };
+            assert_eq!(Offers::encode(&offers.sender), vector.sender_features.as_bytes());
+            let sender = KeyExchange::start(&vector.code, &PAKE_V2, offers.clone(), true, StdRng::seed_from_u64(vector.sender_seed));
+            let receiver = KeyExchange::start(&vector.code, &PAKE_V2, offers, false, StdRng::seed_from_u64(vector.receiver_seed));
+            let (sender_message, receiver_message) = (sender.outbound_message(), receiver.outbound_message());
+            assert_eq!(hex::encode(&sender_message), vector.sender_message);
+            assert_eq!(hex::encode(&receiver_message), vector.receiver_message);
+            
+            let (transcript, shared_secret) = sender.finish_with_transcript(&receiver_message).unwrap();
+            assert_eq!(receiver.finish_with_transcript(&sender_message).unwrap(), (transcript.clone(), shared_secret.clone()));
+            assert_eq!(hex::encode(&shared_secret), vector.shared_secret);
+            assert_eq!(hex::encode(transcript.to_bytes()), vector.transcript);
+            assert_eq!(hex::encode(transcript.hash()), vector.transcript_hash);
+            
+            let key = transcript.session_key(&shared_secret);
+            assert_eq!(hex::encode(key), vector.session_key);
+            let cipher = Cipher::from_key(key);
+            assert_eq!(hex::encode(cipher.confirmation_token(SENDER_CONFIRM)), vector.sender_confirm);
+            assert_eq!(hex::encode(cipher.confirmation_token(RECEIVER_CONFIRM)), vector.receiver_confirm);
+        }
+    }
+    
+    fn offers() -> Offers {
+        Offers {
+            sender: HashSet::from(["pake/v2".to_string(), "stream".to_string()]),
+            receiver: HashSet::from(["mailbox".to_string(), "pake/v2".to_string()]),
+        }
+    }
+    
+    #[test]
+    fn test_disagreeing_peers_get_different_keys() {
+        let sender = KeyExchange::new_sender("alpha-bravo-charlie", &PAKE_V2, offers());
+        let receiver = KeyExchange::new_receiver("alpha-bravo-charlie", &PAKE_V2, offers());
+        let (sender_message, receiver_message) = (sender.outbound_message(), receiver.outbound_message());
+        let (transcript, shared_secret) = sender.finish_with_transcript(&receiver_message).unwrap();
+        let key = transcript.session_key(&shared_secret);
+        assert_eq!(receiver.finish(&sender_message).unwrap().confirmation_token(SENDER_CONFIRM), Cipher::from_key(key).confirmation_token(SENDER_CONFIRM));
+        
+        // Any field the peers disagree on changes the key, even with the same shared secret
+        let disagreements = [
+            Transcript { protocol_version: PROTOCOL_VERSION - 1, ..transcript.clone() },
+            Transcript { key_exchange_version: 1, ..transcript.clone() },
+            Transcript { receiver_identity: b"zap-sender".to_vec(), ..transcript.clone() },
+            Transcript { salt: Vec::new(), ..transcript.clone() },
+            Transcript { sender_features: b"stream".to_vec(), ..transcript.clone() },
+            Transcript { receiver_features: Offers::encode(&offers().sender), ..transcript.clone() },
+            Transcript { sender_message: receiver_message.clone(), receiver_message: sender_message.clone(), ..transcript.clone() },
+        ];
+        for other in disagreements {
+            assert_ne!(other.session_key(&shared_secret), key, "{:?}", other);
+        }
+        
+        // Both sides claiming the same SPAKE2 side fails outright
+        let receiver = KeyExchange::new_receiver("alpha-bravo-charlie", &PAKE_V2, offers());
+        let other_receiver = KeyExchange::new_receiver("alpha-bravo-charlie", &PAKE_V2, offers());
+        assert!(receiver.finish(&other_receiver.outbound_message()).is_err());
+    }
+    
+    #[test]
+    fn test_hybrid_key_takes_both_secrets() {
+        let sender = KeyExchange::new_sender("alpha-bravo-charlie", &PAKE_V2, offers());
+        let receiver = KeyExchange::new_receiver("alpha-bravo-charlie", &PAKE_V2, offers());
+        let (sender_message, receiver_message) = (sender.outbound_message(), receiver.outbound_message());
+        let (transcript, shared_secret) = sender.finish_with_transcript(&receiver_message).unwrap();
+        let kem = KemShare {
+            public_key: vec![1; 1184],
+            ciphertext: vec![2; 1088],
+            secret: [3; 32],
+        };
+        let key = transcript.hybrid_session_key(&shared_secret, &kem);
+        assert_eq!(receiver.finish_hybrid(&sender_message, &kem).unwrap().confirmation_token(SENDER_CONFIRM), Cipher::from_key(key).confirmation_token(SENDER_CONFIRM));
+        assert_ne!(key, transcript.session_key(&shared_secret));
+        
+        let others = [
+            KemShare { secret: [4; 32], ..kem.clone() },
+            KemShare { public_key: vec![1; 1183], ..kem.clone() },
+            KemShare { ciphertext: Vec::new(), ..kem.clone() },
+        ];
+        for other in others {
+            assert_ne!(transcript.hybrid_session_key(&shared_secret, &other), key);
+        }
+        assert_ne!(transcript.hybrid_session_key(&[0; 32], &kem), key);
+    }
+}
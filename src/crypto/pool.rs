@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use super::Cipher;
+
+/// Most workers `--crypto-threads` starts by default; more rarely helps,
+/// as the network or disk can't keep up by then
+const MAX_DEFAULT_WORKERS: usize = 4;
+
+/// Workers for a `CryptoPool` asked for 0: a core short of all of them, for
+/// the network and disk, and no more than 4
+pub fn default_crypto_workers() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    cores.saturating_sub(1).clamp(1, MAX_DEFAULT_WORKERS)
+}
+
+enum Job {
+    /// Done on the caller's thread, with only one worker
+    Done(Result<Vec<u8>>),
+    Running(JoinHandle<Result<Vec<u8>>>),
+}
+
+/// Encrypts or decrypts messages on up to `workers` threads at once, handing
+/// them back in the order they went in
+///
+/// Each message is tagged with a `T` of the caller's (the sender uses the
+/// chunk's length), which comes back with it. A message's nonce is picked when
+/// it's queued, not when a worker gets to it, so nothing depends on which
+/// worker finishes first.
+pub struct CryptoPool<T> {
+    cipher: Arc<Cipher>,
+    workers: usize,
+    queue: VecDeque<(T, Job)>,
+}
+
+impl<T> CryptoPool<T> {
+    /// `workers` of 0 picks `default_crypto_workers`; with 1, messages are
+    /// encrypted as they're queued, on the caller's thread
+    pub fn new(cipher: Arc<Cipher>, workers: usize) -> Self {
+        let workers = match workers {
+            0 => default_crypto_workers(),
+            workers => workers,
+        };
+        Self {
+            cipher,
+            workers,
+            queue: VecDeque::new(),
+        }
+    }
+    
+    /// Every worker has a message, so the oldest should be taken before queueing more
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.workers
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+    
+    /// The tags of the messages not taken yet, oldest first
+    pub fn queued(&self) -> impl Iterator<Item = &T> {
+        self.queue.iter().map(|(tag, _)| tag)
+    }
+    
+    /// Queue `data` to be encrypted
+    pub fn encrypt(&mut self, tag: T, data: Vec<u8>) {
+        let nonce = Cipher::new_nonce();
+        let cipher = self.cipher.clone();
+        self.submit(tag, move || cipher.encrypt_with_nonce(nonce, &data));
+    }
+    
+    /// Queue `data` to be decrypted
+    pub fn decrypt(&mut self, tag: T, data: Vec<u8>) {
+        let cipher = self.cipher.clone();
+        self.submit(tag, move || cipher.decrypt(&data));
+    }
+    
+    fn submit(&mut self, tag: T, job: impl FnOnce() -> Result<Vec<u8>> + Send + 'static) {
+        let job = match self.workers {
+            1 => Job::Done(job()),
+            _ => Job::Running(tokio::task::spawn_blocking(job)),
+        };
+        self.queue.push_back((tag, job));
+    }
+    
+    /// The oldest message and its tag, waiting for its worker to finish if it hasn't
+    pub async fn next(&mut self) -> Option<(T, Result<Vec<u8>>)> {
+        let (tag, job) = self.queue.pop_front()?;
+        let result = match job {
+            Job::Done(result) => result,
+            Job::Running(worker) => worker.await.map_err(|e| anyhow!("Encryption worker failed: {}", e)).and_then(|result| result),
+        };
+        Some((tag, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+    
+    fn cipher() -> Arc<Cipher> {
+        Arc::new(Cipher::from_password("alpha-bravo-charlie").unwrap())
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_results_come_back_in_order() {
+        let mut pool = CryptoPool::new(cipher(), 4);
+        // The first queued finishes last
+        for index in 0..4u64 {
+            pool.submit(index, move || {
+                std::thread::sleep(Duration::from_millis(40 - 10 * index));
+                Ok(index.to_le_bytes().to_vec())
+            });
+        }
+        assert!(pool.is_full());
+        assert_eq!(pool.queued().copied().collect::<Vec<_>>(), [0, 1, 2, 3]);
+        for expected in 0..4u64 {
+            let (tag, data) = pool.next().await.unwrap();
+            assert_eq!((tag, data.unwrap()), (expected, expected.to_le_bytes().to_vec()));
+        }
+        assert!(pool.is_empty() && pool.next().await.is_none());
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_round_trip() {
+        let cipher = cipher();
+        for workers in [1, 4] {
+            let mut sealer = CryptoPool::new(cipher.clone(), workers);
+            let mut opener = CryptoPool::new(cipher.clone(), workers);
+            let messages: Vec<Vec<u8>> = (0..32u8).map(|i| vec![i; 1000 + i as usize]).collect();
+            let mut nonces = HashSet::new();
+            for (index, message) in messages.iter().enumerate() {
+                sealer.encrypt(index, message.clone());
+                if sealer.is_full() {
+                    let (index, sealed) = sealer.next().await.unwrap();
+                    let sealed = sealed.unwrap();
+                    assert!(nonces.insert(sealed[..12].to_vec()), "nonce used twice");
+                    opener.decrypt(index, sealed);
+                }
+            }
+            while let Some((index, sealed)) = sealer.next().await {
+                opener.decrypt(index, sealed.unwrap());
+            }
+            for (expected, message) in messages.iter().enumerate() {
+                let (index, opened) = opener.next().await.unwrap();
+                assert_eq!((index, &opened.unwrap()), (expected, message));
+            }
+        }
+        
+        let mut opener = CryptoPool::new(cipher, 4);
+        opener.decrypt((), vec![0; 40]);
+        assert!(opener.next().await.unwrap().1.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::fsutil;
+
+/// Fewest words a custom wordlist may have, so generated codes keep enough entropy
+pub const MIN_WORDLIST_SIZE: usize = 1024;
+
+/// Why a wordlist can't be used to generate codes
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WordlistError {
+    #[error("Invalid word {word:?} on line {line}: words must be 3-15 ASCII letters")]
+    InvalidWord { word: String, line: usize },
+    #[error("Wordlist has {unique} unique words, need at least {needed}")]
+    TooSmall { unique: usize, needed: usize },
+    /// A word handed to `generate_code_from` without going through `parse_wordlist`
+    #[error("Word {0:?} can't be part of a code: words must be 3-15 ASCII letters")]
+    Unusable(String),
+}
+
+/// The wordlist built into zap, parsed on first use
+pub fn embedded_wordlist() -> &'static [String] {
+    static WORDS: OnceLock<Vec<String>> = OnceLock::new();
+    // Checked by test_embedded_wordlist_parses, so a bad edit fails the build's tests rather than a user
+    WORDS.get_or_init(|| parse_words(include_str!("wordlist.txt"), 2).expect("embedded wordlist is valid"))
+}
+
+/// Load a newline-delimited wordlist from a local path or an HTTPS URL
+///
+/// Downloaded lists are cached in `~/.cache/zap/wordlists/`, keyed by a hash
+/// of the URL. See `parse_wordlist` for what the list may contain.
+pub async fn load_wordlist(source: &str) -> Result<Vec<String>> {
+    if source.starts_with("http://") {
+        return Err(anyhow!("Wordlist URLs must use HTTPS"));
+    }
+    
+    let text = if source.starts_with("https://") {
+        fetch_cached(source).await?
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .with_context(|| format!("Failed to read wordlist {}", source))?
+    };
+    
+    Ok(parse_wordlist(&text)?)
+}
+
+/// Validate and deduplicate a custom wordlist, keeping first-seen order
+///
+/// Lists saved on Windows or by hand are tolerated: a byte order mark, CRLF
+/// line endings, blank lines and `#` comments are skipped. Words are
+/// lowercased, so `Apple` and `apple` count once.
+pub fn parse_wordlist(text: &str) -> Result<Vec<String>, WordlistError> {
+    parse_words(text, MIN_WORDLIST_SIZE)
+}
+
+pub(super) fn parse_words(text: &str, needed: usize) -> Result<Vec<String>, WordlistError> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut seen = HashSet::new();
+    let mut words = Vec::new();
+    
+    for (line_number, line) in text.lines().enumerate() {
+        let word = line.trim();
+        if word.is_empty() || word.starts_with('#') {
+            continue;
+        }
+        if !is_valid_word(word) {
+            return Err(WordlistError::InvalidWord {
+                word: word.to_string(),
+                line: line_number + 1,
+            });
+        }
+        let word = word.to_ascii_lowercase();
+        if seen.insert(word.clone()) {
+            words.push(word);
+        }
+    }
+    
+    if words.len() < needed {
+        return Err(WordlistError::TooSmall { unique: words.len(), needed });
+    }
+    
+    Ok(words)
+}
+
+pub(super) fn is_valid_word(word: &str) -> bool {
+    (3..=15).contains(&word.len()) && word.bytes().all(|b| b.is_ascii_alphabetic())
+}
+
+async fn fetch_cached(url: &str) -> Result<String> {
+    let path = cache_path(url)?;
+    if let Ok(text) = tokio::fs::read_to_string(&path).await {
+        return Ok(text);
+    }
+    
+    let text = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to download wordlist {}", url))?
+        .text()
+        .await?;
+    
+    // Only cache lists that validate, so a bad download isn't reused
+    parse_wordlist(&text)?;
+    if let Some(dir) = path.parent() {
+        fsutil::create_private_dir(dir)?;
+    }
+    fsutil::write_private(&path, text.as_bytes())?;
+    
+    Ok(text)
+}
+
+fn cache_path(url: &str) -> Result<PathBuf> {
+    let dir = dirs::cache_dir().ok_or_else(|| anyhow!("No cache directory available"))?;
+    let key = blake3::hash(url.as_bytes()).to_hex();
+    Ok(dir.join("zap").join("wordlists").join(format!("{}.txt", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    
+    /// Distinct lowercase words: "aaa", "aab", ... in base 26
+    fn words(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|mut n| {
+                let mut word = [b'a'; 3];
+                for byte in word.iter_mut().rev() {
+                    *byte = b'a' + (n % 26) as u8;
+                    n /= 26;
+                }
+                String::from_utf8(word.to_vec()).unwrap()
+            })
+            .collect()
+    }
+    
+    #[tokio::test]
+    async fn test_load_local_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "{}", words(MIN_WORDLIST_SIZE).join("\n")).unwrap();
+        
+        let loaded = load_wordlist(file.path().to_str().unwrap()).await.unwrap();
+        assert_eq!(loaded, words(MIN_WORDLIST_SIZE));
+    }
+    
+    #[test]
+    fn test_deduplicates() {
+        let mut list = words(MIN_WORDLIST_SIZE);
+        list.extend(words(100));
+        
+        let parsed = parse_wordlist(&list.join("\n")).unwrap();
+        assert_eq!(parsed.len(), MIN_WORDLIST_SIZE);
+    }
+    
+    #[test]
+    fn test_rejects_small_list() {
+        let mut list = words(MIN_WORDLIST_SIZE - 1);
+        list.extend(words(10));
+        
+        let err = parse_wordlist(&list.join("\n")).unwrap_err();
+        assert!(err.to_string().contains("need at least 1024"));
+    }
+    
+    #[test]
+    fn test_rejects_invalid_word() {
+        let mut list = words(MIN_WORDLIST_SIZE);
+        list.push("two words".to_string());
+        
+        let err = parse_wordlist(&list.join("\n")).unwrap_err();
+        assert_eq!(err, WordlistError::InvalidWord { word: "two words".to_string(), line: MIN_WORDLIST_SIZE + 1 });
+    }
+    
+    #[test]
+    fn test_tolerates_messy_files() {
+        let list = words(MIN_WORDLIST_SIZE);
+        let shouted: Vec<_> = list.iter().take(50).map(|word| word.to_uppercase()).collect();
+        let text = format!("\u{feff}# my words\r\n\r\n{}\r\n\n  \n{}\r\n", list.join("\r\n"), shouted.join("\n"));
+        
+        assert_eq!(parse_wordlist(&text).unwrap(), list);
+    }
+    
+    #[test]
+    fn test_embedded_wordlist_parses() {
+        let words = embedded_wordlist();
+        assert!(words.len() >= 2);
+        assert_eq!(words.len(), include_str!("wordlist.txt").lines().filter(|line| !line.trim().is_empty()).count());
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Write `data` to `path`, readable and writable only by the user
+///
+/// For the files zap keeps for itself: resume state, tickets, receipts and
+/// caches name the files moved and carry their hashes. A file that's already
+/// there is made private too before it's overwritten. Outside Unix, they're
+/// left to the permissions of the user's own folders they're kept in.
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
+
+/// Create `path` and any missing parents, with the ones created only open to the user
+///
+/// Parents that already exist, like the user's cache folder, are left as they are.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
+
+/// Take away other users' access to the file at `path`, returning whether they had any
+///
+/// A missing file is fine; there's nothing to tighten.
+pub fn tighten(path: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.permissions().mode(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if mode & 0o077 == 0 {
+            return Ok(false);
+        }
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o700))?;
+        Ok(true)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+/// The warning shown when `tighten` had to change something
+pub fn tightened_warning(path: &Path) -> String {
+    format!("{} was readable by other users; it's now private to you", path.display())
+}
+
+/// Whether `error` means there's no room left to write, on the disk or in the user's quota
+///
+/// Covers `ENOSPC` and `EDQUOT` on Unix, and full disks and exceeded quotas
+/// (`ERROR_DISK_FULL`, `ERROR_HANDLE_DISK_FULL`, `ERROR_DISK_QUOTA_EXCEEDED`) on Windows.
+pub fn is_out_of_space(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+/// Whether anything in the chain of `error` is `is_out_of_space`
+pub fn ran_out_of_space(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(is_out_of_space))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+    
+    #[test]
+    fn test_private_files_and_dirs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let nested = dir.path().join("zap/manifests");
+        create_private_dir(&nested).unwrap();
+        assert_eq!((mode(&dir.path().join("zap")), mode(&nested)), (0o700, 0o700));
+        
+        let file = nested.join("state.json");
+        write_private(&file, b"{}").unwrap();
+        assert_eq!(mode(&file), 0o600);
+        
+        // Written over, an existing file loses what others could do with it
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&file, b"[]").unwrap();
+        assert_eq!((mode(&file), std::fs::read(&file).unwrap()), (0o600, b"[]".to_vec()));
+    }
+    
+    #[test]
+    fn test_tighten_loose_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("movie.mkv.zap-state");
+        assert!(!tighten(&file).unwrap());
+        
+        std::fs::write(&file, b"{}").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(tighten(&file).unwrap());
+        assert_eq!(mode(&file), 0o600);
+        assert!(!tighten(&file).unwrap());
+    }
+    
+    #[test]
+    fn test_out_of_space() {
+        // ENOSPC, and EDQUOT as Linux numbers it
+        assert!(is_out_of_space(&io::Error::from_raw_os_error(28)));
+        #[cfg(target_os = "linux")]
+        assert!(is_out_of_space(&io::Error::from_raw_os_error(122)));
+        assert!(!is_out_of_space(&io::Error::from(io::ErrorKind::PermissionDenied)));
+        
+        let wrapped = anyhow::Error::from(io::Error::from(io::ErrorKind::QuotaExceeded)).context("Couldn't write movie.mkv");
+        assert!(ran_out_of_space(&wrapped));
+        assert!(!ran_out_of_space(&anyhow::anyhow!("No space left on device")));
+    }
+}
@@ -0,0 +1,19 @@
+pub mod build_info;
+pub mod cli;
+pub mod config;
+pub mod crypto;
+pub mod fsutil;
+pub mod network;
+pub mod power;
+pub mod protocol;
+pub mod relay;
+pub mod rng;
+pub mod selftest;
+pub mod session;
+pub mod transfer;
+pub mod transport;
+pub mod tui;
+
+pub use session::{probe, receive, receive_over, send, send_over, Offer, ProgressCallback, ReceiveOptions, SendOptions, TransferEvent};
+pub use tokio_util::sync::CancellationToken;
+pub use transport::PeerInfo;
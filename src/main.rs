@@ -1,282 +1,1200 @@
-mod cli;
-mod crypto;
-mod network;
-mod protocol;
-mod relay;
-mod transfer;
-mod transport;
-mod tui;
-
 use anyhow::Result;
-use cli::{Cli, Commands};
-use crypto::Cipher;
-use protocol::Message;
-use std::time::Instant;
-use transfer::{FileChunker, FileWriter};
-use transport::Transport;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use zap::build_info::BuildInfo;
+use zap::cli::{Cli, Commands, ConfigAction, ReceiptAction};
+use zap::config::{self, ZapConfig, Setting, Source};
+use zap::crypto::{self, IdentityKey};
+use zap::fsutil;
+use zap::network::{self, AllowList, ProtocolLog, SocketTimeouts};
+use zap::power::{SleepGuard, SystemInhibitor};
+use zap::relay::{self, MailboxConfig, RelayConfig};
+use zap::selftest;
+use zap::session::schedule::StartCondition;
+use zap::transfer::conflict::ConflictAnswer;
+use zap::transfer::staging::{self, OrphanKind};
+use zap::transfer::{self, AcceptTypes, ChecksumChoice, ConfirmPrompt, ConflictPrompt, ConflictStrategy, ManifestCache, PreflightOptions, Receipt};
+use zap::tui::glyphs::{caution, glyphs, highlight};
+use zap::tui::prompt::{self, Catalog, Choice, Prompt};
+use zap::tui::{self, TransferState, TransferUI};
+use zap::session::{Cancelled, PeerTooOld};
+use zap::{CancellationToken, ReceiveOptions, SendOptions, TransferEvent};
+
+/// Exit code for a transfer called off with Ctrl-C, as shells report for an interrupted program
+const EXIT_CANCELLED: i32 = 130;
+
+/// Exit code for `zap verify` when the file doesn't match, so scripts can tell it from an error (1)
+const EXIT_CHECKSUM_MISMATCH: i32 = 3;
+
+/// Exit code for a peer whose key exchange is below --min-protocol
+const EXIT_PEER_TOO_OLD: i32 = 4;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse_args();
+    // With --json, even a failure or a crash is a line of JSON on stdout, in place of the text
+    let json = cli.command.json();
+    if json {
+        std::panic::set_hook(Box::new(report_panic));
+    }
+    match run(cli).await {
+        Err(e) if json => exit_as_json(&e, None),
+        result => result,
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    // The file's settings are the defaults of the options they stand in for, once it's known which file
+    let (settings_path, settings) = load_settings(cli.config.as_deref(), cli.no_config)?;
+    let defaults = config::file_defaults(&settings);
+    let mut cli = if defaults.is_empty() { cli } else { Cli::parse_with_defaults(&defaults) };
+    for warning in cli.command.post_validate(cli.port)? {
+        eprintln!("Warning: {}", warning);
+    }
+    
+    if let Some(seed) = cli.seed {
+        eprintln!("Warning: --seed makes transfer codes predictable, only use it to reproduce bugs");
+        zap::rng::set_seed(seed);
+    }
+    let timeouts = SocketTimeouts {
+        read: cli.read_timeout.map(Duration::from_secs),
+        write: cli.write_timeout.map(Duration::from_secs),
+    };
+    if let Some(path) = &cli.messages {
+        prompt::set_catalog(Catalog::load(path)?);
+    }
+    #[cfg(not(debug_assertions))]
+    if cli.debug_protocol {
+        eprintln!("Warning: --debug-protocol only works in debug builds, ignoring it");
+    }
+    let protocol_log = cli.debug_protocol.then(|| ProtocolLog::new(network::log_protocol_to_stderr));
+    // Only worth it when someone is there to notice the laptop dozing off
+    let inhibit_sleep = cli.inhibit_sleep.unwrap_or_else(|| std::io::stderr().is_terminal());
+    let min_protocol = cli.min_protocol();
+    let history = open_history(cli.history.as_deref());
     
     match cli.command {
-        Commands::Send { path, code, words, relay } => {
-            send_file(path, code, words, cli.port, cli.no_tui, relay).await?;
+        Commands::Send {
+            path,
+            stdin_passthrough,
+            code,
+            words,
+            wordlist,
+            relay,
+            relay_max_frame_size,
+            mailbox,
+            mailbox_ttl,
+            readahead,
+            format,
+            at,
+            when_idle,
+            estimate,
+            yes,
+            allow,
+            lan_only,
+            receipt,
+            sync,
+            delete,
+            no_manifest_cache,
+            skip_unreadable,
+            crypto_threads,
+            json,
+        } => {
+            // Generate or use custom code
+            let code = match (code, wordlist) {
+                (Some(code), _) => code,
+                (None, Some(source)) => crypto::generate_code_from(&crypto::load_wordlist(&source).await?, words)?,
+                (None, None) => crypto::generate_code(words),
+            };
+            
+            let mut allow = AllowList::new(allow);
+            if lan_only {
+                allow.extend(AllowList::lan_only());
+            }
+            
+            let options = SendOptions {
+                port: cli.port,
+                relay,
+                relay_max_frame_size,
+                try_direct: cli.try_direct,
+                pq: cli.pq,
+                direct_tls: cli.direct_tls,
+                timeouts,
+                protocol_log: protocol_log.clone(),
+                min_protocol,
+                hash_threads: cli.hash_threads,
+                mailbox_ttl: mailbox.then_some(mailbox_ttl),
+                stdin_passthrough,
+                readahead,
+                format,
+                allow,
+                receipt,
+                sync,
+                manifest_cache: path.as_deref().filter(|_| sync && !no_manifest_cache).map(ManifestCache::cache_path),
+                crypto_threads,
+                // With --json, as without a terminal, nobody is asked; --yes stands in for the answer
+                sync_delete: delete.then(|| {
+                    let ask = !yes && !json && std::io::stdin().is_terminal();
+                    ConfirmPrompt::new(move |remote_only| confirm_delete(remote_only, ask, yes, json))
+                }),
+                start: at.map(StartCondition::At).or(when_idle.map(|mbps| StartCondition::WhenIdle { mbps })),
+                estimate: estimate.then(|| {
+                    // Passed-through data comes in on stdin, so there's nobody there to answer
+                    let ask = !yes && !stdin_passthrough && !json && std::io::stdin().is_terminal();
+                    ConfirmPrompt::new(move |estimate| confirm_estimate(estimate, ask, stdin_passthrough || json))
+                }),
+                ..SendOptions::new(path.unwrap_or_default(), code)
+            };
+            let sent = options.path.clone();
+            send_file(options, skip_unreadable, cli.stats, cli.no_tui, cli.verbose, inhibit_sleep, json).await?;
+            // A folder or stdin has no one file to check later
+            if !stdin_passthrough && !sync && sent.is_file() {
+                remember(history.as_ref(), transfer::Direction::Sent, &sent).await;
+            }
         }
-        Commands::Receive { code, output, resume, relay } => {
-            receive_file(code, output, cli.port, cli.no_tui, resume, relay).await?;
+        Commands::Receive {
+            code,
+            output,
+            host,
+            resume,
+            relay,
+            relay_max_frame_size,
+            mailbox,
+            conflict,
+            accept_types,
+            allow_executables,
+            auto_accept,
+            reject_larger_than,
+            memory_limit,
+            tmp_dir,
+            strict_metadata,
+            keep_going,
+            checksum,
+            verify_after,
+            identity,
+            delta,
+            sync,
+            pipe_to,
+            json,
+        } => {
+            if verify_after && checksum == ChecksumChoice::Off {
+                anyhow::bail!("--verify-after has nothing to check with --checksum none");
+            }
+            // Nobody can answer the question when stdin is a pipe, so take the file as before
+            let ask = !auto_accept && !json && std::io::stdin().is_terminal();
+            // Nor with --json, where a program that looks like one is refused instead, and clashing files are kept
+            let executables: fn(&str) -> bool = if json { |_| false } else { confirm_executable };
+            let identity = match identity {
+                Some(path) => {
+                    tighten(&path);
+                    Some(Arc::new(IdentityKey::load(&path)?))
+                }
+                None => None,
+            };
+            let options = ReceiveOptions {
+                output,
+                host,
+                port: cli.port,
+                relay,
+                relay_max_frame_size,
+                try_direct: cli.try_direct,
+                mailbox,
+                pq: cli.pq,
+                direct_tls: cli.direct_tls,
+                happy_eyeballs: !cli.no_happy_eyeballs,
+                timeouts,
+                protocol_log: protocol_log.clone(),
+                min_protocol,
+                conflict,
+                conflict_prompt: (!json).then(|| ConflictPrompt::new(ask_about_conflict)),
+                accept_types: accept_types.as_deref().map(AcceptTypes::parse),
+                confirm_executable: (!allow_executables).then(|| ConfirmPrompt::new(executables)),
+                confirm_offer: ask.then(|| ConfirmPrompt::new(confirm_offer)),
+                reject_larger_than,
+                tmp_dir,
+                strict_metadata,
+                keep_going,
+                checksum,
+                verify_after,
+                resume,
+                identity,
+                delta,
+                sync,
+                hash_threads: cli.hash_threads,
+                memory_limit,
+                pipe_to,
+                ..ReceiveOptions::new(code)
+            };
+            if let Some(saved_to) = receive_file(options, cli.stats, cli.no_tui, cli.verbose, inhibit_sleep, json).await? {
+                if saved_to.is_file() {
+                    remember(history.as_ref(), transfer::Direction::Received, &saved_to).await;
+                }
+            }
+        }
+        Commands::Peek { code, host, relay, relay_max_frame_size, json } => {
+            if host.is_none() && relay.is_none() {
+                anyhow::bail!("zap peek needs the sender's address (--host) or a relay (--relay)");
+            }
+            let options = ReceiveOptions {
+                host,
+                port: cli.port,
+                relay,
+                relay_max_frame_size,
+                try_direct: cli.try_direct,
+                pq: cli.pq,
+                direct_tls: cli.direct_tls,
+                happy_eyeballs: !cli.no_happy_eyeballs,
+                timeouts,
+                protocol_log: protocol_log.clone(),
+                min_protocol,
+                // Only to see the offer; nothing is synced
+                sync: true,
+                ..ReceiveOptions::new(code)
+            };
+            peek(options, json).await?;
+        }
+        Commands::Relay {
+            port,
+            relay_max_frame_size,
+            relay_queue_depth,
+            room_in_flight_bytes,
+            per_room_rate,
+            admin_socket,
+            admin_addr,
+            allow_mailbox,
+            mailbox_dir,
+            mailbox_max_bytes,
+            allowlist,
+            denylist,
+            capacity,
+            session_timeout,
+            register_timeout,
+            pre_register_frame_size,
+            pre_register_frames,
+            path,
+            trust_proxy,
+        } => {
+            relay::run_relay_server(RelayConfig {
+                port,
+                max_frame_size: relay_max_frame_size,
+                queue_depth: relay_queue_depth,
+                room_in_flight_bytes,
+                per_room_rate,
+                admin_socket,
+                admin_addr,
+                mailbox: allow_mailbox.then_some(MailboxConfig {
+                    dir: mailbox_dir,
+                    max_bytes: mailbox_max_bytes,
+                }),
+                allowlist,
+                denylist,
+                capacity,
+                session_timeout: Duration::from_secs(session_timeout),
+                path,
+                trust_proxy,
+                register_timeout: Duration::from_secs(register_timeout),
+                pre_register_frame_size,
+                pre_register_frames,
+            }).await?;
+        }
+        Commands::Selftest { size, with_relay } => {
+            let info = BuildInfo::current();
+            println!("{} Zap self-test ({} {}, {})", glyphs().bolt, info.version, info.git_sha, info.target);
+            println!("{}", glyphs().rule);
+            let report = selftest::run(size, with_relay).await?;
+            println!("{}", report);
+            if !report.passed() {
+                return Err(anyhow::anyhow!("Self-test failed"));
+            }
+        }
+        Commands::Receipt { action } => receipt(action).await?,
+        Commands::Verify { path, checksum, receipt, from_history } => {
+            let expected = match (checksum, receipt) {
+                (Some(checksum), _) => transfer::Expected::checksum(&checksum)?,
+                (None, Some(receipt)) => {
+                    tighten(&receipt);
+                    transfer::Expected::Receipt(Receipt::load(&receipt)?)
+                }
+                (None, None) if from_history => {
+                    let history = history.ok_or_else(|| anyhow::anyhow!("There's no history to look {} up in (--history off)", path.display()))?;
+                    let Some(entry) = history.latest(&path)? else {
+                        anyhow::bail!("{} isn't in the history in {}", path.display(), history.path().display());
+                    };
+                    let at = chrono::DateTime::<chrono::Local>::from(UNIX_EPOCH + Duration::from_secs(entry.at));
+                    let direction = match entry.direction {
+                        transfer::Direction::Sent => "sent",
+                        transfer::Direction::Received => "received",
+                    };
+                    println!("Checking against the file as it was {} on {}", direction, at.format("%Y-%m-%d %H:%M"));
+                    transfer::Expected::checksum(&entry.checksum)?
+                }
+                (None, None) => unreachable!("clap requires --checksum, --receipt or --from-history"),
+            };
+            if !verify(&path, &expected).await? {
+                std::process::exit(EXIT_CHECKSUM_MISMATCH);
+            }
         }
-        Commands::Relay { port } => {
-            relay::run_relay_server(port).await?;
+        Commands::Clean { dir, dry_run, older_than } => clean(&dir, dry_run, older_than)?,
+        Commands::Config { action } => configure(action, settings_path.as_deref(), &settings)?,
+        Commands::Version { json } => {
+            println!("{}", BuildInfo::current().render(json)?);
         }
     }
     
     Ok(())
 }
 
-async fn send_file(
-    path: Option<std::path::PathBuf>,
-    custom_code: Option<String>,
-    word_count: usize,
-    port: Option<u16>,
-    no_tui: bool,
-    relay_addr: Option<String>,
-) -> Result<()> {
-    // Generate or use custom code
-    let code = custom_code.unwrap_or_else(|| crypto::generate_code(word_count));
-    
-    println!("⚡ Zap - Send File");
-    println!("═══════════════════════════════════════");
-    println!("Transfer Code: \x1b[1;32m{}\x1b[0m", code);
-    println!("Waiting for receiver...");
-    println!();
-    
-    // For MVP, we'll use the path if provided, otherwise error
-    let file_path = path.ok_or_else(|| anyhow::anyhow!("File path required for MVP"))?;
-    
-    // Get file metadata
-    let metadata = transfer::get_file_metadata(&file_path).await?;
-    println!("File: {} ({} bytes)", metadata.name, metadata.size);
+/// Print what the sender offers, then leave it for a real receiver
+async fn peek(options: ReceiveOptions, json: bool) -> Result<()> {
+    let offer = zap::session::probe(options).await;
+    if let (true, Err(e)) = (json, &offer) {
+        exit_as_json(e, None);
+    }
+    exit_if_too_old(&offer);
+    let offer = offer?;
+    let metadata = offer.metadata().clone();
+    let peer = offer.peer().to_string();
+    let kind = if offer.is_streamed() {
+        "stream"
+    } else if offer.is_sync() {
+        "sync"
+    } else if metadata.is_directory {
+        "directory"
+    } else {
+        "file"
+    };
+    // Folders go as archives whose size isn't known up front, and the checksum is only there for receipts
+    let size = matches!(kind, "file" | "sync").then_some(metadata.size);
+    let checksum = (metadata.checksum != transfer::NO_CHECKSUM).then_some(metadata.checksum);
+    let waiting = offer.defer().await?;
     
-    // Wait for connection (either direct or via relay)
-    let mut conn = Transport::new_sender(relay_addr, &code, port).await?;
-    if let Some(addr) = conn.peer_addr() {
-        println!("✓ Connected to {}", addr);
+    if json {
+        let report = serde_json::json!({
+            "name": metadata.name,
+            "size": size,
+            "kind": kind,
+            "checksum": checksum,
+            "peer": peer,
+            "sender_waiting": waiting,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!("Offered by {}:", peer);
+    println!("  Name: {}", metadata.name);
+    match size {
+        Some(size) => println!("  Size: {} bytes", size),
+        None => println!("  Size: unknown until it's sent"),
+    }
+    println!("  Kind: {}", kind);
+    if let Some(checksum) = checksum {
+        println!("  Checksum: {}", checksum);
+    }
+    if waiting {
+        println!("{} Left untouched; the sender is still waiting for it to be received", glyphs().check);
     } else {
-        println!("✓ Connected via relay");
+        println!("{} The sender is too old to wait for another receiver, so it took this as a decline", glyphs().warning);
     }
-    
-    // Send hello
-    let hello = Message::Hello { version: protocol::PROTOCOL_VERSION };
-    conn.send(&hello.to_bytes()?).await?;
-    
-    // Receive hello
-    let response = conn.receive().await?;
-    let response_msg = Message::from_bytes(&response)?;
-    match response_msg {
-        Message::Hello { version } => {
-            if version != protocol::PROTOCOL_VERSION {
-                return Err(anyhow::anyhow!("Protocol version mismatch"));
+    Ok(())
+}
+
+/// Make an identity key, or check a file against its receipt
+async fn receipt(action: ReceiptAction) -> Result<()> {
+    match action {
+        ReceiptAction::Keygen { key_file } => {
+            let key = IdentityKey::create(&key_file)?;
+            println!("{} Identity key written to {}", glyphs().check, key_file.display());
+            println!("Public key: {}", highlight(key.public_key()));
+            println!("Senders check your receipts against the public key; keep the key file to yourself");
+        }
+        ReceiptAction::Verify { file, receipt, key } => {
+            let receipt = Receipt::load(&receipt)?;
+            let signer = receipt.verify(&file, key.as_deref()).await?;
+            let received = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(receipt.received_at));
+            println!("{} {} matches the receipt (received {})", glyphs().check, file.display(), received);
+            match signer {
+                Some(signer) => println!("{} Signed by {}", glyphs().check, highlight(signer)),
+                None => println!("{} The receipt isn't signed, so it only shows the file is unchanged", glyphs().warning),
             }
         }
-        _ => return Err(anyhow::anyhow!("Expected Hello message")),
+    }
+    Ok(())
+}
+
+/// Hash `path` with a progress line and say whether it matches, returning false when it doesn't
+async fn verify(path: &Path, expected: &transfer::Expected) -> Result<bool> {
+    let interactive = std::io::stdout().is_terminal();
+    let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+    let started = Instant::now();
+    let mut drawn = None::<Instant>;
+    let verdict = transfer::verify::verify(path, expected, |read, total| {
+        // Redrawing for every chunk would cost more than the hashing
+        if interactive && (read == total || drawn.is_none_or(|at| at.elapsed() >= Duration::from_millis(100))) {
+            let speed = read as f64 / started.elapsed().as_secs_f64().max(0.001);
+            tui::print_progress(&name, read, total, speed);
+            drawn = Some(Instant::now());
+        }
+    })
+    .await?;
+    if drawn.is_some() {
+        println!();
     }
     
-    println!("✓ Handshake complete");
-    
-    // Create cipher from code
-    let cipher = Cipher::from_password(&code)?;
-    
-    // Send metadata
-    let metadata_msg = Message::Metadata {
-        filename: metadata.name.clone(),
-        size: metadata.size,
-        is_directory: metadata.is_directory,
-        checksum: metadata.checksum.clone(),
+    if let Some(signer) = &verdict.signer {
+        println!("{} Receipt signed by {}", glyphs().check, highlight(signer));
+    }
+    if verdict.matches() {
+        let algorithm = crypto::ChecksumAlgorithm::of(&verdict.actual);
+        let hex = verdict.actual.trim_start_matches(crypto::BLAKE3_PREFIX);
+        println!("{} {} matches ({} {})", glyphs().check, path.display(), algorithm, hex);
+        return Ok(true);
+    }
+    println!("{} {} doesn't match", glyphs().warning, path.display());
+    println!("  Expected: {}", verdict.expected);
+    println!("  Actual:   {}", verdict.actual);
+    Ok(false)
+}
+
+/// Take other users' access away from a file zap keeps, like an identity key, warning if they had any
+///
+/// One that isn't ours to change, like a receipt someone else saved, is left as it is.
+fn tighten(path: &Path) {
+    if fsutil::tighten(path).unwrap_or(false) {
+        eprintln!("Warning: {}", fsutil::tightened_warning(path));
+    }
+}
+
+/// The settings file in use, if any, and the settings in effect from it and the environment
+///
+/// A file given with `--config` has to be there and readable. The usual one
+/// is warned about when it can't be read and otherwise left out, so
+/// `zap config` can still be used to fix it.
+fn load_settings(explicit: Option<&Path>, no_config: bool) -> Result<(Option<PathBuf>, Vec<Setting>)> {
+    let env = |name: &str| std::env::var(name).ok();
+    if no_config {
+        return Ok((None, config::environment_only(env)));
+    }
+    let file = match ZapConfig::load(explicit) {
+        Ok(file) => file,
+        Err(e) if explicit.is_none() => {
+            eprintln!("Warning: {:#}", e);
+            return Ok((config::config_path(), config::environment_only(env)));
+        }
+        Err(e) => return Err(e),
     };
-    let encrypted_metadata = cipher.encrypt(&metadata_msg.to_bytes()?)?;
-    conn.send(&encrypted_metadata).await?;
-    
-    println!("✓ Metadata sent (encrypted)");
-    
-    // Wait for ack
-    let ack = conn.receive().await?;
-    let ack_msg = Message::from_bytes(&ack)?;
-    match ack_msg {
-        Message::Ack => {}
-        _ => return Err(anyhow::anyhow!("Expected Ack message")),
+    for warning in file.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok((Some(file.path().to_path_buf()), file.effective(env)))
+}
+
+/// Change the settings file at `path`, or show what's in effect
+fn configure(action: ConfigAction, path: Option<&Path>, settings: &[Setting]) -> Result<()> {
+    let file_to_change = || path.ok_or_else(|| anyhow::anyhow!("No settings file to change: drop --no-config, or pass --config"));
+    match action {
+        ConfigAction::Set { key, value } => {
+            let path = file_to_change()?;
+            let mut file = ZapConfig::read(path)?;
+            file.set(&key, &value)?;
+            file.save()?;
+            let key = config::find_key(&key)?;
+            println!("{} Set {} in {}", glyphs().check, key.name, path.display());
+            if settings.iter().any(|setting| setting.source == Some(Source::Env(key.env))) {
+                println!("{} {} is set too, and takes precedence", glyphs().warning, key.env);
+            }
+        }
+        ConfigAction::Unset { key } => {
+            let path = file_to_change()?;
+            let mut file = ZapConfig::read(path)?;
+            if file.unset(&key)? {
+                file.save()?;
+                println!("{} Removed {} from {}", glyphs().check, key, path.display());
+            } else {
+                println!("{} isn't in {}", key, path.display());
+            }
+        }
+        ConfigAction::Get { key: Some(key) } => {
+            let key = config::find_key(&key)?;
+            match settings.iter().find(|setting| setting.key == key).and_then(|setting| setting.value.as_ref()) {
+                Some(value) => println!("{}", value),
+                None => return Err(anyhow::anyhow!("{} isn't set", key.name)),
+            }
+        }
+        ConfigAction::Get { key: None } => {
+            match path {
+                Some(path) => println!("# {}", path.display()),
+                None => println!("# No settings file"),
+            }
+            for setting in settings {
+                println!("{}", setting);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find what abandoned transfers left in `dir` and remove it, or just list it
+fn clean(dir: &Path, dry_run: bool, older_than: Duration) -> Result<()> {
+    let orphans = staging::find_orphans(dir, older_than)?;
+    if orphans.is_empty() {
+        println!("Nothing to clean in {}", dir.display());
+        return Ok(());
     }
     
-    // Send file chunks
-    println!("Transferring file...");
-    let mut chunker = FileChunker::new(&file_path)?;
-    let mut chunk_index = 0u64;
-    let start_time = Instant::now();
-    
-    while let Some(chunk) = chunker.next_chunk()? {
-        let chunk_msg = Message::Chunk {
-            index: chunk_index,
-            data: chunk,
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    let mut freed = 0;
+    for orphan in &orphans {
+        let kind = match orphan.kind {
+            OrphanKind::Staging => "staging directory",
+            OrphanKind::ResumeState => "resume record",
         };
-        let encrypted_chunk = cipher.encrypt(&chunk_msg.to_bytes()?)?;
-        conn.send(&encrypted_chunk).await?;
-        
-        chunk_index += 1;
-        
-        // Progress update
-        if !no_tui {
-            let elapsed = start_time.elapsed().as_secs_f64();
-            let speed = if elapsed > 0.0 {
-                chunker.bytes_read() as f64 / elapsed
-            } else {
-                0.0
-            };
-            tui::print_progress(
-                &metadata.name,
-                chunker.bytes_read(),
-                chunker.total_size(),
-                speed,
-            );
+        if !dry_run {
+            if let Err(e) = orphan.remove() {
+                println!("{} Couldn't remove {}: {}", glyphs().warning, orphan.path.display(), e);
+                continue;
+            }
+        }
+        freed += orphan.bytes;
+        let age = humantime::format_duration(Duration::from_secs(orphan.age.as_secs() / 60 * 60));
+        println!("{} {} {} ({} bytes, {} old)", verb, kind, orphan.path.display(), orphan.bytes, age);
+    }
+    println!("{} {} bytes in total", verb, freed);
+    Ok(())
+}
+
+/// Print a status line, on stderr when stdout carries the passed-through data
+macro_rules! status {
+    ($passthrough:expr) => {
+        if $passthrough { eprintln!() } else { println!() }
+    };
+    ($passthrough:expr, $($arg:tt)*) => {
+        if $passthrough { eprintln!($($arg)*) } else { println!($($arg)*) }
+    };
+}
+
+/// Logs progress in the background when there's no terminal to draw on
+struct HeadlessLog {
+    state: Arc<Mutex<TransferState>>,
+    worker: JoinHandle<Result<()>>,
+}
+
+impl HeadlessLog {
+    /// Start logging unless stdout is a terminal and the TUI is wanted
+    fn start(code: &str, no_tui: bool) -> Option<Self> {
+        if !no_tui && std::io::stdout().is_terminal() {
+            return None;
         }
+        let state = Arc::new(Mutex::new(TransferState::new(code)));
+        let shared = state.clone();
+        let worker = tokio::task::spawn_blocking(move || {
+            TransferUI::new_headless().run(|| shared.lock().unwrap().clone())
+        });
+        Some(Self { state, worker })
     }
     
-    // Send complete message
-    let complete_msg = Message::Complete;
-    let encrypted_complete = cipher.encrypt(&complete_msg.to_bytes()?)?;
-    conn.send(&encrypted_complete).await?;
+    /// Write the final line and wait for the logger to stop
+    async fn finish<T>(self, result: &Result<T>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match result {
+                Err(e) => state.status = format!("Transfer error: {}", e),
+                // A send the receiver cancelled has said so already
+                Ok(_) if state.is_finished() => {}
+                Ok(_) => state.status = "Transfer complete".to_string(),
+            }
+        }
+        let _ = self.worker.await;
+    }
+}
+
+/// Keeps the computer awake from the handshake until the transfer ends
+struct KeepAwake {
+    enabled: bool,
+    guard: Mutex<Option<SleepGuard>>,
+}
+
+impl KeepAwake {
+    fn new(enabled: bool) -> Self {
+        Self { enabled, guard: Mutex::new(None) }
+    }
     
-    println!();
-    println!("✓ Transfer complete!");
+    fn apply(&self, event: &TransferEvent) {
+        if !self.enabled || !matches!(event, TransferEvent::Handshake { .. }) {
+            return;
+        }
+        let mut guard = self.guard.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(SleepGuard::acquire(&SystemInhibitor, "Transferring files with zap"));
+        }
+    }
     
-    Ok(())
+    fn release(&self) {
+        self.guard.lock().unwrap().take();
+    }
 }
 
-async fn receive_file(
-    code: String,
-    output: Option<std::path::PathBuf>,
-    port: Option<u16>,
+async fn send_file(
+    mut options: SendOptions,
+    skip_unreadable: bool,
+    stats: bool,
     no_tui: bool,
-    resume: bool,
-    relay_addr: Option<String>,
+    verbose: bool,
+    inhibit_sleep: bool,
+    json: bool,
 ) -> Result<()> {
-    println!("⚡ Zap - Receive File");
-    println!("═══════════════════════════════════════");
-    println!("Transfer Code: \x1b[1;32m{}\x1b[0m", code);
-    println!("Connecting to sender...");
-    println!();
+    let passthrough = options.stdin_passthrough;
+    // With --json, stdout is kept for the events, as it is for the data with --stdin-passthrough
+    let quiet = passthrough || json;
+    status!(quiet, "{} Zap - Send File", glyphs().bolt);
+    status!(quiet, "{}", glyphs().rule);
     
-    // Get host if not using relay
-    let host = if relay_addr.is_none() {
-        // For MVP, require host to connect to
-        // In full version, we'd use mDNS discovery
-        println!("Enter sender's IP address (or 'localhost' for local transfer):");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        Some(input.trim().to_string())
+    // For MVP, we'll use the path if provided, otherwise error
+    if options.path.as_os_str().is_empty() && !passthrough {
+        return Err(anyhow::anyhow!("File path required (or --stdin-passthrough)"));
+    }
+    // Problems with the path are for fixing before the receiver is told the code
+    if !passthrough {
+        let report = transfer::preflight(&options.path, &PreflightOptions { skip_unreadable })?;
+        if let Some(target) = &report.target {
+            status!(quiet, "{} is a link, sending {} it points to", options.path.display(), target.display());
+        }
+        if !report.unreadable.is_empty() {
+            status!(quiet, "{}", caution(format!("{} Leaving out what can't be read:", glyphs().warning)));
+            for entry in &report.unreadable {
+                status!(quiet, "  {}: {}", entry.path.display(), entry.reason);
+            }
+        }
+        options.skip = report.skipped();
+    }
+    if json {
+        println!("{}", serde_json::json!({ "event": "code", "code": options.code }));
     } else {
-        None
-    };
+        status!(passthrough, "Transfer Code: {}", highlight(&options.code));
+        status!(passthrough, "Waiting for receiver...");
+        status!(passthrough);
+    }
     
-    // Connect to sender (either direct or via relay)
-    let mut conn = Transport::new_receiver(
-        relay_addr,
-        &code,
-        host.as_deref(),
-        port,
-    ).await?;
+    let headless = if json { None } else { HeadlessLog::start(&options.code, no_tui) };
+    let log_state = headless.as_ref().map(|log| log.state.clone());
+    let awake = Arc::new(KeepAwake::new(inhibit_sleep));
+    let transfer_awake = awake.clone();
+    let peer = Arc::new(Mutex::new(None));
+    let connected_to = peer.clone();
+    let progress = move |event: &TransferEvent| {
+        if let Some(state) = &log_state {
+            state.lock().unwrap().apply(event);
+        }
+        transfer_awake.apply(event);
+        if json {
+            remember_peer(&connected_to, event);
+            println!("{}", event.to_json());
+        } else {
+            sender_event(event, passthrough, log_state.is_none(), verbose, stats)
+        }
+    };
     
-    if let Some(addr) = conn.peer_addr() {
-        println!("✓ Connected to {}", addr);
+    let result = zap::send(options, Some(Arc::new(progress)), CancellationToken::new()).await;
+    awake.release();
+    if let Some(log) = headless {
+        log.finish(&result).await;
+    }
+    if let (true, Err(e)) = (json, &result) {
+        exit_as_json(e, peer.lock().unwrap().as_deref());
+    }
+    exit_if_too_old(&result);
+    result
+}
+
+/// Print `error` as the last line of `--json` and exit with the code it would have had, saying nothing else
+fn exit_as_json(error: &anyhow::Error, peer: Option<&str>) -> ! {
+    println!("{}", zap::session::events::error_to_json(error, peer));
+    let code = if error.is::<Cancelled>() {
+        EXIT_CANCELLED
+    } else if error.is::<PeerTooOld>() {
+        EXIT_PEER_TOO_OLD
     } else {
-        println!("✓ Connected via relay");
+        1
+    };
+    std::process::exit(code);
+}
+
+/// Keep who a transfer connected to, for the error it may end with
+fn remember_peer(peer: &Mutex<Option<String>>, event: &TransferEvent) {
+    if let TransferEvent::Connected { peer: connected } = event {
+        *peer.lock().unwrap() = Some(connected.to_string());
     }
-    
-    // Send hello
-    let hello = Message::Hello { version: protocol::PROTOCOL_VERSION };
-    conn.send(&hello.to_bytes()?).await?;
-    
-    // Receive hello
-    let response = conn.receive().await?;
-    let response_msg = Message::from_bytes(&response)?;
-    match response_msg {
-        Message::Hello { version } => {
-            if version != protocol::PROTOCOL_VERSION {
-                return Err(anyhow::anyhow!("Protocol version mismatch"));
+}
+
+/// With `--json`, a panic is a `{"event": "panic", ...}` line on stdout rather than Rust's text on stderr
+fn report_panic(info: &std::panic::PanicHookInfo<'_>) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panicked");
+    let location = info.location().map(|location| location.to_string());
+    println!("{}", serde_json::json!({ "event": "panic", "message": message, "location": location }));
+}
+
+/// Exit with `EXIT_PEER_TOO_OLD` when `result` is the peer falling below --min-protocol, saying so as returning it would
+fn exit_if_too_old<T>(result: &Result<T>) {
+    if let Err(e) = result {
+        if e.is::<PeerTooOld>() {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(EXIT_PEER_TOO_OLD);
+        }
+    }
+}
+
+/// Print what the sender is doing; `interactive` draws the progress line
+fn sender_event(event: &TransferEvent, passthrough: bool, interactive: bool, verbose: bool, stats: bool) {
+    match event {
+        TransferEvent::Metadata { filename, size } => {
+            if passthrough {
+                eprintln!("Sending {}", filename);
+            } else {
+                println!("File: {} ({} bytes)", filename, size);
+            }
+        }
+        TransferEvent::Listening { port } => {
+            status!(passthrough, "Listening on port: {}", highlight(port));
+        }
+        TransferEvent::PortBusy { port } => {
+            status!(passthrough, "Waiting for the previous instance's socket on port {} to clear", port);
+        }
+        TransferEvent::Reannounced { at } => {
+            if verbose {
+                let at = chrono::DateTime::<chrono::Local>::from(*at);
+                status!(passthrough, "Re-announced on the relay at {}", at.format("%H:%M"));
+            }
+        }
+        TransferEvent::Rejected { addr } => {
+            status!(passthrough, "{} Turned away a connection from {}, which isn't allowed", glyphs().warning, addr.ip());
+        }
+        TransferEvent::Hint { hint } => {
+            status!(passthrough);
+            status!(passthrough, "{}", highlight(&hint.summary));
+            for step in &hint.steps {
+                status!(passthrough, "  {} {}", glyphs().dash, step);
+            }
+        }
+        TransferEvent::Connected { peer } => status!(passthrough, "{} Connected to {}", glyphs().check, peer),
+        TransferEvent::Handshake { session } => {
+            if let Some(warning) = tui::post_quantum_fallback(session) {
+                status!(passthrough, "{} {}", glyphs().warning, warning);
+            }
+            status!(passthrough, "{} {}", glyphs().check, tui::handshake_status(session));
+            status!(passthrough, "Transferring file...");
+        }
+        TransferEvent::TlsVerified { ours, peer } => {
+            if verbose {
+                status!(passthrough, "{} {}", glyphs().check, tui::tls_status(ours, peer));
+            }
+        }
+        TransferEvent::Archiving { files_done, total_files } => {
+            if interactive && !passthrough {
+                tui::print_archiving(*files_done, *total_files);
+            }
+        }
+        TransferEvent::Hashing { files_done, total_files } => {
+            if interactive && !passthrough {
+                tui::print_hashing(*files_done, *total_files);
+            }
+        }
+        TransferEvent::Progress { filename, transferred, total, speed } => {
+            // The progress line would end up mixed into the passed-through data
+            if interactive && !passthrough {
+                tui::print_progress(filename, *transferred, *total, *speed);
+            }
+        }
+        TransferEvent::ChunkSize { chunk_size } => {
+            if verbose {
+                status!(passthrough);
+                status!(passthrough, "Chunk size: {} KB", chunk_size / 1024);
+            }
+        }
+        TransferEvent::PeerLimited { chunk_size, assumed } => {
+            if verbose {
+                status!(passthrough);
+                match assumed {
+                    true => status!(passthrough, "The receiver's zap is too old to say what chunks it takes; sending {} KB ones", chunk_size / 1024),
+                    false => status!(passthrough, "The receiver takes chunks of up to {} KB; sending those", chunk_size / 1024),
+                }
             }
         }
-        _ => return Err(anyhow::anyhow!("Expected Hello message")),
+        TransferEvent::PathLimited { chunk_size, dropped } => {
+            status!(passthrough);
+            status!(
+                passthrough,
+                "{} Chunks of {} KB never reached the receiver, but {} KB ones do; sending those instead.",
+                glyphs().warning,
+                dropped / 1024,
+                chunk_size / 1024
+            );
+            status!(
+                passthrough,
+                "  Something on the way is probably dropping large packets: a path MTU blackhole, or a VPN or router clamping the MSS wrong."
+            );
+        }
+        TransferEvent::Scheduled { starts_in } => {
+            if interactive && !passthrough {
+                tui::print_scheduled(*starts_in);
+            }
+        }
+        TransferEvent::Resuming { .. } | TransferEvent::Conflicts { .. } | TransferEvent::MetadataWarnings { .. } | TransferEvent::Skipped { .. } | TransferEvent::Memory { .. } | TransferEvent::Preparing { .. } => {}
+        TransferEvent::Tightened { path } => {
+            status!(passthrough);
+            status!(passthrough, "{} {}", glyphs().warning, fsutil::tightened_warning(path));
+        }
+        TransferEvent::Stored { ttl } => {
+            println!();
+            println!("{} Stored on the relay for {}", glyphs().check, humantime::format_duration(*ttl));
+        }
+        TransferEvent::Deferred => {
+            status!(passthrough);
+            status!(passthrough, "The receiver only looked at the offer (zap peek); waiting for it to be received...");
+        }
+        TransferEvent::Synced { sent, unchanged, remote_only, deleted, .. } => {
+            status!(passthrough);
+            status!(passthrough, "Synced: {} files sent, {} already up to date", sent, unchanged);
+            if *remote_only > 0 {
+                status!(passthrough, "{} of {} files only the receiver had were deleted (--delete)", deleted, remote_only);
+            }
+        }
+        TransferEvent::Delta { reused, total } => {
+            status!(passthrough);
+            status!(passthrough, "Sent only the changes: {} of {} bytes were already on the receiver's side", reused, total);
+        }
+        TransferEvent::Receipt { receipt, saved_to } => {
+            status!(passthrough);
+            if let Some(path) = saved_to {
+                status!(passthrough, "{} Receipt saved to {}", glyphs().check, path.display());
+            }
+            match &receipt.signature {
+                Some(signature) => status!(passthrough, "Signed by {}", signature.public_key),
+                None => status!(passthrough, "The receiver has no identity key, so it isn't signed"),
+            }
+        }
+        TransferEvent::ReceiverCancelled { transferred, total, resumable } => {
+            status!(passthrough);
+            let at = tui::cancelled_at(*transferred, *total);
+            if *resumable {
+                status!(passthrough, "{} receiver cancelled at {} {} they can resume with --resume", glyphs().warning, at, glyphs().dash);
+            } else {
+                status!(passthrough, "{} receiver cancelled at {}", glyphs().warning, at);
+            }
+        }
+        TransferEvent::Timings { timings } => {
+            if stats {
+                status!(passthrough);
+                status!(passthrough, "Time spent:");
+                for line in timings.to_string().lines() {
+                    status!(passthrough, "  {}", line);
+                }
+            }
+        }
+        TransferEvent::Complete => {
+            status!(passthrough);
+            status!(passthrough, "{} Transfer complete!", glyphs().check);
+        }
     }
+}
+
+/// Receive what's offered, returning where it was saved, or nothing when it was piped into a command
+async fn receive_file(mut options: ReceiveOptions, stats: bool, no_tui: bool, verbose: bool, inhibit_sleep: bool, json: bool) -> Result<Option<PathBuf>> {
+    // With --json, stdout is kept for the events
+    status!(json, "{} Zap - Receive File", glyphs().bolt);
+    status!(json, "{}", glyphs().rule);
+    status!(json, "Transfer Code: {}", highlight(&options.code));
+    status!(json, "Connecting to sender...");
+    status!(json);
     
-    println!("✓ Handshake complete");
-    
-    // Create cipher from code
-    let cipher = Cipher::from_password(&code)?;
-    
-    // Receive metadata
-    let encrypted_metadata = conn.receive().await?;
-    let metadata_bytes = cipher.decrypt(&encrypted_metadata)?;
-    let metadata_msg = Message::from_bytes(&metadata_bytes)?;
+    // Get host if not using relay
+    if options.relay.is_none() && options.host.is_none() {
+        // For MVP, require host to connect to
+        // In full version, we'd use mDNS discovery
+        status!(json, "Enter sender's address, with :port if it isn't the default (or 'localhost' for local transfer):");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        let host: network::Endpoint = input.parse()?;
+        if let Some(note) = host.note() {
+            status!(json, "{}", note);
+        }
+        options.host = Some(host);
+    }
     
-    let (filename, file_size) = match metadata_msg {
-        Message::Metadata { filename, size, .. } => {
-            println!("✓ Metadata received (encrypted)");
-            println!("File: {} ({} bytes)", filename, size);
-            (filename, size)
+    let headless = if json { None } else { HeadlessLog::start(&options.code, no_tui) };
+    let log_state = headless.as_ref().map(|log| log.state.clone());
+    let awake = Arc::new(KeepAwake::new(inhibit_sleep));
+    let transfer_awake = awake.clone();
+    let peer = Arc::new(Mutex::new(None));
+    let connected_to = peer.clone();
+    let progress = move |event: &TransferEvent| {
+        if let Some(state) = &log_state {
+            state.lock().unwrap().apply(event);
+        }
+        transfer_awake.apply(event);
+        if json {
+            remember_peer(&connected_to, event);
+            println!("{}", event.to_json());
+        } else {
+            receiver_event(event, log_state.is_none(), verbose, stats)
         }
-        _ => return Err(anyhow::anyhow!("Expected Metadata message")),
     };
     
-    // Send ack
-    let ack = Message::Ack;
-    conn.send(&ack.to_bytes()?).await?;
-    
-    // Determine output path
-    let output_path = output.unwrap_or_else(|| std::path::PathBuf::from(&filename));
+    // The first Ctrl-C stops cleanly, keeping what arrived for --resume; a second one doesn't wait
+    let cancel = CancellationToken::new();
+    let interrupt = cancel.clone();
+    let interrupted_peer = peer.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if interrupt.is_cancelled() {
+                if json {
+                    exit_as_json(&Cancelled.into(), interrupted_peer.lock().unwrap().as_deref());
+                }
+                std::process::exit(EXIT_CANCELLED);
+            }
+            interrupt.cancel();
+        }
+    });
     
-    // Create file writer
-    let mut writer = FileWriter::new(&output_path, file_size)?;
-    println!("Receiving file...");
-    let start_time = Instant::now();
+    let pipe_to = options.pipe_to.clone();
+    let result = zap::receive(options, Some(Arc::new(progress)), cancel).await;
+    awake.release();
+    if let Some(log) = headless {
+        log.finish(&result).await;
+    }
+    if let (true, Err(e)) = (json, &result) {
+        exit_as_json(e, peer.lock().unwrap().as_deref());
+    }
+    if result.as_ref().is_err_and(|e| e.is::<Cancelled>()) {
+        std::process::exit(EXIT_CANCELLED);
+    }
+    exit_if_too_old(&result);
+    let saved_to = result?;
+    match &pipe_to {
+        Some(command) if json => println!("{}", serde_json::json!({ "event": "piped", "command": command })),
+        None if json => println!("{}", serde_json::json!({ "event": "saved", "path": saved_to })),
+        Some(command) => println!("Piped into: {}", command),
+        None => println!("File saved to: {}", saved_to.display()),
+    }
     
-    // Receive chunks
-    loop {
-        let encrypted_chunk = conn.receive().await?;
-        let chunk_bytes = cipher.decrypt(&encrypted_chunk)?;
-        let chunk_msg = Message::from_bytes(&chunk_bytes)?;
-        
-        match chunk_msg {
-            Message::Chunk { data, .. } => {
-                writer.write_chunk(&data)?;
-                
-                // Progress update
-                if !no_tui {
-                    let elapsed = start_time.elapsed().as_secs_f64();
-                    let speed = if elapsed > 0.0 {
-                        writer.bytes_written() as f64 / elapsed
-                    } else {
-                        0.0
-                    };
-                    tui::print_progress(
-                        &filename,
-                        writer.bytes_written(),
-                        file_size,
-                        speed,
-                    );
-                }
+    Ok(pipe_to.is_none().then_some(saved_to))
+}
+
+/// The history `--history` names, the usual one without it, or none for "off"
+fn open_history(path: Option<&Path>) -> Option<transfer::History> {
+    match path {
+        Some(path) if path == Path::new("off") => None,
+        Some(path) => Some(transfer::History::at(path)),
+        None => transfer::History::default_path().map(transfer::History::at),
+    }
+}
+
+/// Add the file at `path` to the history with its BLAKE3, read afresh, for `zap verify --from-history`
+///
+/// The transfer went through either way, so a history that can't be written is only a warning.
+async fn remember(history: Option<&transfer::History>, direction: transfer::Direction, path: &Path) {
+    let Some(history) = history else {
+        return;
+    };
+    let recorded = async {
+        let size = std::fs::metadata(path)?.len();
+        let checksum = transfer::checksum_file(path, crypto::ChecksumAlgorithm::Blake3, |_, _| {}).await?;
+        history.record(&transfer::HistoryEntry::new(direction, path, size, &checksum)?)
+    };
+    if let Err(e) = recorded.await {
+        eprintln!("Warning: couldn't add {} to the history: {}", path.display(), e);
+    }
+}
+
+/// Print what the receiver is doing; `interactive` draws the progress line
+fn receiver_event(event: &TransferEvent, interactive: bool, verbose: bool, stats: bool) {
+    match event {
+        TransferEvent::Connected { peer } => println!("{} Connected to {}", glyphs().check, peer),
+        TransferEvent::Handshake { session } => {
+            if let Some(warning) = tui::post_quantum_fallback(session) {
+                println!("{} {}", glyphs().warning, warning);
+            }
+            println!("{} {}", glyphs().check, tui::handshake_status(session));
+        }
+        TransferEvent::TlsVerified { ours, peer } => {
+            if verbose {
+                println!("{} {}", glyphs().check, tui::tls_status(ours, peer));
+            }
+        }
+        TransferEvent::Resuming { chunk } => println!("Resuming from chunk {}", chunk),
+        TransferEvent::Metadata { filename, size } => {
+            println!("{} Metadata received (encrypted)", glyphs().check);
+            println!("File: {} ({} bytes)", filename, size);
+            println!("Receiving file...");
+        }
+        TransferEvent::Progress { filename, transferred, total, speed } => {
+            if interactive {
+                tui::print_progress(filename, *transferred, *total, *speed);
+            }
+        }
+        TransferEvent::Scheduled { starts_in } => {
+            if interactive {
+                tui::print_scheduled(*starts_in);
+            }
+        }
+        TransferEvent::Preparing { detail } => {
+            if interactive {
+                tui::print_preparing(detail);
+            }
+        }
+        TransferEvent::Hashing { files_done, total_files } => {
+            if interactive {
+                tui::print_hashing(*files_done, *total_files);
+            }
+        }
+        TransferEvent::Listening { .. }
+        | TransferEvent::PortBusy { .. }
+        | TransferEvent::Reannounced { .. }
+        | TransferEvent::Rejected { .. }
+        | TransferEvent::Archiving { .. }
+        | TransferEvent::ChunkSize { .. }
+        | TransferEvent::PeerLimited { .. }
+        | TransferEvent::PathLimited { .. }
+        | TransferEvent::Stored { .. }
+        | TransferEvent::Deferred => {}
+        TransferEvent::Tightened { path } => println!("{} {}", glyphs().warning, fsutil::tightened_warning(path)),
+        TransferEvent::Hint { hint } => {
+            println!();
+            println!("{}", highlight(&hint.summary));
+            for step in &hint.steps {
+                println!("  {} {}", glyphs().dash, step);
+            }
+        }
+        TransferEvent::Conflicts { resolved } => {
+            println!();
+            println!("{} files clashed with existing ones:", resolved.len());
+            for (path, resolution) in resolved {
+                println!("  {}: {}", path.display(), resolution);
+            }
+        }
+        TransferEvent::MetadataWarnings { warnings } => {
+            println!();
+            println!("{} {} files arrived without their modification time, permissions or hard links:", glyphs().warning, warnings.len());
+            for warning in warnings {
+                println!("  {}", warning);
             }
-            Message::Complete => {
-                writer.finalize()?;
+            println!("(--strict-metadata makes this an error)");
+        }
+        TransferEvent::Skipped { files } => {
+            println!();
+            println!("{} {} files were left out:", glyphs().warning, files.len());
+            for file in files {
+                println!("  {}", file);
+            }
+        }
+        TransferEvent::Synced { sent, unchanged, skipped, remote_only, deleted } => {
+            println!();
+            println!("{} Synced: {} files fetched, {} already up to date", glyphs().check, sent, unchanged);
+            if *skipped > 0 {
+                println!("{} {} files couldn't be written here (listed above)", glyphs().warning, skipped);
+            }
+            if *remote_only > 0 {
+                println!("{} files aren't in the sender's folder; {} of them were deleted", remote_only, deleted);
+            }
+        }
+        TransferEvent::Delta { reused, total } => {
+            println!();
+            println!("{} Rebuilt from the existing copy: {} of {} bytes were already here", glyphs().check, reused, total);
+        }
+        TransferEvent::Receipt { receipt, .. } => {
+            println!();
+            match &receipt.signature {
+                Some(signature) => println!("{} Checked the file and sent a receipt signed by {}", glyphs().check, signature.public_key),
+                None => println!("{} Checked the file and sent an unsigned receipt (--identity signs it)", glyphs().check),
+            }
+        }
+        TransferEvent::ReceiverCancelled { transferred, total, resumable } => {
+            println!();
+            let at = tui::cancelled_at(*transferred, *total);
+            if *resumable {
+                println!("{} Cancelled at {} {} run the same command with --resume to pick up from there", glyphs().warning, at, glyphs().dash);
+            } else {
+                println!("{} Cancelled at {}", glyphs().warning, at);
+            }
+        }
+        TransferEvent::Memory { limit, peak } => {
+            if stats {
                 println!();
-                println!("✓ Transfer complete!");
-                println!("File saved to: {}", output_path.display());
-                break;
+                println!("Memory: {:.1} MB at most, of {:.1} MB allowed", *peak as f64 / 1_048_576.0, *limit as f64 / 1_048_576.0);
             }
-            Message::Error { message } => {
-                return Err(anyhow::anyhow!("Transfer error: {}", message));
+        }
+        TransferEvent::Timings { timings } => {
+            if stats {
+                println!();
+                println!("Time spent:");
+                for line in timings.to_string().lines() {
+                    println!("  {}", line);
+                }
             }
-            _ => return Err(anyhow::anyhow!("Unexpected message type")),
+        }
+        TransferEvent::Complete => {
+            println!();
+            println!("{} Transfer complete!", glyphs().check);
         }
     }
-    
-    Ok(())
 }
+
+/// Show the warning about a file that looks like a program and ask whether to take it
+fn confirm_executable(warning: &str) -> bool {
+    println!();
+    println!("{}", caution(format!("{} {}", glyphs().warning, warning)));
+    Prompt::terminal().confirm("accept-executable", &[], false).unwrap_or(false)
+}
+
+/// Describe the offered file and ask whether to take it
+fn confirm_offer(description: &str) -> bool {
+    println!();
+    println!("Sender is offering {}", description);
+    Prompt::terminal().confirm("accept-offer", &[], true).unwrap_or(false)
+}
+
+/// Show what the connection measured and, with `ask`, whether to send anyway
+fn confirm_estimate(estimate: &str, ask: bool, passthrough: bool) -> bool {
+    status!(passthrough);
+    status!(passthrough, "{}", estimate);
+    if !ask {
+        return true;
+    }
+    Prompt::terminal().confirm("send-anyway", &[], true).unwrap_or(false)
+}
+
+/// List the files only the receiver has and, with `ask`, whether to delete them
+///
+/// Nobody can answer without a terminal, so then only `--yes` deletes them.
+fn confirm_delete(remote_only: &str, ask: bool, yes: bool, quiet: bool) -> bool {
+    status!(quiet);
+    status!(quiet, "{}", remote_only);
+    if !ask {
+        if !yes {
+            status!(quiet, "Keeping them: there's no terminal to confirm on (--yes deletes them)");
+        }
+        return yes;
+    }
+    Prompt::terminal().confirm("delete-remote-only", &[], false).unwrap_or(false)
+}
+
+/// Ask on the terminal what to do about a received file that already exists
+fn ask_about_conflict(path: &Path) -> ConflictAnswer {
+    println!();
+    let path = path.display().to_string();
+    let choice = Prompt::terminal()
+        .choose("conflict", &[("path", &path)], "conflict-answers", 0)
+        .unwrap_or(Choice { index: 0, for_all: false });
+    let strategies = [ConflictStrategy::Skip, ConflictStrategy::Overwrite, ConflictStrategy::Rename, ConflictStrategy::Newer];
+    ConflictAnswer {
+        strategy: strategies[choice.index],
+        apply_to_all: choice.for_all,
+    }
+}
+
@@ -0,0 +1,82 @@
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Ranges `--lan-only` lets in: RFC 1918 and unique local addresses, link-local and loopback
+const LAN_RANGES: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "fe80::/10",
+    "::1/128",
+];
+
+/// Parse an `--allow` range like `192.168.1.0/24` or `fd00::/8`; a bare address allows just itself
+pub fn parse_cidr(text: &str) -> Result<IpNet> {
+    text.parse::<IpNet>()
+        .or_else(|_| text.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow!("Expected an address range like 192.168.1.0/24, got {}", text))
+}
+
+/// Addresses the listening sender will talk to
+///
+/// Checked as each connection is accepted, before any protocol bytes go
+/// either way. An empty list lets anyone in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowList {
+    nets: Vec<IpNet>,
+}
+
+impl AllowList {
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        Self { nets }
+    }
+    
+    /// Only private, link-local and loopback addresses
+    pub fn lan_only() -> Self {
+        Self::new(LAN_RANGES.iter().map(|range| range.parse().expect("valid range")).collect())
+    }
+    
+    /// Add the ranges from `other`
+    pub fn extend(&mut self, other: AllowList) {
+        self.nets.extend(other.nets);
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+    
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        // An IPv4 peer on a dual-stack socket shows up as ::ffff:a.b.c.d
+        let addr = addr.to_canonical();
+        self.nets.is_empty() || self.nets.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_allow_list_matches_ranges() {
+        let allow = AllowList::new(vec![parse_cidr("192.168.1.0/24").unwrap(), parse_cidr("2001:db8::1").unwrap()]);
+        assert!(allow.allows("192.168.1.77".parse().unwrap()));
+        assert!(allow.allows("::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!allow.allows("192.168.2.1".parse().unwrap()));
+        assert!(allow.allows("2001:db8::1".parse().unwrap()));
+        assert!(!allow.allows("2001:db8::2".parse().unwrap()));
+        assert!(AllowList::default().allows("8.8.8.8".parse().unwrap()));
+        assert!(parse_cidr("192.168.1.0/33").is_err());
+        
+        let lan = AllowList::lan_only();
+        for addr in ["10.1.2.3", "172.31.0.1", "192.168.0.10", "169.254.3.4", "127.0.0.1", "fd12::1", "fe80::1", "::1"] {
+            assert!(lan.allows(addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in ["172.32.0.1", "8.8.8.8", "2001:4860::8888"] {
+            assert!(!lan.allows(addr.parse().unwrap()), "{}", addr);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+/// Why a peer address couldn't be used
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EndpointError {
+    #[error("Address is empty")]
+    Empty,
+    #[error("Address {0:?} has no host")]
+    MissingHost(String),
+    #[error("Invalid host {0:?}")]
+    InvalidHost(String),
+    #[error("Invalid port {0:?} (must be 1-65535)")]
+    InvalidPort(String),
+    #[error("Address {0:?} has a path; give just the host and an optional port")]
+    Path(String),
+}
+
+/// A peer's address as typed: a hostname or IP address, with or without a port
+///
+/// Accepts `host`, `host:port`, `[v6]:port` and bare IPv6 literals; trailing
+/// slashes are dropped. A URL scheme pasted in front (`http://host:9999`) is
+/// stripped rather than refused, and `note` says so, since zap speaks its own
+/// protocol whatever the prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    /// IPv6 literals are kept without their brackets
+    host: String,
+    port: Option<u16>,
+    /// The scheme that was stripped, as typed
+    scheme: Option<String>,
+}
+
+impl Endpoint {
+    /// The host, without brackets for IPv6
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    
+    /// The port, if one was given
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+    
+    /// Where to connect: the endpoint's own port, or `default` when it has none
+    pub fn target(&self, default: u16) -> (&str, u16) {
+        (&self.host, self.port.unwrap_or(default))
+    }
+    
+    /// What was ignored while parsing, worth telling the user
+    pub fn note(&self) -> Option<String> {
+        self.scheme.as_ref().map(|scheme| format!("Ignoring {}:// in front of {}; zap connects to it directly", scheme, self))
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+    
+    fn from_str(endpoint: &str) -> Result<Self, Self::Err> {
+        let endpoint = endpoint.trim();
+        if endpoint.is_empty() {
+            return Err(EndpointError::Empty);
+        }
+        
+        let (scheme, rest) = match endpoint.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_string()), rest),
+            None => (None, endpoint),
+        };
+        let authority = rest.trim_end_matches('/');
+        if authority.contains('/') {
+            return Err(EndpointError::Path(endpoint.to_string()));
+        }
+        let (host, port) = split_host_port(authority)?;
+        if host.is_empty() {
+            return Err(EndpointError::MissingHost(endpoint.to_string()));
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_');
+        if host.parse::<Ipv6Addr>().is_err() && !host.chars().all(valid) {
+            return Err(EndpointError::InvalidHost(host.to_string()));
+        }
+        let port = match port {
+            Some(port) => Some(port.parse().ok().filter(|&port| port != 0).ok_or_else(|| EndpointError::InvalidPort(port.to_string()))?),
+            None => None,
+        };
+        
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            scheme,
+        })
+    }
+}
+
+/// Split `host[:port]`, where the host may be a bracketed IPv6 literal
+fn split_host_port(authority: &str) -> Result<(&str, Option<&str>), EndpointError> {
+    if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed.split_once(']').ok_or_else(|| EndpointError::InvalidHost(authority.to_string()))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(EndpointError::InvalidHost(host.to_string()));
+        }
+        return match after {
+            "" => Ok((host, None)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => Ok((host, Some(port))),
+                None => Err(EndpointError::InvalidHost(authority.to_string())),
+            },
+        };
+    }
+    // An unbracketed IPv6 literal can't carry a port
+    if authority.parse::<Ipv6Addr>().is_ok() {
+        return Ok((authority, None));
+    }
+    Ok(match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    })
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parts(endpoint: &str) -> (String, Option<u16>) {
+        let endpoint: Endpoint = endpoint.parse().unwrap();
+        assert_eq!(endpoint.note(), None);
+        (endpoint.host().to_string(), endpoint.port())
+    }
+    
+    fn err(endpoint: &str) -> EndpointError {
+        endpoint.parse::<Endpoint>().unwrap_err()
+    }
+    
+    #[test]
+    fn test_accepted_forms() {
+        let owned = |host: &str, port| (host.to_string(), port);
+        assert_eq!(parts("laptop.local"), owned("laptop.local", None));
+        assert_eq!(parts("laptop.local:9999"), owned("laptop.local", Some(9999)));
+        assert_eq!(parts("  192.168.1.20:9000 "), owned("192.168.1.20", Some(9000)));
+        assert_eq!(parts("192.168.1.20"), owned("192.168.1.20", None));
+        assert_eq!(parts("localhost"), owned("localhost", None));
+        assert_eq!(parts("::1"), owned("::1", None));
+        assert_eq!(parts("fe80::1"), owned("fe80::1", None));
+        assert_eq!(parts("[::1]"), owned("::1", None));
+        assert_eq!(parts("[::1]:9999"), owned("::1", Some(9999)));
+        assert_eq!(parts("[2001:db8::7]:65535"), owned("2001:db8::7", Some(65535)));
+        assert_eq!(parts("build_box-2:1"), owned("build_box-2", Some(1)));
+        assert_eq!(parts("laptop.local:9999/"), owned("laptop.local", Some(9999)));
+        assert_eq!(parts("[::1]:9999//"), owned("::1", Some(9999)));
+    }
+    
+    #[test]
+    fn test_display_round_trips() {
+        for endpoint in ["laptop.local", "laptop.local:9999", "192.168.1.20:9000", "[::1]", "[::1]:9999"] {
+            assert_eq!(endpoint.parse::<Endpoint>().unwrap().to_string(), endpoint);
+        }
+        assert_eq!("::1".parse::<Endpoint>().unwrap().to_string(), "[::1]");
+        assert_eq!("::1".parse::<Endpoint>().unwrap().target(9999), ("::1", 9999));
+        assert_eq!("[::1]:7000".parse::<Endpoint>().unwrap().target(9999), ("::1", 7000));
+    }
+    
+    #[test]
+    fn test_pasted_scheme_is_stripped_with_a_note() {
+        let endpoint: Endpoint = "http://192.168.1.20:9999/".parse().unwrap();
+        assert_eq!((endpoint.host(), endpoint.port()), ("192.168.1.20", Some(9999)));
+        assert_eq!(endpoint.note().unwrap(), "Ignoring http:// in front of 192.168.1.20:9999; zap connects to it directly");
+        
+        let endpoint: Endpoint = "HTTPS://[::1]".parse().unwrap();
+        assert_eq!(endpoint.host(), "::1");
+        assert_eq!(endpoint.note().unwrap(), "Ignoring HTTPS:// in front of [::1]; zap connects to it directly");
+        
+        assert_eq!(err("http://"), EndpointError::MissingHost("http://".into()));
+        assert_eq!(err("http://laptop.local/files"), EndpointError::Path("http://laptop.local/files".into()));
+    }
+    
+    #[test]
+    fn test_rejected_forms() {
+        assert_eq!(err(""), EndpointError::Empty);
+        assert_eq!(err("  "), EndpointError::Empty);
+        assert_eq!(err(":9999"), EndpointError::MissingHost(":9999".into()));
+        assert_eq!(err("/"), EndpointError::MissingHost("/".into()));
+        assert_eq!(err("laptop.local/files"), EndpointError::Path("laptop.local/files".into()));
+        assert_eq!(err("my laptop"), EndpointError::InvalidHost("my laptop".into()));
+        assert_eq!(err("user@laptop.local"), EndpointError::InvalidHost("user@laptop.local".into()));
+        assert_eq!(err("[::1"), EndpointError::InvalidHost("[::1".into()));
+        assert_eq!(err("[laptop]:9999"), EndpointError::InvalidHost("laptop".into()));
+        assert_eq!(err("[::1]9999"), EndpointError::InvalidHost("[::1]9999".into()));
+        assert_eq!(err("1:2:3"), EndpointError::InvalidHost("1:2".into()));
+        assert_eq!(err("laptop.local:"), EndpointError::InvalidPort("".into()));
+        assert_eq!(err("laptop.local:0"), EndpointError::InvalidPort("0".into()));
+        assert_eq!(err("laptop.local:65536"), EndpointError::InvalidPort("65536".into()));
+        assert_eq!(err("laptop.local:ssh"), EndpointError::InvalidPort("ssh".into()));
+        assert_eq!(err("[::1]:99999"), EndpointError::InvalidPort("99999".into()));
+        assert_eq!(
+            err("laptop.local:0").to_string(),
+            "Invalid port \"0\" (must be 1-65535)"
+        );
+    }
+}
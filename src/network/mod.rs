@@ -1,21 +1,219 @@
 use anyhow::{anyhow, Result};
-use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::fmt;
+use std::io;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 
-const DEFAULT_PORT: u16 = 9999;
+mod allow;
+mod endpoint;
+pub mod tls;
+
+use crate::protocol::Message;
+
+pub use allow::{parse_cidr, AllowList};
+pub use endpoint::{Endpoint, EndpointError};
+pub use tls::{TlsIdentity, TlsRole};
+
+pub const DEFAULT_PORT: u16 = 9999;
 const MESSAGE_SIZE_BYTES: usize = 4;
 
+/// Biggest message read off a connection; peers are told it in the handshake
+pub const MAX_MESSAGE_SIZE: usize = 100 * 1024 * 1024;
+
+/// Why a connection refuses to carry on after a message was cut off partway
+pub const DESYNCHRONIZED: &str = "Connection desynchronized: an earlier message was cut off partway";
+
+/// Why raw bytes can't be read while `receive` has only part of a message
+pub const MESSAGE_PARTWAY: &str = "A message is partway received; receive the rest before reading raw bytes";
+
+/// How long a given port held by an instance that's shutting down gets to come free
+pub const PORT_BUSY_WAIT: Duration = Duration::from_secs(5);
+
+/// Between tries at binding a port that's still held
+const PORT_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a zap on a busy port gets to say hello
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes an in-memory transport buffers in each direction
+pub const MEMORY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Head start each address gets before the next one is tried too (RFC 6555)
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Which way a message went, for `Connection::enable_protocol_debug`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Send,
+    Recv,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Send => write!(f, "sent"),
+            Direction::Recv => write!(f, "received"),
+        }
+    }
+}
+
+type LogFn = dyn Fn(Direction, &str) + Send + Sync;
+
+/// Called with each message a connection sends or receives, as pretty-printed JSON
+///
+/// Release builds keep the type so options can carry it, but never call it.
+#[derive(Clone)]
+#[cfg_attr(not(debug_assertions), allow(dead_code))]
+pub struct ProtocolLog(Arc<LogFn>);
+
+impl ProtocolLog {
+    pub fn new(log_fn: impl Fn(Direction, &str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(log_fn))
+    }
+}
+
+impl fmt::Debug for ProtocolLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProtocolLog")
+    }
+}
+
+/// How long a connection may wait on a dead peer before giving up
+///
+/// Each is how long the connection may sit idle in the middle of a message,
+/// with nothing arriving or nothing going out, not how long a whole message
+/// may take. Waiting for a message that's allowed to take its time to start
+/// (see `Connection::wait_for_message`) isn't limited at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketTimeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+/// The `--debug-protocol` log: each message on stderr as it goes by
+pub fn log_protocol_to_stderr(direction: Direction, json: &str) {
+    eprintln!("[protocol] {}: {}", direction, json);
+}
+
+/// IP version of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrFamily {
+    V4,
+    V6,
+}
+
+impl AddrFamily {
+    pub fn of(addr: &SocketAddr) -> Self {
+        if addr.is_ipv4() {
+            Self::V4
+        } else {
+            Self::V6
+        }
+    }
+}
+
+/// What a `Connection` carries its bytes over
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+    
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Plain(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
 /// Network connection wrapper
+///
+/// Also usable as a plain byte stream through `AsyncRead` and `AsyncWrite`,
+/// e.g. with `tokio_util::codec`. `send` and `receive` frame messages with the
+/// same 4-byte big-endian length prefix as `LengthDelimitedCodec`'s defaults.
+/// With `start_tls` it all goes over TLS instead.
 pub struct Connection {
-    stream: TcpStream,
+    stream: Stream,
     peer_addr: SocketAddr,
+    local_port: u16,
+    /// Which IP version the connection ended up using
+    addr_family: AddrFamily,
+    timeouts: SocketTimeouts,
+    /// A `send` stopped partway through its frame; see `FrameGuard`
+    write_torn: bool,
+    /// What's arrived of a message a cancelled `receive` didn't finish
+    reader: FrameReader,
+    #[cfg(debug_assertions)]
+    protocol_log: Option<ProtocolLog>,
 }
 
 impl Connection {
-    /// Create a new connection from a TCP stream
-    pub fn new(stream: TcpStream, peer_addr: SocketAddr) -> Self {
-        Self { stream, peer_addr }
+    /// Create a new connection from a TCP stream, without timeouts until `set_timeouts`
+    pub fn new(stream: TcpStream, peer_addr: SocketAddr, local_port: u16) -> Self {
+        Self {
+            stream: Stream::Plain(stream),
+            peer_addr,
+            local_port,
+            addr_family: AddrFamily::of(&peer_addr),
+            timeouts: SocketTimeouts::default(),
+            write_torn: false,
+            reader: FrameReader::default(),
+            #[cfg(debug_assertions)]
+            protocol_log: None,
+        }
+    }
+    
+    /// Give up on `send` and `receive` when the peer stalls partway for this long
+    ///
+    /// Tokio never blocks in a read or write, so `SO_RCVTIMEO` and
+    /// `SO_SNDTIMEO` would never fire; each step of a message is timed instead.
+    pub fn set_timeouts(&mut self, timeouts: SocketTimeouts) {
+        self.timeouts = timeouts;
     }
     
     /// Get the peer address
@@ -23,78 +221,556 @@ impl Connection {
         self.peer_addr
     }
     
+    /// Get our side's port (the listening port, for the sender)
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+    
+    /// Get the IP version in use
+    pub fn addr_family(&self) -> AddrFamily {
+        self.addr_family
+    }
+    
+    /// Run a TLS handshake as `role`, showing `identity`'s certificate, and carry on over TLS
+    ///
+    /// Gives up after the read timeout, like a message would.
+    pub async fn start_tls(mut self, role: TlsRole, identity: &TlsIdentity) -> Result<Self> {
+        let Stream::Plain(stream) = self.stream else {
+            return Err(anyhow!("The connection is already using TLS"));
+        };
+        let handshake = async { tls::handshake(stream, role, identity).await.map_err(|e| anyhow!("TLS handshake failed: {}", e)) };
+        self.stream = Stream::Tls(Box::new(with_timeout(self.timeouts.read, "TLS handshake", handshake).await?));
+        Ok(self)
+    }
+    
+    /// SHA-256 of the certificate the peer showed, on a TLS connection
+    pub fn peer_cert_fingerprint(&self) -> Option<[u8; 32]> {
+        let Stream::Tls(stream) = &self.stream else {
+            return None;
+        };
+        let cert = stream.get_ref().1.peer_certificates()?.first()?;
+        Some(tls::fingerprint(cert))
+    }
+    
+    /// Pass every message sent or received from now on to `log_fn`, as JSON
+    ///
+    /// Encrypted messages can't be read at this level, so only their size is
+    /// logged. Raw bytes from `send_raw` and `receive_raw` aren't logged.
+    /// Only in debug builds; release builds leave the logging out altogether.
+    #[cfg(debug_assertions)]
+    pub fn enable_protocol_debug(&mut self, log_fn: ProtocolLog) {
+        self.protocol_log = Some(log_fn);
+    }
+    
     /// Send a message (length-prefixed)
+    ///
+    /// Safe to cancel before any of it is written; cancelled partway, the
+    /// connection refuses every later write instead of sending a torn frame.
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
-        let len = data.len() as u32;
-        self.stream.write_all(&len.to_be_bytes()).await?;
-        self.stream.write_all(data).await?;
-        self.stream.flush().await?;
+        intact(self.write_torn)?;
+        #[cfg(debug_assertions)]
+        self.log_message(Direction::Send, data);
+        let mut stream = FrameGuard::new(&mut self.stream, &mut self.write_torn);
+        write_message_within(&mut stream, data, self.timeouts.write).await?;
+        self.write_torn = false;
         Ok(())
     }
     
     /// Receive a message (length-prefixed)
+    ///
+    /// Safe to cancel at any point: whatever part of the message has arrived
+    /// is kept, and the next call carries on from it.
     pub async fn receive(&mut self) -> Result<Vec<u8>> {
-        let mut len_bytes = [0u8; MESSAGE_SIZE_BYTES];
-        self.stream.read_exact(&mut len_bytes).await?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-        
-        if len > 100 * 1024 * 1024 {
-            return Err(anyhow!("Message too large: {} bytes", len));
+        self.receive_within(false).await
+    }
+    
+    /// Like `receive`, for a message the peer may be a long time starting
+    ///
+    /// For the waits that are part of a transfer, like the user deciding on
+    /// an offer or a scheduled start: the read timeout only applies once the
+    /// message has begun to arrive.
+    pub async fn wait_for_message(&mut self) -> Result<Vec<u8>> {
+        self.receive_within(true).await
+    }
+    
+    async fn receive_within(&mut self, patient: bool) -> Result<Vec<u8>> {
+        let data = self.reader.read_within(&mut self.stream, self.timeouts.read, patient).await?;
+        #[cfg(debug_assertions)]
+        self.log_message(Direction::Recv, &data);
+        Ok(data)
+    }
+    
+    #[cfg(debug_assertions)]
+    fn log_message(&self, direction: Direction, data: &[u8]) {
+        let Some(log_fn) = &self.protocol_log else {
+            return;
+        };
+        // Ciphertext now and then decodes as some message by chance, but hardly
+        // ever one that takes up all of it (sets come back in another order, so
+        // only the length is compared)
+        let json = Message::from_bytes(data)
+            .ok()
+            .filter(|message| message.to_bytes().is_ok_and(|bytes| bytes.len() == data.len()))
+            .and_then(|message| message.to_json_debug().ok());
+        match json {
+            Some(json) => (log_fn.0)(direction, &json),
+            None => (log_fn.0)(direction, &format!("<encrypted, {} bytes>", data.len())),
         }
-        
-        let mut buffer = vec![0u8; len];
-        self.stream.read_exact(&mut buffer).await?;
-        Ok(buffer)
     }
     
     /// Send raw bytes (for file chunks)
     pub async fn send_raw(&mut self, data: &[u8]) -> Result<()> {
+        intact(self.write_torn)?;
         self.stream.write_all(data).await?;
         Ok(())
     }
     
     /// Receive raw bytes (for file chunks)
     pub async fn receive_raw(&mut self, size: usize) -> Result<Vec<u8>> {
+        if self.reader.partway() {
+            return Err(anyhow!(MESSAGE_PARTWAY));
+        }
         let mut buffer = vec![0u8; size];
         self.stream.read_exact(&mut buffer).await?;
         Ok(buffer)
     }
 }
 
+impl AsyncRead for Connection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.reader.partway() {
+            return Poll::Ready(Err(io::Error::other(MESSAGE_PARTWAY)));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.write_torn {
+            return Poll::Ready(Err(io::Error::other(DESYNCHRONIZED)));
+        }
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_torn {
+            return Poll::Ready(Err(io::Error::other(DESYNCHRONIZED)));
+        }
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+    
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+    
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Fail with `DESYNCHRONIZED` if a frame was torn in this direction
+fn intact(torn: bool) -> Result<()> {
+    if torn {
+        return Err(anyhow!(DESYNCHRONIZED));
+    }
+    Ok(())
+}
+
+/// A stream that notes when a frame has started to go out through it
+///
+/// `torn` is set by the first byte written and only cleared by the caller
+/// once the whole frame is through. A framed send that's dropped partway (a
+/// timeout, a losing `select!` branch, Ctrl-C) or fails partway leaves it
+/// set, so the half frame never has anything appended to it that the peer
+/// would misread as a length. One dropped before any byte moved leaves the
+/// stream as it was. Reads don't need it; see `FrameReader`.
+struct FrameGuard<'a, S> {
+    stream: &'a mut S,
+    torn: &'a mut bool,
+}
+
+impl<'a, S> FrameGuard<'a, S> {
+    fn new(stream: &'a mut S, torn: &'a mut bool) -> Self {
+        Self { stream, torn }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FrameGuard<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.stream).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(written)) if written > 0) {
+            *self.torn = true;
+        }
+        poll
+    }
+    
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Reads length-prefixed messages, keeping whatever part of one has arrived between calls
+///
+/// `read` is safe to cancel anywhere: the bytes it already took from the
+/// stream stay here and the next call carries on after them, so it can lose
+/// a `select!` or a timeout without losing its place in the stream. It never
+/// reads past the end of the message it's on.
+#[derive(Debug, Default)]
+pub(crate) struct FrameReader {
+    prefix: [u8; MESSAGE_SIZE_BYTES],
+    prefix_read: usize,
+    /// Sized once the prefix is in
+    body: Option<Vec<u8>>,
+    body_read: usize,
+}
+
+impl FrameReader {
+    /// Read the rest of the current message from `stream`
+    pub(crate) async fn read<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> Result<Vec<u8>> {
+        self.read_within(stream, None, false).await
+    }
+    
+    /// Like `read`, failing once nothing has arrived for `idle`
+    ///
+    /// With `patient`, the wait for the message's first byte isn't limited.
+    pub(crate) async fn read_within<R: AsyncRead + Unpin>(&mut self, stream: &mut R, idle: Option<Duration>, patient: bool) -> Result<Vec<u8>> {
+        while self.prefix_read < MESSAGE_SIZE_BYTES {
+            let limit = if patient && self.prefix_read == 0 { None } else { idle };
+            match with_timeout(limit, "Read", read_some(stream, &mut self.prefix[self.prefix_read..])).await? {
+                0 => return Err(early_eof()),
+                read => self.prefix_read += read,
+            }
+        }
+        let len = u32::from_be_bytes(self.prefix) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("Message too large: {} bytes", len));
+        }
+        let body = self.body.get_or_insert_with(|| vec![0u8; len]);
+        while self.body_read < len {
+            match with_timeout(idle, "Read", read_some(stream, &mut body[self.body_read..])).await? {
+                0 => return Err(early_eof()),
+                read => self.body_read += read,
+            }
+        }
+        self.prefix_read = 0;
+        self.body_read = 0;
+        Ok(self.body.take().unwrap_or_default())
+    }
+    
+    /// Whether part of a message has been read and the rest hasn't
+    pub(crate) fn partway(&self) -> bool {
+        self.prefix_read > 0
+    }
+}
+
+async fn read_some<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut [u8]) -> Result<usize> {
+    Ok(stream.read(buf).await?)
+}
+
+/// What `read_exact` gives for a stream that ends partway, so callers can tell a hang-up either way
+fn early_eof() -> anyhow::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "early eof").into()
+}
+
+/// Run a socket operation, failing if it takes longer than `timeout`
+async fn with_timeout<T>(timeout: Option<Duration>, what: &str, operation: impl Future<Output = Result<T>>) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return operation.await;
+    };
+    match tokio::time::timeout(timeout, operation).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} timed out after {}", what, humantime::format_duration(timeout)),
+        ).into()),
+    }
+}
+
+/// Write one length-prefixed message to a byte stream
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8]) -> Result<()> {
+    write_message_within(stream, data, None).await
+}
+
+/// Like `write_message`, failing once nothing has gone out for `idle`
+async fn write_message_within<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8], idle: Option<Duration>) -> Result<()> {
+    let len = (data.len() as u32).to_be_bytes();
+    for part in [&len[..], data] {
+        let mut written = 0;
+        while written < part.len() {
+            match with_timeout(idle, "Write", async { Ok(stream.write(&part[written..]).await?) }).await? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                wrote => written += wrote,
+            }
+        }
+    }
+    with_timeout(idle, "Write", async { Ok(stream.flush().await?) }).await
+}
+
+/// Read one length-prefixed message from a byte stream
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut len_bytes = [0u8; MESSAGE_SIZE_BYTES];
+    stream.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Message too large: {} bytes", len));
+    }
+    
+    let mut buffer = vec![0u8; len];
+    stream.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+/// Bind the sender's listener
+///
+/// With no port given, `DEFAULT_PORT` is tried first and a port picked by the
+/// OS is used if it's taken; check `local_addr()` for the port actually bound.
+pub async fn bind(port: Option<u16>) -> Result<TcpListener> {
+    bind_with(port, |_| {}).await
+}
+
+/// Like `bind`, calling `on_busy` if the given port is taken and waited on
+///
+/// A restarted sender may find its port still held by the instance before
+/// it, so it's tried again for up to `PORT_BUSY_WAIT`. A zap that is still
+/// listening there, or another program that keeps it, is named in the error
+/// instead of the bare "address in use".
+pub async fn bind_with(port: Option<u16>, on_busy: impl FnOnce(u16)) -> Result<TcpListener> {
+    let Some(port) = port else {
+        return match listen_on(DEFAULT_PORT) {
+            Ok(listener) => Ok(listener),
+            Err(_) => Ok(listen_on(0)?),
+        };
+    };
+    let deadline = tokio::time::Instant::now() + PORT_BUSY_WAIT;
+    let mut on_busy = Some(on_busy);
+    loop {
+        match listen_on(port) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if zap_listening(port).await {
+                    return Err(anyhow!("Another zap is already listening on port {}; stop it or use another --port", port));
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(anyhow!("Port {} is in use by another program; use another --port", port));
+                }
+                if let Some(on_busy) = on_busy.take() {
+                    on_busy(port);
+                }
+                tokio::time::sleep(PORT_RETRY_INTERVAL).await;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// A listener on every IPv4 interface that can take over a port whose old connections are in TIME_WAIT
+fn listen_on(port: u16) -> io::Result<TcpListener> {
+    let socket = TcpSocket::new_v4()?;
+    // On Windows this would let a port be taken from under a live listener
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind((Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.listen(1024)
+}
+
+/// Whether a zap sender is what holds `port` here: one says hello to anyone who connects
+async fn zap_listening(port: u16) -> bool {
+    let probe = async {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+        let hello = read_message(&mut stream).await?;
+        if !matches!(Message::from_bytes(&hello), Ok(Message::Hello { .. })) {
+            return Ok(false);
+        }
+        // Its capabilities follow; left unread, hanging up would reset the connection under it
+        read_message(&mut stream).await?;
+        anyhow::Ok(true)
+    };
+    matches!(tokio::time::timeout(PORT_PROBE_TIMEOUT, probe).await, Ok(Ok(true)))
+}
+
+/// Wait for the receiver to connect to a listener from `bind`
+pub async fn accept(listener: &TcpListener) -> Result<Connection> {
+    accept_from(listener, &AllowList::default(), |_| {}).await
+}
+
+/// Wait for a connection from an address `allow` lets in
+///
+/// Any other is hung up on straight away, reported to `on_reject`, and the
+/// wait goes on.
+pub async fn accept_from(listener: &TcpListener, allow: &AllowList, mut on_reject: impl FnMut(SocketAddr)) -> Result<Connection> {
+    let local_port = listener.local_addr()?.port();
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if allow.allows(peer_addr.ip()) {
+            return Ok(Connection::new(stream, peer_addr, local_port));
+        }
+        drop(stream);
+        on_reject(peer_addr);
+    }
+}
+
+/// A TCP socket for `addr`'s IP version whose local port other such sockets may bind as well
+///
+/// Lets a relay connection's port be used again to reach the peer directly
+/// while the relay connection is still open. A port with a listener on it
+/// takes `shared_port_socket` instead.
+pub fn reusable_socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    // BSDs and macOS only share a port in use with SO_REUSEPORT
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+    socket.set_reuseport(true)?;
+    Ok(socket)
+}
+
+/// Like `reusable_socket`, for a port that a listener of ours shares with connects
+///
+/// Only SO_REUSEPORT lets sockets bind next to a listener, and it lets any
+/// of our own sockets with it bind the port, so nothing else asks for it.
+pub fn shared_port_socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+    let socket = reusable_socket(addr)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(true)?;
+    Ok(socket)
+}
+
 /// Start a TCP server and wait for a connection
 pub async fn listen(port: Option<u16>) -> Result<Connection> {
-    let port = port.unwrap_or(DEFAULT_PORT);
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await?;
+    let listener = bind_with(port, |port| println!("Waiting for the previous instance's socket on port {} to clear", port)).await?;
     
-    println!("Listening on {}", addr);
+    println!("Listening on {}", listener.local_addr()?);
     
-    let (stream, peer_addr) = listener.accept().await?;
-    Ok(Connection::new(stream, peer_addr))
+    accept(&listener).await
 }
 
-/// Connect to a remote host
-pub async fn connect(host: &str, port: Option<u16>) -> Result<Connection> {
-    let port = port.unwrap_or(DEFAULT_PORT);
-    let addr = format!("{}:{}", host, port);
+/// Connect to a remote host, on the endpoint's own port or else `port` (`DEFAULT_PORT` if neither)
+///
+/// With `happy_eyeballs`, its addresses are raced with `connect_happy_eyeballs`;
+/// without, they're tried one at a time (`--no-happy-eyeballs`).
+pub async fn connect(endpoint: &Endpoint, port: Option<u16>, happy_eyeballs: bool) -> Result<Connection> {
+    let (host, port) = endpoint.target(port.unwrap_or(DEFAULT_PORT));
+    if happy_eyeballs {
+        return connect_happy_eyeballs(host, port).await;
+    }
     
-    let stream = TcpStream::connect(&addr).await?;
+    let stream = TcpStream::connect((host, port)).await?;
     let peer_addr = stream.peer_addr()?;
+    let local_port = stream.local_addr()?.port();
     
-    Ok(Connection::new(stream, peer_addr))
+    Ok(Connection::new(stream, peer_addr, local_port))
 }
 
-/// Discover peers on the local network using mDNS (simplified for MVP)
+/// Connect to whichever of a host's addresses answers first
+///
+/// When the name resolves to both IPv4 and IPv6 addresses the families are
+/// alternated, and each attempt gets `HAPPY_EYEBALLS_DELAY` to itself before
+/// the next starts alongside it, so a broken IPv6 route costs a quarter of a
+/// second rather than a full connect timeout. The losing attempts are dropped.
+pub async fn connect_happy_eyeballs(host: &str, port: u16) -> Result<Connection> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    let (stream, peer_addr) = race(&addrs, HAPPY_EYEBALLS_DELAY, TcpStream::connect).await?;
+    let local_port = stream.local_addr()?.port();
+    Ok(Connection::new(stream, peer_addr, local_port))
+}
+
+/// Alternate address families, starting with whichever the resolver put first
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.iter().copied().partition(|addr| addr.is_ipv4() == first.is_ipv4());
+    let mut other = other.into_iter();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// Try `addrs` in turn with staggered starts; the first to connect wins
+async fn race<T, F, Fut>(addrs: &[SocketAddr], stagger: Duration, connect: F) -> Result<(T, SocketAddr)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut failures = Vec::new();
+    let attempt = |addr: SocketAddr| {
+        let connecting = connect(addr);
+        async move { (addr, connecting.await) }
+    };
+    
+    let Some(first) = pending.next() else {
+        return Err(anyhow!("No addresses to connect to"));
+    };
+    attempts.push(attempt(first));
+    
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => {
+                    failures.push(format!("{}: {}", addr, e));
+                    // No point waiting out the delay behind a failed attempt
+                    if let Some(next) = pending.next() {
+                        attempts.push(attempt(next));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(stagger), if pending.len() > 0 => {
+                if let Some(next) = pending.next() {
+                    attempts.push(attempt(next));
+                }
+            }
+        }
+    }
+    
+    Err(anyhow!("Couldn't connect to any address ({})", failures.join(", ")))
+}
+
+/// This computer's IPv4 address on the interface its traffic leaves by, the one a receiver on the same network would likely reach
+///
+/// Found by pointing a UDP socket at a documentation address, which only
+/// looks the route up; nothing is sent. `None` without a route or an address.
+pub fn lan_address() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Discover peers on the local network using mDNS: not implemented yet, so never finds one
+///
+/// Receivers are pointed at the sender with `--host`, or meet it on a relay.
 pub async fn discover_mdns(_code: &str) -> Result<Option<SocketAddr>> {
-    // For MVP, we'll skip mDNS and require manual connection
-    // In a full implementation, we'd use mdns-sd to advertise and discover
     Ok(None)
 }
 
-/// Advertise this service on mDNS (simplified for MVP)
+/// Advertise this service on mDNS: not implemented yet, so does nothing
+///
+/// Whatever replaces this mustn't broadcast anything the code can be
+/// brute-forced from to the whole network, and has to re-announce its
+/// records before their TTL runs out while the sender waits, the way relay
+/// rooms are re-registered when they expire.
 pub async fn advertise_mdns(_code: &str, _port: u16) -> Result<()> {
-    // For MVP, we'll skip mDNS advertisement
-    // In a full implementation, we'd use mdns-sd to advertise the service
     Ok(())
 }
 
@@ -102,6 +778,10 @@ pub async fn advertise_mdns(_code: &str, _port: u16) -> Result<()> {
 mod tests {
     use super::*;
     
+    async fn connect_local(port: u16) -> Result<Connection> {
+        connect(&"127.0.0.1".parse().unwrap(), Some(port), true).await
+    }
+    
     #[tokio::test]
     async fn test_connection() {
         let server_handle = tokio::spawn(async {
@@ -113,11 +793,365 @@ mod tests {
         
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
         
-        let mut conn = connect("127.0.0.1", Some(19999)).await.unwrap();
+        let mut conn = connect_local(19999).await.unwrap();
         conn.send(b"test").await.unwrap();
         let response = conn.receive().await.unwrap();
         assert_eq!(response, b"response");
         
         server_handle.await.unwrap();
     }
+    
+    #[tokio::test]
+    async fn test_bind_falls_back_when_default_port_is_taken() {
+        // Hold the default port (another process may already have it, which is fine too)
+        let _taken = TcpListener::bind(("0.0.0.0", DEFAULT_PORT)).await;
+        
+        let listener = bind(None).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_ne!(port, DEFAULT_PORT);
+        
+        let (accepted, connected) = tokio::join!(accept(&listener), connect_local(port));
+        let (mut accepted, mut connected) = (accepted.unwrap(), connected.unwrap());
+        assert_eq!(accepted.local_port(), port);
+        assert_eq!(connected.peer_addr().port(), port);
+        
+        connected.send(b"hello").await.unwrap();
+        assert_eq!(accepted.receive().await.unwrap(), b"hello");
+    }
+    
+    #[tokio::test]
+    async fn test_port_in_the_address_wins() {
+        let listener = bind(Some(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let endpoint: Endpoint = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+        
+        let (accepted, connected) = tokio::join!(accept(&listener), connect(&endpoint, Some(DEFAULT_PORT), true));
+        accepted.unwrap();
+        assert_eq!(connected.unwrap().peer_addr().port(), port);
+        
+        // The same without racing the addresses
+        let (accepted, connected) = tokio::join!(accept(&listener), connect(&endpoint, Some(DEFAULT_PORT), false));
+        accepted.unwrap();
+        assert_eq!(connected.unwrap().peer_addr().port(), port);
+    }
+    
+    #[tokio::test]
+    async fn test_read_timeout_fires_when_peer_goes_quiet() {
+        let listener = bind(Some(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (accepted, connected) = tokio::join!(accept(&listener), connect_local(port));
+        let (mut server, mut client) = (accepted.unwrap(), connected.unwrap());
+        
+        let timeout = Duration::from_millis(200);
+        client.set_timeouts(SocketTimeouts { read: Some(timeout), write: None });
+        
+        // One message, then the server goes silent without closing
+        server.send(b"first").await.unwrap();
+        assert_eq!(client.receive().await.unwrap(), b"first");
+        
+        let start = tokio::time::Instant::now();
+        let err = client.receive().await.unwrap_err();
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+        drop(server);
+    }
+    
+    #[tokio::test]
+    async fn test_read_timeout_only_counts_time_with_nothing_arriving() {
+        let listener = bind(Some(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (accepted, connected) = tokio::join!(accept(&listener), connect_local(port));
+        let (mut server, mut client) = (accepted.unwrap(), connected.unwrap());
+        let timeout = Duration::from_millis(200);
+        client.set_timeouts(SocketTimeouts { read: Some(timeout), write: None });
+        
+        // Quiet for longer than the timeout before the message starts, then a
+        // byte at a time, taking longer than the timeout in all
+        let message = b"slow";
+        let sender = async {
+            tokio::time::sleep(timeout * 2).await;
+            let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(message);
+            for byte in frame {
+                server.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(timeout / 4).await;
+            }
+        };
+        let (received, ()) = tokio::join!(client.wait_for_message(), sender);
+        assert_eq!(received.unwrap(), message);
+        
+        // The same quiet start is too long for a message that should be on its way
+        let err = client.receive().await.unwrap_err();
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+    
+    #[tokio::test]
+    async fn test_bind_waits_for_a_held_port_to_clear() {
+        // Held by something that never answers, like an instance on its way out
+        let held = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = held.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            drop(held);
+        });
+        
+        let mut waited = None;
+        let listener = bind_with(Some(port), |busy| waited = Some(busy)).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        assert_eq!(waited, Some(port));
+    }
+    
+    #[tokio::test]
+    async fn test_bind_names_a_zap_already_on_the_port() {
+        let live = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = live.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = live.accept().await.unwrap();
+            let hello = Message::Hello { version: crate::protocol::PROTOCOL_VERSION };
+            write_message(&mut stream, &hello.to_bytes().unwrap()).await.unwrap();
+            let capabilities = Message::Capabilities { features: crate::protocol::local_features() };
+            write_message(&mut stream, &capabilities.to_bytes().unwrap()).await.unwrap();
+            // Kept open, as a sender waiting for the rest of the handshake would
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        
+        let mut waited = false;
+        let err = bind_with(Some(port), |_| waited = true).await.unwrap_err();
+        assert_eq!(err.to_string(), format!("Another zap is already listening on port {}; stop it or use another --port", port));
+        assert!(!waited);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_accept_turns_away_addresses_outside_allow_list() {
+        // The whole of 127.0.0.0/8 is loopback on Linux, so 127.0.0.2 can stand in for another machine
+        let listener = bind(Some(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let allow = AllowList::new(vec![parse_cidr("127.0.0.2").unwrap()]);
+        let mut rejected = Vec::new();
+        let accepting = accept_from(&listener, &allow, |addr| rejected.push(addr));
+        
+        let attempts = async {
+            // Hung up on before anything is said
+            let mut outsider = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let mut buffer = [0; 1];
+            assert_eq!(outsider.read(&mut buffer).await.unwrap(), 0);
+            
+            // Still listening for someone who is allowed
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+            socket.connect(([127, 0, 0, 1], port).into()).await.unwrap()
+        };
+        let (accepted, _allowed) = tokio::join!(accepting, attempts);
+        assert_eq!(accepted.unwrap().peer_addr().ip(), "127.0.0.2".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].ip(), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+    }
+    
+    async fn connected_pair() -> (Connection, Connection) {
+        let listener = bind(Some(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (accepted, connected) = tokio::join!(accept(&listener), connect_local(port));
+        (accepted.unwrap(), connected.unwrap())
+    }
+    
+    #[tokio::test]
+    async fn test_happy_eyeballs_faster_family_wins() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let Ok(v6) = TcpListener::bind("[::1]:0").await else {
+            // No IPv6 loopback here, so there's nothing to race
+            return;
+        };
+        let v4_addr = v4.local_addr().unwrap();
+        let v6_addr = v6.local_addr().unwrap();
+        let stagger = Duration::from_millis(50);
+        
+        // IPv6 is listed first, but its route is slow; IPv4 starts after the stagger and wins
+        let slow_v6 = |addr: SocketAddr| async move {
+            if addr.is_ipv6() {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            TcpStream::connect(addr).await
+        };
+        let start = tokio::time::Instant::now();
+        let (stream, addr) = race(&[v6_addr, v4_addr], stagger, slow_v6).await.unwrap();
+        assert_eq!(addr, v4_addr);
+        assert!(start.elapsed() >= stagger && start.elapsed() < Duration::from_secs(5));
+        let conn = Connection::new(stream, addr, 0);
+        assert_eq!(conn.addr_family(), AddrFamily::V4);
+        
+        // A healthy first choice connects before the other family is even tried
+        let (stream, addr) = race(&[v6_addr, v4_addr], Duration::from_secs(5), TcpStream::connect).await.unwrap();
+        assert_eq!(addr, v6_addr);
+        assert_eq!(Connection::new(stream, addr, 0).addr_family(), AddrFamily::V6);
+        
+        // A refused address moves straight on to the next
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        drop(dead);
+        let start = tokio::time::Instant::now();
+        let (_, addr) = race(&[dead_addr, v6_addr], Duration::from_secs(5), TcpStream::connect).await.unwrap();
+        assert_eq!(addr, v6_addr);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+    
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(&addrs).iter().map(|addr| addr.to_string()).collect();
+        assert_eq!(ordered, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "[::3]:1"]);
+        assert!(interleave(&[]).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_peer_cert_fingerprint_only_over_tls() {
+        let (accepted, connected) = connected_pair().await;
+        assert_eq!(accepted.peer_cert_fingerprint(), None);
+        assert_eq!(connected.peer_cert_fingerprint(), None);
+        
+        let (server, client) = (TlsIdentity::generate().unwrap(), TlsIdentity::generate().unwrap());
+        let (accepted, connected) = tokio::join!(accepted.start_tls(TlsRole::Server, &server), connected.start_tls(TlsRole::Client, &client));
+        let (mut accepted, mut connected) = (accepted.unwrap(), connected.unwrap());
+        assert_eq!(accepted.peer_cert_fingerprint(), Some(client.fingerprint()));
+        assert_eq!(connected.peer_cert_fingerprint(), Some(server.fingerprint()));
+        assert_ne!(server.fingerprint(), client.fingerprint());
+        
+        connected.send(b"over tls").await.unwrap();
+        assert_eq!(accepted.receive().await.unwrap(), b"over tls");
+        assert!(accepted.start_tls(TlsRole::Server, &server).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_connection_is_a_byte_stream() {
+        let (mut accepted, mut connected) = connected_pair().await;
+        connected.write_all(b"raw bytes").await.unwrap();
+        connected.send(b"framed").await.unwrap();
+        
+        let mut raw = [0u8; 9];
+        accepted.read_exact(&mut raw).await.unwrap();
+        assert_eq!(&raw, b"raw bytes");
+        
+        // A message is its length prefix followed by the payload
+        let mut framed = [0u8; 10];
+        accepted.read_exact(&mut framed).await.unwrap();
+        assert_eq!(&framed, b"\0\0\0\x06framed");
+    }
+    
+    /// Takes a few bytes per write, yielding before each next one
+    #[derive(Default)]
+    struct StutteringWriter {
+        written: Vec<u8>,
+        yielded: bool,
+    }
+    
+    impl AsyncWrite for StutteringWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if !std::mem::replace(&mut self.yielded, true) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.yielded = false;
+            let len = buf.len().min(MESSAGE_SIZE_BYTES);
+            self.written.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+        
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_frame_guard_notes_a_frame_cut_off_midway() {
+        let mut writer = StutteringWriter::default();
+        let mut torn = false;
+        
+        // Dropped before writing anything: nothing to tear
+        {
+            let mut stream = FrameGuard::new(&mut writer, &mut torn);
+            let mut send = std::pin::pin!(write_message(&mut stream, b"hello world"));
+            assert!(futures_util::poll!(send.as_mut()).is_pending());
+        }
+        assert!(!torn);
+        assert!(writer.written.is_empty());
+        
+        // Dropped after the length prefix, as the payload waits its turn
+        {
+            let mut stream = FrameGuard::new(&mut writer, &mut torn);
+            let mut send = std::pin::pin!(write_message(&mut stream, b"hello world"));
+            assert!(futures_util::poll!(send.as_mut()).is_pending());
+        }
+        assert!(torn);
+        assert_eq!(writer.written, [0, 0, 0, 11]);
+        
+        // Left to finish, the whole frame goes out
+        let mut writer = StutteringWriter::default();
+        let mut torn = false;
+        write_message(&mut FrameGuard::new(&mut writer, &mut torn), b"hello world").await.unwrap();
+        assert_eq!(writer.written, b"\0\0\0\x0bhello world");
+    }
+    
+    #[tokio::test]
+    async fn test_cancelled_send_poisons_the_connection() {
+        let (mut accepted, mut connected) = connected_pair().await;
+        
+        // Far more than the socket buffers hold while nobody reads, so the send stalls partway
+        let big = vec![7u8; 64 * 1024 * 1024];
+        assert!(tokio::time::timeout(Duration::from_millis(200), connected.send(&big)).await.is_err());
+        
+        let err = connected.send(b"next").await.unwrap_err();
+        assert_eq!(err.to_string(), DESYNCHRONIZED);
+        assert!(connected.send_raw(b"raw").await.is_err());
+        assert_eq!(connected.write(b"raw").await.unwrap_err().to_string(), DESYNCHRONIZED);
+        
+        // The other direction still works
+        accepted.send(b"reply").await.unwrap();
+        assert_eq!(connected.receive().await.unwrap(), b"reply");
+    }
+    
+    #[tokio::test]
+    async fn test_cancelled_receive_carries_on_where_it_stopped() {
+        use futures_util::FutureExt;
+        
+        let (mut accepted, mut connected) = connected_pair().await;
+        
+        // Like a `select!` branch that lost while nothing had come in
+        assert!(tokio::time::timeout(Duration::from_millis(50), connected.receive()).await.is_err());
+        accepted.send(b"later").await.unwrap();
+        assert_eq!(connected.receive().await.unwrap(), b"later");
+        
+        // Cancelled partway through, what had arrived is kept for the next receive
+        accepted.write_all(&[0, 0, 0, 11, b'h', b'e']).await.unwrap();
+        accepted.flush().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), connected.receive()).await.is_err());
+        assert_eq!(connected.receive_raw(5).await.unwrap_err().to_string(), MESSAGE_PARTWAY);
+        accepted.write_all(b"llo").await.unwrap();
+        accepted.flush().await.unwrap();
+        assert!(connected.receive().now_or_never().is_none());
+        accepted.write_all(b" world").await.unwrap();
+        accepted.send(b"next").await.unwrap();
+        assert_eq!(connected.receive().await.unwrap(), b"hello world");
+        assert_eq!(connected.receive().await.unwrap(), b"next");
+    }
+    
+    #[tokio::test]
+    async fn test_length_delimited_codec_interop() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_util::codec::LengthDelimitedCodec;
+        
+        let (mut accepted, connected) = connected_pair().await;
+        let mut framed = LengthDelimitedCodec::builder().new_framed(connected);
+        framed.send(&b"from the codec"[..]).await.unwrap();
+        assert_eq!(accepted.receive().await.unwrap(), b"from the codec");
+        
+        accepted.send(b"from receive").await.unwrap();
+        assert_eq!(&framed.next().await.unwrap().unwrap()[..], b"from receive");
+    }
 }
@@ -0,0 +1,163 @@
+//! TLS on direct connections (`--direct-tls`)
+//!
+//! Each side makes a throwaway self-signed certificate and shows it to the
+//! other, the receiver as a client certificate. The handshake only checks
+//! that the peer holds its certificate's key; which certificate it should
+//! have been is settled afterwards, when each side vouches for its own
+//! fingerprint under the key the code agreed. Certificates derived from the
+//! code itself would hand anyone who connects something to guess the code
+//! against offline, which is what the key exchange is there to rule out.
+
+use anyhow::Result;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{ClientConfig, DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// Name the client asks for; certificates are never checked against it
+const SERVER_NAME: &str = "zap";
+
+/// Which end of the TLS handshake this side is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsRole {
+    /// The sender, which accepted the connection
+    Server,
+    /// The receiver, which made it
+    Client,
+}
+
+/// A throwaway self-signed certificate and its key, made for one connection
+pub struct TlsIdentity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl TlsIdentity {
+    pub fn generate() -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        Ok(Self {
+            cert: certified.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()),
+        })
+    }
+    
+    /// SHA-256 of the certificate
+    pub fn fingerprint(&self) -> [u8; 32] {
+        fingerprint(&self.cert)
+    }
+}
+
+/// SHA-256 of a DER certificate, what `Connection::peer_cert_fingerprint` gives
+pub fn fingerprint(cert: &[u8]) -> [u8; 32] {
+    Sha256::digest(cert).into()
+}
+
+/// Run the TLS 1.3 handshake over `stream` as `role`, showing `identity`'s certificate
+pub async fn handshake(stream: TcpStream, role: TlsRole, identity: &TlsIdentity) -> Result<TlsStream<TcpStream>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = Arc::new(AnyCertificate(provider.signature_verification_algorithms));
+    let chain = vec![identity.cert.clone()];
+    let key = PrivateKeyDer::Pkcs8(identity.key.clone_key());
+    match role {
+        TlsRole::Server => {
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])?
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(chain, key)?;
+            Ok(TlsAcceptor::from(Arc::new(config)).accept(stream).await?.into())
+        }
+        TlsRole::Client => {
+            let config = ClientConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])?
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_client_auth_cert(chain, key)?;
+            let name = ServerName::try_from(SERVER_NAME)?;
+            Ok(TlsConnector::from(Arc::new(config)).connect(name, stream).await?.into())
+        }
+    }
+}
+
+/// Takes any certificate whose key signed the handshake, for either side
+///
+/// Self-signed certificates have nothing to chain to; see the module docs
+/// for how the right one is told apart.
+#[derive(Debug)]
+struct AnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+    
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+    
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AnyCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+    
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+    
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+    
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+    
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
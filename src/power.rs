@@ -0,0 +1,211 @@
+use anyhow::Result;
+
+/// Something that can stop the computer sleeping
+///
+/// The system one is what zap uses; tests swap in their own.
+pub trait Inhibitor {
+    /// Ask for the computer to stay awake until the returned hold is dropped
+    fn acquire(&self, reason: &str) -> Result<Box<dyn Send>>;
+}
+
+/// Asks the operating system: logind through `systemd-inhibit` on Linux,
+/// `caffeinate` on macOS and `SetThreadExecutionState` on Windows
+pub struct SystemInhibitor;
+
+impl Inhibitor for SystemInhibitor {
+    fn acquire(&self, reason: &str) -> Result<Box<dyn Send>> {
+        platform::acquire(reason)
+    }
+}
+
+/// Keeps the computer awake for as long as it's alive
+///
+/// Getting the inhibitor is best effort: when it can't be had the guard says
+/// so once and the transfer carries on. The hold is released when the guard
+/// is dropped, which also happens while unwinding from a panic; the helper
+/// processes used on Linux and macOS watch zap's pid, so they let go even if
+/// zap is killed.
+pub struct SleepGuard {
+    hold: Option<Box<dyn Send>>,
+}
+
+impl SleepGuard {
+    pub fn acquire(inhibitor: &dyn Inhibitor, reason: &str) -> Self {
+        match inhibitor.acquire(reason) {
+            Ok(hold) => Self { hold: Some(hold) },
+            Err(e) => {
+                // stderr, so it never lands in data passed through stdout
+                eprintln!("Warning: couldn't stop the computer sleeping during the transfer: {}", e);
+                Self { hold: None }
+            }
+        }
+    }
+    
+    /// Whether the computer is actually being kept awake
+    pub fn is_held(&self) -> bool {
+        self.hold.is_some()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod platform {
+    use anyhow::{anyhow, Result};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+    
+    /// A helper process that keeps the computer awake until it's stopped
+    struct Helper(Child);
+    
+    impl Drop for Helper {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+    
+    #[cfg(target_os = "linux")]
+    fn command(reason: &str) -> Command {
+        let mut command = Command::new("systemd-inhibit");
+        command
+            .args(["--what=sleep:idle", "--who=zap", "--mode=block"])
+            .arg(format!("--why={}", reason))
+            .args(["tail", "-f", "/dev/null", "--pid"])
+            .arg(std::process::id().to_string());
+        command
+    }
+    
+    #[cfg(target_os = "macos")]
+    fn command(_reason: &str) -> Command {
+        let mut command = Command::new("caffeinate");
+        command.args(["-i", "-s", "-w"]).arg(std::process::id().to_string());
+        command
+    }
+    
+    pub fn acquire(reason: &str) -> Result<Box<dyn Send>> {
+        let mut command = command(reason);
+        let name = command.get_program().to_string_lossy().into_owned();
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("{}: {}", name, e))?;
+        
+        // A helper with nobody to ask, like logind missing, gives up at once
+        for _ in 0..10 {
+            if let Some(status) = child.try_wait()? {
+                return Err(anyhow!("{} exited straight away ({})", name, status));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(Box::new(Helper(child)))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use anyhow::{anyhow, Result};
+    use std::sync::mpsc;
+    
+    const ES_CONTINUOUS: u32 = 0x8000_0000;
+    const ES_SYSTEM_REQUIRED: u32 = 0x0000_0001;
+    
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetThreadExecutionState(flags: u32) -> u32;
+    }
+    
+    /// Dropping it wakes the thread holding the execution state, which then clears it
+    struct Hold(#[allow(dead_code)] mpsc::Sender<()>);
+    
+    pub fn acquire(_reason: &str) -> Result<Box<dyn Send>> {
+        // The state belongs to the thread that set it, so one thread holds it throughout
+        let (release, released) = mpsc::channel::<()>();
+        let (ready_tx, ready) = mpsc::channel();
+        std::thread::spawn(move || {
+            // SAFETY: only changes this thread's execution state
+            let acquired = unsafe { SetThreadExecutionState(ES_CONTINUOUS | ES_SYSTEM_REQUIRED) } != 0;
+            let _ = ready_tx.send(acquired);
+            if acquired {
+                let _ = released.recv();
+                // SAFETY: as above
+                unsafe { SetThreadExecutionState(ES_CONTINUOUS) };
+            }
+        });
+        match ready.recv() {
+            Ok(true) => Ok(Box::new(Hold(release))),
+            _ => Err(anyhow!("SetThreadExecutionState failed")),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use anyhow::{anyhow, Result};
+    
+    pub fn acquire(_reason: &str) -> Result<Box<dyn Send>> {
+        Err(anyhow!("not supported on this platform"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    
+    /// Counts holds that are currently out
+    struct Counting {
+        held: Arc<AtomicUsize>,
+        fail: bool,
+    }
+    
+    struct CountedHold(Arc<AtomicUsize>);
+    
+    impl Drop for CountedHold {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    
+    impl Inhibitor for Counting {
+        fn acquire(&self, _reason: &str) -> Result<Box<dyn Send>> {
+            if self.fail {
+                return Err(anyhow!("logind isn't running"));
+            }
+            self.held.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(CountedHold(self.held.clone())))
+        }
+    }
+    
+    #[test]
+    fn test_guard_releases_on_every_exit() {
+        let held = Arc::new(AtomicUsize::new(0));
+        let inhibitor = Counting { held: held.clone(), fail: false };
+        
+        let guard = SleepGuard::acquire(&inhibitor, "Sending photos.zip");
+        assert!(guard.is_held());
+        assert_eq!(held.load(Ordering::SeqCst), 1);
+        drop(guard);
+        assert_eq!(held.load(Ordering::SeqCst), 0);
+        
+        // Released while unwinding too
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = SleepGuard::acquire(&inhibitor, "Sending photos.zip");
+            assert_eq!(held.load(Ordering::SeqCst), 1);
+            panic!("transfer blew up");
+        }));
+        assert!(result.is_err());
+        assert_eq!(held.load(Ordering::SeqCst), 0);
+    }
+    
+    #[test]
+    fn test_failure_is_not_fatal() {
+        let held = Arc::new(AtomicUsize::new(0));
+        let guard = SleepGuard::acquire(&Counting { held: held.clone(), fail: true }, "Receiving");
+        assert!(!guard.is_held());
+        drop(guard);
+        assert_eq!(held.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::Message;
+
+/// Control messages bigger than this once serialized are sent in pieces of this size
+pub const FRAGMENT_SIZE: usize = 1024 * 1024;
+
+/// Most a reassembled message may grow to
+pub const MAX_REASSEMBLED_SIZE: usize = 256 * 1024 * 1024;
+
+/// How long the pieces of one message may take to arrive
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Messages that can be partly received at once
+const MAX_PENDING: usize = 4;
+
+/// Cut a serialized message into `Fragment`s of at most `piece_size` bytes
+pub fn split(payload: &[u8], piece_size: usize, id: u64) -> Vec<Message> {
+    let pieces: Vec<&[u8]> = payload.chunks(piece_size.max(1)).collect();
+    let total = pieces.len() as u32;
+    pieces
+        .into_iter()
+        .zip(0..)
+        .map(|(data, index)| Message::Fragment { id, index, total, data: data.to_vec() })
+        .collect()
+}
+
+/// Puts `Fragment`s back together on the receiving side
+///
+/// Anything that doesn't add up — a piece out of range or seen twice, a
+/// message growing past `max_bytes` or taking longer than `timeout` — is an
+/// error, since only a broken or hostile peer sends it.
+pub struct Reassembler {
+    max_bytes: usize,
+    timeout: Duration,
+    pending: HashMap<u64, Partial>,
+}
+
+struct Partial {
+    pieces: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+impl Reassembler {
+    pub fn new(max_bytes: usize, timeout: Duration) -> Self {
+        Self {
+            max_bytes,
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+    
+    /// Bytes held in pieces of messages that aren't whole yet
+    pub fn buffered(&self) -> usize {
+        self.pending.values().map(|partial| partial.bytes).sum()
+    }
+    
+    /// Add a piece, returning the whole message once its last piece is in
+    pub fn push(&mut self, id: u64, index: u32, total: u32, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if index >= total {
+            return Err(anyhow!("Fragment {} of message {} is out of range ({} pieces)", index, id, total));
+        }
+        let now = Instant::now();
+        if self.pending.get(&id).is_some_and(|partial| now.duration_since(partial.started) > self.timeout) {
+            self.pending.remove(&id);
+            return Err(anyhow!("Message {} took more than {:?} to arrive", id, self.timeout));
+        }
+        
+        if !self.pending.contains_key(&id) {
+            // Every piece carries at least one byte, so more pieces than that can't fit
+            if total as usize > self.max_bytes {
+                return Err(anyhow!("Message {} would be larger than {} bytes", id, self.max_bytes));
+            }
+            // Forget messages whose sender gave up on them
+            let timeout = self.timeout;
+            self.pending.retain(|_, partial| now.duration_since(partial.started) <= timeout);
+            if self.pending.len() >= MAX_PENDING {
+                return Err(anyhow!("Too many fragmented messages at once"));
+            }
+            self.pending.insert(id, Partial {
+                pieces: vec![None; total as usize],
+                missing: total as usize,
+                bytes: 0,
+                started: now,
+            });
+        }
+        
+        let buffered = self.buffered();
+        let partial = self.pending.get_mut(&id).expect("inserted above");
+        if partial.pieces.len() != total as usize {
+            return Err(anyhow!("Fragments of message {} disagree on how many pieces there are", id));
+        }
+        if partial.pieces[index as usize].is_some() {
+            return Err(anyhow!("Fragment {} of message {} arrived twice", index, id));
+        }
+        if buffered + data.len() > self.max_bytes {
+            return Err(anyhow!("Message {} would be larger than {} bytes", id, self.max_bytes));
+        }
+        partial.bytes += data.len();
+        partial.pieces[index as usize] = Some(data);
+        partial.missing -= 1;
+        
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+        let partial = self.pending.remove(&id).expect("looked up above");
+        Ok(Some(partial.pieces.into_iter().flatten().flatten().collect()))
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(MAX_REASSEMBLED_SIZE, REASSEMBLY_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::ZapRng;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+    
+    fn pieces(messages: Vec<Message>) -> Vec<(u64, u32, u32, Vec<u8>)> {
+        messages
+            .into_iter()
+            .map(|message| match message {
+                Message::Fragment { id, index, total, data } => (id, index, total, data),
+                _ => unreachable!("split only makes fragments"),
+            })
+            .collect()
+    }
+    
+    #[test]
+    fn test_large_listing_round_trip() {
+        let listing: Vec<String> = (0..200_000).map(|i| format!("photos/2024/{:06}.jpg", i)).collect();
+        let payload = bincode::serialize(&listing).unwrap();
+        assert!(payload.len() > 4 * 1024 * 1024);
+        
+        let mut fragments = pieces(split(&payload, FRAGMENT_SIZE, 7));
+        assert_eq!(fragments.len(), payload.len().div_ceil(FRAGMENT_SIZE));
+        // Order doesn't matter
+        fragments.reverse();
+        
+        let mut reassembler = Reassembler::default();
+        let mut whole = None;
+        for (id, index, total, data) in fragments {
+            assert!(whole.is_none());
+            whole = reassembler.push(id, index, total, data).unwrap();
+        }
+        let received: Vec<String> = bincode::deserialize(&whole.unwrap()).unwrap();
+        assert_eq!(received, listing);
+    }
+    
+    #[test]
+    fn test_rejects_broken_fragments() {
+        let mut reassembler = Reassembler::new(1000, REASSEMBLY_TIMEOUT);
+        assert!(reassembler.push(1, 3, 3, vec![0]).is_err());
+        assert!(reassembler.push(1, 0, 0, vec![0]).is_err());
+        
+        assert_eq!(reassembler.push(2, 0, 2, vec![1; 10]).unwrap(), None);
+        assert!(reassembler.push(2, 0, 2, vec![1; 10]).unwrap_err().to_string().contains("twice"));
+        assert!(reassembler.push(2, 1, 5, vec![1; 10]).unwrap_err().to_string().contains("disagree"));
+        assert!(reassembler.push(3, 0, 2, vec![1; 995]).unwrap_err().to_string().contains("larger"));
+        assert!(reassembler.push(4, 0, 5000, vec![1]).is_err());
+        
+        // Other messages don't disturb one still arriving
+        assert_eq!(reassembler.push(2, 1, 2, vec![2; 10]).unwrap().unwrap().len(), 20);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_message_times_out() {
+        let mut reassembler = Reassembler::new(1000, Duration::from_secs(60));
+        assert_eq!(reassembler.push(1, 0, 2, vec![1]).unwrap(), None);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(reassembler.push(1, 1, 2, vec![2]).unwrap_err().to_string().contains("took more than"));
+        
+        // Abandoned messages don't count against the limit on pending ones
+        for id in 10..10 + MAX_PENDING as u64 {
+            reassembler.push(id, 0, 2, vec![1]).unwrap();
+        }
+        assert!(reassembler.push(99, 0, 2, vec![1]).is_err());
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(reassembler.push(99, 0, 2, vec![1]).unwrap(), None);
+    }
+    
+    #[test]
+    fn test_fuzz_duplicate_missing_and_shuffled() {
+        let mut rng = ZapRng::seeded(692);
+        for _ in 0..500 {
+            let payload: Vec<u8> = (0..rng.gen_range(1..2000)).map(|_| rng.gen()).collect();
+            let mut fragments = pieces(split(&payload, rng.gen_range(1..300), 5));
+            let complete = fragments.len();
+            
+            // Lose some pieces, repeat others, and mix them up
+            fragments.retain(|_| rng.gen_bool(0.95));
+            let lost = fragments.len() < complete;
+            for _ in 0..rng.gen_range(0..3) {
+                if let Some(piece) = fragments.choose(&mut rng).cloned() {
+                    fragments.push(piece);
+                }
+            }
+            fragments.shuffle(&mut rng);
+            
+            let mut reassembler = Reassembler::new(4000, REASSEMBLY_TIMEOUT);
+            let mut result = Ok(None);
+            for (id, index, total, data) in fragments {
+                result = reassembler.push(id, index, total, data);
+                if !matches!(result, Ok(None)) {
+                    break;
+                }
+            }
+            match result {
+                // Whatever comes out whole is exactly what went in
+                Ok(Some(whole)) => assert_eq!(whole, payload),
+                Ok(None) => assert!(lost),
+                Err(e) => assert!(e.to_string().contains("twice"), "{}", e),
+            }
+        }
+    }
+}
@@ -1,7 +1,176 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::fsutil;
+
+pub mod fragment;
 
 /// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Feature tag for the ChaCha20-Poly1305 cipher used for all transfers
+pub const FEATURE_CIPHER_CHACHA20POLY1305: &str = "cipher/chacha20poly1305";
+
+/// Feature tag for accepting content whose length isn't known up front (`StreamMetadata`)
+pub const FEATURE_STREAM: &str = "stream";
+
+/// Feature tag for sending a regular file's first chunk before the receiver accepts it
+pub const FEATURE_PEEK: &str = "peek";
+
+/// Feature tag for `Waiting` heartbeats while a scheduled send holds off
+pub const FEATURE_SCHEDULE: &str = "schedule";
+
+/// Feature tag for splitting oversized control messages into `Fragment`s
+pub const FEATURE_FRAGMENT: &str = "fragment";
+
+/// Feature tag for measuring the connection with `Probe` padding before a transfer
+pub const FEATURE_PROBE: &str = "probe";
+
+/// Feature tag for receiving directories as ZIP archives instead of tar
+pub const FEATURE_ZIP: &str = "archive/zip";
+
+/// Feature tag for the receiver saying where a regular file picks up (`Resume`) and calling transfers off (`Cancel`)
+pub const FEATURE_RESUME: &str = "resume";
+
+/// Feature tag for the receiver checking a regular file against the sender's checksum and answering with a `Receipt`
+pub const FEATURE_RECEIPT: &str = "receipt";
+
+/// Feature tag for `PartialChecksum` checkpoints while a regular file is sent
+pub const FEATURE_CHECKPOINT: &str = "checkpoint";
+
+/// Feature tag for sending a regular file as differences from the receiver's copy (`BlockSignatures`, `Delta`)
+pub const FEATURE_DELTA: &str = "delta";
+
+/// Feature tag for one-shot folder syncs (`SyncOffer`, `SyncRequest`, `SyncDelete`)
+pub const FEATURE_SYNC: &str = "sync";
+
+/// Feature tag for the receiver confirming chunks while the sender asks it to (`AckChunks`, `ChunkAck`)
+pub const FEATURE_CHUNK_ACK: &str = "chunk-ack";
+
+/// Feature tag for agreeing on the session key with SPAKE2 (`KeyExchange`, see `PAKE_V2`)
+pub const FEATURE_PAKE_V2: &str = "pake/v2";
+
+/// Feature tag for the receiver answering `Complete` with an `Ack`, or an `Error` when it couldn't finish
+pub const FEATURE_COMPLETE_ACK: &str = "complete-ack";
+
+/// Feature tag for a receiver that only looked at the offer leaving without turning it down (`Deferred`)
+pub const FEATURE_DEFER: &str = "defer";
+
+/// Feature tag for `Preparing` updates while the sender reads through what it's about to offer
+pub const FEATURE_PREPARING: &str = "preparing";
+
+/// Feature tag for a file's checksum, and so its receipt, being BLAKE3 (`blake3:` then hex) rather than SHA-256
+pub const FEATURE_CHECKSUM_BLAKE3: &str = "checksum/blake3";
+
+/// Feature tag for a tar folder's archive being followed by the BLAKE3 of each file in it (`FileChecksums`)
+pub const FEATURE_FILE_CHECKSUMS: &str = "file-checksums";
+
+/// Feature tag for mixing an ML-KEM-768 secret into the session key (`KemPublicKey`, `KemCiphertext`)
+///
+/// Not in `local_features`: only offered with `--pq`, and only by builds with the `pq` feature.
+pub const FEATURE_KEM_ML_KEM_768: &str = "kem/ml-kem-768";
+
+/// Start of the tag a receiver offers with the largest chunk it takes, e.g. `max-chunk/1397077`
+///
+/// Carrying a number, it's never agreed on; the sender reads it from the
+/// receiver's offer (`Session::peer_max_chunk_size`).
+pub const FEATURE_MAX_CHUNK_PREFIX: &str = "max-chunk/";
+
+/// Start of the tag each peer offers with the biggest message it reads, e.g. `max-frame/104857600`
+pub const FEATURE_MAX_FRAME_PREFIX: &str = "max-frame/";
+
+/// Biggest chunk sent to a receiver from before peers said what they take
+pub const LEGACY_MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Biggest message a peer from before peers said so is taken to read
+pub const LEGACY_MAX_FRAME_SIZE: usize = 100 * 1024 * 1024;
+
+/// Room a chunk's `Message` and its encryption take beyond the data, with plenty to spare
+pub const CHUNK_OVERHEAD: usize = 1024;
+
+/// Feature tag a mailbox upload offers in place of `FEATURE_PAKE_V2`, keyed with `Cipher::for_mailbox`
+///
+/// Not in `local_features`: only receivers offer it back, so live senders never agree to it.
+pub const FEATURE_MAILBOX: &str = "mailbox";
+
+/// Argon2id salt for mailbox keys
+pub const MAILBOX_KEY_SALT: &[u8] = b"zap-mailbox-v1";
+
+/// Key derived from the code alone, which anyone watching could brute-force
+///
+/// What zap did before `PAKE_V2`; only mailbox uploads (`Cipher::for_mailbox`) still key this way.
+pub const KEY_EXCHANGE_V1: u8 = 1;
+
+/// Key exchange with SPAKE2 and the transcript mixed into the key; see `PAKE_V2`
+pub const KEY_EXCHANGE_V2: u8 = 2;
+
+/// `KEY_EXCHANGE_V2` with an ML-KEM-768 secret mixed in as well (`FEATURE_KEM_ML_KEM_768`)
+pub const KEY_EXCHANGE_V3: u8 = 3;
+
+/// The fixed inputs of one version of the PAKE key exchange
+///
+/// Both peers must use exactly the same ones, so each version's are frozen
+/// once released; changing any of them means a new version. They all go
+/// into the transcript the session key is derived from, so peers that
+/// disagree fail key confirmation instead of misbehaving later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PakeSuite {
+    /// Key exchange version this suite is
+    pub version: u8,
+    /// SPAKE2 identity of the sender (side A, or both sides when `symmetric`)
+    pub sender_identity: &'static [u8],
+    /// SPAKE2 identity of the receiver (side B)
+    pub receiver_identity: &'static [u8],
+    /// Both sides start SPAKE2 in symmetric mode instead of as A and B
+    pub symmetric: bool,
+    /// HKDF salt for the session key
+    pub salt: &'static [u8],
+}
+
+/// SPAKE2 over Ed25519, sender as side A and receiver as side B
+pub const PAKE_V2: PakeSuite = PakeSuite {
+    version: KEY_EXCHANGE_V2,
+    sender_identity: b"zap-sender",
+    receiver_identity: b"zap-receiver",
+    symmetric: false,
+    salt: b"zap-pake-v2",
+};
+
+/// The key exchange version both peers support best
+///
+/// A peer without `FEATURE_PAKE_V2` would only have a hash of the code to key
+/// with, which anyone watching could brute-force, so it's refused.
+pub fn key_exchange_version(session: &Session) -> anyhow::Result<u8> {
+    if session.supports(FEATURE_PAKE_V2) {
+        Ok(KEY_EXCHANGE_V2)
+    } else {
+        Err(anyhow::anyhow!("The other side doesn't support the SPAKE2 key exchange (pake/v2); it needs a newer zap"))
+    }
+}
+
+/// How strong a key exchange `session` agreed on, from `KEY_EXCHANGE_V1` to `KEY_EXCHANGE_V3`
+///
+/// What `--min-protocol` is held against. Unlike `key_exchange_version`, a
+/// session without `FEATURE_PAKE_V2` isn't an error here, just v1.
+pub fn key_exchange_strength(session: &Session) -> u8 {
+    if session.supports(FEATURE_MAILBOX) || !session.supports(FEATURE_PAKE_V2) {
+        KEY_EXCHANGE_V1
+    } else if session.supports(FEATURE_KEM_ML_KEM_768) {
+        KEY_EXCHANGE_V3
+    } else {
+        KEY_EXCHANGE_V2
+    }
+}
+
+/// The PAKE inputs for key exchange `version`, if there is one
+pub fn pake_suite(version: u8) -> Option<&'static PakeSuite> {
+    match version {
+        KEY_EXCHANGE_V2 => Some(&PAKE_V2),
+        _ => None,
+    }
+}
 
 /// Message types exchanged during transfer
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,7 +178,7 @@ pub enum Message {
     /// Initial handshake with protocol version
     Hello { version: u8 },
     
-    /// SPAKE2 key exchange message
+    /// SPAKE2 key exchange message, sent by both sides after Capabilities (needs `FEATURE_PAKE_V2`)
     KeyExchange { data: Vec<u8> },
     
     /// Transfer metadata (encrypted)
@@ -26,10 +195,13 @@ pub enum Message {
         data: Vec<u8>,
     },
     
-    /// Request to resume from specific chunk
-    Resume { from_chunk: u64 },
+    /// Where the receiver wants a regular file to pick up: chunks are numbered
+    /// from `from_chunk` and read from byte `offset` (encrypted, needs `FEATURE_RESUME`)
+    ///
+    /// Sent after every Ack, with zeroes when starting afresh.
+    Resume { from_chunk: u64, offset: u64 },
     
-    /// Transfer complete
+    /// Transfer complete; with `FEATURE_COMPLETE_ACK` the receiver answers with `Ack` or `Error`
     Complete,
     
     /// Error message
@@ -37,6 +209,145 @@ pub enum Message {
     
     /// Acknowledgment
     Ack,
+    
+    /// Proof of the session key, sent encrypted before any file data
+    KeyConfirm { token: [u8; 32] },
+    
+    /// Feature tags we support, sent right after Hello
+    Capabilities { features: HashSet<String> },
+    
+    /// Metadata for a stream of unknown length, like stdin (encrypted, needs `FEATURE_STREAM`)
+    StreamMetadata { filename: String },
+    
+    /// The sender is holding off until its scheduled start (encrypted, needs `FEATURE_SCHEDULE`)
+    ///
+    /// Sent periodically so idle connections stay open; `starts_in_secs` is
+    /// unknown when the sender is waiting for the network to go quiet.
+    Waiting { starts_in_secs: Option<u64> },
+    
+    /// The sender is still reading through what it's about to offer, e.g. `hashing folder: 120/12431 files`
+    /// (encrypted, needs `FEATURE_PREPARING`)
+    ///
+    /// Sent at most once a second, so the receiver can show something better
+    /// than silence while a big file or folder is checksummed.
+    Preparing { detail: String },
+    
+    /// Piece `index` of `total` of a serialized control message too big to send whole
+    /// (encrypted, needs `FEATURE_FRAGMENT`); file data is never split this way
+    Fragment { id: u64, index: u32, total: u32, data: Vec<u8> },
+    
+    /// Throwaway padding the sender streams to measure the connection (encrypted, needs `FEATURE_PROBE`)
+    Probe { data: Vec<u8> },
+    
+    /// The last of the probe's padding has been sent
+    ProbeEnd,
+    
+    /// The receiver's measurement of the probe: `bytes` arrived over `millis`
+    ProbeResult { bytes: u64, millis: u64 },
+    
+    /// The receiver called the transfer off; with `resumable` it kept what
+    /// arrived for `--resume` (encrypted, needs `FEATURE_RESUME`)
+    Cancel { resumable: bool },
+    
+    /// The file arrived and matches the checksum in its `Metadata`; sent after
+    /// `Complete` when that checksum was filled in (encrypted, needs `FEATURE_RECEIPT`)
+    Receipt {
+        transfer_id: String,
+        checksum: String,
+        received_at: u64,
+        signature: Option<ReceiptSignature>,
+    },
+    
+    /// BLAKE3 of the chunks since the last checkpoint, or since the transfer
+    /// (re)started, up to but not including `up_to_chunk` (encrypted, needs `FEATURE_CHECKPOINT`)
+    PartialChecksum { up_to_chunk: u64, hash: [u8; 32] },
+    
+    /// Signatures of the receiver's existing copy of a regular file, in
+    /// `block_size` blocks; none to have the file sent whole. Sent after the
+    /// Ack and any `Resume` (encrypted, needs `FEATURE_DELTA`)
+    BlockSignatures { block_size: u32, blocks: Vec<BlockSignature> },
+    
+    /// Part of a file sent as differences from the receiver's copy, in place of `Chunk`s
+    /// (encrypted, needs `FEATURE_DELTA`)
+    Delta { op: DeltaOp },
+    
+    /// In place of `Metadata`, a folder to make the receiver's copy match, with every file in it
+    /// (encrypted, needs `FEATURE_SYNC`)
+    SyncOffer { name: String, entries: Vec<ManifestEntry> },
+    
+    /// The receiver's answer to a `SyncOffer`, after its Ack: the files it lacks or has
+    /// different, and the ones only it has (encrypted, needs `FEATURE_SYNC`)
+    SyncRequest { wanted: Vec<String>, remote_only: Vec<String> },
+    
+    /// Files from the receiver's `remote_only` to delete, after the archive and before
+    /// `Complete` (encrypted, needs `FEATURE_SYNC`)
+    SyncDelete { paths: Vec<String> },
+    
+    /// Have the receiver answer every `Chunk` from here on with a `ChunkAck`, or stop;
+    /// the sender uses it to find out whether big chunks get through (encrypted, needs `FEATURE_CHUNK_ACK`)
+    AckChunks { enabled: bool },
+    
+    /// Chunk `index` arrived, or had already; the file now runs to `bytes_written`
+    /// (encrypted, needs `FEATURE_CHUNK_ACK`)
+    ChunkAck { index: u64, bytes_written: u64 },
+    
+    /// In place of the `Ack` or a decline: the receiver only looked at the offer, and the
+    /// sender should wait for another one to take it (needs `FEATURE_DEFER`)
+    Deferred,
+    
+    /// The receiver's ML-KEM-768 encapsulation key, sent after `KeyExchange` (needs `FEATURE_KEM_ML_KEM_768`)
+    KemPublicKey { key: Vec<u8> },
+    
+    /// The sender's answer to `KemPublicKey`: a secret encapsulated to that key (needs `FEATURE_KEM_ML_KEM_768`)
+    KemCiphertext { ciphertext: Vec<u8> },
+    
+    /// Every regular file in a folder's tar archive, with its BLAKE3, after the last chunk and
+    /// before `Complete`; the receiver checks the archive against them before unpacking it
+    /// (encrypted, needs `FEATURE_FILE_CHECKSUMS`)
+    FileChecksums { files: Vec<ManifestEntry> },
+    
+    /// SHA-256 of the TLS certificate this side showed, sent by both right after the key
+    /// confirmation on a `--direct-tls` connection, so each can check the certificate it saw (encrypted)
+    TlsFingerprint { fingerprint: [u8; 32] },
+}
+
+/// One file in a folder being synced
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Relative to the folder, with `/` between components
+    pub path: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub mtime: u64,
+    /// BLAKE3 of the contents, in hex
+    pub checksum: String,
+}
+
+/// Checksums of one block of the receiver's copy, rsync-style
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    /// Rolling checksum, cheap to slide along the sender's file a byte at a time
+    pub weak: u32,
+    /// BLAKE3, to confirm a weak match
+    pub strong: [u8; 32],
+}
+
+/// One step in rebuilding a file from the receiver's copy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// `count` blocks of the receiver's copy, starting at `block`
+    Copy { block: u64, count: u64 },
+    /// Bytes the receiver's copy doesn't have
+    Literal { data: Vec<u8> },
+    /// The end, with the BLAKE3 of the whole file to check the rebuilt one against
+    Done { hash: [u8; 32] },
+}
+
+/// A receiver's Ed25519 signature over a `Receipt`, both in hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiptSignature {
+    pub public_key: String,
+    pub signature: String,
 }
 
 impl Message {
@@ -49,6 +360,92 @@ impl Message {
     pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
         Ok(bincode::deserialize(data)?)
     }
+    
+    /// Pretty-printed JSON of the message, for reading in logs; not what goes on the wire
+    pub fn to_json_debug(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    
+    /// Parse a message from `to_json_debug`'s JSON, e.g. to hand-write one for a test peer
+    pub fn from_json_debug(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+/// Feature tags this build supports
+pub fn local_features() -> HashSet<String> {
+    [FEATURE_CIPHER_CHACHA20POLY1305, FEATURE_STREAM, FEATURE_PEEK, FEATURE_SCHEDULE, FEATURE_FRAGMENT, FEATURE_PROBE, FEATURE_ZIP, FEATURE_RESUME, FEATURE_RECEIPT, FEATURE_CHECKPOINT, FEATURE_DELTA, FEATURE_SYNC, FEATURE_PAKE_V2, FEATURE_CHUNK_ACK, FEATURE_COMPLETE_ACK, FEATURE_DEFER, FEATURE_PREPARING, FEATURE_CHECKSUM_BLAKE3, FEATURE_FILE_CHECKSUMS]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+/// Works out which features both peers can use
+pub struct CapabilityNegotiator;
+
+impl CapabilityNegotiator {
+    /// Features supported by both sides
+    pub fn negotiate(mine: &HashSet<String>, theirs: &HashSet<String>) -> HashSet<String> {
+        mine.intersection(theirs).cloned().collect()
+    }
+}
+
+/// State agreed with the peer during the handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    features: HashSet<String>,
+    /// What we offered, and what the peer said it offered
+    offered: (HashSet<String>, HashSet<String>),
+}
+
+impl Session {
+    pub fn new(features: HashSet<String>) -> Self {
+        Self {
+            features,
+            offered: Default::default(),
+        }
+    }
+    
+    /// Agree on the features both `ours` and `theirs` offer, keeping both offers for the key exchange transcript
+    ///
+    /// Size limits are read from the offers instead, even when both name the same one.
+    pub fn negotiated(ours: HashSet<String>, theirs: HashSet<String>) -> Self {
+        let mut features = CapabilityNegotiator::negotiate(&ours, &theirs);
+        features.retain(|tag| !tag.starts_with(FEATURE_MAX_CHUNK_PREFIX) && !tag.starts_with(FEATURE_MAX_FRAME_PREFIX));
+        Self {
+            features,
+            offered: (ours, theirs),
+        }
+    }
+    
+    /// Our offer and the peer's, as the handshake carried them
+    pub fn offered(&self) -> (&HashSet<String>, &HashSet<String>) {
+        (&self.offered.0, &self.offered.1)
+    }
+    
+    /// The largest chunk the peer offered to take, if it named one
+    pub fn peer_max_chunk_size(&self) -> Option<usize> {
+        self.peer_limit(FEATURE_MAX_CHUNK_PREFIX)
+    }
+    
+    /// The biggest message the peer offered to read, if it named one
+    pub fn peer_max_frame_size(&self) -> Option<usize> {
+        self.peer_limit(FEATURE_MAX_FRAME_PREFIX)
+    }
+    
+    fn peer_limit(&self, prefix: &str) -> Option<usize> {
+        self.offered.1.iter().find_map(|tag| tag.strip_prefix(prefix)?.parse().ok())
+    }
+    
+    /// Whether both peers agreed to use `feature`
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+    
+    /// All agreed feature tags
+    pub fn features(&self) -> &HashSet<String> {
+        &self.features
+    }
 }
 
 /// Transfer state for resumption
@@ -58,4 +455,316 @@ pub struct TransferState {
     pub total_size: u64,
     pub chunks_received: Vec<u64>,
     pub checksum: String,
+    /// Bytes of the file on disk when this was saved; records from before it was kept say 0
+    #[serde(default)]
+    pub bytes_written: u64,
+}
+
+impl TransferState {
+    pub fn new(filename: impl Into<String>, total_size: u64, checksum: impl Into<String>) -> Self {
+        Self {
+            filename: filename.into(),
+            total_size,
+            chunks_received: Vec::new(),
+            checksum: checksum.into(),
+            bytes_written: 0,
+        }
+    }
+    
+    /// Where the state for `output_file` is kept: next to it, or in `tmp_dir`
+    ///
+    /// In `tmp_dir` the name includes a hash of the output path, so same-named
+    /// files received to different places don't share a record.
+    pub fn state_path(output_file: &Path, tmp_dir: Option<&Path>) -> PathBuf {
+        let Some(tmp_dir) = tmp_dir else {
+            let mut name = output_file.as_os_str().to_os_string();
+            name.push(".zap-state");
+            return PathBuf::from(name);
+        };
+        let absolute = std::path::absolute(output_file).unwrap_or_else(|_| output_file.to_path_buf());
+        let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+        let name = output_file.file_name().unwrap_or_default().to_string_lossy();
+        tmp_dir.join(format!("{}-{}.zap-state", name, &hash[..16]))
+    }
+    
+    /// Write the state to `state_file` as JSON, private to the user
+    pub fn save(&self, state_file: &Path) -> anyhow::Result<()> {
+        fsutil::write_private(state_file, &serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+    
+    /// Read the state saved in `state_file`, if there is one
+    pub fn load(state_file: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(state_file) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    /// Delete `state_file`, if there is one
+    pub fn cleanup(state_file: &Path) -> anyhow::Result<()> {
+        match std::fs::remove_file(state_file) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Record a chunk as written, keeping the list sorted
+    pub fn mark_received(&mut self, index: u64) {
+        if let Err(pos) = self.chunks_received.binary_search(&index) {
+            self.chunks_received.insert(pos, index);
+        }
+    }
+    
+    /// The first chunk not received yet
+    pub fn next_chunk(&self) -> u64 {
+        self.chunks_received
+            .iter()
+            .zip(0..)
+            .take_while(|(received, expected)| **received == *expected)
+            .count() as u64
+    }
+    
+    /// Whether this state was saved for the same file
+    pub fn matches(&self, filename: &str, total_size: u64) -> bool {
+        self.filename == filename && self.total_size == total_size
+    }
+}
+
+/// What the sender keeps about a send the receiver cancelled partway
+///
+/// The receiver picks up by byte offset, so when it resumes the file must be
+/// the one it started with; the ticket is how the sender tells.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SendTicket {
+    pub filename: String,
+    pub size: u64,
+    /// The file's modification time, in seconds since the epoch
+    pub modified: u64,
+    /// Bytes sent before the receiver cancelled
+    pub sent: u64,
+}
+
+impl SendTicket {
+    /// A ticket for `path` as it is now
+    pub fn for_file(path: &Path, sent: u64) -> anyhow::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            filename: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            size: metadata.len(),
+            modified: modified_secs(&metadata),
+            sent,
+        })
+    }
+    
+    /// Where the ticket for sending `path` is kept: the user's cache directory
+    ///
+    /// Only without one does it fall back to the system's shared temporary directory.
+    pub fn ticket_path(path: &Path) -> PathBuf {
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        dir.join("zap").join("tickets").join(format!("{}-{}.zap-send", name, &hash[..16]))
+    }
+    
+    /// Write the ticket to `ticket_file` as JSON, private to the user
+    pub fn save(&self, ticket_file: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = ticket_file.parent() {
+            fsutil::create_private_dir(dir)?;
+        }
+        fsutil::write_private(ticket_file, &serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+    
+    /// Read the ticket saved in `ticket_file`, if there is one
+    pub fn load(ticket_file: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(ticket_file) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    /// Delete `ticket_file`, if there is one
+    pub fn cleanup(ticket_file: &Path) -> anyhow::Result<()> {
+        TransferState::cleanup(ticket_file)
+    }
+    
+    /// Whether `path` is still the file this ticket was written for
+    pub fn unchanged(&self, path: &Path) -> anyhow::Result<bool> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(metadata.len() == self.size && modified_secs(&metadata) == self.modified)
+    }
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn features(tags: &[&str]) -> HashSet<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+    
+    #[test]
+    fn test_negotiate_full_overlap() {
+        let mine = features(&["compression/zstd", "flow-control"]);
+        let agreed = CapabilityNegotiator::negotiate(&mine, &mine.clone());
+        assert_eq!(agreed, mine);
+    }
+    
+    #[test]
+    fn test_negotiate_partial_overlap() {
+        let mine = features(&["compression/zstd", "flow-control", "multiplexing"]);
+        let theirs = features(&["flow-control", "delta-transfer", "multiplexing"]);
+        let session = Session::new(CapabilityNegotiator::negotiate(&mine, &theirs));
+        assert_eq!(session.features(), &features(&["flow-control", "multiplexing"]));
+        assert!(session.supports("flow-control"));
+        assert!(!session.supports("compression/zstd"));
+        assert!(!session.supports("delta-transfer"));
+    }
+    
+    #[test]
+    fn test_negotiate_disjoint() {
+        let mine = features(&["compression/zstd"]);
+        let theirs = features(&["delta-transfer"]);
+        assert!(CapabilityNegotiator::negotiate(&mine, &theirs).is_empty());
+        assert!(CapabilityNegotiator::negotiate(&mine, &HashSet::new()).is_empty());
+    }
+    
+    #[test]
+    fn test_size_limits_read_from_peer_offer() {
+        let receiver = features(&["stream", "max-chunk/1397077"]);
+        let session = Session::negotiated(features(&["stream"]), receiver.clone());
+        assert_eq!(session.peer_max_chunk_size(), Some(1397077));
+        assert!(!session.supports("max-chunk/1397077"));
+        // Only what the peer offered counts
+        assert_eq!(Session::negotiated(receiver, features(&["stream"])).peer_max_chunk_size(), None);
+        assert_eq!(Session::negotiated(HashSet::new(), features(&["max-chunk/lots"])).peer_max_chunk_size(), None);
+        assert_eq!(Session::negotiated(HashSet::new(), features(&["max-frame/65536"])).peer_max_frame_size(), Some(65536));
+        let both = features(&["stream", "max-frame/65536"]);
+        assert_eq!(Session::negotiated(both.clone(), both).features(), &features(&["stream"]));
+    }
+    
+    #[test]
+    fn test_key_exchange_strength() {
+        let strength = |tags: &[&str]| key_exchange_strength(&Session::new(features(tags)));
+        assert_eq!(strength(&["stream"]), KEY_EXCHANGE_V1);
+        assert_eq!(strength(&[FEATURE_MAILBOX, FEATURE_PAKE_V2]), KEY_EXCHANGE_V1);
+        assert_eq!(strength(&[FEATURE_PAKE_V2]), KEY_EXCHANGE_V2);
+        assert_eq!(strength(&[FEATURE_PAKE_V2, FEATURE_KEM_ML_KEM_768]), KEY_EXCHANGE_V3);
+        // The KEM is only ever mixed into the PAKE's key
+        assert_eq!(strength(&[FEATURE_KEM_ML_KEM_768]), KEY_EXCHANGE_V1);
+    }
+    
+    #[test]
+    fn test_transfer_state_save_resume_cleanup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = TransferState::state_path(&dir.path().join("movie.mkv"), None);
+        assert_eq!(output, dir.path().join("movie.mkv.zap-state"));
+        assert!(TransferState::load(&output).unwrap().is_none());
+        
+        let mut state = TransferState::new("movie.mkv", 1_000_000, "abc123");
+        for index in [0, 1, 2, 4] {
+            state.mark_received(index);
+        }
+        state.mark_received(1);
+        state.save(&output).unwrap();
+        
+        let mut resumed = TransferState::load(&output).unwrap().unwrap();
+        assert!(resumed.matches("movie.mkv", 1_000_000));
+        assert_eq!(resumed.chunks_received, [0, 1, 2, 4]);
+        assert_eq!(resumed.next_chunk(), 3);
+        
+        resumed.mark_received(3);
+        resumed.save(&output).unwrap();
+        assert_eq!(TransferState::load(&output).unwrap().unwrap().next_chunk(), 5);
+        
+        TransferState::cleanup(&output).unwrap();
+        assert!(!output.exists());
+        assert!(TransferState::load(&output).unwrap().is_none());
+        TransferState::cleanup(&output).unwrap();
+    }
+    
+    #[test]
+    fn test_send_ticket_notices_changed_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("movie.mkv");
+        std::fs::write(&path, vec![7; 1000]).unwrap();
+        let ticket_file = dir.path().join("movie.zap-send");
+        
+        SendTicket::for_file(&path, 400).unwrap().save(&ticket_file).unwrap();
+        let ticket = SendTicket::load(&ticket_file).unwrap().unwrap();
+        assert_eq!((ticket.filename.as_str(), ticket.size, ticket.sent), ("movie.mkv", 1000, 400));
+        assert!(ticket.unchanged(&path).unwrap());
+        
+        std::fs::write(&path, vec![7; 1200]).unwrap();
+        assert!(!ticket.unchanged(&path).unwrap());
+        SendTicket::cleanup(&ticket_file).unwrap();
+        assert!(SendTicket::load(&ticket_file).unwrap().is_none());
+        let kept_in = dirs::cache_dir().unwrap_or_else(std::env::temp_dir).join("zap").join("tickets");
+        assert!(SendTicket::ticket_path(&path).starts_with(kept_in));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_state_and_ticket_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("movie.mkv");
+        std::fs::write(&path, vec![7; 1000]).unwrap();
+        
+        let state_file = TransferState::state_path(&path, None);
+        TransferState::new("movie.mkv", 1000, "abc123").save(&state_file).unwrap();
+        assert_eq!(mode(&state_file), 0o600);
+        
+        let ticket_file = dir.path().join("tickets/movie.zap-send");
+        SendTicket::for_file(&path, 400).unwrap().save(&ticket_file).unwrap();
+        assert_eq!((mode(&ticket_file), mode(ticket_file.parent().unwrap())), (0o600, 0o700));
+    }
+    
+    #[test]
+    fn test_capabilities_round_trip() {
+        let msg = Message::Capabilities { features: local_features() };
+        match Message::from_bytes(&msg.to_bytes().unwrap()).unwrap() {
+            Message::Capabilities { features } => assert!(features.contains(FEATURE_CIPHER_CHACHA20POLY1305)),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_json_debug_round_trip() {
+        let json = Message::Hello { version: PROTOCOL_VERSION }.to_json_debug().unwrap();
+        assert!(json.contains("\"Hello\"") && json.contains(&format!("\"version\": {}", PROTOCOL_VERSION)), "{}", json);
+        assert!(matches!(Message::from_json_debug(&json).unwrap(), Message::Hello { version: PROTOCOL_VERSION }));
+        
+        let messages = [
+            // One feature, as a set's order changes from one HashSet to the next
+            Message::Capabilities { features: HashSet::from([FEATURE_PAKE_V2.to_string()]) },
+            Message::KeyExchange { data: vec![1, 2, 3] },
+            Message::Metadata {
+                filename: "input.bin".to_string(),
+                size: 5,
+                is_directory: false,
+                checksum: "abc".to_string(),
+            },
+            Message::Complete,
+        ];
+        for message in messages {
+            let json = message.to_json_debug().unwrap();
+            let parsed = Message::from_json_debug(&json).unwrap();
+            assert_eq!(parsed.to_bytes().unwrap(), message.to_bytes().unwrap(), "{}", json);
+        }
+        assert!(Message::from_json_debug("{\"Hello\": {}}").is_err());
+    }
 }
@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// How often the allow and deny list files are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shortest code hash prefix a rule may use, so one rule can't match everyone
+pub const MIN_HASH_PREFIX: usize = 4;
+
+/// Sent to refused clients; it deliberately doesn't say which rule matched
+pub const REFUSED: &str = "Registration refused";
+
+/// An IP address range in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` falls inside this range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow!("Invalid IP address: {}", addr))?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().ok().filter(|len| *len <= max).ok_or_else(|| anyhow!("Invalid prefix length: {}", len))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// One allow or deny entry
+///
+/// Written as an address or CIDR range (`203.0.113.7`, `10.0.0.0/8`) or as a
+/// code hash prefix (`hash:3fa9c2`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    Ip(Cidr),
+    CodeHash(String),
+}
+
+impl Rule {
+    /// Whether a client at `ip` registering `code_hash` matches this rule
+    pub fn matches(&self, ip: IpAddr, code_hash: &str) -> bool {
+        match self {
+            Rule::Ip(cidr) => cidr.contains(ip),
+            Rule::CodeHash(prefix) => code_hash.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let Some(prefix) = s.strip_prefix("hash:") else {
+            return Ok(Rule::Ip(s.parse()?));
+        };
+        let prefix = prefix.to_ascii_lowercase();
+        if prefix.len() < MIN_HASH_PREFIX || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(anyhow!("Hash prefix must be at least {} hex characters", MIN_HASH_PREFIX));
+        }
+        Ok(Rule::CodeHash(prefix))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Ip(cidr) => write!(f, "{}", cidr),
+            Rule::CodeHash(prefix) => write!(f, "hash:{}", prefix),
+        }
+    }
+}
+
+/// Parse a list file: one rule per line, `#` starts a comment
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| line.parse().map_err(|e| anyhow!("Line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// A list file and the rules last read from it
+#[derive(Debug)]
+struct ListFile {
+    path: PathBuf,
+    contents: Mutex<String>,
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl ListFile {
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let rules = parse_rules(&contents).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            contents: Mutex::new(contents),
+            rules: RwLock::new(rules),
+        })
+    }
+    
+    /// Re-read the file, returning whether the rules changed
+    ///
+    /// A file that can't be read or parsed leaves the previous rules in place.
+    fn reload(&self) -> Result<bool> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let mut current = self.contents.lock().unwrap();
+        if *current == contents {
+            return Ok(false);
+        }
+        let rules = parse_rules(&contents).map_err(|e| anyhow!("{}: {}", self.path.display(), e))?;
+        *self.rules.write().unwrap() = rules;
+        *current = contents;
+        Ok(true)
+    }
+    
+    fn matches(&self, ip: IpAddr, code_hash: &str) -> bool {
+        self.rules.read().unwrap().iter().any(|rule| rule.matches(ip, code_hash))
+    }
+}
+
+/// Decides which clients may register with the relay
+///
+/// A client is refused if it matches the deny list or a runtime ban, or if an
+/// allow list is configured and it matches none of its rules.
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    allowlist: Option<ListFile>,
+    denylist: Option<ListFile>,
+    /// Rules added through the admin endpoint, kept until restart
+    banned: RwLock<Vec<Rule>>,
+}
+
+impl AccessControl {
+    /// Read the allow and deny list files
+    pub fn load(allowlist: Option<&Path>, denylist: Option<&Path>) -> Result<Self> {
+        Ok(Self {
+            allowlist: allowlist.map(ListFile::load).transpose()?,
+            denylist: denylist.map(ListFile::load).transpose()?,
+            banned: RwLock::default(),
+        })
+    }
+    
+    /// Whether a client at `ip` may register `code_hash`
+    pub fn allows(&self, ip: IpAddr, code_hash: &str) -> bool {
+        if self.banned.read().unwrap().iter().any(|rule| rule.matches(ip, code_hash)) {
+            return false;
+        }
+        if self.denylist.as_ref().is_some_and(|list| list.matches(ip, code_hash)) {
+            return false;
+        }
+        self.allowlist.as_ref().is_none_or(|list| list.matches(ip, code_hash))
+    }
+    
+    /// Refuse everything matching `rule` from now on
+    pub fn ban(&self, rule: Rule) {
+        let mut banned = self.banned.write().unwrap();
+        if !banned.contains(&rule) {
+            banned.push(rule);
+        }
+    }
+    
+    /// Rule counts for the startup banner: (allow, deny)
+    pub fn rule_counts(&self) -> (Option<usize>, Option<usize>) {
+        let count = |list: &ListFile| list.rules.read().unwrap().len();
+        (self.allowlist.as_ref().map(count), self.denylist.as_ref().map(count))
+    }
+    
+    /// Re-read any list files that changed
+    pub fn reload(&self) {
+        for list in self.allowlist.iter().chain(self.denylist.iter()) {
+            match list.reload() {
+                Ok(true) => println!("Reloaded {}", list.path.display()),
+                Ok(false) => {}
+                Err(e) => eprintln!("Keeping previous rules, couldn't reload {}", e),
+            }
+        }
+    }
+    
+    /// Watch the list files for changes until the relay exits
+    pub async fn run_reloader(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.reload();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    
+    #[test]
+    fn test_cidr_matching() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.200.3.4")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+        // Dual-stack listeners report IPv4 clients as mapped addresses
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        
+        let host: Cidr = "203.0.113.7".parse().unwrap();
+        assert!(host.contains(ip("203.0.113.7")));
+        assert!(!host.contains(ip("203.0.113.8")));
+        
+        let everyone: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everyone.contains(ip("198.51.100.1")));
+        
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::5")));
+        assert!(!v6.contains(ip("2001:db9::5")));
+        
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("relay.example.com".parse::<Cidr>().is_err());
+    }
+    
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules("# abuse reports\n192.0.2.0/24\n\nhash:3FA9C2  # scripted registrations\n").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].to_string(), "192.0.2.0/24");
+        assert_eq!(rules[1], Rule::CodeHash("3fa9c2".to_string()));
+        assert!(rules[1].matches(ip("127.0.0.1"), "3fa9c2deadbeef"));
+        
+        let err = parse_rules("10.0.0.1\nhash:ab\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"));
+    }
+    
+    #[test]
+    fn test_allow_and_deny() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let allow = dir.path().join("allow.txt");
+        let deny = dir.path().join("deny.txt");
+        std::fs::write(&allow, "10.0.0.0/8\n").unwrap();
+        std::fs::write(&deny, "10.6.6.0/24\n").unwrap();
+        
+        let access = AccessControl::load(Some(&allow), Some(&deny)).unwrap();
+        assert!(access.allows(ip("10.1.1.1"), "abcd"));
+        assert!(!access.allows(ip("10.6.6.6"), "abcd"));
+        assert!(!access.allows(ip("192.168.1.1"), "abcd"));
+        
+        access.ban("hash:abcd".parse().unwrap());
+        assert!(!access.allows(ip("10.1.1.1"), "abcdef"));
+        assert!(access.allows(ip("10.1.1.1"), "ffff"));
+        
+        assert!(AccessControl::default().allows(ip("192.168.1.1"), "abcd"));
+    }
+    
+    #[test]
+    fn test_hot_reload() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let deny = dir.path().join("deny.txt");
+        std::fs::write(&deny, "10.0.0.0/8\n").unwrap();
+        let access = AccessControl::load(None, Some(&deny)).unwrap();
+        assert!(!access.allows(ip("10.0.0.1"), "abcd"));
+        assert!(access.allows(ip("192.168.0.1"), "abcd"));
+        
+        std::fs::write(&deny, "192.168.0.0/16\n").unwrap();
+        access.reload();
+        assert!(access.allows(ip("10.0.0.1"), "abcd"));
+        assert!(!access.allows(ip("192.168.0.1"), "abcd"));
+        
+        // A broken edit keeps the rules that were working
+        std::fs::write(&deny, "192.168.0.0/99\n").unwrap();
+        access.reload();
+        assert!(!access.allows(ip("192.168.0.1"), "abcd"));
+    }
+}
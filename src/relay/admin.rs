@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use super::access::{Rule, MIN_HASH_PREFIX};
+use super::state::{RelayState, RoomInfo, StatsSnapshot};
+
+/// Commands understood by the admin endpoint
+///
+/// Each line is either JSON (`{"command":"kick","prefix":"ab12"}`) or the
+/// plain form (`kick ab12`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum AdminRequest {
+    /// Describe every live room
+    List,
+    /// Tear down rooms whose code hash starts with `prefix`
+    Kick { prefix: String },
+    /// Refuse an address, CIDR range or `hash:<prefix>` until restart, closing its rooms
+    Ban { target: String },
+    /// Relay-wide counters
+    Stats,
+}
+
+/// One JSON line sent back per request
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AdminResponse {
+    Rooms { rooms: Vec<RoomInfo> },
+    Banned { banned: String, kicked: usize },
+    Kicked { kicked: usize },
+    Stats(StatsSnapshot),
+    Error { error: String },
+}
+
+impl AdminRequest {
+    /// Parse one request line
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            return Ok(serde_json::from_str(line)?);
+        }
+        
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("list"), None, _) => Ok(AdminRequest::List),
+            (Some("stats"), None, _) => Ok(AdminRequest::Stats),
+            (Some("kick"), Some(prefix), None) => Ok(AdminRequest::Kick { prefix: prefix.to_string() }),
+            (Some("ban"), Some(target), None) => Ok(AdminRequest::Ban { target: target.to_string() }),
+            _ => Err(anyhow!("Unknown command (expected list, kick <hash-prefix>, ban <ip|cidr|hash:prefix> or stats)")),
+        }
+    }
+}
+
+/// Answer a single request
+pub async fn respond(state: &RelayState, request: AdminRequest) -> AdminResponse {
+    match request {
+        AdminRequest::List => AdminResponse::Rooms { rooms: state.list().await },
+        AdminRequest::Kick { prefix } if prefix.len() < MIN_HASH_PREFIX => AdminResponse::Error {
+            error: format!("Hash prefix must be at least {} characters", MIN_HASH_PREFIX),
+        },
+        AdminRequest::Kick { prefix } => AdminResponse::Kicked { kicked: state.kick(&prefix).await },
+        AdminRequest::Ban { target } => match target.parse::<Rule>() {
+            Ok(rule) => AdminResponse::Banned {
+                banned: rule.to_string(),
+                kicked: state.ban(rule).await,
+            },
+            Err(e) => AdminResponse::Error { error: e.to_string() },
+        },
+        AdminRequest::Stats => AdminResponse::Stats(state.stats().await),
+    }
+}
+
+/// Bind the admin TCP endpoint, refusing anything but a loopback address
+pub async fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        return Err(anyhow!("Admin address must be a loopback address, got {}", addr));
+    }
+    Ok(TcpListener::bind(addr).await?)
+}
+
+/// Serve admin clients over loopback TCP
+pub async fn serve_tcp(listener: TcpListener, state: Arc<RelayState>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle_client(stream, state.clone()));
+    }
+}
+
+/// Bind the admin Unix socket, replacing a stale one and restricting it to the owner
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Serve admin clients over a Unix socket
+#[cfg(unix)]
+pub async fn serve_unix(listener: tokio::net::UnixListener, state: Arc<RelayState>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle_client(stream, state.clone()));
+    }
+}
+
+async fn handle_client<S>(stream: S, state: Arc<RelayState>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        
+        let response = match AdminRequest::parse(&line) {
+            Ok(request) => respond(&state, request).await,
+            Err(e) => AdminResponse::Error { error: e.to_string() },
+        };
+        
+        let mut json = serde_json::to_string(&response)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::protocol::{hash_code, RelayMessage, Role};
+    use super::super::server::{serve, RelayConfig};
+    use super::super::state::RoomState;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::Lines;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+    
+    type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+    
+    struct AdminClient<S> {
+        lines: Lines<BufReader<tokio::io::ReadHalf<S>>>,
+        writer: tokio::io::WriteHalf<S>,
+    }
+    
+    impl<S: AsyncRead + AsyncWrite> AdminClient<S> {
+        fn new(stream: S) -> Self {
+            let (reader, writer) = tokio::io::split(stream);
+            Self {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+        
+        async fn command(&mut self, line: &str) -> AdminResponse {
+            self.writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+            let reply = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&reply).unwrap()
+        }
+    }
+    
+    async fn start_relay() -> (SocketAddr, Arc<RelayState>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RelayState::default());
+        let config = RelayConfig {
+            port: addr.port(),
+            ..Default::default()
+        };
+        tokio::spawn(serve(listener, config, state.clone()));
+        (addr, state)
+    }
+    
+    async fn register(relay: SocketAddr, code: &str, role: Role) -> Ws {
+        let (mut ws, _) = connect_async(format!("ws://{}", relay)).await.unwrap();
+        let register = RelayMessage::Register {
+            role,
+            code_hash: hash_code(code),
+            room_id: None,
+            version: None,
+            capabilities: Vec::new(),
+        };
+        ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+        ws
+    }
+    
+    async fn next_text(ws: &mut Ws) -> RelayMessage {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => match RelayMessage::from_json(&text).unwrap() {
+                    RelayMessage::Welcome { .. } => continue,
+                    msg => return msg,
+                },
+                _ => continue,
+            }
+        }
+    }
+    
+    /// Wait for the relay to process registrations, which happen asynchronously
+    async fn rooms(admin: &mut AdminClient<TcpStream>, expected: usize) -> Vec<RoomInfo> {
+        for _ in 0..50 {
+            if let AdminResponse::Rooms { rooms } = admin.command("list").await {
+                if rooms.len() == expected {
+                    return rooms;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("relay never reached {} rooms", expected);
+    }
+    
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(AdminRequest::parse("list").unwrap(), AdminRequest::List);
+        assert_eq!(AdminRequest::parse(" stats ").unwrap(), AdminRequest::Stats);
+        assert_eq!(
+            AdminRequest::parse("kick ab12").unwrap(),
+            AdminRequest::Kick { prefix: "ab12".to_string() }
+        );
+        assert_eq!(
+            AdminRequest::parse(r#"{"command":"kick","prefix":"ab12"}"#).unwrap(),
+            AdminRequest::Kick { prefix: "ab12".to_string() }
+        );
+        assert_eq!(
+            AdminRequest::parse("ban 10.0.0.0/8").unwrap(),
+            AdminRequest::Ban { target: "10.0.0.0/8".to_string() }
+        );
+        assert!(AdminRequest::parse("kick").is_err());
+        assert!(AdminRequest::parse("reboot").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_list_stats_and_kick() {
+        let (relay, state) = start_relay().await;
+        let admin_listener = bind_tcp("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(admin_listener, state));
+        let mut admin = AdminClient::new(TcpStream::connect(admin_addr).await.unwrap());
+        
+        let mut sender = register(relay, "alpha-bravo-charlie", Role::Sender).await;
+        let waiting = rooms(&mut admin, 1).await;
+        assert_eq!(waiting[0].state, RoomState::Waiting);
+        assert_eq!(waiting[0].hash_prefix, &hash_code("alpha-bravo-charlie")[..8]);
+        
+        let mut receiver = register(relay, "alpha-bravo-charlie", Role::Receiver).await;
+        assert!(matches!(next_text(&mut sender).await, RelayMessage::Matched { .. }));
+        assert!(matches!(next_text(&mut receiver).await, RelayMessage::Matched { .. }));
+        
+        // The first registrant's data must reach its peer too
+        sender.send(Message::Binary(vec![7u8; 100])).await.unwrap();
+        loop {
+            if let Message::Binary(data) = receiver.next().await.unwrap().unwrap() {
+                assert_eq!(data, vec![7u8; 100]);
+                break;
+            }
+        }
+        
+        let matched = rooms(&mut admin, 1).await;
+        assert_eq!(matched[0].state, RoomState::Matched);
+        assert_eq!(matched[0].bytes_forwarded, 100);
+        
+        let AdminResponse::Stats(stats) = admin.command(r#"{"command":"stats"}"#).await else {
+            panic!("expected stats");
+        };
+        assert_eq!(stats.rooms_matched, 1);
+        assert_eq!(stats.rooms_waiting, 0);
+        assert_eq!(stats.matches_total, 1);
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!(stats.bytes_forwarded_total, 100);
+        
+        assert!(matches!(admin.command("kick ab").await, AdminResponse::Error { .. }));
+        let prefix = &matched[0].hash_prefix;
+        assert_eq!(admin.command(&format!("kick {}", prefix)).await, AdminResponse::Kicked { kicked: 1 });
+        
+        match next_text(&mut sender).await {
+            RelayMessage::Error { message, .. } => assert_eq!(message, "Room closed by relay operator"),
+            other => panic!("expected error, got {:?}", other),
+        }
+        rooms(&mut admin, 0).await;
+    }
+    
+    #[tokio::test]
+    async fn test_ban() {
+        let (relay, state) = start_relay().await;
+        let admin_listener = bind_tcp("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(admin_listener, state));
+        let mut admin = AdminClient::new(TcpStream::connect(admin_addr).await.unwrap());
+        
+        let mut sender = register(relay, "alpha-bravo-charlie", Role::Sender).await;
+        rooms(&mut admin, 1).await;
+        
+        assert!(matches!(admin.command("ban 127.0.0.1/99").await, AdminResponse::Error { .. }));
+        assert_eq!(
+            admin.command("ban 127.0.0.0/8").await,
+            AdminResponse::Banned {
+                banned: "127.0.0.0/8".to_string(),
+                kicked: 1,
+            }
+        );
+        match next_text(&mut sender).await {
+            RelayMessage::Error { message, .. } => assert_eq!(message, "Room closed by relay operator"),
+            other => panic!("expected error, got {:?}", other),
+        }
+        
+        // Banned addresses can't come back
+        let mut again = register(relay, "delta-echo-foxtrot", Role::Sender).await;
+        match next_text(&mut again).await {
+            RelayMessage::Error { message, .. } => assert_eq!(message, "Registration refused"),
+            other => panic!("expected error, got {:?}", other),
+        }
+        rooms(&mut admin, 0).await;
+        
+        let AdminResponse::Stats(stats) = admin.command("stats").await else {
+            panic!("expected stats");
+        };
+        assert_eq!(stats.denied_total, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_admin_addr_must_be_loopback() {
+        assert!(bind_tcp("0.0.0.0:0".parse().unwrap()).await.is_err());
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let (_, state) = start_relay().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("admin.sock");
+        let listener = bind_unix(&path).unwrap();
+        tokio::spawn(serve_unix(listener, state));
+        
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        
+        let mut admin = AdminClient::new(tokio::net::UnixStream::connect(&path).await.unwrap());
+        let AdminResponse::Stats(stats) = admin.command("stats").await else {
+            panic!("expected stats");
+        };
+        assert_eq!(stats.connections_total, 0);
+        assert!(matches!(admin.command("bogus").await, AdminResponse::Error { .. }));
+    }
+}
@@ -1,64 +1,550 @@
 use anyhow::{anyhow, Result};
 use futures_util::{SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async_tls, connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::PollSender;
 
-use super::protocol::{hash_code, RelayMessage, Role};
+use crate::network;
+use crate::tui::glyphs::glyphs;
+use super::discovery;
+use super::protocol::{
+    hash_code, negotiate, RelayMessage, Role, CAP_FRAME_MAGIC, CAP_KEEPALIVE, CAP_MAILBOX, CAP_PEER_HINT, CAP_PICKUP, CAP_ROOM_EXPIRY, CAP_SPLIT_PAYLOADS, FRAME_MAGIC,
+    MIN_RELAY_PROTOCOL_VERSION, PEER_DISCONNECTED, RELAY_PROTOCOL_VERSION,
+};
+use super::url::RelayUrl;
+
+pub(super) const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// How long to wait for each relay in a list before trying the next
+pub const RELAY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the relay's Welcome before assuming a version 1 relay
+pub const WELCOME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a transfer's relay connection is pinged; twice this without a pong and it's given up on
+pub const RELAY_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Relay features a single-room connection can use
+const CAPABILITIES: &[&str] = &[CAP_MAILBOX, CAP_FRAME_MAGIC, CAP_KEEPALIVE, CAP_ROOM_EXPIRY, CAP_SPLIT_PAYLOADS];
+
+/// Messages buffered per direction before the connection stops reading or writing
+const QUEUE_DEPTH: usize = 8;
+
+type RelayStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Why a relay connection was given up on
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RelayError {
+    /// The relay didn't answer pings for this long
+    #[error("Relay stopped responding (no pong for {:.1}s)", .waited.as_secs_f64())]
+    Unresponsive { waited: Duration },
+}
+
+/// What the health monitor and the websocket pump share
+struct Health {
+    last_pong: Mutex<Instant>,
+    status: watch::Sender<Option<RelayError>>,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            last_pong: Mutex::new(Instant::now()),
+            status: watch::Sender::new(None),
+        }
+    }
+    
+    fn error(&self) -> Option<RelayError> {
+        self.status.borrow().clone()
+    }
+    
+    /// Record the first thing that went wrong; later ones follow from it
+    fn fail(&self, error: RelayError) {
+        self.status.send_if_modified(|status| status.is_none() && status.replace(error).is_none());
+    }
+}
+
+/// What the relay said about itself when we connected
+pub(super) struct Welcome {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// Read the relay's Welcome, treating a relay that sends none as version 1
+pub(super) async fn read_welcome(ws: &mut RelayStream) -> Result<Welcome> {
+    let legacy = Welcome {
+        version: MIN_RELAY_PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+    };
+    
+    let msg = match tokio::time::timeout(WELCOME_TIMEOUT, ws.next()).await {
+        Ok(Some(msg)) => msg?,
+        Ok(None) => return Err(anyhow!("Relay connection closed during handshake")),
+        Err(_) => return Ok(legacy),
+    };
+    
+    match msg {
+        Message::Text(text) => match RelayMessage::from_json(&text) {
+            Ok(RelayMessage::Welcome { version, capabilities }) => {
+                if version < MIN_RELAY_PROTOCOL_VERSION {
+                    return Err(anyhow!(
+                        "Relay speaks protocol version {}, this client needs {}-{}",
+                        version, MIN_RELAY_PROTOCOL_VERSION, RELAY_PROTOCOL_VERSION
+                    ));
+                }
+                Ok(Welcome { version, capabilities })
+            }
+            Ok(RelayMessage::Error { message, .. }) => Err(anyhow!("Relay error: {}", message)),
+            _ => Ok(legacy),
+        },
+        _ => Ok(legacy),
+    }
+}
+
+/// Order relays so both peers try them in the same sequence
+///
+/// The list is sorted first, so it doesn't matter how each side wrote it, then
+/// rotated by a hash of the code so different transfers spread across relays.
+pub fn relay_order(relays: &[String], code: &str) -> Vec<String> {
+    let mut ordered = relays.to_vec();
+    ordered.sort();
+    ordered.dedup();
+    if ordered.is_empty() {
+        return ordered;
+    }
+    
+    let start = (code_seed(code) % ordered.len() as u64) as usize;
+    ordered.rotate_left(start);
+    ordered
+}
+
+/// Number derived from the code that both peers agree on without talking
+pub(super) fn code_seed(code: &str) -> u64 {
+    let hash = blake3::hash(code.as_bytes());
+    u64::from_be_bytes(hash.as_bytes()[..8].try_into().expect("hash is 32 bytes"))
+}
+
+/// Prepend the payload length so the peer can reassemble it from several frames
+pub(super) fn length_prefixed(data: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| anyhow!("Payload too large: {} bytes", data.len()))?;
+    
+    let mut payload = Vec::with_capacity(LENGTH_PREFIX_SIZE + data.len());
+    payload.extend_from_slice(&len.to_be_bytes());
+    payload.extend_from_slice(data);
+    Ok(payload)
+}
+
+/// Read the payload length from the first frame of a length-prefixed payload
+pub(super) fn payload_len(first_frame: &[u8]) -> Result<usize> {
+    if first_frame.len() < LENGTH_PREFIX_SIZE {
+        return Err(anyhow!("Relay frame too short: {} bytes", first_frame.len()));
+    }
+    
+    let mut len_bytes = [0u8; LENGTH_PREFIX_SIZE];
+    len_bytes.copy_from_slice(&first_frame[..LENGTH_PREFIX_SIZE]);
+    Ok(u32::from_be_bytes(len_bytes) as usize)
+}
+
+/// Open a websocket to `url` on a `network::reusable_socket`, returning it and its local address
+async fn connect_reusable(url: &str) -> Result<(RelayStream, Option<SocketAddr>)> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    let host = uri.host().ok_or_else(|| anyhow!("No host in relay URL {}", url))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+    
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host((host.as_str(), port)).await? {
+        match network::reusable_socket(&addr)?.connect(addr).await {
+            Ok(stream) => {
+                let local_addr = stream.local_addr()?;
+                let (ws_stream, _) = client_async_tls(request, stream).await?;
+                return Ok((ws_stream, Some(local_addr)));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.map_or_else(|| anyhow!("{} has no addresses", host), Into::into))
+}
 
 /// Relay client connection
+///
+/// A background task pumps the websocket, so the health monitor's pings can
+/// go out while a transfer is busy elsewhere and its pongs are seen even when
+/// nothing is being received.
 pub struct RelayConnection {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    outgoing: mpsc::Sender<Message>,
+    /// The same queue, for writes through `AsyncWrite`
+    writer: PollSender<Message>,
+    incoming: mpsc::Receiver<Result<Message, WsError>>,
+    health: Arc<Health>,
+    /// Pumps the websocket; finishes once everything queued is written
+    driver: JoinHandle<()>,
+    max_frame_size: usize,
+    /// URL of the relay this connection ended up on
+    relay: String,
+    /// Protocol version the relay announced (1 if it didn't)
+    relay_version: u32,
+    /// Capabilities both we and the relay support
+    features: Vec<String>,
+    /// Frame being handed out through `AsyncRead`, and how much of it has been read
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Frames of a message `receive` has only part of, kept if it's cancelled
+    partial: Vec<u8>,
+    /// Binary frames carry `FRAME_MAGIC` in both directions
+    frame_magic: bool,
+    /// Payloads are length-prefixed and split across frames (`CAP_SPLIT_PAYLOADS`)
+    split: bool,
+    /// Our end of the relay socket, when it was opened for `connect_with_hint`
+    local_addr: Option<SocketAddr>,
+    /// Where the relay saw the peer connect from, if both of us asked
+    peer_hint: Option<SocketAddr>,
+    /// The role this connection registered with, once it has
+    role: Option<Role>,
 }
 
 impl RelayConnection {
     /// Connect to a relay server and register
-    pub async fn connect(relay_addr: &str, code: &str, role: Role) -> Result<Self> {
-        // Ensure the address has ws:// prefix
-        let url = if relay_addr.starts_with("ws://") || relay_addr.starts_with("wss://") {
-            relay_addr.to_string()
-        } else {
-            format!("ws://{}", relay_addr)
-        };
-        
-        println!("Connecting to relay: {}", url);
-        
-        let (ws_stream, _) = connect_async(&url)
-            .await
-            .map_err(|e| anyhow!("Failed to connect to relay: {}", e))?;
-        
-        let mut conn = Self { ws: ws_stream };
+    ///
+    /// With several relays, they're tried in `relay_order` until one accepts
+    /// the connection.
+    ///
+    /// Payloads larger than `max_frame_size` are split across several
+    /// WebSocket frames so the relay never rejects them. A relay from before
+    /// `CAP_SPLIT_PAYLOADS` gets each payload as one frame, as it expects.
+    pub async fn connect(relays: &[RelayUrl], code: &str, role: Role, max_frame_size: usize) -> Result<Self> {
+        Self::connect_with(relays, code, role, max_frame_size, false, || {}).await
+    }
+    
+    /// Connect like `connect`, and ask the relay where the peer is connecting from
+    ///
+    /// The relay only says when the peer asked as well; see `peer_hint`. The
+    /// socket's local port can be bound again while it's open, so the peer
+    /// can be tried from the same port the relay saw (`Transport::upgrade_direct`).
+    pub async fn connect_with_hint(relays: &[RelayUrl], code: &str, role: Role, max_frame_size: usize) -> Result<Self> {
+        Self::connect_with(relays, code, role, max_frame_size, true, || {}).await
+    }
+    
+    /// Connect like `connect`, or `connect_with_hint` with `hint`, calling `on_renew` whenever the room is registered again
+    ///
+    /// A peer can take hours to turn up (`--wait`), longer than the relay keeps
+    /// a waiting room or a NAT keeps a quiet connection. When the relay says
+    /// the room expired, or the connection is lost once it has stood a while,
+    /// the room is registered again instead of the wait failing.
+    pub async fn connect_with(
+        relays: &[RelayUrl],
+        code: &str,
+        role: Role,
+        max_frame_size: usize,
+        hint: bool,
+        on_renew: impl Fn(),
+    ) -> Result<Self> {
+        Self::register(relays, code, role, max_frame_size, hint, false, on_renew).await
+    }
+    
+    /// Connect as the receiver, asking to be served an upload left in the relay's mailbox if no live sender is there
+    ///
+    /// Registers again, calling `on_renew`, as `connect_with` does.
+    pub async fn pickup(relays: &[RelayUrl], code: &str, max_frame_size: usize, on_renew: impl Fn()) -> Result<Self> {
+        Self::register(relays, code, Role::Receiver, max_frame_size, false, true, on_renew).await
+    }
+    
+    async fn register(
+        relays: &[RelayUrl],
+        code: &str,
+        role: Role,
+        max_frame_size: usize,
+        hint: bool,
+        pickup: bool,
+        on_renew: impl Fn(),
+    ) -> Result<Self> {
+        loop {
+            let mut conn = Self::open(relays, code, max_frame_size, hint, pickup).await?;
+            if pickup && !conn.supports(CAP_PICKUP) {
+                return Err(anyhow!("Relay {} doesn't hand out mailbox uploads", conn.relay));
+            }
+            conn.role = Some(role.clone());
+            
+            // Send registration message
+            let code_hash = hash_code(code);
+            let register_msg = RelayMessage::Register {
+                role: role.clone(),
+                code_hash,
+                room_id: None,
+                // Only the version and features the relay announced, so both ends agree on how frames look
+                version: Some(conn.spoken_version()),
+                capabilities: conn.features.clone(),
+            };
+            
+            conn.send_message(&register_msg).await?;
+            
+            if conn.wait_for_peer().await? {
+                println!("{} Matched with peer via relay", glyphs().check);
+                return Ok(conn);
+            }
+            on_renew();
+        }
+    }
+    
+    /// Connect to a relay server and ask it to store this upload in its mailbox
+    ///
+    /// Returns the connection and how long the relay will keep the upload.
+    pub async fn store(relays: &[RelayUrl], code: &str, ttl: Duration, max_frame_size: usize) -> Result<(Self, Duration)> {
+        let mut conn = Self::open(relays, code, max_frame_size, false, false).await?;
+        if !conn.supports(CAP_MAILBOX) {
+            return Err(anyhow!("Relay {} doesn't support mailbox mode", conn.relay));
+        }
         
-        // Send registration message
-        let code_hash = hash_code(code);
-        let register_msg = RelayMessage::Register {
-            role,
-            code_hash,
+        let offer_msg = RelayMessage::OfferStore {
+            code_hash: hash_code(code),
+            ttl_secs: ttl.as_secs(),
+            version: Some(conn.spoken_version()),
+            capabilities: conn.features.clone(),
         };
+        conn.send_message(&offer_msg).await?;
         
-        conn.send_message(&register_msg).await?;
+        match conn.wait_for(|msg| matches!(msg, RelayMessage::StoreAccepted { .. })).await? {
+            RelayMessage::StoreAccepted { ttl_secs } => Ok((conn, Duration::from_secs(ttl_secs))),
+            _ => unreachable!("wait_for only returns the requested message"),
+        }
+    }
+    
+    /// Tell the relay the mailbox upload is complete and wait until it's stored
+    pub async fn finish_store(&mut self) -> Result<()> {
+        // Queued behind the upload, so the relay sees it last
+        self.send_message(&RelayMessage::StoreDone).await?;
+        self.wait_for(|msg| matches!(msg, RelayMessage::Stored)).await?;
+        Ok(())
+    }
+    
+    /// URL of the relay this connection is using
+    pub fn relay_url(&self) -> &str {
+        &self.relay
+    }
+    
+    /// Protocol version the relay announced (1 for relays that predate versioning)
+    pub fn relay_version(&self) -> u32 {
+        self.relay_version
+    }
+    
+    /// The protocol version both ends speak
+    fn spoken_version(&self) -> u32 {
+        self.relay_version.min(RELAY_PROTOCOL_VERSION)
+    }
+    
+    /// Where the relay saw the peer connect from, when both sides used `connect_with_hint`
+    pub fn peer_hint(&self) -> Option<SocketAddr> {
+        self.peer_hint
+    }
+    
+    /// The role this connection registered with; `None` for mailbox uploads
+    pub fn role(&self) -> Option<&Role> {
+        self.role.as_ref()
+    }
+    
+    /// Our end of the relay socket, when it was opened by `connect_with_hint`
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    
+    /// Whether both sides support `capability`
+    pub fn supports(&self, capability: &str) -> bool {
+        self.features.iter().any(|feature| feature == capability)
+    }
+    
+    /// Ping the relay every `interval` and give up on it when no pong comes back within `2 * interval`
+    ///
+    /// Giving up fails `send` and `receive` with a `RelayError`, including any
+    /// already waiting, and shows on the `health` channel. The relay must
+    /// support `CAP_KEEPALIVE`, as older ones don't answer pings once matched.
+    /// The monitor stops by itself once the connection is gone.
+    pub fn spawn_health_monitor(&self, interval: Duration) -> JoinHandle<()> {
+        let outgoing = self.outgoing.downgrade();
+        let health = self.health.clone();
+        tokio::spawn(async move {
+            *health.last_pong.lock().unwrap() = Instant::now();
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let waited = health.last_pong.lock().unwrap().elapsed();
+                if waited > interval * 2 {
+                    health.fail(RelayError::Unresponsive { waited });
+                    return;
+                }
+                let (Some(outgoing), Ok(ping)) = (outgoing.upgrade(), RelayMessage::Ping.to_json()) else {
+                    return;
+                };
+                // A full queue is waiting on the relay anyway; the missing pong will tell
+                if let Err(mpsc::error::TrySendError::Closed(_)) = outgoing.try_send(Message::Text(ping)) {
+                    return;
+                }
+            }
+        })
+    }
+    
+    /// Whether the health monitor, if any, still trusts the relay
+    pub fn is_healthy(&self) -> bool {
+        self.health.error().is_none()
+    }
+    
+    /// Follow the health monitor's verdict, which stays `None` while the relay answers
+    pub fn health(&self) -> watch::Receiver<Option<RelayError>> {
+        self.health.status.subscribe()
+    }
+    
+    /// The health monitor's error, if it has given up on the relay
+    fn check_health(&self) -> Result<()> {
+        match self.health.error() {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+    
+    /// Why the pump stopped: the health monitor's error, or `closed`
+    fn closed(&self, closed: &str) -> anyhow::Error {
+        match self.health.error() {
+            Some(error) => error.into(),
+            None => anyhow!("{}", closed),
+        }
+    }
+    
+    /// Connect to the first reachable relay in the list, asking for `CAP_PEER_HINT` if `hint` is set and `CAP_PICKUP` if `pickup` is
+    async fn open(relays: &[RelayUrl], code: &str, max_frame_size: usize, hint: bool, pickup: bool) -> Result<Self> {
+        let mut urls = Vec::new();
+        for relay in relays {
+            urls.push(discovery::resolve(relay, code).await);
+        }
+        let relays = relay_order(&urls, code);
+        let mut failures = Vec::new();
+        
+        for url in relays {
+            println!("Connecting to relay: {}", url);
+            
+            let connecting = async {
+                if hint {
+                    connect_reusable(&url).await
+                } else {
+                    let (ws_stream, _) = connect_async(&url).await?;
+                    Ok((ws_stream, None))
+                }
+            };
+            match tokio::time::timeout(RELAY_CONNECT_TIMEOUT, connecting).await {
+                Ok(Ok((mut ws_stream, local_addr))) => {
+                    let welcome = read_welcome(&mut ws_stream).await?;
+                    let mut features = negotiate(CAPABILITIES, &welcome.capabilities);
+                    if hint {
+                        features.extend(negotiate(&[CAP_PEER_HINT], &welcome.capabilities));
+                    }
+                    if pickup {
+                        features.extend(negotiate(&[CAP_PICKUP], &welcome.capabilities));
+                    }
+                    let frame_magic = features.iter().any(|feature| feature == CAP_FRAME_MAGIC);
+                    let split = features.iter().any(|feature| feature == CAP_SPLIT_PAYLOADS);
+                    let (outgoing, outgoing_rx) = mpsc::channel(QUEUE_DEPTH);
+                    let (incoming_tx, incoming) = mpsc::channel(QUEUE_DEPTH);
+                    let health = Arc::new(Health::new());
+                    let driver = tokio::spawn(drive(ws_stream, outgoing_rx, incoming_tx, health.clone()));
+                    return Ok(Self {
+                        writer: PollSender::new(outgoing.clone()),
+                        outgoing,
+                        incoming,
+                        health,
+                        driver,
+                        max_frame_size: max_frame_size.max(LENGTH_PREFIX_SIZE + 2),
+                        relay: url,
+                        relay_version: welcome.version,
+                        features,
+                        read_buf: Vec::new(),
+                        read_pos: 0,
+                        partial: Vec::new(),
+                        frame_magic,
+                        split,
+                        local_addr,
+                        peer_hint: None,
+                        role: None,
+                    });
+                }
+                Ok(Err(e)) => failures.push(format!("{}: {}", url, e)),
+                Err(_) => failures.push(format!("{}: timed out", url)),
+            }
+            println!("Relay unavailable, trying the next one");
+        }
         
-        // Wait for matched response
+        if failures.is_empty() {
+            return Err(anyhow!("No relay address given"));
+        }
+        Err(anyhow!("Failed to connect to relay: {}", failures.join("; ")))
+    }
+    
+    /// Wait to be matched, noting the peer's address if it comes first; false when the room has to be registered again
+    ///
+    /// Relays that answer pings are pinged meanwhile, which keeps NATs from
+    /// forgetting the connection and notices a relay that went quiet. Losing
+    /// the connection right after registering is still an error, so a relay
+    /// that hangs up on us isn't retried over and over.
+    async fn wait_for_peer(&mut self) -> Result<bool> {
+        let registered = Instant::now();
+        let keepalive = self.supports(CAP_KEEPALIVE);
+        *self.health.last_pong.lock().unwrap() = registered;
+        let mut pings = tokio::time::interval_at(registered + RELAY_PING_INTERVAL, RELAY_PING_INTERVAL);
         loop {
-            if let Some(msg) = conn.ws.next().await {
-                match msg? {
-                    Message::Text(text) => {
-                        match RelayMessage::from_json(&text) {
-                            Ok(RelayMessage::Matched) => {
-                                println!("✓ Matched with peer via relay");
-                                return Ok(conn);
-                            }
-                            Ok(RelayMessage::Error { message }) => {
-                                return Err(anyhow!("Relay error: {}", message));
-                            }
-                            _ => {
-                                // Ignore other messages during handshake
-                            }
-                        }
+            let msg = tokio::select! {
+                msg = self.incoming.recv() => msg,
+                _ = pings.tick(), if keepalive => {
+                    if self.health.last_pong.lock().unwrap().elapsed() > RELAY_PING_INTERVAL * 2 {
+                        return Ok(false);
                     }
+                    self.send_message(&RelayMessage::Ping).await?;
+                    continue;
+                }
+            };
+            match msg {
+                Some(Ok(Message::Text(text))) => match RelayMessage::from_json(&text) {
+                    Ok(RelayMessage::Matched { .. }) => return Ok(true),
+                    Ok(RelayMessage::PeerHint { external_addr, .. }) => self.peer_hint = Some(external_addr),
+                    Ok(RelayMessage::RoomExpired { .. }) => return Ok(false),
+                    Ok(RelayMessage::Error { message, .. }) => return Err(anyhow!("Relay error: {}", message)),
                     _ => {}
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None if registered.elapsed() >= RELAY_PING_INTERVAL => return Ok(false),
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(Message::Close(_))) | None => return Err(self.closed("Relay connection closed during handshake")),
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+    
+    /// Wait for a control message matching `wanted`, failing on relay errors
+    async fn wait_for(&mut self, wanted: impl Fn(&RelayMessage) -> bool) -> Result<RelayMessage> {
+        loop {
+            if let Some(msg) = self.incoming.recv().await {
+                if let Message::Text(text) = msg? {
+                    match RelayMessage::from_json(&text) {
+                        Ok(RelayMessage::Error { message, .. }) => {
+                            return Err(anyhow!("Relay error: {}", message));
+                        }
+                        Ok(msg) if wanted(&msg) => return Ok(msg),
+                        _ => {
+                            // Ignore other messages during handshake
+                        }
+                    }
                 }
             } else {
-                return Err(anyhow!("Relay connection closed during handshake"));
+                return Err(self.closed("Relay connection closed during handshake"));
             }
         }
     }
@@ -66,31 +552,102 @@ impl RelayConnection {
     /// Send a relay protocol message (JSON)
     async fn send_message(&mut self, msg: &RelayMessage) -> Result<()> {
         let json = msg.to_json()?;
-        self.ws.send(Message::Text(json)).await?;
+        self.queue(Message::Text(json)).await
+    }
+    
+    /// Hand a message to the pump
+    async fn queue(&mut self, msg: Message) -> Result<()> {
+        if self.outgoing.send(msg).await.is_err() {
+            return Err(self.closed("Relay connection closed"));
+        }
         Ok(())
     }
     
     /// Send binary data through relay
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
-        self.ws.send(Message::Binary(data.to_vec())).await?;
+        self.check_health()?;
+        self.send_framed(data).await
+    }
+    
+    /// Send a length-prefixed payload, split into frames no larger than `max_frame_size`
+    ///
+    /// Without `CAP_SPLIT_PAYLOADS` the payload goes as it is, in one frame.
+    async fn send_framed(&mut self, data: &[u8]) -> Result<()> {
+        if !self.split {
+            let frame = self.mark(data);
+            return self.queue(Message::Binary(frame)).await;
+        }
+        let payload = length_prefixed(data)?;
+        let magic_len = usize::from(self.frame_magic);
+        for frame in payload.chunks(self.max_frame_size - magic_len) {
+            let frame = self.mark(frame);
+            self.queue(Message::Binary(frame)).await?;
+        }
         Ok(())
     }
     
-    /// Receive binary data from relay
+    /// A binary frame carrying `data`, with the magic byte if agreed
+    fn mark(&self, data: &[u8]) -> Vec<u8> {
+        if !self.frame_magic {
+            return data.to_vec();
+        }
+        let mut frame = Vec::with_capacity(1 + data.len());
+        frame.push(FRAME_MAGIC);
+        frame.extend_from_slice(data);
+        frame
+    }
+    
+    /// Check and remove the magic byte from a frame the relay forwarded
+    fn unmark(&self, mut frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !self.frame_magic {
+            return Ok(frame);
+        }
+        if frame.first() != Some(&FRAME_MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Relay frame is missing the zap frame marker"));
+        }
+        frame.remove(0);
+        Ok(frame)
+    }
+    
+    /// Receive binary data from relay, reassembling split payloads; safe to cancel, like `Connection::receive`
     pub async fn receive(&mut self) -> Result<Vec<u8>> {
+        self.check_health()?;
+        if !self.split {
+            return self.receive_frame().await;
+        }
+        // Frames are only taken off the queue whole, so stopping between them loses nothing
         loop {
-            if let Some(msg) = self.ws.next().await {
+            let frame = self.receive_frame().await?;
+            self.partial.extend_from_slice(&frame);
+            let len = payload_len(&self.partial)?;
+            if self.partial.len() >= LENGTH_PREFIX_SIZE + len {
+                let mut payload = std::mem::take(&mut self.partial);
+                if payload.len() != LENGTH_PREFIX_SIZE + len {
+                    return Err(anyhow!("Relay payload length mismatch"));
+                }
+                return Ok(payload.split_off(LENGTH_PREFIX_SIZE));
+            }
+        }
+    }
+    
+    /// Receive a single binary frame from relay
+    async fn receive_frame(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(msg) = self.incoming.recv().await {
                 match msg? {
                     Message::Binary(data) => {
-                        return Ok(data);
+                        return Ok(self.unmark(data)?);
                     }
                     Message::Text(text) => {
                         // Handle control messages
                         if let Ok(relay_msg) = RelayMessage::from_json(&text) {
                             match relay_msg {
-                                RelayMessage::Error { message } => {
+                                RelayMessage::Error { message, .. } => {
                                     return Err(anyhow!("Relay error: {}", message));
                                 }
+                                RelayMessage::PeerDisconnected { .. } => {
+                                    return Err(anyhow!(PEER_DISCONNECTED));
+                                }
                                 RelayMessage::Ping => {
                                     self.send_message(&RelayMessage::Pong).await?;
                                 }
@@ -106,14 +663,453 @@ impl RelayConnection {
                     _ => {}
                 }
             } else {
-                return Err(anyhow!("Relay connection closed"));
+                return Err(self.closed("Relay connection closed"));
             }
         }
     }
     
-    /// Close the connection
-    pub async fn close(mut self) -> Result<()> {
-        self.ws.close(None).await?;
+    /// Close the connection once everything queued has been written
+    pub async fn close(self) -> Result<()> {
+        let Self { outgoing, writer, driver, .. } = self;
+        drop((outgoing, writer));
+        driver.await?;
         Ok(())
     }
 }
+
+/// Pump the websocket: write queued messages and queue incoming ones, keeping pongs for the health monitor
+///
+/// Incoming messages wait for the connection to read them without holding up
+/// outgoing ones. Once the health monitor gives up, the pump drops the
+/// websocket so whatever is waiting on the connection fails straight away.
+async fn drive(
+    ws: RelayStream,
+    mut outgoing_rx: mpsc::Receiver<Message>,
+    incoming: mpsc::Sender<Result<Message, WsError>>,
+    health: Arc<Health>,
+) {
+    let (mut sink, mut stream) = ws.split();
+    let mut status = health.status.subscribe();
+    let mut pending = None;
+    let mut reading = true;
+    
+    loop {
+        tokio::select! {
+            msg = outgoing_rx.recv() => match msg {
+                Some(msg) => {
+                    if sink.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    // The connection was dropped or closed
+                    let _ = sink.close().await;
+                    break;
+                }
+            },
+            permit = incoming.reserve(), if pending.is_some() => match permit {
+                Ok(permit) => permit.send(pending.take().expect("pending checked above")),
+                // Nobody reads any more, but what's queued still goes out
+                Err(_) => {
+                    pending = None;
+                    reading = false;
+                }
+            },
+            msg = stream.next(), if pending.is_none() && reading => match msg {
+                Some(Ok(Message::Text(text))) => match RelayMessage::from_json(&text) {
+                    Ok(RelayMessage::Pong) => *health.last_pong.lock().unwrap() = Instant::now(),
+                    _ => pending = Some(Ok(Message::Text(text))),
+                },
+                Some(Ok(msg)) => pending = Some(Ok(msg)),
+                Some(Err(e)) => {
+                    let _ = incoming.try_send(Err(e));
+                    break;
+                }
+                None => break,
+            },
+            // The status only ever changes to an error
+            _ = status.changed() => break,
+        }
+    }
+}
+
+/// Raw byte stream over the relay
+///
+/// Each write goes out as binary frames of at most `max_frame_size` bytes, and
+/// reads hand back the peer's frames end to end, much like a TCP stream. Don't
+/// interleave this with `send` and `receive` on the same connection.
+impl AsyncRead for RelayConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.read_pos == this.read_buf.len() {
+            match ready!(this.incoming.poll_recv(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = this.unmark(data)?;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(RelayMessage::Error { message, .. }) = RelayMessage::from_json(&text) {
+                        return Poll::Ready(Err(io::Error::other(format!("Relay error: {}", message))));
+                    }
+                }
+                Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(())),
+                None => {
+                    return Poll::Ready(match this.health.error() {
+                        Some(error) => Err(io::Error::other(error)),
+                        None => Ok(()),
+                    });
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+        
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RelayConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.writer.poll_reserve(cx)).map_err(|_| self.write_closed())?;
+        let len = buf.len().min(self.max_frame_size - usize::from(self.frame_magic));
+        let frame = self.mark(&buf[..len]);
+        self.writer.send_item(Message::Binary(frame)).map_err(|_| self.write_closed())?;
+        Poll::Ready(Ok(len))
+    }
+    
+    /// Queued frames are written by the pump, so there's nothing to wait for
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.writer.poll_reserve(cx)).map_err(|_| self.write_closed())?;
+        self.writer.send_item(Message::Close(None)).map_err(|_| self.write_closed())?;
+        self.writer.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl RelayConnection {
+    fn write_closed(&self) -> io::Error {
+        match self.health.error() {
+            Some(error) => io::Error::other(error),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "Relay connection closed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{serve, Mailbox, MailboxConfig, RelayConfig, RelaySession, RelayState, MAX_RELAY_FRAME_SIZE};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    
+    async fn start_relay(state: RelayState) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(state)));
+        url
+    }
+    
+    /// Front a current relay but drop its Welcome, like a relay from before versioning
+    async fn start_legacy_relay() -> String {
+        let relay = start_relay(RelayState::default()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let relay = relay.clone();
+                tokio::spawn(async move {
+                    let client = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let (upstream, _) = connect_async(&relay).await.unwrap();
+                    let (mut client_tx, mut client_rx) = client.split();
+                    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+                    
+                    let to_relay = async {
+                        while let Some(Ok(msg)) = client_rx.next().await {
+                            if upstream_tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    };
+                    let to_client = async {
+                        while let Some(Ok(msg)) = upstream_rx.next().await {
+                            if let Message::Text(ref text) = msg {
+                                if let Ok(RelayMessage::Welcome { .. }) = RelayMessage::from_json(text) {
+                                    continue;
+                                }
+                            }
+                            if client_tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    };
+                    tokio::select! {
+                        _ = to_relay => {}
+                        _ = to_client => {}
+                    }
+                });
+            }
+        });
+        url
+    }
+    
+    /// Forward TCP to `target` until the returned flag is set, then swallow everything without closing
+    ///
+    /// Like the relay's host dropping off the network: nothing comes back, but nothing says so either.
+    async fn start_freezable_proxy(target: &str) -> (String, Arc<AtomicBool>) {
+        let target = target.trim_start_matches("ws://").to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let frozen = Arc::new(AtomicBool::new(false));
+        
+        let flag = frozen.clone();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let upstream = TcpStream::connect(&target).await.unwrap();
+                let (client_rx, client_tx) = client.into_split();
+                let (upstream_rx, upstream_tx) = upstream.into_split();
+                tokio::spawn(pipe(client_rx, upstream_tx, flag.clone()));
+                tokio::spawn(pipe(upstream_rx, client_tx, flag.clone()));
+            }
+        });
+        (url, frozen)
+    }
+    
+    async fn pipe(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, frozen: Arc<AtomicBool>) {
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let read = match from.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => read,
+            };
+            if frozen.load(Ordering::SeqCst) {
+                // Hold both ends open so neither side sees a disconnect
+                return std::future::pending().await;
+            }
+            if to.write_all(&buf[..read]).await.is_err() {
+                return;
+            }
+        }
+    }
+    
+    fn parse(list: &str) -> Vec<RelayUrl> {
+        RelayUrl::parse_list(list).unwrap()
+    }
+    
+    async fn connect_pair(relay: &str) -> (RelayConnection, RelayConnection) {
+        let relays = parse(relay);
+        let (sender, receiver) = tokio::join!(
+            RelayConnection::connect(&relays, "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE),
+            RelayConnection::connect(&relays, "alpha-bravo-charlie", Role::Receiver, MAX_RELAY_FRAME_SIZE),
+        );
+        (sender.unwrap(), receiver.unwrap())
+    }
+    
+    #[test]
+    fn test_relay_order_ignores_list_order() {
+        let relays: Vec<String> = ["ws://a", "ws://b", "ws://c"].map(String::from).to_vec();
+        let reversed: Vec<String> = relays.iter().rev().cloned().collect();
+        
+        for code in ["alpha-bravo-charlie", "delta-echo-foxtrot", "golf-hotel-india"] {
+            let order = relay_order(&relays, code);
+            assert_eq!(order, relay_order(&reversed, code));
+            
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, relays);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_peers_fail_over_to_the_same_relay() {
+        // Nothing listens on the first relay's port any more
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = format!("ws://{}", dead.local_addr().unwrap());
+        drop(dead);
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        // Pick a code that makes both peers try the dead relay first
+        let relays = [dead_url.clone(), live_url.clone()];
+        let code = (0..)
+            .map(|i| format!("failover-test-{}", i))
+            .find(|code| relay_order(&relays, code)[0] == dead_url)
+            .unwrap();
+        
+        let sender_list = parse(&format!("{},{}", dead_url, live_url));
+        let receiver_list = parse(&format!("{},{}", live_url, dead_url));
+        let (sender, receiver) = tokio::join!(
+            RelayConnection::connect(&sender_list, &code, Role::Sender, MAX_RELAY_FRAME_SIZE),
+            RelayConnection::connect(&receiver_list, &code, Role::Receiver, MAX_RELAY_FRAME_SIZE),
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        assert_eq!(sender.relay_url(), live_url);
+        assert_eq!(receiver.relay_url(), live_url);
+        
+        sender.send(b"hello").await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"hello");
+    }
+    
+    #[tokio::test]
+    async fn test_url_path_sent_in_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = RelayConfig {
+            path: Some("/zap".to_string()),
+            ..Default::default()
+        };
+        tokio::spawn(serve(listener, config, Arc::new(RelayState::default())));
+        
+        // Both ways of opening the websocket go to the path in the URL
+        let relays = parse(&format!("ws://{}/zap/", addr));
+        let (sender, receiver) = tokio::join!(
+            RelayConnection::connect(&relays, "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE),
+            RelayConnection::connect_with_hint(&relays, "alpha-bravo-charlie", Role::Receiver, MAX_RELAY_FRAME_SIZE),
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        sender.send(b"hello").await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"hello");
+        
+        let wrong = RelayConnection::connect(&parse(&format!("ws://{}/other", addr)), "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE).await;
+        let error = format!("{:#}", wrong.err().unwrap());
+        assert!(error.contains("404"), "{}", error);
+    }
+    
+    #[tokio::test]
+    async fn test_new_client_old_relay() {
+        let relay = start_legacy_relay().await;
+        
+        let (mut sender, mut receiver) = connect_pair(&relay).await;
+        assert_eq!(sender.relay_version(), MIN_RELAY_PROTOCOL_VERSION);
+        assert!(!sender.supports(CAP_MAILBOX));
+        assert!(!sender.supports(CAP_KEEPALIVE));
+        sender.send(b"hello").await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"hello");
+        
+        // Features the old relay can't have are refused before using them
+        let err = RelayConnection::store(&parse(&relay), "delta-echo-foxtrot", Duration::from_secs(60), MAX_RELAY_FRAME_SIZE)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("doesn't support mailbox mode"));
+        let err = RelaySession::connect(&relay.parse().unwrap(), MAX_RELAY_FRAME_SIZE).await.err().unwrap();
+        assert!(err.to_string().contains("doesn't support several rooms"));
+    }
+    
+    #[tokio::test]
+    async fn test_capabilities_selected_from_welcome() {
+        let (sender, _receiver) = connect_pair(&start_relay(RelayState::default()).await).await;
+        assert_eq!(sender.relay_version(), RELAY_PROTOCOL_VERSION);
+        assert!(!sender.supports(CAP_MAILBOX));
+        assert!(sender.supports(CAP_KEEPALIVE));
+        
+        let dir = tempfile::TempDir::new().unwrap();
+        let mailbox = Mailbox::open(MailboxConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 1024 * 1024,
+        }).await.unwrap();
+        let (sender, _receiver) = connect_pair(&start_relay(RelayState::with_mailbox(mailbox)).await).await;
+        assert!(sender.supports(CAP_MAILBOX));
+    }
+    
+    #[tokio::test]
+    async fn test_health_monitor_notices_silent_relay() {
+        let interval = Duration::from_millis(200);
+        let relay = start_relay(RelayState::default()).await;
+        let (proxy, frozen) = start_freezable_proxy(&relay).await;
+        let (relay, proxy) = (parse(&relay), parse(&proxy));
+        let (sender, receiver) = tokio::join!(
+            RelayConnection::connect(&relay, "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE),
+            RelayConnection::connect(&proxy, "alpha-bravo-charlie", Role::Receiver, MAX_RELAY_FRAME_SIZE),
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        let monitor = receiver.spawn_health_monitor(interval);
+        let mut health = receiver.health();
+        
+        // Pongs keep coming back while the relay is reachable
+        sender.send(b"first chunk").await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"first chunk");
+        tokio::time::sleep(interval * 3).await;
+        assert!(receiver.is_healthy());
+        
+        // Mid-transfer the relay goes quiet; the receive waiting on it fails instead of hanging
+        frozen.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        sender.send(b"lost chunk").await.unwrap();
+        let err = tokio::time::timeout(interval * 3 + Duration::from_millis(150), receiver.receive())
+            .await
+            .expect("monitor should fire within 3 intervals")
+            .unwrap_err();
+        // The last pong came back at most an interval before the freeze
+        assert!(started.elapsed() >= interval, "{:?}", started.elapsed());
+        assert!(matches!(err.downcast_ref::<RelayError>(), Some(RelayError::Unresponsive { .. })), "{}", err);
+        
+        health.changed().await.unwrap();
+        assert!(matches!(*health.borrow(), Some(RelayError::Unresponsive { .. })));
+        assert!(!receiver.is_healthy());
+        assert!(receiver.send(b"reply").await.unwrap_err().to_string().contains("Relay stopped responding"));
+        monitor.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_expired_room_is_registered_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = parse(&format!("ws://{}", listener.local_addr().unwrap()));
+        let state = Arc::new(RelayState::default());
+        tokio::spawn(serve(listener, RelayConfig::default(), state.clone()));
+        let waiting = || async {
+            while state.list().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        
+        let renewals = Arc::new(AtomicUsize::new(0));
+        let sender = tokio::spawn({
+            let (relay, renewals) = (relay.clone(), renewals.clone());
+            async move {
+                let on_renew = || {
+                    renewals.fetch_add(1, Ordering::SeqCst);
+                };
+                RelayConnection::connect_with(&relay, "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE, false, on_renew).await
+            }
+        });
+        
+        // The relay gives up on the room twice before the receiver turns up
+        for expired in 1..=2 {
+            waiting().await;
+            assert_eq!(state.expire_waiting(Duration::ZERO).await, 1);
+            while renewals.load(Ordering::SeqCst) < expired {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        waiting().await;
+        let mut receiver = RelayConnection::connect(&relay, "alpha-bravo-charlie", Role::Receiver, MAX_RELAY_FRAME_SIZE).await.unwrap();
+        let mut sender = sender.await.unwrap().unwrap();
+        sender.send(b"still here").await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"still here");
+        assert_eq!(renewals.load(Ordering::SeqCst), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_all_relays_unreachable() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = format!("ws://{}", dead.local_addr().unwrap());
+        drop(dead);
+        
+        let err = RelayConnection::connect(&parse(&dead_url), "alpha-bravo-charlie", Role::Sender, MAX_RELAY_FRAME_SIZE)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(&dead_url));
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::Result;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use super::client::code_seed;
+use super::url::RelayUrl;
+
+/// SRV service name prepended to a relay domain
+const SRV_SERVICE: &str = "_zap-relay._tcp";
+
+/// How long to wait for DNS before using the value as a literal host
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// An SRV record advertising a relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Connection hints from the service's TXT record
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxtHints {
+    /// Connect with wss:// (`tls=1`)
+    pub tls: bool,
+    /// WebSocket path (`path=/zap`)
+    pub path: Option<String>,
+}
+
+/// Everything DNS said about a relay domain
+#[derive(Debug, Clone, Default)]
+struct Discovered {
+    records: Vec<SrvRecord>,
+    hints: TxtHints,
+}
+
+/// DNS queries needed for discovery, so tests can stand in for a resolver
+pub trait RelayLookup {
+    fn srv(&self, name: &str) -> impl Future<Output = Result<Vec<SrvRecord>>> + Send;
+    fn txt(&self, name: &str) -> impl Future<Output = Result<Vec<String>>> + Send;
+}
+
+/// System resolver from /etc/resolv.conf (or the platform equivalent)
+pub struct SystemLookup(TokioAsyncResolver);
+
+impl SystemLookup {
+    pub fn new() -> Result<Self> {
+        Ok(Self(TokioAsyncResolver::tokio_from_system_conf()?))
+    }
+}
+
+impl RelayLookup for SystemLookup {
+    async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        let lookup = self.0.srv_lookup(name).await?;
+        Ok(lookup
+            .iter()
+            .map(|srv| SrvRecord {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            })
+            .collect())
+    }
+    
+    async fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let lookup = self.0.txt_lookup(name).await?;
+        Ok(lookup
+            .iter()
+            .flat_map(|txt| txt.txt_data().iter().map(|data| String::from_utf8_lossy(data).into_owned()))
+            .collect())
+    }
+}
+
+/// Parse `key=value` TXT strings into connection hints
+pub fn parse_txt(entries: &[String]) -> TxtHints {
+    let mut hints = TxtHints::default();
+    for entry in entries.iter().flat_map(|entry| entry.split_whitespace()) {
+        match entry.split_once('=') {
+            Some(("tls", value)) => hints.tls = value == "1" || value.eq_ignore_ascii_case("true"),
+            Some(("path", value)) if !value.is_empty() => {
+                hints.path = Some(if value.starts_with('/') { value.to_string() } else { format!("/{}", value) });
+            }
+            _ => {}
+        }
+    }
+    hints
+}
+
+/// Pick a record from the lowest priority, weighted by `weight`
+///
+/// The choice is driven by `seed` rather than a random number, so two peers
+/// using the same code end up on the same relay.
+pub fn select_srv(records: &[SrvRecord], seed: u64) -> Option<&SrvRecord> {
+    let priority = records.iter().map(|record| record.priority).min()?;
+    let mut candidates: Vec<_> = records.iter().filter(|record| record.priority == priority).collect();
+    candidates.sort_by(|a, b| (&a.target, a.port).cmp(&(&b.target, b.port)));
+    
+    let total: u64 = candidates.iter().map(|record| record.weight as u64).sum();
+    if total == 0 {
+        return candidates.get((seed % candidates.len() as u64) as usize).copied();
+    }
+    
+    let mut point = seed % total;
+    for record in candidates {
+        if point < record.weight as u64 {
+            return Some(record);
+        }
+        point -= record.weight as u64;
+    }
+    None
+}
+
+/// Build the relay URL for a record
+pub fn srv_url(record: &SrvRecord, hints: &TxtHints) -> String {
+    let scheme = if hints.tls { "wss" } else { "ws" };
+    format!("{}://{}:{}{}", scheme, record.target, record.port, hints.path.as_deref().unwrap_or(""))
+}
+
+/// Turn a relay argument into a WebSocket URL, consulting DNS for bare domains
+///
+/// Lookups are cached for the life of the process; with no SRV records (or
+/// no answer in time) the value is used as a literal host.
+pub async fn resolve(relay: &RelayUrl, code: &str) -> String {
+    if !relay.needs_discovery() {
+        return relay.to_string();
+    }
+    
+    static CACHE: OnceLock<Mutex<HashMap<String, Option<Discovered>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    
+    let cached = cache.lock().unwrap().get(relay.host()).cloned();
+    let discovered = match cached {
+        Some(discovered) => discovered,
+        None => {
+            let discovered = match SystemLookup::new() {
+                Ok(lookup) => discover(&lookup, relay.host()).await,
+                Err(_) => None,
+            };
+            cache.lock().unwrap().insert(relay.host().to_string(), discovered.clone());
+            discovered
+        }
+    };
+    
+    url_for(relay, discovered.as_ref(), code)
+}
+
+/// Resolve with a given lookup, without the cache
+pub async fn resolve_with(lookup: &impl RelayLookup, relay: &RelayUrl, code: &str) -> String {
+    if !relay.needs_discovery() {
+        return relay.to_string();
+    }
+    url_for(relay, discover(lookup, relay.host()).await.as_ref(), code)
+}
+
+async fn discover(lookup: &impl RelayLookup, domain: &str) -> Option<Discovered> {
+    let name = format!("{}.{}", SRV_SERVICE, domain);
+    let records = tokio::time::timeout(DISCOVERY_TIMEOUT, lookup.srv(&name)).await.ok()?.ok()?;
+    if records.is_empty() {
+        return None;
+    }
+    
+    // TXT hints are optional
+    let txt = tokio::time::timeout(DISCOVERY_TIMEOUT, lookup.txt(&name))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    
+    Some(Discovered {
+        records,
+        hints: parse_txt(&txt),
+    })
+}
+
+fn url_for(relay: &RelayUrl, discovered: Option<&Discovered>, code: &str) -> String {
+    discovered
+        .and_then(|discovered| select_srv(&discovered.records, code_seed(code)).map(|record| srv_url(record, &discovered.hints)))
+        .unwrap_or_else(|| relay.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    
+    struct MockLookup {
+        srv: Vec<SrvRecord>,
+        txt: Vec<String>,
+    }
+    
+    impl RelayLookup for MockLookup {
+        async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+            assert_eq!(name, "_zap-relay._tcp.example.com");
+            if self.srv.is_empty() {
+                return Err(anyhow!("no records found"));
+            }
+            Ok(self.srv.clone())
+        }
+        
+        async fn txt(&self, _name: &str) -> Result<Vec<String>> {
+            Ok(self.txt.clone())
+        }
+    }
+    
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 7777,
+            target: target.to_string(),
+        }
+    }
+    
+    #[test]
+    fn test_parse_txt() {
+        assert_eq!(parse_txt(&[]), TxtHints::default());
+        assert_eq!(
+            parse_txt(&["tls=1 path=zap".to_string(), "other=x".to_string()]),
+            TxtHints {
+                tls: true,
+                path: Some("/zap".to_string()),
+            }
+        );
+    }
+    
+    #[test]
+    fn test_select_prefers_lowest_priority() {
+        let records = [record(20, 100, "backup.example.com"), record(10, 0, "main.example.com")];
+        for seed in 0..10 {
+            assert_eq!(select_srv(&records, seed).unwrap().target, "main.example.com");
+        }
+        assert!(select_srv(&[], 0).is_none());
+    }
+    
+    #[test]
+    fn test_select_by_weight() {
+        let records = [record(10, 1, "a.example.com"), record(10, 3, "b.example.com")];
+        let picks: Vec<_> = (0..4).map(|seed| select_srv(&records, seed).unwrap().target.as_str()).collect();
+        assert_eq!(picks, ["a.example.com", "b.example.com", "b.example.com", "b.example.com"]);
+        
+        // Record order from DNS doesn't change the choice
+        let reversed = [records[1].clone(), records[0].clone()];
+        for seed in 0..4 {
+            assert_eq!(select_srv(&records, seed), select_srv(&reversed, seed));
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_resolve_with_records() {
+        let lookup = MockLookup {
+            srv: vec![record(10, 5, "relay.example.com")],
+            txt: vec!["tls=1".to_string(), "path=/ws".to_string()],
+        };
+        assert_eq!(
+            resolve_with(&lookup, &"example.com".parse().unwrap(), "alpha-bravo-charlie").await,
+            "wss://relay.example.com:7777/ws"
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_resolve_falls_back_to_literal_host() {
+        let lookup = MockLookup {
+            srv: Vec::new(),
+            txt: Vec::new(),
+        };
+        assert_eq!(resolve_with(&lookup, &"example.com".parse().unwrap(), "alpha-bravo-charlie").await, "ws://example.com");
+        assert_eq!(resolve_with(&lookup, &"example.com:8888".parse().unwrap(), "alpha-bravo-charlie").await, "ws://example.com:8888");
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Most bytes of request line and headers read before the WebSocket upgrade
+pub const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a new connection gets to send its whole request head
+pub const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The HTTP request a connection opens with, read before it's handed to the WebSocket handshake
+///
+/// Reverse proxies and their health checks send plain requests too, so the
+/// relay looks at the path and the upgrade headers first and answers those
+/// itself instead of failing the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    /// Without any query string
+    pub path: String,
+    /// A GET asking for a WebSocket upgrade
+    pub upgrade: bool,
+    /// The last address in `X-Forwarded-For`: the one the proxy next to the relay added
+    pub forwarded_for: Option<IpAddr>,
+}
+
+impl RequestHead {
+    /// Parse a request line and headers, up to the blank line that ends them
+    pub fn parse(head: &[u8]) -> Result<Self> {
+        let head = std::str::from_utf8(head).map_err(|_| anyhow!("Request head isn't text"))?;
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(anyhow!("Malformed request line {:?}", request_line));
+        };
+        if !version.starts_with("HTTP/") {
+            return Err(anyhow!("Malformed request line {:?}", request_line));
+        }
+        
+        let (mut websocket, mut connection_upgrade, mut forwarded_for) = (false, false, None);
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(anyhow!("Malformed header {:?}", line));
+            };
+            let value = value.trim();
+            let has_token = |token: &str| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token));
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => websocket |= has_token("websocket"),
+                "connection" => connection_upgrade |= has_token("upgrade"),
+                "x-forwarded-for" => forwarded_for = value.rsplit(',').next().and_then(forwarded_addr),
+                _ => {}
+            }
+        }
+        
+        let path = target.split('?').next().unwrap_or_default();
+        Ok(Self {
+            path: path.to_string(),
+            upgrade: method == "GET" && websocket && connection_upgrade,
+            forwarded_for,
+        })
+    }
+    
+    /// Whether the request is for `prefix` (or anything under it); any path is when there's none
+    pub fn under(&self, prefix: Option<&str>) -> bool {
+        let Some(prefix) = prefix else {
+            return true;
+        };
+        match self.path.trim_end_matches('/').strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// One `X-Forwarded-For` entry, which some proxies write with a port
+fn forwarded_addr(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry.parse().ok().or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Read up to and including the blank line that ends the request head
+///
+/// Returns everything read, which can run past the head; the WebSocket
+/// handshake is given all of it.
+pub async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before the request was complete"));
+        }
+        // The end marker can straddle two reads
+        let from = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        if head[from..].windows(4).any(|window| window == b"\r\n\r\n") {
+            return Ok(head);
+        }
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(anyhow!("Request head is over {} bytes", MAX_REQUEST_HEAD));
+        }
+    }
+}
+
+/// A complete response with a short text body, after which the connection closes
+pub fn response(status: &str, extra_headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+    for (name, value) in extra_headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response.into_bytes()
+}
+
+/// `--path` as given, as the prefix requests are matched against: a leading slash and no trailing one
+pub fn parse_path_prefix(text: &str) -> Result<String> {
+    let path = text.trim().trim_end_matches('/');
+    if path.is_empty() {
+        return Err(anyhow!("Give a path like /zap, or leave --path out to accept any"));
+    }
+    if path.contains(|c: char| c.is_whitespace() || c == '?' || c == '#') {
+        return Err(anyhow!("Invalid path {:?}", text));
+    }
+    Ok(if path.starts_with('/') { path.to_string() } else { format!("/{}", path) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parse(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap()
+    }
+    
+    #[test]
+    fn test_parse_request_head() {
+        let upgrade = parse(
+            "GET /zap?x=1 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, Upgrade\r\nUpgrade: WebSocket\r\n\
+             X-Forwarded-For: 198.51.100.1, 203.0.113.7\r\n\r\n",
+        );
+        assert_eq!(upgrade.path, "/zap");
+        assert!(upgrade.upgrade);
+        assert_eq!(upgrade.forwarded_for, Some("203.0.113.7".parse().unwrap()));
+        
+        let health_check = parse("GET /healthz HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert!(!health_check.upgrade);
+        assert_eq!(health_check.forwarded_for, None);
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-Forwarded-For: [2001:db8::7]:443\r\n\r\n").forwarded_for, Some("2001:db8::7".parse().unwrap()));
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-Forwarded-For: unknown\r\n\r\n").forwarded_for, None);
+        assert!(!parse("POST /zap HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n").upgrade);
+        
+        assert!(RequestHead::parse(b"\x16\x03\x01 TLS hello\r\n\r\n").is_err());
+        assert!(RequestHead::parse(b"GET /\r\n\r\n").is_err());
+    }
+    
+    #[test]
+    fn test_paths_under_prefix() {
+        let at = |path: &str| RequestHead { path: path.to_string(), upgrade: true, forwarded_for: None };
+        assert!(at("/anything").under(None));
+        for path in ["/zap", "/zap/", "/zap/ws"] {
+            assert!(at(path).under(Some("/zap")), "{}", path);
+        }
+        for path in ["/", "/zapper", "/other/zap"] {
+            assert!(!at(path).under(Some("/zap")), "{}", path);
+        }
+        
+        assert_eq!(parse_path_prefix("zap/").unwrap(), "/zap");
+        assert_eq!(parse_path_prefix("/relays/zap").unwrap(), "/relays/zap");
+        assert!(parse_path_prefix("/").is_err());
+        assert!(parse_path_prefix("/zap?x").is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::fsutil;
+
+/// Longest a sender may ask the relay to hold an upload
+pub const MAX_MAILBOX_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default cap on disk space used by all stored uploads (1 GB)
+pub const DEFAULT_MAILBOX_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often expired uploads are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bytes before each stored frame holding its length
+const FRAME_HEADER_SIZE: u64 = 4;
+
+/// Bytes at the start of a mailbox file holding its expiry (ms since the epoch)
+const FILE_HEADER_SIZE: u64 = 8;
+
+const STORED_EXTENSION: &str = "mbox";
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Where and how much the relay may store
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// Directory holding stored uploads
+    pub dir: PathBuf,
+    /// Cap on disk space used by all stored and in-progress uploads
+    pub max_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    expires_at: SystemTime,
+    /// Being replayed to a receiver right now
+    delivering: bool,
+}
+
+/// Disk-backed store of encrypted uploads waiting for an offline receiver
+///
+/// Uploads are keyed by code hash and hold exactly the binary frames the
+/// sender sent, so the relay stays blind: it never has the code or plaintext.
+#[derive(Debug)]
+pub struct Mailbox {
+    config: MailboxConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Bytes on disk, including uploads still in progress
+    used: AtomicU64,
+}
+
+impl Mailbox {
+    /// Open the mailbox directory, dropping partial uploads and expired ones
+    pub async fn open(config: MailboxConfig) -> Result<Arc<Self>> {
+        fsutil::create_private_dir(&config.dir)
+            .with_context(|| format!("Failed to create mailbox directory {}", config.dir.display()))?;
+        
+        let mut entries = HashMap::new();
+        let mut used = 0;
+        let now = SystemTime::now();
+        
+        let mut dir = tokio::fs::read_dir(&config.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            let code_hash = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string);
+            let stored = path.extension().is_some_and(|ext| ext == STORED_EXTENSION);
+            
+            let entry = match code_hash {
+                Some(code_hash) if stored && is_code_hash(&code_hash) => read_expiry(&path)
+                    .await
+                    .ok()
+                    .filter(|expires_at| *expires_at > now)
+                    .map(|expires_at| (code_hash, expires_at)),
+                _ => None,
+            };
+            
+            match entry {
+                Some((code_hash, expires_at)) => {
+                    let size = file.metadata().await?.len();
+                    used += size;
+                    entries.insert(code_hash, Entry { path, size, expires_at, delivering: false });
+                }
+                None => {
+                    // Partial, expired or foreign file
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+            }
+        }
+        
+        Ok(Arc::new(Self {
+            config,
+            entries: Mutex::new(entries),
+            used: AtomicU64::new(used),
+        }))
+    }
+    
+    /// Start storing an upload for `code_hash`, kept for at most `ttl`
+    pub async fn begin(self: &Arc<Self>, code_hash: &str, ttl: Duration) -> Result<Upload> {
+        if !is_code_hash(code_hash) {
+            return Err(anyhow!("Invalid code hash"));
+        }
+        if self.entries.lock().unwrap().contains_key(code_hash) {
+            return Err(anyhow!("A mailbox upload for this code already exists"));
+        }
+        
+        let ttl = ttl.min(MAX_MAILBOX_TTL);
+        let expires_at = SystemTime::now() + ttl;
+        
+        let path = self.path_for(code_hash, PARTIAL_EXTENSION);
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .map_err(|_| anyhow!("A mailbox upload for this code is already in progress"))?;
+        
+        let mut upload = Upload {
+            mailbox: self.clone(),
+            code_hash: code_hash.to_string(),
+            path,
+            file: BufWriter::new(file),
+            size: 0,
+            ttl,
+            expires_at,
+            committed: false,
+        };
+        upload.reserve(FILE_HEADER_SIZE)?;
+        upload.file.write_all(&to_millis(expires_at).to_be_bytes()).await?;
+        Ok(upload)
+    }
+    
+    /// Claim the stored upload for `code_hash` so it can be replayed to a receiver
+    pub fn take(self: &Arc<Self>, code_hash: &str) -> Option<Delivery> {
+        self.sweep();
+        
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(code_hash).filter(|entry| !entry.delivering)?;
+        entry.delivering = true;
+        
+        Some(Delivery {
+            mailbox: self.clone(),
+            code_hash: code_hash.to_string(),
+            path: entry.path.clone(),
+            delivered: false,
+        })
+    }
+    
+    /// Whether an upload for `code_hash` is stored
+    pub fn contains(&self, code_hash: &str) -> bool {
+        self.sweep();
+        self.entries.lock().unwrap().contains_key(code_hash)
+    }
+    
+    /// Bytes on disk, including uploads still in progress
+    pub fn used_bytes(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+    
+    /// Delete expired uploads that aren't being delivered
+    pub fn sweep(&self) {
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| {
+            if entry.delivering || entry.expires_at > now {
+                return true;
+            }
+            let _ = std::fs::remove_file(&entry.path);
+            self.used.fetch_sub(entry.size, Ordering::Relaxed);
+            false
+        });
+    }
+    
+    /// Sweep expired uploads periodically
+    pub async fn run_sweeper(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+    
+    fn path_for(&self, code_hash: &str, extension: &str) -> PathBuf {
+        self.config.dir.join(format!("{}.{}", code_hash, extension))
+    }
+    
+    fn remove(&self, code_hash: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().remove(code_hash) {
+            let _ = std::fs::remove_file(&entry.path);
+            self.used.fetch_sub(entry.size, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An upload being written to the mailbox; dropped without `commit`, it is deleted
+pub struct Upload {
+    mailbox: Arc<Mailbox>,
+    code_hash: String,
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    ttl: Duration,
+    expires_at: SystemTime,
+    committed: bool,
+}
+
+impl Upload {
+    /// Code hash the upload is stored under
+    pub fn code_hash(&self) -> &str {
+        &self.code_hash
+    }
+    
+    /// How long the relay will keep this upload
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+    
+    /// Append one binary frame, failing if it would exceed the mailbox quota
+    pub async fn write(&mut self, frame: &[u8]) -> Result<()> {
+        let len = u32::try_from(frame.len()).map_err(|_| anyhow!("Frame too large"))?;
+        self.reserve(FRAME_HEADER_SIZE + frame.len() as u64)?;
+        self.file.write_all(&len.to_be_bytes()).await?;
+        self.file.write_all(frame).await?;
+        Ok(())
+    }
+    
+    /// Make the upload available to receivers
+    pub async fn commit(mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file.get_mut().sync_all().await?;
+        
+        let stored = self.mailbox.path_for(&self.code_hash, STORED_EXTENSION);
+        tokio::fs::rename(&self.path, &stored).await?;
+        self.committed = true;
+        
+        self.mailbox.entries.lock().unwrap().insert(self.code_hash.clone(), Entry {
+            path: stored,
+            size: self.size,
+            expires_at: self.expires_at,
+            delivering: false,
+        });
+        Ok(())
+    }
+    
+    fn reserve(&mut self, bytes: u64) -> Result<()> {
+        let used = self.mailbox.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.mailbox.config.max_bytes {
+            self.mailbox.used.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(anyhow!("Mailbox quota exceeded"));
+        }
+        self.size += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.path);
+            self.mailbox.used.fetch_sub(self.size, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A stored upload claimed by a receiver
+///
+/// `finish` deletes it; dropped unfinished, it becomes available again.
+pub struct Delivery {
+    mailbox: Arc<Mailbox>,
+    code_hash: String,
+    path: PathBuf,
+    delivered: bool,
+}
+
+impl Delivery {
+    /// Read back the stored frames in order
+    pub async fn frames(&self) -> Result<FrameReader> {
+        let mut file = BufReader::new(File::open(&self.path).await?);
+        let mut header = [0u8; FILE_HEADER_SIZE as usize];
+        file.read_exact(&mut header).await?;
+        Ok(FrameReader { file })
+    }
+    
+    /// The receiver has everything; delete the upload
+    pub fn finish(mut self) {
+        self.delivered = true;
+        self.mailbox.remove(&self.code_hash);
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        if !self.delivered {
+            if let Some(entry) = self.mailbox.entries.lock().unwrap().get_mut(&self.code_hash) {
+                entry.delivering = false;
+            }
+        }
+    }
+}
+
+/// Reads frames back from a stored upload
+pub struct FrameReader {
+    file: BufReader<File>,
+}
+
+impl FrameReader {
+    /// Next stored frame, or `None` at the end
+    pub async fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; FRAME_HEADER_SIZE as usize];
+        match self.file.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        
+        let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+        self.file.read_exact(&mut frame).await?;
+        Ok(Some(frame))
+    }
+}
+
+/// Code hashes double as file names, so only accept what `hash_code` produces
+fn is_code_hash(code_hash: &str) -> bool {
+    code_hash.len() == 64 && code_hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+async fn read_expiry(path: &Path) -> Result<SystemTime> {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    File::open(path).await?.read_exact(&mut header).await?;
+    Ok(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(header)))
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::protocol::hash_code;
+    use tempfile::TempDir;
+    
+    async fn open(dir: &TempDir, max_bytes: u64) -> Arc<Mailbox> {
+        Mailbox::open(MailboxConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes,
+        })
+        .await
+        .unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_store_and_take() {
+        let dir = TempDir::new().unwrap();
+        let mailbox = open(&dir, DEFAULT_MAILBOX_MAX_BYTES).await;
+        let code_hash = hash_code("alpha-bravo-charlie");
+        
+        let mut upload = mailbox.begin(&code_hash, Duration::from_secs(60)).await.unwrap();
+        upload.write(b"hello").await.unwrap();
+        upload.write(&[7u8; 1000]).await.unwrap();
+        assert!(mailbox.take(&code_hash).is_none(), "uploads aren't visible until committed");
+        upload.commit().await.unwrap();
+        
+        let delivery = mailbox.take(&code_hash).unwrap();
+        assert!(mailbox.take(&code_hash).is_none(), "only one receiver at a time");
+        let mut frames = delivery.frames().await.unwrap();
+        assert_eq!(frames.next_frame().await.unwrap().unwrap(), b"hello");
+        assert_eq!(frames.next_frame().await.unwrap().unwrap(), vec![7u8; 1000]);
+        assert!(frames.next_frame().await.unwrap().is_none());
+        
+        delivery.finish();
+        assert!(!mailbox.contains(&code_hash));
+        assert_eq!(mailbox.used_bytes(), 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_unfinished_delivery_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let mailbox = open(&dir, DEFAULT_MAILBOX_MAX_BYTES).await;
+        let code_hash = hash_code("alpha-bravo-charlie");
+        
+        let upload = mailbox.begin(&code_hash, Duration::from_secs(60)).await.unwrap();
+        upload.commit().await.unwrap();
+        
+        drop(mailbox.take(&code_hash).unwrap());
+        assert!(mailbox.take(&code_hash).is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_expired_upload_is_removed() {
+        let dir = TempDir::new().unwrap();
+        let mailbox = open(&dir, DEFAULT_MAILBOX_MAX_BYTES).await;
+        let code_hash = hash_code("alpha-bravo-charlie");
+        
+        let mut upload = mailbox.begin(&code_hash, Duration::from_millis(200)).await.unwrap();
+        upload.write(b"hello").await.unwrap();
+        upload.commit().await.unwrap();
+        assert!(mailbox.contains(&code_hash));
+        
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(mailbox.take(&code_hash).is_none());
+        assert_eq!(mailbox.used_bytes(), 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_quota_rejects_upload() {
+        let dir = TempDir::new().unwrap();
+        let mailbox = open(&dir, 1024).await;
+        let code_hash = hash_code("alpha-bravo-charlie");
+        
+        let mut upload = mailbox.begin(&code_hash, Duration::from_secs(60)).await.unwrap();
+        upload.write(&[0u8; 500]).await.unwrap();
+        let err = upload.write(&[0u8; 600]).await.unwrap_err();
+        assert!(err.to_string().contains("quota"));
+        
+        drop(upload);
+        assert_eq!(mailbox.used_bytes(), 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_startup_cleanup() {
+        let dir = TempDir::new().unwrap();
+        let kept = hash_code("alpha-bravo-charlie");
+        {
+            let mailbox = open(&dir, DEFAULT_MAILBOX_MAX_BYTES).await;
+            let mut upload = mailbox.begin(&kept, Duration::from_secs(60)).await.unwrap();
+            upload.write(b"hello").await.unwrap();
+            upload.commit().await.unwrap();
+            
+            let upload = mailbox.begin(&hash_code("delta-echo-foxtrot"), Duration::from_millis(1)).await.unwrap();
+            upload.commit().await.unwrap();
+        }
+        std::fs::write(dir.path().join(format!("{}.partial", hash_code("golf-hotel-india"))), b"junk").unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        
+        let mailbox = open(&dir, DEFAULT_MAILBOX_MAX_BYTES).await;
+        assert!(mailbox.contains(&kept));
+        assert_eq!(mailbox.used_bytes(), FILE_HEADER_SIZE + FRAME_HEADER_SIZE + 5);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
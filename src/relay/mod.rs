@@ -1,7 +1,24 @@
+pub mod access;
+pub mod admin;
 pub mod client;
+pub mod discovery;
+pub mod http;
+pub mod mailbox;
+pub mod multiplex;
 pub mod protocol;
+pub mod queue;
+pub mod rooms;
 pub mod server;
+pub mod state;
+pub mod url;
 
-pub use client::RelayConnection;
-pub use protocol::Role;
-pub use server::run_relay_server;
+pub use access::{AccessControl, Rule};
+pub use client::{relay_order, RelayConnection, RelayError, RELAY_CONNECT_TIMEOUT, RELAY_PING_INTERVAL};
+pub use mailbox::{Mailbox, MailboxConfig, DEFAULT_MAILBOX_MAX_BYTES, MAX_MAILBOX_TTL};
+pub use multiplex::{RelayRoom, RelaySession};
+pub use protocol::{Role, MAX_RELAY_FRAME_SIZE};
+pub use queue::{ForwardQueue, RoomLane, RoomLimits, DEFAULT_QUEUE_DEPTH, DEFAULT_ROOM_IN_FLIGHT_BYTES};
+pub use rooms::{RoomMap, ROOM_SHARDS};
+pub use server::{run_relay_server, serve, RelayConfig, DEFAULT_PRE_REGISTER_FRAMES, DEFAULT_PRE_REGISTER_FRAME_SIZE, DEFAULT_REGISTER_TIMEOUT};
+pub use state::{RelayState, RoomInfo, RoomState, StatsSnapshot, DEFAULT_SESSION_TIMEOUT};
+pub use url::{RelayUrl, RelayUrlError};
@@ -0,0 +1,466 @@
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use super::client::{length_prefixed, payload_len, read_welcome, LENGTH_PREFIX_SIZE};
+use super::protocol::{hash_code, RelayMessage, Role, CAP_ROOMS, CAP_SPLIT_PAYLOADS, PEER_DISCONNECTED, RELAY_PROTOCOL_VERSION, ROOM_ID_SIZE};
+use super::url::RelayUrl;
+
+/// Frames buffered per direction before the session stops reading or writing
+const QUEUE_DEPTH: usize = 64;
+
+/// A frame for one room, or the relay's error message for it
+type Inbound = Result<Vec<u8>, String>;
+
+/// Where the session delivers traffic for one room
+struct Route {
+    matched: Option<oneshot::Sender<Result<(), String>>>,
+    frames: mpsc::Sender<Inbound>,
+}
+
+type Routes = Arc<Mutex<HashMap<u32, Route>>>;
+
+/// A single relay connection carrying several rooms
+///
+/// Each room opened on the session behaves like its own `RelayConnection`,
+/// so a daemon can hold many pending transfers without a websocket apiece.
+pub struct RelaySession {
+    outgoing: mpsc::Sender<Message>,
+    routes: Routes,
+    next_room_id: AtomicU32,
+    max_frame_size: usize,
+    relay_url: String,
+}
+
+impl RelaySession {
+    /// Connect to a relay server without registering any room yet
+    ///
+    /// The relay drops connections that haven't registered within its
+    /// `--register-timeout` (10 seconds by default), so open a room soon after.
+    pub async fn connect(relay: &RelayUrl, max_frame_size: usize) -> Result<Self> {
+        let url = relay.to_string();
+        let (mut ws, _) = connect_async(&url)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to relay: {}", e))?;
+        
+        // Older relays would take the room id for a single-room registration
+        let welcome = read_welcome(&mut ws).await?;
+        if !welcome.capabilities.iter().any(|capability| capability == CAP_ROOMS) {
+            return Err(anyhow!("Relay {} doesn't support several rooms per connection", url));
+        }
+        
+        let (outgoing, outgoing_rx) = mpsc::channel(QUEUE_DEPTH);
+        let routes = Routes::default();
+        tokio::spawn(drive(ws, outgoing_rx, routes.clone()));
+        
+        Ok(Self {
+            outgoing,
+            routes,
+            next_room_id: AtomicU32::new(0),
+            max_frame_size: max_frame_size.max(ROOM_ID_SIZE + LENGTH_PREFIX_SIZE + 1),
+            relay_url: url,
+        })
+    }
+    
+    /// URL of the relay this session is connected to
+    pub fn relay_url(&self) -> &str {
+        &self.relay_url
+    }
+    
+    /// Register a room for `code` and wait until the relay matches it with a peer
+    pub async fn open(&self, code: &str, role: Role) -> Result<RelayRoom> {
+        let room_id = self.next_room_id.fetch_add(1, Ordering::Relaxed);
+        let (matched_tx, matched_rx) = oneshot::channel();
+        let (frames_tx, frames_rx) = mpsc::channel(QUEUE_DEPTH);
+        self.routes.lock().unwrap().insert(room_id, Route {
+            matched: Some(matched_tx),
+            frames: frames_tx,
+        });
+        
+        // Dropping the room (including on error) tells the relay we're done with it
+        let room = RelayRoom {
+            room_id,
+            outgoing: self.outgoing.clone(),
+            writer: PollSender::new(self.outgoing.clone()),
+            frames: frames_rx,
+            routes: self.routes.clone(),
+            max_frame_size: self.max_frame_size,
+            relay_url: self.relay_url.clone(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            partial: Vec::new(),
+        };
+        
+        let register_msg = RelayMessage::Register {
+            role,
+            code_hash: hash_code(code),
+            room_id: Some(room_id),
+            version: Some(RELAY_PROTOCOL_VERSION),
+            // Rooms always split their payloads, so the relay checks their peers do too
+            capabilities: vec![CAP_ROOMS.to_string(), CAP_SPLIT_PAYLOADS.to_string()],
+        };
+        self.outgoing
+            .send(Message::Text(register_msg.to_json()?))
+            .await
+            .map_err(|_| anyhow!("Relay connection closed"))?;
+        
+        match matched_rx.await {
+            Ok(Ok(())) => Ok(room),
+            Ok(Err(message)) => Err(anyhow!("Relay error: {}", message)),
+            Err(_) => Err(anyhow!("Relay connection closed during handshake")),
+        }
+    }
+}
+
+/// One room on a `RelaySession`, with the same send/receive interface as `RelayConnection`
+pub struct RelayRoom {
+    room_id: u32,
+    outgoing: mpsc::Sender<Message>,
+    /// The same queue, for writes through `AsyncWrite`
+    writer: PollSender<Message>,
+    frames: mpsc::Receiver<Inbound>,
+    routes: Routes,
+    max_frame_size: usize,
+    relay_url: String,
+    /// Frame being handed out through `AsyncRead`, and how much of it has been read
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Frames of a message `receive` has only part of, kept if it's cancelled
+    partial: Vec<u8>,
+}
+
+impl RelayRoom {
+    /// Id of this room on its session
+    pub fn room_id(&self) -> u32 {
+        self.room_id
+    }
+    
+    /// URL of the relay carrying this room
+    pub fn relay_url(&self) -> &str {
+        &self.relay_url
+    }
+    
+    /// Send binary data through relay
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let payload = length_prefixed(data)?;
+        for chunk in payload.chunks(self.max_frame_size - ROOM_ID_SIZE) {
+            let mut frame = Vec::with_capacity(ROOM_ID_SIZE + chunk.len());
+            frame.extend_from_slice(&self.room_id.to_be_bytes());
+            frame.extend_from_slice(chunk);
+            self.outgoing
+                .send(Message::Binary(frame))
+                .await
+                .map_err(|_| anyhow!("Relay connection closed"))?;
+        }
+        Ok(())
+    }
+    
+    /// Receive binary data from relay, reassembling split payloads; safe to cancel, like `Connection::receive`
+    pub async fn receive(&mut self) -> Result<Vec<u8>> {
+        // Frames are only taken off the queue whole, so stopping between them loses nothing
+        loop {
+            let frame = self.receive_frame().await?;
+            self.partial.extend_from_slice(&frame);
+            let len = payload_len(&self.partial)?;
+            if self.partial.len() >= LENGTH_PREFIX_SIZE + len {
+                let mut payload = std::mem::take(&mut self.partial);
+                if payload.len() != LENGTH_PREFIX_SIZE + len {
+                    return Err(anyhow!("Relay payload length mismatch"));
+                }
+                return Ok(payload.split_off(LENGTH_PREFIX_SIZE));
+            }
+        }
+    }
+    
+    async fn receive_frame(&mut self) -> Result<Vec<u8>> {
+        match self.frames.recv().await {
+            Some(Ok(frame)) => Ok(frame),
+            Some(Err(message)) => Err(anyhow!("Relay error: {}", message)),
+            None => Err(anyhow!("Relay connection closed")),
+        }
+    }
+}
+
+/// Raw byte stream over the room, like `RelayConnection`'s
+impl AsyncRead for RelayRoom {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.read_pos == this.read_buf.len() {
+            match ready!(this.frames.poll_recv(cx)) {
+                Some(Ok(frame)) => {
+                    this.read_buf = frame;
+                    this.read_pos = 0;
+                }
+                Some(Err(message)) => {
+                    return Poll::Ready(Err(io::Error::other(format!("Relay error: {}", message))));
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RelayRoom {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let closed = |_| io::Error::new(io::ErrorKind::BrokenPipe, "Relay connection closed");
+        ready!(self.writer.poll_reserve(cx)).map_err(closed)?;
+        
+        let len = buf.len().min(self.max_frame_size - ROOM_ID_SIZE);
+        let mut frame = Vec::with_capacity(ROOM_ID_SIZE + len);
+        frame.extend_from_slice(&self.room_id.to_be_bytes());
+        frame.extend_from_slice(&buf[..len]);
+        self.writer.send_item(Message::Binary(frame)).map_err(closed)?;
+        Poll::Ready(Ok(len))
+    }
+    
+    /// Queued frames are written by the session's task, so there's nothing to wait for
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for RelayRoom {
+    fn drop(&mut self) {
+        self.routes.lock().unwrap().remove(&self.room_id);
+        
+        // Best effort: if the queue is full the relay frees the room when the connection closes
+        if let Ok(json) = (RelayMessage::Leave { room_id: self.room_id }).to_json() {
+            let _ = self.outgoing.try_send(Message::Text(json));
+        }
+    }
+}
+
+/// Pump the websocket: write queued frames and route incoming ones to their rooms
+///
+/// A room whose reader falls behind holds up incoming traffic (which pushes
+/// back on the relay) but never outgoing traffic, so a room that is busy
+/// sending can't deadlock against its own unread frames.
+async fn drive(
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    mut outgoing_rx: mpsc::Receiver<Message>,
+    routes: Routes,
+) {
+    let (mut sink, mut stream) = ws.split();
+    let mut pending: Option<(mpsc::Sender<Inbound>, Inbound)> = None;
+    
+    loop {
+        let reserve = pending.as_ref().map(|(frames, _)| frames.clone().reserve_owned());
+        
+        tokio::select! {
+            msg = outgoing_rx.recv() => match msg {
+                Some(msg) => {
+                    if sink.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    // Session and every room dropped
+                    let _ = sink.close().await;
+                    break;
+                }
+            },
+            Some(permit) = async { match reserve { Some(reserve) => Some(reserve.await), None => None } }, if pending.is_some() => {
+                let (_, frame) = pending.take().expect("pending checked above");
+                if let Ok(permit) = permit {
+                    permit.send(frame);
+                }
+            }
+            msg = stream.next(), if pending.is_none() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    if data.len() < ROOM_ID_SIZE {
+                        continue;
+                    }
+                    let (prefix, frame) = data.split_at(ROOM_ID_SIZE);
+                    let room_id = u32::from_be_bytes(prefix.try_into().expect("prefix is ROOM_ID_SIZE bytes"));
+                    let frames = routes.lock().unwrap().get(&room_id).map(|route| route.frames.clone());
+                    if let Some(frames) = frames {
+                        pending = Some((frames, Ok(frame.to_vec())));
+                    }
+                }
+                Some(Ok(Message::Text(text))) => match RelayMessage::from_json(&text) {
+                    Ok(RelayMessage::Matched { room_id: Some(room_id) }) => {
+                        let matched = routes.lock().unwrap().get_mut(&room_id).and_then(|route| route.matched.take());
+                        if let Some(matched) = matched {
+                            let _ = matched.send(Ok(()));
+                        }
+                    }
+                    Ok(RelayMessage::Error { message, room_id: Some(room_id) }) => close_route(&routes, room_id, message),
+                    Ok(RelayMessage::PeerDisconnected { room_id: Some(room_id) }) => {
+                        close_route(&routes, room_id, PEER_DISCONNECTED.to_string());
+                    }
+                    Ok(RelayMessage::Error { message, room_id: None }) => {
+                        fail_all(&routes, &message);
+                        return;
+                    }
+                    Ok(RelayMessage::Ping) => {
+                        if let Ok(json) = RelayMessage::Pong.to_json() {
+                            if sink.send(Message::Text(json)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    
+    fail_all(&routes, "Relay connection closed");
+}
+
+/// The relay is done with this room; its reader sees `message`, then a closed room
+fn close_route(routes: &Routes, room_id: u32, message: String) {
+    if let Some(mut route) = routes.lock().unwrap().remove(&room_id) {
+        match route.matched.take() {
+            Some(matched) => {
+                let _ = matched.send(Err(message));
+            }
+            None => {
+                let _ = route.frames.try_send(Err(message));
+            }
+        }
+    }
+}
+
+/// Report an error to every open room and forget them
+fn fail_all(routes: &Routes, message: &str) {
+    for (_, mut route) in routes.lock().unwrap().drain() {
+        match route.matched.take() {
+            Some(matched) => {
+                let _ = matched.send(Err(message.to_string()));
+            }
+            None => {
+                let _ = route.frames.try_send(Err(message.to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::server::{serve, RelayConfig};
+    use crate::relay::state::RelayState;
+    use crate::relay::RelayConnection;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    
+    /// Small frames so every payload is split across several of them
+    const FRAME_SIZE: usize = 16 * 1024;
+    
+    async fn start_relay() -> (SocketAddr, Arc<RelayState>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RelayState::default());
+        let config = RelayConfig {
+            port: addr.port(),
+            ..Default::default()
+        };
+        tokio::spawn(serve(listener, config, state.clone()));
+        (addr, state)
+    }
+    
+    async fn transfer(mut sender: RelayRoom, mut receiver: RelayRoom, fill: u8) {
+        let send = async {
+            for _ in 0..20 {
+                sender.send(&vec![fill; 100_000]).await.unwrap();
+            }
+            assert_eq!(sender.receive().await.unwrap(), b"done");
+        };
+        let receive = async {
+            for _ in 0..20 {
+                assert_eq!(receiver.receive().await.unwrap(), vec![fill; 100_000]);
+            }
+            receiver.send(b"done").await.unwrap();
+        };
+        tokio::join!(send, receive);
+    }
+    
+    #[tokio::test]
+    async fn test_two_transfers_over_one_connection() {
+        let (relay, state) = start_relay().await;
+        let senders = RelaySession::connect(&relay.into(), FRAME_SIZE).await.unwrap();
+        let receivers = RelaySession::connect(&relay.into(), FRAME_SIZE).await.unwrap();
+        
+        let (send_a, receive_a) = tokio::join!(
+            senders.open("alpha-bravo-charlie", Role::Sender),
+            receivers.open("alpha-bravo-charlie", Role::Receiver),
+        );
+        let (send_b, receive_b) = tokio::join!(
+            senders.open("delta-echo-foxtrot", Role::Sender),
+            receivers.open("delta-echo-foxtrot", Role::Receiver),
+        );
+        assert_ne!(send_a.as_ref().unwrap().room_id(), send_b.as_ref().unwrap().room_id());
+        
+        tokio::join!(
+            transfer(send_a.unwrap(), receive_a.unwrap(), 0xaa),
+            transfer(send_b.unwrap(), receive_b.unwrap(), 0xbb),
+        );
+        
+        let stats = state.stats().await;
+        assert_eq!(stats.connections_total, 2);
+        assert_eq!(stats.matches_total, 2);
+    }
+    
+    #[tokio::test]
+    async fn test_multi_room_peer_with_single_room_peer() {
+        let (relay, _) = start_relay().await;
+        let relay = RelayUrl::from(relay);
+        let session = RelaySession::connect(&relay, FRAME_SIZE).await.unwrap();
+        
+        let (room, legacy) = tokio::join!(
+            session.open("alpha-bravo-charlie", Role::Sender),
+            RelayConnection::connect(std::slice::from_ref(&relay), "alpha-bravo-charlie", Role::Receiver, FRAME_SIZE),
+        );
+        let (mut room, mut legacy) = (room.unwrap(), legacy.unwrap());
+        
+        room.send(&vec![7u8; 50_000]).await.unwrap();
+        assert_eq!(legacy.receive().await.unwrap(), vec![7u8; 50_000]);
+        legacy.send(b"ack").await.unwrap();
+        assert_eq!(room.receive().await.unwrap(), b"ack");
+    }
+    
+    #[tokio::test]
+    async fn test_kick_closes_only_that_room() {
+        let (relay, state) = start_relay().await;
+        let senders = RelaySession::connect(&relay.into(), FRAME_SIZE).await.unwrap();
+        let receivers = RelaySession::connect(&relay.into(), FRAME_SIZE).await.unwrap();
+        
+        let (_send_a, receive_a) = tokio::join!(
+            senders.open("alpha-bravo-charlie", Role::Sender),
+            receivers.open("alpha-bravo-charlie", Role::Receiver),
+        );
+        let (send_b, receive_b) = tokio::join!(
+            senders.open("delta-echo-foxtrot", Role::Sender),
+            receivers.open("delta-echo-foxtrot", Role::Receiver),
+        );
+        
+        assert_eq!(state.kick(&hash_code("alpha-bravo-charlie")).await, 1);
+        let err = receive_a.unwrap().receive().await.unwrap_err();
+        assert!(err.to_string().contains("Room closed by relay operator"));
+        
+        transfer(send_b.unwrap(), receive_b.unwrap(), 0xcc).await;
+    }
+}
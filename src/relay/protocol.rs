@@ -1,26 +1,158 @@
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Maximum size of a single WebSocket frame accepted by the relay (2 MB)
+pub const MAX_RELAY_FRAME_SIZE: usize = 2 * 1024 * 1024;
+
+/// Size of the room id that prefixes binary frames on multi-room connections
+pub const ROOM_ID_SIZE: usize = 4;
+
+/// Relay protocol version spoken by this build
+pub const RELAY_PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest relay protocol still served; clients that send no version speak this one
+pub const MIN_RELAY_PROTOCOL_VERSION: u32 = 1;
+
+/// Capability: several rooms on one connection (`room_id`)
+pub const CAP_ROOMS: &str = "rooms";
+
+/// Capability: store-and-forward uploads (`OfferStore`)
+pub const CAP_MAILBOX: &str = "mailbox";
+
+/// Capability: a receiver registering with it is served a stored upload when no live sender is there
+///
+/// Only receivers that ask (`zap receive --mailbox`) ever get one; a stored
+/// upload is keyed from the code alone, so nobody should be switched to it
+/// without knowing.
+pub const CAP_PICKUP: &str = "pickup";
+
+/// Capability: binary frames start with `FRAME_MAGIC` (single-room connections)
+pub const CAP_FRAME_MAGIC: &str = "frame-magic";
+
+/// Oldest version whose single-room clients must agree on `CAP_FRAME_MAGIC`
+///
+/// Clients from before versioning are served unmarked until
+/// `MIN_RELAY_PROTOCOL_VERSION` moves past them.
+pub const FRAME_MAGIC_REQUIRED_VERSION: u32 = 2;
+
+/// Capability: `Ping` is answered with `Pong` at any time, including once matched
+pub const CAP_KEEPALIVE: &str = "keepalive";
+
+/// Capability: matched peers that both ask for it learn each other's address (`PeerHint`)
+pub const CAP_PEER_HINT: &str = "peer-hint";
+
+/// Capability: a room that waited too long for its peer is closed with `RoomExpired`, so the client can register again
+pub const CAP_ROOM_EXPIRY: &str = "room-expiry";
+
+/// Capability: payloads are length-prefixed and may be split across several binary frames
+///
+/// The relay passes frames on as they are, so both peers in a room must
+/// agree on it; see `SPLIT_MISMATCH`. Without it each payload is a single
+/// frame, as relays and clients from before `--relay-max-frame-size` expect.
+pub const CAP_SPLIT_PAYLOADS: &str = "split-payloads";
+
+/// First byte of every binary frame once `CAP_FRAME_MAGIC` is agreed
+///
+/// The relay hangs up on such clients when a frame doesn't start with it, so
+/// stray data never reaches the peer's parser. It marks the frame, not its
+/// (encrypted) contents.
+pub const FRAME_MAGIC: u8 = 0xA3;
 
 /// Relay protocol messages for handshake
+///
+/// `room_id` is only set by clients that register several rooms on one
+/// connection. Once a connection registers with a room id, every binary frame
+/// it sends or receives starts with that room's id (`ROOM_ID_SIZE` bytes,
+/// big-endian). Connections that never send a room id keep the original
+/// one-room protocol with unprefixed frames.
+///
+/// The relay greets every connection with `Welcome`, and clients put their
+/// `version` and `capabilities` on their first message. Both are left out by
+/// version 1 clients, which the relay keeps serving as before.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum RelayMessage {
+    /// Relay's greeting, sent as soon as a client connects
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    
     /// Client registers with the relay
     Register {
         role: Role,
         code_hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
     },
     
     /// Relay confirms successful match
-    Matched,
+    Matched {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+    },
+    
+    /// Where the relay sees the other peer connecting from, sent just before
+    /// `Matched` when both peers asked for `CAP_PEER_HINT`
+    PeerHint {
+        external_addr: SocketAddr,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+    },
     
     /// Error from relay
     Error {
         message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+    },
+    
+    /// Client is done with one room of a multi-room connection
+    Leave {
+        room_id: u32,
+    },
+    
+    /// Sender asks the relay to hold its upload for a receiver that isn't online yet
+    OfferStore {
+        code_hash: String,
+        ttl_secs: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
+    },
+    
+    /// Relay will store the upload for `ttl_secs` (possibly less than requested)
+    StoreAccepted {
+        ttl_secs: u64,
     },
     
+    /// Sender has uploaded everything
+    StoreDone,
+    
+    /// Relay has committed the upload to disk
+    Stored,
+    
     /// Ping/pong for keepalive
     Ping,
     Pong,
+    
+    /// The other peer in the room went away, so nothing more will arrive from it
+    PeerDisconnected {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+    },
+    
+    /// The room waited longer than the relay keeps rooms for; sent instead of
+    /// an `Error` to clients that asked for `CAP_ROOM_EXPIRY`
+    RoomExpired {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room_id: Option<u32>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -30,6 +162,16 @@ pub enum Role {
     Receiver,
 }
 
+impl Role {
+    /// The role a peer must have to be matched with this one
+    pub fn opposite(&self) -> Role {
+        match self {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        }
+    }
+}
+
 impl RelayMessage {
     /// Serialize to JSON string
     pub fn to_json(&self) -> serde_json::Result<String> {
@@ -42,6 +184,43 @@ impl RelayMessage {
     }
 }
 
+/// What a client reports when the relay says its peer went away
+pub const PEER_DISCONNECTED: &str = "Peer disconnected from the relay";
+
+/// Why the relay hung up on a client that sent a frame without `FRAME_MAGIC`
+pub const UNMARKED_FRAME: &str = "Binary frame without the zap frame marker";
+
+/// Why the relay won't match two peers that frame their payloads differently (`CAP_SPLIT_PAYLOADS`)
+pub const SPLIT_MISMATCH: &str = "The other side's zap frames relayed messages differently from this one; update the older of the two";
+
+/// Check a client's protocol version, returning the error to send if it's unsupported
+pub fn check_version(version: Option<u32>) -> Result<(), String> {
+    match version {
+        Some(version) if !(MIN_RELAY_PROTOCOL_VERSION..=RELAY_PROTOCOL_VERSION).contains(&version) => Err(format!(
+            "Unsupported relay protocol version {} (this relay supports {}-{})",
+            version, MIN_RELAY_PROTOCOL_VERSION, RELAY_PROTOCOL_VERSION
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a single-room client of `version` marks its frames, when that version must
+pub fn check_frame_magic(version: Option<u32>, capabilities: &[String]) -> Result<(), String> {
+    let version = version.unwrap_or(MIN_RELAY_PROTOCOL_VERSION);
+    if version >= FRAME_MAGIC_REQUIRED_VERSION && !capabilities.iter().any(|capability| capability == CAP_FRAME_MAGIC) {
+        return Err(format!("Relay protocol version {} requires the {} capability", version, CAP_FRAME_MAGIC));
+    }
+    Ok(())
+}
+
+/// Features both sides support
+pub fn negotiate(ours: &[&str], theirs: &[String]) -> Vec<String> {
+    ours.iter()
+        .filter(|capability| theirs.iter().any(|theirs| theirs == *capability))
+        .map(|capability| capability.to_string())
+        .collect()
+}
+
 /// Hash a transfer code using BLAKE3
 pub fn hash_code(code: &str) -> String {
     let hash = blake3::hash(code.as_bytes());
@@ -68,17 +247,83 @@ mod tests {
         let msg = RelayMessage::Register {
             role: Role::Sender,
             code_hash: "test123".to_string(),
+            room_id: None,
+            version: Some(RELAY_PROTOCOL_VERSION),
+            capabilities: vec![CAP_ROOMS.to_string()],
         };
         
         let json = msg.to_json().unwrap();
         let deserialized = RelayMessage::from_json(&json).unwrap();
         
         match deserialized {
-            RelayMessage::Register { role, code_hash } => {
+            RelayMessage::Register { role, code_hash, room_id, version, capabilities } => {
                 assert_eq!(role, Role::Sender);
                 assert_eq!(code_hash, "test123");
+                assert_eq!(room_id, None);
+                assert_eq!(version, Some(RELAY_PROTOCOL_VERSION));
+                assert_eq!(capabilities, [CAP_ROOMS]);
             }
             _ => panic!("Wrong message type"),
         }
     }
+    
+    #[test]
+    fn test_legacy_messages_unchanged() {
+        // Single-room clients must keep seeing exactly the original JSON
+        assert_eq!(RelayMessage::Matched { room_id: None }.to_json().unwrap(), r#"{"type":"matched"}"#);
+        assert!(matches!(
+            RelayMessage::from_json(r#"{"type":"error","message":"nope"}"#),
+            Ok(RelayMessage::Error { room_id: None, .. })
+        ));
+        assert!(matches!(
+            RelayMessage::from_json(r#"{"type":"matched","room_id":7}"#),
+            Ok(RelayMessage::Matched { room_id: Some(7) })
+        ));
+        assert_eq!(RelayMessage::RoomExpired { room_id: None }.to_json().unwrap(), r#"{"type":"roomexpired"}"#);
+        assert!(matches!(
+            RelayMessage::from_json(r#"{"type":"register","role":"sender","code_hash":"abc"}"#),
+            Ok(RelayMessage::Register { version: None, ref capabilities, .. }) if capabilities.is_empty()
+        ));
+    }
+    
+    #[test]
+    fn test_peer_hint_serialization() {
+        let hint = RelayMessage::PeerHint {
+            external_addr: "203.0.113.7:40123".parse().unwrap(),
+            room_id: None,
+        };
+        assert_eq!(hint.to_json().unwrap(), r#"{"type":"peerhint","external_addr":"203.0.113.7:40123"}"#);
+        assert!(matches!(
+            RelayMessage::from_json(r#"{"type":"peerhint","external_addr":"[2001:db8::1]:9"}"#),
+            Ok(RelayMessage::PeerHint { external_addr, room_id: None }) if external_addr.port() == 9
+        ));
+    }
+    
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(None).is_ok());
+        assert!(check_version(Some(MIN_RELAY_PROTOCOL_VERSION)).is_ok());
+        assert!(check_version(Some(RELAY_PROTOCOL_VERSION)).is_ok());
+        
+        let err = check_version(Some(RELAY_PROTOCOL_VERSION + 1)).unwrap_err();
+        assert!(err.contains(&format!("{}-{}", MIN_RELAY_PROTOCOL_VERSION, RELAY_PROTOCOL_VERSION)));
+    }
+    
+    #[test]
+    fn test_check_frame_magic() {
+        let marked = vec![CAP_FRAME_MAGIC.to_string()];
+        assert!(check_frame_magic(None, &[]).is_ok());
+        assert!(check_frame_magic(Some(RELAY_PROTOCOL_VERSION), &marked).is_ok());
+        
+        let err = check_frame_magic(Some(RELAY_PROTOCOL_VERSION), &[CAP_MAILBOX.to_string()]).unwrap_err();
+        assert!(err.contains(CAP_FRAME_MAGIC), "{}", err);
+    }
+    
+    #[test]
+    fn test_negotiate() {
+        let theirs = vec![CAP_ROOMS.to_string(), "future-thing".to_string()];
+        assert_eq!(negotiate(&[CAP_ROOMS, CAP_MAILBOX], &theirs), [CAP_ROOMS]);
+        assert!(negotiate(&[CAP_MAILBOX], &theirs).is_empty());
+        assert!(negotiate(&[CAP_ROOMS], &[]).is_empty());
+    }
 }
@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Frames buffered per connection before the relay stops reading from its peer
+pub const DEFAULT_QUEUE_DEPTH: usize = 64;
+
+/// Bytes one room may have queued towards a client before its sender waits
+pub const DEFAULT_ROOM_IN_FLIGHT_BYTES: usize = 4 * 1024 * 1024;
+
+/// A frame waiting to be written to a client
+///
+/// Holds its room's in-flight allowance until the frame has been written.
+#[derive(Debug)]
+pub struct Queued {
+    pub msg: Message,
+    _reservation: Option<OwnedSemaphorePermit>,
+}
+
+/// Bounded queue of frames waiting to be written to one relay client
+///
+/// When the client reads slower than its peer sends, the queue fills and
+/// `send` waits, which stops the relay reading from the peer's socket and
+/// pushes the backpressure all the way back to the sender.
+#[derive(Debug, Clone)]
+pub struct ForwardQueue {
+    tx: mpsc::Sender<Queued>,
+    backpressure_counter: Arc<AtomicUsize>,
+}
+
+impl ForwardQueue {
+    /// Create a queue holding at most `capacity` frames
+    ///
+    /// `backpressure_counter` is bumped every time a send finds the queue full;
+    /// the relay shares one counter across all connections for its metrics.
+    pub fn new(capacity: usize, backpressure_counter: Arc<AtomicUsize>) -> (Self, mpsc::Receiver<Queued>) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let queue = Self {
+            tx,
+            backpressure_counter,
+        };
+        (queue, rx)
+    }
+    
+    /// Whether the next `send` would have to wait
+    pub fn is_full(&self) -> bool {
+        self.tx.capacity() == 0
+    }
+    
+    /// Queue a frame, waiting while the queue is full
+    ///
+    /// Returns whether the queue was full, i.e. backpressure was applied.
+    pub async fn send(&self, msg: Message) -> Result<bool> {
+        self.send_queued(Queued { msg, _reservation: None }).await
+    }
+    
+    /// Queue a frame holding a room's in-flight allowance from `RoomLane::reserve`
+    pub async fn send_reserved(&self, msg: Message, reservation: OwnedSemaphorePermit) -> Result<bool> {
+        self.send_queued(Queued {
+            msg,
+            _reservation: Some(reservation),
+        }).await
+    }
+    
+    async fn send_queued(&self, msg: Queued) -> Result<bool> {
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(false),
+            Err(TrySendError::Full(msg)) => {
+                self.backpressure_counter.fetch_add(1, Ordering::Relaxed);
+                self.tx
+                    .send(msg)
+                    .await
+                    .map_err(|_| anyhow!("Peer disconnected"))?;
+                Ok(true)
+            }
+            Err(TrySendError::Closed(_)) => Err(anyhow!("Peer disconnected")),
+        }
+    }
+    
+    /// Queue a control message without waiting, dropping it if the queue is full
+    pub fn try_send(&self, msg: Message) -> bool {
+        self.tx.try_send(Queued { msg, _reservation: None }).is_ok()
+    }
+}
+
+/// Forwarding limits applied to each room
+#[derive(Debug, Clone, Copy)]
+pub struct RoomLimits {
+    /// Bytes a room may have queued towards a client
+    pub in_flight_bytes: usize,
+    /// Bytes per second a room may forward in each direction
+    pub rate: Option<u64>,
+}
+
+impl Default for RoomLimits {
+    fn default() -> Self {
+        Self {
+            in_flight_bytes: DEFAULT_ROOM_IN_FLIGHT_BYTES,
+            rate: None,
+        }
+    }
+}
+
+/// One direction of a room's traffic towards a client
+///
+/// Rooms on a multi-room connection share its `ForwardQueue`. Capping what
+/// each room may have queued keeps a saturating room from filling the queue,
+/// so the others' frames are interleaved with its own instead of waiting
+/// behind them.
+#[derive(Debug)]
+pub struct RoomLane {
+    in_flight: Arc<Semaphore>,
+    max_in_flight: usize,
+    rate: Option<RateLimiter>,
+    backpressure_counter: Arc<AtomicUsize>,
+}
+
+impl RoomLane {
+    /// Create a lane; `backpressure_counter` is bumped whenever a frame waits for allowance
+    pub fn new(limits: RoomLimits, backpressure_counter: Arc<AtomicUsize>) -> Self {
+        let max_in_flight = limits.in_flight_bytes.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight,
+            rate: limits.rate.filter(|rate| *rate > 0).map(RateLimiter::new),
+            backpressure_counter,
+        }
+    }
+    
+    /// Wait for the room's rate share and in-flight allowance for a frame of `bytes`
+    ///
+    /// Frames larger than the whole allowance wait until nothing else is in flight.
+    pub async fn reserve(&self, bytes: usize) -> Result<OwnedSemaphorePermit> {
+        if let Some(ref rate) = self.rate {
+            rate.wait(bytes as u64).await;
+        }
+        let permits = u32::try_from(bytes.min(self.max_in_flight)).unwrap_or(u32::MAX);
+        if let Ok(reservation) = self.in_flight.clone().try_acquire_many_owned(permits) {
+            return Ok(reservation);
+        }
+        self.backpressure_counter.fetch_add(1, Ordering::Relaxed);
+        self.in_flight
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .map_err(|_| anyhow!("Room closed"))
+    }
+}
+
+/// Token bucket pacing a room to a byte rate, allowing up to a second of burst
+#[derive(Debug)]
+struct RateLimiter {
+    rate: u64,
+    /// Available bytes (negative while in debt) and when they were last topped up
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+    
+    async fn wait(&self, bytes: u64) {
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (ref mut available, ref mut refilled) = *bucket;
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            *available = (*available + now.duration_since(*refilled).as_secs_f64() * rate).min(rate) - bytes as f64;
+            *refilled = now;
+            if *available < 0.0 {
+                Duration::from_secs_f64(-*available / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_send_waits_when_full() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (queue, mut rx) = ForwardQueue::new(2, counter.clone());
+        assert!(!queue.send(Message::Binary(vec![1])).await.unwrap());
+        assert!(!queue.send(Message::Binary(vec![2])).await.unwrap());
+        assert!(queue.is_full());
+        
+        let blocked = tokio::time::timeout(Duration::from_millis(100), queue.send(Message::Binary(vec![3]))).await;
+        assert!(blocked.is_err(), "send should wait while the queue is full");
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+        assert!(!queue.try_send(Message::Binary(vec![3])));
+        
+        let pending = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.send(Message::Binary(vec![3])).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+        assert_eq!(rx.recv().await.map(|queued| queued.msg), Some(Message::Binary(vec![1])));
+        assert!(pending.await.unwrap());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_lane_caps_in_flight_bytes() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let lane = RoomLane::new(RoomLimits {
+            in_flight_bytes: 100,
+            rate: None,
+        }, counter.clone());
+        let (queue, mut rx) = ForwardQueue::new(16, Arc::default());
+        
+        queue.send_reserved(Message::Binary(vec![0; 60]), lane.reserve(60).await.unwrap()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), lane.reserve(60)).await.is_err());
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+        
+        // Writing the frame out returns its allowance
+        drop(rx.recv().await.unwrap());
+        let reservation = tokio::time::timeout(Duration::from_millis(100), lane.reserve(60)).await.unwrap().unwrap();
+        drop(reservation);
+        
+        // A frame bigger than the allowance still gets through on its own
+        drop(lane.reserve(1000).await.unwrap());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_lane_rate() {
+        let lane = RoomLane::new(RoomLimits {
+            in_flight_bytes: DEFAULT_ROOM_IN_FLIGHT_BYTES,
+            rate: Some(100_000),
+        }, Arc::default());
+        
+        // The first second's worth is burst, the rest is paced
+        let start = Instant::now();
+        for _ in 0..10 {
+            drop(lane.reserve(10_000).await.unwrap());
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..5 {
+            drop(lane.reserve(10_000).await.unwrap());
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+    
+    #[tokio::test]
+    async fn test_send_fails_once_receiver_is_gone() {
+        let (queue, rx) = ForwardQueue::new(1, Arc::default());
+        drop(rx);
+        assert!(queue.send(Message::Binary(vec![1])).await.is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use dashmap::DashMap;
+
+use super::state::Room;
+
+/// Shards the relay splits its rooms across by default
+pub const ROOM_SHARDS: usize = 64;
+
+/// Every live room, keyed by code hash and split across independently locked shards
+///
+/// Registering, forwarding and leaving only lock the shard their code hash
+/// falls in, and only while they look at the room, so rooms in different
+/// shards never wait on each other; only the admin listing and stats walk
+/// every shard. Nothing may hold a room across an `.await`. `RelayState`
+/// keeps the map itself; this only says how it's split and sized.
+///
+/// Memory: a room takes about 330 bytes in its shard (the 64-byte code hash,
+/// the room itself with both peer slots inline, and the table's slack), plus
+/// about 150 bytes of lane and semaphore for each peer in it. A million
+/// waiting rooms are therefore around 480 MB; the connections behind them,
+/// with their socket buffers and forward queues, cost far more than that.
+#[derive(Debug)]
+pub struct RoomMap(DashMap<String, Room>);
+
+impl Default for RoomMap {
+    fn default() -> Self {
+        Self::with_capacity(0)
+    }
+}
+
+impl RoomMap {
+    /// A map with room for `capacity` rooms before any shard has to grow
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_shards(ROOM_SHARDS, capacity)
+    }
+    
+    /// A map split `shards` ways, rounded up to a power of two and at least two
+    pub fn with_shards(shards: usize, capacity: usize) -> Self {
+        let shards = shards.max(2).next_power_of_two();
+        Self(DashMap::with_capacity_and_shard_amount(capacity, shards))
+    }
+}
+
+impl From<RoomMap> for DashMap<String, Room> {
+    fn from(rooms: RoomMap) -> Self {
+        rooms.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayState;
+    use std::sync::Arc;
+    
+    #[tokio::test]
+    async fn test_rooms_spread_across_shards() {
+        let state = RelayState::default();
+        let rooms = DashMap::from(RoomMap::with_shards(8, 1000));
+        for i in 0..1000 {
+            let code_hash = format!("{:064x}", i);
+            rooms.insert(code_hash, state.new_room());
+        }
+        
+        let sizes: Vec<_> = rooms.shards().iter().map(|shard| shard.read().len()).collect();
+        assert_eq!(sizes.len(), 8);
+        assert_eq!(sizes.iter().sum::<usize>(), 1000);
+        assert!(sizes.iter().all(|&size| size > 50), "uneven shards: {:?}", sizes);
+        
+        // Each code hash always lands in the same shard
+        assert!(rooms.contains_key(&format!("{:064x}", 7)));
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_rooms_touched_from_many_tasks_at_once() {
+        const TASKS: usize = 16;
+        const ROOMS: usize = 200;
+        let state = Arc::new(RelayState::default());
+        let rooms = Arc::new(DashMap::from(RoomMap::with_shards(8, TASKS * ROOMS)));
+        
+        // Each task opens its rooms, forwards through them and closes every other one
+        let tasks: Vec<_> = (0..TASKS).map(|task| {
+            let (state, rooms) = (state.clone(), rooms.clone());
+            tokio::spawn(async move {
+                let code_hash = |i: usize| format!("{:032x}{:032x}", task, i);
+                for i in 0..ROOMS {
+                    rooms.entry(code_hash(i)).or_insert_with(|| state.new_room());
+                    tokio::task::yield_now().await;
+                }
+                for _ in 0..4 {
+                    for i in 0..ROOMS {
+                        rooms.get_mut(&code_hash(i)).unwrap().bytes_forwarded += 1;
+                    }
+                    tokio::task::yield_now().await;
+                }
+                for i in (0..ROOMS).step_by(2) {
+                    let id = rooms.get(&code_hash(i)).unwrap().id;
+                    assert!(rooms.remove_if(&code_hash(i), |_, room| room.id == id).is_some());
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        
+        assert_eq!(rooms.len(), TASKS * ROOMS / 2);
+        assert!(rooms.iter().all(|room| room.bytes_forwarded == 4));
+        let sizes: Vec<_> = rooms.shards().iter().map(|shard| shard.read().len()).collect();
+        assert!(sizes.iter().all(|&size| size > 0), "uneven shards: {:?}", sizes);
+    }
+}
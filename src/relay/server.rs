@@ -1,145 +1,821 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures_util::{SinkExt, StreamExt};
 use std::collections::HashMap;
+use std::io::Cursor;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::time::Instant;
+use tokio_tungstenite::accept_async_with_config;
+use tokio_tungstenite::tungstenite::error::{CapacityError, Error as WsError};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
 
-use super::protocol::{RelayMessage, Role};
+use crate::tui::glyphs::glyphs;
+use super::access::{AccessControl, REFUSED};
+use super::admin;
+use super::http::{self, RequestHead};
+use super::mailbox::{Delivery, Mailbox, MailboxConfig, Upload};
+use super::protocol::{
+    check_frame_magic, check_version, RelayMessage, Role, CAP_FRAME_MAGIC, CAP_KEEPALIVE, CAP_MAILBOX, CAP_PEER_HINT, CAP_PICKUP, CAP_ROOM_EXPIRY, CAP_ROOMS,
+    CAP_SPLIT_PAYLOADS, FRAME_MAGIC, MAX_RELAY_FRAME_SIZE, RELAY_PROTOCOL_VERSION, ROOM_ID_SIZE, SPLIT_MISMATCH, UNMARKED_FRAME,
+};
+use super::queue::{ForwardQueue, RoomLane, RoomLimits, DEFAULT_QUEUE_DEPTH, DEFAULT_ROOM_IN_FLIGHT_BYTES};
+use super::rooms::RoomMap;
+use super::state::{hash_prefix, Peer, RelayState, RelayStats, Room, DEFAULT_SESSION_TIMEOUT};
 
-type Tx = mpsc::UnboundedSender<Message>;
-type PeerMap = Arc<Mutex<HashMap<String, Peer>>>;
+/// How long a connection has from being accepted to register or offer an upload
+pub const DEFAULT_REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Represents a connected peer (sender or receiver)
-#[derive(Debug)]
-struct Peer {
-    role: Role,
-    tx: Tx,
-    addr: SocketAddr,
+/// Largest frame a client may send before it has registered; a Register is a few hundred bytes
+pub const DEFAULT_PRE_REGISTER_FRAME_SIZE: usize = 4 * 1024;
+
+/// Frames other than a Register, like pings, a client may send before registering
+pub const DEFAULT_PRE_REGISTER_FRAMES: usize = 8;
+
+/// Relay server settings
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Port to listen on
+    pub port: u16,
+    /// Largest WebSocket frame (and message) a client may send
+    pub max_frame_size: usize,
+    /// Frames buffered per client before the relay stops reading from its peer
+    pub queue_depth: usize,
+    /// Bytes one room may have queued towards a client, so busy rooms can't crowd out others
+    pub room_in_flight_bytes: usize,
+    /// Bytes per second each room may forward in each direction
+    pub per_room_rate: Option<u64>,
+    /// Unix socket for the admin endpoint
+    pub admin_socket: Option<PathBuf>,
+    /// Loopback TCP address for the admin endpoint
+    pub admin_addr: Option<SocketAddr>,
+    /// Store uploads for receivers that aren't online yet
+    pub mailbox: Option<MailboxConfig>,
+    /// Only clients matching this file's rules may register
+    pub allowlist: Option<PathBuf>,
+    /// Clients matching this file's rules are refused
+    pub denylist: Option<PathBuf>,
+    /// Rooms to make space for up front, so a busy relay doesn't keep growing its tables
+    pub capacity: usize,
+    /// Longest a room may wait for its second peer
+    pub session_timeout: Duration,
+    /// Only upgrade requests for this path (or under it), as normalized by `http::parse_path_prefix`
+    pub path: Option<String>,
+    /// Take client addresses from `X-Forwarded-For`, for a relay behind a reverse proxy
+    pub trust_proxy: bool,
+    /// Longest from accepting a connection to its first Register or OfferStore
+    pub register_timeout: Duration,
+    /// Largest frame taken before a client has registered
+    pub pre_register_frame_size: usize,
+    /// Frames other than a Register taken before a client has registered
+    pub pre_register_frames: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            port: 7777,
+            max_frame_size: MAX_RELAY_FRAME_SIZE,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            room_in_flight_bytes: DEFAULT_ROOM_IN_FLIGHT_BYTES,
+            per_room_rate: None,
+            admin_socket: None,
+            admin_addr: None,
+            mailbox: None,
+            allowlist: None,
+            denylist: None,
+            capacity: 0,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            path: None,
+            trust_proxy: false,
+            register_timeout: DEFAULT_REGISTER_TIMEOUT,
+            pre_register_frame_size: DEFAULT_PRE_REGISTER_FRAME_SIZE,
+            pre_register_frames: DEFAULT_PRE_REGISTER_FRAMES,
+        }
+    }
 }
 
 /// Run the relay server
-pub async fn run_relay_server(port: u16) -> Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
+pub async fn run_relay_server(config: RelayConfig) -> Result<()> {
+    let addr = format!("0.0.0.0:{}", config.port);
     let listener = TcpListener::bind(&addr).await?;
+    let mailbox = match config.mailbox {
+        Some(ref mailbox_config) => Some(Mailbox::open(mailbox_config.clone()).await?),
+        None => None,
+    };
+    let access = Arc::new(AccessControl::load(config.allowlist.as_deref(), config.denylist.as_deref())?);
+    let state = Arc::new(match mailbox.clone() {
+        Some(mailbox) => RelayState::with_mailbox(mailbox),
+        None => RelayState::default(),
+    }.with_access(access.clone()).with_rooms(RoomMap::with_capacity(config.capacity)));
     
-    println!("⚡ Zap Relay Server");
-    println!("═══════════════════════════════════════");
+    println!("{} Zap Relay Server", glyphs().bolt);
+    println!("{}", glyphs().rule);
     println!("Listening on: {}", addr);
+    println!("Max frame size: {} bytes", config.max_frame_size);
+    println!("Forward queue depth: {} frames", config.queue_depth);
+    println!("Per-room in-flight limit: {} bytes", config.room_in_flight_bytes);
+    if let Some(rate) = config.per_room_rate {
+        println!("Per-room rate: {} bytes/s", rate);
+    }
+    if let Some(path) = &config.path {
+        println!("WebSocket path: {}", path);
+    }
+    if config.trust_proxy {
+        println!("Client addresses taken from X-Forwarded-For");
+    }
+    println!("Rooms close after waiting {}s for a peer", config.session_timeout.as_secs());
     println!("Relay is blind - all data is encrypted E2E");
-    println!();
+    tokio::spawn(state.clone().run_cleanup(config.session_timeout));
     
-    let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
+    if let (Some(mailbox), Some(mailbox_config)) = (mailbox, &config.mailbox) {
+        println!(
+            "Mailbox: {} (max {} bytes, {} in use)",
+            mailbox_config.dir.display(),
+            mailbox_config.max_bytes,
+            mailbox.used_bytes()
+        );
+        tokio::spawn(mailbox.run_sweeper());
+    }
+    let (allow_rules, deny_rules) = access.rule_counts();
+    if let (Some(count), Some(path)) = (allow_rules, &config.allowlist) {
+        println!("Allowlist: {} ({} rules)", path.display(), count);
+    }
+    if let (Some(count), Some(path)) = (deny_rules, &config.denylist) {
+        println!("Denylist: {} ({} rules)", path.display(), count);
+    }
+    if allow_rules.is_some() || deny_rules.is_some() {
+        tokio::spawn(access.run_reloader());
+    }
+    if let Some(admin_addr) = config.admin_addr {
+        let admin_listener = admin::bind_tcp(admin_addr).await?;
+        println!("Admin endpoint: {}", admin_addr);
+        tokio::spawn(admin::serve_tcp(admin_listener, state.clone()));
+    }
+    if let Some(ref path) = config.admin_socket {
+        spawn_admin_socket(path, state.clone())?;
+        println!("Admin socket: {}", path.display());
+    }
+    println!();
     
+    serve(listener, config, state).await
+}
+
+#[cfg(unix)]
+fn spawn_admin_socket(path: &std::path::Path, state: Arc<RelayState>) -> Result<()> {
+    let admin_listener = admin::bind_unix(path)?;
+    tokio::spawn(admin::serve_unix(admin_listener, state));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_admin_socket(_path: &std::path::Path, _state: Arc<RelayState>) -> Result<()> {
+    Err(anyhow::anyhow!("--admin-socket needs Unix sockets; use --admin-addr instead"))
+}
+
+/// Accept relay clients on an already-bound listener
+pub async fn serve(listener: TcpListener, config: RelayConfig, state: Arc<RelayState>) -> Result<()> {
+    let config = Arc::new(config);
     loop {
         let (stream, addr) = listener.accept().await?;
-        let peers = peers.clone();
+        let state = state.clone();
+        let config = config.clone();
         
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, addr, peers).await {
+            if let Err(e) = handle_connection(stream, addr, state, &config).await {
                 eprintln!("Error handling connection from {}: {}", addr, e);
             }
         });
     }
 }
 
-async fn handle_connection(stream: TcpStream, addr: SocketAddr, peers: PeerMap) -> Result<()> {
+/// WebSocket settings that cap how much a single client frame can make us buffer
+fn websocket_config(max_frame_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_frame_size),
+        max_frame_size: Some(max_frame_size),
+        ..Default::default()
+    }
+}
+
+/// Keeps the active connection count accurate however a handler exits
+struct ConnectionGuard<'a>(&'a RelayStats);
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(stats: &'a RelayStats) -> Self {
+        stats.active_connections.fetch_add(1, Ordering::Relaxed);
+        stats.connections_total.fetch_add(1, Ordering::Relaxed);
+        Self(stats)
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A room this connection has joined
+struct Membership {
+    code_hash: String,
+    /// Id of the room in `RelayState`, so a replaced room isn't touched
+    room: u64,
+    role: Role,
+}
+
+/// Per-connection state shared by the message handlers
+struct Client {
+    addr: SocketAddr,
+    tx: ForwardQueue,
+    kicked: CancellationToken,
+    /// Rooms keyed by the client's room id (`None` for a single-room client)
+    memberships: HashMap<Option<u32>, Membership>,
+    throttled: bool,
+    /// Mailbox upload in progress on this connection
+    upload: Option<Upload>,
+    /// Forwarding limits for each room this client joins
+    limits: RoomLimits,
+    /// The client marks its binary frames with `FRAME_MAGIC` and expects the same back
+    frame_magic: bool,
+    /// The client wants its peer's address, to try reaching it directly
+    peer_hint: bool,
+    /// The client registers again when its room expires
+    room_expiry: bool,
+    /// The client, a receiver, asked for a stored upload if there's no live sender
+    pickup: bool,
+    /// The client length-prefixes its payloads and may split them across frames
+    split_payloads: bool,
+}
+
+/// What the read loop should do after handling a message
+enum Flow {
+    Continue,
+    Disconnect,
+}
+
+impl Client {
+    /// Whether this connection registered with room ids
+    fn multiplexed(&self) -> bool {
+        self.memberships.keys().any(Option::is_some)
+    }
+    
+    /// Check the allow/deny lists, counting and logging refusals
+    fn refused(&self, state: &RelayState, code_hash: &str) -> bool {
+        if state.access.allows(self.addr.ip(), code_hash) {
+            return false;
+        }
+        println!("[{}] Registration refused for code hash {}", self.addr, hash_prefix(code_hash));
+        state.stats.denied_total.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+    
+    /// Strip the frame marker from a binary frame, or count, log and report a frame without one
+    ///
+    /// Past a frame without it the connection can't be trusted to be in step,
+    /// so `None` means the caller should hang up.
+    fn unmark<'a>(&self, state: &RelayState, frame: &'a [u8]) -> Result<Option<&'a [u8]>> {
+        if !self.frame_magic {
+            return Ok(Some(frame));
+        }
+        match frame.split_first() {
+            Some((&FRAME_MAGIC, rest)) => Ok(Some(rest)),
+            _ => {
+                println!("[{}] Binary frame without the zap frame marker, disconnecting", self.addr);
+                state.stats.errors_total.fetch_add(1, Ordering::Relaxed);
+                self.send_error(UNMARKED_FRAME, None)?;
+                Ok(None)
+            }
+        }
+    }
+    
+    fn send_error(&self, message: &str, room_id: Option<u32>) -> Result<()> {
+        let error_msg = RelayMessage::Error {
+            message: message.to_string(),
+            room_id,
+        }.to_json()?;
+        self.tx.try_send(Message::Text(error_msg));
+        Ok(())
+    }
+    
+    async fn register(&mut self, state: &RelayState, r: Role, ch: String, room_id: Option<u32>) -> Result<Flow> {
+        if self.refused(state, &ch) {
+            self.send_error(REFUSED, room_id)?;
+            return Ok(if room_id.is_some() { Flow::Continue } else { Flow::Disconnect });
+        }
+        println!("[{}] Registered as {:?} with code hash {}", self.addr, r, hash_prefix(&ch));
+        
+        if room_id.is_none() && !self.memberships.is_empty() {
+            self.send_error("Multi-room connections must set room_id", None)?;
+            return Ok(Flow::Continue);
+        }
+        if self.memberships.contains_key(&room_id) {
+            self.send_error("Room id already in use on this connection", room_id)?;
+            return Ok(Flow::Continue);
+        }
+        
+        let mut entry = state.rooms.entry(ch.clone()).or_insert_with(|| state.new_room());
+        let room = entry.value_mut();
+        
+        if room.peer(&r).is_some() {
+            // Same role - error
+            self.send_error("Both peers have the same role", room_id)?;
+            // A single-room client has nothing else to do
+            return Ok(if room_id.is_some() { Flow::Continue } else { Flow::Disconnect });
+        }
+        
+        // Frames go on as they are, so one peer's split payloads would be garbage to the other
+        if let Some(other_peer) = room.peer(&r.opposite()).filter(|other_peer| other_peer.split_payloads != self.split_payloads) {
+            println!("[{}] Frames payloads differently from {}, not matching them", self.addr, other_peer.addr);
+            let error = RelayMessage::Error {
+                message: SPLIT_MISMATCH.to_string(),
+                room_id: other_peer.room_id,
+            };
+            other_peer.tx.try_send(Message::Text(error.to_json()?));
+            self.send_error(SPLIT_MISMATCH, room_id)?;
+            return Ok(if room_id.is_some() { Flow::Continue } else { Flow::Disconnect });
+        }
+        
+        // Store this peer
+        *room.slot(&r) = Some(Peer {
+            tx: self.tx.clone(),
+            addr: self.addr,
+            kicked: self.kicked.clone(),
+            room_id,
+            lane: Arc::new(RoomLane::new(self.limits, state.stats.backpressure_events_total.clone())),
+            frame_magic: self.frame_magic,
+            peer_hint: self.peer_hint,
+            room_expiry: self.room_expiry,
+            pickup: self.pickup,
+            split_payloads: self.split_payloads,
+        });
+        
+        // Check if there's a matching peer
+        if let Some(other_peer) = room.peer(&r.opposite()) {
+            // Match found! Notify both
+            println!("[{}] {} Matched with {}", self.addr, glyphs().check, other_peer.addr);
+            
+            // Only when both asked, so nobody's address goes to a peer they didn't agree to show it to
+            if self.peer_hint && other_peer.peer_hint {
+                let hint = RelayMessage::PeerHint { external_addr: other_peer.addr, room_id };
+                self.tx.try_send(Message::Text(hint.to_json()?));
+                let hint = RelayMessage::PeerHint { external_addr: self.addr, room_id: other_peer.room_id };
+                other_peer.tx.try_send(Message::Text(hint.to_json()?));
+            }
+            self.tx.try_send(Message::Text(RelayMessage::Matched { room_id }.to_json()?));
+            other_peer.tx.try_send(Message::Text(RelayMessage::Matched { room_id: other_peer.room_id }.to_json()?));
+            
+            room.matched_at = Some(Instant::now());
+            state.stats.matches_total.fetch_add(1, Ordering::Relaxed);
+        } else if let Some(delivery) = state.mailbox.as_ref().filter(|_| r == Role::Receiver && self.pickup).and_then(|mailbox| mailbox.take(&ch)) {
+            // The sender left its upload in the mailbox
+            println!("[{}] {} Serving stored upload from mailbox", self.addr, glyphs().check);
+            serve_from_mailbox(state, room, delivery)?;
+        } else {
+            // No match yet, wait for peer
+            println!("[{}] Waiting for matching peer...", self.addr);
+        }
+        
+        let membership = Membership {
+            code_hash: ch,
+            room: room.id,
+            role: r,
+        };
+        self.memberships.insert(room_id, membership);
+        Ok(Flow::Continue)
+    }
+    
+    /// Start storing this connection's upload in the mailbox
+    async fn offer_store(&mut self, state: &RelayState, code_hash: String, ttl_secs: u64) -> Result<Flow> {
+        let Some(ref mailbox) = state.mailbox else {
+            self.send_error("Mailbox mode is not enabled on this relay", None)?;
+            return Ok(Flow::Disconnect);
+        };
+        if self.refused(state, &code_hash) {
+            self.send_error(REFUSED, None)?;
+            return Ok(Flow::Disconnect);
+        }
+        
+        match mailbox.begin(&code_hash, Duration::from_secs(ttl_secs)).await {
+            Ok(upload) => {
+                println!("[{}] Storing upload for code hash {}", self.addr, hash_prefix(&code_hash));
+                let accepted = RelayMessage::StoreAccepted { ttl_secs: upload.ttl().as_secs() };
+                self.tx.try_send(Message::Text(accepted.to_json()?));
+                self.upload = Some(upload);
+                Ok(Flow::Continue)
+            }
+            Err(e) => {
+                self.send_error(&e.to_string(), None)?;
+                Ok(Flow::Disconnect)
+            }
+        }
+    }
+    
+    /// Spool a binary frame of the upload to disk
+    async fn store(&mut self, state: &RelayState, data: &[u8]) -> Result<Flow> {
+        let Some(data) = self.unmark(state, data)? else {
+            return Ok(Flow::Disconnect);
+        };
+        let Some(ref mut upload) = self.upload else {
+            return Ok(Flow::Continue);
+        };
+        if let Err(e) = upload.write(data).await {
+            println!("[{}] Mailbox upload rejected: {}", self.addr, e);
+            self.upload = None;
+            self.send_error(&e.to_string(), None)?;
+            return Ok(Flow::Disconnect);
+        }
+        Ok(Flow::Continue)
+    }
+    
+    /// Commit the upload and hand it to a receiver that's already waiting
+    async fn store_done(&mut self, state: &RelayState) -> Result<Flow> {
+        let (Some(upload), Some(mailbox)) = (self.upload.take(), state.mailbox.as_ref()) else {
+            return Ok(Flow::Continue);
+        };
+        let code_hash = upload.code_hash().to_string();
+        upload.commit().await?;
+        println!("[{}] {} Upload stored for code hash {}", self.addr, glyphs().check, hash_prefix(&code_hash));
+        self.tx.try_send(Message::Text(RelayMessage::Stored.to_json()?));
+        
+        let waiting = |room: &Room| room.matched_at.is_none() && room.receiver.as_ref().is_some_and(|receiver| receiver.pickup);
+        if let Some(mut room) = state.rooms.get_mut(&code_hash).filter(|room| waiting(room)) {
+            if let Some(delivery) = mailbox.take(&code_hash) {
+                serve_from_mailbox(state, &mut room, delivery)?;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+    
+    /// Forward a binary frame to the other peer in its room
+    async fn forward(&mut self, state: &RelayState, data: Vec<u8>) -> Result<Flow> {
+        // Multi-room frames carry the room id up front
+        let (room_id, payload) = if self.multiplexed() {
+            if data.len() < ROOM_ID_SIZE {
+                return Ok(Flow::Continue);
+            }
+            let (prefix, payload) = data.split_at(ROOM_ID_SIZE);
+            let room_id = u32::from_be_bytes(prefix.try_into().expect("prefix is ROOM_ID_SIZE bytes"));
+            (Some(room_id), payload)
+        } else {
+            (None, data.as_slice())
+        };
+        let Some(payload) = self.unmark(state, payload)? else {
+            return Ok(Flow::Disconnect);
+        };
+        
+        let Some(membership) = self.memberships.get(&room_id) else {
+            return Ok(Flow::Continue);
+        };
+        
+        let other = state.rooms
+            .get(&membership.code_hash)
+            .filter(|room| room.id == membership.room)
+            .and_then(|room| {
+                room.peer(&membership.role.opposite())
+                    .map(|other_peer| (other_peer.tx.clone(), other_peer.room_id, other_peer.lane.clone(), other_peer.frame_magic))
+            });
+        let Some((other_tx, other_room_id, lane, other_magic)) = other else {
+            return Ok(Flow::Continue);
+        };
+        
+        // Re-address and re-mark the frame for the other peer's view of the room
+        let frame = match (room_id, other_room_id) {
+            (None, None) if self.frame_magic == other_magic => data,
+            _ => address_frame(other_room_id, other_magic, payload),
+        };
+        
+        if other_tx.is_full() && !self.throttled {
+            println!("[{}] Peer is reading slowly, pausing reads (forward queue full)", self.addr);
+        }
+        
+        // Waiting here stops us reading from this peer until the other side catches up
+        let len = frame.len() as u64;
+        let Ok(reservation) = lane.reserve(frame.len()).await else {
+            return Ok(Flow::Continue);
+        };
+        if let Ok(blocked) = other_tx.send_reserved(Message::Binary(frame), reservation).await {
+            self.throttled = blocked;
+            
+            if let Some(mut room) = state.rooms.get_mut(&membership.code_hash).filter(|room| room.id == membership.room) {
+                room.bytes_forwarded += len;
+            }
+            state.stats.bytes_forwarded_total.fetch_add(len, Ordering::Relaxed);
+        }
+        Ok(Flow::Continue)
+    }
+    
+    /// Tear down one room, unless it was already torn down or replaced, and tell the other peer
+    async fn leave(&mut self, state: &RelayState, room_id: Option<u32>) {
+        let Some(membership) = self.memberships.remove(&room_id) else {
+            return;
+        };
+        let room = state.rooms
+            .remove_if(&membership.code_hash, |_, room| room.id == membership.room)
+            .map(|(_, room)| room);
+        // Otherwise the other peer waits for frames that will never come
+        if let Some(other_peer) = room.as_ref().and_then(|room| room.peer(&membership.role.opposite())) {
+            if let Ok(json) = (RelayMessage::PeerDisconnected { room_id: other_peer.room_id }).to_json() {
+                // Behind whatever was already forwarded, waiting for space if the queue is full
+                let tx = other_peer.tx.clone();
+                tokio::spawn(async move { tx.send(Message::Text(json)).await });
+            }
+        }
+    }
+}
+
+/// Replay a stored upload to the room's waiting receiver as though the sender were live
+///
+/// Anything the receiver sends back has no peer to go to and is dropped.
+fn serve_from_mailbox(state: &RelayState, room: &mut Room, delivery: Delivery) -> Result<()> {
+    let Some(receiver) = room.receiver.as_ref() else {
+        return Ok(());
+    };
+    let (tx, room_id, addr, frame_magic) = (receiver.tx.clone(), receiver.room_id, receiver.addr, receiver.frame_magic);
+    
+    tx.try_send(Message::Text(RelayMessage::Matched { room_id }.to_json()?));
+    room.matched_at = Some(Instant::now());
+    state.stats.matches_total.fetch_add(1, Ordering::Relaxed);
+    
+    tokio::spawn(async move {
+        match replay(&delivery, &tx, room_id, frame_magic).await {
+            Ok(()) => {
+                println!("[{}] {} Mailbox upload delivered", addr, glyphs().check);
+                delivery.finish();
+            }
+            // Dropping the delivery keeps the upload for another try
+            Err(e) => println!("[{}] Mailbox delivery failed: {}", addr, e),
+        }
+    });
+    Ok(())
+}
+
+async fn replay(delivery: &Delivery, tx: &ForwardQueue, room_id: Option<u32>, frame_magic: bool) -> Result<()> {
+    let mut frames = delivery.frames().await?;
+    while let Some(frame) = frames.next_frame().await? {
+        let frame = match (room_id, frame_magic) {
+            (None, false) => frame,
+            _ => address_frame(room_id, frame_magic, &frame),
+        };
+        tx.send(Message::Binary(frame)).await?;
+    }
+    Ok(())
+}
+
+/// A binary frame carrying `payload` to a client, with its room id and frame marker if it uses them
+fn address_frame(room_id: Option<u32>, frame_magic: bool, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ROOM_ID_SIZE + 1 + payload.len());
+    if let Some(room_id) = room_id {
+        frame.extend_from_slice(&room_id.to_be_bytes());
+    }
+    if frame_magic {
+        frame.push(FRAME_MAGIC);
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read the request head, answering anything that isn't an upgrade for our path without a WebSocket
+///
+/// Returns the client's address, from `X-Forwarded-For` when the proxy is trusted, and
+/// the stream to hand to the handshake with the head still to be read from it.
+async fn accept_http(stream: TcpStream, addr: SocketAddr, config: &RelayConfig) -> Result<Option<(SocketAddr, bool, impl AsyncRead + AsyncWrite + Unpin)>> {
+    let (mut read, mut write) = stream.into_split();
+    let head = tokio::time::timeout(http::REQUEST_HEAD_TIMEOUT, http::read_head(&mut read))
+        .await
+        .map_err(|_| anyhow!("No request within {}s", http::REQUEST_HEAD_TIMEOUT.as_secs()))??;
+    let request = RequestHead::parse(&head)?;
+    if !request.under(config.path.as_deref()) {
+        println!("[{}] No relay at {}, answered 404", addr, request.path);
+        write.write_all(&http::response("404 Not Found", &[], "Not found\n")).await?;
+        return Ok(None);
+    }
+    // Health checks and browsers, which don't need to show up in the log
+    if !request.upgrade {
+        let body = "This is a zap relay; point zap's --relay at it\n";
+        write.write_all(&http::response("426 Upgrade Required", &[("Upgrade", "websocket")], body)).await?;
+        return Ok(None);
+    }
+    
+    let (addr, forwarded) = match request.forwarded_for {
+        Some(ip) if config.trust_proxy => (SocketAddr::new(ip, addr.port()), true),
+        _ => (addr, false),
+    };
+    Ok(Some((addr, forwarded, tokio::io::join(Cursor::new(head).chain(read), write))))
+}
+
+/// Whether `msg` is what a new connection has to start with: a Register, or an OfferStore
+fn registers(msg: &Message) -> bool {
+    match msg {
+        Message::Text(text) => matches!(RelayMessage::from_json(text), Ok(RelayMessage::Register { .. } | RelayMessage::OfferStore { .. })),
+        _ => false,
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: Arc<RelayState>, config: &RelayConfig) -> Result<()> {
+    // A client that trickles in its request or Register, or never sends one, only holds a slot this long
+    let deadline = Instant::now() + config.register_timeout;
+    let timed_out = || {
+        state.stats.handshake_timeouts_total.fetch_add(1, Ordering::Relaxed);
+        anyhow!("Didn't register within {}s", config.register_timeout.as_secs_f64())
+    };
+    let accepted = tokio::time::timeout_at(deadline, accept_http(stream, addr, config)).await.map_err(|_| timed_out())?;
+    let Some((addr, forwarded, stream)) = accepted? else {
+        return Ok(());
+    };
     println!("[{}] New connection", addr);
+    let _guard = ConnectionGuard::new(&state.stats);
+    let limits = RoomLimits {
+        in_flight_bytes: config.room_in_flight_bytes,
+        rate: config.per_room_rate,
+    };
     
-    let ws_stream = accept_async(stream).await?;
+    let upgrade = accept_async_with_config(stream, Some(websocket_config(config.max_frame_size)));
+    let ws_stream = tokio::time::timeout_at(deadline, upgrade).await.map_err(|_| timed_out())??;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
     
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = ForwardQueue::new(config.queue_depth, state.stats.backpressure_events_total.clone());
     
     // Spawn task to forward messages from channel to websocket
-    let forward_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
-                break;
+    let writer_gone = CancellationToken::new();
+    let mut forward_task = tokio::spawn({
+        let writer_gone = writer_gone.clone();
+        async move {
+            // However the writer stops, the read loop hears of it
+            let _gone = writer_gone.drop_guard();
+            while let Some(queued) = rx.recv().await {
+                if let Err(e) = ws_sender.send(queued.msg).await {
+                    println!("[{}] Couldn't write to client: {}", addr, e);
+                    break;
+                }
             }
         }
     });
     
-    let mut code_hash: Option<String> = None;
-    let mut role: Option<Role> = None;
+    let mut client = Client {
+        addr,
+        tx,
+        kicked: CancellationToken::new(),
+        memberships: HashMap::new(),
+        throttled: false,
+        upload: None,
+        limits,
+        frame_magic: false,
+        peer_hint: false,
+        room_expiry: false,
+        pickup: false,
+        split_payloads: false,
+    };
+    let mut result = Ok(());
+    // Until then, the client is held to the deadline and the pre-registration limits
+    let mut registered = false;
+    let mut strays = 0;
+    
+    // Version 1 clients ignore messages they don't know, so everyone gets a Welcome
+    let mut capabilities = vec![
+        CAP_ROOMS.to_string(),
+        CAP_FRAME_MAGIC.to_string(),
+        CAP_KEEPALIVE.to_string(),
+        CAP_ROOM_EXPIRY.to_string(),
+        CAP_SPLIT_PAYLOADS.to_string(),
+    ];
+    // Behind a proxy the port is the proxy's, so there's no address worth hinting at
+    if !forwarded {
+        capabilities.push(CAP_PEER_HINT.to_string());
+    }
+    if state.mailbox.is_some() {
+        capabilities.push(CAP_MAILBOX.to_string());
+        capabilities.push(CAP_PICKUP.to_string());
+    }
+    let welcome = RelayMessage::Welcome {
+        version: RELAY_PROTOCOL_VERSION,
+        capabilities,
+    };
+    client.tx.try_send(Message::Text(welcome.to_json()?));
     
     // Handle incoming messages
-    while let Some(msg) = ws_receiver.next().await {
-        match msg? {
-            Message::Text(text) => {
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = client.kicked.cancelled() => {
+                // Whatever closed the room already queued the reason for the client
+                println!("[{}] Room closed by relay", addr);
+                break;
+            }
+            _ = writer_gone.cancelled() => {
+                // Nothing queued for this client will reach it, so its peers shouldn't wait on it
+                break;
+            }
+            _ = tokio::time::sleep_until(deadline), if !registered => {
+                println!("[{}] {}, disconnecting", addr, timed_out());
+                client.send_error("Registration timed out", None)?;
+                break;
+            }
+        };
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size })) => {
+                println!("[{}] Frame too large ({} > {} bytes), disconnecting", addr, size, max_size);
+                client.send_error("Frame too large", None)?;
+                break;
+            }
+            Err(e) => {
+                // Still clean up this connection's rooms below
+                result = Err(e.into());
+                break;
+            }
+        };
+        if !registered && !msg.is_close() {
+            if msg.len() > config.pre_register_frame_size {
+                state.stats.handshake_timeouts_total.fetch_add(1, Ordering::Relaxed);
+                println!("[{}] {}-byte frame before registering, disconnecting", addr, msg.len());
+                client.send_error("Frame too large", None)?;
+                break;
+            }
+            if !registers(&msg) {
+                strays += 1;
+                if strays > config.pre_register_frames {
+                    state.stats.handshake_timeouts_total.fetch_add(1, Ordering::Relaxed);
+                    println!("[{}] {} frames without registering, disconnecting", addr, strays);
+                    client.send_error("Expected Register message", None)?;
+                    break;
+                }
+            }
+        }
+        
+        match msg {
+            // Answered in any state, so a matched client can tell the relay is still there
+            Message::Text(text) if matches!(RelayMessage::from_json(&text), Ok(RelayMessage::Ping)) => {
+                client.tx.try_send(Message::Text(RelayMessage::Pong.to_json()?));
+            }
+            // A single-room client only ever registers once
+            Message::Text(text) if client.memberships.is_empty() || client.multiplexed() => {
                 // Handle handshake
-                if code_hash.is_none() {
-                    match RelayMessage::from_json(&text) {
-                        Ok(RelayMessage::Register { role: r, code_hash: ch }) => {
-                            println!("[{}] Registered as {:?} with code hash {}", addr, r, &ch[..8]);
-                            
-                            // Store this peer
-                            let peer = Peer {
-                                role: r.clone(),
-                                tx: tx.clone(),
-                                addr,
-                            };
-                            
-                            let mut peers_lock = peers.lock().await;
-                            
-                            // Check if there's a matching peer
-                            if let Some(other_peer) = peers_lock.get(&ch) {
-                                // Ensure roles are different
-                                if other_peer.role != r {
-                                    // Match found! Notify both
-                                    println!("[{}] ✓ Matched with {}", addr, other_peer.addr);
-                                    
-                                    let matched_msg = RelayMessage::Matched.to_json()?;
-                                    
-                                    // Notify both peers
-                                    let _ = tx.send(Message::Text(matched_msg.clone()));
-                                    let _ = other_peer.tx.send(Message::Text(matched_msg));
-                                    
-                                    code_hash = Some(ch.clone());
-                                    role = Some(r);
-                                } else {
-                                    // Same role - error
-                                    let error_msg = RelayMessage::Error {
-                                        message: "Both peers have the same role".to_string(),
-                                    }.to_json()?;
-                                    let _ = tx.send(Message::Text(error_msg));
-                                    return Ok(());
-                                }
-                            } else {
-                                // No match yet, wait for peer
-                                peers_lock.insert(ch.clone(), peer);
-                                code_hash = Some(ch);
-                                role = Some(r);
-                                println!("[{}] Waiting for matching peer...", addr);
-                            }
-                        }
-                        Ok(RelayMessage::Ping) => {
-                            let _ = tx.send(Message::Text(RelayMessage::Pong.to_json()?));
+                let relay_msg = RelayMessage::from_json(&text);
+                if let Ok(RelayMessage::Register { version, .. } | RelayMessage::OfferStore { version, .. }) = relay_msg {
+                    if let Err(message) = check_version(version) {
+                        println!("[{}] {}", addr, message);
+                        client.send_error(&message, None)?;
+                        break;
+                    }
+                }
+                if let Ok(RelayMessage::Register { version, room_id: None, capabilities, .. } | RelayMessage::OfferStore { version, capabilities, .. }) = &relay_msg {
+                    if let Err(message) = check_frame_magic(*version, capabilities) {
+                        println!("[{}] {}", addr, message);
+                        client.send_error(&message, None)?;
+                        break;
+                    }
+                }
+                match relay_msg {
+                    Ok(RelayMessage::Register { role: r, code_hash: ch, room_id, capabilities, .. }) if client.upload.is_none() => {
+                        client.split_payloads = capabilities.iter().any(|capability| capability == CAP_SPLIT_PAYLOADS);
+                        // Multi-room frames keep their room id up front instead
+                        if room_id.is_none() {
+                            client.frame_magic = capabilities.iter().any(|capability| capability == CAP_FRAME_MAGIC);
+                            client.peer_hint = capabilities.iter().any(|capability| capability == CAP_PEER_HINT);
+                            client.room_expiry = capabilities.iter().any(|capability| capability == CAP_ROOM_EXPIRY);
+                            client.pickup = capabilities.iter().any(|capability| capability == CAP_PICKUP);
                         }
-                        _ => {
-                            let error_msg = RelayMessage::Error {
-                                message: "Expected Register message".to_string(),
-                            }.to_json()?;
-                            let _ = tx.send(Message::Text(error_msg));
+                        if let Flow::Disconnect = client.register(&state, r, ch, room_id).await? {
                             return Ok(());
                         }
                     }
+                    Ok(RelayMessage::Leave { room_id }) => {
+                        client.leave(&state, Some(room_id)).await;
+                    }
+                    Ok(RelayMessage::OfferStore { code_hash, ttl_secs, capabilities, .. }) if client.memberships.is_empty() && client.upload.is_none() => {
+                        client.frame_magic = capabilities.iter().any(|capability| capability == CAP_FRAME_MAGIC);
+                        if let Flow::Disconnect = client.offer_store(&state, code_hash, ttl_secs).await? {
+                            break;
+                        }
+                    }
+                    Ok(RelayMessage::StoreDone) if client.upload.is_some() => {
+                        client.store_done(&state).await?;
+                    }
+                    _ if client.multiplexed() => {}
+                    _ => {
+                        client.send_error("Expected Register message", None)?;
+                        return Ok(());
+                    }
+                }
+            }
+            Message::Binary(data) if client.upload.is_some() => {
+                if let Flow::Disconnect = client.store(&state, &data).await? {
+                    break;
                 }
             }
             Message::Binary(data) => {
                 // After matched, forward binary data to the other peer
-                if let Some(ref ch) = code_hash {
-                    let peers_lock = peers.lock().await;
-                    if let Some(other_peer) = peers_lock.get(ch) {
-                        // Only forward if roles are different (the matched peer)
-                        if let Some(ref my_role) = role {
-                            if &other_peer.role != my_role {
-                                let _ = other_peer.tx.send(Message::Binary(data));
-                            }
-                        }
-                    }
+                if let Flow::Disconnect = client.forward(&state, data).await? {
+                    break;
                 }
             }
             Message::Close(_) => {
@@ -147,15 +823,685 @@ async fn handle_connection(stream: TcpStream, addr: SocketAddr, peers: PeerMap)
             }
             _ => {}
         }
+        registered |= !client.memberships.is_empty() || client.upload.is_some();
     }
     
     // Cleanup
-    if let Some(ch) = code_hash {
-        let mut peers_lock = peers.lock().await;
-        peers_lock.remove(&ch);
+    if !client.memberships.is_empty() {
+        let room_ids: Vec<_> = client.memberships.keys().copied().collect();
+        for room_id in room_ids {
+            client.leave(&state, room_id).await;
+        }
         println!("[{}] Disconnected", addr);
     }
     
+    // Give any pending error message a chance to reach the client
+    drop(client);
+    let _ = tokio::time::timeout(Duration::from_secs(1), &mut forward_task).await;
     forward_task.abort();
-    Ok(())
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::protocol::hash_code;
+    use super::super::url::RelayUrl;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+    
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = RelayConfig {
+            port: addr.port(),
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        tokio::spawn(serve(listener, config, Arc::new(RelayState::default())));
+        
+        let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let register = RelayMessage::Register {
+            role: Role::Sender,
+            code_hash: hash_code("alpha-bravo-charlie"),
+            room_id: None,
+            version: Some(RELAY_PROTOCOL_VERSION),
+            capabilities: vec![CAP_FRAME_MAGIC.to_string()],
+        };
+        ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+        ws.send(Message::Binary(marked(0, 4096))).await.unwrap();
+        
+        let mut rejected = false;
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Text(text) = msg {
+                if let Ok(RelayMessage::Error { message, .. }) = RelayMessage::from_json(&text) {
+                    assert_eq!(message, "Frame too large");
+                    rejected = true;
+                }
+            }
+        }
+        
+        assert!(rejected, "relay should reject the oversized frame");
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_busy_room_does_not_starve_others() {
+        use super::super::client::RelayConnection;
+        use super::super::multiplex::RelaySession;
+        
+        const WINDOW: Duration = Duration::from_secs(2);
+        const SLOW_INTERVAL: Duration = Duration::from_millis(10);
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = RelayUrl::from(listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        // All four rooms share one receiving connection, so they share its forward queue
+        let session = RelaySession::connect(&relay, MAX_RELAY_FRAME_SIZE).await.unwrap();
+        let mut rooms = Vec::new();
+        let mut senders = Vec::new();
+        for code in ["fast-room-one", "slow-room-one", "slow-room-two", "slow-room-three"] {
+            let (room, sender) = tokio::join!(
+                session.open(code, Role::Receiver),
+                RelayConnection::connect(std::slice::from_ref(&relay), code, Role::Sender, MAX_RELAY_FRAME_SIZE),
+            );
+            rooms.push(room.unwrap());
+            senders.push(sender.unwrap());
+        }
+        
+        let deadline = Instant::now() + WINDOW;
+        let readers: Vec<_> = rooms.into_iter().map(|mut room| tokio::spawn(async move {
+            let mut received = 0usize;
+            while let Ok(Ok(_)) = tokio::time::timeout_at(deadline, room.receive()).await {
+                received += 1;
+            }
+            received
+        })).collect();
+        
+        let mut senders = senders.into_iter();
+        let mut fast = senders.next().unwrap();
+        tokio::spawn(async move {
+            let payload = vec![0u8; 1024 * 1024];
+            while fast.send(&payload).await.is_ok() {}
+        });
+        for mut slow in senders {
+            tokio::spawn(async move {
+                let payload = vec![0u8; 16 * 1024];
+                let mut ticker = tokio::time::interval(SLOW_INTERVAL);
+                while Instant::now() < deadline {
+                    ticker.tick().await;
+                    if slow.send(&payload).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        
+        let mut received = Vec::new();
+        for reader in readers {
+            received.push(reader.await.unwrap());
+        }
+        let offered = (WINDOW.as_millis() / SLOW_INTERVAL.as_millis()) as usize;
+        assert!(received[0] > 0, "fast room made no progress");
+        for (i, count) in received[1..].iter().enumerate() {
+            assert!(*count * 2 >= offered, "slow room {} got {} of {} messages", i + 1, count, offered);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_slow_receiver_applies_backpressure() {
+        const FRAME_SIZE: usize = 1024 * 1024;
+        const FRAMES: usize = 128;
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = RelayConfig {
+            port: addr.port(),
+            queue_depth: 4,
+            ..Default::default()
+        };
+        let state = Arc::new(RelayState::default());
+        tokio::spawn(serve(listener, config, state.clone()));
+        
+        let code_hash = hash_code("alpha-bravo-charlie");
+        let connect = |role| {
+            let register = RelayMessage::Register {
+                role,
+                code_hash: code_hash.clone(),
+                room_id: None,
+                version: Some(RELAY_PROTOCOL_VERSION),
+                capabilities: vec![CAP_FRAME_MAGIC.to_string()],
+            };
+            async move {
+                let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+                ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+                ws
+            }
+        };
+        let mut sender = connect(Role::Sender).await;
+        let mut receiver = connect(Role::Receiver).await;
+        for ws in [&mut sender, &mut receiver] {
+            assert!(matches!(next_message(ws).await, RelayMessage::Welcome { .. }));
+            let matched = ws.next().await.unwrap().unwrap();
+            assert!(matches!(RelayMessage::from_json(matched.to_text().unwrap()), Ok(RelayMessage::Matched { .. })));
+        }
+        
+        // 128 MB is more than the queue and both sockets' kernel buffers can absorb
+        let send_task = tokio::spawn(async move {
+            for _ in 0..FRAMES {
+                sender.send(Message::Binary(marked(0, FRAME_SIZE))).await.unwrap();
+            }
+            sender
+        });
+        
+        // The receiver isn't reading, so the relay must stop reading from the sender
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while state.stats().await.backpressure_events_total == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("relay never applied backpressure");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!send_task.is_finished(), "sender should be blocked by the slow receiver");
+        
+        let mut received = 0;
+        while received < FRAMES * FRAME_SIZE {
+            if let Message::Binary(data) = receiver.next().await.unwrap().unwrap() {
+                received += data.len();
+            }
+        }
+        assert_eq!(received, FRAMES * FRAME_SIZE);
+        send_task.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_mailbox_quota_rejects_upload() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mailbox = Mailbox::open(MailboxConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 64 * 1024,
+        }).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::with_mailbox(mailbox.clone()))));
+        
+        let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let offer = RelayMessage::OfferStore {
+            code_hash: hash_code("alpha-bravo-charlie"),
+            ttl_secs: 60,
+            version: Some(RELAY_PROTOCOL_VERSION),
+            capabilities: vec![CAP_MAILBOX.to_string(), CAP_FRAME_MAGIC.to_string()],
+        };
+        ws.send(Message::Text(offer.to_json().unwrap())).await.unwrap();
+        match next_message(&mut ws).await {
+            RelayMessage::Welcome { capabilities, .. } => assert!(capabilities.contains(&CAP_MAILBOX.to_string())),
+            msg => panic!("expected Welcome, got {:?}", msg),
+        }
+        assert!(matches!(next_message(&mut ws).await, RelayMessage::StoreAccepted { ttl_secs: 60 }));
+        
+        for _ in 0..4 {
+            if ws.send(Message::Binary(marked(0, 32 * 1024))).await.is_err() {
+                break;
+            }
+        }
+        
+        let mut rejected = false;
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Text(text) = msg {
+                if let Ok(RelayMessage::Error { message, .. }) = RelayMessage::from_json(&text) {
+                    assert_eq!(message, "Mailbox quota exceeded");
+                    rejected = true;
+                }
+            }
+        }
+        assert!(rejected, "relay should reject an upload over the quota");
+        assert_eq!(mailbox.used_bytes(), 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_mailbox_disabled_rejects_offer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        let err = crate::relay::RelayConnection::store(&[addr.into()], "alpha-bravo-charlie", Duration::from_secs(60), MAX_RELAY_FRAME_SIZE)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("doesn't support mailbox mode"));
+    }
+    
+    /// A binary frame of `len` bytes of `fill`, marked the way the relay expects
+    fn marked(fill: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![fill; len];
+        frame[0] = FRAME_MAGIC;
+        frame
+    }
+    
+    async fn next_message(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> RelayMessage {
+        loop {
+            if let Message::Text(text) = ws.next().await.unwrap().unwrap() {
+                return RelayMessage::from_json(&text).unwrap();
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_unversioned_client_gets_legacy_behaviour() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        // Exactly what a client from before versioning sends
+        let register = |role| format!(r#"{{"type":"register","role":"{}","code_hash":"{}"}}"#, role, hash_code("alpha-bravo-charlie"));
+        let (mut sender, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let (mut receiver, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        sender.send(Message::Text(register("sender"))).await.unwrap();
+        receiver.send(Message::Text(register("receiver"))).await.unwrap();
+        
+        for ws in [&mut sender, &mut receiver] {
+            assert!(matches!(next_message(ws).await, RelayMessage::Welcome { version: RELAY_PROTOCOL_VERSION, .. }));
+            assert!(matches!(next_message(ws).await, RelayMessage::Matched { room_id: None }));
+        }
+        sender.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(receiver.next().await.unwrap().unwrap(), Message::Binary(vec![1, 2, 3]));
+    }
+    
+    #[tokio::test]
+    async fn test_unmarked_frame_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RelayState::default());
+        tokio::spawn(serve(listener, RelayConfig::default(), state.clone()));
+        
+        // The sender marks its frames, the receiver is from before versioning
+        let register = |role, version, capabilities: &[&str]| RelayMessage::Register {
+            role,
+            code_hash: hash_code("alpha-bravo-charlie"),
+            room_id: None,
+            version,
+            capabilities: capabilities.iter().map(|capability| capability.to_string()).collect(),
+        }.to_json().unwrap();
+        let (mut sender, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let (mut receiver, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        sender.send(Message::Text(register(Role::Sender, Some(RELAY_PROTOCOL_VERSION), &[CAP_FRAME_MAGIC]))).await.unwrap();
+        receiver.send(Message::Text(register(Role::Receiver, None, &[]))).await.unwrap();
+        for ws in [&mut sender, &mut receiver] {
+            match next_message(ws).await {
+                RelayMessage::Welcome { capabilities, .. } => assert!(capabilities.iter().any(|c| c == CAP_FRAME_MAGIC)),
+                msg => panic!("expected Welcome, got {:?}", msg),
+            }
+            assert!(matches!(next_message(ws).await, RelayMessage::Matched { room_id: None }));
+        }
+        
+        // A marked frame gets through without its marker
+        sender.send(Message::Binary(vec![FRAME_MAGIC, 4, 5, 6])).await.unwrap();
+        assert_eq!(receiver.next().await.unwrap().unwrap(), Message::Binary(vec![4, 5, 6]));
+        
+        // A corrupted one ends the connection instead of going missing
+        sender.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        match next_message(&mut sender).await {
+            RelayMessage::Error { message, .. } => assert_eq!(message, UNMARKED_FRAME),
+            msg => panic!("expected Error, got {:?}", msg),
+        }
+        loop {
+            match sender.next().await {
+                Some(Ok(Message::Binary(data))) => panic!("got a frame after the error: {:?}", data),
+                Some(Ok(_)) => {}
+                _ => break,
+            }
+        }
+        assert!(matches!(next_message(&mut receiver).await, RelayMessage::PeerDisconnected { room_id: None }));
+        assert_eq!(state.stats().await.errors_total, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_peers_framing_payloads_differently_told_why() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = RelayUrl::from(listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        // A client from before split payloads, waiting for its receiver
+        let code = "alpha-bravo-charlie";
+        let register = format!(r#"{{"type":"register","role":"sender","code_hash":"{}"}}"#, hash_code(code));
+        let (mut legacy, _) = connect_async(relay.to_string()).await.unwrap();
+        legacy.send(Message::Text(register)).await.unwrap();
+        assert!(matches!(next_message(&mut legacy).await, RelayMessage::Welcome { .. }));
+        
+        let err = super::super::client::RelayConnection::connect(std::slice::from_ref(&relay), code, Role::Receiver, MAX_RELAY_FRAME_SIZE).await.err().unwrap();
+        assert!(err.to_string().contains(SPLIT_MISMATCH), "{}", err);
+        match next_message(&mut legacy).await {
+            RelayMessage::Error { message, .. } => assert_eq!(message, SPLIT_MISMATCH),
+            msg => panic!("expected Error, got {:?}", msg),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_unmarked_client_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let register = RelayMessage::Register {
+            role: Role::Sender,
+            code_hash: hash_code("alpha-bravo-charlie"),
+            room_id: None,
+            version: Some(RELAY_PROTOCOL_VERSION),
+            capabilities: vec![CAP_KEEPALIVE.to_string()],
+        };
+        ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+        
+        assert!(matches!(next_message(&mut ws).await, RelayMessage::Welcome { .. }));
+        match next_message(&mut ws).await {
+            RelayMessage::Error { message, .. } => assert!(message.contains(CAP_FRAME_MAGIC), "{}", message),
+            msg => panic!("expected Error, got {:?}", msg),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_future_version_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        
+        let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let register = RelayMessage::Register {
+            role: Role::Sender,
+            code_hash: hash_code("alpha-bravo-charlie"),
+            room_id: None,
+            version: Some(RELAY_PROTOCOL_VERSION + 1),
+            capabilities: Vec::new(),
+        };
+        ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+        
+        assert!(matches!(next_message(&mut ws).await, RelayMessage::Welcome { .. }));
+        match next_message(&mut ws).await {
+            RelayMessage::Error { message, .. } => {
+                assert!(message.contains(&format!("supports 1-{}", RELAY_PROTOCOL_VERSION)), "{}", message);
+            }
+            msg => panic!("expected Error, got {:?}", msg),
+        }
+    }    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_many_pairs_at_once() {
+        use super::super::client::RelayConnection;
+        
+        const PAIRS: usize = 100;
+        
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = RelayUrl::from(listener.local_addr().unwrap());
+        let state = Arc::new(RelayState::default());
+        tokio::spawn(serve(listener, RelayConfig::default(), state.clone()));
+        
+        let pairs: Vec<_> = (0..PAIRS).map(|i| {
+            let relay = relay.clone();
+            tokio::spawn(async move {
+                let code = format!("pair-{}-of-many", i);
+                let (sender, receiver) = tokio::join!(
+                    RelayConnection::connect(std::slice::from_ref(&relay), &code, Role::Sender, MAX_RELAY_FRAME_SIZE),
+                    RelayConnection::connect(std::slice::from_ref(&relay), &code, Role::Receiver, MAX_RELAY_FRAME_SIZE),
+                );
+                let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+                sender.send(code.as_bytes()).await.unwrap();
+                // Every pair hears only its own peer
+                assert_eq!(receiver.receive().await.unwrap(), code.as_bytes());
+                sender.close().await.unwrap();
+                receiver.close().await.unwrap();
+            })
+        }).collect();
+        tokio::time::timeout(Duration::from_secs(30), async {
+            for pair in pairs {
+                pair.await.unwrap();
+            }
+        })
+        .await
+        .expect("pairs timed out");
+        assert_eq!(state.stats().await.matches_total, PAIRS as u64);
+        
+        // Every room is torn down once its peers hang up
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.list().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("rooms were left behind");
+    }    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_peer_killed_mid_transfer_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RelayState::default());
+        tokio::spawn(serve(listener, RelayConfig::default(), state.clone()));
+        
+        let code_hash = hash_code("alpha-bravo-charlie");
+        let mut peers = Vec::new();
+        for role in [Role::Sender, Role::Receiver] {
+            let (mut ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+            let register = RelayMessage::Register {
+                role,
+                code_hash: code_hash.clone(),
+                room_id: None,
+                version: Some(RELAY_PROTOCOL_VERSION),
+                capabilities: vec![CAP_FRAME_MAGIC.to_string()],
+            };
+            ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+            peers.push(ws);
+        }
+        let (mut sender, mut receiver) = (peers.remove(0), peers.remove(0));
+        for ws in [&mut sender, &mut receiver] {
+            assert!(matches!(next_message(ws).await, RelayMessage::Welcome { .. }));
+            assert!(matches!(next_message(ws).await, RelayMessage::Matched { .. }));
+        }
+        sender.send(Message::Binary(marked(1, 64 * 1024))).await.unwrap();
+        assert_eq!(receiver.next().await.unwrap().unwrap().len(), 64 * 1024);
+        
+        // The receiver's machine goes away: a reset, no close handshake
+        match receiver.get_ref() {
+            MaybeTlsStream::Plain(stream) => socket2::SockRef::from(stream).set_linger(Some(Duration::ZERO)).unwrap(),
+            _ => unreachable!("plain ws:// connection"),
+        }
+        drop(receiver);
+        
+        // The sender keeps going and hears about it instead of stalling
+        let (mut sink, mut stream) = sender.split();
+        let writer = tokio::spawn(async move {
+            while sink.send(Message::Binary(marked(2, 64 * 1024))).await.is_ok() {}
+        });
+        let notified = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match stream.next().await {
+                    Some(Ok(Message::Text(text))) => return RelayMessage::from_json(&text).unwrap(),
+                    Some(Ok(_)) => {}
+                    other => panic!("relay hung up instead: {:?}", other),
+                }
+            }
+        })
+        .await
+        .expect("sender was never told its peer went away");
+        writer.abort();
+        assert!(matches!(notified, RelayMessage::PeerDisconnected { room_id: None }), "{:?}", notified);
+        assert_eq!(state.stats().await.rooms_matched, 0);
+    }
+    
+    /// Send `request` on a fresh connection and read the whole response
+    async fn raw_http(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+    
+    async fn start_relay(config: RelayConfig, state: Arc<RelayState>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, config, state));
+        addr
+    }
+    
+    #[tokio::test]
+    async fn test_plain_http_told_to_upgrade() {
+        let state = Arc::new(RelayState::default());
+        let addr = start_relay(RelayConfig::default(), state.clone()).await;
+        let response = raw_http(addr, "GET /healthz HTTP/1.1\r\nHost: relay.example.com\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", response);
+        assert!(response.contains("\r\nUpgrade: websocket\r\n"), "{}", response);
+        assert!(response.ends_with("This is a zap relay; point zap's --relay at it\n"), "{}", response);
+        
+        // Not even counted as a connection
+        assert_eq!(state.stats().await.connections_total, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_wrong_path_not_found() {
+        let config = RelayConfig {
+            path: Some("/zap".to_string()),
+            ..Default::default()
+        };
+        let addr = start_relay(config, Arc::new(RelayState::default())).await;
+        
+        match connect_async(format!("ws://{}/other", addr)).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), 404),
+            other => panic!("expected a 404, got {:?}", other.map(|(_, response)| response)),
+        }
+        let response = raw_http(addr, "GET /zapper HTTP/1.1\r\nHost: relay.example.com\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        
+        // The right path still upgrades
+        let (mut ws, _) = connect_async(format!("ws://{}/zap", addr)).await.unwrap();
+        assert!(matches!(next_message(&mut ws).await, RelayMessage::Welcome { .. }));
+    }
+    
+    #[tokio::test]
+    async fn test_proxied_upgrade_uses_forwarded_address() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        
+        let dir = tempfile::TempDir::new().unwrap();
+        let denylist = dir.path().join("denylist.txt");
+        std::fs::write(&denylist, "203.0.113.7\n").unwrap();
+        let access = AccessControl::load(None, Some(&denylist)).unwrap();
+        let config = RelayConfig {
+            path: Some("/zap".to_string()),
+            trust_proxy: true,
+            ..Default::default()
+        };
+        let addr = start_relay(config, Arc::new(RelayState::default().with_access(Arc::new(access)))).await;
+        
+        // As nginx would pass it on: the original path, and the client's address added last
+        let connect = |forwarded_for: &'static str| async move {
+            let mut request = format!("ws://{}/zap", addr).into_client_request().unwrap();
+            request.headers_mut().insert("X-Forwarded-For", forwarded_for.parse().unwrap());
+            let (mut ws, _) = connect_async(request).await.unwrap();
+            let register = RelayMessage::Register {
+                role: Role::Sender,
+                code_hash: hash_code("alpha-bravo-charlie"),
+                room_id: None,
+                version: Some(RELAY_PROTOCOL_VERSION),
+                capabilities: vec![CAP_FRAME_MAGIC.to_string(), CAP_PEER_HINT.to_string()],
+            };
+            let capabilities = match next_message(&mut ws).await {
+                RelayMessage::Welcome { capabilities, .. } => capabilities,
+                other => panic!("expected Welcome, got {:?}", other),
+            };
+            ws.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+            (ws, capabilities)
+        };
+        
+        // The proxy's own address isn't on the list, but the client's is
+        let (mut refused, capabilities) = connect("198.51.100.1, 203.0.113.7").await;
+        assert!(!capabilities.iter().any(|capability| capability == CAP_PEER_HINT));
+        assert!(matches!(next_message(&mut refused).await, RelayMessage::Error { message, .. } if message == REFUSED));
+        
+        // Only the last entry is the proxy's to vouch for
+        let (mut accepted, _) = connect("203.0.113.7, 198.51.100.2").await;
+        let waiting = tokio::time::timeout(Duration::from_millis(300), accepted.next()).await;
+        assert!(waiting.is_err(), "registration should be waiting for a peer: {:?}", waiting);
+    }
+    
+    /// Read until the relay hangs up, returning the last error it sent
+    async fn hung_up<S>(ws: &mut S) -> Option<String>
+    where
+        S: StreamExt<Item = Result<Message, WsError>> + Unpin,
+    {
+        let mut error = None;
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Text(text) = msg {
+                if let Ok(RelayMessage::Error { message, .. }) = RelayMessage::from_json(&text) {
+                    error = Some(message);
+                }
+            }
+        }
+        error
+    }
+    
+    #[tokio::test]
+    async fn test_unregistered_client_evicted() {
+        let state = Arc::new(RelayState::default());
+        let config = RelayConfig {
+            register_timeout: Duration::from_millis(500),
+            pre_register_frames: usize::MAX,
+            ..Default::default()
+        };
+        let addr = start_relay(config, state.clone()).await;
+        let register = RelayMessage::Register {
+            role: Role::Sender,
+            code_hash: hash_code("alpha-bravo-charlie"),
+            room_id: None,
+            version: Some(RELAY_PROTOCOL_VERSION),
+            capabilities: vec![CAP_FRAME_MAGIC.to_string()],
+        };
+        let (mut registered, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        assert!(matches!(next_message(&mut registered).await, RelayMessage::Welcome { .. }));
+        registered.send(Message::Text(register.to_json().unwrap())).await.unwrap();
+        
+        // Busy pinging, but never registering
+        let started = Instant::now();
+        let (ws, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let (mut write, mut read) = ws.split();
+        let pinging = tokio::spawn(async move {
+            while write.send(Message::Ping(b"still here".to_vec())).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        assert_eq!(hung_up(&mut read).await.as_deref(), Some("Registration timed out"));
+        assert!(started.elapsed() < Duration::from_secs(3), "took {:?}", started.elapsed());
+        pinging.abort();
+        
+        // Half a Register, then nothing: the frame never finishes parsing
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut stalled, _) = tokio_tungstenite::client_async(format!("ws://{}", addr), stream).await.unwrap();
+        let mut frame = vec![0x81, 0x80 | 100, 1, 2, 3, 4];
+        frame.extend_from_slice(br#"{"type":"#);
+        stalled.get_mut().write_all(&frame).await.unwrap();
+        assert_eq!(hung_up(&mut stalled).await.as_deref(), Some("Registration timed out"));
+        assert_eq!(state.stats().await.handshake_timeouts_total, 2);
+        
+        // Registering in time lifts the deadline
+        let waiting = tokio::time::timeout(Duration::from_millis(300), registered.next()).await;
+        assert!(waiting.is_err(), "registration should be waiting for a peer: {:?}", waiting);
+    }
+    
+    #[tokio::test]
+    async fn test_pre_register_frame_limits() {
+        let state = Arc::new(RelayState::default());
+        let config = RelayConfig {
+            pre_register_frame_size: 256,
+            pre_register_frames: 2,
+            ..Default::default()
+        };
+        let addr = start_relay(config, state.clone()).await;
+        
+        let (mut chatty, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        for _ in 0..3 {
+            chatty.send(Message::Text(RelayMessage::Ping.to_json().unwrap())).await.unwrap();
+        }
+        assert_eq!(hung_up(&mut chatty).await.as_deref(), Some("Expected Register message"));
+        
+        let (mut oversized, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        oversized.send(Message::Text("x".repeat(1024))).await.unwrap();
+        assert_eq!(hung_up(&mut oversized).await.as_deref(), Some("Frame too large"));
+        assert_eq!(state.stats().await.handshake_timeouts_total, 2);
+    }
 }
@@ -0,0 +1,348 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use super::access::{AccessControl, Rule};
+use super::mailbox::Mailbox;
+use super::protocol::{RelayMessage, Role};
+use super::queue::{ForwardQueue, RoomLane};
+use super::rooms::RoomMap;
+
+/// Longest a room may wait for its second peer before the relay closes it
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How often the relay looks for rooms that waited too long
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+const KICKED: &str = "Room closed by relay operator";
+const EXPIRED: &str = "Room expired waiting for a peer";
+
+/// Represents a connected peer (sender or receiver)
+#[derive(Debug)]
+pub(crate) struct Peer {
+    pub tx: ForwardQueue,
+    pub addr: SocketAddr,
+    /// Cancelled when the relay closes the peer's room
+    pub kicked: CancellationToken,
+    /// The peer's id for this room on a multi-room connection
+    pub room_id: Option<u32>,
+    /// Limits on what this room may queue towards the peer
+    pub lane: Arc<RoomLane>,
+    /// Frames to this peer start with `FRAME_MAGIC`
+    pub frame_magic: bool,
+    /// The peer asked to be told the other peer's address (`CAP_PEER_HINT`)
+    pub peer_hint: bool,
+    /// The peer registers again when told its room expired (`CAP_ROOM_EXPIRY`)
+    pub room_expiry: bool,
+    /// The peer, a receiver, takes a stored upload when no live sender is there (`CAP_PICKUP`)
+    pub pickup: bool,
+    /// The peer's payloads are length-prefixed and may span frames (`CAP_SPLIT_PAYLOADS`)
+    pub split_payloads: bool,
+}
+
+/// A sender and receiver that registered with the same code hash
+#[derive(Debug)]
+pub(crate) struct Room {
+    /// Distinguishes this room from a later one reusing the same code hash
+    pub id: u64,
+    pub sender: Option<Peer>,
+    pub receiver: Option<Peer>,
+    pub created: Instant,
+    pub matched_at: Option<Instant>,
+    pub bytes_forwarded: u64,
+}
+
+impl Room {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            sender: None,
+            receiver: None,
+            created: Instant::now(),
+            matched_at: None,
+            bytes_forwarded: 0,
+        }
+    }
+    
+    /// Get the peer registered with `role`
+    pub fn peer(&self, role: &Role) -> Option<&Peer> {
+        match role {
+            Role::Sender => self.sender.as_ref(),
+            Role::Receiver => self.receiver.as_ref(),
+        }
+    }
+    
+    /// Get the slot for `role`
+    pub fn slot(&mut self, role: &Role) -> &mut Option<Peer> {
+        match role {
+            Role::Sender => &mut self.sender,
+            Role::Receiver => &mut self.receiver,
+        }
+    }
+    
+    fn info(&self, code_hash: &str) -> RoomInfo {
+        RoomInfo {
+            hash_prefix: hash_prefix(code_hash).to_string(),
+            state: if self.matched_at.is_some() { RoomState::Matched } else { RoomState::Waiting },
+            age_secs: self.created.elapsed().as_secs(),
+            matched_secs: self.matched_at.map(|at| at.elapsed().as_secs()),
+            bytes_forwarded: self.bytes_forwarded,
+        }
+    }
+    
+    /// Tell both peers why the room is going away and hang up single-room ones
+    fn close(&self, reason: &str) {
+        self.close_with(|peer| RelayMessage::Error {
+            message: reason.to_string(),
+            room_id: peer.room_id,
+        });
+    }
+    
+    /// Close a room that waited too long, telling peers that can register again to do so
+    fn expire(&self) {
+        self.close_with(|peer| match peer.room_expiry {
+            true => RelayMessage::RoomExpired { room_id: peer.room_id },
+            false => RelayMessage::Error {
+                message: EXPIRED.to_string(),
+                room_id: peer.room_id,
+            },
+        });
+    }
+    
+    fn close_with(&self, message: impl Fn(&Peer) -> RelayMessage) {
+        for peer in self.sender.iter().chain(self.receiver.iter()) {
+            if let Ok(json) = message(peer).to_json() {
+                peer.tx.try_send(Message::Text(json));
+            }
+            // Other rooms may share a multi-room connection, so only this one is closed
+            if peer.room_id.is_none() {
+                peer.kicked.cancel();
+            }
+        }
+    }
+}
+
+/// Running totals since the relay started
+#[derive(Debug, Default)]
+pub(crate) struct RelayStats {
+    pub active_connections: AtomicU64,
+    pub connections_total: AtomicU64,
+    pub matches_total: AtomicU64,
+    pub bytes_forwarded_total: AtomicU64,
+    /// Forwarded frames that had to wait for a full queue to drain
+    pub backpressure_events_total: Arc<AtomicUsize>,
+    /// Registrations refused by the allow/deny lists or a ban
+    pub denied_total: AtomicU64,
+    /// Binary frames dropped for missing the frame marker
+    pub errors_total: AtomicU64,
+    /// Connections dropped for not registering in time, or sending too much or
+    /// too many other frames first
+    pub handshake_timeouts_total: AtomicU64,
+}
+
+/// Rooms and counters shared by every relay connection and the admin endpoint
+#[derive(Debug)]
+pub struct RelayState {
+    pub(crate) rooms: DashMap<String, Room>,
+    pub(crate) stats: RelayStats,
+    /// Store for uploads waiting on an offline receiver, if enabled
+    pub(crate) mailbox: Option<Arc<Mailbox>>,
+    /// Who may register
+    pub(crate) access: Arc<AccessControl>,
+    next_room_id: AtomicU64,
+    started: Instant,
+}
+
+impl Default for RelayState {
+    fn default() -> Self {
+        Self {
+            rooms: RoomMap::default().into(),
+            stats: RelayStats::default(),
+            mailbox: None,
+            access: Arc::default(),
+            next_room_id: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl RelayState {
+    /// Relay state that stores uploads for offline receivers in `mailbox`
+    pub fn with_mailbox(mailbox: Arc<Mailbox>) -> Self {
+        Self {
+            mailbox: Some(mailbox),
+            ..Self::default()
+        }
+    }
+    
+    /// Check registrations against `access`
+    pub fn with_access(self, access: Arc<AccessControl>) -> Self {
+        Self { access, ..self }
+    }
+    
+    /// Keep rooms in `rooms`, e.g. one sized for the expected load
+    pub fn with_rooms(self, rooms: RoomMap) -> Self {
+        Self { rooms: rooms.into(), ..self }
+    }
+    
+    /// Create an empty room for a code hash that has no room yet
+    pub(crate) fn new_room(&self) -> Room {
+        Room::new(self.next_room_id.fetch_add(1, Ordering::Relaxed))
+    }
+    
+    /// Describe every live room
+    pub async fn list(&self) -> Vec<RoomInfo> {
+        let mut list: Vec<_> = self.rooms.iter().map(|room| room.info(room.key())).collect();
+        list.sort_by_key(|room| std::cmp::Reverse(room.age_secs));
+        list
+    }
+    
+    /// Tear down every room whose code hash starts with `prefix`, returning how many were closed
+    pub async fn kick(&self, prefix: &str) -> usize {
+        self.close_where(|room| room.close(KICKED), |code_hash, _| code_hash.starts_with(prefix)).await
+    }
+    
+    /// Refuse future registrations matching `rule` and tear down the rooms it matches
+    pub async fn ban(&self, rule: Rule) -> usize {
+        let kicked = self.close_where(|room| room.close(KICKED), |code_hash, room| {
+            room.sender.iter().chain(room.receiver.iter()).any(|peer| rule.matches(peer.addr.ip(), code_hash))
+        }).await;
+        self.access.ban(rule);
+        kicked
+    }
+    
+    /// Close every room that has waited longer than `timeout` for its second peer
+    ///
+    /// Matched rooms are left alone however old they are, since a big transfer
+    /// can take hours.
+    pub async fn expire_waiting(&self, timeout: Duration) -> usize {
+        self.close_where(Room::expire, |_, room| room.matched_at.is_none() && room.created.elapsed() >= timeout).await
+    }
+    
+    /// Expire rooms that waited longer than `session_timeout`, every minute
+    pub async fn run_cleanup(self: Arc<Self>, session_timeout: Duration) {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let expired = self.expire_waiting(session_timeout).await;
+            if expired > 0 {
+                println!("Closed {} room(s) that waited over {}s for a peer", expired, session_timeout.as_secs());
+            }
+        }
+    }
+    
+    async fn close_where(&self, close: impl Fn(&Room), mut matches: impl FnMut(&str, &Room) -> bool) -> usize {
+        let mut closed = 0;
+        self.rooms.retain(|code_hash, room| {
+            if matches(code_hash, room) {
+                close(room);
+                closed += 1;
+                false
+            } else {
+                true
+            }
+        });
+        closed
+    }
+    
+    /// Snapshot the relay-wide counters
+    pub async fn stats(&self) -> StatsSnapshot {
+        let rooms_total = self.rooms.len();
+        let rooms_matched = self.rooms.iter().filter(|room| room.matched_at.is_some()).count();
+        
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            active_connections: self.stats.active_connections.load(Ordering::Relaxed),
+            connections_total: self.stats.connections_total.load(Ordering::Relaxed),
+            rooms_waiting: rooms_total - rooms_matched,
+            rooms_matched,
+            matches_total: self.stats.matches_total.load(Ordering::Relaxed),
+            bytes_forwarded_total: self.stats.bytes_forwarded_total.load(Ordering::Relaxed),
+            backpressure_events_total: self.stats.backpressure_events_total.load(Ordering::Relaxed) as u64,
+            denied_total: self.stats.denied_total.load(Ordering::Relaxed),
+            errors_total: self.stats.errors_total.load(Ordering::Relaxed),
+            handshake_timeouts_total: self.stats.handshake_timeouts_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether a room is still waiting for its second peer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomState {
+    Waiting,
+    Matched,
+}
+
+/// One room as reported by the admin endpoint
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub hash_prefix: String,
+    pub state: RoomState,
+    pub age_secs: u64,
+    pub matched_secs: Option<u64>,
+    pub bytes_forwarded: u64,
+}
+
+/// Relay-wide counters as reported by the admin endpoint
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub active_connections: u64,
+    pub connections_total: u64,
+    pub rooms_waiting: usize,
+    pub rooms_matched: usize,
+    pub matches_total: u64,
+    pub bytes_forwarded_total: u64,
+    pub backpressure_events_total: u64,
+    pub denied_total: u64,
+    pub errors_total: u64,
+    pub handshake_timeouts_total: u64,
+}
+
+/// Short form of a code hash for logs and admin output
+pub fn hash_prefix(code_hash: &str) -> &str {
+    code_hash.get(..8).unwrap_or(code_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_ages_follow_the_clock() {
+        let state = RelayState::default();
+        state.rooms.insert("ab".repeat(32), state.new_room());
+        tokio::time::advance(Duration::from_secs(90)).await;
+        
+        let rooms = state.list().await;
+        assert_eq!(rooms[0].age_secs, 90);
+        assert_eq!(rooms[0].matched_secs, None);
+        assert_eq!(state.stats().await.uptime_secs, 90);
+    }    
+    #[tokio::test(start_paused = true)]
+    async fn test_waiting_rooms_expire() {
+        let state = RelayState::default();
+        state.rooms.insert("ab".repeat(32), state.new_room());
+        tokio::time::advance(Duration::from_secs(30 * 60)).await;
+        let mut matched = state.new_room();
+        matched.matched_at = Some(Instant::now());
+        state.rooms.insert("cd".repeat(32), matched);
+        state.rooms.insert("ef".repeat(32), state.new_room());
+        
+        assert_eq!(state.expire_waiting(DEFAULT_SESSION_TIMEOUT).await, 0);
+        tokio::time::advance(Duration::from_secs(45 * 60)).await;
+        
+        // Only the room that has waited over an hour goes; the matched one stays however old
+        assert_eq!(state.expire_waiting(DEFAULT_SESSION_TIMEOUT).await, 1);
+        let stats = state.stats().await;
+        assert_eq!((stats.rooms_waiting, stats.rooms_matched), (1, 1));
+        assert!(!state.rooms.contains_key(&"ab".repeat(32)));
+    }
+}
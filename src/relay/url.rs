@@ -0,0 +1,281 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Why a `--relay` value couldn't be used
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RelayUrlError {
+    #[error("Relay address is empty")]
+    Empty,
+    /// An http(s):// URL, which a relay won't answer
+    #[error("Relays speak WebSocket, not {scheme}: try {suggestion}")]
+    HttpScheme { scheme: String, suggestion: String },
+    #[error("Unsupported relay scheme {0}:// (use ws:// or wss://)")]
+    UnsupportedScheme(String),
+    #[error("Relay address {0:?} has no host")]
+    MissingHost(String),
+    #[error("Invalid relay host {0:?}")]
+    InvalidHost(String),
+    #[error("Invalid relay port {0:?} (must be 1-65535)")]
+    InvalidPort(String),
+}
+
+/// A relay address as given to `--relay`, checked before anything connects
+///
+/// Accepts a bare host, `host:port`, or a `ws://`/`wss://` URL with an
+/// optional path; trailing slashes are dropped. A bare domain, with no
+/// scheme, port or path, is looked up in DNS first (see `discovery`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RelayUrl {
+    /// `Some(true)` for wss://, `None` when no scheme was given
+    tls: Option<bool>,
+    /// IPv6 literals are kept without their brackets
+    host: String,
+    port: Option<u16>,
+    /// Empty, or starting with `/`
+    path: String,
+}
+
+impl RelayUrl {
+    /// Split a comma-separated relay list, e.g. `wss://a.example,wss://b.example`
+    pub fn parse_list(relays: &str) -> Result<Vec<Self>, RelayUrlError> {
+        let relays: Vec<_> = relays
+            .split(',')
+            .map(str::trim)
+            .filter(|relay| !relay.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        if relays.is_empty() {
+            return Err(RelayUrlError::Empty);
+        }
+        Ok(relays)
+    }
+    
+    /// The host, without brackets for IPv6
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    
+    /// The port, if one was given
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+    
+    /// The path without its trailing slash; empty for none
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    
+    /// Whether this is a bare domain that should be looked up in DNS
+    ///
+    /// Anything with a scheme, a port or a path, or that is an IP address, is used as given.
+    pub fn needs_discovery(&self) -> bool {
+        self.tls.is_none() && self.port.is_none() && self.path.is_empty() && self.host.parse::<IpAddr>().is_err()
+    }
+}
+
+impl FromStr for RelayUrl {
+    type Err = RelayUrlError;
+    
+    fn from_str(relay: &str) -> Result<Self, Self::Err> {
+        let relay = relay.trim();
+        if relay.is_empty() {
+            return Err(RelayUrlError::Empty);
+        }
+        
+        let (tls, rest) = match relay.split_once("://") {
+            Some((scheme, rest)) => match scheme.to_ascii_lowercase().as_str() {
+                "ws" => (Some(false), rest),
+                "wss" => (Some(true), rest),
+                "http" | "https" => {
+                    let suggested = if scheme.eq_ignore_ascii_case("https") { "wss" } else { "ws" };
+                    return Err(RelayUrlError::HttpScheme {
+                        scheme: format!("{}://", scheme.to_ascii_lowercase()),
+                        suggestion: format!("{}://{}", suggested, rest.trim_end_matches('/')),
+                    });
+                }
+                _ => return Err(RelayUrlError::UnsupportedScheme(scheme.to_string())),
+            },
+            None => (None, relay),
+        };
+        
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = split_host_port(authority)?;
+        if host.is_empty() {
+            return Err(RelayUrlError::MissingHost(relay.to_string()));
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_');
+        if host.parse::<Ipv6Addr>().is_err() && !host.chars().all(valid) {
+            return Err(RelayUrlError::InvalidHost(host.to_string()));
+        }
+        let port = match port {
+            Some(port) => Some(port.parse().ok().filter(|&port| port != 0).ok_or_else(|| RelayUrlError::InvalidPort(port.to_string()))?),
+            None => None,
+        };
+        
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// Split `host[:port]`, where the host may be a bracketed IPv6 literal
+fn split_host_port(authority: &str) -> Result<(&str, Option<&str>), RelayUrlError> {
+    if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed.split_once(']').ok_or_else(|| RelayUrlError::InvalidHost(authority.to_string()))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(RelayUrlError::InvalidHost(host.to_string()));
+        }
+        return match after {
+            "" => Ok((host, None)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => Ok((host, Some(port))),
+                None => Err(RelayUrlError::InvalidHost(authority.to_string())),
+            },
+        };
+    }
+    // An unbracketed IPv6 literal can't carry a port
+    if authority.parse::<Ipv6Addr>().is_ok() {
+        return Ok((authority, None));
+    }
+    Ok(match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    })
+}
+
+impl fmt::Display for RelayUrl {
+    /// The WebSocket URL to connect to; ws:// when no scheme was given
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls == Some(true) { "wss" } else { "ws" };
+        write!(f, "{}://", scheme)?;
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        f.write_str(&self.path)
+    }
+}
+
+impl From<SocketAddr> for RelayUrl {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            tls: None,
+            host: addr.ip().to_string(),
+            port: Some(addr.port()),
+            path: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn url(relay: &str) -> String {
+        relay.parse::<RelayUrl>().unwrap().to_string()
+    }
+    
+    fn err(relay: &str) -> RelayUrlError {
+        relay.parse::<RelayUrl>().unwrap_err()
+    }
+    
+    #[test]
+    fn test_accepted_forms() {
+        assert_eq!(url("relay.example.com"), "ws://relay.example.com");
+        assert_eq!(url("relay.example.com:7777"), "ws://relay.example.com:7777");
+        assert_eq!(url("  relay.example.com:7777 "), "ws://relay.example.com:7777");
+        assert_eq!(url("127.0.0.1:7777"), "ws://127.0.0.1:7777");
+        assert_eq!(url("ws://relay.example.com"), "ws://relay.example.com");
+        assert_eq!(url("wss://relay.example.com"), "wss://relay.example.com");
+        assert_eq!(url("WSS://relay.example.com:443"), "wss://relay.example.com:443");
+        assert_eq!(url("[::1]:7777"), "ws://[::1]:7777");
+        assert_eq!(url("::1"), "ws://[::1]");
+        assert_eq!(url("wss://[2001:db8::1]/zap"), "wss://[2001:db8::1]/zap");
+        assert_eq!(url("relay_01.internal:65535"), "ws://relay_01.internal:65535");
+    }
+    
+    #[test]
+    fn test_paths_kept_and_trailing_slashes_dropped() {
+        assert_eq!(url("relay.example.com/path"), "ws://relay.example.com/path");
+        assert_eq!(url("wss://relay.example.com/zap/ws"), "wss://relay.example.com/zap/ws");
+        assert_eq!(url("wss://relay.example.com/"), "wss://relay.example.com");
+        assert_eq!(url("wss://relay.example.com/zap//"), "wss://relay.example.com/zap");
+        assert_eq!(url("relay.example.com:7777/"), "ws://relay.example.com:7777");
+        
+        let relay: RelayUrl = "wss://relay.example.com:8443/zap/".parse().unwrap();
+        assert_eq!((relay.host(), relay.port(), relay.path()), ("relay.example.com", Some(8443), "/zap"));
+    }
+    
+    #[test]
+    fn test_rejected_forms() {
+        assert_eq!(err(""), RelayUrlError::Empty);
+        assert_eq!(err("   "), RelayUrlError::Empty);
+        assert_eq!(err("ftp://relay.example.com"), RelayUrlError::UnsupportedScheme("ftp".into()));
+        assert_eq!(err("ws://"), RelayUrlError::MissingHost("ws://".into()));
+        assert_eq!(err(":7777"), RelayUrlError::MissingHost(":7777".into()));
+        assert_eq!(err("wss:///zap"), RelayUrlError::MissingHost("wss:///zap".into()));
+        assert_eq!(err("relay example.com"), RelayUrlError::InvalidHost("relay example.com".into()));
+        assert_eq!(err("user@relay.example.com"), RelayUrlError::InvalidHost("user@relay.example.com".into()));
+        assert_eq!(err("[::1"), RelayUrlError::InvalidHost("[::1".into()));
+        assert_eq!(err("[not-ipv6]:7777"), RelayUrlError::InvalidHost("not-ipv6".into()));
+        assert_eq!(err("[::1]7777"), RelayUrlError::InvalidHost("[::1]7777".into()));
+        assert_eq!(err("relay.example.com:0"), RelayUrlError::InvalidPort("0".into()));
+        assert_eq!(err("relay.example.com:65536"), RelayUrlError::InvalidPort("65536".into()));
+        assert_eq!(err("relay.example.com:http"), RelayUrlError::InvalidPort("http".into()));
+        assert_eq!(err("relay.example.com:"), RelayUrlError::InvalidPort("".into()));
+        assert_eq!(err("[::1]:99999"), RelayUrlError::InvalidPort("99999".into()));
+    }
+    
+    #[test]
+    fn test_http_schemes_suggest_websocket() {
+        assert_eq!(
+            err("https://relay.example.com/zap/").to_string(),
+            "Relays speak WebSocket, not https://: try wss://relay.example.com/zap"
+        );
+        assert_eq!(
+            err("HTTP://relay.example.com:8080").to_string(),
+            "Relays speak WebSocket, not http://: try ws://relay.example.com:8080"
+        );
+    }
+    
+    #[test]
+    fn test_parse_list() {
+        let relays = RelayUrl::parse_list("wss://a.example, b.example:7777,,").unwrap();
+        assert_eq!(relays.iter().map(ToString::to_string).collect::<Vec<_>>(), ["wss://a.example", "ws://b.example:7777"]);
+        assert_eq!(RelayUrl::parse_list(" , ").unwrap_err(), RelayUrlError::Empty);
+        assert!(matches!(RelayUrl::parse_list("a.example,https://b.example"), Err(RelayUrlError::HttpScheme { .. })));
+    }
+    
+    #[test]
+    fn test_only_bare_domains_need_discovery() {
+        let needs = |relay: &str| relay.parse::<RelayUrl>().unwrap().needs_discovery();
+        assert!(needs("example.com"));
+        assert!(needs("relay.example.com/"));
+        assert!(!needs("example.com:7777"));
+        assert!(!needs("ws://example.com"));
+        assert!(!needs("wss://example.com"));
+        assert!(!needs("example.com/zap"));
+        assert!(!needs("192.0.2.1"));
+        assert!(!needs("::1"));
+    }
+    
+    #[test]
+    fn test_from_socket_addr() {
+        let v4: SocketAddr = "127.0.0.1:7777".parse().unwrap();
+        let v6: SocketAddr = "[::1]:7777".parse().unwrap();
+        assert_eq!(RelayUrl::from(v4).to_string(), "ws://127.0.0.1:7777");
+        assert_eq!(RelayUrl::from(v6).to_string(), "ws://[::1]:7777");
+        assert_eq!(RelayUrl::from(v4), "127.0.0.1:7777".parse().unwrap());
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::crypto;
+use crate::relay::{self, RelayConfig, RelayState, RelayUrl};
+use crate::rng::ZapRng;
+use crate::transport::Transport;
+use crate::{CancellationToken, ReceiveOptions, SendOptions, TransferEvent};
+
+/// Size of the generated test file unless `--size` says otherwise
+pub const DEFAULT_SELFTEST_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How long one stage may take before it counts as failed
+pub const STAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Outcome of one stage of the self-test
+#[derive(Debug)]
+pub struct StageResult {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+impl StageResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Every stage that ran, in order; prints as a PASS/FAIL table
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfTestReport {
+    /// Whether every stage passed
+    pub fn passed(&self) -> bool {
+        self.stages.iter().all(StageResult::passed)
+    }
+    
+    async fn run_stage(&mut self, name: &'static str, stage: impl Future<Output = Result<()>>) -> bool {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(STAGE_TIMEOUT, stage).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("Timed out after {}s", STAGE_TIMEOUT.as_secs())),
+        };
+        let passed = result.is_ok();
+        self.stages.push(StageResult {
+            name,
+            elapsed: start.elapsed(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        });
+        passed
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10} {:<6} {:>10}", "Stage", "Result", "Time")?;
+        for stage in &self.stages {
+            let result = if stage.passed() { "PASS" } else { "FAIL" };
+            write!(f, "{:<10} {:<6} {:>7} ms", stage.name, result, stage.elapsed.as_millis())?;
+            if let Some(error) = &stage.error {
+                write!(f, "  {}", error)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Overall: {}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+/// Send a generated file through every transport inside this process
+///
+/// The file goes over an in-memory pipe, then a localhost TCP socket, then
+/// (with `with_relay`) a relay started for the occasion, and each copy must
+/// hash the same as the original.
+pub async fn run(size: u64, with_relay: bool) -> Result<SelfTestReport> {
+    let dir = tempfile::TempDir::new()?;
+    let input = dir.path().join("selftest.bin");
+    let mut report = SelfTestReport::default();
+    
+    if !report.run_stage("generate", generate_file(&input, size)).await {
+        return Ok(report);
+    }
+    let expected = hash_file(&input)?;
+    
+    let output = dir.path().join("memory.bin");
+    report.run_stage("memory", async {
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions::new(&input, crypto::generate_code(3));
+        let receive_options = ReceiveOptions {
+            output: Some(output.clone()),
+            ..ReceiveOptions::new(&options.code)
+        };
+        tokio::try_join!(
+            crate::send_over(sender, options, None, CancellationToken::new()),
+            crate::receive_over(receiver, receive_options, None, CancellationToken::new()),
+        )?;
+        check_copy(&output, expected)
+    }).await;
+    
+    let output = dir.path().join("tcp.bin");
+    report.run_stage("tcp", async {
+        let options = SendOptions {
+            port: Some(0),
+            ..SendOptions::new(&input, crypto::generate_code(3))
+        };
+        
+        // The sender picks a free port and reports it through its events
+        let (port_tx, port_rx) = oneshot::channel();
+        let port_tx = Mutex::new(Some(port_tx));
+        let progress = move |event: &TransferEvent| {
+            if let TransferEvent::Listening { port } = event {
+                if let Some(tx) = port_tx.lock().unwrap().take() {
+                    let _ = tx.send(*port);
+                }
+            }
+        };
+        let receive_options = ReceiveOptions {
+            output: Some(output.clone()),
+            host: Some("127.0.0.1".parse().unwrap()),
+            ..ReceiveOptions::new(&options.code)
+        };
+        let receive = async {
+            let port = port_rx.await.map_err(|_| anyhow!("Sender never started listening"))?;
+            let options = ReceiveOptions {
+                port: Some(port),
+                ..receive_options
+            };
+            crate::receive(options, None, CancellationToken::new()).await
+        };
+        tokio::try_join!(crate::send(options, Some(Arc::new(progress)), CancellationToken::new()), receive)?;
+        check_copy(&output, expected)
+    }).await;
+    
+    if with_relay {
+        let output = dir.path().join("relay.bin");
+        report.run_stage("relay", async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let relay_addr = vec![RelayUrl::from(listener.local_addr()?)];
+            let relay = tokio::spawn(relay::serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+            
+            let options = SendOptions {
+                relay: Some(relay_addr.clone()),
+                ..SendOptions::new(&input, crypto::generate_code(3))
+            };
+            let receive_options = ReceiveOptions {
+                output: Some(output.clone()),
+                relay: Some(relay_addr),
+                ..ReceiveOptions::new(&options.code)
+            };
+            let result = tokio::try_join!(
+                crate::send(options, None, CancellationToken::new()),
+                crate::receive(receive_options, None, CancellationToken::new()),
+            );
+            relay.abort();
+            result?;
+            check_copy(&output, expected)
+        }).await;
+    }
+    
+    Ok(report)
+}
+
+/// Write `size` random bytes to `path`
+async fn generate_file(path: &Path, size: u64) -> Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut rng = ZapRng::new();
+        let mut block = vec![0u8; 64 * 1024];
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(block.len() as u64) as usize;
+            rng.fill_bytes(&mut block[..len]);
+            file.write_all(&block[..len])?;
+            remaining -= len as u64;
+        }
+        file.flush()?;
+        Ok(())
+    }).await?
+}
+
+fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+fn check_copy(output: &Path, expected: blake3::Hash) -> Result<()> {
+    if hash_file(output)? != expected {
+        return Err(anyhow!("Received file doesn't match what was sent"));
+    }
+    Ok(())
+}
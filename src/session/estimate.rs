@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::crypto::Cipher;
+use crate::protocol::Message;
+use crate::transport::Transport;
+
+use super::Cancelled;
+
+/// How long the sender streams padding to measure the connection
+pub const PROBE_DURATION: Duration = Duration::from_secs(4);
+
+/// Bytes of padding in each probe message
+const PADDING_SIZE: usize = 64 * 1024;
+
+/// Throughput the receiver saw during a probe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub bytes_per_sec: f64,
+}
+
+impl Estimate {
+    /// From the receiver's `ProbeResult`
+    pub fn new(bytes: u64, millis: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes as f64 * 1000.0 / millis.max(1) as f64,
+        }
+    }
+    
+    /// How long `size` bytes would take at this rate; unknown when nothing got through
+    pub fn duration_for(&self, size: u64) -> Option<Duration> {
+        (self.bytes_per_sec > 0.0).then(|| Duration::from_secs_f64(size as f64 / self.bytes_per_sec))
+    }
+    
+    /// The measured rate and, when the size is known, how long the transfer would take
+    pub fn describe(&self, size: Option<u64>) -> String {
+        let rate = format!("Measured {:.1} Mbps", self.bytes_per_sec * 8.0 / 1_000_000.0);
+        match size.map(|size| (size, self.duration_for(size))) {
+            Some((size, Some(duration))) => {
+                let duration = humantime::format_duration(Duration::from_secs(duration.as_secs().max(1)));
+                format!("{}: {} bytes would take about {}", rate, size, duration)
+            }
+            Some((_, None)) => format!("{}: nothing got through, so the transfer may never finish", rate),
+            None => rate,
+        }
+    }
+}
+
+/// Bytes a send of `path` will move: the file, or every file under a directory
+pub fn payload_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Sender's half of a probe: stream padding for `duration`, then wait for the receiver's measurement
+///
+/// The padding is encrypted like file data so it costs what the transfer will,
+/// and through a relay it exercises the relay too. None of it counts towards
+/// the file's progress.
+pub async fn measure(conn: &mut Transport, cipher: &Cipher, duration: Duration, cancel: &CancellationToken) -> Result<Estimate> {
+    let padding = cipher.encrypt(&Message::Probe { data: vec![0; PADDING_SIZE] }.to_bytes()?)?;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        conn.send(&padding).await?;
+    }
+    conn.send(&cipher.encrypt(&Message::ProbeEnd.to_bytes()?)?).await?;
+    
+    match Message::from_bytes(&cipher.decrypt(&conn.receive().await?)?)? {
+        Message::ProbeResult { bytes, millis } => Ok(Estimate::new(bytes, millis)),
+        _ => Err(anyhow!("Expected ProbeResult message")),
+    }
+}
+
+/// Receiver's half of a probe, once the first piece of padding has arrived
+///
+/// Time starts with that first piece, so only the pieces after it count.
+pub async fn answer(conn: &mut Transport, cipher: &Cipher) -> Result<()> {
+    let start = Instant::now();
+    let mut bytes = 0;
+    loop {
+        let data = conn.receive().await?;
+        match Message::from_bytes(&cipher.decrypt(&data)?)? {
+            Message::Probe { .. } => bytes += data.len() as u64,
+            Message::ProbeEnd => break,
+            _ => return Err(anyhow!("Expected probe padding")),
+        }
+    }
+    let result = Message::ProbeResult {
+        bytes,
+        millis: start.elapsed().as_millis() as u64,
+    };
+    conn.send(&cipher.encrypt(&result.to_bytes()?)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_projection() {
+        // 1 MB in four seconds is 2 Mbps
+        let estimate = Estimate::new(1_000_000, 4000);
+        assert_eq!(estimate.bytes_per_sec, 250_000.0);
+        assert_eq!(estimate.duration_for(40_000_000_000), Some(Duration::from_secs(160_000)));
+        assert_eq!(estimate.describe(Some(40_000_000_000)), "Measured 2.0 Mbps: 40000000000 bytes would take about 1day 20h 26m 40s");
+        assert_eq!(estimate.describe(None), "Measured 2.0 Mbps");
+        
+        let stalled = Estimate::new(0, 4000);
+        assert_eq!(stalled.duration_for(1), None);
+        assert!(stalled.describe(Some(1)).contains("nothing got through"));
+    }
+    
+    /// A link carrying the probe at `bytes_per_sec`, then the receiver's answer back
+    async fn throttle(mut sender: Transport, mut receiver: Transport, bytes_per_sec: f64) -> Result<()> {
+        loop {
+            let data = sender.receive().await?;
+            tokio::time::sleep(Duration::from_secs_f64(data.len() as f64 / bytes_per_sec)).await;
+            receiver.send(&data).await?;
+            // Only ProbeEnd is smaller than the padding
+            if data.len() < PADDING_SIZE {
+                return sender.send(&receiver.receive().await?).await;
+            }
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_over_rate_limited_link() {
+        let cipher = Cipher::from_password("alpha-bravo-charlie").unwrap();
+        let (mut sender, link_in) = Transport::memory_pair();
+        let (link_out, mut receiver) = Transport::memory_pair();
+        let link = tokio::spawn(throttle(link_in, link_out, 500_000.0));
+        
+        let receive = async {
+            let first = receiver.receive().await?;
+            assert!(matches!(Message::from_bytes(&cipher.decrypt(&first)?)?, Message::Probe { .. }));
+            answer(&mut receiver, &cipher).await
+        };
+        let cancel = CancellationToken::new();
+        let (estimate, _) = tokio::try_join!(measure(&mut sender, &cipher, PROBE_DURATION, &cancel), receive).unwrap();
+        link.await.unwrap().unwrap();
+        
+        // Encryption overhead rides along with the padding, so allow a little either way
+        assert!((estimate.bytes_per_sec - 500_000.0).abs() < 25_000.0, "{:?}", estimate);
+        let projected = estimate.duration_for(50_000_000).unwrap().as_secs_f64();
+        assert!((95.0..105.0).contains(&projected), "{}", projected);
+    }
+}
@@ -0,0 +1,498 @@
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{self, Session};
+use crate::relay::RelayError;
+use crate::session::hint::WaitingHint;
+use crate::session::timing::PhaseTimings;
+use crate::session::{Cancelled, OutOfSpace, PeerTooOld};
+use crate::transfer::{MetadataWarning, Receipt, Resolution, SkippedFile, TransferError};
+use crate::transport::PeerInfo;
+use tokio::task::JoinHandle;
+
+/// Events reported while a transfer is running
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferEvent {
+    /// Waiting for the receiver on this port (direct transfers only)
+    Listening { port: u16 },
+    
+    /// The port asked for is still held, likely by the instance before this one; waiting for it to clear
+    PortBusy { port: u16 },
+    
+    /// A connection from an address outside `--allow` was hung up on; still listening
+    Rejected { addr: SocketAddr },
+    
+    /// The other side still hasn't turned up after the options' `hint_delay`; here's what to try
+    Hint { hint: WaitingHint },
+    
+    /// The relay room was registered again at `at` while waiting for the peer,
+    /// after the relay expired it or the connection to the relay was lost
+    Reannounced { at: SystemTime },
+    
+    /// Connected to the peer
+    Connected { peer: PeerInfo },
+    
+    /// Protocol handshake finished with these agreed features
+    Handshake { session: Session },
+    
+    /// Over `--direct-tls`, the peer's certificate is the one it vouched for under the code; both fingerprints are SHA-256
+    TlsVerified { ours: [u8; 32], peer: [u8; 32] },
+    
+    /// A saved state from an earlier attempt at this file was found; it stopped before `chunk`
+    Resuming { chunk: u64 },
+    
+    /// File metadata is known (local file for the sender, decrypted offer for the receiver)
+    Metadata { filename: String, size: u64 },
+    
+    /// The sender is holding off until its scheduled start; `starts_in` is
+    /// unknown while waiting for the network to go quiet
+    Scheduled { starts_in: Option<Duration> },
+    
+    /// The sender is still reading through what it's about to offer, e.g. `hashing file: 40%`
+    Preparing { detail: String },
+    
+    /// The most memory the receiver's incoming messages took at once, against its `--memory-limit`, in bytes
+    Memory { limit: usize, peak: usize },
+    
+    /// Files added to a directory's archive so far
+    Archiving { files_done: u64, total_files: u64 },
+    
+    /// Files checksummed so far while a folder sync compares the two sides
+    Hashing { files_done: u64, total_files: u64 },
+    
+    /// Bytes transferred so far for the current file
+    Progress {
+        filename: String,
+        transferred: u64,
+        total: u64,
+        speed: f64, // bytes per second
+    },
+    
+    /// The sender's adaptive controller changed the chunk size
+    ChunkSize { chunk_size: usize },
+    
+    /// The receiver takes chunks of at most `chunk_size`, less than the sender
+    /// would grow to; `assumed` when it's too old to say and the old limit is kept to
+    PeerLimited { chunk_size: usize, assumed: bool },
+    
+    /// The sender's first chunk never arrived, and probing found that chunks of
+    /// `dropped` bytes are lost while ones of `chunk_size` get through; the
+    /// rest go in chunks no bigger than that
+    PathLimited { chunk_size: usize, dropped: usize },
+    
+    /// The relay stored the upload in its mailbox and will keep it for `ttl`
+    Stored { ttl: Duration },
+    
+    /// A receiver looked at the offer and left without taking it; waiting for another
+    Deferred,
+    
+    /// A file zap keeps for itself, like resume state, was readable by other
+    /// users and has been made private
+    Tightened { path: PathBuf },
+    
+    /// Received files clashed with existing ones and were resolved like this
+    Conflicts { resolved: Vec<(PathBuf, Resolution)> },
+    
+    /// Received files arrived but their modification times or permissions couldn't be set
+    MetadataWarnings { warnings: Vec<MetadataWarning> },
+    
+    /// Files of a received folder that were left out: ones that couldn't be written,
+    /// with `--keep-going`, and in a sync, ones whose names clash on this filesystem
+    Skipped { files: Vec<SkippedFile> },
+    
+    /// The receiver called the transfer off after `transferred` of `total` bytes
+    /// (`total` is 0 when unknown); with `resumable`, `--resume` picks it up again
+    ReceiverCancelled { transferred: u64, total: u64, resumable: bool },
+    
+    /// The receiver checked the file and sent this receipt; on the sender's
+    /// side it was saved to `saved_to`
+    Receipt { receipt: Receipt, saved_to: Option<PathBuf> },
+    
+    /// The file went as differences from the receiver's copy, which supplied `reused` of its `total` bytes
+    Delta { reused: u64, total: u64 },
+    
+    /// A folder sync sent the `sent` files that differed, left `unchanged` alone, couldn't
+    /// write `skipped` and deleted `deleted` of the `remote_only` files only the receiver had
+    ///
+    /// Only the receiver knows what it skipped; the sender's count is always 0.
+    Synced { sent: usize, unchanged: usize, skipped: usize, remote_only: usize, deleted: usize },
+    
+    /// How long each phase of the transfer took, reported just before `Complete`
+    Timings { timings: PhaseTimings },
+    
+    /// Transfer finished successfully
+    Complete,
+}
+
+impl TransferEvent {
+    /// The event as one JSON object for `--json`, with its kind in `event`, e.g. `{"event": "hint", ...}`
+    ///
+    /// Durations are in seconds and times in seconds since the Unix epoch.
+    pub fn to_json(&self) -> Value {
+        match self {
+            TransferEvent::Listening { port } => json!({ "event": "listening", "port": port }),
+            TransferEvent::PortBusy { port } => json!({ "event": "port_busy", "port": port }),
+            TransferEvent::Rejected { addr } => json!({ "event": "rejected", "addr": addr.to_string() }),
+            TransferEvent::Hint { hint } => json!({ "event": "hint", "summary": hint.summary, "steps": hint.steps }),
+            TransferEvent::Reannounced { at } => json!({ "event": "reannounced", "at": unix_secs(*at) }),
+            TransferEvent::Connected { peer } => json!({ "event": "connected", "peer": peer.to_string(), "addr": peer.addr().map(|addr| addr.to_string()) }),
+            TransferEvent::TlsVerified { ours, peer } => json!({ "event": "tls_verified", "ours": hex::encode(ours), "peer": hex::encode(peer) }),
+            TransferEvent::Handshake { session } => {
+                let mut features: Vec<_> = session.features().iter().collect();
+                features.sort();
+                json!({ "event": "handshake", "key_exchange": protocol::key_exchange_strength(session), "features": features })
+            }
+            TransferEvent::Resuming { chunk } => json!({ "event": "resuming", "chunk": chunk }),
+            TransferEvent::Metadata { filename, size } => json!({ "event": "metadata", "filename": filename, "size": size }),
+            TransferEvent::Scheduled { starts_in } => json!({ "event": "scheduled", "starts_in": starts_in.map(|wait| wait.as_secs_f64()) }),
+            TransferEvent::Preparing { detail } => json!({ "event": "preparing", "detail": detail }),
+            TransferEvent::Memory { limit, peak } => json!({ "event": "memory", "limit": limit, "peak": peak }),
+            TransferEvent::Archiving { files_done, total_files } => json!({ "event": "archiving", "files_done": files_done, "total_files": total_files }),
+            TransferEvent::Hashing { files_done, total_files } => json!({ "event": "hashing", "files_done": files_done, "total_files": total_files }),
+            TransferEvent::Progress { filename, transferred, total, speed } => {
+                json!({ "event": "progress", "filename": filename, "transferred": transferred, "total": total, "speed": speed })
+            }
+            TransferEvent::ChunkSize { chunk_size } => json!({ "event": "chunk_size", "chunk_size": chunk_size }),
+            TransferEvent::PeerLimited { chunk_size, assumed } => json!({ "event": "peer_limited", "chunk_size": chunk_size, "assumed": assumed }),
+            TransferEvent::PathLimited { chunk_size, dropped } => json!({ "event": "path_limited", "chunk_size": chunk_size, "dropped": dropped }),
+            TransferEvent::Stored { ttl } => json!({ "event": "stored", "ttl": ttl.as_secs_f64() }),
+            TransferEvent::Deferred => json!({ "event": "deferred" }),
+            TransferEvent::Tightened { path } => json!({ "event": "tightened", "path": path }),
+            TransferEvent::Conflicts { resolved } => {
+                let resolved: Vec<_> = resolved.iter().map(|(path, resolution)| json!({ "path": path, "resolution": resolution.to_string() })).collect();
+                json!({ "event": "conflicts", "resolved": resolved })
+            }
+            TransferEvent::MetadataWarnings { warnings } => {
+                let warnings: Vec<_> = warnings.iter().map(|warning| json!({ "path": warning.path, "reason": warning.reason })).collect();
+                json!({ "event": "metadata_warnings", "warnings": warnings })
+            }
+            TransferEvent::Skipped { files } => {
+                let files: Vec<_> = files.iter().map(|file| json!({ "path": file.path, "reason": file.reason })).collect();
+                json!({ "event": "skipped", "files": files })
+            }
+            TransferEvent::ReceiverCancelled { transferred, total, resumable } => {
+                json!({ "event": "receiver_cancelled", "transferred": transferred, "total": total, "resumable": resumable })
+            }
+            TransferEvent::Receipt { receipt, saved_to } => json!({ "event": "receipt", "receipt": receipt, "saved_to": saved_to }),
+            TransferEvent::Delta { reused, total } => json!({ "event": "delta", "reused": reused, "total": total }),
+            TransferEvent::Synced { sent, unchanged, skipped, remote_only, deleted } => json!({
+                "event": "synced",
+                "sent": sent,
+                "unchanged": unchanged,
+                "skipped": skipped,
+                "remote_only": remote_only,
+                "deleted": deleted,
+            }),
+            TransferEvent::Timings { timings } => json!({ "event": "timings", "timings": timings }),
+            TransferEvent::Complete => json!({ "event": "complete" }),
+        }
+    }
+}
+
+/// What a transfer failed with as the last line of `--json`, `{"event": "error", "kind": ..., "message": ..., "details": {...}}`
+///
+/// `kind` names the error, e.g. `checksum_mismatch` or `peer_too_old`, and is
+/// plain `error` for anything else. `details` always has `expected` and
+/// `actual` (the checksums, or key exchange versions, that didn't match, when
+/// there were any), the `peer` it was talking to, if it got that far, and
+/// whether the same command is worth running again as it is (`retryable`).
+pub fn error_to_json(error: &anyhow::Error, peer: Option<&str>) -> Value {
+    let (kind, expected, actual, retryable) = if let Some(failed) = error.downcast_ref::<TransferError>() {
+        match failed {
+            TransferError::DataExceedsExpectedSize { .. } => ("data_exceeds_expected_size", None, None, false),
+            TransferError::ChecksumMismatch { .. } => ("checksum_mismatch", None, None, true),
+            TransferError::FileChecksumMismatch { expected, actual, .. } => ("file_checksum_mismatch", Some(expected.clone()), Some(actual.clone()), true),
+            TransferError::DeltaMismatch => ("delta_mismatch", None, None, true),
+            TransferError::FilesDamaged { .. } => ("files_damaged", None, None, true),
+        }
+    } else if let Some(RelayError::Unresponsive { .. }) = error.downcast_ref::<RelayError>() {
+        ("relay_unresponsive", None, None, true)
+    } else if let Some(too_old) = error.downcast_ref::<PeerTooOld>() {
+        ("peer_too_old", Some(format!("v{}", too_old.required)), Some(format!("v{}", too_old.peer)), false)
+    } else if let Some(full) = error.downcast_ref::<OutOfSpace>() {
+        // Only once there's room, but then --resume carries on
+        ("out_of_space", None, None, full.resumable)
+    } else if error.is::<Cancelled>() {
+        ("cancelled", None, None, true)
+    } else {
+        ("error", None, None, false)
+    };
+    json!({
+        "event": "error",
+        "kind": kind,
+        "message": format!("{:#}", error),
+        "details": { "expected": expected, "actual": actual, "peer": peer, "retryable": retryable },
+    })
+}
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+/// Callback interface for consumers that don't want an async event stream
+///
+/// Callbacks run on a dedicated thread, so a slow callback never stalls the
+/// network pipeline; consecutive progress events are coalesced while it is busy.
+pub trait ProgressCallback: Send + Sync {
+    fn on_event(&self, event: &TransferEvent);
+}
+
+impl<F> ProgressCallback for F
+where
+    F: Fn(&TransferEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &TransferEvent) {
+        self(event)
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<TransferEvent>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// Delivers events to a `ProgressCallback` without blocking the caller
+pub struct EventDispatcher {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl EventDispatcher {
+    /// Start a dispatcher; with no callback, events are simply dropped
+    pub fn new(callback: Option<Arc<dyn ProgressCallback>>) -> Self {
+        let shared = Arc::new(Shared::default());
+        
+        let worker = callback.map(|callback| {
+            let shared = shared.clone();
+            tokio::task::spawn_blocking(move || deliver(&shared, callback.as_ref()))
+        });
+        
+        Self { shared, worker }
+    }
+    
+    /// Queue an event, dropping a pending progress, archiving or hashing event of the same kind that hasn't been delivered yet
+    ///
+    /// The newer one goes to the back, so events still arrive in the order
+    /// they were emitted, and however slow the callback, at most one of each
+    /// of those kinds is ever waiting. Other events are all kept: each stands
+    /// for something that happened once per file, connection or phase, and
+    /// the callback can't make up for one that went missing (a skipped file,
+    /// `Complete`), so the queue only ever holds as many as the transfer's
+    /// own work produced.
+    pub fn emit(&self, event: TransferEvent) {
+        if self.worker.is_none() {
+            return;
+        }
+        
+        let mut queue = self.shared.queue.lock().unwrap();
+        let coalescable = matches!(
+            event,
+            TransferEvent::Progress { .. } | TransferEvent::Archiving { .. } | TransferEvent::Hashing { .. }
+        );
+        let kind = std::mem::discriminant(&event);
+        if coalescable {
+            if let Some(stale) = queue.events.iter().position(|pending| std::mem::discriminant(pending) == kind) {
+                queue.events.remove(stale);
+            }
+        }
+        queue.events.push_back(event);
+        self.shared.ready.notify_one();
+    }
+    
+    /// Wait until every queued event has been delivered
+    pub async fn finish(mut self) {
+        self.close();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.await;
+        }
+    }
+    
+    fn close(&self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+impl Drop for EventDispatcher {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn deliver(shared: &Shared, callback: &dyn ProgressCallback) {
+    loop {
+        let event = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(event) = queue.events.pop_front() {
+                    break event;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+        callback.on_event(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    fn progress(transferred: u64) -> TransferEvent {
+        TransferEvent::Progress {
+            filename: "file.bin".to_string(),
+            transferred,
+            total: 1000,
+            speed: 0.0,
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_slow_callback_coalesces_progress() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| {
+            std::thread::sleep(Duration::from_millis(20));
+            recorded.lock().unwrap().push(event.clone());
+        };
+        
+        let dispatcher = EventDispatcher::new(Some(Arc::new(callback)));
+        dispatcher.emit(TransferEvent::Handshake { session: Session::default() });
+        for i in 1..=1000 {
+            dispatcher.emit(progress(i));
+        }
+        dispatcher.emit(TransferEvent::Complete);
+        dispatcher.finish().await;
+        
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() < 10, "progress events should be coalesced, got {}", seen.len());
+        assert_eq!(seen.first(), Some(&TransferEvent::Handshake { session: Session::default() }));
+        assert_eq!(seen.last(), Some(&TransferEvent::Complete));
+        assert_eq!(seen[seen.len() - 2], progress(1000));
+    }
+    
+    #[tokio::test]
+    async fn test_interleaved_events_keep_one_progress_pending() {
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| {
+            // Held up on the first event until everything else is queued
+            if recorded.lock().unwrap().is_empty() {
+                blocked.lock().unwrap().recv().unwrap();
+            }
+            recorded.lock().unwrap().push(event.clone());
+        };
+        
+        let dispatcher = EventDispatcher::new(Some(Arc::new(callback)));
+        dispatcher.emit(TransferEvent::Handshake { session: Session::default() });
+        for i in 1..=1000 {
+            dispatcher.emit(progress(i));
+            dispatcher.emit(TransferEvent::Resuming { chunk: i });
+        }
+        assert!(dispatcher.shared.queue.lock().unwrap().events.len() <= 1002);
+        release.send(()).unwrap();
+        dispatcher.finish().await;
+        
+        let seen = seen.lock().unwrap();
+        let progress_events: Vec<_> = seen.iter().filter(|event| matches!(event, TransferEvent::Progress { .. })).collect();
+        assert!(progress_events.len() <= 2, "got {} progress events", progress_events.len());
+        assert_eq!(progress_events.last(), Some(&&progress(1000)));
+        let resumed = seen.iter().filter(|event| matches!(event, TransferEvent::Resuming { .. })).count();
+        assert_eq!(resumed, 1000);
+    }
+    
+    #[tokio::test]
+    async fn test_coalescing_keeps_emit_order() {
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| {
+            if recorded.lock().unwrap().is_empty() {
+                blocked.lock().unwrap().recv().unwrap();
+            }
+            recorded.lock().unwrap().push(event.clone());
+        };
+        
+        let dispatcher = EventDispatcher::new(Some(Arc::new(callback)));
+        dispatcher.emit(TransferEvent::Handshake { session: Session::default() });
+        // Wait for the worker to be held up on the handshake, so everything after it queues
+        while !dispatcher.shared.queue.lock().unwrap().events.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        dispatcher.emit(progress(1));
+        dispatcher.emit(TransferEvent::ChunkSize { chunk_size: 65536 });
+        dispatcher.emit(TransferEvent::Hashing { files_done: 1, total_files: 3 });
+        dispatcher.emit(TransferEvent::Resuming { chunk: 4 });
+        dispatcher.emit(progress(2));
+        dispatcher.emit(TransferEvent::Hashing { files_done: 3, total_files: 3 });
+        dispatcher.emit(TransferEvent::Complete);
+        release.send(()).unwrap();
+        dispatcher.finish().await;
+        
+        assert_eq!(*seen.lock().unwrap(), [
+            TransferEvent::Handshake { session: Session::default() },
+            TransferEvent::ChunkSize { chunk_size: 65536 },
+            TransferEvent::Resuming { chunk: 4 },
+            progress(2),
+            TransferEvent::Hashing { files_done: 3, total_files: 3 },
+            TransferEvent::Complete,
+        ]);
+    }
+    
+    #[test]
+    fn test_events_as_json() {
+        let hint = WaitingHint::sender("alpha-bravo-charlie", Some(40123), Some("192.168.1.20".parse().unwrap()), None);
+        let json = TransferEvent::Hint { hint: hint.clone() }.to_json();
+        assert_eq!(json["event"], "hint");
+        assert_eq!(json["summary"], hint.summary.as_str());
+        assert_eq!(json["steps"].as_array().unwrap().len(), hint.steps.len());
+        
+        assert_eq!(progress(250).to_json(), json!({ "event": "progress", "filename": "file.bin", "transferred": 250, "total": 1000, "speed": 0.0 }));
+        assert_eq!(TransferEvent::Stored { ttl: Duration::from_secs(90) }.to_json()["ttl"], 90.0);
+        assert_eq!(TransferEvent::Complete.to_json(), json!({ "event": "complete" }));
+    }
+    
+    #[test]
+    fn test_errors_as_json() {
+        let damaged = anyhow::Error::from(TransferError::FileChecksumMismatch {
+            name: "file.bin".to_string(),
+            expected: "blake3:aa".to_string(),
+            actual: "blake3:bb".to_string(),
+        });
+        let json = error_to_json(&damaged, Some("192.168.1.20:9999"));
+        assert_eq!(json["event"], "error");
+        assert_eq!(json["kind"], "file_checksum_mismatch");
+        assert_eq!(json["message"], "file.bin arrived damaged: its checksum doesn't match the sender's");
+        assert_eq!(json["details"], json!({ "expected": "blake3:aa", "actual": "blake3:bb", "peer": "192.168.1.20:9999", "retryable": true }));
+        
+        // Found under context, which the message keeps
+        let too_old = anyhow::Error::from(PeerTooOld { peer: 2, required: 3 }).context("Handshake failed");
+        let json = error_to_json(&too_old, None);
+        assert_eq!(json["kind"], "peer_too_old");
+        assert!(json["message"].as_str().unwrap().starts_with("Handshake failed: Peer too old (v2)"), "{}", json);
+        assert_eq!(json["details"]["expected"], "v3");
+        assert_eq!(json["details"]["retryable"], false);
+        
+        let json = error_to_json(&anyhow::anyhow!("Wrong transfer code"), None);
+        assert_eq!(json["kind"], "error");
+        assert_eq!(json["details"], json!({ "expected": null, "actual": null, "peer": null, "retryable": false }));
+    }
+}
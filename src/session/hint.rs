@@ -0,0 +1,133 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::network::Endpoint;
+use crate::relay::RelayUrl;
+
+/// How long either side waits for the other, by default, before it's shown a `WaitingHint`
+pub const HINT_DELAY: Duration = Duration::from_secs(30);
+
+/// What to do when the other side hasn't turned up, put together from how this side is set up
+///
+/// Written for whoever only started one side, or gave the receiver an
+/// address it can't reach: the sender's has the command to pass on, with
+/// the port it actually got and this computer's address; the receiver's is
+/// a list of things to check against the host or relay it's using.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitingHint {
+    /// One line, for the status bar
+    pub summary: String,
+    /// What else to try or check, one per line
+    pub steps: Vec<String>,
+}
+
+impl WaitingHint {
+    /// For a sender listening on `port` at `address`, or registered on `relays`
+    pub fn sender(code: &str, port: Option<u16>, address: Option<IpAddr>, relays: Option<&[RelayUrl]>) -> Self {
+        if let Some(relays) = relays {
+            return Self {
+                summary: format!("No receiver yet. On the other computer, run: zap receive {} --relay {}", code, joined(relays)),
+                steps: vec![
+                    "Both sides have to use the same relay, and the code exactly as shown".to_string(),
+                ],
+            };
+        }
+        let port = port.unwrap_or(crate::network::DEFAULT_PORT);
+        let host = match address {
+            Some(address) => SocketAddr::new(address, port).to_string(),
+            None => format!("<this computer's address>:{}", port),
+        };
+        Self {
+            summary: format!("No receiver yet. On the other computer, run: zap receive {} --host {}", code, host),
+            steps: vec![
+                format!("It has to be on the same network, and able to reach port {} here through any firewall", port),
+                "From another network, send with --relay <server> instead, and receive with the same --relay".to_string(),
+            ],
+        }
+    }
+    
+    /// For a receiver connecting to `host` (on `port` when it names none), or registered on `relays`
+    pub fn receiver(code: &str, host: Option<&Endpoint>, port: Option<u16>, relays: Option<&[RelayUrl]>) -> Self {
+        let running = format!("Is zap send running on the other computer, showing the code {}?", code);
+        if let Some(relays) = relays {
+            let relays = joined(relays);
+            return Self {
+                summary: format!("The sender hasn't joined on {} yet: is it running, with the same relay and code?", relays),
+                steps: vec![
+                    running,
+                    format!("Is it sending through the same relay? It needs --relay {} too", relays),
+                ],
+            };
+        }
+        let host = match host {
+            Some(host) => {
+                let (name, port) = host.target(port.unwrap_or(crate::network::DEFAULT_PORT));
+                match name.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, port).to_string(),
+                    Err(_) => format!("{}:{}", name, port),
+                }
+            }
+            None => "the sender".to_string(),
+        };
+        Self {
+            summary: format!("No answer from {} yet: is the sender running, on the same network? Or try --relay", host),
+            steps: vec![
+                running,
+                format!("Is {} the address it shows for this network, and reachable from here?", host),
+                format!("On different networks, use a relay on both sides: zap send --relay <server>, then zap receive {} --relay <server>", code),
+            ],
+        }
+    }
+}
+
+fn joined(relays: &[RelayUrl]) -> String {
+    relays.iter().map(|relay| relay.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Wait for `waiting`, calling `on_hint` once if it's still going after `delay`
+pub async fn hint_after<T>(delay: Duration, waiting: impl Future<Output = T>, on_hint: impl FnOnce()) -> T {
+    tokio::pin!(waiting);
+    tokio::select! {
+        done = &mut waiting => return done,
+        _ = tokio::time::sleep(delay) => on_hint(),
+    }
+    waiting.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_hints_name_what_each_side_uses() {
+        let sender = WaitingHint::sender("alpha-bravo-charlie", Some(40123), Some("192.168.1.20".parse().unwrap()), None);
+        assert_eq!(sender.summary, "No receiver yet. On the other computer, run: zap receive alpha-bravo-charlie --host 192.168.1.20:40123");
+        let unknown = WaitingHint::sender("alpha-bravo-charlie", None, None, None);
+        assert!(unknown.summary.ends_with("--host <this computer's address>:9999"), "{}", unknown.summary);
+        
+        let relays = vec!["relay.example.com:7777".parse::<RelayUrl>().unwrap()];
+        let relayed = WaitingHint::sender("alpha-bravo-charlie", None, None, Some(&relays));
+        assert!(relayed.summary.ends_with("zap receive alpha-bravo-charlie --relay ws://relay.example.com:7777"), "{}", relayed.summary);
+        let relayed = WaitingHint::receiver("alpha-bravo-charlie", None, None, Some(&relays));
+        assert!(relayed.steps[1].contains("--relay ws://relay.example.com:7777"), "{:?}", relayed.steps);
+        
+        let host = "fe80::1".parse::<Endpoint>().unwrap();
+        let direct = WaitingHint::receiver("alpha-bravo-charlie", Some(&host), Some(4000), None);
+        assert!(direct.summary.starts_with("No answer from [fe80::1]:4000 yet"), "{}", direct.summary);
+        assert!(direct.steps[0].contains("alpha-bravo-charlie"));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_hint_only_while_still_waiting() {
+        let mut hinted = 0;
+        let quick = hint_after(HINT_DELAY, tokio::time::sleep(Duration::from_secs(5)), || hinted += 1).await;
+        assert_eq!((quick, hinted), ((), 0));
+        
+        let slow = hint_after(HINT_DELAY, async {
+            tokio::time::sleep(Duration::from_secs(90)).await;
+            7
+        }, || hinted += 1).await;
+        assert_eq!((slow, hinted), (7, 1));
+    }
+}
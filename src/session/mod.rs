@@ -0,0 +1,4532 @@
+pub mod estimate;
+pub mod events;
+pub mod hint;
+pub mod schedule;
+pub mod timing;
+pub mod traffic;
+
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::crypto::{self, ChecksumAlgorithm, Cipher, CryptoError, CryptoPool, IdentityKey, KemShare, KeyExchange, Offers, RECEIVER_CONFIRM, SENDER_CONFIRM};
+use crate::fsutil;
+use crate::network::{self, AllowList, Endpoint, ProtocolLog, SocketTimeouts, TlsIdentity, TlsRole};
+use crate::protocol::fragment::{self, Reassembler, FRAGMENT_SIZE, MAX_REASSEMBLED_SIZE, REASSEMBLY_TIMEOUT};
+use crate::protocol::{
+    self, BlockSignature, ManifestEntry, DeltaOp, Message, SendTicket, Session, TransferState, FEATURE_CHECKPOINT,
+    FEATURE_CHECKSUM_BLAKE3, FEATURE_CHUNK_ACK, FEATURE_COMPLETE_ACK, FEATURE_DELTA, FEATURE_FRAGMENT, FEATURE_KEM_ML_KEM_768, FEATURE_MAILBOX, FEATURE_MAX_CHUNK_PREFIX, FEATURE_MAX_FRAME_PREFIX, FEATURE_PAKE_V2, FEATURE_PEEK, FEATURE_PROBE, FEATURE_RECEIPT, FEATURE_RESUME, FEATURE_SCHEDULE,
+    FEATURE_DEFER, FEATURE_FILE_CHECKSUMS, FEATURE_PREPARING, FEATURE_STREAM, FEATURE_SYNC, FEATURE_ZIP, CHUNK_OVERHEAD, LEGACY_MAX_CHUNK_SIZE, LEGACY_MAX_FRAME_SIZE,
+};
+use crate::relay::{RelayUrl, Role, MAX_RELAY_FRAME_SIZE};
+use crate::transfer::adaptive::{ChunkSizeController, PathProbe, ProbeStep, MAX_CHUNK_SIZE};
+use crate::transfer::conflict::{self, ConflictResolver};
+use crate::transfer::delta;
+use crate::transfer::filetype;
+use crate::transfer::metadata::{MetadataApplier, SystemFs};
+use crate::transfer::staging::StagingDir;
+use crate::transfer::sync::{self, SyncPlan};
+use crate::transfer::archive::{self, ArchiveFormat};
+use crate::transfer::{
+    self, AcceptTypes, ArchivePiece, ArchiveStream, Checkpointer, ChecksumChoice, ConfirmPrompt, ConflictPrompt, ConflictStrategy, DeltaDecoder, DeltaEncoder,
+    FileChunker, FileMetadata, FileWriter, HashStage, MemoryBudget, PipeSink, ReadAheadChunker, Receipt, StdinChunker, TeeChunker, CHECKPOINT_INTERVAL, CHUNK_SIZE,
+    DEFAULT_MEMORY_LIMIT, DEFAULT_READAHEAD, DELTA_BLOCK_SIZE, NO_CHECKSUM,
+};
+use crate::transport::{PeerInfo, Transport};
+
+/// Sent to the peer when its key confirmation doesn't match ours
+const WRONG_CODE: &str = "Wrong transfer code";
+
+/// Name offered to the receiver for data read from stdin
+const STDIN_NAME: &str = "stdin";
+
+/// Chunks written between saves of the receiver's resume state
+const STATE_SAVE_INTERVAL: usize = 100;
+
+/// Least time between the sender's `Preparing` updates
+const PREPARING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a receiver that cancelled keeps reading for the sender to hang up
+const CANCEL_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the sender waits for its first chunk to arrive before trying smaller ones
+pub const CHUNK_ACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Tells apart the fragmented messages this process sends
+static NEXT_FRAGMENT_ID: AtomicU64 = AtomicU64::new(0);
+
+pub use events::{EventDispatcher, ProgressCallback, TransferEvent};
+
+/// The transfer was called off on this side, through the `CancellationToken`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Transfer cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The disk, or the user's quota on it, filled up while receiving
+///
+/// Added as context to the error that said so. With `resumable`, the
+/// `written` bytes were kept and `--resume` picks up from there once
+/// there's room; the sender was told either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfSpace {
+    pub written: u64,
+    pub resumable: bool,
+}
+
+impl OutOfSpace {
+    /// What the sender is told
+    fn for_sender(&self) -> String {
+        let mut message = format!("The receiver ran out of disk space after {} bytes", self.written);
+        if self.resumable {
+            message.push_str("; it can pick up from there with --resume once there's room");
+        }
+        message
+    }
+}
+
+impl fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ran out of disk space after receiving {} bytes", self.written)?;
+        if self.resumable {
+            f.write_str("; free some up and run the same command with --resume to pick up from there")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for OutOfSpace {}
+
+/// The receiver only looked at the offer, so the sender waits for another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Deferred;
+
+impl fmt::Display for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The receiver only looked at the offer")
+    }
+}
+
+impl std::error::Error for Deferred {}
+
+/// The peer hung up before saying hello, as another zap checking whether
+/// the port is taken does; a listening sender waits for the next one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HungUp;
+
+impl fmt::Display for HungUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The peer hung up before the handshake")
+    }
+}
+
+impl std::error::Error for HungUp {}
+
+/// The peer's best key exchange is below the floor `--min-protocol` set
+///
+/// Found right after the handshake, so nothing about the transfer was sent;
+/// the peer was told why in plaintext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTooOld {
+    /// Key exchange version the peer could agree on (`protocol::key_exchange_strength`)
+    pub peer: u8,
+    /// The floor
+    pub required: u8,
+}
+
+impl fmt::Display for PeerTooOld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer too old (v{}), required ≥ v{}", self.peer, self.required)?;
+        if self.peer == protocol::KEY_EXCHANGE_V2 && self.required == protocol::KEY_EXCHANGE_V3 {
+            f.write_str("; the other side has to send or receive with --pq")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PeerTooOld {}
+use estimate::PROBE_DURATION;
+use hint::{hint_after, WaitingHint, HINT_DELAY};
+use schedule::{InterfaceCounters, Schedule, StartCondition, Tick};
+use timing::{Phase, PhaseTimer};
+use traffic::Traffic;
+
+/// Options for sending a file
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    /// File to send
+    pub path: PathBuf,
+    /// Transfer code shared with the receiver
+    pub code: String,
+    /// Port to listen on for direct connections
+    pub port: Option<u16>,
+    /// Relay servers, several for failover
+    pub relay: Option<Vec<RelayUrl>>,
+    /// Maximum relay WebSocket frame size
+    pub relay_max_frame_size: usize,
+    /// Once matched through the relay, try switching to a direct connection to the receiver
+    pub try_direct: bool,
+    /// Upload to the relay's mailbox, kept this long, instead of waiting for the receiver
+    pub mailbox_ttl: Option<Duration>,
+    /// Send stdin instead of `path`, copying it to stdout as it's read
+    pub stdin_passthrough: bool,
+    /// Chunks of the file to read ahead while earlier ones are being sent
+    pub readahead: usize,
+    /// Connect now but hold the data back until this is met
+    pub start: Option<StartCondition>,
+    /// Measure the connection first and go ahead only if this confirms the estimate
+    pub estimate: Option<ConfirmPrompt>,
+    /// How a directory is packed
+    pub format: ArchiveFormat,
+    /// Who may connect to a direct transfer; anyone when empty
+    pub allow: AllowList,
+    /// Have the receiver check the file arrived intact and send back a receipt, saved next to it
+    pub receipt: bool,
+    /// Make the receiver's copy of the folder match, sending only the files that differ
+    pub sync: bool,
+    /// When syncing, delete the files only the receiver has once this confirms the list
+    pub sync_delete: Option<ConfirmPrompt>,
+    /// Threads hashing the folder's files for a sync (0 for one per core)
+    pub hash_threads: usize,
+    /// Where a synced folder's manifest is cached, so files that haven't changed since the last sync aren't read again
+    pub manifest_cache: Option<PathBuf>,
+    /// How long a chunk may take to arrive while the sender checks big ones get through
+    pub chunk_ack_timeout: Duration,
+    /// Files and folders under a directory to leave out of it, like the unreadable ones `transfer::preflight` finds
+    pub skip: Vec<PathBuf>,
+    /// Threads encrypting a file's chunks (0 for `crypto::default_crypto_workers`)
+    pub crypto_threads: usize,
+    /// How long to wait for a receiver before suggesting how to start one
+    pub hint_delay: Duration,
+    /// Offer to mix an ML-KEM-768 secret into the session key, used when the receiver offers it too
+    pub pq: bool,
+    /// Weakest key exchange to go on with (`protocol::KEY_EXCHANGE_V1` and up); v3 offers `pq` as well
+    pub min_protocol: u8,
+    /// Accept the receiver over TLS, checking its certificate against the one it vouches for under the code
+    pub direct_tls: bool,
+    /// How long a direct connection to the receiver may stall partway through a message
+    pub timeouts: SocketTimeouts,
+    /// Log each message on a direct connection with this (debug builds only)
+    pub protocol_log: Option<ProtocolLog>,
+}
+
+impl SendOptions {
+    pub fn new(path: impl Into<PathBuf>, code: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            code: code.into(),
+            port: None,
+            relay: None,
+            relay_max_frame_size: MAX_RELAY_FRAME_SIZE,
+            try_direct: false,
+            mailbox_ttl: None,
+            stdin_passthrough: false,
+            readahead: DEFAULT_READAHEAD,
+            start: None,
+            estimate: None,
+            format: ArchiveFormat::default(),
+            allow: AllowList::default(),
+            receipt: false,
+            sync: false,
+            sync_delete: None,
+            hash_threads: 0,
+            manifest_cache: None,
+            chunk_ack_timeout: CHUNK_ACK_TIMEOUT,
+            skip: Vec::new(),
+            crypto_threads: 0,
+            hint_delay: HINT_DELAY,
+            pq: false,
+            min_protocol: protocol::KEY_EXCHANGE_V1,
+            direct_tls: false,
+            timeouts: SocketTimeouts::default(),
+            protocol_log: None,
+        }
+    }
+}
+
+/// Options for receiving a file
+#[derive(Debug, Clone)]
+pub struct ReceiveOptions {
+    /// Transfer code from the sender
+    pub code: String,
+    /// Output path (defaults to the sender's filename)
+    pub output: Option<PathBuf>,
+    /// Sender's address for direct connections
+    pub host: Option<Endpoint>,
+    /// Sender's port for direct connections
+    pub port: Option<u16>,
+    /// Relay servers, several for failover
+    pub relay: Option<Vec<RelayUrl>>,
+    /// Maximum relay WebSocket frame size
+    pub relay_max_frame_size: usize,
+    /// Once matched through the relay, try switching to a direct connection to the sender
+    pub try_direct: bool,
+    /// Ask the relay for an upload the sender left in its mailbox, if the sender isn't there
+    pub mailbox: bool,
+    /// What to do with files in a received directory that already exist
+    pub conflict: ConflictStrategy,
+    /// Asked about each clash when `conflict` is `Ask`; without one, existing files are kept
+    pub conflict_prompt: Option<ConflictPrompt>,
+    /// Decline files whose name or contents aren't one of these types
+    pub accept_types: Option<AcceptTypes>,
+    /// Files that look like programs are only accepted if this confirms them
+    pub confirm_executable: Option<ConfirmPrompt>,
+    /// Shown a description of the offered file, which is only accepted if this confirms it
+    pub confirm_offer: Option<ConfirmPrompt>,
+    /// Decline anything bigger than this many bytes
+    pub reject_larger_than: Option<u64>,
+    /// Where temporary files go instead of next to the output
+    pub tmp_dir: Option<PathBuf>,
+    /// Fail when a received file's modification time, permissions or hard link can't be set, instead of warning
+    pub strict_metadata: bool,
+    /// Leave out files of a received folder that can't be written, reporting them, instead of failing
+    pub keep_going: bool,
+    /// Pick up from the record an earlier, unfinished attempt at the same file left
+    pub resume: bool,
+    /// Sign receipts with this key
+    pub identity: Option<Arc<IdentityKey>>,
+    /// When the output file already exists, have only the parts that changed sent
+    pub delta: bool,
+    /// Take a folder sync, which only sends what differs from the output folder
+    pub sync: bool,
+    /// Threads hashing the output folder's files for a sync (0 for one per core)
+    pub hash_threads: usize,
+    /// Most memory, in bytes, the transfer's incoming messages may take at once;
+    /// senders are asked for chunks small enough to fit
+    pub memory_limit: usize,
+    /// Write what arrives into this shell command's stdin instead of a file;
+    /// `receive` then returns an empty path
+    pub pipe_to: Option<String>,
+    /// How long to wait for the sender before suggesting what to check
+    pub hint_delay: Duration,
+    /// How what arrives is checked, for receipts and checkpoints; `Off` turns both down
+    pub checksum: ChecksumChoice,
+    /// Check a file for its receipt by reading it back once it's all arrived, instead of hashing it on the way in
+    pub verify_after: bool,
+    /// Offer to mix an ML-KEM-768 secret into the session key, used when the sender offers it too
+    pub pq: bool,
+    /// Weakest key exchange to go on with (`protocol::KEY_EXCHANGE_V1` and up); v3 offers `pq` as well
+    pub min_protocol: u8,
+    /// Connect to the sender over TLS, checking its certificate against the one it vouches for under the code
+    pub direct_tls: bool,
+    /// Race the sender's IPv4 and IPv6 addresses when connecting to it directly, see `network::connect`
+    pub happy_eyeballs: bool,
+    /// How long a direct connection to the sender may stall partway through a message
+    pub timeouts: SocketTimeouts,
+    /// Log each message on a direct connection with this (debug builds only)
+    pub protocol_log: Option<ProtocolLog>,
+}
+
+impl ReceiveOptions {
+    pub fn new(code: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            output: None,
+            host: None,
+            port: None,
+            relay: None,
+            relay_max_frame_size: MAX_RELAY_FRAME_SIZE,
+            try_direct: false,
+            mailbox: false,
+            conflict: ConflictStrategy::default(),
+            conflict_prompt: None,
+            accept_types: None,
+            confirm_executable: None,
+            confirm_offer: None,
+            reject_larger_than: None,
+            tmp_dir: None,
+            strict_metadata: false,
+            keep_going: false,
+            resume: false,
+            identity: None,
+            delta: false,
+            sync: false,
+            hash_threads: 0,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            pipe_to: None,
+            hint_delay: HINT_DELAY,
+            checksum: ChecksumChoice::default(),
+            verify_after: false,
+            pq: false,
+            min_protocol: protocol::KEY_EXCHANGE_V1,
+            direct_tls: false,
+            happy_eyeballs: true,
+            timeouts: SocketTimeouts::default(),
+            protocol_log: None,
+        }
+    }
+}
+
+/// Send a file, reporting progress to `progress` and stopping when `cancel` fires
+///
+/// A receiver that only looks at the offer (`zap peek`) leaves the sender
+/// waiting for the next one, on the same port or relay code.
+pub async fn send(
+    options: SendOptions,
+    progress: Option<Arc<dyn ProgressCallback>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let events = EventDispatcher::new(progress);
+    let mut listener = None;
+    let result = loop {
+        match send_inner(&options, None, &mut listener, &events, &cancel).await {
+            Err(e) if e.is::<Deferred>() => events.emit(TransferEvent::Deferred),
+            Err(e) if e.is::<HungUp>() && listener.is_some() => {}
+            result => break result,
+        }
+    };
+    events.finish().await;
+    result
+}
+
+/// Send a file over a transport that's already connected, ignoring the options' connection settings
+pub async fn send_over(
+    conn: Transport,
+    options: SendOptions,
+    progress: Option<Arc<dyn ProgressCallback>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let events = EventDispatcher::new(progress);
+    let result = send_inner(&options, Some(conn), &mut None, &events, &cancel).await;
+    events.finish().await;
+    result
+}
+
+/// One go at sending, to whoever connects; `listener` is bound on the first for direct transfers
+async fn send_inner(
+    options: &SendOptions,
+    conn: Option<Transport>,
+    listener: &mut Option<TcpListener>,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let mut timer = PhaseTimer::new(Phase::Metadata);
+    let mut metadata = if options.stdin_passthrough {
+        FileMetadata {
+            name: STDIN_NAME.to_string(),
+            size: 0,
+            is_directory: false,
+            checksum: String::from("tbd"),
+        }
+    } else {
+        // Anything read through for a checksum waits until the receiver is there to be told about it
+        transfer::get_file_metadata(&options.path, false).await?
+    };
+    if options.receipt && (options.stdin_passthrough || options.mailbox_ttl.is_some() || metadata.is_directory) {
+        return Err(anyhow!("Receipts are only for single files sent straight to the receiver"));
+    }
+    if options.sync && (options.stdin_passthrough || options.mailbox_ttl.is_some() || !metadata.is_directory) {
+        return Err(anyhow!("Only folders sent straight to the receiver can be synced"));
+    }
+    if options.mailbox_ttl.is_some() {
+        mailbox_meets(options.min_protocol)?;
+    }
+    // Left out of a sync, the receiver's copies would look like files to delete
+    if options.sync && !options.skip.is_empty() {
+        return Err(anyhow!("Unreadable files can't be left out of a sync"));
+    }
+    // Kept by an older zap, these may still be readable by anyone
+    for kept in [Some(SendTicket::ticket_path(&options.path)), options.manifest_cache.clone()].into_iter().flatten() {
+        if fsutil::tighten(&kept).unwrap_or(false) {
+            events.emit(TransferEvent::Tightened { path: kept });
+        }
+    }
+    events.emit(TransferEvent::Metadata {
+        filename: metadata.name.clone(),
+        size: metadata.size,
+    });
+    let mut schedule = match &options.start {
+        Some(condition) => Some(Schedule::new(condition, Box::new(InterfaceCounters))?),
+        None => None,
+    };
+    
+    if options.direct_tls && options.relay.is_some() {
+        return Err(anyhow!("--direct-tls is only for direct connections, not ones through a relay"));
+    }
+    // A throwaway certificate for this connection, vouched for once the key exchange is done
+    let tls_identity = if options.direct_tls && conn.is_none() { Some(TlsIdentity::generate()?) } else { None };
+    
+    // Wait for connection (either direct or via relay)
+    let connect = async {
+        match (options.mailbox_ttl, &options.relay) {
+            (Some(ttl), Some(relay)) => {
+                let (conn, ttl) = Transport::new_mailbox_sender(relay, &options.code, ttl, options.relay_max_frame_size).await?;
+                Ok((conn, Some(ttl)))
+            }
+            (Some(_), None) => Err(anyhow!("Mailbox mode needs a relay")),
+            (None, Some(relays)) => {
+                let registered = Transport::new_sender(
+                    options.relay.clone(),
+                    &options.code,
+                    options.port,
+                    options.relay_max_frame_size,
+                    options.try_direct,
+                    || events.emit(TransferEvent::Reannounced { at: SystemTime::now() }),
+                );
+                let hint = WaitingHint::sender(&options.code, None, None, Some(relays));
+                let mut conn = hint_after(options.hint_delay, registered, || events.emit(TransferEvent::Hint { hint })).await?;
+                conn.try_direct().await;
+                conn.set_timeouts(options.timeouts);
+                Ok((conn, None))
+            }
+            (None, None) => {
+                let listener = match listener {
+                    Some(listener) => listener,
+                    None => {
+                        // The port may not be the default one, so tell the user which it is
+                        let bound = network::bind_with(options.port, |port| events.emit(TransferEvent::PortBusy { port })).await?;
+                        let port = bound.local_addr()?.port();
+                        events.emit(TransferEvent::Listening { port });
+                        network::advertise_mdns(&options.code, port).await?;
+                        listener.insert(bound)
+                    }
+                };
+                let port = listener.local_addr()?.port();
+                let on_hint = || {
+                    let hint = WaitingHint::sender(&options.code, Some(port), network::lan_address().map(IpAddr::V4), None);
+                    events.emit(TransferEvent::Hint { hint });
+                };
+                let on_reject = |addr| events.emit(TransferEvent::Rejected { addr });
+                let mut accepted = hint_after(options.hint_delay, network::accept_from(listener, &options.allow, on_reject), on_hint).await?;
+                accepted.set_timeouts(options.timeouts);
+                let conn = match &tls_identity {
+                    Some(identity) => accepted.start_tls(TlsRole::Server, identity).await?,
+                    None => accepted,
+                };
+                Ok((Transport::Direct(conn), None))
+            }
+        }
+    };
+    timer.enter(Phase::WaitingForPeer);
+    let (mut conn, mailbox_ttl) = match conn {
+        Some(conn) => (conn, None),
+        None => tokio::select! {
+            conn = connect => conn?,
+            _ = cancel.cancelled() => return Err(Cancelled.into()),
+        },
+    };
+    #[cfg(debug_assertions)]
+    if let Some(log_fn) = &options.protocol_log {
+        conn.enable_protocol_debug(log_fn.clone());
+    }
+    events.emit(TransferEvent::Connected { peer: conn.peer_info() });
+    timer.enter(Phase::Handshake);
+    
+    // A mailbox upload is replayed to the receiver later, so nothing comes back to wait for.
+    // Not knowing what the receiver supports, stick to the baseline protocol.
+    let mailbox = mailbox_ttl.is_some();
+    let session = if mailbox {
+        let mut features = protocol::local_features();
+        // Nobody is there to answer a key exchange, or to say how the transfer went
+        features.remove(FEATURE_PAKE_V2);
+        features.remove(FEATURE_COMPLETE_ACK);
+        features.insert(FEATURE_MAILBOX.to_string());
+        send_hello(&mut conn, features).await?;
+        Session::new(HashSet::from([FEATURE_MAILBOX.to_string()]))
+    } else {
+        let mut ours = offered_features();
+        offer_post_quantum(&mut ours, options.pq || options.min_protocol >= protocol::KEY_EXCHANGE_V3)?;
+        handshake_offering(&mut conn, ours).await?
+    };
+    if options.stdin_passthrough && !session.supports(FEATURE_STREAM) {
+        return Err(anyhow!("The receiver can't accept data of unknown length from stdin"));
+    }
+    let zip = metadata.is_directory && options.format == ArchiveFormat::Zip;
+    if zip && !session.supports(FEATURE_ZIP) {
+        return Err(anyhow!("The receiver can't unpack ZIP archives, send with --format tar"));
+    }
+    if options.estimate.is_some() && !session.supports(FEATURE_PROBE) {
+        return Err(anyhow!("The receiver can't measure the connection, send without --estimate"));
+    }
+    if options.receipt && !session.supports(FEATURE_RECEIPT) {
+        return Err(anyhow!("The receiver can't send receipts, send without --receipt"));
+    }
+    if options.sync && !session.supports(FEATURE_SYNC) {
+        return Err(anyhow!("The receiver can't sync folders, send without --sync"));
+    }
+    if !mailbox {
+        require_min_protocol(&mut conn, &session, options.min_protocol).await?;
+    }
+    events.emit(TransferEvent::Handshake { session: session.clone() });
+    
+    let cipher = key_exchange(&mut conn, &options.code, &session, Role::Sender).await?;
+    
+    // Make sure the receiver has the same key before sending anything about the file
+    send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+    if !mailbox {
+        receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+        verify_tls_peer(&mut conn, &cipher, tls_identity.as_ref(), events).await?;
+    }
+    
+    if let Some(prompt) = &options.estimate {
+        let estimate = estimate::measure(&mut conn, &cipher, PROBE_DURATION, cancel).await?;
+        let size = if options.stdin_passthrough {
+            None
+        } else if metadata.is_directory {
+            Some(estimate::payload_size(&options.path))
+        } else {
+            Some(metadata.size)
+        };
+        if !prompt.confirm(&estimate.describe(size)) {
+            return cancel_send(&mut conn, &cipher).await;
+        }
+    }
+    
+    // Only the receiver checks the file's checksum, for a receipt, so it's not read an extra time otherwise
+    let manifest = if options.receipt || options.sync {
+        let algorithm = match session.supports(FEATURE_CHECKSUM_BLAKE3) {
+            true => ChecksumAlgorithm::Blake3,
+            false => ChecksumAlgorithm::Sha256,
+        };
+        let hash_start = Instant::now();
+        let prepared = prepare(options, &mut metadata, &mut conn, &cipher, algorithm, session.supports(FEATURE_PREPARING), events).await?;
+        timer.hashing(hash_start.elapsed());
+        prepared
+    } else {
+        None
+    };
+    
+    timer.enter(Phase::WaitingForPeer);
+    if let Some(schedule) = &mut schedule {
+        wait_for_start(schedule, &mut conn, &cipher, session.supports(FEATURE_SCHEDULE), events, cancel).await?;
+    }
+    
+    // Send metadata
+    timer.enter(Phase::Metadata);
+    let metadata_msg = if let Some(entries) = &manifest {
+        Message::SyncOffer {
+            name: metadata.name.clone(),
+            entries: entries.clone(),
+        }
+    } else if options.stdin_passthrough {
+        Message::StreamMetadata {
+            filename: metadata.name.clone(),
+        }
+    } else {
+        Message::Metadata {
+            filename: metadata.name.clone(),
+            size: metadata.size,
+            is_directory: metadata.is_directory,
+            checksum: metadata.checksum.clone(),
+        }
+    };
+    send_control(&mut conn, &cipher, &metadata_msg, session.supports(FEATURE_FRAGMENT)).await?;
+    
+    // The receiver may want to see how a regular file starts before accepting it
+    let peek = !mailbox && session_supports_peek(&session, &metadata, options.stdin_passthrough);
+    if !mailbox && !peek {
+        timer.enter(Phase::WaitingForPeer);
+        wait_for_ack(&mut conn).await?;
+    }
+    // A receiver that can call the transfer off is listened to between chunks
+    let listen = !mailbox && session.supports(FEATURE_RESUME);
+    // Nobody is there to say what they take from a mailbox, so it's left as it was
+    let max_chunk_size = match mailbox {
+        true => MAX_CHUNK_SIZE,
+        false => {
+            let (chunk_size, assumed) = chunk_ceiling(&session);
+            if chunk_size < MAX_CHUNK_SIZE {
+                events.emit(TransferEvent::PeerLimited { chunk_size, assumed });
+            }
+            chunk_size
+        }
+    };
+    
+    timer.enter(Phase::Transfer);
+    let called_off = if let Some(manifest) = &manifest {
+        send_sync(options, &metadata, manifest, &mut conn, &cipher, session.supports(FEATURE_FRAGMENT), events).await?;
+        None
+    } else if options.stdin_passthrough {
+        send_stream(&metadata, &mut conn, &cipher, max_chunk_size, listen, events, cancel).await?
+    } else if zip {
+        let archive = ArchiveStream::zip(&options.path, &options.skip, transfer::CHUNK_SIZE)?;
+        send_archive(archive, &metadata, &mut conn, &cipher, &session, listen, events, cancel).await?
+    } else if metadata.is_directory {
+        // Stream the directory as a tar archive, no temporary file needed
+        let archive = ArchiveStream::tar(&options.path, &options.skip, transfer::CHUNK_SIZE);
+        send_archive(archive, &metadata, &mut conn, &cipher, &session, listen, events, cancel).await?
+    } else {
+        let checkpoints = !mailbox && session.supports(FEATURE_CHECKPOINT);
+        let delta = !mailbox && session.supports(FEATURE_DELTA);
+        let ack_chunks = !mailbox && session.supports(FEATURE_CHUNK_ACK);
+        send_chunks(options, &metadata, &mut conn, &cipher, max_chunk_size, peek, listen, checkpoints, delta, ack_chunks, &mut timer, events, cancel).await?
+    };
+    
+    // Called off by the receiver isn't a failure here; remember how far it got so a resume can be checked
+    let ticket_file = SendTicket::ticket_path(&options.path);
+    if let Some(CalledOff { transferred, resumable }) = called_off {
+        if resumable {
+            SendTicket::for_file(&options.path, transferred)?.save(&ticket_file)?;
+        }
+        events.emit(TransferEvent::ReceiverCancelled {
+            transferred,
+            total: metadata.size,
+            resumable,
+        });
+        return Ok(());
+    }
+    
+    // Send complete message
+    let complete_msg = Message::Complete;
+    let encrypted_complete = cipher.encrypt(&complete_msg.to_bytes()?)?;
+    conn.send(&encrypted_complete).await?;
+    
+    if let Some(ttl) = mailbox_ttl {
+        conn.finish_store().await?;
+        events.emit(TransferEvent::Stored { ttl });
+    }
+    timer.enter(Phase::Verification);
+    // Not done until the receiver has dealt with it all, like a command it pipes into exiting cleanly
+    if !mailbox && session.supports(FEATURE_COMPLETE_ACK) {
+        wait_for_ack(&mut conn).await?;
+    }
+    if options.receipt {
+        let receipt = receive_receipt(&mut conn, &cipher, &metadata.checksum).await?;
+        let receipt_file = Receipt::receipt_path(&options.path);
+        receipt.save(&receipt_file)?;
+        events.emit(TransferEvent::Receipt { receipt, saved_to: Some(receipt_file) });
+    }
+    timer.enter(Phase::Finalize);
+    if !options.stdin_passthrough && !metadata.is_directory {
+        SendTicket::cleanup(&ticket_file)?;
+    }
+    
+    events.emit(TransferEvent::Timings { timings: timer.timings() });
+    events.emit(TransferEvent::Complete);
+    Ok(())
+}
+
+/// Wait for the receiver's receipt for the file with `checksum`, checking any signature on it
+async fn receive_receipt(conn: &mut Transport, cipher: &Cipher, checksum: &str) -> Result<Receipt> {
+    // Reading the file back to check it may take the receiver a while
+    match Message::from_bytes(&cipher.decrypt(&conn.wait_for_message().await?)?)? {
+        Message::Receipt { transfer_id, checksum: received, received_at, signature } => {
+            let receipt = Receipt {
+                transfer_id,
+                checksum: received,
+                received_at,
+                signature,
+            };
+            if receipt.checksum != checksum {
+                return Err(anyhow!("The receipt is for different contents than were sent"));
+            }
+            receipt.check_signature(None)?;
+            Ok(receipt)
+        }
+        Message::Error { message } => Err(anyhow!("Transfer error: {}", message)),
+        _ => Err(anyhow!("Expected Receipt message")),
+    }
+}
+
+/// How far a send got before the receiver called it off
+struct CalledOff {
+    transferred: u64,
+    resumable: bool,
+}
+
+/// The biggest chunks the receiver takes, and whether that's only assumed
+///
+/// A receiver that doesn't say, from before peers did, gets `LEGACY_MAX_CHUNK_SIZE`
+/// and is taken to read messages up to `LEGACY_MAX_FRAME_SIZE`.
+fn chunk_ceiling(session: &Session) -> (usize, bool) {
+    let (chunk, frame) = (session.peer_max_chunk_size(), session.peer_max_frame_size());
+    let assumed = chunk.is_none() && frame.is_none();
+    let fits_frame = frame.unwrap_or(LEGACY_MAX_FRAME_SIZE).saturating_sub(CHUNK_OVERHEAD);
+    (chunk.unwrap_or(LEGACY_MAX_CHUNK_SIZE).min(fits_frame).min(MAX_CHUNK_SIZE), assumed)
+}
+
+/// Whether a regular file's first chunk goes out before the receiver's Ack
+fn session_supports_peek(session: &Session, metadata: &FileMetadata, streamed: bool) -> bool {
+    session.supports(FEATURE_PEEK) && !metadata.is_directory && !streamed
+}
+
+/// Whether the receiver says where a regular file picks up, and can resume it after cancelling
+fn session_supports_resume(session: &Session, metadata: &FileMetadata, streamed: bool) -> bool {
+    session.supports(FEATURE_RESUME) && !metadata.is_directory && !streamed
+}
+
+/// Read through what's about to be offered: the file for its receipt's checksum by `algorithm`, or a folder for its sync manifest
+///
+/// Either can take minutes on something big, so a receiver that can show it is
+/// told how far along it is, at most once per `PREPARING_INTERVAL`.
+async fn prepare(
+    options: &SendOptions,
+    metadata: &mut FileMetadata,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    algorithm: ChecksumAlgorithm,
+    announce: bool,
+    events: &EventDispatcher,
+) -> Result<Option<Vec<ManifestEntry>>> {
+    let first = if options.sync { "hashing folder" } else { "hashing file" };
+    if announce {
+        let preparing = Message::Preparing { detail: first.to_string() };
+        conn.send(&cipher.encrypt(&preparing.to_bytes()?)?).await?;
+    }
+    let (detail, mut latest) = tokio::sync::watch::channel(first.to_string());
+    let work = async {
+        if options.sync {
+            let progress = |files_done, total_files| {
+                events.emit(TransferEvent::Hashing { files_done, total_files });
+                detail.send_replace(format!("hashing folder: {}/{} files", files_done, total_files));
+            };
+            let manifest = match &options.manifest_cache {
+                Some(cache_file) => sync::build_manifest_cached(&options.path, options.hash_threads, cache_file, progress).await?.0,
+                None => sync::build_manifest(&options.path, options.hash_threads, progress).await?,
+            };
+            Ok(Some(manifest))
+        } else {
+            let progress = |read: u64, total: u64| {
+                detail.send_replace(format!("hashing file: {}%", read * 100 / total.max(1)));
+            };
+            metadata.checksum = transfer::checksum_file(&options.path, algorithm, progress).await?;
+            Ok(None)
+        }
+    };
+    tokio::pin!(work);
+    let mut ticks = tokio::time::interval_at(Instant::now() + PREPARING_INTERVAL, PREPARING_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = ticks.tick(), if announce => {
+                if latest.has_changed()? {
+                    let preparing = Message::Preparing {
+                        detail: latest.borrow_and_update().clone(),
+                    };
+                    conn.send(&cipher.encrypt(&preparing.to_bytes()?)?).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Hold off until the scheduled start, keeping the connection alive with heartbeats
+///
+/// Receivers that don't know about heartbeats just wait without them.
+async fn wait_for_start(
+    schedule: &mut Schedule,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    heartbeats: bool,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    loop {
+        let tick = tokio::select! {
+            tick = schedule.tick() => tick?,
+            _ = cancel.cancelled() => return cancel_send(conn, cipher).await,
+        };
+        let Tick::Waiting { starts_in } = tick else {
+            return Ok(());
+        };
+        events.emit(TransferEvent::Scheduled { starts_in });
+        if heartbeats {
+            let waiting = Message::Waiting {
+                starts_in_secs: starts_in.map(|left| left.as_secs()),
+            };
+            conn.send(&cipher.encrypt(&waiting.to_bytes()?)?).await?;
+        }
+    }
+}
+
+/// Wait for the receiver to accept the transfer, or to finish with it
+///
+/// Either may wait on the user, so the read timeout doesn't cut it short.
+async fn wait_for_ack(conn: &mut Transport) -> Result<()> {
+    let ack = conn.wait_for_message().await?;
+    match Message::from_bytes(&ack)? {
+        Message::Ack => Ok(()),
+        Message::Deferred => Err(Deferred.into()),
+        Message::Error { message } => Err(anyhow!("Transfer error: {}", message)),
+        _ => Err(anyhow!("Expected Ack message")),
+    }
+}
+
+/// Where the receiver wants `path` to pick up, as a chunk index and a byte offset
+///
+/// Picking up partway only works if the file is the one the receiver started
+/// with, so one that changed since a cancelled send is refused.
+async fn receive_resume(conn: &mut Transport, cipher: &Cipher, path: &Path) -> Result<(u64, u64)> {
+    let (from_chunk, offset) = match Message::from_bytes(&cipher.decrypt(&conn.receive().await?)?)? {
+        Message::Resume { from_chunk, offset } => (from_chunk, offset),
+        _ => return Err(anyhow!("Expected Resume message")),
+    };
+    if offset == 0 {
+        return Ok((from_chunk, offset));
+    }
+    if let Some(ticket) = SendTicket::load(&SendTicket::ticket_path(path))? {
+        if !ticket.unchanged(path)? {
+            let message = format!("{} changed since the transfer was cancelled, receive it again without --resume", ticket.filename);
+            conn.send(&cipher.encrypt(&Message::Error { message: message.clone() }.to_bytes()?)?).await?;
+            return Err(anyhow!(message));
+        }
+    }
+    Ok((from_chunk, offset))
+}
+
+/// Whether the receiver has sent a Cancel while data was going out, without waiting for one
+///
+/// Nothing else comes back mid-transfer. Part of a message that has only
+/// partly arrived stays with the transport until the rest comes in.
+fn receiver_cancel(conn: &mut Transport, cipher: &Cipher) -> Result<Option<bool>> {
+    let Some(data) = conn.try_receive()? else {
+        return Ok(None);
+    };
+    match cipher.decrypt(&data).and_then(|bytes| Message::from_bytes(&bytes)) {
+        Ok(Message::Cancel { resumable }) => return Ok(Some(resumable)),
+        // A late answer for a chunk that was sent again while probing
+        Ok(Message::ChunkAck { .. }) => return Ok(None),
+        _ => {}
+    }
+    match Message::from_bytes(&data) {
+        Ok(Message::Error { message }) => Err(anyhow!("Transfer error: {}", message)),
+        _ => Err(anyhow!("Unexpected message from the receiver")),
+    }
+}
+
+/// Read `path` from `offset` on a background thread
+fn open_chunker(path: &Path, offset: u64, chunk_size: usize, readahead: usize) -> Result<ReadAheadChunker> {
+    let mut chunker = FileChunker::open_at(path, offset)?;
+    chunker.set_chunk_size(chunk_size);
+    Ok(chunker.with_readahead(readahead))
+}
+
+/// Send a regular file as encrypted chunks of up to `max_chunk_size`
+///
+/// With `peek`, the first chunk goes out before the receiver has accepted the
+/// file, and the rest only once it has. With `listen`, the receiver says where
+/// to pick up after accepting, and may call the send off between chunks.
+/// With `checkpoints`, a `PartialChecksum` follows every `CHECKPOINT_INTERVAL` chunks.
+/// With `delta`, the receiver then sends signatures of any copy it already has,
+/// and the file goes as differences from that instead.
+/// With `ack_chunks`, the first chunk has to arrive before the rest go, and
+/// if it doesn't, a `PathProbe` finds a chunk size that does.
+#[allow(clippy::too_many_arguments)]
+async fn send_chunks(
+    options: &SendOptions,
+    metadata: &FileMetadata,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    max_chunk_size: usize,
+    peek: bool,
+    listen: bool,
+    checkpoints: bool,
+    delta: bool,
+    ack_chunks: bool,
+    timer: &mut PhaseTimer,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<Option<CalledOff>> {
+    let mut controller = ChunkSizeController::new(max_chunk_size);
+    let (mut chunk_index, mut resumed_from) = match listen && !peek {
+        true => receive_resume(conn, cipher, &options.path).await?,
+        false => (0, 0),
+    };
+    if delta && !peek {
+        if let Some((block_size, blocks)) = receive_signatures(conn, cipher).await? {
+            return send_delta(&options.path, metadata, conn, cipher, block_size, blocks, listen, events, cancel).await;
+        }
+    }
+    let mut chunker = open_chunker(&options.path, resumed_from, controller.chunk_size(), options.readahead)?;
+    let mut checkpointer = checkpoints.then(|| Checkpointer::new(CHECKPOINT_INTERVAL));
+    let mut probe = ack_chunks.then(|| PathProbe::new(controller.chunk_size()));
+    if probe.is_some() {
+        conn.send(&cipher.encrypt(&Message::AckChunks { enabled: true }.to_bytes()?)?).await?;
+    }
+    // Done probing, so the receiver can stop acking chunks once the next one goes
+    let mut stop_acks = false;
+    // Chunks are encrypted ahead on worker threads, tagged with their length
+    let mut sealer = CryptoPool::new(Arc::new(cipher.clone()), options.crypto_threads);
+    let mut traffic = Traffic::new(Some(metadata.size.saturating_sub(resumed_from)));
+    let start_time = Instant::now();
+    
+    // An empty file still gives the receiver a (blank) first chunk to look at
+    let read_start = Instant::now();
+    let mut next = chunker.next_chunk().await?;
+    timer.disk(read_start.elapsed());
+    if peek && next.is_none() {
+        next = Some(Vec::new());
+    }
+    
+    while let Some(chunk) = next {
+        if cancel.is_cancelled() {
+            return cancel_send(conn, cipher).await;
+        }
+        if listen {
+            if let Some(resumable) = receiver_cancel(conn, cipher)? {
+                let unsent: usize = sealer.queued().sum();
+                let transferred = chunker.bytes_read() - (chunk.len() + unsent) as u64;
+                return Ok(Some(CalledOff { transferred, resumable }));
+            }
+        }
+        
+        let chunk_len = chunk.len();
+        if let Some(path_probe) = &mut probe {
+            let offset = chunker.bytes_read() - chunk_len as u64;
+            let timeout = options.chunk_ack_timeout;
+            let send_start = Instant::now();
+            let arrived = match send_probed(conn, cipher, &options.path, chunk_index, offset, chunk, path_probe, timeout, &mut traffic).await? {
+                Probed::Arrived(data) => data,
+                Probed::CalledOff { resumable } => return Ok(Some(CalledOff { transferred: offset, resumable })),
+            };
+            timer.network(send_start.elapsed());
+            let hash_start = Instant::now();
+            let checkpoint = checkpointer.as_mut().and_then(|checkpointer| checkpointer.record(chunk_index, &arrived));
+            timer.hashing(hash_start.elapsed());
+            if let Some(checkpoint) = checkpoint {
+                conn.send(&cipher.encrypt(&checkpoint.to_bytes()?)?).await?;
+            }
+            let chunk_size = match path_probe.arrived(arrived.len()) {
+                ProbeStep::Try(chunk_size) => chunk_size,
+                ProbeStep::Settled(chunk_size) => {
+                    stop_acks = true;
+                    if let Some((_, dropped)) = path_probe.limit() {
+                        controller = ChunkSizeController::new(chunk_size);
+                        events.emit(TransferEvent::PathLimited { chunk_size, dropped });
+                    }
+                    probe = None;
+                    chunk_size
+                }
+            };
+            // Carry on from the end of whichever copy the receiver kept
+            let position = offset + arrived.len() as u64;
+            if position != chunker.bytes_read() || chunk_size != chunk_len {
+                chunker = open_chunker(&options.path, position, chunk_size, options.readahead)?;
+            }
+        } else {
+            // Not straight after the peeked chunk, which the receiver may be about to turn down
+            if std::mem::take(&mut stop_acks) {
+                sealer.encrypt(0, Message::AckChunks { enabled: false }.to_bytes()?);
+            }
+            let hash_start = Instant::now();
+            let checkpoint = checkpointer.as_mut().and_then(|checkpointer| checkpointer.record(chunk_index, &chunk));
+            timer.hashing(hash_start.elapsed());
+            let chunk_msg = Message::Chunk {
+                index: chunk_index,
+                data: chunk,
+            };
+            sealer.encrypt(chunk_len, chunk_msg.to_bytes()?);
+            if let Some(checkpoint) = checkpoint {
+                sealer.encrypt(0, checkpoint.to_bytes()?);
+            }
+            while sealer.is_full() {
+                if let Some(chunk_size) = send_sealed(conn, &mut sealer, &mut controller, timer, &mut traffic).await? {
+                    chunker.set_chunk_size(chunk_size);
+                    events.emit(TransferEvent::ChunkSize { chunk_size });
+                }
+            }
+        }
+        chunk_index += 1;
+        if peek && chunk_index == 1 {
+            while !sealer.is_empty() {
+                send_sealed(conn, &mut sealer, &mut controller, timer, &mut traffic).await?;
+            }
+            wait_for_ack(conn).await?;
+            if listen {
+                let (from_chunk, offset) = receive_resume(conn, cipher, &options.path).await?;
+                // The receiver already has the peeked chunk, along with everything else before `offset`
+                if offset > 0 {
+                    let chunk_size = probe.as_ref().map_or(controller.chunk_size(), PathProbe::chunk_size);
+                    chunker = open_chunker(&options.path, offset, chunk_size, options.readahead)?;
+                    (chunk_index, resumed_from) = (from_chunk, offset);
+                    traffic.total = Some(metadata.size.saturating_sub(offset));
+                    if let Some(checkpointer) = &mut checkpointer {
+                        checkpointer.restart();
+                    }
+                }
+            }
+            if delta {
+                if let Some((block_size, blocks)) = receive_signatures(conn, cipher).await? {
+                    // The receiver sets the peeked chunk aside and rebuilds the file from the start
+                    return send_delta(&options.path, metadata, conn, cipher, block_size, blocks, listen, events, cancel).await;
+                }
+            }
+        }
+        
+        events.emit(TransferEvent::Progress {
+            filename: metadata.name.clone(),
+            transferred: chunker.bytes_read(),
+            total: chunker.total_size(),
+            speed: speed(chunker.bytes_read() - resumed_from, start_time),
+        });
+        let read_start = Instant::now();
+        next = chunker.next_chunk().await?;
+        timer.disk(read_start.elapsed());
+    }
+    while !sealer.is_empty() {
+        send_sealed(conn, &mut sealer, &mut controller, timer, &mut traffic).await?;
+    }
+    
+    Ok(None)
+}
+
+/// Send the oldest chunk or message `sealer` has encrypted
+///
+/// A chunk's send time goes to `controller`, and any new chunk size it picks
+/// comes back. If the connection fails, the error says how much `traffic` got through.
+async fn send_sealed(
+    conn: &mut Transport,
+    sealer: &mut CryptoPool<usize>,
+    controller: &mut ChunkSizeController,
+    timer: &mut PhaseTimer,
+    traffic: &mut Traffic,
+) -> Result<Option<usize>> {
+    let Some((chunk_len, sealed)) = sealer.next().await else {
+        return Ok(None);
+    };
+    let sealed = sealed?;
+    let send_start = Instant::now();
+    if let Err(e) = conn.send(&sealed).await {
+        return Err(traffic.lost(e, true, !conn.is_relayed()));
+    }
+    timer.network(send_start.elapsed());
+    Ok(match chunk_len {
+        0 => {
+            traffic.frame();
+            None
+        }
+        chunk_len => {
+            traffic.chunk(None, chunk_len);
+            controller.record(chunk_len, send_start.elapsed())
+        }
+    })
+}
+
+/// How a chunk sent while probing the path went
+enum Probed {
+    /// The receiver kept this copy of it
+    Arrived(Vec<u8>),
+    /// The receiver called the transfer off instead
+    CalledOff { resumable: bool },
+}
+
+/// Send chunk `index`, which starts `offset` bytes into `path`, and wait for it to arrive
+///
+/// Each time it doesn't within `timeout`, it goes again as a smaller chunk
+/// from `probe`. A copy given up on may still turn up before the smaller one;
+/// the receiver keeps whichever comes first and says how far the file runs,
+/// which tells them apart.
+#[allow(clippy::too_many_arguments)]
+async fn send_probed(
+    conn: &mut Transport,
+    cipher: &Cipher,
+    path: &Path,
+    index: u64,
+    offset: u64,
+    chunk: Vec<u8>,
+    probe: &mut PathProbe,
+    timeout: Duration,
+    traffic: &mut Traffic,
+) -> Result<Probed> {
+    let direct = !conn.is_relayed();
+    let mut copies = vec![chunk];
+    loop {
+        let data = copies.last().cloned().unwrap_or_default();
+        let len = data.len();
+        if let Err(e) = conn.send(&cipher.encrypt(&Message::Chunk { index, data }.to_bytes()?)?).await {
+            return Err(traffic.lost(e, true, direct));
+        }
+        traffic.chunk(Some(index), len);
+        
+        let deadline = Instant::now() + timeout;
+        let bytes_written = loop {
+            let data = match tokio::time::timeout_at(deadline, conn.receive()).await {
+                Ok(data) => data.map_err(|e| traffic.lost(e, true, direct))?,
+                Err(_) => break None,
+            };
+            match cipher.decrypt(&data).and_then(|bytes| Message::from_bytes(&bytes)) {
+                Ok(Message::ChunkAck { index: acked, bytes_written }) if acked == index => {
+                    traffic.acked = Some(bytes_written);
+                    break Some(bytes_written);
+                }
+                // Late answers for earlier chunks that went twice
+                Ok(Message::ChunkAck { .. }) => {}
+                Ok(Message::Cancel { resumable }) => return Ok(Probed::CalledOff { resumable }),
+                Ok(_) => return Err(anyhow!("Unexpected message from the receiver")),
+                Err(_) => match Message::from_bytes(&data) {
+                    Ok(Message::Error { message }) => return Err(anyhow!("Transfer error: {}", message)),
+                    // Left after seeing the offer and its first chunk
+                    Ok(Message::Deferred) => return Err(Deferred.into()),
+                    _ => return Err(anyhow!("Unexpected message from the receiver")),
+                },
+            }
+        };
+        
+        let Some(bytes_written) = bytes_written else {
+            let Some(chunk_size) = probe.dropped(len) else {
+                return Err(anyhow!(
+                    "The receiver stopped answering: a {}-byte chunk didn't arrive within {}",
+                    len,
+                    humantime::format_duration(timeout)
+                ));
+            };
+            let mut chunker = FileChunker::open_at(path, offset)?;
+            chunker.set_chunk_size(chunk_size);
+            copies.push(chunker.next_chunk()?.unwrap_or_default());
+            continue;
+        };
+        return match copies.iter().position(|copy| offset + copy.len() as u64 == bytes_written) {
+            Some(kept) => Ok(Probed::Arrived(copies.swap_remove(kept))),
+            None => Err(anyhow!("The receiver has {} bytes after chunk {}, which doesn't match what was sent", bytes_written, index)),
+        };
+    }
+}
+
+/// Send the files in `manifest` the receiver asks for, and have it delete the ones only it has once confirmed
+async fn send_sync(
+    options: &SendOptions,
+    metadata: &FileMetadata,
+    manifest: &[ManifestEntry],
+    conn: &mut Transport,
+    cipher: &Cipher,
+    fragment: bool,
+    events: &EventDispatcher,
+) -> Result<()> {
+    let (wanted, remote_only) = match receive_control(conn, cipher, &mut Reassembler::default()).await? {
+        Message::SyncRequest { wanted, remote_only } => (wanted, remote_only),
+        Message::Error { message } => return Err(anyhow!("Transfer error: {}", message)),
+        _ => return Err(anyhow!("Expected SyncRequest message")),
+    };
+    // Whatever the receiver asks for, only files that were offered go
+    let offered: std::collections::HashSet<&str> = manifest.iter().map(|entry| entry.path.as_str()).collect();
+    if let Some(path) = wanted.iter().find(|path| !offered.contains(path.as_str())) {
+        return Err(anyhow!("The receiver asked for {}, which isn't in the folder", path));
+    }
+    let delete = match &options.sync_delete {
+        Some(prompt) if !remote_only.is_empty() => {
+            let question = format!("{} files are only on the receiver's side:\n  {}", remote_only.len(), remote_only.join("\n  "));
+            prompt.confirm(&question)
+        }
+        _ => false,
+    };
+    
+    let sent = transfer::stream_tar_files_to_transport(&options.path, &wanted, conn, cipher, CHUNK_SIZE, |files_done, total_files| {
+        events.emit(TransferEvent::Archiving { files_done, total_files });
+    }).await?;
+    events.emit(TransferEvent::Progress {
+        filename: metadata.name.clone(),
+        transferred: sent,
+        total: sent,
+        speed: 0.0,
+    });
+    let deleted = if delete { remote_only.len() } else { 0 };
+    if delete {
+        send_control(conn, cipher, &Message::SyncDelete { paths: remote_only.clone() }, fragment).await?;
+    }
+    events.emit(TransferEvent::Synced {
+        sent: wanted.len(),
+        unchanged: manifest.len().saturating_sub(wanted.len()),
+        skipped: 0,
+        remote_only: remote_only.len(),
+        deleted,
+    });
+    Ok(())
+}
+
+/// The receiver's signatures of its copy of the file, if it has one worth working from
+async fn receive_signatures(conn: &mut Transport, cipher: &Cipher) -> Result<Option<(usize, Vec<BlockSignature>)>> {
+    match receive_control(conn, cipher, &mut Reassembler::default()).await? {
+        Message::BlockSignatures { blocks, .. } if blocks.is_empty() => Ok(None),
+        Message::BlockSignatures { block_size, blocks } if block_size > 0 => Ok(Some((block_size as usize, blocks))),
+        Message::Error { message } => Err(anyhow!("Transfer error: {}", message)),
+        _ => Err(anyhow!("Expected BlockSignatures message")),
+    }
+}
+
+/// Send a regular file as differences from the receiver's copy, which it signed in `block_size` blocks
+///
+/// The differences are worked out on a background thread, a few ops ahead of the network.
+#[allow(clippy::too_many_arguments)]
+async fn send_delta(
+    path: &Path,
+    metadata: &FileMetadata,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    block_size: usize,
+    blocks: Vec<BlockSignature>,
+    listen: bool,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<Option<CalledOff>> {
+    let file = std::fs::File::open(path)?;
+    let (tx, mut ops) = tokio::sync::mpsc::channel(DEFAULT_READAHEAD);
+    tokio::task::spawn_blocking(move || {
+        let mut encoder = DeltaEncoder::new(std::io::BufReader::new(file), block_size, blocks);
+        loop {
+            let op = encoder.next_op().transpose();
+            let Some(op) = op else {
+                break;
+            };
+            // Stop after an error, or once nobody is taking ops any more
+            let failed = op.is_err();
+            let op = op.map(|op| (op, encoder.position(), encoder.copied()));
+            if tx.blocking_send(op).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let start_time = Instant::now();
+    let mut covered = 0u64;
+    let mut reused = 0u64;
+    let mut done = false;
+    
+    while let Some(op) = ops.recv().await {
+        let (op, position, copied) = op?;
+        if cancel.is_cancelled() {
+            return cancel_send(conn, cipher).await;
+        }
+        if listen {
+            // Nothing of a delta can be picked up from, whatever the receiver says
+            if receiver_cancel(conn, cipher)?.is_some() {
+                return Ok(Some(CalledOff { transferred: covered, resumable: false }));
+            }
+        }
+        done = matches!(op, DeltaOp::Done { .. });
+        conn.send(&cipher.encrypt(&Message::Delta { op }.to_bytes()?)?).await?;
+        (covered, reused) = (position, copied);
+        events.emit(TransferEvent::Progress {
+            filename: metadata.name.clone(),
+            transferred: covered,
+            total: metadata.size,
+            speed: speed(covered, start_time),
+        });
+    }
+    if !done {
+        return Err(anyhow!("File reader stopped after {} of {} bytes", covered, metadata.size));
+    }
+    events.emit(TransferEvent::Delta { reused, total: metadata.size });
+    Ok(None)
+}
+
+/// Send a directory as an archive built a chunk at a time on a blocking thread
+///
+/// Like a file, it can be cancelled here or called off by the receiver between
+/// chunks. The checksums of the files in a tar archive follow it when the
+/// receiver takes them.
+#[allow(clippy::too_many_arguments)]
+async fn send_archive(
+    mut archive: ArchiveStream,
+    metadata: &FileMetadata,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    session: &Session,
+    listen: bool,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<Option<CalledOff>> {
+    let mut chunk_index = 0u64;
+    let mut sent = 0u64;
+    
+    while let Some(piece) = archive.next().await? {
+        let chunk = match piece {
+            ArchivePiece::Data(chunk) => chunk,
+            ArchivePiece::Progress { files_done, total_files } => {
+                events.emit(TransferEvent::Archiving { files_done, total_files });
+                continue;
+            }
+            ArchivePiece::Checksums(files) => {
+                if session.supports(FEATURE_FILE_CHECKSUMS) {
+                    send_control(conn, cipher, &Message::FileChecksums { files }, session.supports(FEATURE_FRAGMENT)).await?;
+                }
+                continue;
+            }
+        };
+        if cancel.is_cancelled() {
+            return cancel_send(conn, cipher).await;
+        }
+        if listen {
+            if let Some(resumable) = receiver_cancel(conn, cipher)? {
+                return Ok(Some(CalledOff { transferred: sent, resumable }));
+            }
+        }
+        
+        sent += chunk.len() as u64;
+        let chunk_msg = Message::Chunk {
+            index: chunk_index,
+            data: chunk,
+        };
+        conn.send(&cipher.encrypt(&chunk_msg.to_bytes()?)?).await?;
+        chunk_index += 1;
+    }
+    
+    events.emit(TransferEvent::Progress {
+        filename: metadata.name.clone(),
+        transferred: sent,
+        total: sent,
+        speed: 0.0,
+    });
+    Ok(None)
+}
+
+/// Send stdin as encrypted chunks of up to `max_chunk_size`, copying it to stdout as it's read
+async fn send_stream(
+    metadata: &FileMetadata,
+    conn: &mut Transport,
+    cipher: &Cipher,
+    max_chunk_size: usize,
+    listen: bool,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<Option<CalledOff>> {
+    let mut chunker = TeeChunker::new(StdinChunker::new(), tokio::io::stdout());
+    let mut controller = ChunkSizeController::new(max_chunk_size);
+    chunker.set_chunk_size(controller.chunk_size());
+    let mut chunk_index = 0u64;
+    let start_time = Instant::now();
+    
+    while let Some(chunk) = chunker.next_chunk().await? {
+        if cancel.is_cancelled() {
+            return cancel_send(conn, cipher).await;
+        }
+        if listen {
+            if let Some(resumable) = receiver_cancel(conn, cipher)? {
+                let transferred = chunker.bytes_read() - chunk.len() as u64;
+                return Ok(Some(CalledOff { transferred, resumable }));
+            }
+        }
+        
+        let chunk_len = chunk.len();
+        let chunk_msg = Message::Chunk {
+            index: chunk_index,
+            data: chunk,
+        };
+        let encrypted_chunk = cipher.encrypt(&chunk_msg.to_bytes()?)?;
+        let send_start = Instant::now();
+        conn.send(&encrypted_chunk).await?;
+        
+        if let Some(chunk_size) = controller.record(chunk_len, send_start.elapsed()) {
+            chunker.set_chunk_size(chunk_size);
+            events.emit(TransferEvent::ChunkSize { chunk_size });
+        }
+        
+        chunk_index += 1;
+        events.emit(TransferEvent::Progress {
+            filename: metadata.name.clone(),
+            transferred: chunker.bytes_read(),
+            total: 0,
+            speed: speed(chunker.bytes_read(), start_time),
+        });
+    }
+    
+    // Don't report success until everything has reached stdout too
+    chunker.finish().await?;
+    Ok(None)
+}
+
+/// Tell the receiver the sender gave up
+async fn cancel_send<T>(conn: &mut Transport, cipher: &Cipher) -> Result<T> {
+    let error_msg = Message::Error {
+        message: "Transfer cancelled by sender".to_string(),
+    };
+    conn.send(&cipher.encrypt(&error_msg.to_bytes()?)?).await?;
+    Err(Cancelled.into())
+}
+
+/// Receive a file, returning the path it was saved to
+pub async fn receive(
+    options: ReceiveOptions,
+    progress: Option<Arc<dyn ProgressCallback>>,
+    cancel: CancellationToken,
+) -> Result<PathBuf> {
+    let events = EventDispatcher::new(progress);
+    let result = match probe_inner(options, None, &events, &cancel).await {
+        Ok(offer) => offer.accept_inner(&events, &cancel).await,
+        Err(e) => Err(e),
+    };
+    events.finish().await;
+    result
+}
+
+/// Receive a file over a transport that's already connected, ignoring the options' connection settings
+pub async fn receive_over(
+    conn: Transport,
+    options: ReceiveOptions,
+    progress: Option<Arc<dyn ProgressCallback>>,
+    cancel: CancellationToken,
+) -> Result<PathBuf> {
+    let events = EventDispatcher::new(progress);
+    let result = match probe_inner(options, Some(conn), &events, &cancel).await {
+        Ok(offer) => offer.accept_inner(&events, &cancel).await,
+        Err(e) => Err(e),
+    };
+    events.finish().await;
+    result
+}
+
+/// Perform the handshake and return the decrypted metadata without accepting the transfer
+///
+/// The sender keeps waiting until the returned `Offer` is accepted or declined.
+pub async fn probe(options: ReceiveOptions) -> Result<Offer> {
+    let events = EventDispatcher::new(None);
+    probe_inner(options, None, &events, &CancellationToken::new()).await
+}
+
+async fn probe_inner(
+    options: ReceiveOptions,
+    conn: Option<Transport>,
+    events: &EventDispatcher,
+    cancel: &CancellationToken,
+) -> Result<Offer> {
+    let mut timer = PhaseTimer::new(Phase::WaitingForPeer);
+    if options.mailbox {
+        mailbox_meets(options.min_protocol)?;
+    }
+    if options.direct_tls && options.relay.is_some() {
+        return Err(anyhow!("--direct-tls is only for direct connections, not ones through a relay"));
+    }
+    // A throwaway certificate for this connection, vouched for once the key exchange is done
+    let tls_identity = if options.direct_tls && conn.is_none() { Some(TlsIdentity::generate()?) } else { None };
+    // Connect to sender (either direct or via relay)
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => tokio::select! {
+            conn = async {
+                let reannounced = || events.emit(TransferEvent::Reannounced { at: SystemTime::now() });
+                let connected = async {
+                    match (&options.relay, options.mailbox) {
+                        (Some(relays), true) => Transport::new_mailbox_receiver(relays, &options.code, options.relay_max_frame_size, reannounced).await,
+                        (None, true) => Err(anyhow!("--mailbox picks an upload up from a relay, so it needs --relay")),
+                        (_, false) => {
+                            Transport::new_receiver(
+                                options.relay.clone(),
+                                &options.code,
+                                options.host.as_ref(),
+                                options.port,
+                                options.happy_eyeballs,
+                                options.relay_max_frame_size,
+                                options.try_direct,
+                                reannounced,
+                            ).await
+                        }
+                    }
+                };
+                let hint = WaitingHint::receiver(&options.code, options.host.as_ref(), options.port, options.relay.as_deref());
+                let mut conn = hint_after(options.hint_delay, connected, || events.emit(TransferEvent::Hint { hint })).await?;
+                conn.try_direct().await;
+                conn.set_timeouts(options.timeouts);
+                match &tls_identity {
+                    Some(identity) => conn.start_tls(TlsRole::Client, identity).await,
+                    None => anyhow::Ok(conn),
+                }
+            } => conn?,
+            _ = cancel.cancelled() => return Err(Cancelled.into()),
+        },
+    };
+    #[cfg(debug_assertions)]
+    if let Some(log_fn) = &options.protocol_log {
+        conn.enable_protocol_debug(log_fn.clone());
+    }
+    events.emit(TransferEvent::Connected { peer: conn.peer_info() });
+    timer.enter(Phase::Handshake);
+    
+    // The sender keeps its chunks to what fits in the memory budget
+    let mut budget = MemoryBudget::new(options.memory_limit);
+    let mut ours = offered_features();
+    ours.insert(format!("{}{}", FEATURE_MAX_CHUNK_PREFIX, budget.max_chunk_size()));
+    // Only a receiver that asked the relay for a stored upload takes one, keyed from the code alone
+    if options.mailbox && conn.mailbox_pickup() {
+        ours.insert(FEATURE_MAILBOX.to_string());
+    }
+    offer_post_quantum(&mut ours, options.pq || options.min_protocol >= protocol::KEY_EXCHANGE_V3)?;
+    // Without hashing there's nothing to vouch for what arrived with, so nothing that needs it is offered
+    match options.checksum {
+        ChecksumChoice::Blake3 => {}
+        ChecksumChoice::Sha256 => {
+            ours.remove(FEATURE_CHECKSUM_BLAKE3);
+        }
+        ChecksumChoice::Off => ours.retain(|tag| ![FEATURE_CHECKSUM_BLAKE3, FEATURE_RECEIPT, FEATURE_CHECKPOINT, FEATURE_FILE_CHECKSUMS].contains(&tag.as_str())),
+    }
+    let session = handshake_offering(&mut conn, ours).await?;
+    require_min_protocol(&mut conn, &session, options.min_protocol).await?;
+    events.emit(TransferEvent::Handshake { session: session.clone() });
+    
+    let cipher = key_exchange(&mut conn, &options.code, &session, Role::Receiver).await?;
+    
+    receive_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+    send_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+    verify_tls_peer(&mut conn, &cipher, tls_identity.as_ref(), events).await?;
+    
+    // Receive metadata, after any probe of the connection, wait for the sender's scheduled start and its preparations
+    let mut reassembler = Reassembler::new(budget.limit().min(MAX_REASSEMBLED_SIZE), REASSEMBLY_TIMEOUT);
+    // The sender may be holding off for a scheduled start or still preparing the file
+    let mut offer = receive_within(&mut conn, &cipher, &mut reassembler, &mut budget, true).await?;
+    loop {
+        match offer {
+            Message::Waiting { starts_in_secs } => {
+                timer.enter(Phase::WaitingForPeer);
+                events.emit(TransferEvent::Scheduled {
+                    starts_in: starts_in_secs.map(Duration::from_secs),
+                });
+            }
+            Message::Preparing { detail } => {
+                timer.enter(Phase::WaitingForPeer);
+                events.emit(TransferEvent::Preparing { detail });
+            }
+            Message::Probe { .. } => estimate::answer(&mut conn, &cipher).await?,
+            _ => break,
+        }
+        offer = tokio::select! {
+            message = receive_within(&mut conn, &cipher, &mut reassembler, &mut budget, true) => message?,
+            _ = cancel.cancelled() => return Err(Cancelled.into()),
+        };
+    }
+    timer.enter(Phase::Metadata);
+    let mut sync_offer = None;
+    let (metadata, streamed) = match offer {
+        Message::SyncOffer { name, entries } => {
+            if !options.sync {
+                let message = format!("{} is being synced, receive it with --sync", name);
+                conn.send(&Message::Error { message: message.clone() }.to_bytes()?).await?;
+                return Err(anyhow!(message));
+            }
+            let metadata = FileMetadata {
+                name,
+                size: entries.iter().map(|entry| entry.size).sum(),
+                is_directory: true,
+                checksum: String::from(NO_CHECKSUM),
+            };
+            sync_offer = Some(entries);
+            (metadata, false)
+        }
+        Message::Metadata { filename, size, is_directory, checksum } => {
+            let metadata = FileMetadata {
+                name: filename,
+                size,
+                is_directory,
+                checksum,
+            };
+            (metadata, false)
+        }
+        Message::StreamMetadata { filename } => {
+            let metadata = FileMetadata {
+                name: filename,
+                size: 0,
+                is_directory: false,
+                checksum: String::new(),
+            };
+            (metadata, true)
+        }
+        Message::Error { message } => return Err(anyhow!("Transfer error: {}", message)),
+        _ => return Err(anyhow!("Expected Metadata message")),
+    };
+    events.emit(TransferEvent::Metadata {
+        filename: metadata.name.clone(),
+        size: metadata.size,
+    });
+    let traffic = Traffic::new((!metadata.is_directory && !streamed).then_some(metadata.size));
+    
+    Ok(Offer {
+        metadata,
+        conn,
+        cipher,
+        reassembler,
+        budget,
+        session,
+        streamed,
+        output: options.output,
+        conflicts: ConflictResolver::new(options.conflict, options.conflict_prompt),
+        metadata_applier: MetadataApplier::new(Box::new(SystemFs), options.strict_metadata),
+        keep_going: options.keep_going,
+        verify_after: options.verify_after,
+        accept_types: options.accept_types,
+        confirm_executable: options.confirm_executable,
+        confirm_offer: options.confirm_offer,
+        reject_larger_than: options.reject_larger_than,
+        tmp_dir: options.tmp_dir,
+        resume: options.resume,
+        identity: options.identity,
+        delta: options.delta,
+        sync_offer,
+        hash_threads: options.hash_threads,
+        ack_chunks: false,
+        pipe_to: options.pipe_to,
+        timer,
+        traffic,
+    })
+}
+
+/// A transfer offered by the sender that hasn't been accepted yet
+pub struct Offer {
+    metadata: FileMetadata,
+    conn: Transport,
+    cipher: Cipher,
+    reassembler: Reassembler,
+    budget: MemoryBudget,
+    session: Session,
+    /// The sender doesn't know how much data is coming
+    streamed: bool,
+    output: Option<PathBuf>,
+    conflicts: ConflictResolver,
+    metadata_applier: MetadataApplier,
+    keep_going: bool,
+    /// Check the file for its receipt once it's all arrived, rather than in a `HashStage` on the way in
+    verify_after: bool,
+    accept_types: Option<AcceptTypes>,
+    confirm_executable: Option<ConfirmPrompt>,
+    confirm_offer: Option<ConfirmPrompt>,
+    reject_larger_than: Option<u64>,
+    tmp_dir: Option<PathBuf>,
+    resume: bool,
+    identity: Option<Arc<IdentityKey>>,
+    delta: bool,
+    /// The sender's files, when it's syncing a folder
+    sync_offer: Option<Vec<ManifestEntry>>,
+    hash_threads: usize,
+    /// The sender asked for each chunk to be answered with a `ChunkAck`
+    ack_chunks: bool,
+    pipe_to: Option<String>,
+    timer: PhaseTimer,
+    /// What the sender has sent so far, for when it stops short
+    traffic: Traffic,
+}
+
+impl Offer {
+    /// Decrypted metadata of the offered file
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+    
+    /// Features agreed with the sender
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+    
+    /// The sender doesn't know how much data is coming, as with `--stdin-passthrough`
+    pub fn is_streamed(&self) -> bool {
+        self.streamed
+    }
+    
+    /// The sender is syncing a folder rather than sending it whole
+    pub fn is_sync(&self) -> bool {
+        self.sync_offer.is_some()
+    }
+    
+    /// Who the offer came from
+    pub fn peer(&self) -> PeerInfo {
+        self.conn.peer_info()
+    }
+    
+    /// Accept the transfer and receive the file
+    pub async fn accept(
+        self,
+        progress: Option<Arc<dyn ProgressCallback>>,
+        cancel: CancellationToken,
+    ) -> Result<PathBuf> {
+        let events = EventDispatcher::new(progress);
+        let result = self.accept_inner(&events, &cancel).await;
+        events.finish().await;
+        result
+    }
+    
+    /// Leave without taking the transfer or turning it down, so the sender waits for another receiver
+    ///
+    /// Returns whether it will: a sender without `FEATURE_DEFER` takes this as
+    /// a decline and gives up.
+    pub async fn defer(mut self) -> Result<bool> {
+        if !self.session.supports(FEATURE_DEFER) {
+            self.decline().await?;
+            return Ok(false);
+        }
+        // Take the peeked chunk first, as for a decline
+        if session_supports_peek(&self.session, &self.metadata, self.streamed) {
+            self.receive_message().await?;
+        }
+        self.conn.send(&Message::Deferred.to_bytes()?).await?;
+        Ok(true)
+    }
+    
+    /// Decline the transfer; the sender fails with a "declined" error
+    pub async fn decline(mut self) -> Result<()> {
+        // Take the peeked chunk first, or closing with it unread could reset the connection
+        // before the sender sees why
+        if session_supports_peek(&self.session, &self.metadata, self.streamed) {
+            self.receive_message().await?;
+        }
+        let decline = Message::Error {
+            message: "Transfer declined by receiver".to_string(),
+        };
+        self.conn.send(&decline.to_bytes()?).await
+    }
+    
+    async fn accept_inner(mut self, events: &EventDispatcher, cancel: &CancellationToken) -> Result<PathBuf> {
+        // Look at how the file starts, when the sender shows us, before taking it
+        let mut pending = if session_supports_peek(&self.session, &self.metadata, self.streamed) {
+            Some(self.receive_message().await?)
+        } else {
+            None
+        };
+        // The sender may be waiting to hear it arrived before anyone looks at it
+        if let Some(Message::Chunk { index, data }) = &pending {
+            if self.ack_chunks {
+                self.ack_chunk(*index, data.len() as u64).await?;
+            }
+        }
+        let first_chunk = match &pending {
+            Some(Message::Chunk { data, .. }) => data.as_slice(),
+            _ => &[],
+        };
+        if let Err(reason) = self.screen(first_chunk) {
+            let decline = Message::Error { message: reason.clone() };
+            self.conn.send(&decline.to_bytes()?).await?;
+            return Err(anyhow!(reason));
+        }
+        
+        // Send ack
+        let ack = Message::Ack;
+        self.conn.send(&ack.to_bytes()?).await?;
+        self.timer.enter(Phase::Transfer);
+        if let Some(command) = self.pipe_to.take() {
+            return self.receive_piped(&command, pending, events, cancel).await;
+        }
+        
+        // Determine output path; a file sent into an existing directory keeps its name
+        let output_path = match self.output.take() {
+            Some(dir) if dir.is_dir() && !self.metadata.is_directory => {
+                let name = Path::new(&self.metadata.name).file_name().ok_or_else(|| anyhow!("Invalid file name: {}", self.metadata.name))?;
+                dir.join(name)
+            }
+            Some(output) => output,
+            None => PathBuf::from(&self.metadata.name),
+        };
+        
+        // A folder being synced only gets the files that differ from ours
+        let sync_plan = match self.sync_offer.take() {
+            Some(theirs) => {
+                self.timer.enter(Phase::Metadata);
+                let plan = self.request_sync(&output_path, theirs, events).await?;
+                self.timer.enter(Phase::Transfer);
+                Some(plan)
+            }
+            None => None,
+        };
+        let mut sync_deletions = Vec::new();
+        let mut file_checksums = None;
+        
+        // Directories arrive as a tar stream, staged and extracted at the end
+        let staging = if self.metadata.is_directory {
+            Some(StagingDir::create(self.tmp_dir.as_deref(), &output_path)?)
+        } else {
+            None
+        };
+        let write_path = match &staging {
+            Some(staging) => staging.path().join("archive.tar"),
+            None => output_path.clone(),
+        };
+        
+        // Progress is saved as we go, so an interrupted or cancelled transfer leaves a record behind.
+        // With --resume, a record of this file and the partial file it describes are picked up from.
+        let resumable = session_supports_resume(&self.session, &self.metadata, self.streamed);
+        let state_file = TransferState::state_path(&output_path, self.tmp_dir.as_deref());
+        if fsutil::tighten(&state_file).unwrap_or(false) {
+            events.emit(TransferEvent::Tightened { path: state_file.clone() });
+        }
+        let earlier = match TransferState::load(&state_file)? {
+            Some(state) if self.resume && resumable && state.bytes_written > 0 && state.matches(&self.metadata.name, self.metadata.size) => {
+                FileWriter::resume(&write_path, self.metadata.size, state.bytes_written, state.next_chunk()).ok().map(|writer| (writer, state))
+            }
+            _ => None,
+        };
+        // With --delta, an existing file at the output is rebuilt rather than sent again
+        let delta_capable = self.session.supports(FEATURE_DELTA) && !self.metadata.is_directory && !self.streamed;
+        if delta_capable && earlier.is_none() {
+            if let Some(signatures) = self.delta_signatures(&output_path)? {
+                return self.receive_delta(&output_path, signatures, resumable, events, cancel).await;
+            }
+        }
+        let (mut writer, mut state) = match earlier {
+            Some((writer, state)) => {
+                events.emit(TransferEvent::Resuming { chunk: state.next_chunk() });
+                (writer, state)
+            }
+            None => {
+                // Tar and stdin streams have no length up front
+                let writer = if self.metadata.is_directory || self.streamed {
+                    FileWriter::streaming(&write_path)?
+                } else {
+                    FileWriter::new(&write_path, self.metadata.size)?
+                };
+                (writer, TransferState::new(&self.metadata.name, self.metadata.size, &self.metadata.checksum))
+            }
+        };
+        if resumable {
+            let resume = Message::Resume {
+                from_chunk: state.next_chunk(),
+                offset: writer.bytes_written(),
+            };
+            self.conn.send(&self.cipher.encrypt(&resume.to_bytes()?)?).await?;
+            if writer.bytes_written() > 0 {
+                // The peeked first chunk is already on disk
+                pending = None;
+            }
+        }
+        if delta_capable {
+            // Nothing to work from, so the sender sends it all
+            let whole = Message::BlockSignatures { block_size: 0, blocks: Vec::new() };
+            send_control(&mut self.conn, &self.cipher, &whole, self.session.supports(FEATURE_FRAGMENT)).await?;
+        }
+        // A receipt's checksum is worked out on the way in, unless it's to be read back once the file's done
+        let mut hash_stage = (self.receipt_wanted() && !self.verify_after)
+            .then(|| HashStage::start(ChecksumAlgorithm::of(&self.metadata.checksum), &write_path, writer.bytes_written()));
+        let start_time = Instant::now();
+        let resumed_from = writer.bytes_written();
+        // Where the next checkpoint's chunks start
+        let mut checkpoint_from = state.next_chunk();
+        
+        // Receive chunks
+        loop {
+            let receive_start = Instant::now();
+            let (chunk_msg, peeked) = match pending.take() {
+                Some(message) => (message, true),
+                None => tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => return self.stop(writer, &mut state, &state_file, resumable, events).await,
+                    message = self.receive_data() => (message?, false),
+                },
+            };
+            self.timer.network(receive_start.elapsed());
+            
+            match chunk_msg {
+                // A copy the sender gave up on turned up after all; the one already written stands
+                Message::Chunk { index, .. } if self.ack_chunks && index < writer.next_chunk() => {
+                    self.ack_chunk(index, writer.bytes_written()).await?;
+                }
+                Message::Chunk { index, data } => {
+                    // Streams and archives don't say how big they are up front
+                    if let Some(limit) = self.reject_larger_than {
+                        if writer.bytes_written() + data.len() as u64 > limit {
+                            return Err(anyhow!(too_large(&self.metadata.name, limit)));
+                        }
+                    }
+                    let write_start = Instant::now();
+                    let written = writer.write_chunk(&data);
+                    self.timer.disk(write_start.elapsed());
+                    if let Err(e) = written {
+                        let kept = resumable.then_some((&mut state, state_file.as_path()));
+                        return Err(self.out_of_space(e, writer.bytes_written(), kept).await);
+                    }
+                    if let Some(stage) = &mut hash_stage {
+                        stage.push(data).await?;
+                    }
+                    state.mark_received(index);
+                    if self.ack_chunks && !peeked {
+                        self.ack_chunk(index, writer.bytes_written()).await?;
+                    }
+                    if state.chunks_received.len() % STATE_SAVE_INTERVAL == 0 {
+                        state.bytes_written = writer.bytes_written();
+                        if let Err(e) = state.save(&state_file) {
+                            let kept = resumable.then_some((&mut state, state_file.as_path()));
+                            return Err(self.out_of_space(e, writer.bytes_written(), kept).await);
+                        }
+                    }
+                    events.emit(TransferEvent::Progress {
+                        filename: self.metadata.name.clone(),
+                        transferred: writer.bytes_written(),
+                        total: self.metadata.size,
+                        speed: speed(writer.bytes_written() - resumed_from, start_time),
+                    });
+                }
+                Message::SyncDelete { paths } if sync_plan.is_some() => sync_deletions = paths,
+                Message::FileChecksums { files } if staging.is_some() => file_checksums = Some(files),
+                Message::PartialChecksum { up_to_chunk, hash } => {
+                    let hash_start = Instant::now();
+                    let verified = writer.verify_partial_checksum(checkpoint_from..up_to_chunk, hash);
+                    self.timer.hashing(hash_start.elapsed());
+                    if let Err(e) = verified {
+                        // Resuming would build on the damaged part, so start over next time
+                        TransferState::cleanup(&state_file)?;
+                        let error = Message::Error { message: e.to_string() };
+                        let _ = self.conn.send(&error.to_bytes()?).await;
+                        return Err(e);
+                    }
+                    checkpoint_from = up_to_chunk;
+                }
+                Message::Complete => {
+                    self.timer.enter(Phase::Finalize);
+                    let received = writer.bytes_written();
+                    writer.finalize()?;
+                    TransferState::cleanup(&state_file)?;
+                    let mut skipped = Vec::new();
+                    if let Some(staging) = &staging {
+                        std::fs::create_dir_all(&output_path)?;
+                        let tar_path = match ArchiveFormat::detect(&write_path)? {
+                            ArchiveFormat::Tar => write_path.clone(),
+                            ArchiveFormat::Zip => {
+                                let repacked = staging.path().join("repacked.tar");
+                                archive::zip_to_tar(&write_path, &repacked)?;
+                                repacked
+                            }
+                        };
+                        if let Some(checksums) = &file_checksums {
+                            let hash_start = Instant::now();
+                            let checked = transfer::check_file_checksums(&tar_path, checksums);
+                            self.timer.hashing(hash_start.elapsed());
+                            if let Err(e) = checked {
+                                // In place of the Ack the sender is waiting for
+                                let error = Message::Error { message: e.to_string() };
+                                let _ = self.conn.send(&error.to_bytes()?).await;
+                                return Err(e);
+                            }
+                        }
+                        let extracted = conflict::extract_with_conflicts(
+                            &tar_path,
+                            &output_path,
+                            staging.path(),
+                            &mut self.conflicts,
+                            &mut self.metadata_applier,
+                            self.keep_going,
+                        );
+                        let extracted = match extracted {
+                            Ok(extracted) => extracted,
+                            // All of it arrived, so there's nothing to resume from
+                            Err(e) => return Err(self.out_of_space(e, received, None).await),
+                        };
+                        if !extracted.resolved.is_empty() {
+                            events.emit(TransferEvent::Conflicts { resolved: extracted.resolved });
+                        }
+                        let warnings = self.metadata_applier.take_warnings();
+                        if !warnings.is_empty() {
+                            events.emit(TransferEvent::MetadataWarnings { warnings });
+                        }
+                        skipped = extracted.skipped;
+                    }
+                    // Files that didn't fit are kept apart from those the plan left out, for the sync's counts
+                    let failed = skipped.len();
+                    if let Some(plan) = &sync_plan {
+                        skipped.splice(0..0, plan.skipped.iter().cloned());
+                    }
+                    let skipped_count = skipped.len();
+                    if !skipped.is_empty() {
+                        events.emit(TransferEvent::Skipped { files: skipped });
+                    }
+                    if let Some(plan) = &sync_plan {
+                        let deleted = sync::delete_remote_only(&output_path, plan, &sync_deletions)?;
+                        events.emit(TransferEvent::Synced {
+                            sent: plan.wanted.len().saturating_sub(failed),
+                            unchanged: plan.unchanged,
+                            skipped: skipped_count,
+                            remote_only: plan.remote_only.len(),
+                            deleted,
+                        });
+                    }
+                    self.timer.enter(Phase::Verification);
+                    self.confirm_complete().await?;
+                    if self.receipt_wanted() {
+                        self.send_receipt(&output_path, hash_stage.take(), events).await?;
+                    }
+                    self.complete(events);
+                    return Ok(output_path);
+                }
+                Message::Error { message } => {
+                    return Err(anyhow!("Transfer error: {}", message));
+                }
+                _ => return Err(anyhow!("Unexpected message type")),
+            }
+        }
+    }
+    
+    /// Called off on this side: keep what arrived for `--resume`, and tell the sender
+    async fn stop(
+        &mut self,
+        writer: FileWriter,
+        state: &mut TransferState,
+        state_file: &Path,
+        resumable: bool,
+        events: &EventDispatcher,
+    ) -> Result<PathBuf> {
+        let transferred = writer.bytes_written();
+        writer.finalize()?;
+        if resumable {
+            state.bytes_written = transferred;
+            state.save(state_file)?;
+        }
+        self.call_off(transferred, resumable, events).await
+    }
+    
+    /// When `error` says the disk or quota is full, after `written` bytes: record them for `--resume` in `kept`, and tell the sender
+    ///
+    /// Any other error is handed back as it is.
+    async fn out_of_space(&mut self, error: anyhow::Error, written: u64, kept: Option<(&mut TransferState, &Path)>) -> anyhow::Error {
+        if !fsutil::ran_out_of_space(&error) {
+            return error;
+        }
+        let resumable = match kept {
+            Some((state, state_file)) => {
+                state.bytes_written = written;
+                // The record may not fit either, unless it's kept on another disk with --tmp-dir
+                state.save(state_file).is_ok()
+            }
+            None => false,
+        };
+        let stopped = OutOfSpace { written, resumable };
+        if let Ok(message) = (Message::Error { message: stopped.for_sender() }).to_bytes() {
+            let _ = self.conn.send(&message).await;
+        }
+        error.context(stopped)
+    }
+    
+    /// Tell the sender we've stopped after `transferred` bytes
+    async fn call_off(&mut self, transferred: u64, resumable: bool, events: &EventDispatcher) -> Result<PathBuf> {
+        // A sender that doesn't know Cancel just sees the connection close, as before
+        if self.session.supports(FEATURE_RESUME) {
+            let stop = Message::Cancel { resumable };
+            // The sender may be gone already; what arrived is kept either way
+            if self.conn.send(&self.cipher.encrypt(&stop.to_bytes()?)?).await.is_ok() {
+                // Take chunks still on their way, so the sender isn't stuck writing
+                // into a full connection before it reads the Cancel and hangs up
+                let drain = async { while self.conn.receive().await.is_ok() {} };
+                let _ = tokio::time::timeout(CANCEL_DRAIN_TIMEOUT, drain).await;
+            }
+        }
+        events.emit(TransferEvent::ReceiverCancelled {
+            transferred,
+            total: self.metadata.size,
+            resumable,
+        });
+        Err(Cancelled.into())
+    }
+    
+    /// Compare the sender's files with ours at `output_path` and ask for the ones that differ
+    ///
+    /// The filesystem the output goes on is probed once for whether it ignores
+    /// case, so names it can't tell apart are compared as it would.
+    async fn request_sync(&mut self, output_path: &Path, theirs: Vec<ManifestEntry>, events: &EventDispatcher) -> Result<SyncPlan> {
+        let progress = |files_done, total_files| events.emit(TransferEvent::Hashing { files_done, total_files });
+        let ours = sync::build_manifest(output_path, self.hash_threads, progress).await?;
+        // The output folder may not exist yet; the nearest folder that does is on the same filesystem, most likely
+        let fold_case = output_path
+            .ancestors()
+            .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+            .find(|dir| dir.is_dir())
+            .is_some_and(conflict::is_case_insensitive);
+        let plan = sync::plan(&theirs, &ours, fold_case);
+        let request = Message::SyncRequest {
+            wanted: plan.wanted.clone(),
+            remote_only: plan.remote_only.clone(),
+        };
+        send_control(&mut self.conn, &self.cipher, &request, self.session.supports(FEATURE_FRAGMENT)).await?;
+        Ok(plan)
+    }
+    
+    /// With `--delta`, signatures of the file already at `output_path`, when it's worth working from
+    fn delta_signatures(&self, output_path: &Path) -> Result<Option<Vec<BlockSignature>>> {
+        if !self.delta {
+            return Ok(None);
+        }
+        match std::fs::metadata(output_path) {
+            Ok(existing) if existing.is_file() && delta::worth_signing(existing.len(), self.metadata.size, DELTA_BLOCK_SIZE) => {
+                let base = std::io::BufReader::new(std::fs::File::open(output_path)?);
+                Ok(Some(delta::block_signatures(base, DELTA_BLOCK_SIZE)?))
+            }
+            _ => Ok(None),
+        }
+    }
+    
+    /// Rebuild the file from our copy at `output_path` and the differences the sender sends
+    ///
+    /// The result is staged and checked against the sender's hash before it
+    /// takes the old copy's place, which is left alone if anything goes wrong.
+    async fn receive_delta(
+        &mut self,
+        output_path: &Path,
+        signatures: Vec<BlockSignature>,
+        resumable: bool,
+        events: &EventDispatcher,
+        cancel: &CancellationToken,
+    ) -> Result<PathBuf> {
+        if resumable {
+            // Nothing to pick up from; a peeked first chunk is set aside too
+            let resume = Message::Resume { from_chunk: 0, offset: 0 };
+            self.conn.send(&self.cipher.encrypt(&resume.to_bytes()?)?).await?;
+        }
+        let blocks = signatures.len() as u64;
+        let signatures = Message::BlockSignatures {
+            block_size: DELTA_BLOCK_SIZE as u32,
+            blocks: signatures,
+        };
+        send_control(&mut self.conn, &self.cipher, &signatures, self.session.supports(FEATURE_FRAGMENT)).await?;
+        
+        let staging = StagingDir::create(self.tmp_dir.as_deref(), output_path)?;
+        let staged = staging.path().join("delta");
+        let base = std::fs::File::open(output_path)?;
+        let mut decoder = DeltaDecoder::new(base, DELTA_BLOCK_SIZE, blocks, std::fs::File::create(&staged)?, self.metadata.size);
+        let start_time = Instant::now();
+        
+        loop {
+            let receive_start = Instant::now();
+            let message = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return self.call_off(decoder.written(), false, events).await,
+                message = self.receive_message() => message?,
+            };
+            self.timer.network(receive_start.elapsed());
+            match message {
+                Message::Delta { op } => {
+                    let write_start = Instant::now();
+                    let applied = decoder.apply(op);
+                    self.timer.disk(write_start.elapsed());
+                    if let Err(e) = applied {
+                        let error = Message::Error { message: e.to_string() };
+                        let _ = self.conn.send(&error.to_bytes()?).await;
+                        return Err(e);
+                    }
+                    events.emit(TransferEvent::Progress {
+                        filename: self.metadata.name.clone(),
+                        transferred: decoder.written(),
+                        total: self.metadata.size,
+                        speed: speed(decoder.written(), start_time),
+                    });
+                }
+                Message::Complete if decoder.is_finished() => {
+                    self.timer.enter(Phase::Finalize);
+                    let reused = decoder.copied();
+                    drop(decoder);
+                    delta::replace_with(&staged, output_path)?;
+                    events.emit(TransferEvent::Delta { reused, total: self.metadata.size });
+                    self.timer.enter(Phase::Verification);
+                    self.confirm_complete().await?;
+                    if self.receipt_wanted() {
+                        self.send_receipt(output_path, None, events).await?;
+                    }
+                    self.complete(events);
+                    return Ok(output_path.to_path_buf());
+                }
+                Message::Error { message } => {
+                    return Err(anyhow!("Transfer error: {}", message));
+                }
+                _ => return Err(anyhow!("Unexpected message type")),
+            }
+        }
+    }
+    
+    /// Write what arrives into `command` instead of a file, for `--pipe-to`
+    ///
+    /// Nothing is kept to resume from, and a command that stops reading early
+    /// or exits with an error fails the transfer on both sides.
+    async fn receive_piped(
+        &mut self,
+        command: &str,
+        mut pending: Option<Message>,
+        events: &EventDispatcher,
+        cancel: &CancellationToken,
+    ) -> Result<PathBuf> {
+        let mut sink = PipeSink::spawn(command)?;
+        if session_supports_resume(&self.session, &self.metadata, self.streamed) {
+            // Always from the start; the peeked first chunk is the first thing the command gets
+            let resume = Message::Resume { from_chunk: 0, offset: 0 };
+            self.conn.send(&self.cipher.encrypt(&resume.to_bytes()?)?).await?;
+        }
+        if self.session.supports(FEATURE_DELTA) && !self.metadata.is_directory && !self.streamed {
+            // There's no earlier copy to rebuild from
+            let whole = Message::BlockSignatures { block_size: 0, blocks: Vec::new() };
+            send_control(&mut self.conn, &self.cipher, &whole, self.session.supports(FEATURE_FRAGMENT)).await?;
+        }
+        // Only regular files have a size to hold the sender to
+        let expected = (!self.metadata.is_directory && !self.streamed).then_some(self.metadata.size);
+        let mut receipt_hasher = self.receipt_wanted().then(|| ChecksumAlgorithm::of(&self.metadata.checksum).hasher());
+        let mut checkpoint = blake3::Hasher::new();
+        let (mut next_chunk, mut checkpoint_from) = (0, 0);
+        let start_time = Instant::now();
+        
+        loop {
+            let receive_start = Instant::now();
+            let (message, peeked) = match pending.take() {
+                Some(message) => (message, true),
+                None => tokio::select! {
+                    biased;
+                    // Dropping the sink stops the command
+                    _ = cancel.cancelled() => return self.call_off(sink.bytes_written(), false, events).await,
+                    message = self.receive_data() => (message?, false),
+                },
+            };
+            self.timer.network(receive_start.elapsed());
+            match message {
+                Message::Chunk { index, .. } if self.ack_chunks && index < next_chunk => {
+                    self.ack_chunk(index, sink.bytes_written()).await?;
+                }
+                Message::Chunk { index, data } => {
+                    let received = sink.bytes_written() + data.len() as u64;
+                    if let Some(expected) = expected.filter(|&expected| received > expected) {
+                        return Err(transfer::TransferError::DataExceedsExpectedSize { expected, received }.into());
+                    }
+                    if let Some(limit) = self.reject_larger_than.filter(|&limit| received > limit) {
+                        return Err(anyhow!(too_large(&self.metadata.name, limit)));
+                    }
+                    let write_start = Instant::now();
+                    let written = sink.write(&data).await;
+                    self.timer.disk(write_start.elapsed());
+                    if let Err(e) = written {
+                        let error = Message::Error { message: e.to_string() };
+                        let _ = self.conn.send(&error.to_bytes()?).await;
+                        return Err(e);
+                    }
+                    checkpoint.update(&data);
+                    if let Some(hasher) = &mut receipt_hasher {
+                        let started = Instant::now();
+                        hasher.update(&data);
+                        self.timer.hashing(started.elapsed());
+                    }
+                    next_chunk = index + 1;
+                    if self.ack_chunks && !peeked {
+                        self.ack_chunk(index, sink.bytes_written()).await?;
+                    }
+                    events.emit(TransferEvent::Progress {
+                        filename: self.metadata.name.clone(),
+                        transferred: sink.bytes_written(),
+                        total: self.metadata.size,
+                        speed: speed(sink.bytes_written(), start_time),
+                    });
+                }
+                Message::PartialChecksum { up_to_chunk, hash } => {
+                    // The command has had the damaged part already; all that's left is to stop it
+                    if *checkpoint.finalize().as_bytes() != hash {
+                        let e = anyhow::Error::from(transfer::TransferError::ChecksumMismatch { from: checkpoint_from, to: up_to_chunk });
+                        let error = Message::Error { message: e.to_string() };
+                        let _ = self.conn.send(&error.to_bytes()?).await;
+                        return Err(e);
+                    }
+                    checkpoint.reset();
+                    checkpoint_from = up_to_chunk;
+                }
+                Message::Complete => {
+                    self.timer.enter(Phase::Finalize);
+                    if let Err(e) = sink.finish().await {
+                        let error = Message::Error { message: e.to_string() };
+                        let _ = self.conn.send(&error.to_bytes()?).await;
+                        return Err(e);
+                    }
+                    self.timer.enter(Phase::Verification);
+                    self.confirm_complete().await?;
+                    if let Some(hasher) = receipt_hasher.take() {
+                        self.send_receipt_for(&hasher.checksum(), events).await?;
+                    }
+                    self.complete(events);
+                    return Ok(PathBuf::new());
+                }
+                Message::Error { message } => {
+                    return Err(anyhow!("Transfer error: {}", message));
+                }
+                _ => return Err(anyhow!("Unexpected message type")),
+            }
+        }
+    }
+    
+    /// Report how long each phase took, then that the transfer is done
+    fn complete(&mut self, events: &EventDispatcher) {
+        events.emit(TransferEvent::Memory {
+            limit: self.budget.limit(),
+            peak: self.budget.peak(),
+        });
+        events.emit(TransferEvent::Timings { timings: self.timer.timings() });
+        events.emit(TransferEvent::Complete);
+    }
+    
+    /// Tell a sender that waits for it that everything arrived and was dealt with
+    async fn confirm_complete(&mut self) -> Result<()> {
+        if self.session.supports(FEATURE_COMPLETE_ACK) {
+            self.conn.send(&Message::Ack.to_bytes()?).await?;
+        }
+        Ok(())
+    }
+    
+    /// The sender's next message, other than turning `ChunkAck`s on or off
+    async fn receive_message(&mut self) -> Result<Message> {
+        loop {
+            let message = receive_within(&mut self.conn, &self.cipher, &mut self.reassembler, &mut self.budget, false).await?;
+            match &message {
+                Message::Chunk { index, data } => self.traffic.chunk(Some(*index), data.len()),
+                _ => self.traffic.frame(),
+            }
+            match message {
+                Message::AckChunks { enabled } => self.ack_chunks = enabled,
+                message => return Ok(message),
+            }
+        }
+    }
+    
+    /// Like `receive_message` while the data is arriving, saying how far it got if the connection ends first
+    async fn receive_data(&mut self) -> Result<Message> {
+        match self.receive_message().await {
+            Ok(message) => Ok(message),
+            Err(e) => Err(self.traffic.lost(e, false, !self.conn.is_relayed())),
+        }
+    }
+    
+    /// Tell the sender chunk `index` arrived and the file runs to `bytes_written`
+    async fn ack_chunk(&mut self, index: u64, bytes_written: u64) -> Result<()> {
+        let ack = Message::ChunkAck { index, bytes_written };
+        self.conn.send(&self.cipher.encrypt(&ack.to_bytes()?)?).await
+    }
+    
+    /// Whether the sender filled in the file's checksum, which is how it asks for a receipt
+    fn receipt_wanted(&self) -> bool {
+        self.session.supports(FEATURE_RECEIPT)
+            && !self.metadata.is_directory
+            && !self.streamed
+            && self.metadata.checksum != NO_CHECKSUM
+    }
+    
+    /// Check the finished file at `path` against the sender's checksum, then send a receipt for it
+    ///
+    /// The checksum comes from the `stage` that hashed the file on the way in,
+    /// or without one from reading it back. Either way it's only compared now,
+    /// with the whole file in place, and a mismatch fails the same way.
+    async fn send_receipt(&mut self, path: &Path, stage: Option<HashStage>, events: &EventDispatcher) -> Result<()> {
+        let hash_start = Instant::now();
+        let checksum = match stage {
+            Some(stage) => stage.finish().await.map(|(checksum, busy)| {
+                self.timer.hashing(busy);
+                checksum
+            }),
+            None => {
+                let algorithm = ChecksumAlgorithm::of(&self.metadata.checksum);
+                let checksum = match tokio::fs::File::open(path).await {
+                    Ok(file) => crypto::checksum_stream(file, algorithm, CHUNK_SIZE).await,
+                    Err(e) => Err(e.into()),
+                };
+                self.timer.hashing(hash_start.elapsed());
+                checksum
+            }
+        };
+        match checksum {
+            Ok(checksum) => self.send_receipt_for(&checksum, events).await,
+            Err(e) => {
+                let error = Message::Error {
+                    message: format!("Couldn't check {} arrived whole: {}", self.metadata.name, e),
+                };
+                let _ = self.conn.send(&self.cipher.encrypt(&error.to_bytes()?)?).await;
+                Err(e)
+            }
+        }
+    }
+    
+    /// Send a receipt for contents with `checksum`, if they're what the sender sent
+    async fn send_receipt_for(&mut self, checksum: &str, events: &EventDispatcher) -> Result<()> {
+        if checksum != self.metadata.checksum {
+            let damaged = transfer::TransferError::FileChecksumMismatch {
+                name: self.metadata.name.clone(),
+                expected: self.metadata.checksum.clone(),
+                actual: checksum.to_string(),
+            };
+            let error = Message::Error { message: damaged.to_string() };
+            // Failing here matters more than the sender hearing why
+            let _ = self.conn.send(&self.cipher.encrypt(&error.to_bytes()?)?).await;
+            return Err(damaged.into());
+        }
+        let receipt = Receipt::new(checksum, self.identity.as_deref())?;
+        let message = Message::from(receipt.clone());
+        self.conn.send(&self.cipher.encrypt(&message.to_bytes()?)?).await?;
+        events.emit(TransferEvent::Receipt { receipt, saved_to: None });
+        Ok(())
+    }
+    
+    /// Check the file against the receiver's guardrails, returning why it's refused
+    fn screen(&self, first_chunk: &[u8]) -> Result<(), String> {
+        if self.pipe_to.is_some() && self.sync_offer.is_some() {
+            return Err(format!("{} is being synced, which needs a folder to sync into rather than a command", self.metadata.name));
+        }
+        if let Some(limit) = self.reject_larger_than {
+            if self.metadata.size > limit {
+                return Err(too_large(&self.metadata.name, limit));
+            }
+        }
+        if let Some(accept_types) = &self.accept_types {
+            if self.metadata.is_directory {
+                return Err(format!("{} is a directory, which can't be checked against the accepted file types", self.metadata.name));
+            }
+            accept_types.check(&self.metadata.name, first_chunk)?;
+        }
+        if let Some(prompt) = &self.confirm_executable {
+            if let Some(warning) = filetype::executable_warning(&self.metadata.name, first_chunk) {
+                // Confirming the warning accepts the offer too, so there's only one question
+                return match prompt.confirm(&warning) {
+                    true => Ok(()),
+                    false => Err("Transfer declined by receiver".to_string()),
+                };
+            }
+        }
+        if let Some(prompt) = &self.confirm_offer {
+            if !prompt.confirm(&self.describe(first_chunk)) {
+                return Err("Transfer declined by receiver".to_string());
+            }
+        }
+        Ok(())
+    }
+    
+    /// One line about the offered file, for the accept prompt
+    fn describe(&self, first_chunk: &[u8]) -> String {
+        let size = if self.streamed {
+            "size unknown".to_string()
+        } else {
+            format!("{} bytes", self.metadata.size)
+        };
+        let kind = if self.metadata.is_directory {
+            "directory"
+        } else {
+            filetype::sniff(first_chunk).map_or("type not recognised", |kind| kind.name)
+        };
+        format!("{} ({}, {})", self.metadata.name, size, kind)
+    }
+}
+
+/// The features we offer, as either role
+///
+/// Both say how big a message they read (`FEATURE_MAX_FRAME_PREFIX`).
+/// `FEATURE_MAILBOX` is never among them: a mailbox upload offers it, and a
+/// receiver only offers it back once it has asked a relay for one.
+fn offered_features() -> HashSet<String> {
+    let mut features = protocol::local_features();
+    features.insert(format!("{}{}", FEATURE_MAX_FRAME_PREFIX, network::MAX_MESSAGE_SIZE));
+    features
+}
+
+/// Add `FEATURE_KEM_ML_KEM_768` to `features` when `pq` asks for it, failing if this build can't do it
+fn offer_post_quantum(features: &mut HashSet<String>, pq: bool) -> Result<()> {
+    if !pq {
+        return Ok(());
+    }
+    if !cfg!(feature = "pq") {
+        return Err(anyhow!("This zap was built without post-quantum key exchange (the pq feature), run without --pq"));
+    }
+    features.insert(FEATURE_KEM_ML_KEM_768.to_string());
+    Ok(())
+}
+
+/// Refuse a mailbox upload or pickup, which is keyed from the code alone, under a floor above v1
+fn mailbox_meets(min_protocol: u8) -> Result<()> {
+    if min_protocol <= protocol::KEY_EXCHANGE_V1 {
+        return Ok(());
+    }
+    let too_old = PeerTooOld { peer: protocol::KEY_EXCHANGE_V1, required: min_protocol };
+    let context = format!("Mailbox transfers are keyed from the code alone (v1), below --min-protocol {}", min_protocol);
+    Err(anyhow::Error::new(too_old).context(context))
+}
+
+/// Stop with `PeerTooOld`, telling the peer why in plaintext, when `session` agreed on a key exchange below `min_protocol`
+async fn require_min_protocol(conn: &mut Transport, session: &Session, min_protocol: u8) -> Result<()> {
+    let agreed = protocol::key_exchange_strength(session);
+    if agreed >= min_protocol {
+        return Ok(());
+    }
+    let mut message = format!("The other side requires key exchange v{} or newer (--min-protocol), and this one is v{}", min_protocol, agreed);
+    if agreed == protocol::KEY_EXCHANGE_V2 && min_protocol == protocol::KEY_EXCHANGE_V3 {
+        message.push_str("; try again with --pq");
+    }
+    conn.send(&Message::Error { message }.to_bytes()?).await?;
+    // Take the peer's KeyExchange first, or hanging up with it unread can reset the connection before the error's read
+    let _ = tokio::time::timeout(Duration::from_secs(5), conn.receive()).await;
+    Err(PeerTooOld { peer: agreed, required: min_protocol }.into())
+}
+
+/// Exchange Hello and Capabilities messages offering `ours`, check protocol versions and agree on features
+async fn handshake_offering(conn: &mut Transport, ours: HashSet<String>) -> Result<Session> {
+    send_hello(conn, ours.clone()).await?;
+    
+    // Receive hello
+    let response = conn.receive().await.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if matches!(io.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset) => HungUp.into(),
+        _ => e,
+    })?;
+    let response_msg = Message::from_bytes(&response)?;
+    match response_msg {
+        Message::Hello { version } => {
+            if version != protocol::PROTOCOL_VERSION {
+                return Err(anyhow!("Protocol version mismatch"));
+            }
+        }
+        _ => return Err(anyhow!("Expected Hello message")),
+    }
+    
+    let response = conn.receive().await?;
+    let theirs = match Message::from_bytes(&response)? {
+        Message::Capabilities { features } => features,
+        _ => return Err(anyhow!("Expected Capabilities message")),
+    };
+    
+    Ok(Session::negotiated(ours, theirs))
+}
+
+/// Send an encrypted control message, in `Fragment`s if it's too big to go whole
+///
+/// Only splits when the peer agreed to `FEATURE_FRAGMENT`; otherwise it's sent
+/// as it is and may be refused for its size, as before.
+async fn send_control(conn: &mut Transport, cipher: &Cipher, message: &Message, fragment: bool) -> Result<()> {
+    let bytes = message.to_bytes()?;
+    if !fragment || bytes.len() <= FRAGMENT_SIZE {
+        return conn.send(&cipher.encrypt(&bytes)?).await;
+    }
+    let id = NEXT_FRAGMENT_ID.fetch_add(1, Ordering::Relaxed);
+    for piece in fragment::split(&bytes, FRAGMENT_SIZE, id) {
+        conn.send(&cipher.encrypt(&piece.to_bytes()?)?).await?;
+    }
+    Ok(())
+}
+
+/// Receive an encrypted message, putting fragmented ones back together
+///
+/// These are answers the peer may have to work out first, like its hashes of
+/// a folder, so the read timeout only starts once the message does.
+async fn receive_control(conn: &mut Transport, cipher: &Cipher, reassembler: &mut Reassembler) -> Result<Message> {
+    receive_within(conn, cipher, reassembler, &mut MemoryBudget::new(usize::MAX), true).await
+}
+
+/// Like `receive_control`, refusing a message that would take more memory than `budget` allows
+///
+/// A `patient` wait for the message to start isn't cut short by the read timeout.
+async fn receive_within(conn: &mut Transport, cipher: &Cipher, reassembler: &mut Reassembler, budget: &mut MemoryBudget, patient: bool) -> Result<Message> {
+    loop {
+        let data = if patient { conn.wait_for_message().await? } else { conn.receive().await? };
+        budget.hold(data.len(), reassembler.buffered())?;
+        let (id, index, total, data) = match Message::from_bytes(&cipher.decrypt(&data)?)? {
+            Message::Fragment { id, index, total, data } => (id, index, total, data),
+            message => return Ok(message),
+        };
+        if let Some(whole) = reassembler.push(id, index, total, data)? {
+            return match Message::from_bytes(&whole)? {
+                Message::Fragment { .. } => Err(anyhow!("Fragmented message contained another fragment")),
+                message => Ok(message),
+            };
+        }
+    }
+}
+
+/// Agree on the session key with SPAKE2, refusing a peer that can't
+///
+/// The PAKE's identities and salts come from the negotiated key exchange
+/// version; see `protocol::pake_suite`. Both handshake offers go into the
+/// transcript, so one changed on the way breaks key confirmation. A mailbox
+/// upload has nobody to run a PAKE with and uses `Cipher::for_mailbox`; that
+/// is only agreed with a receiver that asked its relay for a stored upload
+/// (`ReceiveOptions::mailbox`), never on a direct connection or as a fallback.
+/// With `FEATURE_KEM_ML_KEM_768` agreed, an ML-KEM secret is mixed in too.
+async fn key_exchange(conn: &mut Transport, code: &str, session: &Session, role: Role) -> Result<Cipher> {
+    if session.supports(FEATURE_MAILBOX) {
+        return Cipher::for_mailbox(code);
+    }
+    let suite = match protocol::key_exchange_version(session).map(protocol::pake_suite) {
+        Ok(Some(suite)) => suite,
+        Ok(None) => return Err(anyhow!("No key exchange agreed with the other side")),
+        Err(e) => {
+            // An older peer waits for our key confirmation, so tell it why there won't be one
+            conn.send(&Message::Error { message: e.to_string() }.to_bytes()?).await?;
+            return Err(e);
+        }
+    };
+    let (ours, theirs) = session.offered();
+    let exchange = match role {
+        Role::Sender => KeyExchange::new_sender(code, suite, Offers { sender: ours.clone(), receiver: theirs.clone() }),
+        Role::Receiver => KeyExchange::new_receiver(code, suite, Offers { sender: theirs.clone(), receiver: ours.clone() }),
+    };
+    let ours = Message::KeyExchange { data: exchange.outbound_message() };
+    conn.send(&ours.to_bytes()?).await?;
+    let theirs = match Message::from_bytes(&conn.receive().await?)? {
+        Message::KeyExchange { data } => data,
+        Message::Error { message } => return Err(anyhow!("Transfer error: {}", message)),
+        _ => return Err(anyhow!("Expected KeyExchange message")),
+    };
+    if !session.supports(FEATURE_KEM_ML_KEM_768) {
+        return exchange.finish(&theirs);
+    }
+    let kem = kem_exchange(conn, role).await?;
+    exchange.finish_hybrid(&theirs, &kem)
+}
+
+/// Run ML-KEM-768 alongside the PAKE: the receiver sends a fresh public key, and the sender a secret encapsulated to it
+///
+/// Both go in plaintext; the secret is only good for the key, which the
+/// PAKE's key confirmation then checks as usual.
+#[cfg(feature = "pq")]
+async fn kem_exchange(conn: &mut Transport, role: Role) -> Result<KemShare> {
+    match role {
+        Role::Receiver => {
+            let pair = crypto::KemKeyPair::generate();
+            conn.send(&Message::KemPublicKey { key: pair.public_key().to_vec() }.to_bytes()?).await?;
+            match Message::from_bytes(&conn.receive().await?)? {
+                Message::KemCiphertext { ciphertext } => pair.decapsulate(&ciphertext),
+                _ => Err(anyhow!("Expected KemCiphertext message")),
+            }
+        }
+        Role::Sender => {
+            let key = match Message::from_bytes(&conn.receive().await?)? {
+                Message::KemPublicKey { key } => key,
+                _ => return Err(anyhow!("Expected KemPublicKey message")),
+            };
+            let kem = crypto::encapsulate(&key)?;
+            conn.send(&Message::KemCiphertext { ciphertext: kem.ciphertext.clone() }.to_bytes()?).await?;
+            Ok(kem)
+        }
+    }
+}
+
+/// Never agreed on without the `pq` feature, since `offer_post_quantum` won't offer it
+#[cfg(not(feature = "pq"))]
+async fn kem_exchange(_conn: &mut Transport, _role: Role) -> Result<KemShare> {
+    Err(anyhow!("Agreed on a post-quantum key exchange this build can't do"))
+}
+
+/// Send an encrypted token proving we hold the session key
+async fn send_key_confirm(conn: &mut Transport, cipher: &Cipher, context: &[u8]) -> Result<()> {
+    let confirm = Message::KeyConfirm {
+        token: cipher.confirmation_token(context),
+    };
+    conn.send(&cipher.encrypt(&confirm.to_bytes()?)?).await
+}
+
+/// Check the peer's key confirmation, telling it in plaintext if it doesn't match
+async fn receive_key_confirm(conn: &mut Transport, cipher: &Cipher, context: &[u8]) -> Result<()> {
+    let data = conn.receive().await?;
+    let confirmed = match cipher.decrypt(&data) {
+        Ok(bytes) => matches!(
+            Message::from_bytes(&bytes),
+            Ok(Message::KeyConfirm { token }) if cipher.verify_confirmation(context, &token)
+        ),
+        Err(_) => {
+            // A peer that couldn't decrypt our confirmation says so unencrypted
+            if let Ok(Message::Error { message }) = Message::from_bytes(&data) {
+                return Err(anyhow!("Transfer error: {}", message));
+            }
+            false
+        }
+    };
+    
+    if !confirmed {
+        let error = Message::Error {
+            message: WRONG_CODE.to_string(),
+        };
+        conn.send(&error.to_bytes()?).await?;
+        return Err(anyhow!(WRONG_CODE));
+    }
+    Ok(())
+}
+
+/// Over `--direct-tls`, vouch for our certificate under the session key and check the peer's against the one it vouches for
+///
+/// Someone in the middle has to show each side a certificate of its own,
+/// and can't vouch for it to either without the code.
+async fn verify_tls_peer(conn: &mut Transport, cipher: &Cipher, identity: Option<&TlsIdentity>, events: &EventDispatcher) -> Result<()> {
+    let Some(identity) = identity else {
+        return Ok(());
+    };
+    let actual = conn.peer_cert_fingerprint().ok_or_else(|| anyhow!("The peer didn't show a TLS certificate"))?;
+    let ours = identity.fingerprint();
+    conn.send(&cipher.encrypt(&Message::TlsFingerprint { fingerprint: ours }.to_bytes()?)?).await?;
+    let expected = match Message::from_bytes(&cipher.decrypt(&conn.receive().await?)?)? {
+        Message::TlsFingerprint { fingerprint } => fingerprint,
+        Message::Error { message } => return Err(anyhow!("Transfer error: {}", message)),
+        _ => return Err(anyhow!("Expected the peer's TLS certificate fingerprint")),
+    };
+    if expected != actual {
+        return Err(CryptoError::CertificateMismatch { expected, actual }.into());
+    }
+    events.emit(TransferEvent::TlsVerified { ours, peer: actual });
+    Ok(())
+}
+
+/// Send our Hello and Capabilities, offering `features`, without waiting for the peer's
+async fn send_hello(conn: &mut Transport, features: HashSet<String>) -> Result<()> {
+    let hello = Message::Hello { version: protocol::PROTOCOL_VERSION };
+    conn.send(&hello.to_bytes()?).await?;
+    
+    let capabilities = Message::Capabilities { features };
+    conn.send(&capabilities.to_bytes()?).await
+}
+
+fn too_large(name: &str, limit: u64) -> String {
+    format!("{} is larger than the receiver accepts ({} bytes)", name, limit)
+}
+
+/// Average transfer speed in bytes per second
+fn speed(bytes: u64, start_time: Instant) -> f64 {
+    let elapsed = start_time.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        bytes as f64 / elapsed
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    
+    /// `handshake_offering` our usual features
+    async fn handshake(conn: &mut Transport) -> Result<Session> {
+        handshake_offering(conn, offered_features()).await
+    }
+    
+    fn write_fixture(dir: &TempDir, len: usize) -> PathBuf {
+        let path = dir.path().join("input.bin");
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+    
+    fn receive_options(code: &str, port: u16, output: PathBuf) -> ReceiveOptions {
+        ReceiveOptions {
+            output: Some(output),
+            host: Some("127.0.0.1".parse().unwrap()),
+            port: Some(port),
+            ..ReceiveOptions::new(code)
+        }
+    }
+    
+    async fn start_sender(options: SendOptions) -> tokio::task::JoinHandle<Result<()>> {
+        let handle = tokio::spawn(send(options, None, CancellationToken::new()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        handle
+    }
+    
+    #[tokio::test]
+    async fn test_probe_then_decline() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 1000);
+        let sender = start_sender(SendOptions {
+            port: Some(19101),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        }).await;
+        
+        let output = dir.path().join("output.bin");
+        let offer = probe(receive_options("alpha-bravo-charlie", 19101, output.clone())).await.unwrap();
+        assert_eq!(offer.metadata().name, "input.bin");
+        assert_eq!(offer.metadata().size, 1000);
+        offer.decline().await.unwrap();
+        
+        let err = sender.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("declined"));
+        assert!(!output.exists());
+    }
+    
+    #[tokio::test]
+    async fn test_deferred_offer_waits_for_next_receiver() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 200_000);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let options = SendOptions {
+            port: Some(19109),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let sender = tokio::spawn(send(options, Some(Arc::new(callback)), CancellationToken::new()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        
+        // A peek, then the real receiver on the same port
+        let output = dir.path().join("output.bin");
+        let offer = probe(receive_options("alpha-bravo-charlie", 19109, output.clone())).await.unwrap();
+        assert!(!offer.is_streamed() && !offer.is_sync());
+        assert!(offer.defer().await.unwrap());
+        assert!(!output.exists());
+        
+        let offer = probe(receive_options("alpha-bravo-charlie", 19109, output.clone())).await.unwrap();
+        offer.accept(None, CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        let seen = seen.lock().unwrap();
+        let listening = seen.iter().filter(|e| matches!(e, TransferEvent::Listening { .. })).count();
+        let deferred = seen.iter().filter(|e| matches!(e, TransferEvent::Deferred)).count();
+        assert_eq!((listening, deferred), (1, 1));
+        assert_eq!(seen.last(), Some(&TransferEvent::Complete));
+    }
+    
+    #[tokio::test]
+    async fn test_waiting_sender_hints_its_port_and_address() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 1000);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let callback = move |event: &TransferEvent| {
+            let _ = tx.send(event.clone());
+        };
+        let cancel = CancellationToken::new();
+        let options = SendOptions {
+            port: Some(0),
+            hint_delay: Duration::from_millis(200),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let sender = tokio::spawn(send(options, Some(Arc::new(callback)), cancel.clone()));
+        
+        let mut port = None;
+        let hint = loop {
+            match rx.recv().await.unwrap() {
+                TransferEvent::Listening { port: bound } => port = Some(bound),
+                TransferEvent::Hint { hint } => break hint,
+                _ => {}
+            }
+        };
+        // The port the OS picked and the address found for this computer, ready to paste
+        let port = port.unwrap();
+        let host = match network::lan_address() {
+            Some(address) => format!("{}:{}", address, port),
+            None => format!("<this computer's address>:{}", port),
+        };
+        assert!(hint.summary.ends_with(&format!("zap receive alpha-bravo-charlie --host {}", host)), "{}", hint.summary);
+        assert!(hint.steps[0].contains(&format!("port {}", port)), "{:?}", hint.steps);
+        
+        cancel.cancel();
+        assert!(sender.await.unwrap().unwrap_err().is::<Cancelled>());
+    }
+    
+    #[tokio::test]
+    async fn test_sender_outlasts_another_zap_checking_its_port() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 1000);
+        let sender = start_sender(SendOptions {
+            port: Some(19110),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        }).await;
+        
+        // A second sender on the same port finds this one and leaves it be
+        let err = network::bind(Some(19110)).await.unwrap_err();
+        assert!(err.to_string().contains("Another zap is already listening on port 19110"), "{}", err);
+        
+        let output = dir.path().join("output.bin");
+        receive(receive_options("alpha-bravo-charlie", 19110, output.clone()), None, CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_small_memory_limit_gets_smaller_chunks() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 6 * 1024 * 1024);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let options = SendOptions {
+            port: Some(19111),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let sender = tokio::spawn(send(options, Some(Arc::new(callback)), CancellationToken::new()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let output = dir.path().join("output.bin");
+        let options = ReceiveOptions {
+            memory_limit: transfer::MIN_MEMORY_LIMIT,
+            ..receive_options("alpha-bravo-charlie", 19111, output.clone())
+        };
+        receive(options, Some(Arc::new(callback)), CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        let max_chunk_size = MemoryBudget::new(transfer::MIN_MEMORY_LIMIT).max_chunk_size();
+        assert!(sent.lock().unwrap().contains(&TransferEvent::PeerLimited { chunk_size: max_chunk_size, assumed: false }));
+        for event in sent.lock().unwrap().iter() {
+            if let TransferEvent::ChunkSize { chunk_size } = event {
+                assert!(*chunk_size <= max_chunk_size, "{} > {}", chunk_size, max_chunk_size);
+            }
+        }
+        let memory = seen.lock().unwrap().iter().find_map(|event| match event {
+            TransferEvent::Memory { limit, peak } => Some((*limit, *peak)),
+            _ => None,
+        });
+        let (limit, peak) = memory.unwrap();
+        assert_eq!(limit, transfer::MIN_MEMORY_LIMIT);
+        assert!(peak > 0 && peak <= limit);
+    }
+    
+    #[tokio::test]
+    async fn test_probe_then_accept() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 200_000);
+        let sender = start_sender(SendOptions {
+            port: Some(19102),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        }).await;
+        
+        let output = dir.path().join("output.bin");
+        let offer = probe(receive_options("alpha-bravo-charlie", 19102, output.clone())).await.unwrap();
+        assert_eq!(offer.session().features(), &protocol::local_features());
+        
+        // Left behind by an earlier attempt that got two chunks in; without --resume it starts over
+        let mut earlier = TransferState::new("input.bin", 200_000, "");
+        earlier.mark_received(0);
+        earlier.mark_received(1);
+        earlier.save(&TransferState::state_path(&output, None)).unwrap();
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let saved = offer.accept(Some(Arc::new(callback)), CancellationToken::new()).await.unwrap();
+        
+        sender.await.unwrap().unwrap();
+        assert_eq!(saved, output);
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        assert!(!TransferState::state_path(&output, None).exists());
+        
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.last(), Some(&TransferEvent::Complete));
+        assert!(seen.iter().any(|e| matches!(e, TransferEvent::Progress { transferred: 200_000, .. })));
+        assert!(!seen.iter().any(|e| matches!(e, TransferEvent::Resuming { .. })));
+    }
+    
+    #[tokio::test]
+    async fn test_file_into_existing_directory_keeps_its_name() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 1000);
+        let sender = start_sender(SendOptions {
+            port: Some(19108),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        }).await;
+        
+        let downloads = dir.path().join("downloads");
+        std::fs::create_dir(&downloads).unwrap();
+        let saved = receive(receive_options("alpha-bravo-charlie", 19108, downloads.clone()), None, CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(saved, downloads.join("input.bin"));
+        assert_eq!(std::fs::read(&saved).unwrap(), std::fs::read(&input).unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_cancelled_receive() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 200_000);
+        let sender = start_sender(SendOptions {
+            port: Some(19103),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        }).await;
+        
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let output = dir.path().join("output.bin");
+        let offer = probe(receive_options("alpha-bravo-charlie", 19103, output)).await.unwrap();
+        let err = offer.accept(None, cancel).await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+        
+        sender.abort();
+    }
+    
+    /// Carries messages between the two ends like a connection that stalls partway
+    ///
+    /// Once `stall_after` bytes from the sender have gone through, the rest wait
+    /// until the receiver's Cancel has gone back, so the sender can't finish first.
+    /// The receiver says nothing else mid-file, so whatever it sends once the
+    /// link has stalled is taken to be the Cancel; the session key that would
+    /// show it is one never leaves the two ends.
+    async fn stalling_link(sender: DuplexStream, receiver: DuplexStream, stall_after: usize) {
+        let (mut from_sender, mut to_sender) = tokio::io::split(sender);
+        let (mut from_receiver, mut to_receiver) = tokio::io::split(receiver);
+        let released = &tokio::sync::Notify::new();
+        let stalled = &std::sync::atomic::AtomicBool::new(false);
+        let forth = async move {
+            let mut passed = 0;
+            while let Ok(data) = network::read_message(&mut from_sender).await {
+                passed += data.len();
+                if network::write_message(&mut to_receiver, &data).await.is_err() {
+                    break;
+                }
+                if passed >= stall_after && !stalled.swap(true, Ordering::SeqCst) {
+                    released.notified().await;
+                }
+            }
+            // Hang up on the receiver once the sender has
+            let _ = to_receiver.shutdown().await;
+        };
+        let back = async move {
+            while let Ok(data) = network::read_message(&mut from_receiver).await {
+                if network::write_message(&mut to_sender, &data).await.is_err() {
+                    break;
+                }
+                if stalled.load(Ordering::SeqCst) {
+                    released.notify_one();
+                }
+            }
+        };
+        tokio::join!(forth, back);
+    }
+    
+    #[tokio::test]
+    async fn test_cancel_halfway_then_resume() {
+        const SIZE: usize = 8 * 1024 * 1024;
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, SIZE);
+        let output = dir.path().join("output.bin");
+        let state_file = TransferState::state_path(&output, None);
+        let options = ReceiveOptions {
+            output: Some(output.clone()),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        
+        // The receiver's user gives up once half the file is in
+        let cancel = CancellationToken::new();
+        let halfway = cancel.clone();
+        let on_progress = move |event: &TransferEvent| {
+            if let TransferEvent::Progress { transferred, total, .. } = event {
+                if transferred * 2 >= *total {
+                    halfway.cancel();
+                }
+            }
+        };
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let on_sent = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, sender_end) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+        let (receiver_end, receiver) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+        // A little past halfway, to allow for the handshake and what encryption adds
+        let link = tokio::spawn(stalling_link(sender_end, receiver_end, SIZE / 2 + 64 * 1024));
+        let (send_result, receive_result) = tokio::join!(
+            send_over(Transport::Memory(sender.into()), SendOptions::new(&input, "alpha-bravo-charlie"), Some(Arc::new(on_sent)), CancellationToken::new()),
+            receive_over(Transport::Memory(receiver.into()), options.clone(), Some(Arc::new(on_progress)), cancel),
+        );
+        link.await.unwrap();
+        
+        // The sender stops without an error, and both sides keep track of how far it got
+        send_result.unwrap();
+        assert!(receive_result.unwrap_err().is::<Cancelled>());
+        let state = TransferState::load(&state_file).unwrap().unwrap();
+        assert!((SIZE as u64 / 2..SIZE as u64).contains(&state.bytes_written), "{}", state.bytes_written);
+        assert_eq!(std::fs::metadata(&output).unwrap().len(), state.bytes_written);
+        let (transferred, resumable) = sent.lock().unwrap().iter().find_map(|event| match event {
+            TransferEvent::ReceiverCancelled { transferred, resumable, .. } => Some((*transferred, *resumable)),
+            _ => None,
+        }).unwrap();
+        assert!(resumable);
+        assert!(transferred >= state.bytes_written);
+        assert_eq!(SendTicket::load(&SendTicket::ticket_path(&input)).unwrap().unwrap().sent, transferred);
+        
+        // With --resume, only the rest is sent
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, receiver) = Transport::memory_pair();
+        tokio::try_join!(
+            send_over(sender, SendOptions::new(&input, "alpha-bravo-charlie"), None, CancellationToken::new()),
+            receive_over(receiver, ReceiveOptions { resume: true, ..options }, Some(Arc::new(callback)), CancellationToken::new()),
+        ).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        assert!(!state_file.exists());
+        assert!(!SendTicket::ticket_path(&input).exists());
+        
+        let seen = seen.lock().unwrap();
+        assert!(seen.contains(&TransferEvent::Resuming { chunk: state.next_chunk() }));
+        let first = seen.iter().find_map(|event| match event {
+            TransferEvent::Progress { transferred, .. } => Some(*transferred),
+            _ => None,
+        }).unwrap();
+        assert!(first > state.bytes_written, "{} <= {}", first, state.bytes_written);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_transfer() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photos");
+        std::fs::create_dir_all(source.join("2024")).unwrap();
+        std::fs::write(source.join("index.txt"), b"two photos").unwrap();
+        std::fs::write(source.join("2024/beach.jpg"), vec![42u8; 150_000]).unwrap();
+        
+        let sender = start_sender(SendOptions {
+            port: Some(19104),
+            ..SendOptions::new(&source, "alpha-bravo-charlie")
+        }).await;
+        
+        let output = dir.path().join("received");
+        let tmp_dir = TempDir::new().unwrap();
+        let options = ReceiveOptions {
+            tmp_dir: Some(tmp_dir.path().to_path_buf()),
+            ..receive_options("alpha-bravo-charlie", 19104, output.clone())
+        };
+        let saved = receive(options, None, CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        
+        assert_eq!(saved, output);
+        assert_eq!(std::fs::read(output.join("index.txt")).unwrap(), b"two photos");
+        assert_eq!(std::fs::read(output.join("2024/beach.jpg")).unwrap(), vec![42u8; 150_000]);
+        // Nothing is left behind in either place
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_as_zip() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photos");
+        std::fs::create_dir_all(source.join("2024")).unwrap();
+        std::fs::write(source.join("index.txt"), b"three files").unwrap();
+        std::fs::write(source.join("2024/beach.jpg"), vec![42u8; 150_000]).unwrap();
+        std::fs::write(source.join("2024/empty.txt"), b"").unwrap();
+        
+        let output = dir.path().join("received");
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            format: ArchiveFormat::Zip,
+            ..SendOptions::new(&source, "alpha-bravo-charlie")
+        };
+        tokio::try_join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive_options("alpha-bravo-charlie", 0, output.clone()), None, CancellationToken::new()),
+        ).unwrap();
+        
+        assert_eq!(std::fs::read(output.join("index.txt")).unwrap(), b"three files");
+        assert_eq!(std::fs::read(output.join("2024/beach.jpg")).unwrap(), vec![42u8; 150_000]);
+        assert_eq!(std::fs::read(output.join("2024/empty.txt")).unwrap(), b"");
+    }
+    
+    #[tokio::test]
+    async fn test_directory_on_current_thread_runtime() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photos");
+        std::fs::create_dir_all(source.join("2024")).unwrap();
+        std::fs::write(source.join("index.txt"), b"two photos").unwrap();
+        std::fs::write(source.join("2024/beach.jpg"), vec![42u8; 600_000]).unwrap();
+        
+        // Archives are built off the runtime, so a single thread has to do for both ends
+        for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+            let output = dir.path().join(format!("received-{:?}", format));
+            let (sender, receiver) = Transport::memory_pair();
+            let options = SendOptions {
+                format,
+                ..SendOptions::new(&source, "alpha-bravo-charlie")
+            };
+            tokio::try_join!(
+                send_over(sender, options, None, CancellationToken::new()),
+                receive_over(receiver, receive_options("alpha-bravo-charlie", 0, output.clone()), None, CancellationToken::new()),
+            ).unwrap();
+            
+            assert_eq!(std::fs::read(output.join("index.txt")).unwrap(), b"two photos");
+            assert_eq!(std::fs::read(output.join("2024/beach.jpg")).unwrap(), vec![42u8; 600_000]);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_directory_send_called_off() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photos");
+        std::fs::create_dir_all(&source).unwrap();
+        for i in 0..64 {
+            std::fs::write(source.join(format!("photo-{:02}.jpg", i)), vec![i as u8; 128 * 1024]).unwrap();
+        }
+        
+        // The sender's user gives up once archiving is under way
+        let cancel = CancellationToken::new();
+        let started = cancel.clone();
+        let on_sent = move |event: &TransferEvent| {
+            if let TransferEvent::Archiving { .. } = event {
+                started.cancel();
+            }
+        };
+        let (sender, receiver) = Transport::memory_pair();
+        let (send_result, receive_result) = tokio::join!(
+            send_over(sender, SendOptions::new(&source, "alpha-bravo-charlie"), Some(Arc::new(on_sent)), cancel),
+            receive_over(receiver, receive_options("alpha-bravo-charlie", 0, dir.path().join("first")), None, CancellationToken::new()),
+        );
+        assert!(send_result.unwrap_err().is::<Cancelled>());
+        assert!(receive_result.unwrap_err().to_string().contains("cancelled by sender"));
+        
+        // And the receiver's user once the first chunk is in
+        let cancel = CancellationToken::new();
+        let started = cancel.clone();
+        let on_progress = move |event: &TransferEvent| {
+            if let TransferEvent::Progress { .. } = event {
+                started.cancel();
+            }
+        };
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let on_sent = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, receiver) = Transport::memory_pair();
+        let (send_result, receive_result) = tokio::join!(
+            send_over(sender, SendOptions::new(&source, "alpha-bravo-charlie"), Some(Arc::new(on_sent)), CancellationToken::new()),
+            receive_over(receiver, receive_options("alpha-bravo-charlie", 0, dir.path().join("second")), Some(Arc::new(on_progress)), cancel),
+        );
+        send_result.unwrap();
+        assert!(receive_result.unwrap_err().is::<Cancelled>());
+        let transferred = sent.lock().unwrap().iter().find_map(|event| match event {
+            TransferEvent::ReceiverCancelled { transferred, .. } => Some(*transferred),
+            _ => None,
+        }).unwrap();
+        assert!(transferred < 64 * 128 * 1024, "{}", transferred);
+    }
+    
+    /// Carries messages between the two ends like a path that silently loses big packets
+    ///
+    /// Messages from the sender over `mtu` bytes are dropped, or with `late`,
+    /// only the first of them is held back that long and the rest go through.
+    /// Returns how many were dropped.
+    async fn lossy_link(sender: DuplexStream, receiver: DuplexStream, mtu: usize, late: Option<Duration>) -> usize {
+        let (mut from_sender, mut to_sender) = tokio::io::split(sender);
+        let (mut from_receiver, mut to_receiver) = tokio::io::split(receiver);
+        let forth = async move {
+            let (mut dropped, mut delayed) = (0, false);
+            while let Ok(data) = network::read_message(&mut from_sender).await {
+                if data.len() > mtu {
+                    match late {
+                        Some(delay) if !delayed => {
+                            delayed = true;
+                            tokio::time::sleep(delay).await;
+                        }
+                        Some(_) => {}
+                        None => {
+                            dropped += 1;
+                            continue;
+                        }
+                    }
+                }
+                if network::write_message(&mut to_receiver, &data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = to_receiver.shutdown().await;
+            dropped
+        };
+        let back = async move {
+            while let Ok(data) = network::read_message(&mut from_receiver).await {
+                if network::write_message(&mut to_sender, &data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::join!(forth, back).0
+    }
+    
+    #[tokio::test]
+    async fn test_big_chunks_lost_on_the_way() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 300_000);
+        let timeout = Duration::from_millis(200);
+        
+        for late in [None, Some(timeout * 2)] {
+            let output = dir.path().join("output.bin");
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let recorded = seen.clone();
+            let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+            let (sender, sender_end) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+            let (receiver_end, receiver) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+            let link = tokio::spawn(lossy_link(sender_end, receiver_end, 20_000, late));
+            let options = SendOptions {
+                chunk_ack_timeout: timeout,
+                ..SendOptions::new(&input, "alpha-bravo-charlie")
+            };
+            tokio::try_join!(
+                send_over(Transport::Memory(sender.into()), options, Some(Arc::new(callback)), CancellationToken::new()),
+                receive_over(Transport::Memory(receiver.into()), receive_options("alpha-bravo-charlie", 0, output.clone()), None, CancellationToken::new()),
+            ).unwrap();
+            let dropped = link.await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+            std::fs::remove_file(&output).unwrap();
+            
+            let limited = seen.lock().unwrap().iter().find_map(|event| match event {
+                TransferEvent::PathLimited { chunk_size, dropped } => Some((*chunk_size, *dropped)),
+                _ => None,
+            });
+            match late {
+                // 256 KB and 32 KB chunks were lost, 16 KB ones went through
+                None => {
+                    assert_eq!(limited, Some((16 * 1024, 32 * 1024)));
+                    assert_eq!(dropped, 2);
+                }
+                // The first chunk only took its time, and the copy sent after it was set aside
+                Some(_) => {
+                    assert_eq!(limited, None);
+                    assert_eq!(dropped, 0);
+                }
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_receipt_after_verified_transfer() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 300_000);
+        let identity = Arc::new(IdentityKey::create(&dir.path().join("identity.key")).unwrap());
+        
+        let output = dir.path().join("received.bin");
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            receipt: true,
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let receive = ReceiveOptions {
+            identity: Some(identity.clone()),
+            ..receive_options("alpha-bravo-charlie", 0, output.clone())
+        };
+        tokio::try_join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive, None, CancellationToken::new()),
+        ).unwrap();
+        
+        // Both copies match the receipt, which the receiver's key signed
+        let receipt = Receipt::load(&Receipt::receipt_path(&input)).unwrap();
+        let signer = receipt.verify(&input, Some(&identity.public_key())).await.unwrap();
+        assert_eq!(signer, Some(identity.public_key().as_str()));
+        receipt.verify(&output, None).await.unwrap();
+        assert!(receipt.checksum.starts_with(crypto::BLAKE3_PREFIX), "{}", receipt.checksum);
+    }
+    
+    #[tokio::test]
+    async fn test_receipt_checksum_choice() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 300_000);
+        let code = "alpha-bravo-charlie";
+        let options = || SendOptions {
+            receipt: true,
+            ..SendOptions::new(&input, code)
+        };
+        
+        // Asked for SHA-256, read back once everything's there
+        let (sender, receiver) = Transport::memory_pair();
+        let receive = ReceiveOptions {
+            checksum: ChecksumChoice::Sha256,
+            verify_after: true,
+            ..receive_options(code, 0, dir.path().join("sha256.bin"))
+        };
+        tokio::try_join!(
+            send_over(sender, options(), None, CancellationToken::new()),
+            receive_over(receiver, receive, None, CancellationToken::new()),
+        ).unwrap();
+        let receipt = Receipt::load(&Receipt::receipt_path(&input)).unwrap();
+        assert_eq!(receipt.checksum, crypto::checksum(&std::fs::read(&input).unwrap()));
+        
+        // With no checksums at all there's nothing to put in a receipt
+        let (sender, receiver) = Transport::memory_pair();
+        let receive = ReceiveOptions {
+            checksum: ChecksumChoice::Off,
+            ..receive_options(code, 0, dir.path().join("none.bin"))
+        };
+        let (sent, _) = tokio::join!(
+            send_over(sender, options(), None, CancellationToken::new()),
+            receive_over(receiver, receive, None, CancellationToken::new()),
+        );
+        assert!(sent.unwrap_err().to_string().contains("can't send receipts"));
+    }
+    
+    #[tokio::test]
+    async fn test_receiver_told_while_sender_prepares() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 300_000);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            receipt: true,
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let receive = receive_options("alpha-bravo-charlie", 0, dir.path().join("received.bin"));
+        tokio::try_join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive, Some(Arc::new(callback)), CancellationToken::new()),
+        ).unwrap();
+        
+        // Heard about before the offer, let alone any data
+        let seen = seen.lock().unwrap();
+        let position = |wanted: fn(&TransferEvent) -> bool| seen.iter().position(wanted).unwrap();
+        let preparing = position(|e| matches!(e, TransferEvent::Preparing { .. }));
+        assert_eq!(seen[preparing], TransferEvent::Preparing { detail: "hashing file".to_string() });
+        let last = seen.iter().rposition(|e| matches!(e, TransferEvent::Preparing { .. })).unwrap();
+        assert!(last < position(|e| matches!(e, TransferEvent::Metadata { .. })));
+        assert!(last < position(|e| matches!(e, TransferEvent::Progress { .. })));
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pipe_to_command() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 300_000);
+        let transfer = |command: String, receipt: bool| {
+            let (sender, receiver) = Transport::memory_pair();
+            let options = SendOptions {
+                receipt,
+                ..SendOptions::new(&input, "alpha-bravo-charlie")
+            };
+            let receive = ReceiveOptions {
+                pipe_to: Some(command),
+                ..ReceiveOptions::new("alpha-bravo-charlie")
+            };
+            async move {
+                tokio::join!(
+                    send_over(sender, options, None, CancellationToken::new()),
+                    receive_over(receiver, receive, None, CancellationToken::new()),
+                )
+            }
+        };
+        
+        let copy = dir.path().join("copy.bin");
+        let (sent, received) = transfer(format!("cat > '{}'", copy.display()), true).await;
+        sent.unwrap();
+        assert_eq!(received.unwrap(), PathBuf::new());
+        // The receipt was worked out from the stream, and matches what the command got
+        let receipt = Receipt::load(&Receipt::receipt_path(&input)).unwrap();
+        receipt.verify(&input, None).await.unwrap();
+        receipt.verify(&copy, None).await.unwrap();
+        
+        // A command that fails after reading everything fails the send too
+        let (sent, received) = transfer("cat > /dev/null; echo 'disk full' >&2; exit 3".to_string(), false).await;
+        let (sent, received) = (sent.unwrap_err().to_string(), received.unwrap_err().to_string());
+        assert!(sent.contains("exit status: 3") && sent.contains("disk full"), "{}", sent);
+        assert!(received.contains("exit status: 3") && received.contains("disk full"), "{}", received);
+        
+        // So does one that stops reading partway
+        let (sent, received) = transfer("head -c 1000 > /dev/null; echo 'not a tar archive' >&2; exit 2".to_string(), false).await;
+        let (sent, received) = (sent.unwrap_err().to_string(), received.unwrap_err().to_string());
+        assert!(received.contains("stopped reading") && received.contains("not a tar archive"), "{}", received);
+        assert!(sent.contains("not a tar archive"), "{}", sent);
+    }
+    
+    #[tokio::test]
+    async fn test_phase_timings() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 2_000_000);
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            receipt: true,
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let receive = receive_options("alpha-bravo-charlie", 0, dir.path().join("received.bin"));
+        let timings = |seen: Arc<Mutex<Option<timing::PhaseTimings>>>| {
+            move |event: &TransferEvent| {
+                if let TransferEvent::Timings { timings } = event {
+                    *seen.lock().unwrap() = Some(*timings);
+                }
+            }
+        };
+        let (sent, received) = (Arc::new(Mutex::new(None)), Arc::new(Mutex::new(None)));
+        let start = Instant::now();
+        tokio::try_join!(
+            send_over(sender, options, Some(Arc::new(timings(sent.clone()))), CancellationToken::new()),
+            receive_over(receiver, receive, Some(Arc::new(timings(received.clone()))), CancellationToken::new()),
+        ).unwrap();
+        let wall = start.elapsed();
+        
+        for timings in [sent.lock().unwrap().unwrap(), received.lock().unwrap().unwrap()] {
+            let phases = [
+                timings.waiting_for_peer,
+                timings.handshake,
+                timings.metadata,
+                timings.transfer,
+                timings.verification,
+                timings.finalize,
+            ];
+            assert!(phases.iter().all(|phase| !phase.is_zero()), "{:?}", timings);
+            assert!(!timings.disk_wait.is_zero() && !timings.network_wait.is_zero(), "{:?}", timings);
+            assert!(timings.disk_wait + timings.network_wait <= timings.transfer, "{:?}", timings);
+            // Both sides hash what goes through: the sender up front, the receiver as it writes
+            assert!(!timings.hashing.is_zero(), "{:?}", timings);
+            // The phases follow one another with no gaps, covering the whole transfer
+            assert!(timings.total() <= wall && timings.total() >= wall.mul_f64(0.8), "{:?} of {:?}", timings, wall);
+        }
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_chunks_encrypted_on_workers() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 12 * CHUNK_SIZE + 1234);
+        let output = dir.path().join("received.bin");
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            receipt: true,
+            crypto_threads: 4,
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let receive = receive_options("alpha-bravo-charlie", 0, output.clone());
+        tokio::try_join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive, None, CancellationToken::new()),
+        ).unwrap();
+        
+        // In order, checkpoints and all, with the receipt's checksum matching
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        assert!(Receipt::load(&Receipt::receipt_path(&input)).is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_delta_against_older_copy() {
+        let dir = TempDir::new().unwrap();
+        let mut state = 0x9e37_79b9u32;
+        let older: Vec<u8> = (0..3 * DELTA_BLOCK_SIZE + 500)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let output = dir.path().join("backup.img");
+        std::fs::write(&output, &older).unwrap();
+        
+        // Something in front, a byte changed in the second block, and more on the end
+        let mut newer = b"version 2\n".to_vec();
+        newer.extend_from_slice(&older);
+        newer[10 + DELTA_BLOCK_SIZE + 77] ^= 0xff;
+        newer.extend_from_slice(b"appended");
+        let input = dir.path().join("backup-new.img");
+        std::fs::write(&input, &newer).unwrap();
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, receiver) = Transport::memory_pair();
+        let receive = ReceiveOptions {
+            delta: true,
+            ..receive_options("alpha-bravo-charlie", 0, output.clone())
+        };
+        tokio::try_join!(
+            send_over(sender, SendOptions::new(&input, "alpha-bravo-charlie"), None, CancellationToken::new()),
+            receive_over(receiver, receive, Some(Arc::new(callback)), CancellationToken::new()),
+        ).unwrap();
+        
+        assert_eq!(std::fs::read(&output).unwrap(), newer);
+        let seen = seen.lock().unwrap();
+        let reused = 2 * DELTA_BLOCK_SIZE as u64;
+        assert!(seen.contains(&TransferEvent::Delta { reused, total: newer.len() as u64 }), "{:?}", seen.last());
+        assert_eq!(seen.last(), Some(&TransferEvent::Complete));
+        // Only the rebuilt file is left next to it
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_folder_sync_sends_only_changes() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("notes");
+        std::fs::create_dir_all(source.join("2024")).unwrap();
+        for i in 0..5 {
+            std::fs::write(source.join(format!("2024/day{}.md", i)), format!("day {}", i)).unwrap();
+        }
+        std::fs::write(source.join("index.md"), "five days").unwrap();
+        let output = dir.path().join("copy");
+        
+        async fn sync_once(source: &Path, output: &Path, delete: bool) -> Vec<TransferEvent> {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let recorded = seen.clone();
+            let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+            let (sender, receiver) = Transport::memory_pair();
+            let options = SendOptions {
+                sync: true,
+                sync_delete: delete.then(|| ConfirmPrompt::new(|_| true)),
+                ..SendOptions::new(source, "alpha-bravo-charlie")
+            };
+            let receive = ReceiveOptions {
+                sync: true,
+                ..receive_options("alpha-bravo-charlie", 0, output.to_path_buf())
+            };
+            tokio::try_join!(
+                send_over(sender, options, None, CancellationToken::new()),
+                receive_over(receiver, receive, Some(Arc::new(callback)), CancellationToken::new()),
+            ).unwrap();
+            let seen = seen.lock().unwrap().clone();
+            seen
+        }
+        let synced = |events: &[TransferEvent]| events.iter().find(|e| matches!(e, TransferEvent::Synced { .. })).cloned();
+        
+        let first = sync_once(&source, &output, false).await;
+        assert_eq!(synced(&first), Some(TransferEvent::Synced { sent: 6, unchanged: 0, skipped: 0, remote_only: 0, deleted: 0 }));
+        assert_eq!(std::fs::read_to_string(output.join("2024/day3.md")).unwrap(), "day 3");
+        
+        // One file edited, one added, one removed, and something the receiver added itself
+        std::fs::write(source.join("index.md"), "six days").unwrap();
+        std::fs::write(source.join("2024/day5.md"), "day 5").unwrap();
+        std::fs::remove_file(source.join("2024/day0.md")).unwrap();
+        std::fs::create_dir(output.join("local")).unwrap();
+        std::fs::write(output.join("local/todo.md"), "mine").unwrap();
+        
+        let second = sync_once(&source, &output, false).await;
+        assert_eq!(synced(&second), Some(TransferEvent::Synced { sent: 2, unchanged: 4, skipped: 0, remote_only: 2, deleted: 0 }));
+        assert_eq!(std::fs::read_to_string(output.join("index.md")).unwrap(), "six days");
+        assert_eq!(std::fs::read_to_string(output.join("2024/day5.md")).unwrap(), "day 5");
+        assert!(output.join("2024/day0.md").exists(), "nothing is deleted without --delete");
+        
+        let third = sync_once(&source, &output, true).await;
+        assert_eq!(synced(&third), Some(TransferEvent::Synced { sent: 0, unchanged: 6, skipped: 0, remote_only: 2, deleted: 2 }));
+        assert!(!output.join("2024/day0.md").exists());
+        assert!(!output.join("local").exists());
+        let contents = |root: PathBuf| async move {
+            let manifest = sync::build_manifest(&root, 0, |_, _| {}).await.unwrap();
+            manifest.into_iter().map(|entry| (entry.path, entry.checksum)).collect::<Vec<_>>()
+        };
+        assert_eq!(contents(output).await, contents(source).await);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_into_existing_files() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photos");
+        let sent_at = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        std::fs::create_dir_all(&source).unwrap();
+        for (name, contents) in [("index.txt", "sent index"), ("beach.jpg", "sent beach"), ("new.txt", "sent new")] {
+            std::fs::write(source.join(name), contents).unwrap();
+            std::fs::File::options().write(true).open(source.join(name)).unwrap().set_modified(sent_at).unwrap();
+        }
+        
+        // One file edited since the sender's copy, one older than it
+        let output = dir.path().join("received");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("index.txt"), "edited here").unwrap();
+        std::fs::write(output.join("beach.jpg"), "stale beach").unwrap();
+        let stale = std::time::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        std::fs::File::options().write(true).open(output.join("beach.jpg")).unwrap().set_modified(stale).unwrap();
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, receiver) = Transport::memory_pair();
+        let options = ReceiveOptions {
+            output: Some(output.clone()),
+            conflict: ConflictStrategy::Newer,
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        tokio::try_join!(
+            send_over(sender, SendOptions::new(&source, "alpha-bravo-charlie"), None, CancellationToken::new()),
+            receive_over(receiver, options, Some(Arc::new(callback)), CancellationToken::new()),
+        ).unwrap();
+        
+        assert_eq!(std::fs::read_to_string(output.join("index.txt")).unwrap(), "edited here");
+        assert_eq!(std::fs::read_to_string(output.join("beach.jpg")).unwrap(), "sent beach");
+        assert_eq!(std::fs::read_to_string(output.join("new.txt")).unwrap(), "sent new");
+        
+        let seen = seen.lock().unwrap();
+        let resolved = seen.iter().find_map(|event| match event {
+            TransferEvent::Conflicts { resolved } => Some(resolved.clone()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(resolved, [
+            (PathBuf::from("beach.jpg"), transfer::Resolution::Overwritten),
+            (PathBuf::from("index.txt"), transfer::Resolution::Skipped),
+        ]);
+    }
+    
+    #[tokio::test]
+    async fn test_scheduled_send_waits_with_heartbeats() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 100_000);
+        let output = dir.path().join("output.bin");
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            start: Some(StartCondition::At(std::time::SystemTime::now() + Duration::from_secs(2))),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let receive_options = ReceiveOptions {
+            output: Some(output.clone()),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        let begun = Instant::now();
+        tokio::try_join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive_options, Some(Arc::new(callback)), CancellationToken::new()),
+        ).unwrap();
+        assert!(begun.elapsed() >= Duration::from_millis(1900));
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        // The receiver heard the countdown before the offer arrived
+        let seen = seen.lock().unwrap();
+        assert!(seen.contains(&TransferEvent::Scheduled { starts_in: Some(Duration::from_secs(1)) }));
+        let scheduled = seen.iter().position(|event| matches!(event, TransferEvent::Scheduled { .. })).unwrap();
+        let offered = seen.iter().position(|event| matches!(event, TransferEvent::Metadata { .. })).unwrap();
+        assert!(scheduled < offered);
+    }
+    
+    #[tokio::test]
+    async fn test_estimate_declined_before_any_data() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 100_000);
+        let output = dir.path().join("output.bin");
+        
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let recorded = asked.clone();
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions {
+            estimate: Some(ConfirmPrompt::new(move |estimate: &str| {
+                recorded.lock().unwrap().push(estimate.to_string());
+                false
+            })),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let (sent, received) = tokio::join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            receive_over(receiver, receive_options("alpha-bravo-charlie", 0, output.clone()), None, CancellationToken::new()),
+        );
+        
+        assert!(sent.unwrap_err().to_string().contains("cancelled"));
+        assert!(received.unwrap_err().to_string().contains("cancelled by sender"));
+        assert!(!output.exists());
+        let asked = asked.lock().unwrap();
+        assert_eq!(asked.len(), 1);
+        assert!(asked[0].starts_with("Measured ") && asked[0].contains("100000 bytes would take about"), "{}", asked[0]);
+    }
+    
+    #[tokio::test]
+    async fn test_receiver_guardrails() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("holiday.jpg");
+        std::fs::write(&input, b"\x7fELF\x02\x01\x01\x00 not really a photo").unwrap();
+        let output = dir.path().join("received.jpg");
+        
+        let transfer = |options: ReceiveOptions| async {
+            let (sender, receiver) = Transport::memory_pair();
+            tokio::join!(
+                send_over(sender, SendOptions::new(&input, "alpha-bravo-charlie"), None, CancellationToken::new()),
+                receive_over(receiver, options, None, CancellationToken::new()),
+            )
+        };
+        let options = |accept_types: Option<&str>, confirm: bool| ReceiveOptions {
+            output: Some(output.clone()),
+            accept_types: accept_types.map(AcceptTypes::parse),
+            confirm_executable: Some(ConfirmPrompt::new(move |_| confirm)),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        
+        // The name is fine but the contents give it away
+        let (sent, received) = transfer(options(Some("jpg,png"), true)).await;
+        assert!(received.unwrap_err().to_string().contains("recognised as ELF executable"));
+        assert!(sent.unwrap_err().to_string().contains("recognised as ELF executable"));
+        assert!(!output.exists());
+        
+        let (sent, received) = transfer(options(Some("pdf"), true)).await;
+        assert!(received.unwrap_err().to_string().contains("not one of the accepted file types"));
+        assert!(sent.is_err());
+        
+        // Without an allowlist it comes down to the executable warning
+        let (sent, received) = transfer(options(None, false)).await;
+        assert!(sent.unwrap_err().to_string().contains("declined"));
+        assert!(received.is_err());
+        assert!(!output.exists());
+        
+        let (sent, received) = transfer(options(None, true)).await;
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_rejected_offer_over_relay() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 200_000);
+        let output = dir.path().join("output.bin");
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = vec![RelayUrl::from(listener.local_addr().unwrap())];
+        let relay = tokio::spawn(crate::relay::serve(listener, Default::default(), Default::default()));
+        
+        let transfer = |options: ReceiveOptions| {
+            let relay_addr = relay_addr.clone();
+            let input = input.clone();
+            async move {
+                let send_options = SendOptions {
+                    relay: Some(relay_addr.clone()),
+                    ..SendOptions::new(&input, &options.code)
+                };
+                let options = ReceiveOptions { relay: Some(relay_addr), ..options };
+                tokio::join!(
+                    send(send_options, None, CancellationToken::new()),
+                    receive(options, None, CancellationToken::new()),
+                )
+            }
+        };
+        
+        let too_small = ReceiveOptions {
+            output: Some(output.clone()),
+            reject_larger_than: Some(100_000),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        let (sent, received) = transfer(too_small).await;
+        assert!(sent.unwrap_err().to_string().contains("larger than the receiver accepts"));
+        assert!(received.is_err());
+        assert!(!output.exists());
+        
+        let described = Arc::new(Mutex::new(String::new()));
+        let seen = described.clone();
+        let declined = ReceiveOptions {
+            output: Some(output.clone()),
+            confirm_offer: Some(ConfirmPrompt::new(move |description| {
+                *seen.lock().unwrap() = description.to_string();
+                false
+            })),
+            ..ReceiveOptions::new("alpha-bravo-delta")
+        };
+        let (sent, received) = transfer(declined).await;
+        assert_eq!(described.lock().unwrap().as_str(), "input.bin (200000 bytes, type not recognised)");
+        assert!(sent.unwrap_err().to_string().contains("declined"));
+        assert!(received.is_err());
+        assert!(!output.exists());
+        
+        let accepted = ReceiveOptions {
+            output: Some(output.clone()),
+            reject_larger_than: Some(200_000),
+            confirm_offer: Some(ConfirmPrompt::new(|_| true)),
+            ..ReceiveOptions::new("alpha-bravo-echo")
+        };
+        let (sent, received) = transfer(accepted).await;
+        relay.abort();
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_wrong_code_rejected_before_file_data() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 200_000);
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let sender = tokio::spawn(send(
+            SendOptions {
+                port: Some(19105),
+                ..SendOptions::new(&input, "alpha-bravo-charlie")
+            },
+            Some(Arc::new(callback)),
+            CancellationToken::new(),
+        ));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        
+        let output = dir.path().join("output.bin");
+        let err = probe(receive_options("alpha-bravo-delta", 19105, output.clone())).await.err().unwrap();
+        assert_eq!(err.to_string(), "Wrong transfer code");
+        
+        let err = sender.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("Wrong transfer code"));
+        assert!(!output.exists());
+        assert!(!seen.lock().unwrap().iter().any(|e| matches!(e, TransferEvent::Progress { .. })));
+    }
+    
+    #[tokio::test]
+    async fn test_direct_tls_checks_the_certificate_vouched_for() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 50_000);
+        let output = dir.path().join("output.bin");
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let sender = tokio::spawn(send(
+            SendOptions { port: Some(19115), direct_tls: true, ..SendOptions::new(&input, "alpha-bravo-charlie") },
+            Some(Arc::new(callback)),
+            CancellationToken::new(),
+        ));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let options = ReceiveOptions { direct_tls: true, ..receive_options("alpha-bravo-charlie", 19115, output.clone()) };
+        receive(options, None, CancellationToken::new()).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        assert!(seen.lock().unwrap().iter().any(|e| matches!(e, TransferEvent::TlsVerified { ours, peer } if ours != peer)));
+        
+        // Something in the middle terminating TLS on each side passes the key exchange through
+        // untouched, but can only show certificates of its own
+        let sender = start_sender(SendOptions { port: Some(19116), direct_tls: true, ..SendOptions::new(&input, "alpha-bravo-charlie") }).await;
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:19117").await.unwrap();
+        tokio::spawn(async move {
+            let (receiver_side, _) = proxy.accept().await.unwrap();
+            let sender_side = tokio::net::TcpStream::connect("127.0.0.1:19116").await.unwrap();
+            let (ours, theirs) = (TlsIdentity::generate().unwrap(), TlsIdentity::generate().unwrap());
+            let mut receiver_side = network::tls::handshake(receiver_side, TlsRole::Server, &ours).await.unwrap();
+            let mut sender_side = network::tls::handshake(sender_side, TlsRole::Client, &theirs).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut receiver_side, &mut sender_side).await;
+        });
+        let options = ReceiveOptions { direct_tls: true, ..receive_options("alpha-bravo-charlie", 19117, dir.path().join("intercepted.bin")) };
+        let err = receive(options, None, CancellationToken::new()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<CryptoError>(), Some(CryptoError::CertificateMismatch { .. })));
+        let err = sender.await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<CryptoError>(), Some(CryptoError::CertificateMismatch { .. })));
+        assert!(!dir.path().join("intercepted.bin").exists());
+    }
+    
+    #[cfg(feature = "pq")]
+    #[tokio::test]
+    async fn test_post_quantum_hybrid() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 50_000);
+        let output = dir.path().join("output.bin");
+        let transfer = |port: u16, send_pq: (bool, u8), receive_pq: (bool, u8)| {
+            let (input, output) = (input.clone(), output.clone());
+            async move {
+                let _ = std::fs::remove_file(&output);
+                let sender = start_sender(SendOptions {
+                    port: Some(port),
+                    pq: send_pq.0,
+                    min_protocol: send_pq.1,
+                    ..SendOptions::new(&input, "alpha-bravo-charlie")
+                })
+                .await;
+                let sessions = Arc::new(Mutex::new(Vec::new()));
+                let seen = sessions.clone();
+                let callback = move |event: &TransferEvent| {
+                    if let TransferEvent::Handshake { session } = event {
+                        seen.lock().unwrap().push(session.clone());
+                    }
+                };
+                let options = ReceiveOptions {
+                    pq: receive_pq.0,
+                    min_protocol: receive_pq.1,
+                    ..receive_options("alpha-bravo-charlie", port, output)
+                };
+                let received = receive(options, Some(Arc::new(callback)), CancellationToken::new()).await;
+                let hybrid = sessions.lock().unwrap().first().map(|session| session.supports(FEATURE_KEM_ML_KEM_768));
+                (sender.await.unwrap(), received, hybrid)
+            }
+        };
+        
+        // Both offering it mix the KEM's secret in
+        let (sent, received, hybrid) = transfer(19112, (true, 1), (true, 1)).await;
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(hybrid, Some(true));
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        // One side without it falls back to the PAKE alone
+        let (sent, received, hybrid) = transfer(19113, (true, 1), (false, 1)).await;
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(hybrid, Some(false));
+        
+        // Unless that's refused, and both sides are told why
+        let (sent, received, hybrid) = transfer(19114, (false, 1), (false, protocol::KEY_EXCHANGE_V3)).await;
+        assert_eq!(received.unwrap_err().downcast::<PeerTooOld>().unwrap(), PeerTooOld { peer: 2, required: 3 });
+        assert!(sent.unwrap_err().to_string().contains("try again with --pq"));
+        assert_eq!(hybrid, None);
+        assert!(!output.exists());
+    }
+    
+    /// A peer from an older zap offering `features`, failing with the Error it's sent back
+    async fn old_peer(mut conn: Transport, features: HashSet<String>) -> Result<()> {
+        send_hello(&mut conn, features).await?;
+        conn.receive().await?;
+        conn.receive().await?;
+        match Message::from_bytes(&conn.receive().await?)? {
+            Message::Error { message } => Err(anyhow!(message)),
+            other => Err(anyhow!("Expected Error message, got {:?}", other)),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_min_protocol_floor() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 1000);
+        let output = dir.path().join("output.bin");
+        let mut before_pake = protocol::local_features();
+        before_pake.remove(FEATURE_PAKE_V2);
+        let too_old = |result: Result<()>| result.unwrap_err().downcast::<PeerTooOld>().unwrap();
+        
+        // A sender from before the PAKE is refused by a receiver wanting it, and told why
+        let (sender, receiver) = Transport::memory_pair();
+        let options = ReceiveOptions { output: Some(output.clone()), min_protocol: 2, ..ReceiveOptions::new("alpha-bravo-charlie") };
+        let (received, peer) = tokio::join!(
+            receive_over(receiver, options, None, CancellationToken::new()),
+            old_peer(sender, before_pake.clone()),
+        );
+        assert_eq!(too_old(received.map(|_| ())), PeerTooOld { peer: 1, required: 2 });
+        assert!(peer.unwrap_err().to_string().contains("requires key exchange v2 or newer"));
+        assert!(!output.exists());
+        
+        // And the other way round
+        let (sender, receiver) = Transport::memory_pair();
+        let options = SendOptions { min_protocol: 2, ..SendOptions::new(&input, "alpha-bravo-charlie") };
+        let (sent, peer) = tokio::join!(
+            send_over(sender, options, None, CancellationToken::new()),
+            old_peer(receiver, before_pake),
+        );
+        assert_eq!(too_old(sent), PeerTooOld { peer: 1, required: 2 });
+        assert!(peer.unwrap_err().to_string().contains("(--min-protocol), and this one is v1"));
+        
+        // A peer without the post-quantum key exchange is too old for v3, both ways
+        if cfg!(feature = "pq") {
+            let (sender, receiver) = Transport::memory_pair();
+            let options = ReceiveOptions { output: Some(output.clone()), min_protocol: 3, ..ReceiveOptions::new("alpha-bravo-charlie") };
+            let (received, peer) = tokio::join!(
+                receive_over(receiver, options, None, CancellationToken::new()),
+                old_peer(sender, offered_features()),
+            );
+            assert_eq!(too_old(received.map(|_| ())), PeerTooOld { peer: 2, required: 3 });
+            assert!(peer.unwrap_err().to_string().contains("try again with --pq"));
+            
+            let (sender, receiver) = Transport::memory_pair();
+            let options = SendOptions { min_protocol: 3, ..SendOptions::new(&input, "alpha-bravo-charlie") };
+            let (sent, peer) = tokio::join!(
+                send_over(sender, options, None, CancellationToken::new()),
+                old_peer(receiver, offered_features()),
+            );
+            assert_eq!(too_old(sent), PeerTooOld { peer: 2, required: 3 });
+            assert!(peer.is_err());
+        }
+        
+        // Mailbox transfers only have the code to key with, so any floor above v1 refuses them up front
+        let options = SendOptions {
+            relay: Some(vec!["ws://127.0.0.1:1".parse().unwrap()]),
+            mailbox_ttl: Some(Duration::from_secs(60)),
+            min_protocol: 2,
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        };
+        let error = send(options, None, CancellationToken::new()).await.unwrap_err();
+        assert!(error.is::<PeerTooOld>());
+        assert!(error.to_string().contains("keyed from the code alone"));
+        let options = ReceiveOptions { mailbox: true, min_protocol: 2, ..receive_options("alpha-bravo-charlie", 1, output) };
+        assert!(receive(options, None, CancellationToken::new()).await.unwrap_err().is::<PeerTooOld>());
+    }
+    
+    #[tokio::test]
+    async fn test_sender_lying_about_size() {
+        let dir = TempDir::new().unwrap();
+        let code = "alpha-bravo-charlie";
+        
+        // Announce 100 bytes, then send 200
+        let sender = tokio::spawn(async move {
+            let mut conn = Transport::new_sender(None, code, Some(19107), MAX_RELAY_FRAME_SIZE, false, || {}).await?;
+            let session = handshake(&mut conn).await?;
+            let cipher = key_exchange(&mut conn, code, &session, Role::Sender).await?;
+            send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+            
+            let metadata = Message::Metadata {
+                filename: "input.bin".to_string(),
+                size: 100,
+                is_directory: false,
+                checksum: "tbd".to_string(),
+            };
+            conn.send(&cipher.encrypt(&metadata.to_bytes()?)?).await?;
+            
+            // Both sides support peeking, so the first chunk goes before the Ack
+            let chunk = Message::Chunk {
+                index: 0,
+                data: vec![7u8; 200],
+            };
+            conn.send(&cipher.encrypt(&chunk.to_bytes()?)?).await?;
+            conn.receive().await?;
+            Ok::<_, anyhow::Error>(conn)
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        
+        let output = dir.path().join("output.bin");
+        let err = receive(receive_options(code, 19107, output.clone()), None, CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<transfer::TransferError>(),
+            Some(&transfer::TransferError::DataExceedsExpectedSize { expected: 100, received: 200 })
+        );
+        assert_eq!(std::fs::metadata(&output).unwrap().len(), 0);
+        sender.await.unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_receiver_reports_where_connection_closed() {
+        let dir = TempDir::new().unwrap();
+        let code = "alpha-bravo-charlie";
+        let (mut conn, receiver) = Transport::memory_pair();
+        
+        // Offer ten 1000-byte chunks and hang up after the fifth
+        let sender = async move {
+            let session = handshake(&mut conn).await?;
+            let cipher = key_exchange(&mut conn, code, &session, Role::Sender).await?;
+            send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+            let metadata = Message::Metadata {
+                filename: "input.bin".to_string(),
+                size: 10_000,
+                is_directory: false,
+                checksum: NO_CHECKSUM.to_string(),
+            };
+            conn.send(&cipher.encrypt(&metadata.to_bytes()?)?).await?;
+            let chunk = |index| cipher.encrypt(&Message::Chunk { index, data: vec![7u8; 1000] }.to_bytes()?);
+            conn.send(&chunk(0)?).await?;
+            // Ack, Resume and BlockSignatures
+            for _ in 0..3 {
+                conn.receive().await?;
+            }
+            for index in 1..5 {
+                conn.send(&chunk(index)?).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let output = dir.path().join("output.bin");
+        let (sent, received) = tokio::join!(sender, receive_over(receiver, receive_options(code, 0, output), None, CancellationToken::new()));
+        sent.unwrap();
+        
+        let err = received.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Connection closed after chunk 4 of ~10 (5 chunks in 5 messages received); likely middlebox interference, try --relay"
+        );
+        let lost = err.downcast_ref::<traffic::ConnectionLost>().unwrap();
+        assert_eq!((lost.traffic.last_chunk, lost.traffic.bytes), (Some(4), 5000));
+    }
+    
+    #[tokio::test]
+    async fn test_sender_reports_where_connection_closed() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 8 * 1024 * 1024);
+        let (sender, from_sender) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+        let (to_receiver, receiver) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+        
+        // Passes everything back, but cuts both ends once some of the file has gone through
+        let middlebox = async move {
+            let (mut sender_read, mut sender_write) = tokio::io::split(from_sender);
+            let (mut receiver_read, mut receiver_write) = tokio::io::split(to_receiver);
+            let back = tokio::spawn(async move { tokio::io::copy(&mut receiver_read, &mut sender_write).await });
+            let mut forwarded = 0;
+            while forwarded < 300_000 {
+                let frame = network::read_message(&mut sender_read).await.unwrap();
+                network::write_message(&mut receiver_write, &frame).await.unwrap();
+                forwarded += frame.len();
+            }
+            back.abort();
+            let _ = back.await;
+        };
+        let output = dir.path().join("output.bin");
+        let (sent, received, _) = tokio::join!(
+            send_over(Transport::Memory(sender.into()), SendOptions::new(&input, "alpha-bravo-charlie"), None, CancellationToken::new()),
+            receive_over(Transport::Memory(receiver.into()), receive_options("alpha-bravo-charlie", 0, output), None, CancellationToken::new()),
+            middlebox,
+        );
+        
+        let sent = sent.unwrap_err();
+        assert!(sent.to_string().starts_with("Connection closed after sending "), "{}", sent);
+        let sent = sent.downcast_ref::<traffic::ConnectionLost>().unwrap().traffic;
+        let received = received.unwrap_err();
+        assert!(received.to_string().starts_with("Connection closed after chunk "), "{}", received);
+        let received = received.downcast_ref::<traffic::ConnectionLost>().unwrap().traffic;
+        assert!(received.chunks > 0 && sent.chunks >= received.chunks);
+        // The first chunk went while probing the path, so it was heard back about
+        assert!(sent.acked.is_some());
+    }
+    
+    /// Handshake and key exchange between us and a peer that sets up its end with `peer`
+    async fn agree_keys<F, Fut>(code: &str, peer: F) -> (Result<Session>, Result<()>)
+    where
+        F: FnOnce(Transport) -> Fut,
+        Fut: std::future::Future<Output = Result<()>>,
+    {
+        let (mut conn, theirs) = Transport::memory_pair();
+        let ours = async move {
+            let session = handshake(&mut conn).await?;
+            let cipher = key_exchange(&mut conn, code, &session, Role::Receiver).await?;
+            receive_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            send_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+            Ok(session)
+        };
+        tokio::join!(ours, peer(theirs))
+    }
+    
+    #[tokio::test]
+    async fn test_key_exchange_versions() {
+        let code = "alpha-bravo-charlie";
+        
+        // Two current peers use SPAKE2
+        let (session, peer) = agree_keys(code, |mut conn| async move {
+            let session = handshake(&mut conn).await?;
+            let cipher = key_exchange(&mut conn, code, &session, Role::Sender).await?;
+            send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await
+        })
+        .await;
+        peer.unwrap();
+        assert_eq!(protocol::key_exchange_version(&session.unwrap()).unwrap(), protocol::KEY_EXCHANGE_V2);
+        
+        // A peer from before pake/v2 (or one with it stripped on the way) is refused, and told why
+        let (session, peer) = agree_keys(code, |mut conn| async move {
+            let mut features = protocol::local_features();
+            features.remove(FEATURE_PAKE_V2);
+            send_hello(&mut conn, features).await?;
+            conn.receive().await?;
+            conn.receive().await?;
+            match Message::from_bytes(&conn.receive().await?)? {
+                Message::Error { message } => Err(anyhow!(message)),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(session.unwrap_err().to_string().contains("pake/v2"));
+        assert!(peer.unwrap_err().to_string().contains("pake/v2"));
+        
+        // A peer claiming to be a mailbox upload still has to run the PAKE with a receiver that didn't ask for one
+        let (session, peer) = agree_keys(code, |mut conn| async move {
+            let mut features = offered_features();
+            features.insert(FEATURE_MAILBOX.to_string());
+            send_hello(&mut conn, features).await?;
+            conn.receive().await?;
+            conn.receive().await?;
+            match Message::from_bytes(&conn.receive().await?)? {
+                Message::KeyExchange { .. } => Ok(()),
+                other => Err(anyhow!("Expected KeyExchange message, got {:?}", other)),
+            }
+        })
+        .await;
+        peer.unwrap();
+        assert!(session.is_err());
+        
+        // An offer changed on the way, here the peer's losing a feature, breaks key confirmation
+        let (session, peer) = agree_keys(code, |mut conn| async move {
+            let mut stripped = offered_features();
+            stripped.remove(FEATURE_CHECKPOINT);
+            send_hello(&mut conn, stripped).await?;
+            conn.receive().await?;
+            let Message::Capabilities { features } = Message::from_bytes(&conn.receive().await?)? else {
+                return Err(anyhow!("Expected Capabilities message"));
+            };
+            let session = Session::negotiated(offered_features(), features);
+            let cipher = key_exchange(&mut conn, code, &session, Role::Sender).await?;
+            send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await
+        })
+        .await;
+        assert_eq!(session.unwrap_err().to_string(), WRONG_CODE);
+        assert!(peer.unwrap_err().to_string().contains(WRONG_CODE));
+        
+        // Peers disagreeing on the PAKE's identities end up with different keys
+        let (session, peer) = agree_keys(code, |mut conn| async move {
+            const OTHER_SUITE: protocol::PakeSuite = protocol::PakeSuite {
+                sender_identity: b"zap-other-sender",
+                ..protocol::PAKE_V2
+            };
+            let session = handshake(&mut conn).await?;
+            let (ours, theirs) = session.offered();
+            let offers = Offers { sender: ours.clone(), receiver: theirs.clone() };
+            let exchange = KeyExchange::new_sender(code, &OTHER_SUITE, offers);
+            conn.send(&Message::KeyExchange { data: exchange.outbound_message() }.to_bytes()?).await?;
+            let Message::KeyExchange { data } = Message::from_bytes(&conn.receive().await?)? else {
+                return Err(anyhow!("Expected KeyExchange message"));
+            };
+            let cipher = exchange.finish(&data)?;
+            send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+            receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await
+        })
+        .await;
+        assert_eq!(session.unwrap_err().to_string(), WRONG_CODE);
+        assert!(peer.unwrap_err().to_string().contains(WRONG_CODE));
+    }
+    
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn test_protocol_debug_log() {
+        use network::Direction;
+        
+        let listener = network::bind(Some(0)).await.unwrap();
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], listener.local_addr().unwrap().port()));
+        let (accepted, stream) = tokio::join!(network::accept(&listener), tokio::net::TcpStream::connect(addr));
+        let mut sender = accepted.unwrap();
+        let stream = stream.unwrap();
+        let local_port = stream.local_addr().unwrap().port();
+        let mut receiver = Transport::Direct(network::Connection::new(stream, addr, local_port));
+        
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let recorded = log.clone();
+        sender.enable_protocol_debug(ProtocolLog::new(move |direction: Direction, json: &str| {
+            recorded.lock().unwrap().push((direction, json.to_string()));
+        }));
+        let mut sender = Transport::Direct(sender);
+        
+        let code = "alpha-bravo-charlie";
+        let (sent, received) = tokio::join!(
+            async {
+                let session = handshake(&mut sender).await?;
+                let cipher = key_exchange(&mut sender, code, &session, Role::Sender).await?;
+                send_key_confirm(&mut sender, &cipher, SENDER_CONFIRM).await
+            },
+            async {
+                let session = handshake(&mut receiver).await?;
+                let cipher = key_exchange(&mut receiver, code, &session, Role::Receiver).await?;
+                receive_key_confirm(&mut receiver, &cipher, SENDER_CONFIRM).await
+            }
+        );
+        sent.unwrap();
+        received.unwrap();
+        
+        let log = log.lock().unwrap();
+        let hello = Message::Hello { version: protocol::PROTOCOL_VERSION }.to_json_debug().unwrap();
+        assert!(log.contains(&(Direction::Send, hello.clone())), "{:?}", log);
+        assert!(log.contains(&(Direction::Recv, hello)), "{:?}", log);
+        assert!(log.iter().any(|(direction, json)| *direction == Direction::Recv && json.contains("\"KeyExchange\"")));
+        let (direction, last) = log.last().unwrap();
+        assert_eq!(*direction, Direction::Send);
+        assert!(last.starts_with("<encrypted, "), "key confirmation logged as {}", last);
+        assert_eq!(log.len(), 2 * 3 + 1, "{:?}", log);
+    }
+    
+    #[tokio::test]
+    async fn test_no_receipt_for_damaged_file() {
+        // Hashed as it's written, or read back once it's all there
+        for verify_after in [false, true] {
+            let dir = TempDir::new().unwrap();
+            let code = "alpha-bravo-charlie";
+            let output = dir.path().join("output.bin");
+            let (mut conn, receiver) = Transport::memory_pair();
+            
+            // Ask for a receipt with the checksum of something else
+            let sender = async move {
+                let session = handshake(&mut conn).await?;
+                let cipher = key_exchange(&mut conn, code, &session, Role::Sender).await?;
+                send_key_confirm(&mut conn, &cipher, SENDER_CONFIRM).await?;
+                receive_key_confirm(&mut conn, &cipher, RECEIVER_CONFIRM).await?;
+                let metadata = Message::Metadata {
+                    filename: "input.bin".to_string(),
+                    size: 5,
+                    is_directory: false,
+                    checksum: crypto::checksum(b"other"),
+                };
+                conn.send(&cipher.encrypt(&metadata.to_bytes()?)?).await?;
+                let chunk = Message::Chunk { index: 0, data: b"bytes".to_vec() };
+                conn.send(&cipher.encrypt(&chunk.to_bytes()?)?).await?;
+                wait_for_ack(&mut conn).await?;
+                // The receiver's Resume, starting afresh, and its (empty) signatures without --delta
+                conn.receive().await?;
+                conn.receive().await?;
+                conn.send(&cipher.encrypt(&Message::Complete.to_bytes()?)?).await?;
+                // Everything arrived, so the Ack comes before the checksum is looked at
+                wait_for_ack(&mut conn).await?;
+                Message::from_bytes(&cipher.decrypt(&conn.receive().await?)?)
+            };
+            
+            let (reply, received) = tokio::join!(sender, receive_over(receiver, ReceiveOptions { verify_after, ..receive_options(code, 0, output) }, None, CancellationToken::new()));
+            let err = received.unwrap_err();
+            assert!(err.to_string().contains("arrived damaged"), "{}", err);
+            assert!(matches!(reply.unwrap(), Message::Error { message } if message.contains("arrived damaged")));
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_oversized_control_message_is_fragmented() {
+        let code = "alpha-bravo-charlie";
+        let listing: Vec<String> = (0..200_000).map(|i| format!("photos/2024/{:06}.jpg", i)).collect();
+        let metadata = Message::Metadata {
+            filename: listing.join("\n"),
+            size: 0,
+            is_directory: true,
+            checksum: "tbd".to_string(),
+        };
+        assert!(metadata.to_bytes().unwrap().len() > 3 * FRAGMENT_SIZE);
+        
+        let (mut sender, mut receiver) = Transport::memory_pair();
+        let cipher = Cipher::from_password(code).unwrap();
+        let send = async {
+            let session = handshake(&mut sender).await?;
+            send_control(&mut sender, &cipher, &metadata, session.supports(FEATURE_FRAGMENT)).await?;
+            // Messages that fit still go whole
+            send_control(&mut sender, &cipher, &Message::Complete, true).await
+        };
+        let receive = async {
+            handshake(&mut receiver).await?;
+            let mut reassembler = Reassembler::default();
+            let first = receive_control(&mut receiver, &cipher, &mut reassembler).await?;
+            let second = receive_control(&mut receiver, &cipher, &mut reassembler).await?;
+            Ok::<_, anyhow::Error>((first, second))
+        };
+        let ((), (first, second)) = tokio::try_join!(send, receive).unwrap();
+        assert!(matches!(second, Message::Complete));
+        let Message::Metadata { filename, .. } = first else {
+            panic!("expected the metadata back");
+        };
+        assert_eq!(filename.lines().count(), 200_000);
+        assert_eq!(filename, listing.join("\n"));
+    }
+    
+    #[tokio::test]
+    async fn test_receiver_that_doesnt_say_gets_legacy_chunks() {
+        let dir = TempDir::new().unwrap();
+        let input = write_fixture(&dir, 2 * 1024 * 1024);
+        let code = "alpha-bravo-charlie";
+        let (conn, mut legacy) = Transport::memory_pair();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        let sender = send_over(conn, SendOptions::new(&input, code), Some(Arc::new(callback)), CancellationToken::new());
+        
+        // Offers what zap did before peers said what they take, and records every frame that arrives
+        let receiver = async {
+            let features = [protocol::FEATURE_CIPHER_CHACHA20POLY1305, FEATURE_STREAM, FEATURE_FRAGMENT, FEATURE_PAKE_V2];
+            let session = handshake_offering(&mut legacy, features.into_iter().map(String::from).collect()).await?;
+            let cipher = key_exchange(&mut legacy, code, &session, Role::Receiver).await?;
+            receive_key_confirm(&mut legacy, &cipher, SENDER_CONFIRM).await?;
+            send_key_confirm(&mut legacy, &cipher, RECEIVER_CONFIRM).await?;
+            receive_control(&mut legacy, &cipher, &mut Reassembler::default()).await?;
+            legacy.send(&Message::Ack.to_bytes()?).await?;
+            let (mut frames, mut data) = (Vec::new(), Vec::new());
+            loop {
+                let frame = legacy.receive().await?;
+                frames.push(frame.len());
+                match Message::from_bytes(&cipher.decrypt(&frame)?)? {
+                    Message::Chunk { data: chunk, .. } => data.extend(chunk),
+                    Message::Complete => return anyhow::Ok((frames, data)),
+                    _ => return Err(anyhow!("Expected a Chunk or Complete")),
+                }
+            }
+        };
+        let ((), (frames, data)) = tokio::try_join!(sender, receiver).unwrap();
+        assert_eq!(data, std::fs::read(&input).unwrap());
+        assert!(frames.iter().all(|&len| len <= LEGACY_MAX_CHUNK_SIZE + CHUNK_OVERHEAD), "{:?}", frames);
+        let limited = TransferEvent::PeerLimited { chunk_size: LEGACY_MAX_CHUNK_SIZE, assumed: true };
+        assert!(seen.lock().unwrap().contains(&limited));
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mailbox_store_then_pickup() {
+        use crate::relay::{self, Mailbox, MailboxConfig, RelayConfig, RelayState};
+        
+        let dir = TempDir::new().unwrap();
+        let mailbox = Mailbox::open(MailboxConfig {
+            dir: dir.path().join("mailbox"),
+            max_bytes: relay::DEFAULT_MAILBOX_MAX_BYTES,
+        }).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = vec![RelayUrl::from(listener.local_addr().unwrap())];
+        let state = Arc::new(RelayState::with_mailbox(mailbox.clone()));
+        tokio::spawn(relay::serve(listener, RelayConfig::default(), state));
+        
+        // The sender finishes before the receiver ever connects
+        let input = write_fixture(&dir, 300_000);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let callback = move |event: &TransferEvent| recorded.lock().unwrap().push(event.clone());
+        send(
+            SendOptions {
+                relay: Some(relay_addr.clone()),
+                mailbox_ttl: Some(Duration::from_secs(3600)),
+                ..SendOptions::new(&input, "alpha-bravo-charlie")
+            },
+            Some(Arc::new(callback)),
+            CancellationToken::new(),
+        ).await.unwrap();
+        assert!(seen.lock().unwrap().contains(&TransferEvent::Stored { ttl: Duration::from_secs(3600) }));
+        
+        // A receiver that didn't ask for the mailbox only waits for a live sender
+        let output = dir.path().join("output.bin");
+        let live = ReceiveOptions {
+            output: Some(output.clone()),
+            relay: Some(relay_addr.clone()),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        };
+        let waited = tokio::time::timeout(Duration::from_secs(1), receive(live, None, CancellationToken::new())).await;
+        assert!(waited.is_err(), "a live receiver was handed the stored upload");
+        assert!(mailbox.used_bytes() > 0);
+        
+        let saved = receive(
+            ReceiveOptions {
+                output: Some(output.clone()),
+                relay: Some(relay_addr),
+                mailbox: true,
+                ..ReceiveOptions::new("alpha-bravo-charlie")
+            },
+            None,
+            CancellationToken::new(),
+        ).await.unwrap();
+        assert_eq!(saved, output);
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        // Delivered uploads are deleted
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while mailbox.used_bytes() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("mailbox upload was not deleted after delivery");
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// How often a waiting sender tells the receiver it's still there
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long interface counters are watched to judge whether the network is busy
+pub const IDLE_SAMPLE: Duration = Duration::from_secs(2);
+
+/// When a scheduled send may start moving data
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartCondition {
+    /// At this time
+    At(SystemTime),
+    /// Once the machine's network traffic drops below this many megabits per second
+    WhenIdle { mbps: f64 },
+}
+
+/// Parse `--at`: a local time of day like `02:00` (the next one to come round) or an RFC 3339 timestamp
+pub fn parse_start_time(text: &str) -> Result<SystemTime> {
+    start_time_after(text, Local::now())
+}
+
+fn start_time_after(text: &str, now: DateTime<Local>) -> Result<SystemTime> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.into());
+    }
+    let time = NaiveTime::parse_from_str(text, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M:%S"))
+        .map_err(|_| anyhow!("Expected a time like 02:00 or 2024-05-01T02:00:00+01:00, got {}", text))?;
+    
+    // Today if it's still to come, otherwise tomorrow; a time skipped by a clock change moves to the next day
+    let mut day = now.date_naive();
+    for _ in 0..3 {
+        if let Some(start) = Local.from_local_datetime(&day.and_time(time)).earliest().filter(|start| *start > now) {
+            return Ok(start.into());
+        }
+        day = day.succ_opt().ok_or_else(|| anyhow!("{} is out of range", text))?;
+    }
+    Err(anyhow!("{} doesn't exist in the local time zone", text))
+}
+
+/// Total bytes moved by the machine's network interfaces, to see how busy they are
+pub trait ThroughputProbe: Send + Sync {
+    fn total_bytes(&self) -> Result<u64>;
+}
+
+/// Reads the kernel's interface counters, leaving out loopback
+pub struct InterfaceCounters;
+
+impl ThroughputProbe for InterfaceCounters {
+    #[cfg(target_os = "linux")]
+    fn total_bytes(&self) -> Result<u64> {
+        let stats = std::fs::read_to_string("/proc/net/dev")?;
+        Ok(stats
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim() != "lo")
+            .map(|(_, counters)| {
+                // Received bytes come first and transmitted bytes ninth
+                let counters: Vec<u64> = counters.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+                counters.first().unwrap_or(&0) + counters.get(8).unwrap_or(&0)
+            })
+            .sum())
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    fn total_bytes(&self) -> Result<u64> {
+        Err(anyhow!("--when-idle isn't supported on this platform yet"))
+    }
+}
+
+/// What a waiting sender should do next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tick {
+    /// Keep waiting; send a heartbeat and show how long is left, if that's known
+    Waiting { starts_in: Option<Duration> },
+    /// Time to send the data
+    Start,
+}
+
+/// Paces a sender that's holding off until its `StartCondition` is met
+pub struct Schedule {
+    condition: Condition,
+    first: bool,
+}
+
+enum Condition {
+    /// Kept as a tokio instant so paused clocks in tests move it along
+    At(Instant),
+    WhenIdle { bits_per_sec: f64, probe: Box<dyn ThroughputProbe> },
+}
+
+impl Schedule {
+    /// Checks the probe works now, so a send that can never start fails before anyone connects
+    pub fn new(condition: &StartCondition, probe: Box<dyn ThroughputProbe>) -> Result<Self> {
+        let condition = match *condition {
+            StartCondition::At(time) => {
+                let wait = time.duration_since(SystemTime::now()).unwrap_or_default();
+                Condition::At(Instant::now() + wait)
+            }
+            StartCondition::WhenIdle { mbps } => {
+                probe.total_bytes()?;
+                Condition::WhenIdle { bits_per_sec: mbps * 1_000_000.0, probe }
+            }
+        };
+        Ok(Self { condition, first: true })
+    }
+    
+    /// Wait for the next heartbeat or for the start, whichever comes first
+    ///
+    /// The first call returns straight away so the countdown can be shown.
+    pub async fn tick(&mut self) -> Result<Tick> {
+        let first = std::mem::take(&mut self.first);
+        match &self.condition {
+            Condition::At(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if !first && !left.is_zero() {
+                    tokio::time::sleep(left.min(HEARTBEAT_INTERVAL)).await;
+                }
+                match deadline.saturating_duration_since(Instant::now()) {
+                    Duration::ZERO => Ok(Tick::Start),
+                    left => Ok(Tick::Waiting { starts_in: Some(left) }),
+                }
+            }
+            Condition::WhenIdle { bits_per_sec, probe } => {
+                let before = probe.total_bytes()?;
+                tokio::time::sleep(IDLE_SAMPLE).await;
+                let moved = probe.total_bytes()?.saturating_sub(before);
+                if (moved * 8) as f64 / IDLE_SAMPLE.as_secs_f64() < *bits_per_sec {
+                    return Ok(Tick::Start);
+                }
+                if !first {
+                    tokio::time::sleep(HEARTBEAT_INTERVAL - IDLE_SAMPLE).await;
+                }
+                Ok(Tick::Waiting { starts_in: None })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    
+    #[test]
+    fn test_parse_start_time() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 22, 30, 0).unwrap();
+        let at = |text| DateTime::<Local>::from(start_time_after(text, now).unwrap());
+        assert_eq!(at("23:15"), Local.with_ymd_and_hms(2024, 5, 1, 23, 15, 0).unwrap());
+        // Already gone today, so tomorrow's
+        assert_eq!(at("02:00"), Local.with_ymd_and_hms(2024, 5, 2, 2, 0, 0).unwrap());
+        assert_eq!(at("22:30:00"), Local.with_ymd_and_hms(2024, 5, 2, 22, 30, 0).unwrap());
+        
+        let exact = start_time_after("2024-05-02T01:00:00Z", now).unwrap();
+        assert_eq!(exact.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(), 1714611600);
+        assert!(start_time_after("2am", now).is_err());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_countdown_heartbeats_until_start() {
+        let start = SystemTime::now() + Duration::from_secs(40);
+        let mut schedule = Schedule::new(&StartCondition::At(start), Box::new(InterfaceCounters)).unwrap();
+        let begun = Instant::now();
+        
+        let mut countdown = Vec::new();
+        while let Tick::Waiting { starts_in } = schedule.tick().await.unwrap() {
+            countdown.push(starts_in.unwrap().as_secs_f64().round() as u64);
+        }
+        assert_eq!(countdown, [40, 25, 10]);
+        assert_eq!(begun.elapsed().as_secs_f64().round() as u64, 40);
+        
+        // A time that has passed starts at once
+        let mut late = Schedule::new(&StartCondition::At(SystemTime::now() - Duration::from_secs(5)), Box::new(InterfaceCounters)).unwrap();
+        assert_eq!(late.tick().await.unwrap(), Tick::Start);
+    }
+    
+    /// Counters that advance by the next rate on every read
+    struct FakeNetwork {
+        counter: AtomicU64,
+        bytes_per_read: Arc<AtomicU64>,
+    }
+    
+    impl ThroughputProbe for FakeNetwork {
+        fn total_bytes(&self) -> Result<u64> {
+            Ok(self.counter.fetch_add(self.bytes_per_read.load(Ordering::SeqCst), Ordering::SeqCst))
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_waits_for_idle_network() {
+        // 5 MB between samples two seconds apart is 20 Mbps
+        let bytes_per_read = Arc::new(AtomicU64::new(5_000_000));
+        let probe = FakeNetwork { counter: AtomicU64::new(0), bytes_per_read: bytes_per_read.clone() };
+        let mut schedule = Schedule::new(&StartCondition::WhenIdle { mbps: 10.0 }, Box::new(probe)).unwrap();
+        
+        let begun = Instant::now();
+        assert_eq!(schedule.tick().await.unwrap(), Tick::Waiting { starts_in: None });
+        assert_eq!(begun.elapsed(), IDLE_SAMPLE);
+        assert_eq!(schedule.tick().await.unwrap(), Tick::Waiting { starts_in: None });
+        assert_eq!(begun.elapsed(), IDLE_SAMPLE + HEARTBEAT_INTERVAL);
+        
+        // Traffic drops to 4 Mbps
+        bytes_per_read.store(1_000_000, Ordering::SeqCst);
+        assert_eq!(schedule.tick().await.unwrap(), Tick::Start);
+    }
+}
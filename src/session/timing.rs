@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The parts a transfer's time is split between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Connecting, and waiting on the other side: for it to turn up, to accept, or for the scheduled start
+    WaitingForPeer,
+    /// Agreeing on features and the session key, and measuring the connection
+    Handshake,
+    /// Reading and checksumming what's offered, and offering it
+    Metadata,
+    /// Moving the data
+    Transfer,
+    /// Checking what arrived and hearing that it did
+    Verification,
+    /// Putting it in place: finishing the file, unpacking folders, tidying up records
+    Finalize,
+}
+
+/// How long a transfer spent in each phase
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PhaseTimings {
+    pub waiting_for_peer: Duration,
+    pub handshake: Duration,
+    pub metadata: Duration,
+    pub transfer: Duration,
+    pub verification: Duration,
+    pub finalize: Duration,
+    /// Of `transfer`, time spent waiting on the disk, or on a `--pipe-to` command
+    pub disk_wait: Duration,
+    /// Of `transfer`, time spent waiting on the network
+    pub network_wait: Duration,
+    /// Of any phase, time spent checksumming: for a receipt, a checkpoint or a sync's manifest
+    ///
+    /// Hashing in its own stage while data arrives overlaps the other phases
+    /// rather than adding to them, so this is CPU time more than waiting.
+    pub hashing: Duration,
+}
+
+impl PhaseTimings {
+    /// Time spent in all the phases together
+    pub fn total(&self) -> Duration {
+        self.waiting_for_peer + self.handshake + self.metadata + self.transfer + self.verification + self.finalize
+    }
+    
+    fn phase_mut(&mut self, phase: Phase) -> &mut Duration {
+        match phase {
+            Phase::WaitingForPeer => &mut self.waiting_for_peer,
+            Phase::Handshake => &mut self.handshake,
+            Phase::Metadata => &mut self.metadata,
+            Phase::Transfer => &mut self.transfer,
+            Phase::Verification => &mut self.verification,
+            Phase::Finalize => &mut self.finalize,
+        }
+    }
+}
+
+/// A table of the phases, with each one's share of the total
+impl fmt::Display for PhaseTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().as_secs_f64();
+        let rows = [
+            ("Waiting for peer", self.waiting_for_peer),
+            ("Handshake", self.handshake),
+            ("Metadata", self.metadata),
+            ("Transfer", self.transfer),
+            ("  disk", self.disk_wait),
+            ("  network", self.network_wait),
+            ("Verification", self.verification),
+            ("Finalize", self.finalize),
+            ("Hashing", self.hashing),
+        ];
+        for (name, time) in rows {
+            let share = match total {
+                0.0 => 0.0,
+                total => time.as_secs_f64() * 100.0 / total,
+            };
+            writeln!(f, "{:<18}{:>9.3}s {:>5.1}%", name, time.as_secs_f64(), share)?;
+        }
+        write!(f, "{:<18}{:>9.3}s", "Total", total)
+    }
+}
+
+/// Splits a transfer's time between phases as it moves from one to the next
+#[derive(Debug)]
+pub struct PhaseTimer {
+    timings: PhaseTimings,
+    current: Phase,
+    since: Instant,
+}
+
+impl PhaseTimer {
+    /// Start timing, in `phase`
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            timings: PhaseTimings::default(),
+            current: phase,
+            since: Instant::now(),
+        }
+    }
+    
+    /// Move on to `phase`, which may be one that came before
+    pub fn enter(&mut self, phase: Phase) {
+        let now = Instant::now();
+        *self.timings.phase_mut(self.current) += now - self.since;
+        (self.current, self.since) = (phase, now);
+    }
+    
+    /// Count `waited` as spent waiting on the disk
+    pub fn disk(&mut self, waited: Duration) {
+        self.timings.disk_wait += waited;
+    }
+    
+    /// Count `waited` as spent waiting on the network
+    pub fn network(&mut self, waited: Duration) {
+        self.timings.network_wait += waited;
+    }
+    
+    /// Count `spent` as spent checksumming
+    pub fn hashing(&mut self, spent: Duration) {
+        self.timings.hashing += spent;
+    }
+    
+    /// The timings so far, the current phase included up to now
+    pub fn timings(&mut self) -> PhaseTimings {
+        self.enter(self.current);
+        self.timings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_phases_add_up() {
+        let mut timer = PhaseTimer::new(Phase::WaitingForPeer);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        timer.enter(Phase::Transfer);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        timer.network(Duration::from_secs(3));
+        timer.hashing(Duration::from_secs(2));
+        timer.enter(Phase::WaitingForPeer);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        
+        let timings = timer.timings();
+        assert_eq!(timings.waiting_for_peer, Duration::from_secs(3));
+        assert_eq!(timings.transfer, Duration::from_secs(5));
+        assert_eq!(timings.network_wait, Duration::from_secs(3));
+        assert_eq!(timings.total(), Duration::from_secs(8));
+        
+        let table = timings.to_string();
+        assert!(table.contains("Transfer              5.000s  62.5%"), "{}", table);
+        assert!(table.contains("Hashing               2.000s  25.0%"), "{}", table);
+        assert!(table.ends_with("Total                 8.000s"), "{}", table);
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::fmt;
+
+/// What has gone over the connection since the data started, for saying how far a transfer got when it breaks off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Traffic {
+    /// Messages of any kind, chunks included
+    pub frames: u64,
+    pub chunks: u64,
+    /// Chunk data, in bytes
+    pub bytes: u64,
+    /// Index of the last chunk that arrived, on the receiving side
+    pub last_chunk: Option<u64>,
+    /// How far the receiver last said the file runs, on the sending side
+    pub acked: Option<u64>,
+    /// Bytes there are to move, when that's known up front
+    pub total: Option<u64>,
+}
+
+impl Traffic {
+    /// Nothing moved yet, out of `total` bytes when that's known
+    pub fn new(total: Option<u64>) -> Self {
+        Self { total, ..Self::default() }
+    }
+    
+    /// A message that isn't a chunk
+    pub fn frame(&mut self) {
+        self.frames += 1;
+    }
+    
+    /// Chunk `index`, `len` bytes long
+    pub fn chunk(&mut self, index: Option<u64>, len: usize) {
+        self.frames += 1;
+        self.chunks += 1;
+        self.bytes += len as u64;
+        if index.is_some() {
+            self.last_chunk = index;
+        }
+    }
+    
+    /// Roughly how many chunks the whole transfer takes, going by the size of those so far
+    pub fn expected_chunks(&self) -> Option<u64> {
+        let total = self.total.filter(|&total| total > 0)?;
+        if self.bytes == 0 {
+            return None;
+        }
+        let average = self.bytes.div_ceil(self.chunks);
+        Some(total.div_ceil(average).max(self.chunks))
+    }
+    
+    /// `error` ended the transfer on the `sending` or receiving side before it completed
+    ///
+    /// Only a direct connection failing outright suggests the relay; anything
+    /// else keeps its own error as the cause.
+    pub fn lost(&self, error: anyhow::Error, sending: bool, direct: bool) -> anyhow::Error {
+        let dropped = error.chain().any(|cause| cause.is::<std::io::Error>());
+        let lost = ConnectionLost {
+            traffic: *self,
+            sending,
+            suggest_relay: direct && dropped,
+        };
+        error.context(lost)
+    }
+}
+
+/// The connection ended partway through the data, with how far it had got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConnectionLost {
+    pub traffic: Traffic,
+    /// Seen from the sender
+    pub sending: bool,
+    /// The connection was direct and simply dropped, as middleboxes that cut long transfers do
+    pub suggest_relay: bool,
+}
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let traffic = &self.traffic;
+        let of = match traffic.expected_chunks() {
+            Some(expected) => format!(" of ~{}", grouped(expected)),
+            None => String::new(),
+        };
+        if self.sending {
+            write!(f, "Connection closed after sending {}{} chunks ({} messages", grouped(traffic.chunks), of, grouped(traffic.frames))?;
+            if let Some(acked) = traffic.acked {
+                write!(f, "; the receiver had confirmed {} bytes", grouped(acked))?;
+            }
+            f.write_str(")")?;
+        } else {
+            match traffic.last_chunk {
+                Some(index) => write!(f, "Connection closed after chunk {}{}", grouped(index), of)?,
+                None => f.write_str("Connection closed before any chunk arrived")?,
+            }
+            write!(f, " ({} chunks in {} messages received)", grouped(traffic.chunks), grouped(traffic.frames))?;
+        }
+        if self.suggest_relay {
+            f.write_str("; likely middlebox interference, try --relay")?;
+        }
+        Ok(())
+    }
+}
+
+/// `n` with its thousands separated by commas
+fn grouped(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_connection_lost_message() {
+        let mut traffic = Traffic::new(Some(3_000 * 1000));
+        traffic.frame();
+        for index in 0..=1_204 {
+            traffic.chunk(Some(index), 1000);
+        }
+        let lost = ConnectionLost { traffic, sending: false, suggest_relay: true };
+        assert_eq!(
+            lost.to_string(),
+            "Connection closed after chunk 1,204 of ~3,000 (1,205 chunks in 1,206 messages received); likely middlebox interference, try --relay"
+        );
+        
+        traffic.acked = Some(1_048_576);
+        let lost = ConnectionLost { traffic, sending: true, suggest_relay: false };
+        assert_eq!(
+            lost.to_string(),
+            "Connection closed after sending 1,205 of ~3,000 chunks (1,206 messages; the receiver had confirmed 1,048,576 bytes)"
+        );
+        
+        let streamed = ConnectionLost { traffic: Traffic::new(None), sending: false, suggest_relay: false };
+        assert_eq!(streamed.to_string(), "Connection closed before any chunk arrived (0 chunks in 0 messages received)");
+        assert_eq!(grouped(999), "999");
+        assert_eq!(grouped(1_000_000), "1,000,000");
+    }
+}
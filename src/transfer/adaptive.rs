@@ -0,0 +1,337 @@
+use std::time::Duration;
+
+/// Chunk size the sender starts with
+pub const INITIAL_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Smallest chunk size the controller will shrink to
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest chunk size the controller will grow to
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunk size the sender drops to when its first chunk never arrives
+pub const FALLBACK_CHUNK_SIZE: usize = 4 * 1024;
+
+/// Chunks measured before each sizing decision
+const SAMPLE_WINDOW: usize = 4;
+
+/// Throughput must improve by this fraction for a larger chunk to be kept growing
+const GROWTH_MARGIN: f64 = 0.10;
+
+/// A chunk taking this many times longer than expected counts as a stall
+const STALL_FACTOR: f64 = 4.0;
+
+/// Throughput below this fraction of the best seen so far triggers a shrink
+const COLLAPSE_RATIO: f64 = 0.5;
+
+/// Picks the sender's chunk size from observed per-chunk send times
+///
+/// Starts at `INITIAL_CHUNK_SIZE` and doubles while throughput keeps improving,
+/// holds once larger chunks stop paying off, and halves on stalls or when
+/// throughput collapses.
+#[derive(Debug, Clone)]
+pub struct ChunkSizeController {
+    chunk_size: usize,
+    min_chunk_size: usize,
+    max_chunk_size: usize,
+    best_throughput: f64,
+    window_bytes: u64,
+    window_time: Duration,
+    window_chunks: usize,
+    stalled: bool,
+}
+
+impl ChunkSizeController {
+    /// Create a controller that never exceeds `max_chunk_size`
+    ///
+    /// A maximum below `MIN_CHUNK_SIZE` (from a `PathProbe`) is kept to as well.
+    pub fn new(max_chunk_size: usize) -> Self {
+        let max_chunk_size = max_chunk_size.max(1);
+        Self {
+            chunk_size: INITIAL_CHUNK_SIZE.min(max_chunk_size),
+            min_chunk_size: MIN_CHUNK_SIZE.min(max_chunk_size),
+            max_chunk_size,
+            best_throughput: 0.0,
+            window_bytes: 0,
+            window_time: Duration::ZERO,
+            window_chunks: 0,
+            stalled: false,
+        }
+    }
+    
+    /// Get the current chunk size
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+    
+    /// Record how long one chunk took to send
+    ///
+    /// Returns the new chunk size when the controller decides to change it.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) -> Option<usize> {
+        if self.best_throughput > 0.0 {
+            let expected = bytes as f64 / self.best_throughput;
+            if elapsed.as_secs_f64() > expected * STALL_FACTOR {
+                self.stalled = true;
+            }
+        }
+        
+        self.window_bytes += bytes as u64;
+        self.window_time += elapsed;
+        self.window_chunks += 1;
+        
+        if self.stalled {
+            return self.shrink();
+        }
+        if self.window_chunks < SAMPLE_WINDOW {
+            return None;
+        }
+        
+        let throughput = self.window_bytes as f64 / self.window_time.as_secs_f64().max(f64::EPSILON);
+        self.reset_window();
+        
+        if throughput < self.best_throughput * COLLAPSE_RATIO {
+            return self.shrink();
+        }
+        
+        if throughput >= self.best_throughput * (1.0 + GROWTH_MARGIN) {
+            self.best_throughput = throughput;
+            return self.resize(self.chunk_size.saturating_mul(2));
+        }
+        
+        None
+    }
+    
+    fn shrink(&mut self) -> Option<usize> {
+        // Forget the old best so the controller can climb back once things recover
+        self.best_throughput = 0.0;
+        self.reset_window();
+        self.resize(self.chunk_size / 2)
+    }
+    
+    fn resize(&mut self, chunk_size: usize) -> Option<usize> {
+        let chunk_size = chunk_size.clamp(self.min_chunk_size, self.max_chunk_size);
+        if chunk_size == self.chunk_size {
+            return None;
+        }
+        self.chunk_size = chunk_size;
+        Some(chunk_size)
+    }
+    
+    fn reset_window(&mut self) {
+        self.window_bytes = 0;
+        self.window_time = Duration::ZERO;
+        self.window_chunks = 0;
+        self.stalled = false;
+    }
+}
+
+/// What the sender does next while probing which chunk sizes get through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeStep {
+    /// Send the next chunk at this size and wait for it to arrive too
+    Try(usize),
+    /// Done: send chunks of up to this size without waiting for each
+    Settled(usize),
+}
+
+/// Finds the biggest chunks that get through a path silently dropping large ones
+///
+/// A path MTU blackhole lets the handshake's small messages through but loses
+/// every full-size chunk. The sender waits for its first chunk to arrive; if
+/// it doesn't, it falls back to `FALLBACK_CHUNK_SIZE` and doubles from there
+/// while chunks keep arriving, settling below the smallest size that didn't.
+#[derive(Debug, Clone)]
+pub struct PathProbe {
+    chunk_size: usize,
+    /// Biggest chunk that arrived since the first was lost
+    arrived: Option<usize>,
+    /// Smallest chunk that never arrived
+    dropped: Option<usize>,
+}
+
+impl PathProbe {
+    /// Start probing with chunks of `chunk_size`
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size,
+            arrived: None,
+            dropped: None,
+        }
+    }
+    
+    /// Size of the chunks being tried
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+    
+    /// A chunk of `len` bytes didn't arrive in time
+    ///
+    /// Returns the smaller size to send it again at, or `None` when even a
+    /// fallback-sized chunk was lost and the size can't be to blame.
+    pub fn dropped(&mut self, len: usize) -> Option<usize> {
+        if len <= FALLBACK_CHUNK_SIZE {
+            return None;
+        }
+        self.dropped = Some(self.dropped.map_or(len, |dropped| dropped.min(len)));
+        self.chunk_size = self.arrived.unwrap_or(FALLBACK_CHUNK_SIZE);
+        Some(self.chunk_size)
+    }
+    
+    /// A chunk of `len` bytes arrived
+    pub fn arrived(&mut self, len: usize) -> ProbeStep {
+        let Some(dropped) = self.dropped else {
+            return ProbeStep::Settled(self.chunk_size);
+        };
+        let arrived = self.arrived.map_or(len, |arrived| arrived.max(len));
+        self.arrived = Some(arrived);
+        if arrived >= dropped {
+            // What looked lost was only slow
+            self.dropped = None;
+            self.chunk_size = arrived;
+            return ProbeStep::Settled(arrived);
+        }
+        let next = arrived.saturating_mul(2);
+        if next >= dropped {
+            self.chunk_size = arrived;
+            return ProbeStep::Settled(arrived);
+        }
+        self.chunk_size = next;
+        ProbeStep::Try(next)
+    }
+    
+    /// The biggest chunk size that got through and the smallest that didn't,
+    /// if any chunk was lost
+    pub fn limit(&self) -> Option<(usize, usize)> {
+        Some((self.arrived?, self.dropped?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Time to send one chunk over a link with fixed per-chunk latency and bandwidth
+    fn link(latency_ms: f64, bytes_per_sec: f64) -> impl Fn(usize) -> Duration {
+        move |bytes| Duration::from_secs_f64(latency_ms / 1000.0 + bytes as f64 / bytes_per_sec)
+    }
+    
+    fn run(controller: &mut ChunkSizeController, chunks: usize, link: impl Fn(usize) -> Duration) {
+        for _ in 0..chunks {
+            let size = controller.chunk_size();
+            controller.record(size, link(size));
+        }
+    }
+    
+    #[test]
+    fn test_grows_to_max_on_high_latency_link() {
+        let mut controller = ChunkSizeController::new(MAX_CHUNK_SIZE);
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), MAX_CHUNK_SIZE);
+    }
+    
+    #[test]
+    fn test_converges_when_larger_chunks_stop_helping() {
+        let mut controller = ChunkSizeController::new(MAX_CHUNK_SIZE);
+        run(&mut controller, 50, link(1.0, 10_000_000.0));
+        let converged = controller.chunk_size();
+        assert!(converged < MAX_CHUNK_SIZE);
+        
+        run(&mut controller, 200, link(1.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), converged);
+    }
+    
+    #[test]
+    fn test_backs_off_on_stall_and_recovers() {
+        let mut controller = ChunkSizeController::new(MAX_CHUNK_SIZE);
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), MAX_CHUNK_SIZE);
+        
+        let size = controller.chunk_size();
+        let shrunk = controller.record(size, Duration::from_secs(10));
+        assert_eq!(shrunk, Some(MAX_CHUNK_SIZE / 2));
+        
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), MAX_CHUNK_SIZE);
+    }
+    
+    #[test]
+    fn test_shrinks_when_throughput_collapses() {
+        let mut controller = ChunkSizeController::new(MAX_CHUNK_SIZE);
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        
+        // Every chunk gets three times slower: not a stall, but throughput collapses
+        let slow = link(150.0, 10_000_000.0 / 3.0);
+        let decisions: Vec<_> = (0..SAMPLE_WINDOW)
+            .filter_map(|_| {
+                let size = controller.chunk_size();
+                controller.record(size, slow(size))
+            })
+            .collect();
+        assert_eq!(decisions, vec![MAX_CHUNK_SIZE / 2]);
+    }
+    
+    #[test]
+    fn test_respects_max() {
+        let mut controller = ChunkSizeController::new(64 * 1024);
+        assert_eq!(controller.chunk_size(), 64 * 1024);
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), 64 * 1024);
+    }
+    
+    #[test]
+    fn test_keeps_to_a_max_below_min_chunk_size() {
+        let mut controller = ChunkSizeController::new(8 * 1024);
+        assert_eq!(controller.chunk_size(), 8 * 1024);
+        run(&mut controller, 100, link(50.0, 10_000_000.0));
+        assert_eq!(controller.chunk_size(), 8 * 1024);
+        assert_eq!(controller.record(8 * 1024, Duration::from_secs(10)), None);
+    }
+    
+    /// Probe a path that loses every chunk over `mtu` bytes, returning where it settled
+    fn probe_path(mtu: usize) -> (usize, Vec<usize>, Option<(usize, usize)>) {
+        let mut probe = PathProbe::new(INITIAL_CHUNK_SIZE);
+        let mut tried = vec![probe.chunk_size()];
+        loop {
+            let size = probe.chunk_size();
+            let step = if size > mtu {
+                match probe.dropped(size) {
+                    Some(size) => ProbeStep::Try(size),
+                    None => panic!("gave up at {}", size),
+                }
+            } else {
+                probe.arrived(size)
+            };
+            match step {
+                ProbeStep::Try(size) => tried.push(size),
+                ProbeStep::Settled(size) => return (size, tried, probe.limit()),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_probe_settles_below_path_limit() {
+        let (settled, tried, limit) = probe_path(20_000);
+        assert_eq!(settled, 16 * 1024);
+        assert_eq!(tried, [256 * 1024, 4096, 8192, 16 * 1024, 32 * 1024, 16 * 1024]);
+        assert_eq!(limit, Some((16 * 1024, 32 * 1024)));
+        
+        // Right up to the chunk that was lost first
+        assert_eq!(probe_path(200_000).0, 128 * 1024);
+        
+        // A path that takes the first chunk is never probed
+        assert_eq!(probe_path(usize::MAX), (INITIAL_CHUNK_SIZE, vec![INITIAL_CHUNK_SIZE], None));
+    }
+    
+    #[test]
+    fn test_probe_gives_up_when_small_chunks_are_lost_too() {
+        let mut probe = PathProbe::new(INITIAL_CHUNK_SIZE);
+        assert_eq!(probe.dropped(INITIAL_CHUNK_SIZE), Some(FALLBACK_CHUNK_SIZE));
+        assert_eq!(probe.dropped(FALLBACK_CHUNK_SIZE), None);
+        
+        // A chunk that only looked lost arrives after all
+        let mut probe = PathProbe::new(INITIAL_CHUNK_SIZE);
+        probe.dropped(INITIAL_CHUNK_SIZE);
+        assert_eq!(probe.arrived(INITIAL_CHUNK_SIZE), ProbeStep::Settled(INITIAL_CHUNK_SIZE));
+        assert_eq!(probe.limit(), None);
+    }
+}
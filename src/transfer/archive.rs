@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{archive_entries, wire_path};
+
+/// How a directory is packed for sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    /// tar, written straight into the transport
+    #[default]
+    Tar,
+    /// Deflated ZIP, built chunk by chunk by `ZipDirectoryChunker`
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Tell a received archive's format from how it starts
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = Vec::with_capacity(4);
+        File::open(path)?.take(4).read_to_end(&mut magic)?;
+        // A local file header, or the end of the central directory when there are no entries
+        Ok(match magic.as_slice() {
+            b"PK\x03\x04" | b"PK\x05\x06" => Self::Zip,
+            _ => Self::Tar,
+        })
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            other => Err(anyhow!("Unknown archive format '{}' (expected tar or zip)", other)),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tar => "tar",
+            Self::Zip => "zip",
+        })
+    }
+}
+
+/// Where the `ZipWriter` output collects until the chunker hands it out
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Packs a directory into a ZIP a chunk at a time, without a temporary file
+///
+/// A ZIP ends with a central directory listing every entry, so unlike tar it
+/// can't be produced purely front to back. Instead the `ZipWriter` writes into
+/// a buffer and each `next_chunk` feeds it just enough — the next directory
+/// entry, file header or `chunk_size` bytes of a file — for `chunk_size` bytes
+/// to pile up, then hands those out. Sizes and checksums go in a descriptor
+/// after each file's data, as they aren't known when its header is written,
+/// and the central directory comes out in the last chunks once every file is
+/// in. Between calls at most about one chunk of input is buffered, plus
+/// whatever deflate is holding on to.
+pub struct ZipDirectoryChunker {
+    zip: Option<ZipWriter<StreamWriter<SharedBuffer>>>,
+    output: SharedBuffer,
+    root: PathBuf,
+    entries: std::vec::IntoIter<walkdir::DirEntry>,
+    /// File whose contents are being added
+    current: Option<File>,
+    chunk_size: usize,
+    total_files: u64,
+    files_done: u64,
+    bytes_read: u64,
+}
+
+impl ZipDirectoryChunker {
+    /// Walks the tree up front, leaving out `skip`, so the number of files is known from the start
+    pub fn new(dir_path: &Path, skip: &[PathBuf], chunk_size: usize) -> Result<Self> {
+        let entries = archive_entries(dir_path, skip)?;
+        let total_files = entries.iter().filter(|entry| !entry.file_type().is_dir()).count() as u64;
+        let output = SharedBuffer::default();
+        Ok(Self {
+            zip: Some(ZipWriter::new_stream(output.clone())),
+            output,
+            root: dir_path.to_path_buf(),
+            entries: entries.into_iter(),
+            current: None,
+            chunk_size: chunk_size.max(1),
+            total_files,
+            files_done: 0,
+            bytes_read: 0,
+        })
+    }
+    
+    /// The next `chunk_size` bytes of the archive; the last chunk may be shorter
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            {
+                let mut output = self.output.0.lock().unwrap();
+                if output.len() >= self.chunk_size || (self.zip.is_none() && !output.is_empty()) {
+                    let len = output.len().min(self.chunk_size);
+                    return Ok(Some(output.drain(..len).collect()));
+                }
+            }
+            if self.zip.is_none() {
+                return Ok(None);
+            }
+            self.step()?;
+        }
+    }
+    
+    /// Files whose contents are all in the archive
+    pub fn files_done(&self) -> u64 {
+        self.files_done
+    }
+    
+    /// Files the archive will hold
+    pub fn total_files(&self) -> u64 {
+        self.total_files
+    }
+    
+    /// Bytes of file contents read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+    
+    /// Give the `ZipWriter` its next piece of work
+    fn step(&mut self) -> Result<()> {
+        let zip = self.zip.as_mut().expect("checked by next_chunk");
+        if let Some(file) = &mut self.current {
+            let mut buffer = vec![0; self.chunk_size];
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                self.current = None;
+                self.files_done += 1;
+            } else {
+                zip.write_all(&buffer[..read])?;
+                self.bytes_read += read as u64;
+            }
+            return Ok(());
+        }
+        
+        let Some(entry) = self.entries.next() else {
+            self.zip.take().expect("checked above").finish()?;
+            return Ok(());
+        };
+        let name = wire_path::relative_to(&self.root, entry.path())?;
+        let metadata = entry.metadata()?;
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(zip_time(metadata.modified()?))
+            // Must be decided before the data goes out, as the header can't be rewritten
+            .large_file(metadata.len() >= u32::MAX as u64);
+        #[cfg(unix)]
+        let options = {
+            use std::os::unix::fs::PermissionsExt;
+            options.unix_permissions(metadata.permissions().mode())
+        };
+        if entry.file_type().is_dir() {
+            zip.add_directory_from_path(name, options)?;
+        } else {
+            zip.start_file_from_path(name, options)?;
+            self.current = Some(File::open(entry.path())?);
+        }
+        Ok(())
+    }
+}
+
+/// ZIP times are local and can't go back before 1980
+fn zip_time(time: SystemTime) -> zip::DateTime {
+    zip::DateTime::try_from(DateTime::<Local>::from(time).naive_local()).unwrap_or_default()
+}
+
+/// Seconds since the epoch for a ZIP entry's local time
+fn unix_time(time: zip::DateTime) -> Option<u64> {
+    let local = Local.from_local_datetime(&NaiveDateTime::try_from(time).ok()?).earliest()?;
+    SystemTime::from(local).duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+/// Repack a received ZIP as tar, so it's extracted with the same conflict handling as one
+pub fn zip_to_tar(zip_path: &Path, tar_path: &Path) -> Result<()> {
+    let mut zip = ZipArchive::new(File::open(zip_path)?)?;
+    let mut tar = tar::Builder::new(File::create(tar_path)?);
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let name = entry
+            .enclosed_name()
+            .ok_or_else(|| anyhow!("Archive entry escapes the output directory: {}", entry.name()))?;
+        let is_dir = entry.is_dir();
+        
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.last_modified().and_then(unix_time).unwrap_or(0));
+        header.set_mode(entry.unix_mode().map_or(if is_dir { 0o755 } else { 0o644 }, |mode| mode & 0o7777));
+        if is_dir {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            tar.append_data(&mut header, &name, io::empty())?;
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(entry.size());
+            tar.append_data(&mut header, &name, &mut entry)?;
+        }
+    }
+    tar.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::ZapRng;
+    use rand::Rng;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_zip_chunks_extract() {
+        let source = TempDir::new().unwrap();
+        // Random, so deflate can't shrink it below a few chunks
+        let mut rng = ZapRng::seeded(694);
+        let big: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        std::fs::write(source.path().join("a.txt"), b"first file").unwrap();
+        std::fs::write(source.path().join("empty"), b"").unwrap();
+        std::fs::write(source.path().join("nested/big.bin"), &big).unwrap();
+        
+        let mut chunker = ZipDirectoryChunker::new(source.path(), &[], 4096).unwrap();
+        assert_eq!(chunker.total_files(), 3);
+        let mut archive = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            assert!(chunk.len() <= 4096);
+            archive.extend_from_slice(&chunk);
+            chunks += 1;
+        }
+        assert_eq!(chunker.files_done(), 3);
+        assert_eq!(chunker.bytes_read(), 300_010);
+        assert!(chunks > 70);
+        assert!(chunker.next_chunk().unwrap().is_none());
+        
+        let mut zip = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut read = |name: &str| {
+            let mut data = Vec::new();
+            zip.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+            data
+        };
+        assert_eq!(read("a.txt"), b"first file");
+        assert_eq!(read("empty"), b"");
+        assert_eq!(read("nested/big.bin"), big);
+        assert!(zip.by_name("nested/").unwrap().is_dir());
+    }
+    
+    #[test]
+    fn test_zip_repacked_as_tar() {
+        let source = TempDir::new().unwrap();
+        std::fs::create_dir(source.path().join("docs")).unwrap();
+        std::fs::write(source.path().join("docs/readme.md"), b"# Hello").unwrap();
+        std::fs::write(source.path().join("notes.txt"), b"some notes").unwrap();
+        
+        let work = TempDir::new().unwrap();
+        let zip_path = work.path().join("archive.zip");
+        let mut chunker = ZipDirectoryChunker::new(source.path(), &[], 100).unwrap();
+        let mut file = File::create(&zip_path).unwrap();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            file.write_all(&chunk).unwrap();
+        }
+        assert_eq!(ArchiveFormat::detect(&zip_path).unwrap(), ArchiveFormat::Zip);
+        
+        let tar_path = work.path().join("archive.tar");
+        zip_to_tar(&zip_path, &tar_path).unwrap();
+        assert_eq!(ArchiveFormat::detect(&tar_path).unwrap(), ArchiveFormat::Tar);
+        let output = work.path().join("output");
+        tar::Archive::new(File::open(&tar_path).unwrap()).unpack(&output).unwrap();
+        assert_eq!(std::fs::read(output.join("docs/readme.md")).unwrap(), b"# Hello");
+        assert_eq!(std::fs::read(output.join("notes.txt")).unwrap(), b"some notes");
+        
+        // ZIP keeps times to two seconds
+        let sent = std::fs::metadata(source.path().join("notes.txt")).unwrap().modified().unwrap();
+        let received = std::fs::metadata(output.join("notes.txt")).unwrap().modified().unwrap();
+        let apart = sent.duration_since(received).unwrap_or_else(|e| e.duration());
+        assert!(apart.as_secs() <= 2, "{:?}", apart);
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+
+use crate::protocol::CHUNK_OVERHEAD;
+
+/// How much memory the receive path holds at most unless `--memory-limit` says otherwise
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Smallest `--memory-limit`: a control message's 1 MB fragments have to fit, three times over
+pub const MIN_MEMORY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// A received message is held this many times over at once: as it came
+/// off the wire, decrypted, and decoded
+const COPIES_PER_MESSAGE: usize = 3;
+
+/// The memory a receiver lets one transfer hold at once
+///
+/// Senders are told the largest chunk that fits (see `max_chunk_size`) and
+/// send smaller ones; a message that still doesn't fit is an error rather
+/// than an allocation that could take the machine down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryBudget {
+    limit: usize,
+    peak: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, peak: 0 }
+    }
+    
+    /// The most that may be held at once, in bytes
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    
+    /// The most held at once so far, in bytes
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+    
+    /// The largest chunk a sender should send, so a chunk message fits with room to spare
+    pub fn max_chunk_size(&self) -> usize {
+        (self.limit / COPIES_PER_MESSAGE).saturating_sub(CHUNK_OVERHEAD)
+    }
+    
+    /// Account for a `message_len`-byte message arriving while `buffered` bytes are held already
+    pub fn hold(&mut self, message_len: usize, buffered: usize) -> Result<()> {
+        let needed = message_len.saturating_mul(COPIES_PER_MESSAGE).saturating_add(buffered);
+        if needed > self.limit {
+            return Err(anyhow!(
+                "A {}-byte message from the sender doesn't fit in the {}-byte memory limit (--memory-limit)",
+                message_len,
+                self.limit
+            ));
+        }
+        self.peak = self.peak.max(needed);
+        Ok(())
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_chunks_that_fit_are_held_and_peak_kept() {
+        let mut budget = MemoryBudget::new(MIN_MEMORY_LIMIT);
+        let chunk = budget.max_chunk_size() + CHUNK_OVERHEAD;
+        budget.hold(1000, 0).unwrap();
+        budget.hold(chunk, 0).unwrap();
+        budget.hold(1000, 500).unwrap();
+        assert_eq!(budget.peak(), chunk * COPIES_PER_MESSAGE);
+        assert!(budget.peak() <= budget.limit());
+    }
+    
+    #[test]
+    fn test_message_over_the_limit_is_an_error() {
+        let mut budget = MemoryBudget::new(MIN_MEMORY_LIMIT);
+        let err = budget.hold(MIN_MEMORY_LIMIT, 0).unwrap_err();
+        assert!(err.to_string().contains("--memory-limit"));
+        // What's already buffered counts too
+        let fits = budget.max_chunk_size();
+        assert!(budget.hold(fits, MIN_MEMORY_LIMIT / 2).is_err());
+        assert_eq!(budget.peak(), 0);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::protocol::Message;
+
+/// Chunks between the sender's checkpoint checksums
+pub const CHECKPOINT_INTERVAL: u64 = 500;
+
+/// The sender's running BLAKE3 hash of the chunks since its last checkpoint
+///
+/// Each checkpoint covers only the chunks since the one before, or since the
+/// transfer (re)started, which is where the receiver counts from too.
+pub struct Checkpointer {
+    interval: u64,
+    hasher: blake3::Hasher,
+}
+
+impl Checkpointer {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+            hasher: blake3::Hasher::new(),
+        }
+    }
+    
+    /// Forget what was hashed, when the transfer picks up somewhere else
+    pub fn restart(&mut self) {
+        self.hasher.reset();
+    }
+    
+    /// Add chunk `index`, returning the `PartialChecksum` to send after it when it ends an interval
+    pub fn record(&mut self, index: u64, data: &[u8]) -> Option<Message> {
+        self.hasher.update(data);
+        let up_to_chunk = index + 1;
+        if !up_to_chunk.is_multiple_of(self.interval) {
+            return None;
+        }
+        let hash = *self.hasher.finalize().as_bytes();
+        self.hasher.reset();
+        Some(Message::PartialChecksum { up_to_chunk, hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::{FileWriter, TransferError};
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::NamedTempFile;
+    
+    #[test]
+    fn test_checkpoint_catches_corrupted_byte() {
+        let output_file = NamedTempFile::new().unwrap();
+        let mut writer = FileWriter::streaming(output_file.path()).unwrap();
+        let mut checkpointer = Checkpointer::new(CHECKPOINT_INTERVAL);
+        let mut checkpoints = Vec::new();
+        // Uneven chunks, as the adaptive chunk size makes them
+        for index in 0..1000 {
+            let chunk = vec![(index % 251) as u8; 64 + (index as usize % 7) * 16];
+            writer.write_chunk(&chunk).unwrap();
+            checkpoints.extend(checkpointer.record(index, &chunk));
+        }
+        let [Message::PartialChecksum { up_to_chunk: 500, hash: first }, Message::PartialChecksum { up_to_chunk: 1000, hash: second }] =
+            checkpoints[..]
+        else {
+            panic!("expected checkpoints after chunks 500 and 1000, got {:?}", checkpoints);
+        };
+        writer.verify_partial_checksum(0..500, first).unwrap();
+        writer.verify_partial_checksum(500..1000, second).unwrap();
+        
+        // One byte goes bad on disk, somewhere in the first 500 chunks
+        let mut file = File::options().write(true).open(output_file.path()).unwrap();
+        file.seek(SeekFrom::Start(12_345)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        
+        let err = writer.verify_partial_checksum(0..500, first).unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>(), Some(&TransferError::ChecksumMismatch { from: 0, to: 500 }));
+        writer.verify_partial_checksum(500..1000, second).unwrap();
+        assert!(writer.verify_partial_checksum(500..1001, second).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::metadata::MetadataApplier;
+use super::wire_path::is_contained;
+
+/// What to do when a received file would replace one that already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictStrategy {
+    /// Keep the existing file
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Write the new file next to it as `name (1).ext`
+    Rename,
+    /// Replace the existing file only if the sender's copy was modified more recently
+    Newer,
+    /// Ask, offering to apply the answer to every later conflict
+    Ask,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "rename" => Ok(Self::Rename),
+            "newer" => Ok(Self::Newer),
+            "ask" => Ok(Self::Ask),
+            other => Err(anyhow!("Unknown conflict strategy '{}' (expected skip, overwrite, rename, newer or ask)", other)),
+        }
+    }
+}
+
+impl fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Skip => "skip",
+            Self::Overwrite => "overwrite",
+            Self::Rename => "rename",
+            Self::Newer => "newer",
+            Self::Ask => "ask",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a strategy says to do with one incoming file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Write,
+    Skip,
+    Rename,
+    Ask,
+}
+
+/// Decide what to do with an incoming file
+///
+/// `existing` is the modification time of the file already at the destination,
+/// if there is one; `incoming` is the one the sender recorded. Files that don't
+/// exist yet are always written.
+pub fn decide(strategy: ConflictStrategy, existing: Option<SystemTime>, incoming: SystemTime) -> Decision {
+    let Some(existing) = existing else {
+        return Decision::Write;
+    };
+    match strategy {
+        ConflictStrategy::Skip => Decision::Skip,
+        ConflictStrategy::Overwrite => Decision::Write,
+        ConflictStrategy::Rename => Decision::Rename,
+        ConflictStrategy::Newer if incoming > existing => Decision::Write,
+        ConflictStrategy::Newer => Decision::Skip,
+        ConflictStrategy::Ask => Decision::Ask,
+    }
+}
+
+/// How a conflicting file was resolved, for the transfer summary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Skipped,
+    Overwritten,
+    /// Written under this other name instead
+    Renamed(PathBuf),
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolution::Skipped => write!(f, "skipped"),
+            Resolution::Overwritten => write!(f, "overwritten"),
+            Resolution::Renamed(path) => write!(f, "renamed to {}", path.display()),
+        }
+    }
+}
+
+/// A file that wasn't written, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    /// Where it would have gone, relative to the output directory
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for SkippedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
+    }
+}
+
+/// What came of extracting an archive, besides the files written
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extracted {
+    /// How every clash was resolved, in archive order
+    pub resolved: Vec<(PathBuf, Resolution)>,
+    /// Files that couldn't be written, left out rather than failing the extraction
+    pub skipped: Vec<SkippedFile>,
+}
+
+/// Answer to a conflict prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictAnswer {
+    pub strategy: ConflictStrategy,
+    /// Use `strategy` for every later conflict instead of asking again
+    pub apply_to_all: bool,
+}
+
+/// Asks the user about a conflicting file, for `ConflictStrategy::Ask`
+#[derive(Clone)]
+pub struct ConflictPrompt(Arc<dyn Fn(&Path) -> ConflictAnswer + Send + Sync>);
+
+impl ConflictPrompt {
+    pub fn new(prompt: impl Fn(&Path) -> ConflictAnswer + Send + Sync + 'static) -> Self {
+        Self(Arc::new(prompt))
+    }
+}
+
+impl fmt::Debug for ConflictPrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConflictPrompt")
+    }
+}
+
+/// Applies a strategy to each incoming file, asking when it has to
+pub struct ConflictResolver {
+    strategy: ConflictStrategy,
+    prompt: Option<ConflictPrompt>,
+}
+
+impl ConflictResolver {
+    /// Without a prompt, `Ask` keeps existing files
+    pub fn new(strategy: ConflictStrategy, prompt: Option<ConflictPrompt>) -> Self {
+        Self { strategy, prompt }
+    }
+    
+    fn resolve(&mut self, path: &Path, existing: Option<SystemTime>, incoming: SystemTime) -> Decision {
+        let decision = decide(self.strategy, existing, incoming);
+        if decision != Decision::Ask {
+            return decision;
+        }
+        
+        let Some(prompt) = &self.prompt else {
+            return Decision::Skip;
+        };
+        let answer = (prompt.0)(path);
+        if answer.apply_to_all {
+            self.strategy = answer.strategy;
+        }
+        match decide(answer.strategy, existing, incoming) {
+            Decision::Ask => Decision::Skip,
+            decision => decision,
+        }
+    }
+    
+    /// Like `resolve`, for a file that clashes with one written earlier in the same transfer
+    ///
+    /// Overwriting would silently lose the sender's other file, so the default
+    /// strategy renames instead.
+    fn resolve_collision(&mut self, path: &Path, existing: Option<SystemTime>, incoming: SystemTime) -> Decision {
+        match self.strategy {
+            ConflictStrategy::Overwrite => Decision::Rename,
+            _ => self.resolve(path, existing, incoming),
+        }
+    }
+}
+
+/// The first free `name (n).ext` next to `path`
+pub fn renamed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Where a file is copied before being moved over its destination
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".zap-part");
+    PathBuf::from(name)
+}
+
+/// Move an unpacked file from the staging directory over `target`
+///
+/// Staging on another filesystem can't be renamed across, so the file is
+/// copied next to `target` first and renamed from there.
+pub(super) fn move_into_place(unpacked: &Path, target: &Path) -> io::Result<()> {
+    if fs::rename(unpacked, target).is_ok() {
+        return Ok(());
+    }
+    let part = part_path(target);
+    let copied = fs::copy(unpacked, &part).and_then(|_| fs::rename(&part, target));
+    if copied.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    let _ = fs::remove_file(unpacked);
+    copied
+}
+
+/// Whether `dir` is on a filesystem that ignores case, found by creating a file there
+pub fn is_case_insensitive(dir: &Path) -> bool {
+    let probe = dir.join(format!(".zap-case-probe-{}", std::process::id()));
+    if File::create(&probe).is_err() {
+        return false;
+    }
+    let insensitive = dir.join(format!(".ZAP-CASE-PROBE-{}", std::process::id())).exists();
+    let _ = fs::remove_file(&probe);
+    insensitive
+}
+
+/// Files written so far by one extraction, to catch entries that land on the same file
+struct Written {
+    fold_case: bool,
+    paths: HashSet<String>,
+    /// Identity of each file, which also catches names the filesystem treats
+    /// as equal for other reasons, like Unicode normalization on macOS
+    files: HashSet<(u64, u64)>,
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// Not available without unstable APIs; names are only compared by `Written::key` here
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+impl Written {
+    fn new(fold_case: bool) -> Self {
+        Self {
+            fold_case,
+            paths: HashSet::new(),
+            files: HashSet::new(),
+        }
+    }
+    
+    fn key(&self, relative: &Path) -> String {
+        let path = relative.to_string_lossy();
+        if self.fold_case {
+            path.to_lowercase()
+        } else {
+            path.into_owned()
+        }
+    }
+    
+    /// Whether `destination` is a file this extraction already wrote
+    fn contains(&self, relative: &Path, destination: &Path) -> bool {
+        self.paths.contains(&self.key(relative)) || file_id(destination).is_some_and(|id| self.files.contains(&id))
+    }
+    
+    fn insert(&mut self, relative: &Path, written: &Path) {
+        self.paths.insert(self.key(relative));
+        self.files.extend(file_id(written));
+    }
+}
+
+/// Extract a tar archive into `output_dir`, resolving clashes with existing files
+///
+/// Each file is unpacked into `staging` and put in place through `metadata`,
+/// so an overwritten file is replaced in one step; its modification time and
+/// permissions are then set the same way. Entries that would land on a file
+/// written earlier in the same archive (`Foo.txt` then `foo.txt` on a
+/// case-insensitive filesystem, say) count as clashes too. A file that can't
+/// be written, for lack of space or anything else, fails the extraction; with
+/// `keep_going` it's left out and the rest carry on.
+pub fn extract_with_conflicts(
+    archive_path: &Path,
+    output_dir: &Path,
+    staging: &Path,
+    resolver: &mut ConflictResolver,
+    metadata: &mut MetadataApplier,
+    keep_going: bool,
+) -> Result<Extracted> {
+    extract(archive_path, output_dir, staging, resolver, metadata, keep_going, is_case_insensitive(output_dir))
+}
+
+fn extract(
+    archive_path: &Path,
+    output_dir: &Path,
+    staging: &Path,
+    resolver: &mut ConflictResolver,
+    metadata: &mut MetadataApplier,
+    keep_going: bool,
+    fold_case: bool,
+) -> Result<Extracted> {
+    let mut archive = tar::Archive::new(File::open(archive_path)?);
+    let mut extracted = Extracted::default();
+    let mut written = Written::new(fold_case);
+    
+    for (index, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        let relative = entry.path()?.into_owned();
+        if !is_contained(&relative) {
+            return Err(anyhow!("Archive entry escapes the output directory: {}", relative.display()));
+        }
+        let destination = output_dir.join(&relative);
+        
+        if entry.header().entry_type().is_dir() {
+            fs::create_dir_all(&destination)?;
+            continue;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        
+        let incoming = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+        let existing = fs::metadata(&destination).and_then(|metadata| metadata.modified()).ok();
+        let collided = written.contains(&relative, &destination);
+        let decision = if collided {
+            // Names that only clash once case is folded may both fit on this filesystem,
+            // but they wouldn't on the sender's or the next one they're copied to
+            resolver.resolve_collision(&relative, existing.or(Some(incoming)), incoming)
+        } else {
+            resolver.resolve(&relative, existing, incoming)
+        };
+        let (target, resolution) = match decision {
+            Decision::Skip => {
+                extracted.resolved.push((relative, Resolution::Skipped));
+                continue;
+            }
+            Decision::Rename => {
+                let renamed = renamed_path(&destination);
+                let shown = relative.with_file_name(renamed.file_name().unwrap());
+                (renamed, Some(Resolution::Renamed(shown)))
+            }
+            _ => (destination, existing.map(|_| Resolution::Overwritten)),
+        };
+        
+        let part = staging.join(format!("entry-{}", index));
+        let regular = entry.header().entry_type().is_file();
+        let unpacked = if entry.header().entry_type().is_hard_link() {
+            // The tar crate would resolve the link against the working directory
+            let link = entry.link_name()?.ok_or_else(|| anyhow!("Hard link without a target: {}", relative.display()))?;
+            if !is_contained(&link) {
+                return Err(anyhow!("Archive hard link points outside the output directory: {} -> {}", relative.display(), link.display()));
+            }
+            metadata.link_or_copy(&output_dir.join(&link), &part, &relative, &link)
+        } else if regular {
+            // Just the contents: the tar crate would give up on the whole file if its metadata can't be set
+            File::create_new(&part).and_then(|mut file| io::copy(&mut entry, &mut file)).map(|_| ()).map_err(anyhow::Error::from)
+        } else {
+            entry.unpack(&part).map(|_| ()).map_err(anyhow::Error::from)
+        };
+        let placed = unpacked.and_then(|_| Ok(metadata.place(&part, &target)?));
+        if let Err(e) = placed {
+            let _ = fs::remove_file(&part);
+            if !keep_going {
+                return Err(e);
+            }
+            extracted.skipped.push(SkippedFile { path: relative, reason: e.to_string() });
+            continue;
+        }
+        if regular {
+            metadata.apply(&target, &relative, entry.header().mode()? & 0o777, incoming)?;
+        }
+        written.insert(target.strip_prefix(output_dir).unwrap_or(&relative), &target);
+        
+        if let Some(resolution) = resolution {
+            extracted.resolved.push((relative, resolution));
+        }
+    }
+    
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_decision_matrix() {
+        let incoming = UNIX_EPOCH + Duration::from_secs(2_000);
+        let older = Some(UNIX_EPOCH + Duration::from_secs(1_000));
+        let newer = Some(UNIX_EPOCH + Duration::from_secs(3_000));
+        
+        use ConflictStrategy::*;
+        use Decision as D;
+        let cases = [
+            (Skip, [D::Write, D::Skip, D::Skip]),
+            (Overwrite, [D::Write, D::Write, D::Write]),
+            (Rename, [D::Write, D::Rename, D::Rename]),
+            (Newer, [D::Write, D::Write, D::Skip]),
+            (Ask, [D::Write, D::Ask, D::Ask]),
+        ];
+        for (strategy, [missing, exists_older, exists_newer]) in cases {
+            assert_eq!(decide(strategy, None, incoming), missing, "{} / missing", strategy);
+            assert_eq!(decide(strategy, older, incoming), exists_older, "{} / older", strategy);
+            assert_eq!(decide(strategy, newer, incoming), exists_newer, "{} / newer", strategy);
+        }
+    }
+    
+    #[test]
+    fn test_renamed_path() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("photo.jpg");
+        assert_eq!(renamed_path(&path), dir.path().join("photo (1).jpg"));
+        
+        fs::write(dir.path().join("photo (1).jpg"), b"").unwrap();
+        assert_eq!(renamed_path(&path), dir.path().join("photo (2).jpg"));
+        assert_eq!(renamed_path(&dir.path().join("notes")), dir.path().join("notes (1)"));
+    }
+    
+    fn append(builder: &mut tar::Builder<File>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_000);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+    
+    fn append_hard_link(builder: &mut tar::Builder<File>, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+    
+    #[test]
+    fn test_colliding_and_hostile_entries() {
+        let dir = TempDir::new().unwrap();
+        let archive_path = dir.path().join("colliding.tar");
+        let mut builder = tar::Builder::new(File::create(&archive_path).unwrap());
+        append(&mut builder, "docs/Foo.txt", b"first");
+        append(&mut builder, "docs/foo.txt", b"second");
+        append(&mut builder, "docs/foo.txt", b"third");
+        append_hard_link(&mut builder, "docs/link.txt", "docs/Foo.txt");
+        builder.into_inner().unwrap();
+        
+        // As a case-insensitive filesystem would see it, whatever this one does
+        let output = dir.path().join("out");
+        fs::create_dir(&output).unwrap();
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        let resolved = extract(&archive_path, &output, dir.path(), &mut resolver, &mut MetadataApplier::default(), false, true).unwrap().resolved;
+        assert_eq!(fs::read(output.join("docs/Foo.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(output.join("docs/foo (1).txt")).unwrap(), b"second");
+        assert_eq!(fs::read(output.join("docs/foo (2).txt")).unwrap(), b"third");
+        assert_eq!(fs::read(output.join("docs/link.txt")).unwrap(), b"first");
+        assert_eq!(resolved, vec![
+            (PathBuf::from("docs/foo.txt"), Resolution::Renamed(PathBuf::from("docs/foo (1).txt"))),
+            (PathBuf::from("docs/foo.txt"), Resolution::Renamed(PathBuf::from("docs/foo (2).txt"))),
+        ]);
+        
+        // Skip keeps whichever came first
+        let output = dir.path().join("skipped");
+        fs::create_dir(&output).unwrap();
+        let mut resolver = ConflictResolver::new(ConflictStrategy::Skip, None);
+        let resolved = extract(&archive_path, &output, dir.path(), &mut resolver, &mut MetadataApplier::default(), false, true).unwrap().resolved;
+        assert_eq!(fs::read(output.join("docs/Foo.txt")).unwrap(), b"first");
+        assert_eq!(resolved.len(), 2);
+        assert!(resolved.iter().all(|(_, resolution)| *resolution == Resolution::Skipped));
+        
+        let hostile_path = dir.path().join("hostile.tar");
+        let mut builder = tar::Builder::new(File::create(&hostile_path).unwrap());
+        append_hard_link(&mut builder, "passwd", "../../etc/passwd");
+        builder.into_inner().unwrap();
+        let output = dir.path().join("hostile");
+        fs::create_dir(&output).unwrap();
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        let err = extract(&hostile_path, &output, dir.path(), &mut resolver, &mut MetadataApplier::default(), false, false).unwrap_err();
+        assert!(err.to_string().contains("points outside the output directory"));
+        assert!(!output.join("passwd").exists());
+    }
+    
+    #[test]
+    fn test_ask_apply_to_all() {
+        let asked = Arc::new(std::sync::Mutex::new(0));
+        let counter = asked.clone();
+        let prompt = ConflictPrompt::new(move |_| {
+            *counter.lock().unwrap() += 1;
+            ConflictAnswer { strategy: ConflictStrategy::Rename, apply_to_all: true }
+        });
+        let mut resolver = ConflictResolver::new(ConflictStrategy::Ask, Some(prompt));
+        
+        let now = SystemTime::now();
+        for _ in 0..3 {
+            assert_eq!(resolver.resolve(Path::new("a"), Some(now), now), Decision::Rename);
+        }
+        assert_eq!(*asked.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,465 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::protocol::{BlockSignature, DeltaOp};
+
+use super::TransferError;
+
+/// Block size the receiver signs its existing copy in
+pub const DELTA_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Largest share of the file size the signatures may take before the file is just sent whole
+pub const MAX_SIGNATURE_FRACTION: f64 = 0.05;
+
+/// Bytes of signatures per block on the wire, near enough
+const SIGNATURE_SIZE: u64 = 36;
+
+/// Longest literal run sent in one `Delta` message
+const MAX_LITERAL: usize = 256 * 1024;
+
+/// Whether signing a `base_len` byte copy in `block_size` blocks is worth it for a `file_size` byte file
+pub fn worth_signing(base_len: u64, file_size: u64, block_size: usize) -> bool {
+    let blocks = base_len / block_size as u64;
+    blocks > 0 && (blocks * SIGNATURE_SIZE) as f64 <= file_size as f64 * MAX_SIGNATURE_FRACTION
+}
+
+/// rsync's rolling checksum: two 16-bit sums, one of the bytes and one weighted by position
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+    
+    /// Slide the window on by one byte
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+    
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 32] {
+    *blake3::hash(block).as_bytes()
+}
+
+/// Signatures of each whole `block_size` block of `base`; a shorter tail is left out
+pub fn block_signatures(mut base: impl Read, block_size: usize) -> Result<Vec<BlockSignature>> {
+    let mut signatures = Vec::new();
+    let mut block = vec![0u8; block_size];
+    loop {
+        let mut filled = 0;
+        while filled < block_size {
+            match base.read(&mut block[filled..])? {
+                0 => return Ok(signatures),
+                n => filled += n,
+            }
+        }
+        signatures.push(BlockSignature {
+            weak: Rolling::new(&block).value(),
+            strong: strong_hash(&block),
+        });
+    }
+}
+
+/// Turns the sender's file into `DeltaOp`s against the receiver's signatures
+///
+/// Slides a block-sized window along the file a byte at a time. Where the
+/// rolling checksum and then BLAKE3 match a block of the receiver's copy, that
+/// block is copied; bytes in between go as literals.
+pub struct DeltaEncoder<R> {
+    reader: R,
+    block_size: usize,
+    weak_index: HashMap<u32, Vec<u64>>,
+    signatures: Vec<BlockSignature>,
+    /// Bytes read but not yet sent, from `literal_start`
+    buf: Vec<u8>,
+    /// Start of the window in `buf`
+    pos: usize,
+    literal_start: usize,
+    /// Checksum of the window at `pos`, while it's known
+    window: Option<Rolling>,
+    eof: bool,
+    done: bool,
+    /// An op taken to see whether a `Copy` runs on, with `Some(None)` for the end
+    held: Option<Option<DeltaOp>>,
+    hasher: blake3::Hasher,
+    position: u64,
+    copied: u64,
+}
+
+impl<R: Read> DeltaEncoder<R> {
+    pub fn new(reader: R, block_size: usize, signatures: Vec<BlockSignature>) -> Self {
+        let mut weak_index: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (block, signature) in signatures.iter().enumerate() {
+            weak_index.entry(signature.weak).or_default().push(block as u64);
+        }
+        Self {
+            reader,
+            block_size: block_size.max(1),
+            weak_index,
+            signatures,
+            buf: Vec::new(),
+            pos: 0,
+            literal_start: 0,
+            window: None,
+            eof: false,
+            done: false,
+            held: None,
+            hasher: blake3::Hasher::new(),
+            position: 0,
+            copied: 0,
+        }
+    }
+    
+    /// Bytes of the file the ops so far cover
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    
+    /// Bytes of the file the ops so far copy from the receiver's copy
+    pub fn copied(&self) -> u64 {
+        self.copied
+    }
+    
+    /// The next op, with runs of blocks copied in one; `Done` comes last
+    pub fn next_op(&mut self) -> Result<Option<DeltaOp>> {
+        let op = match self.held.take() {
+            Some(op) => op,
+            None => self.next_raw()?,
+        };
+        let Some(DeltaOp::Copy { block, mut count }) = op else {
+            return Ok(op);
+        };
+        loop {
+            match self.next_raw()? {
+                Some(DeltaOp::Copy { block: next, count: more }) if next == block + count => count += more,
+                other => {
+                    self.held = Some(other);
+                    return Ok(Some(DeltaOp::Copy { block, count }));
+                }
+            }
+        }
+    }
+    
+    fn next_raw(&mut self) -> Result<Option<DeltaOp>> {
+        if self.done {
+            return Ok(None);
+        }
+        let block_size = self.block_size;
+        loop {
+            self.fill()?;
+            if self.buf.len() - self.pos < block_size {
+                // Too little left for a block to match, so the rest is literal
+                if self.literal_start < self.buf.len() {
+                    let end = self.buf.len().min(self.literal_start + MAX_LITERAL);
+                    return Ok(Some(self.take_literal(end)));
+                }
+                self.done = true;
+                let hash = *self.hasher.finalize().as_bytes();
+                return Ok(Some(DeltaOp::Done { hash }));
+            }
+            
+            let window = &self.buf[self.pos..self.pos + block_size];
+            let rolling = *self.window.get_or_insert_with(|| Rolling::new(window));
+            if let Some(block) = self.find_block(rolling.value(), window) {
+                // Send what came before the match first; the window stays put
+                if self.pos > self.literal_start {
+                    return Ok(Some(self.take_literal(self.pos)));
+                }
+                self.pos += block_size;
+                self.literal_start = self.pos;
+                self.window = None;
+                self.position += block_size as u64;
+                self.copied += block_size as u64;
+                return Ok(Some(DeltaOp::Copy { block, count: 1 }));
+            }
+            
+            if self.pos - self.literal_start >= MAX_LITERAL {
+                return Ok(Some(self.take_literal(self.pos)));
+            }
+            // `fill` made sure the byte after the window is here, unless the file ended
+            self.window = self.buf.get(self.pos + block_size).map(|&into| {
+                let mut rolling = rolling;
+                rolling.roll(self.buf[self.pos], into);
+                rolling
+            });
+            self.pos += 1;
+        }
+    }
+    
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.weak_index.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates.iter().copied().find(|&block| self.signatures[block as usize].strong == strong)
+    }
+    
+    fn take_literal(&mut self, end: usize) -> DeltaOp {
+        let data = self.buf[self.literal_start..end].to_vec();
+        self.literal_start = end;
+        self.position += data.len() as u64;
+        DeltaOp::Literal { data }
+    }
+    
+    /// Read until there's a window and the byte after it past `pos`, or the file ends
+    fn fill(&mut self) -> Result<()> {
+        let wanted = self.pos + self.block_size + 1;
+        if self.eof || self.buf.len() >= wanted {
+            return Ok(());
+        }
+        // Everything before `literal_start` has gone out already
+        self.buf.drain(..self.literal_start);
+        self.pos -= self.literal_start;
+        self.literal_start = 0;
+        
+        let wanted = self.pos + self.block_size + 1;
+        let mut chunk = vec![0u8; self.block_size.max(64 * 1024)];
+        while self.buf.len() < wanted {
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            self.hasher.update(&chunk[..n]);
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the sender's file from the receiver's copy and `DeltaOp`s
+pub struct DeltaDecoder {
+    base: File,
+    block_size: u64,
+    blocks: u64,
+    output: BufWriter<File>,
+    expected_size: u64,
+    hasher: blake3::Hasher,
+    written: u64,
+    copied: u64,
+    finished: bool,
+}
+
+impl DeltaDecoder {
+    /// `blocks` is how many blocks of `base` were signed
+    pub fn new(base: File, block_size: usize, blocks: u64, output: File, expected_size: u64) -> Self {
+        Self {
+            base,
+            block_size: block_size as u64,
+            blocks,
+            output: BufWriter::new(output),
+            expected_size,
+            hasher: blake3::Hasher::new(),
+            written: 0,
+            copied: 0,
+            finished: false,
+        }
+    }
+    
+    /// Bytes of the file rebuilt so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+    
+    /// Bytes of those that came from the receiver's copy
+    pub fn copied(&self) -> u64 {
+        self.copied
+    }
+    
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+    
+    pub fn apply(&mut self, op: DeltaOp) -> Result<()> {
+        if self.finished {
+            return Err(anyhow!("Sender sent more after finishing the delta"));
+        }
+        match op {
+            DeltaOp::Copy { block, count } => {
+                if block.checked_add(count).is_none_or(|end| end > self.blocks) {
+                    return Err(anyhow!("Sender asked for blocks {}..{} of a {} block copy", block, block.saturating_add(count), self.blocks));
+                }
+                let len = count * self.block_size;
+                self.check_size(len)?;
+                self.base.seek(SeekFrom::Start(block * self.block_size))?;
+                let mut source = (&mut self.base).take(len);
+                let mut buffer = vec![0u8; self.block_size.min(64 * 1024) as usize];
+                loop {
+                    let n = source.read(&mut buffer)?;
+                    if n == 0 {
+                        break;
+                    }
+                    self.hasher.update(&buffer[..n]);
+                    self.output.write_all(&buffer[..n])?;
+                }
+                self.written += len;
+                self.copied += len;
+            }
+            DeltaOp::Literal { data } => {
+                self.check_size(data.len() as u64)?;
+                self.hasher.update(&data);
+                self.output.write_all(&data)?;
+                self.written += data.len() as u64;
+            }
+            DeltaOp::Done { hash } => {
+                if self.written != self.expected_size || *self.hasher.finalize().as_bytes() != hash {
+                    return Err(TransferError::DeltaMismatch.into());
+                }
+                self.output.flush()?;
+                self.output.get_ref().sync_all()?;
+                self.finished = true;
+            }
+        }
+        Ok(())
+    }
+    
+    fn check_size(&self, len: u64) -> Result<()> {
+        let received = self.written + len;
+        if received > self.expected_size {
+            return Err(TransferError::DataExceedsExpectedSize { expected: self.expected_size, received }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Put the rebuilt file at `staged` in place of `output`, copying when they're on different filesystems
+pub fn replace_with(staged: &std::path::Path, output: &std::path::Path) -> io::Result<()> {
+    if std::fs::rename(staged, output).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(staged, output)?;
+    std::fs::remove_file(staged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    const BLOCK: usize = 1024;
+    
+    fn base_file() -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..100 * BLOCK + 300)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+    
+    /// Send `new` against `base` and rebuild it, returning the literal bytes sent
+    fn round_trip(base: &[u8], new: &[u8]) -> u64 {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base");
+        std::fs::write(&base_path, base).unwrap();
+        let signatures = block_signatures(File::open(&base_path).unwrap(), BLOCK).unwrap();
+        assert_eq!(signatures.len(), base.len() / BLOCK);
+        let blocks = signatures.len() as u64;
+        
+        let mut encoder = DeltaEncoder::new(new, BLOCK, signatures);
+        let output_path = dir.path().join("output");
+        let mut decoder = DeltaDecoder::new(
+            File::open(&base_path).unwrap(),
+            BLOCK,
+            blocks,
+            File::create(&output_path).unwrap(),
+            new.len() as u64,
+        );
+        let mut literal = 0;
+        while let Some(op) = encoder.next_op().unwrap() {
+            if let DeltaOp::Literal { data } = &op {
+                assert!(data.len() <= MAX_LITERAL);
+                literal += data.len() as u64;
+            }
+            decoder.apply(op).unwrap();
+        }
+        assert!(decoder.is_finished());
+        assert_eq!(encoder.position(), new.len() as u64);
+        assert_eq!(decoder.copied(), encoder.copied());
+        assert_eq!(decoder.written(), new.len() as u64);
+        assert_eq!(std::fs::read(&output_path).unwrap(), new);
+        literal
+    }
+    
+    #[test]
+    fn test_prepended() {
+        let base = base_file();
+        let mut new = b"a new header line\n".to_vec();
+        new.extend_from_slice(&base);
+        // The header and the base's short tail
+        assert_eq!(round_trip(&base, &new), 18 + 300);
+    }
+    
+    #[test]
+    fn test_appended() {
+        let base = base_file();
+        let mut new = base.clone();
+        new.extend(std::iter::repeat_n(7u8, 5000));
+        assert_eq!(round_trip(&base, &new), 300 + 5000);
+    }
+    
+    #[test]
+    fn test_modified_in_place() {
+        let base = base_file();
+        let mut new = base.clone();
+        new[40 * BLOCK + 10] ^= 0xff;
+        new[70 * BLOCK + 999] ^= 0xff;
+        // Only the two damaged blocks go again
+        assert_eq!(round_trip(&base, &new), 2 * BLOCK as u64 + 300);
+    }
+    
+    #[test]
+    fn test_unrelated_file_goes_literally() {
+        let base = base_file();
+        let new: Vec<u8> = base.iter().rev().copied().collect();
+        assert_eq!(round_trip(&base, &new), new.len() as u64);
+        assert_eq!(round_trip(&base, b""), 0);
+    }
+    
+    #[test]
+    fn test_decoder_rejects_wrong_result() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base");
+        std::fs::write(&base_path, vec![1u8; 4 * BLOCK]).unwrap();
+        let mut decoder = DeltaDecoder::new(
+            File::open(&base_path).unwrap(),
+            BLOCK,
+            4,
+            File::create(dir.path().join("output")).unwrap(),
+            2 * BLOCK as u64,
+        );
+        assert!(decoder.apply(DeltaOp::Copy { block: 3, count: 2 }).is_err());
+        decoder.apply(DeltaOp::Copy { block: 0, count: 2 }).unwrap();
+        let err = decoder.apply(DeltaOp::Done { hash: [0; 32] }).unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>(), Some(&TransferError::DeltaMismatch));
+    }
+    
+    #[test]
+    fn test_worth_signing() {
+        assert!(worth_signing(10 * DELTA_BLOCK_SIZE as u64, 10 * DELTA_BLOCK_SIZE as u64, DELTA_BLOCK_SIZE));
+        // No whole block to match against
+        assert!(!worth_signing(1000, 10 * DELTA_BLOCK_SIZE as u64, DELTA_BLOCK_SIZE));
+        // Signatures bigger than a tiny file
+        assert!(!worth_signing(100 * DELTA_BLOCK_SIZE as u64, 100, DELTA_BLOCK_SIZE));
+    }
+}
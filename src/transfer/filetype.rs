@@ -0,0 +1,170 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A file type recognised from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kind {
+    pub name: &'static str,
+    /// Extensions files of this type normally have
+    pub extensions: &'static [&'static str],
+    /// Runnable as a program once saved
+    pub executable: bool,
+}
+
+const fn kind(name: &'static str, extensions: &'static [&'static str], executable: bool) -> Kind {
+    Kind { name, extensions, executable }
+}
+
+/// Magic bytes at the start of a file, in the order they're checked
+const SIGNATURES: &[(&[u8], Kind)] = &[
+    (b"\x7fELF", kind("ELF executable", &[], true)),
+    (b"MZ", kind("Windows executable", &["exe", "dll", "sys", "scr", "com"], true)),
+    (b"\xfe\xed\xfa\xce", kind("Mach-O executable", &[], true)),
+    (b"\xfe\xed\xfa\xcf", kind("Mach-O executable", &[], true)),
+    (b"\xce\xfa\xed\xfe", kind("Mach-O executable", &[], true)),
+    (b"\xcf\xfa\xed\xfe", kind("Mach-O executable", &[], true)),
+    (b"\xca\xfe\xba\xbe", kind("Mach-O universal binary", &["class"], true)),
+    (b"#!", kind("script", &["sh", "bash", "zsh", "py", "pl", "rb"], true)),
+    (b"\xff\xd8\xff", kind("JPEG image", &["jpg", "jpeg"], false)),
+    (b"\x89PNG\r\n\x1a\n", kind("PNG image", &["png"], false)),
+    (b"GIF8", kind("GIF image", &["gif"], false)),
+    (b"%PDF", kind("PDF document", &["pdf"], false)),
+    (b"PK\x03\x04", kind("zip archive", &["zip", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "jar", "apk"], false)),
+    (b"\x1f\x8b", kind("gzip archive", &["gz", "tgz"], false)),
+    (b"BZh", kind("bzip2 archive", &["bz2", "tbz2"], false)),
+    (b"\xfd7zXZ\x00", kind("xz archive", &["xz", "txz"], false)),
+    (b"7z\xbc\xaf\x27\x1c", kind("7-Zip archive", &["7z"], false)),
+    (b"Rar!\x1a\x07", kind("RAR archive", &["rar"], false)),
+    (b"ID3", kind("MP3 audio", &["mp3"], false)),
+    (b"OggS", kind("Ogg media", &["ogg", "oga", "ogv", "opus"], false)),
+    (b"fLaC", kind("FLAC audio", &["flac"], false)),
+];
+
+/// Recognise a file from its first chunk
+pub fn sniff(first_chunk: &[u8]) -> Option<Kind> {
+    if let Some((_, kind)) = SIGNATURES.iter().find(|(magic, _)| first_chunk.starts_with(magic)) {
+        return Some(*kind);
+    }
+    // Containers whose tag isn't at the very start
+    match (first_chunk.get(..4), first_chunk.get(4..8), first_chunk.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => Some(kind("WebP image", &["webp"], false)),
+        (Some(b"RIFF"), _, Some(b"WAVE")) => Some(kind("WAV audio", &["wav"], false)),
+        (Some(b"RIFF"), _, Some(b"AVI ")) => Some(kind("AVI video", &["avi"], false)),
+        (_, Some(b"ftyp"), _) => Some(kind("MP4 media", &["mp4", "m4a", "m4v", "mov", "heic"], false)),
+        _ => None,
+    }
+}
+
+/// Lower-cased extension of a received file's name
+fn extension(filename: &str) -> Option<String> {
+    Path::new(filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Extensions the receiver is willing to accept, like `jpg,png,pdf`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptTypes(Vec<String>);
+
+impl AcceptTypes {
+    /// Parse a comma-separated list; dots and case are ignored
+    pub fn parse(list: &str) -> Self {
+        Self(
+            list.split(',')
+                .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect(),
+        )
+    }
+    
+    fn contains(&self, ext: &str) -> bool {
+        self.0.iter().any(|accepted| accepted == ext)
+    }
+    
+    /// Check a file's name, and its contents when they're recognisable
+    ///
+    /// Both must agree with the list: a `.jpg` that is really a program is
+    /// refused even though `jpg` is accepted. Returns the reason on refusal.
+    pub fn check(&self, filename: &str, first_chunk: &[u8]) -> Result<(), String> {
+        let Some(ext) = extension(filename).filter(|ext| self.contains(ext)) else {
+            return Err(format!("{} is not one of the accepted file types ({})", filename, self));
+        };
+        match sniff(first_chunk) {
+            Some(kind) if !kind.extensions.iter().any(|known| self.contains(known)) => Err(format!(
+                "{} is named .{} but its contents were recognised as {}",
+                filename, ext, kind.name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for AcceptTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
+
+/// Why a file looks like a program, if it does
+pub fn executable_warning(filename: &str, first_chunk: &[u8]) -> Option<String> {
+    sniff(first_chunk)
+        .filter(|kind| kind.executable)
+        .map(|kind| format!("{} looks like a program ({}). Only open it if you trust the sender.", filename, kind.name))
+}
+
+/// Asked to confirm a received file that looks like a program
+#[derive(Clone)]
+pub struct ConfirmPrompt(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl ConfirmPrompt {
+    /// `confirm` gets the warning to show and returns whether to accept the file anyway
+    pub fn new(confirm: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(confirm))
+    }
+    
+    pub fn confirm(&self, warning: &str) -> bool {
+        (self.0)(warning)
+    }
+}
+
+impl fmt::Debug for ConfirmPrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfirmPrompt")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF";
+    const ELF: &[u8] = b"\x7fELF\x02\x01\x01\x00";
+    
+    #[test]
+    fn test_extension_mismatch() {
+        let accept = AcceptTypes::parse("jpg, .PNG,pdf");
+        assert_eq!(accept.check("holiday.JPG", JPEG), Ok(()));
+        assert_eq!(accept.check("notes.txt", b"hello").unwrap_err(), "notes.txt is not one of the accepted file types (jpg, png, pdf)");
+        assert!(accept.check("no-extension", JPEG).is_err());
+    }
+    
+    #[test]
+    fn test_magic_mismatch_with_lying_extension() {
+        let accept = AcceptTypes::parse("jpg,png");
+        assert_eq!(accept.check("holiday.jpg", ELF).unwrap_err(), "holiday.jpg is named .jpg but its contents were recognised as ELF executable");
+        assert!(accept.check("holiday.jpg", b"\x89PNG\r\n\x1a\n").is_ok());
+        assert!(accept.check("holiday.png", b"%PDF-1.7").is_err());
+        // Contents nobody recognises are judged by name alone
+        assert!(accept.check("holiday.jpg", b"plain text").is_ok());
+    }
+    
+    #[test]
+    fn test_executable_warning() {
+        assert!(executable_warning("tool", ELF).unwrap().contains("ELF executable"));
+        assert!(executable_warning("setup.exe", b"MZ\x90\x00").is_some());
+        assert!(executable_warning("install.sh", b"#!/bin/sh\n").is_some());
+        assert!(executable_warning("photo.jpg", JPEG).is_none());
+        assert!(executable_warning("empty", b"").is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::protocol::ManifestEntry;
+
+/// Files already in an archive, by `(device, inode)`, so further hard links
+/// to them go in as links instead of another copy of the contents
+///
+/// Outside Unix there are no inode numbers to go by, and every link is
+/// archived as a file of its own.
+#[derive(Debug, Default)]
+pub struct HardLinkTracker {
+    inodes: HashMap<(u64, u64), PathBuf>,
+}
+
+impl HardLinkTracker {
+    /// The name an earlier link to the same file went into the archive as
+    ///
+    /// When there isn't one, `name` is remembered for the links that follow.
+    #[cfg(unix)]
+    pub fn earlier_name(&mut self, metadata: &Metadata, name: &Path) -> Option<PathBuf> {
+        use std::collections::hash_map::Entry;
+        use std::os::unix::fs::MetadataExt;
+        
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            return None;
+        }
+        match self.inodes.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(first) => Some(first.get().clone()),
+            Entry::Vacant(slot) => {
+                slot.insert(name.to_path_buf());
+                None
+            }
+        }
+    }
+    
+    #[cfg(not(unix))]
+    pub fn earlier_name(&mut self, _metadata: &Metadata, _name: &Path) -> Option<PathBuf> {
+        None
+    }
+    
+    /// Append `path` to `archive` as `name`, as a hard link if its file is already in there
+    ///
+    /// A regular file is hashed as it's read into the archive, and comes back
+    /// as the entry the receiver checks it against; links and folders don't,
+    /// nor do files whose names can't go in a manifest.
+    pub fn append<W: Write>(&mut self, archive: &mut tar::Builder<W>, path: &Path, name: &Path) -> Result<Option<ManifestEntry>> {
+        debug_assert!(super::wire_path::is_contained(name), "tar entry {} escapes the folder", name.display());
+        // Followed through symlinks, like the walk and the archive itself
+        let metadata = fs::metadata(path)?;
+        if let Some(target) = self.earlier_name(&metadata, name) {
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_size(0);
+            archive.append_link(&mut header, name, target)?;
+            return Ok(None);
+        }
+        let manifest_path = super::wire_path::manifest_path(name).ok();
+        let (true, Some(manifest_path)) = (metadata.is_file(), manifest_path) else {
+            archive.append_path_with_name(path, name)?;
+            return Ok(None);
+        };
+        
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        let mut file = HashingReader::new(File::open(path)?);
+        archive.append_data(&mut header, name, &mut file)?;
+        Ok(Some(ManifestEntry {
+            path: manifest_path,
+            size: metadata.len(),
+            mtime: metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            checksum: file.finalize(),
+        }))
+    }
+}
+
+/// Reader that hashes what passes through it with BLAKE3
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, hasher: blake3::Hasher::new() }
+    }
+    
+    /// The hash of everything read, in hex
+    pub fn finalize(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::transfer::{create_tar_archive, extract_tar_archive};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_hard_links_archived_once() {
+        const SIZE: usize = 1024 * 1024;
+        
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("packages");
+        fs::create_dir_all(source.join("lib")).unwrap();
+        fs::write(source.join("lib/libzap.so"), vec![7u8; SIZE]).unwrap();
+        fs::hard_link(source.join("lib/libzap.so"), source.join("lib/libzap.so.1")).unwrap();
+        fs::write(source.join("readme.txt"), "not linked").unwrap();
+        
+        let archive_path = dir.path().join("packages.tar");
+        create_tar_archive(&source, &archive_path).unwrap();
+        let archive_size = fs::metadata(&archive_path).unwrap().len() as usize;
+        // One copy of the contents plus headers and padding, not two
+        assert!(archive_size > SIZE && archive_size < SIZE + 16 * 1024, "archive is {} bytes", archive_size);
+        
+        let output = dir.path().join("output");
+        extract_tar_archive(&archive_path, &output).unwrap();
+        let first = fs::metadata(output.join("lib/libzap.so")).unwrap();
+        let second = fs::metadata(output.join("lib/libzap.so.1")).unwrap();
+        assert_eq!(fs::read(output.join("lib/libzap.so.1")).unwrap(), vec![7u8; SIZE]);
+        assert_eq!((first.ino(), first.nlink()), (second.ino(), 2), "still linked after extracting");
+        assert_eq!(fs::read_to_string(output.join("readme.txt")).unwrap(), "not linked");
+    }
+}
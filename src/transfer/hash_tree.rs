@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tokio::sync::mpsc;
+
+use crate::protocol::ManifestEntry;
+
+use super::wire_path::{self, manifest_path};
+
+/// Files at least this big are hashed on every core instead of one
+pub const PARALLEL_HASH_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// How much of a big file the multithreaded hasher gets at a time
+const PARALLEL_HASH_BUFFER: usize = 16 * 1024 * 1024;
+
+/// Hashed files waiting for the caller before the workers stop to let it catch up
+const RESULT_QUEUE: usize = 256;
+
+/// A file's size and modification time, to tell next time whether it may have changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch
+    pub mtime_ns: u64,
+}
+
+impl Stamp {
+    pub fn of(metadata: &fs::Metadata) -> io::Result<Self> {
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            mtime_ns: mtime.as_nanos().try_into().unwrap_or(u64::MAX),
+        })
+    }
+}
+
+/// Entries already worked out for files as they were stamped, keyed by manifest path
+pub type Known = HashMap<String, (Stamp, ManifestEntry)>;
+
+/// One file's manifest entry, with the stamp it had before it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashedFile {
+    pub entry: ManifestEntry,
+    pub stamp: Stamp,
+    /// False when the entry came from `Known` instead of reading the file
+    pub read: bool,
+}
+
+/// The files under a folder, hashed by a pool of threads and handed over as each one finishes
+pub struct TreeHashes {
+    total: usize,
+    rx: mpsc::Receiver<Result<HashedFile>>,
+}
+
+impl TreeHashes {
+    /// How many files there are to hash
+    pub fn total(&self) -> usize {
+        self.total
+    }
+    
+    /// The next file to finish hashing, in no particular order
+    pub async fn next(&mut self) -> Option<Result<HashedFile>> {
+        self.rx.recv().await
+    }
+    
+    /// Wait for every file and sort them by path, calling `progress` with
+    /// files done and total files after each one
+    pub async fn collect(self, progress: impl FnMut(u64, u64)) -> Result<Vec<ManifestEntry>> {
+        Ok(self.collect_files(progress).await?.into_iter().map(|file| file.entry).collect())
+    }
+    
+    /// Like `collect`, keeping each file's stamp
+    pub async fn collect_files(mut self, mut progress: impl FnMut(u64, u64)) -> Result<Vec<HashedFile>> {
+        let mut files = Vec::with_capacity(self.total);
+        while let Some(file) = self.next().await {
+            files.push(file?);
+            progress(files.len() as u64, self.total as u64);
+        }
+        if files.len() != self.total {
+            return Err(anyhow!("Hashing stopped after {} of {} files", files.len(), self.total));
+        }
+        files.sort_by(|a, b| a.entry.path.cmp(&b.entry.path));
+        Ok(files)
+    }
+}
+
+/// Hash every file under `root` on `parallelism` threads, or one per core when it's 0
+///
+/// Files and folders named in `excludes`, by name or by path relative to
+/// `root`, are left out. Links are followed as archives follow them. The
+/// folder is walked before hashing starts, so the total is known up front.
+pub async fn hash_tree(root: &Path, excludes: &[String], parallelism: usize) -> Result<TreeHashes> {
+    hash_tree_reusing(root, excludes, parallelism, Known::new()).await
+}
+
+/// Like `hash_tree`, taking a file's entry from `known` instead when its stamp still matches
+pub async fn hash_tree_reusing(root: &Path, excludes: &[String], parallelism: usize, known: Known) -> Result<TreeHashes> {
+    let (root, excludes) = (root.to_path_buf(), excludes.to_vec());
+    let files = tokio::task::spawn_blocking(move || walk(&root, &excludes)).await??;
+    
+    let total = files.len();
+    let threads = match parallelism {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        threads => threads,
+    };
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let known = Arc::new(known);
+    let (tx, rx) = mpsc::channel(RESULT_QUEUE);
+    for _ in 0..threads.min(total) {
+        let (queue, tx, known) = (queue.clone(), tx.clone(), known.clone());
+        std::thread::spawn(move || loop {
+            let Some((path, relative)) = queue.lock().unwrap().next() else {
+                break;
+            };
+            // The caller stopped listening, so there's no point hashing the rest
+            if tx.blocking_send(hash_file(&path, relative, &known)).is_err() {
+                break;
+            }
+        });
+    }
+    Ok(TreeHashes { total, rx })
+}
+
+/// Every file under `root` and its manifest path, sorted by file name
+fn walk(root: &Path, excludes: &[String]) -> Result<Vec<(PathBuf, String)>> {
+    if !root.is_dir() {
+        return Err(anyhow!("{} isn't a folder", root.display()));
+    }
+    let excluded = |path: &Path| {
+        let name = path.file_name().and_then(|name| name.to_str());
+        let relative = wire_path::relative_to(root, path).ok().and_then(|relative| manifest_path(&relative).ok());
+        excludes.iter().any(|exclude| Some(exclude.as_str()) == name || Some(exclude) == relative.as_ref())
+    };
+    
+    let mut files = Vec::new();
+    let walker = walkdir::WalkDir::new(root).min_depth(1).follow_links(true).sort_by_file_name();
+    for entry in walker.into_iter().filter_entry(|entry| !excluded(entry.path())) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = manifest_path(&wire_path::relative_to(root, entry.path())?)?;
+        files.push((entry.into_path(), relative));
+    }
+    Ok(files)
+}
+
+/// Hash the file at `path`, unless `known` has an entry for it with the stamp it has now
+///
+/// The stamp is taken before reading, so a file that changes while it's
+/// hashed is stamped as it was and doesn't match next time.
+fn hash_file(path: &Path, relative: String, known: &Known) -> Result<HashedFile> {
+    let hash = || -> io::Result<HashedFile> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let stamp = Stamp::of(&metadata)?;
+        if let Some((_, entry)) = known.get(&relative).filter(|(was, _)| *was == stamp) {
+            return Ok(HashedFile { entry: entry.clone(), stamp, read: false });
+        }
+        let mut hasher = blake3::Hasher::new();
+        if metadata.len() >= PARALLEL_HASH_THRESHOLD {
+            let mut buf = vec![0u8; PARALLEL_HASH_BUFFER];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update_rayon(&buf[..n]);
+            }
+        } else {
+            io::copy(&mut file, &mut hasher)?;
+        }
+        let entry = ManifestEntry {
+            path: relative,
+            size: metadata.len(),
+            mtime: metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            checksum: hasher.finalize().to_hex().to_string(),
+        };
+        Ok(HashedFile { entry, stamp, read: true })
+    };
+    hash().map_err(|e| anyhow!("Couldn't hash {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    
+    fn tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for folder in 0..10 {
+            fs::create_dir_all(dir.path().join(format!("folder{}/nested", folder))).unwrap();
+            for file in 0..20 {
+                let path = dir.path().join(format!("folder{}/file{}.txt", folder, file));
+                fs::write(path, format!("{} {}", folder, file).repeat(folder * 100 + file)).unwrap();
+            }
+            fs::write(dir.path().join(format!("folder{}/nested/deep.txt", folder)), "deep").unwrap();
+        }
+        fs::create_dir_all(dir.path().join(".git/objects")).unwrap();
+        fs::write(dir.path().join(".git/objects/abc"), "object").unwrap();
+        dir
+    }
+    
+    #[tokio::test]
+    async fn test_manifest_is_the_same_on_any_number_of_threads() {
+        let dir = tree();
+        let single = hash_tree(dir.path(), &[], 1).await.unwrap().collect(|_, _| {}).await.unwrap();
+        assert_eq!(single.len(), 211);
+        assert!(single.windows(2).all(|pair| pair[0].path < pair[1].path), "manifest isn't sorted");
+        
+        let mut seen = Vec::new();
+        let hashes = hash_tree(dir.path(), &[], 8).await.unwrap();
+        assert_eq!(hashes.total(), 211);
+        let parallel = hashes.collect(|done, total| seen.push((done, total))).await.unwrap();
+        assert_eq!(parallel, single);
+        assert_eq!(seen.len(), 211);
+        assert_eq!(seen.last(), Some(&(211, 211)));
+        
+        let entry = single.iter().find(|entry| entry.path == "folder3/file7.txt").unwrap();
+        let contents = "3 7".repeat(307);
+        assert_eq!(entry.size, contents.len() as u64);
+        assert_eq!(entry.checksum, blake3::hash(contents.as_bytes()).to_hex().to_string());
+    }
+    
+    #[tokio::test]
+    async fn test_excludes() {
+        let dir = tree();
+        let excludes = [".git".to_string(), "folder1".to_string(), "folder2/nested/deep.txt".to_string()];
+        let manifest = hash_tree(dir.path(), &excludes, 4).await.unwrap().collect(|_, _| {}).await.unwrap();
+        assert_eq!(manifest.len(), 211 - 1 - 21 - 1);
+        assert!(manifest.iter().all(|entry| !entry.path.starts_with(".git/") && !entry.path.starts_with("folder1/")));
+        assert!(!manifest.iter().any(|entry| entry.path == "folder2/nested/deep.txt"));
+        assert!(manifest.iter().any(|entry| entry.path == "folder3/nested/deep.txt"), "only that path is excluded");
+        
+        assert!(hash_tree(&dir.path().join("folder0/file0.txt"), &[], 1).await.is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::crypto::ChecksumAlgorithm;
+
+/// Chunks written but not yet hashed before the write path waits for the hash stage
+pub const HASH_QUEUE: usize = 4;
+
+/// How a receiver checks what arrives, as `--checksum` picks it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumChoice {
+    /// BLAKE3 when the sender can, SHA-256 otherwise
+    #[default]
+    Blake3,
+    /// SHA-256 even when BLAKE3 would do, for receipts older tools can check
+    Sha256,
+    /// No receipts or checkpoints, so nothing is hashed; the encryption still catches damage on the way
+    Off,
+}
+
+impl FromStr for ChecksumChoice {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "blake3" => Ok(Self::Blake3),
+            "sha256" | "sha-256" => Ok(Self::Sha256),
+            "none" | "off" => Ok(Self::Off),
+            other => Err(anyhow!("Unknown checksum '{}' (expected blake3, sha256 or none)", other)),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+            Self::Off => "none",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Checksums a file as it's written, on a thread of its own
+///
+/// The write path hands each chunk over once it's on disk and carries on,
+/// so hashing overlaps the network instead of holding up the next chunk; it
+/// only waits when the stage falls `HASH_QUEUE` chunks behind.
+pub struct HashStage {
+    chunks: mpsc::Sender<Vec<u8>>,
+    /// Taken when the stage stops early, to say why
+    worker: Option<JoinHandle<Result<(String, Duration)>>>,
+}
+
+impl HashStage {
+    /// Start checksumming by `algorithm`, first reading back the `already` bytes an earlier attempt left at the start of `path`
+    pub fn start(algorithm: ChecksumAlgorithm, path: &Path, already: u64) -> Self {
+        let (chunks, mut queued) = mpsc::channel::<Vec<u8>>(HASH_QUEUE);
+        let path = path.to_path_buf();
+        let worker = tokio::task::spawn_blocking(move || {
+            let mut hasher = algorithm.hasher();
+            let started = Instant::now();
+            if already > 0 {
+                let file = File::open(&path).map_err(|e| anyhow!("Couldn't read back {}: {}", path.display(), e))?;
+                io::copy(&mut file.take(already), &mut hasher)?;
+            }
+            let mut busy = started.elapsed();
+            while let Some(chunk) = queued.blocking_recv() {
+                let started = Instant::now();
+                hasher.update(&chunk);
+                busy += started.elapsed();
+            }
+            Ok((hasher.checksum(), busy))
+        });
+        Self { chunks, worker: Some(worker) }
+    }
+    
+    /// Hand over `chunk`, just written after everything handed over before it
+    pub async fn push(&mut self, chunk: Vec<u8>) -> Result<()> {
+        if self.chunks.send(chunk).await.is_ok() {
+            return Ok(());
+        }
+        // The stage only stops by itself when it fails
+        let worker = self.worker.take().ok_or_else(stopped)?;
+        worker.await??;
+        Err(stopped())
+    }
+    
+    /// The checksum of everything handed over, and how long the stage spent hashing it
+    pub async fn finish(self) -> Result<(String, Duration)> {
+        drop(self.chunks);
+        self.worker.ok_or_else(stopped)?.await?
+    }
+}
+
+fn stopped() -> anyhow::Error {
+    anyhow!("Checksumming what arrived stopped early")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use tempfile::TempDir;
+    
+    #[tokio::test]
+    async fn test_stage_matches_whole_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("received.bin");
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        
+        let mut stage = HashStage::start(ChecksumAlgorithm::Sha256, &path, 0);
+        for chunk in data.chunks(64 * 1024) {
+            stage.push(chunk.to_vec()).await.unwrap();
+        }
+        assert_eq!(stage.finish().await.unwrap().0, crypto::checksum(&data));
+        
+        // Picking up after the first 100000 bytes, which are read back from disk
+        std::fs::write(&path, &data[..100_000]).unwrap();
+        let mut stage = HashStage::start(ChecksumAlgorithm::Blake3, &path, 100_000);
+        stage.push(data[100_000..].to_vec()).await.unwrap();
+        assert_eq!(stage.finish().await.unwrap().0, format!("blake3:{}", blake3::hash(&data).to_hex()));
+        
+        // A partial file that's gone can't be vouched for
+        let mut stage = HashStage::start(ChecksumAlgorithm::Sha256, &dir.path().join("missing.bin"), 10);
+        let err = loop {
+            // Handed over until the stage has given up, which is then what's said
+            if let Err(e) = stage.push(vec![0; 10]).await {
+                break e;
+            }
+        };
+        assert!(err.to_string().contains("Couldn't read back"), "{}", err);
+        assert!(stage.finish().await.is_err());
+    }
+    
+    #[test]
+    fn test_choice_round_trips() {
+        for choice in [ChecksumChoice::Blake3, ChecksumChoice::Sha256, ChecksumChoice::Off] {
+            assert_eq!(choice.to_string().parse::<ChecksumChoice>().unwrap(), choice);
+        }
+        assert_eq!("SHA-256".parse::<ChecksumChoice>().unwrap(), ChecksumChoice::Sha256);
+        assert!("md5".parse::<ChecksumChoice>().is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fsutil;
+
+/// The most entries the history keeps; older ones are dropped as new ones come in
+pub const MAX_HISTORY_ENTRIES: usize = 1000;
+
+/// Which way a file in the history went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A file that went through zap, as it was then
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the transfer finished, in seconds since the Unix epoch
+    pub at: u64,
+    pub direction: Direction,
+    /// Absolute path of the file
+    pub path: PathBuf,
+    pub size: u64,
+    /// SHA-256 of the file, in hex, or its BLAKE3 after `blake3:`, as in receipts
+    pub checksum: String,
+}
+
+impl HistoryEntry {
+    /// An entry for the file at `path`, with `checksum`, that went `direction` just now
+    pub fn new(direction: Direction, path: &Path, size: u64, checksum: &str) -> Result<Self> {
+        Ok(Self {
+            at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            direction,
+            path: std::path::absolute(path)?,
+            size,
+            checksum: checksum.to_string(),
+        })
+    }
+}
+
+/// A log of the single files sent and received, one JSON entry per line, oldest first
+///
+/// What `zap verify --from-history` checks a file against. Folders, streams
+/// and piped transfers aren't in it: there's no one file to check later.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    /// Most entries kept
+    limit: usize,
+}
+
+impl History {
+    /// The log at `path`
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), limit: MAX_HISTORY_ENTRIES }
+    }
+    
+    /// Where the log is kept unless `--history` says otherwise: `zap/history.jsonl` in the user's data directory
+    pub fn default_path() -> Option<PathBuf> {
+        Some(dirs::data_dir()?.join("zap").join("history.jsonl"))
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    /// Every entry, oldest first; a missing log has none, and lines that can't be read are left out
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>> {
+        let data = match std::fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("Couldn't read the history in {}: {}", self.path.display(), e)),
+        };
+        Ok(data.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+    
+    /// The most recent entry for the file at `path`
+    pub fn latest(&self, path: &Path) -> Result<Option<HistoryEntry>> {
+        let path = std::path::absolute(path)?;
+        Ok(self.entries()?.into_iter().rev().find(|entry| entry.path == path))
+    }
+    
+    /// Add `entry`, keeping the log private to the user and at most `MAX_HISTORY_ENTRIES` long
+    ///
+    /// The log is replaced in one go, so a crash midway leaves the old one.
+    pub fn record(&self, entry: &HistoryEntry) -> Result<()> {
+        let mut entries = self.entries()?;
+        entries.push(entry.clone());
+        let kept = &entries[entries.len().saturating_sub(self.limit)..];
+        let mut data = Vec::new();
+        for entry in kept {
+            serde_json::to_writer(&mut data, entry)?;
+            data.push(b'\n');
+        }
+        
+        let dir = self.path.parent().ok_or_else(|| anyhow!("No folder for {}", self.path.display()))?;
+        fsutil::create_private_dir(dir)?;
+        let mut part = self.path.as_os_str().to_os_string();
+        part.push(".part");
+        fsutil::write_private(Path::new(&part), &data)?;
+        std::fs::rename(&part, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[test]
+    fn test_latest_entry_for_a_path() {
+        let dir = TempDir::new().unwrap();
+        let history = History::at(dir.path().join("zap").join("history.jsonl"));
+        let photo = dir.path().join("photo.jpg");
+        assert_eq!(history.latest(&photo).unwrap(), None);
+        
+        let first = HistoryEntry::new(Direction::Received, &photo, 10, &"a".repeat(64)).unwrap();
+        let other = HistoryEntry::new(Direction::Sent, &dir.path().join("notes.txt"), 5, &"b".repeat(64)).unwrap();
+        let second = HistoryEntry { checksum: format!("blake3:{}", "c".repeat(64)), ..first.clone() };
+        for entry in [&first, &other, &second] {
+            history.record(entry).unwrap();
+        }
+        assert_eq!(history.latest(&photo).unwrap(), Some(second));
+        assert_eq!(history.entries().unwrap().len(), 3);
+        
+        // A damaged line costs only itself
+        let mut data = std::fs::read_to_string(history.path()).unwrap();
+        data.insert_str(0, "{not json\n");
+        std::fs::write(history.path(), data).unwrap();
+        assert_eq!(history.entries().unwrap().len(), 3);
+    }
+    
+    #[test]
+    fn test_oldest_entries_dropped() {
+        let dir = TempDir::new().unwrap();
+        let history = History { limit: 10, ..History::at(dir.path().join("history.jsonl")) };
+        for i in 0..15 {
+            let entry = HistoryEntry::new(Direction::Sent, &dir.path().join(format!("{}.bin", i)), i as u64, &"a".repeat(64)).unwrap();
+            history.record(&entry).unwrap();
+        }
+        let entries = history.entries().unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[0].size, 5);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(history.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
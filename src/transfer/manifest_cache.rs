@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fsutil;
+use crate::protocol::ManifestEntry;
+
+use super::hash_tree::{HashedFile, Known, Stamp};
+
+/// Bumped whenever what's cached changes meaning; other versions are thrown away
+pub const MANIFEST_CACHE_VERSION: u32 = 1;
+
+/// Files changed this close to when the cache was started aren't trusted
+///
+/// A write in the same tick of the filesystem's clock as the stamp can
+/// leave the modification time as it was; FAT's tick is two seconds.
+const RACY_WINDOW_NS: u64 = 2_000_000_000;
+
+/// A folder's manifest as it was last built, so unchanged files needn't be read again
+///
+/// A file is only taken from the cache when its size and modification time
+/// (to the nanosecond) are what they were, and it had already stopped changing
+/// before that build started. Anything that rewrites a file keeping both the
+/// same, like `touch -r`, isn't noticed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestCache {
+    version: u32,
+    /// Absolute path of the folder
+    root: PathBuf,
+    /// When the build that wrote this started, in nanoseconds since the Unix epoch
+    started_ns: u64,
+    files: Vec<CachedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    stamp: Stamp,
+    entry: ManifestEntry,
+}
+
+impl ManifestCache {
+    /// Where the manifest of the folder at `root` is cached: the user's cache directory, or the temp directory
+    pub fn cache_path(root: &Path) -> PathBuf {
+        let absolute = std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf());
+        let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+        let name = root.file_name().unwrap_or_default().to_string_lossy();
+        let dir = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        dir.join("zap").join("manifests").join(format!("{}-{}.json", name, &hash[..16]))
+    }
+    
+    /// A cache for `files`, hashed by a build that started at `started`
+    pub fn new(root: &Path, started: SystemTime, files: &[HashedFile]) -> Self {
+        Self {
+            version: MANIFEST_CACHE_VERSION,
+            root: std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf()),
+            started_ns: nanos(started),
+            files: files
+                .iter()
+                .map(|file| CachedFile {
+                    stamp: file.stamp,
+                    entry: file.entry.clone(),
+                })
+                .collect(),
+        }
+    }
+    
+    /// The entries in `cache_file` that can be trusted for the folder at `root`
+    ///
+    /// A missing, unreadable or damaged cache, or one written by another
+    /// version or for another folder, has nothing to offer.
+    pub fn load(cache_file: &Path, root: &Path) -> Known {
+        let Ok(data) = std::fs::read(cache_file) else {
+            return Known::new();
+        };
+        let Ok(cache) = serde_json::from_slice::<Self>(&data) else {
+            return Known::new();
+        };
+        let root = std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf());
+        if cache.version != MANIFEST_CACHE_VERSION || cache.root != root {
+            return Known::new();
+        }
+        cache
+            .files
+            .into_iter()
+            .filter(|file| file.stamp.mtime_ns.saturating_add(RACY_WINDOW_NS) < cache.started_ns)
+            .map(|file| (file.entry.path.clone(), (file.stamp, file.entry)))
+            .collect()
+    }
+    
+    /// Write the cache to `cache_file`, private to the user, replacing what was there in one go
+    pub fn save(&self, cache_file: &Path) -> Result<()> {
+        let dir = cache_file.parent().ok_or_else(|| anyhow!("No folder for {}", cache_file.display()))?;
+        fsutil::create_private_dir(dir)?;
+        let mut part = cache_file.as_os_str().to_os_string();
+        part.push(".part");
+        fsutil::write_private(Path::new(&part), &serde_json::to_vec(self)?)?;
+        std::fs::rename(&part, cache_file)?;
+        Ok(())
+    }
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_nanos().try_into().unwrap_or(u64::MAX)
+}
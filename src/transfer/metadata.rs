@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Puts a received file in place, sets its modification time and permissions, and links it to others
+///
+/// The system one is what zap uses; tests swap in one that fails on purpose.
+pub trait MetadataFs: Send {
+    fn set_mtime(&self, path: &Path, mtime: SystemTime) -> io::Result<()>;
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+    
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+    
+    /// Move `unpacked`, in the staging directory, over `target`
+    fn place(&self, unpacked: &Path, target: &Path) -> io::Result<()> {
+        super::conflict::move_into_place(unpacked, target)
+    }
+}
+
+/// The real filesystem
+pub struct SystemFs;
+
+impl MetadataFs for SystemFs {
+    fn set_mtime(&self, path: &Path, mtime: SystemTime) -> io::Result<()> {
+        File::options().write(true).open(path)?.set_modified(mtime)
+    }
+    
+    #[cfg(unix)]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+    
+    /// Only the read-only bit carries over
+    #[cfg(not(unix))]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+}
+
+/// A received file whose modification time or permissions couldn't be set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataWarning {
+    /// Where the file is, relative to the output directory
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for MetadataWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
+    }
+}
+
+/// Gives received files the sender's modification times and permissions
+///
+/// Some filesystems (FAT and exFAT drives, certain network mounts) refuse
+/// one or both. The file itself arrived fine, so by default each refusal is
+/// noted and the transfer carries on; `strict` makes it an error instead.
+pub struct MetadataApplier {
+    fs: Box<dyn MetadataFs>,
+    strict: bool,
+    warnings: Vec<MetadataWarning>,
+}
+
+impl MetadataApplier {
+    pub fn new(fs: Box<dyn MetadataFs>, strict: bool) -> Self {
+        Self {
+            fs,
+            strict,
+            warnings: Vec::new(),
+        }
+    }
+    
+    /// Set both on `path`, naming it `relative` in any warning
+    pub fn apply(&mut self, path: &Path, relative: &Path, mode: u32, mtime: SystemTime) -> Result<()> {
+        // The time first, as setting it needs write access the permissions may take away
+        let results = [
+            ("modification time", self.fs.set_mtime(path, mtime)),
+            ("permissions", self.fs.set_permissions(path, mode)),
+        ];
+        for (what, result) in results {
+            let Err(e) = result else {
+                continue;
+            };
+            let warning = MetadataWarning {
+                path: relative.to_path_buf(),
+                reason: format!("couldn't set {}: {}", what, e),
+            };
+            if self.strict {
+                return Err(anyhow!("{}", warning));
+            }
+            self.warnings.push(warning);
+        }
+        Ok(())
+    }
+    
+    /// Move the file unpacked at `unpacked` over `target`
+    pub fn place(&self, unpacked: &Path, target: &Path) -> io::Result<()> {
+        self.fs.place(unpacked, target)
+    }
+    
+    /// Make `link` a hard link to `original`, or a copy of it where links can't be made
+    ///
+    /// FAT and exFAT drives and some network mounts have no hard links. The
+    /// copy is noted like any other metadata that didn't carry over, or with
+    /// `strict` the transfer fails instead.
+    pub fn link_or_copy(&mut self, original: &Path, link: &Path, relative: &Path, original_relative: &Path) -> Result<()> {
+        let Err(e) = self.fs.hard_link(original, link) else {
+            return Ok(());
+        };
+        // A missing original is a broken archive, not a filesystem without links
+        if self.strict || e.kind() == io::ErrorKind::NotFound {
+            return Err(anyhow!("{}: couldn't hard link it to {}: {}", relative.display(), original_relative.display(), e));
+        }
+        fs::copy(original, link)?;
+        self.warnings.push(MetadataWarning {
+            path: relative.to_path_buf(),
+            reason: format!("couldn't hard link it to {}, so it's a copy: {}", original_relative.display(), e),
+        });
+        Ok(())
+    }
+    
+    /// Everything that couldn't be set so far, in the order it happened
+    pub fn take_warnings(&mut self) -> Vec<MetadataWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+}
+
+impl Default for MetadataApplier {
+    fn default() -> Self {
+        Self::new(Box::new(SystemFs), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::conflict::{extract_with_conflicts, ConflictResolver};
+    use crate::transfer::ConflictStrategy;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+    
+    /// Refuses the mtime, permissions or links of files whose name contains a marker, like a FAT drive would
+    struct Fussy {
+        no_mtime: &'static str,
+        no_permissions: &'static str,
+        no_links: &'static str,
+    }
+    
+    impl MetadataFs for Fussy {
+        fn set_mtime(&self, path: &Path, mtime: SystemTime) -> io::Result<()> {
+            if path.to_string_lossy().contains(self.no_mtime) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Operation not permitted"));
+            }
+            SystemFs.set_mtime(path, mtime)
+        }
+        
+        fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+            if path.to_string_lossy().contains(self.no_permissions) {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "not supported by the filesystem"));
+            }
+            SystemFs.set_permissions(path, mode)
+        }
+        
+        fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+            if original.to_string_lossy().contains(self.no_links) {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "Operation not supported"));
+            }
+            fs::hard_link(original, link)
+        }
+    }
+    
+    /// Runs out of quota for files whose name contains a marker
+    struct OverQuota(&'static str);
+    
+    impl MetadataFs for OverQuota {
+        fn set_mtime(&self, path: &Path, mtime: SystemTime) -> io::Result<()> {
+            SystemFs.set_mtime(path, mtime)
+        }
+        
+        fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+            SystemFs.set_permissions(path, mode)
+        }
+        
+        fn place(&self, unpacked: &Path, target: &Path) -> io::Result<()> {
+            if target.to_string_lossy().contains(self.0) {
+                return Err(io::Error::new(io::ErrorKind::QuotaExceeded, "Disk quota exceeded"));
+            }
+            SystemFs.place(unpacked, target)
+        }
+    }
+    
+    fn archive(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("sent.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for name in ["fine.txt", "usb/clock.txt", "usb/mode.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(name.len() as u64);
+            header.set_mode(0o640);
+            header.set_mtime(1_000_000);
+            builder.append_data(&mut header, name, name.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap();
+        path
+    }
+    
+    #[test]
+    fn test_failures_become_warnings() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive(&dir);
+        let output = dir.path().join("out");
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        let mut applier = MetadataApplier::new(Box::new(Fussy { no_mtime: "clock.txt", no_permissions: "mode.txt", no_links: "usb" }), false);
+        
+        extract_with_conflicts(&archive, &output, dir.path(), &mut resolver, &mut applier, false).unwrap();
+        // Every file arrives regardless
+        for name in ["fine.txt", "usb/clock.txt", "usb/mode.txt"] {
+            assert_eq!(fs::read(output.join(name)).unwrap(), name.as_bytes());
+        }
+        let modified = fs::metadata(output.join("fine.txt")).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_000_000));
+        
+        let warnings = applier.take_warnings();
+        assert_eq!(warnings.iter().map(|w| w.path.clone()).collect::<Vec<_>>(), [PathBuf::from("usb/clock.txt"), PathBuf::from("usb/mode.txt")]);
+        assert_eq!(warnings[0].to_string(), "usb/clock.txt: couldn't set modification time: Operation not permitted");
+        assert!(warnings[1].reason.starts_with("couldn't set permissions"));
+        assert!(applier.take_warnings().is_empty());
+    }
+    
+    #[test]
+    fn test_strict_metadata_fails_the_transfer() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive(&dir);
+        let output = dir.path().join("out");
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        let mut applier = MetadataApplier::new(Box::new(Fussy { no_mtime: "missing.txt", no_permissions: "mode.txt", no_links: "usb" }), true);
+        
+        let err = extract_with_conflicts(&archive, &output, dir.path(), &mut resolver, &mut applier, false).unwrap_err();
+        assert!(err.to_string().starts_with("usb/mode.txt: couldn't set permissions"), "{}", err);
+    }
+    
+    #[test]
+    fn test_links_fall_back_to_copies() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("linked.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for name in ["disk/lib.so", "usb/lib.so"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(7);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, &b"library"[..]).unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_size(0);
+            builder.append_link(&mut header, format!("{}.1", name), name).unwrap();
+        }
+        builder.into_inner().unwrap();
+        
+        let output = dir.path().join("out");
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        let mut applier = MetadataApplier::new(Box::new(Fussy { no_mtime: "never", no_permissions: "never", no_links: "usb" }), false);
+        extract_with_conflicts(&path, &output, dir.path(), &mut resolver, &mut applier, false).unwrap();
+        for name in ["disk/lib.so.1", "usb/lib.so.1"] {
+            assert_eq!(fs::read(output.join(name)).unwrap(), b"library");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |name: &str| fs::metadata(output.join(name)).unwrap().ino();
+            assert_eq!(inode("disk/lib.so"), inode("disk/lib.so.1"));
+            assert_ne!(inode("usb/lib.so"), inode("usb/lib.so.1"));
+        }
+        let warnings = applier.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "usb/lib.so.1: couldn't hard link it to usb/lib.so, so it's a copy: Operation not supported");
+        
+        // Strict wants the link
+        let output = dir.path().join("strict");
+        let mut applier = MetadataApplier::new(Box::new(Fussy { no_mtime: "never", no_permissions: "never", no_links: "usb" }), true);
+        let err = extract_with_conflicts(&path, &output, dir.path(), &mut resolver, &mut applier, false).unwrap_err();
+        assert!(err.to_string().starts_with("usb/lib.so.1: couldn't hard link it to usb/lib.so"), "{}", err);
+    }
+    
+    #[test]
+    fn test_quota_skips_files_with_keep_going() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive(&dir);
+        let mut resolver = ConflictResolver::new(ConflictStrategy::default(), None);
+        
+        // Out of room fails the whole extraction, as out of space
+        let output = dir.path().join("out");
+        let mut applier = MetadataApplier::new(Box::new(OverQuota("usb")), false);
+        let err = extract_with_conflicts(&archive, &output, dir.path(), &mut resolver, &mut applier, false).unwrap_err();
+        assert!(crate::fsutil::ran_out_of_space(&err), "{}", err);
+        
+        // With keep_going, only the files that didn't fit are left out
+        let output = dir.path().join("kept");
+        let mut applier = MetadataApplier::new(Box::new(OverQuota("clock")), false);
+        let extracted = extract_with_conflicts(&archive, &output, dir.path(), &mut resolver, &mut applier, true).unwrap();
+        assert_eq!(fs::read(output.join("fine.txt")).unwrap(), b"fine.txt");
+        assert_eq!(fs::read(output.join("usb/mode.txt")).unwrap(), b"usb/mode.txt");
+        assert!(!output.join("usb/clock.txt").exists());
+        assert_eq!(extracted.skipped.len(), 1);
+        assert_eq!(extracted.skipped[0].to_string(), "usb/clock.txt: Disk quota exceeded");
+        assert!(extracted.resolved.is_empty());
+        let leftovers: Vec<_> = fs::read_dir(output.join("usb")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(leftovers, ["mode.txt"]);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_system_fs_sets_both() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"").unwrap();
+        
+        let mut applier = MetadataApplier::default();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        applier.apply(&path, Path::new("file"), 0o600, mtime).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert!(applier.take_warnings().is_empty());
+    }
+}
@@ -1,10 +1,83 @@
+pub mod adaptive;
+pub mod archive;
+pub mod budget;
+pub mod checkpoint;
+pub mod conflict;
+pub mod delta;
+pub mod filetype;
+pub mod hardlink;
+pub mod hash_tree;
+pub mod hashing;
+pub mod history;
+pub mod manifest_cache;
+pub mod metadata;
+pub mod pipe;
+pub mod preflight;
+pub mod receipt;
+pub mod staging;
+pub mod stream;
+pub mod sync;
+pub mod verify;
+pub mod wire_path;
+
 use anyhow::{anyhow, Result};
-use std::fs::{File, metadata};
-use std::io::{Read, Write};
+use futures_util::Stream;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use tokio::fs as async_fs;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::crypto::{self, ChecksumAlgorithm, Cipher};
+use crate::protocol::{ManifestEntry, Message};
+use crate::transport::Transport;
+
+pub use archive::{ArchiveFormat, ZipDirectoryChunker};
+pub use budget::{MemoryBudget, DEFAULT_MEMORY_LIMIT, MIN_MEMORY_LIMIT};
+pub use checkpoint::{Checkpointer, CHECKPOINT_INTERVAL};
+pub use conflict::{ConflictPrompt, ConflictStrategy, Resolution, SkippedFile};
+pub use delta::{DeltaDecoder, DeltaEncoder, DELTA_BLOCK_SIZE};
+pub use filetype::{AcceptTypes, ConfirmPrompt};
+pub use hardlink::HardLinkTracker;
+pub use hashing::{ChecksumChoice, HashStage};
+pub use hash_tree::{hash_tree, hash_tree_reusing, HashedFile, Known, Stamp, TreeHashes, PARALLEL_HASH_THRESHOLD};
+pub use history::{Direction, History, HistoryEntry};
+pub use manifest_cache::ManifestCache;
+pub use metadata::{MetadataApplier, MetadataWarning};
+pub use pipe::PipeSink;
+pub use preflight::{preflight, PathKind, Preflight, PreflightOptions};
+pub use receipt::Receipt;
+pub use stream::{StdinChunker, TeeChunker};
+pub use verify::{Expected, Verdict};
+
+pub const CHUNK_SIZE: usize = 64 * 1024; // 64 KB chunks
+
+/// Sent in place of a checksum the sender didn't work out
+pub const NO_CHECKSUM: &str = "tbd";
 
-const CHUNK_SIZE: usize = 64 * 1024; // 64 KB chunks
+/// Chunks the sender reads ahead of the network unless `--readahead` says otherwise
+pub const DEFAULT_READAHEAD: usize = 4;
+
+/// Errors a receiver can hit while writing incoming data
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TransferError {
+    #[error("Sender sent more data than announced ({received} bytes, expected {expected})")]
+    DataExceedsExpectedSize { expected: u64, received: u64 },
+    #[error("Chunks {from} to {to} were damaged on the way (checkpoint checksum doesn't match)")]
+    ChecksumMismatch { from: u64, to: u64 },
+    #[error("{name} arrived damaged: its checksum doesn't match the sender's")]
+    FileChecksumMismatch { name: String, expected: String, actual: String },
+    #[error("File rebuilt from the existing copy doesn't match the sender's")]
+    DeltaMismatch,
+    #[error("{count} files in the folder arrived damaged or missing (their checksums don't match the sender's), {first} among them")]
+    FilesDamaged { count: usize, first: String },
+}
 
 /// File metadata for transfer
 #[derive(Debug, Clone)]
@@ -16,20 +89,23 @@ pub struct FileMetadata {
 }
 
 /// Read file metadata
-pub async fn get_file_metadata(path: &Path) -> Result<FileMetadata> {
+///
+/// With `stream_checksum`, a regular file is read through once to fill in its
+/// checksum; otherwise that's left as a placeholder.
+pub async fn get_file_metadata(path: &Path, stream_checksum: bool) -> Result<FileMetadata> {
     let metadata = async_fs::metadata(path).await?;
     
-    let name = path
-        .file_name()
-        .ok_or_else(|| anyhow!("Invalid file path"))?
-        .to_string_lossy()
-        .to_string();
+    // Only the last part of the path; nothing about where it is on this computer
+    let name = wire_path::offered_name(path)?;
     
     let is_directory = metadata.is_dir();
     let size = if is_directory { 0 } else { metadata.len() };
     
-    // For MVP, we'll skip checksum calculation for large files
-    let checksum = String::from("tbd");
+    let checksum = if stream_checksum && !is_directory {
+        checksum_file(path, ChecksumAlgorithm::Sha256, |_, _| {}).await?
+    } else {
+        String::from(NO_CHECKSUM)
+    };
     
     Ok(FileMetadata {
         name,
@@ -39,9 +115,27 @@ pub async fn get_file_metadata(path: &Path) -> Result<FileMetadata> {
     })
 }
 
+/// Checksum of the file at `path` by `algorithm`, telling `progress` the bytes read so far and the total
+pub async fn checksum_file(path: &Path, algorithm: ChecksumAlgorithm, mut progress: impl FnMut(u64, u64)) -> Result<String> {
+    let file = async_fs::File::open(path).await.map_err(|e| anyhow!("Couldn't open {}: {}", path.display(), e))?;
+    let total = file.metadata().await?.len();
+    let mut stream = crypto::ChecksumStream::new(file, algorithm);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut read = 0u64;
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        progress(read, total);
+    }
+    Ok(stream.checksum())
+}
+
 /// File chunker for streaming transfer
-pub struct FileChunker {
-    file: File,
+pub struct FileChunker<R = File> {
+    file: R,
     chunk_size: usize,
     total_size: u64,
     bytes_read: u64,
@@ -52,13 +146,30 @@ impl FileChunker {
     pub fn new(path: &Path) -> Result<Self> {
         let file = File::open(path)?;
         let total_size = file.metadata()?.len();
-        
-        Ok(Self {
-            file,
+        Ok(Self::from_reader(file, total_size))
+    }
+    
+    /// Start `offset` bytes in, as if those had already been read
+    pub fn open_at(path: &Path, offset: u64) -> Result<Self> {
+        let mut chunker = Self::new(path)?;
+        if offset > chunker.total_size {
+            return Err(anyhow!("Can't resume at byte {} of a {} byte file", offset, chunker.total_size));
+        }
+        chunker.file.seek(SeekFrom::Start(offset))?;
+        chunker.bytes_read = offset;
+        Ok(chunker)
+    }
+}
+
+impl<R: Read> FileChunker<R> {
+    /// Chunk `total_size` bytes from any reader
+    pub fn from_reader(reader: R, total_size: u64) -> Self {
+        Self {
+            file: reader,
             chunk_size: CHUNK_SIZE,
             total_size,
             bytes_read: 0,
-        })
+        }
     }
     
     /// Read the next chunk
@@ -79,6 +190,16 @@ impl FileChunker {
         Ok(Some(buffer))
     }
     
+    /// Change the size of subsequent chunks
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+    
+    /// Get the current chunk size
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+    
     /// Get progress (0.0 to 1.0)
     pub fn progress(&self) -> f64 {
         if self.total_size == 0 {
@@ -96,45 +217,323 @@ impl FileChunker {
     pub fn bytes_read(&self) -> u64 {
         self.bytes_read
     }
+    
+    /// Iterate over the remaining chunks for synchronous callers
+    pub fn into_sync_iter(self) -> SyncChunkIter<R> {
+        SyncChunkIter(self)
+    }
+}
+
+impl<R: Read + Send + 'static> FileChunker<R> {
+    /// Read on a blocking thread, keeping up to `capacity` chunks ready
+    ///
+    /// Disk reads then overlap with encryption and sending instead of waiting
+    /// for them, which matters on slow disks. The reader stops once the
+    /// returned chunker is dropped. Must be called inside a Tokio runtime.
+    pub fn with_readahead(mut self, capacity: usize) -> ReadAheadChunker {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let chunk_size = Arc::new(AtomicUsize::new(self.chunk_size));
+        let total_size = self.total_size;
+        let bytes_read = self.bytes_read;
+        
+        let requested_size = chunk_size.clone();
+        tokio::task::spawn_blocking(move || loop {
+            self.set_chunk_size(requested_size.load(Ordering::Relaxed));
+            let Some(chunk) = self.next_chunk().transpose() else {
+                break;
+            };
+            // Stop after an error, or once nobody is taking chunks any more
+            let failed = chunk.is_err();
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        });
+        
+        ReadAheadChunker {
+            chunks: rx,
+            chunk_size,
+            total_size,
+            bytes_read,
+        }
+    }
+}
+
+impl<R: Read> Iterator for FileChunker<R> {
+    type Item = Result<Vec<u8>>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Blocking iterator over a file's chunks, see `FileChunker::into_sync_iter`
+pub struct SyncChunkIter<R = File>(FileChunker<R>);
+
+impl<R: Read> SyncChunkIter<R> {
+    /// The underlying chunker, e.g. to check progress or change the chunk size
+    pub fn chunker(&mut self) -> &mut FileChunker<R> {
+        &mut self.0
+    }
+}
+
+impl<R: Read> Iterator for SyncChunkIter<R> {
+    type Item = Result<Vec<u8>>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_chunk().transpose()
+    }
+}
+
+/// Chunks read ahead on a background thread, see `FileChunker::with_readahead`
+pub struct ReadAheadChunker {
+    chunks: mpsc::Receiver<Result<Vec<u8>>>,
+    /// Size the reader uses for the chunks it hasn't read yet
+    chunk_size: Arc<AtomicUsize>,
+    total_size: u64,
+    bytes_read: u64,
+}
+
+impl ReadAheadChunker {
+    /// Take the next chunk, waiting for the reader if none are buffered
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.chunks.recv().await {
+            Some(Ok(chunk)) => {
+                self.bytes_read += chunk.len() as u64;
+                Ok(Some(chunk))
+            }
+            Some(Err(e)) => Err(e),
+            None if self.bytes_read < self.total_size => Err(anyhow!(
+                "File reader stopped after {} of {} bytes",
+                self.bytes_read,
+                self.total_size
+            )),
+            None => Ok(None),
+        }
+    }
+    
+    /// Change the size of subsequent chunks
+    ///
+    /// Chunks already buffered keep the size they were read with.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size.store(chunk_size.max(1), Ordering::Relaxed);
+    }
+    
+    /// Get the current chunk size
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.load(Ordering::Relaxed)
+    }
+    
+    /// Get total size
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+    
+    /// Get bytes handed out so far, not counting those still buffered
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+/// File chunker backed by `tokio::fs`, usable as a `Stream` of chunks
+pub struct AsyncChunker {
+    file: async_fs::File,
+    chunk_size: usize,
+    total_size: u64,
+    bytes_read: u64,
+    /// Buffer for the read in progress, kept across `Pending` polls
+    buffer: Vec<u8>,
+}
+
+impl AsyncChunker {
+    /// Create a new file chunker
+    pub async fn new(path: &Path) -> Result<Self> {
+        let file = async_fs::File::open(path).await?;
+        let total_size = file.metadata().await?.len();
+        
+        Ok(Self {
+            file,
+            chunk_size: CHUNK_SIZE,
+            total_size,
+            bytes_read: 0,
+            buffer: Vec::new(),
+        })
+    }
+    
+    /// Read the next chunk
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .transpose()
+    }
+    
+    /// Change the size of subsequent chunks
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+    
+    /// Get the current chunk size
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+    
+    /// Get total size
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+    
+    /// Get bytes read
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl Stream for AsyncChunker {
+    type Item = Result<Vec<u8>>;
+    
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.bytes_read >= this.total_size {
+            return Poll::Ready(None);
+        }
+        
+        if this.buffer.is_empty() {
+            this.buffer = vec![0u8; this.chunk_size];
+        }
+        let mut read_buf = ReadBuf::new(&mut this.buffer);
+        if let Err(e) = ready!(Pin::new(&mut this.file).poll_read(cx, &mut read_buf)) {
+            this.buffer = Vec::new();
+            return Poll::Ready(Some(Err(e.into())));
+        }
+        
+        let bytes_read = read_buf.filled().len();
+        let mut chunk = std::mem::take(&mut this.buffer);
+        if bytes_read == 0 {
+            return Poll::Ready(None);
+        }
+        
+        chunk.truncate(bytes_read);
+        this.bytes_read += bytes_read as u64;
+        Poll::Ready(Some(Ok(chunk)))
+    }
 }
 
 /// File writer for receiving chunks
 pub struct FileWriter {
     file: File,
+    path: PathBuf,
     bytes_written: u64,
-    expected_size: u64,
+    /// `None` for streams whose length isn't known up front, like directory archives
+    expected_size: Option<u64>,
+    /// Index of the first chunk this writer was given
+    first_chunk: u64,
+    /// Where each chunk written so far starts, from `first_chunk` on
+    chunk_starts: Vec<u64>,
 }
 
 impl FileWriter {
-    /// Create a new file writer
+    /// Create a new file writer that refuses more than `expected_size` bytes
     pub fn new(path: &Path, expected_size: u64) -> Result<Self> {
+        Self::create(path, Some(expected_size))
+    }
+    
+    /// Create a file writer for a stream of unknown length
+    pub fn streaming(path: &Path) -> Result<Self> {
+        Self::create(path, None)
+    }
+    
+    /// Carry on writing a partly received file after its first `offset` bytes, from chunk `first_chunk`
+    pub fn resume(path: &Path, expected_size: u64, offset: u64, first_chunk: u64) -> Result<Self> {
+        let mut file = File::options().write(true).open(path)?;
+        if file.metadata()?.len() < offset || offset > expected_size {
+            return Err(anyhow!("{} is shorter than the {} bytes recorded for it", path.display(), offset));
+        }
+        // Anything past the offset was written after the record was saved, so isn't counted
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            bytes_written: offset,
+            expected_size: Some(expected_size),
+            first_chunk,
+            chunk_starts: Vec::new(),
+        })
+    }
+    
+    fn create(path: &Path, expected_size: Option<u64>) -> Result<Self> {
         let file = File::create(path)?;
         
         Ok(Self {
             file,
+            path: path.to_path_buf(),
             bytes_written: 0,
             expected_size,
+            first_chunk: 0,
+            chunk_starts: Vec::new(),
         })
     }
     
-    /// Write a chunk
+    /// Write a chunk, failing without writing anything if it goes past the expected size
     pub fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() as u64 > self.bytes_remaining().unwrap_or(u64::MAX) {
+            return Err(TransferError::DataExceedsExpectedSize {
+                expected: self.expected_size.unwrap_or_default(),
+                received: self.bytes_written + data.len() as u64,
+            }.into());
+        }
         self.file.write_all(data)?;
+        self.chunk_starts.push(self.bytes_written);
         self.bytes_written += data.len() as u64;
         Ok(())
     }
     
+    /// Check the chunks in `chunk_range`, already on disk, against a BLAKE3 `expected_hash` of them
+    ///
+    /// Reads them back from the file, so it's only worth doing when the sender
+    /// sends a checkpoint.
+    pub fn verify_partial_checksum(&self, chunk_range: Range<u64>, expected_hash: [u8; 32]) -> Result<()> {
+        let start = self.chunk_start(chunk_range.start)?;
+        let end = self.chunk_start(chunk_range.end)?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut file.take(end.saturating_sub(start)), &mut hasher)?;
+        if *hasher.finalize().as_bytes() != expected_hash {
+            return Err(TransferError::ChecksumMismatch {
+                from: chunk_range.start,
+                to: chunk_range.end,
+            }.into());
+        }
+        Ok(())
+    }
+    
+    /// Where chunk `index` starts in the file; just past the end for the next one to come
+    fn chunk_start(&self, index: u64) -> Result<u64> {
+        let written = self.chunk_starts.len() as u64;
+        match index.checked_sub(self.first_chunk) {
+            Some(offset) if offset < written => Ok(self.chunk_starts[offset as usize]),
+            Some(offset) if offset == written => Ok(self.bytes_written),
+            _ => Err(anyhow!("Chunk {} isn't among those written ({} to {})", index, self.first_chunk, self.first_chunk + written)),
+        }
+    }
+    
+    /// Bytes still to come, if the size is known
+    pub fn bytes_remaining(&self) -> Option<u64> {
+        self.expected_size?.checked_sub(self.bytes_written)
+    }
+    
     /// Get progress (0.0 to 1.0)
     pub fn progress(&self) -> f64 {
-        if self.expected_size == 0 {
-            return 1.0;
+        match self.expected_size {
+            Some(0) => 1.0,
+            Some(expected_size) => self.bytes_written as f64 / expected_size as f64,
+            None => 0.0,
         }
-        self.bytes_written as f64 / self.expected_size as f64
     }
     
     /// Check if transfer is complete
     pub fn is_complete(&self) -> bool {
-        self.bytes_written >= self.expected_size
+        self.bytes_remaining() == Some(0)
     }
     
     /// Get bytes written
@@ -142,6 +541,11 @@ impl FileWriter {
         self.bytes_written
     }
     
+    /// Index of the chunk that goes next
+    pub fn next_chunk(&self) -> u64 {
+        self.first_chunk + self.chunk_starts.len() as u64
+    }
+    
     /// Finalize the file
     pub fn finalize(self) -> Result<()> {
         self.file.sync_all()?;
@@ -151,15 +555,284 @@ impl FileWriter {
 
 /// Create a tar archive from a directory (for directory transfers)
 pub fn create_tar_archive(dir_path: &Path, output_path: &Path) -> Result<()> {
+    create_tar_archive_with_progress(dir_path, output_path, |_, _| {})
+}
+
+/// Create a tar archive, calling `progress(files_done, total_files)` after each file
+pub fn create_tar_archive_with_progress(
+    dir_path: &Path,
+    output_path: &Path,
+    progress: impl Fn(u64, u64),
+) -> Result<()> {
     let tar_file = File::create(output_path)?;
     let mut archive = tar::Builder::new(tar_file);
     
-    archive.append_dir_all(".", dir_path)?;
+    append_dir_with_progress(&mut archive, dir_path, &[], progress)?;
     archive.finish()?;
     
     Ok(())
 }
 
+/// Append everything under `dir_path` to `archive`, reporting progress per file
+///
+/// The tree is walked once up front so the total is known before the first
+/// file is added. Directories are archived too but don't count as files.
+/// Further hard links to a file already archived go in as links. Returns the
+/// checksums taken of the regular files on the way, see `HardLinkTracker::append`.
+fn append_dir_with_progress<W: Write>(
+    archive: &mut tar::Builder<W>,
+    dir_path: &Path,
+    skip: &[PathBuf],
+    progress: impl Fn(u64, u64),
+) -> Result<Vec<ManifestEntry>> {
+    let entries = archive_entries(dir_path, skip)?;
+    let total_files = entries.iter().filter(|entry| !entry.file_type().is_dir()).count() as u64;
+    
+    let mut links = HardLinkTracker::default();
+    let mut checksums = Vec::new();
+    let mut files_done = 0;
+    for entry in entries {
+        let name = wire_path::relative_to(dir_path, entry.path())?;
+        checksums.extend(links.append(archive, entry.path(), &name)?);
+        if !entry.file_type().is_dir() {
+            files_done += 1;
+            progress(files_done, total_files);
+        }
+    }
+    Ok(checksums)
+}
+
+/// Everything under `dir_path` but `skip` in the order archives list it, links followed
+///
+/// A skipped folder is left out along with everything in it.
+fn archive_entries(dir_path: &Path, skip: &[PathBuf]) -> Result<Vec<walkdir::DirEntry>> {
+    let skipped = |path: &Path| skip.iter().any(|skipped| skipped == path);
+    let walker = walkdir::WalkDir::new(dir_path).min_depth(1).follow_links(true).sort_by_file_name();
+    let mut entries = Vec::new();
+    for entry in walker.into_iter().filter_entry(|entry| !skipped(entry.path())) {
+        match entry {
+            Ok(entry) => entries.push(entry),
+            // Like a link to nowhere, which can't be looked at to filter it out first
+            Err(e) if e.path().is_some_and(skipped) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(entries)
+}
+
+/// Chunks of an archive built on a blocking thread
+///
+/// How many files the archive covers so far comes in between the data, in
+/// order. A tar archive ends with the checksums of the files in it.
+#[derive(Debug, PartialEq)]
+pub enum ArchivePiece {
+    Data(Vec<u8>),
+    Progress { files_done: u64, total_files: u64 },
+    Checksums(Vec<ManifestEntry>),
+}
+
+/// Archive chunks kept ready by the builder before it waits for them to be taken
+const ARCHIVE_READAHEAD: usize = 8;
+
+/// Archive built on a blocking thread and handed over a piece at a time
+///
+/// Walking the tree, reading files and compressing all block, so none of it
+/// runs on the async side, which only encrypts and sends what it's handed. The
+/// builder stops once the stream is dropped.
+pub struct ArchiveStream {
+    pieces: mpsc::Receiver<ArchivePiece>,
+    builder: Option<tokio::task::JoinHandle<Result<()>>>,
+}
+
+impl ArchiveStream {
+    /// Tar everything under `dir_path` but `skip`, in chunks of `chunk_size` bytes
+    ///
+    /// Small files are packed back to back into shared chunks, so a tree of many
+    /// tiny files costs no per-file messages or acks.
+    pub fn tar(dir_path: &Path, skip: &[PathBuf], chunk_size: usize) -> Self {
+        let (dir_path, skip) = (dir_path.to_path_buf(), skip.to_vec());
+        Self::build(move |tx| {
+            let mut writer = TarStreamWriter::new(tx.clone(), chunk_size);
+            let mut archive = tar::Builder::new(&mut writer);
+            let checksums = append_dir_with_progress(&mut archive, &dir_path, &skip, |files_done, total_files| {
+                let _ = tx.blocking_send(ArchivePiece::Progress { files_done, total_files });
+            })?;
+            archive.finish()?;
+            drop(archive);
+            writer.flush()?;
+            let _ = tx.blocking_send(ArchivePiece::Checksums(checksums));
+            Ok(())
+        })
+    }
+    
+    /// Like `tar`, but only `files` from under `dir_path`, named relative to it
+    pub fn tar_files(dir_path: &Path, files: &[String], chunk_size: usize) -> Self {
+        let (dir_path, files) = (dir_path.to_path_buf(), files.to_vec());
+        Self::build(move |tx| {
+            let mut writer = TarStreamWriter::new(tx.clone(), chunk_size);
+            let mut archive = tar::Builder::new(&mut writer);
+            let mut links = HardLinkTracker::default();
+            let mut checksums = Vec::new();
+            for (files_done, name) in files.iter().enumerate() {
+                if !wire_path::is_contained(Path::new(name)) {
+                    return Err(anyhow!("{} isn't in the folder being sent", name));
+                }
+                checksums.extend(links.append(&mut archive, &dir_path.join(name), Path::new(name))?);
+                let progress = ArchivePiece::Progress { files_done: files_done as u64 + 1, total_files: files.len() as u64 };
+                let _ = tx.blocking_send(progress);
+            }
+            archive.finish()?;
+            drop(archive);
+            writer.flush()?;
+            let _ = tx.blocking_send(ArchivePiece::Checksums(checksums));
+            Ok(())
+        })
+    }
+    
+    /// ZIP everything under `dir_path` but `skip`, see `ZipDirectoryChunker`
+    pub fn zip(dir_path: &Path, skip: &[PathBuf], chunk_size: usize) -> Result<Self> {
+        let mut chunker = ZipDirectoryChunker::new(dir_path, skip, chunk_size)?;
+        Ok(Self::build(move |tx| {
+            while let Some(chunk) = chunker.next_chunk()? {
+                let progress = ArchivePiece::Progress { files_done: chunker.files_done(), total_files: chunker.total_files() };
+                if tx.blocking_send(ArchivePiece::Data(chunk)).is_err() || tx.blocking_send(progress).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }))
+    }
+    
+    /// Run `build` on a blocking thread, handing what it sends to the stream
+    ///
+    /// Must be called inside a Tokio runtime.
+    fn build(build: impl FnOnce(mpsc::Sender<ArchivePiece>) -> Result<()> + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel(ARCHIVE_READAHEAD);
+        let builder = tokio::task::spawn_blocking(move || build(tx));
+        Self {
+            pieces: rx,
+            builder: Some(builder),
+        }
+    }
+    
+    /// Take the next piece, or `None` once the whole archive has been handed over
+    ///
+    /// An archive that couldn't be built to the end is an error rather than a short one.
+    pub async fn next(&mut self) -> Result<Option<ArchivePiece>> {
+        if let Some(piece) = self.pieces.recv().await {
+            return Ok(Some(piece));
+        }
+        if let Some(builder) = self.builder.take() {
+            builder.await??;
+        }
+        Ok(None)
+    }
+}
+
+/// `Write` sink that cuts tar output into chunks for an `ArchiveStream`
+///
+/// Runs on the builder's blocking thread, so waiting for room in the channel is fine.
+pub struct TarStreamWriter {
+    tx: mpsc::Sender<ArchivePiece>,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl TarStreamWriter {
+    /// Create a writer that hands over chunks of `chunk_size` bytes
+    pub fn new(tx: mpsc::Sender<ArchivePiece>, chunk_size: usize) -> Self {
+        Self {
+            tx,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size: chunk_size.max(1),
+        }
+    }
+    
+    /// Hand the buffered bytes over as one chunk
+    fn send_buffer(&mut self) -> io::Result<()> {
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size));
+        self.tx
+            .blocking_send(ArchivePiece::Data(data))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive stream was dropped"))
+    }
+}
+
+impl Write for TarStreamWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..len]);
+        
+        if self.buffer.len() >= self.chunk_size {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encrypt and send an archive's chunks as they're built
+///
+/// Returns the total number of archive bytes sent. `progress(files_done, total_files)`
+/// is called as the archive covers more files.
+pub async fn send_archive(
+    mut archive: ArchiveStream,
+    transport: &mut Transport,
+    cipher: &Cipher,
+    progress: impl Fn(u64, u64),
+) -> Result<u64> {
+    let mut chunk_index = 0u64;
+    let mut sent = 0u64;
+    while let Some(piece) = archive.next().await? {
+        match piece {
+            ArchivePiece::Data(data) => {
+                sent += data.len() as u64;
+                let chunk_msg = Message::Chunk {
+                    index: chunk_index,
+                    data,
+                };
+                transport.send(&cipher.encrypt(&chunk_msg.to_bytes()?)?).await?;
+                chunk_index += 1;
+            }
+            ArchivePiece::Progress { files_done, total_files } => progress(files_done, total_files),
+            ArchivePiece::Checksums(_) => {}
+        }
+    }
+    Ok(sent)
+}
+
+/// Tar a directory straight into the transport without a temporary archive file
+///
+/// See `ArchiveStream::tar`. Returns the total number of archive bytes sent.
+/// `progress(files_done, total_files)` is called after each file.
+pub async fn stream_tar_to_transport(
+    dir_path: &Path,
+    skip: &[PathBuf],
+    transport: &mut Transport,
+    cipher: &Cipher,
+    chunk_size: usize,
+    progress: impl Fn(u64, u64),
+) -> Result<u64> {
+    send_archive(ArchiveStream::tar(dir_path, skip, chunk_size), transport, cipher, progress).await
+}
+
+/// Like `stream_tar_to_transport`, but only `files` from under `dir_path`, named relative to it
+pub async fn stream_tar_files_to_transport(
+    dir_path: &Path,
+    files: &[String],
+    transport: &mut Transport,
+    cipher: &Cipher,
+    chunk_size: usize,
+    progress: impl Fn(u64, u64),
+) -> Result<u64> {
+    send_archive(ArchiveStream::tar_files(dir_path, files, chunk_size), transport, cipher, progress).await
+}
+
 /// Extract a tar archive (for directory transfers)
 pub fn extract_tar_archive(archive_path: &Path, output_dir: &Path) -> Result<()> {
     let tar_file = File::open(archive_path)?;
@@ -170,6 +843,41 @@ pub fn extract_tar_archive(archive_path: &Path, output_dir: &Path) -> Result<()>
     Ok(())
 }
 
+/// Check the regular files in the tar archive at `archive_path` against the sender's `checksums`
+///
+/// Done before anything is unpacked, so a damaged file never lands on disk.
+/// Files the sender didn't list aren't checked; listed ones the archive lacks
+/// count as damaged.
+pub fn check_file_checksums(archive_path: &Path, checksums: &[ManifestEntry]) -> Result<()> {
+    let mut expected: std::collections::HashMap<&str, &str> =
+        checksums.iter().map(|entry| (entry.path.as_str(), entry.checksum.as_str())).collect();
+    let mut damaged = Vec::new();
+    let mut archive = tar::Archive::new(File::open(archive_path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Ok(path) = wire_path::manifest_path(&entry.path()?) else {
+            continue;
+        };
+        let Some(checksum) = expected.remove(path.as_str()) else {
+            continue;
+        };
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut entry, &mut hasher)?;
+        if hasher.finalize().to_hex().as_str() != checksum {
+            damaged.push(path);
+        }
+    }
+    damaged.extend(expected.into_keys().map(String::from));
+    damaged.sort();
+    match damaged.first() {
+        Some(first) => Err(TransferError::FilesDamaged { count: damaged.len(), first: first.clone() }.into()),
+        None => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,7 +892,7 @@ mod tests {
         temp_file.flush().unwrap();
         
         let mut chunker = FileChunker::new(temp_file.path()).unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
+        let output_file = NamedTempFile::new().unwrap();
         let mut writer = FileWriter::new(output_file.path(), test_data.len() as u64).unwrap();
         
         while let Some(chunk) = chunker.next_chunk().unwrap() {
@@ -197,4 +905,312 @@ mod tests {
         output_file.reopen().unwrap().read_to_end(&mut result).unwrap();
         assert_eq!(result, test_data);
     }
+    
+    #[tokio::test]
+    async fn test_metadata_checksum() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let data = vec![7u8; 3 * CHUNK_SIZE + 11];
+        temp_file.write_all(&data).unwrap();
+        
+        let metadata = get_file_metadata(temp_file.path(), true).await.unwrap();
+        assert_eq!(metadata.checksum, crypto::checksum(&data));
+        assert_eq!(get_file_metadata(temp_file.path(), false).await.unwrap().checksum, "tbd");
+    }
+    
+    #[test]
+    fn test_writer_rejects_overflow() {
+        let output_file = NamedTempFile::new().unwrap();
+        let mut writer = FileWriter::new(output_file.path(), 100).unwrap();
+        writer.write_chunk(&[1u8; 60]).unwrap();
+        assert_eq!(writer.bytes_remaining(), Some(40));
+        
+        let err = writer.write_chunk(&[2u8; 60]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TransferError>(),
+            Some(&TransferError::DataExceedsExpectedSize { expected: 100, received: 120 })
+        );
+        assert_eq!(writer.bytes_written(), 60);
+        
+        writer.write_chunk(&[3u8; 40]).unwrap();
+        assert!(writer.is_complete());
+        assert!(writer.write_chunk(&[4u8]).is_err());
+        writer.finalize().unwrap();
+        assert_eq!(std::fs::metadata(output_file.path()).unwrap().len(), 100);
+        
+        let mut streaming = FileWriter::streaming(output_file.path()).unwrap();
+        assert_eq!(streaming.bytes_remaining(), None);
+        streaming.write_chunk(&[5u8; 200]).unwrap();
+    }
+    
+    #[test]
+    fn test_resume_partway() {
+        let (temp_file, data) = chunk_fixture(1000);
+        let output_file = NamedTempFile::new().unwrap();
+        // 400 bytes recorded, plus some written after the record was saved
+        std::fs::write(output_file.path(), &data[..450]).unwrap();
+        
+        let mut writer = FileWriter::resume(output_file.path(), 1000, 400, 4).unwrap();
+        assert_eq!(writer.bytes_remaining(), Some(600));
+        let mut chunker = FileChunker::open_at(temp_file.path(), 400).unwrap();
+        assert_eq!(chunker.bytes_read(), 400);
+        for chunk in chunker.by_ref() {
+            writer.write_chunk(&chunk.unwrap()).unwrap();
+        }
+        assert!(writer.is_complete());
+        writer.finalize().unwrap();
+        assert_eq!(std::fs::read(output_file.path()).unwrap(), data);
+        
+        assert!(FileWriter::resume(output_file.path(), 2000, 1500, 15).is_err());
+        assert!(FileChunker::open_at(temp_file.path(), 1001).is_err());
+    }
+    
+    fn chunk_fixture(len: usize) -> (NamedTempFile, Vec<u8>) {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&data).unwrap();
+        temp_file.flush().unwrap();
+        (temp_file, data)
+    }
+    
+    #[test]
+    fn test_chunker_iterators() {
+        let (temp_file, data) = chunk_fixture(CHUNK_SIZE * 2 + 100);
+        
+        let chunks: Vec<_> = FileChunker::new(temp_file.path()).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [CHUNK_SIZE, CHUNK_SIZE, 100]);
+        assert_eq!(chunks.concat(), data);
+        
+        let mut chunker = FileChunker::new(temp_file.path()).unwrap();
+        chunker.set_chunk_size(1000);
+        let mut iter = chunker.into_sync_iter();
+        let first = iter.next().unwrap().unwrap();
+        assert_eq!(first.len(), 1000);
+        assert_eq!(iter.chunker().bytes_read(), 1000);
+        
+        let rest: Vec<u8> = iter.flat_map(Result::unwrap).collect();
+        assert_eq!([first, rest].concat(), data);
+    }
+    
+    #[test]
+    fn test_chunker_iterator_propagates_errors() {
+        let (temp_file, _) = chunk_fixture(100);
+        let mut chunker = FileChunker::new(temp_file.path()).unwrap();
+        
+        // A write-only handle fails every read
+        chunker.file = File::options().write(true).open(temp_file.path()).unwrap();
+        let mut iter = chunker.into_sync_iter();
+        assert!(iter.next().unwrap().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_async_chunker_stream() {
+        use futures_util::StreamExt;
+        
+        let (temp_file, data) = chunk_fixture(CHUNK_SIZE * 3 + 7);
+        
+        let mut chunker = AsyncChunker::new(temp_file.path()).await.unwrap();
+        let first = chunker.next_chunk().await.unwrap().unwrap();
+        assert_eq!(first.len(), CHUNK_SIZE);
+        
+        chunker.set_chunk_size(10_000);
+        let lengths: Vec<usize> = chunker.map(|chunk| chunk.unwrap().len()).collect().await;
+        assert_eq!(lengths.iter().sum::<usize>(), data.len() - CHUNK_SIZE);
+        assert!(lengths.iter().all(|&len| len <= 10_000));
+        
+        let chunker = AsyncChunker::new(temp_file.path()).await.unwrap();
+        let chunks: Vec<Vec<u8>> = chunker.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), data);
+    }
+    
+    #[tokio::test]
+    async fn test_readahead_chunker() {
+        let (temp_file, data) = chunk_fixture(CHUNK_SIZE * 3 + 7);
+        
+        let mut chunker = FileChunker::new(temp_file.path()).unwrap().with_readahead(2);
+        let first = chunker.next_chunk().await.unwrap().unwrap();
+        assert_eq!(chunker.bytes_read(), CHUNK_SIZE as u64);
+        
+        // Chunks already buffered keep their size, later ones shrink
+        chunker.set_chunk_size(10_000);
+        let mut lengths = Vec::new();
+        let mut received = first;
+        while let Some(chunk) = chunker.next_chunk().await.unwrap() {
+            lengths.push(chunk.len());
+            received.extend(chunk);
+        }
+        assert_eq!(received, data);
+        assert!(lengths.iter().filter(|&&len| len == 10_000).count() >= 10);
+        
+        // A failed read reaches the caller
+        let mut failing = FileChunker::new(temp_file.path()).unwrap();
+        failing.file = File::options().write(true).open(temp_file.path()).unwrap();
+        let mut chunker = failing.with_readahead(2);
+        assert!(chunker.next_chunk().await.is_err());
+    }
+    
+    #[test]
+    fn test_archive_progress() {
+        let source = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(source.path().join("nested/deeper")).unwrap();
+        for name in ["a.txt", "b.txt", "nested/c.txt", "nested/d.txt", "nested/deeper/e.txt"] {
+            std::fs::write(source.path().join(name), name).unwrap();
+        }
+        
+        let output = tempfile::TempDir::new().unwrap();
+        let archive_path = output.path().join("archive.tar");
+        let calls = std::cell::RefCell::new(Vec::new());
+        create_tar_archive_with_progress(source.path(), &archive_path, |done, total| {
+            calls.borrow_mut().push((done, total));
+        }).unwrap();
+        assert_eq!(calls.into_inner(), vec![(1, 5), (2, 5), (3, 5), (4, 5), (5, 5)]);
+        
+        let extracted = output.path().join("extracted");
+        extract_tar_archive(&archive_path, &extracted).unwrap();
+        assert_eq!(std::fs::read(extracted.join("nested/deeper/e.txt")).unwrap(), b"nested/deeper/e.txt");
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_tar_to_transport() {
+        let source = tempfile::TempDir::new().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"first file").unwrap();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        std::fs::write(source.path().join("nested/b.bin"), vec![7u8; 200_000]).unwrap();
+        
+        let receiver = tokio::spawn(async {
+            let mut transport = Transport::Direct(crate::network::listen(Some(19201)).await.unwrap());
+            let cipher = Cipher::from_password("tar-test").unwrap();
+            let mut archive = Vec::new();
+            loop {
+                let encrypted = transport.receive().await.unwrap();
+                match Message::from_bytes(&cipher.decrypt(&encrypted).unwrap()).unwrap() {
+                    Message::Chunk { data, .. } => archive.extend_from_slice(&data),
+                    Message::Complete => break,
+                    _ => panic!("Unexpected message type"),
+                }
+            }
+            archive
+        });
+        
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        
+        let mut transport = Transport::Direct(crate::network::connect(&"127.0.0.1".parse().unwrap(), Some(19201), true).await.unwrap());
+        let cipher = Cipher::from_password("tar-test").unwrap();
+        let sent = stream_tar_to_transport(source.path(), &[], &mut transport, &cipher, CHUNK_SIZE, |_, _| {}).await.unwrap();
+        transport.send(&cipher.encrypt(&Message::Complete.to_bytes().unwrap()).unwrap()).await.unwrap();
+        
+        let archive = receiver.await.unwrap();
+        assert_eq!(archive.len() as u64, sent);
+        
+        let output = tempfile::TempDir::new().unwrap();
+        tar::Archive::new(archive.as_slice()).unpack(output.path()).unwrap();
+        assert_eq!(std::fs::read(output.path().join("a.txt")).unwrap(), b"first file");
+        assert_eq!(std::fs::read(output.path().join("nested/b.bin")).unwrap(), vec![7u8; 200_000]);
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_small_files_share_chunks() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let source = tempfile::TempDir::new().unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        for i in 0..500 {
+            // Sizes vary so entries straddle chunk boundaries
+            let path = source.path().join(format!("file-{:03}.txt", i));
+            std::fs::write(&path, vec![(i % 256) as u8; 1000 + i * 37]).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+            File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        }
+        
+        let receiver = tokio::spawn(async {
+            let mut transport = Transport::Direct(crate::network::listen(Some(19202)).await.unwrap());
+            let cipher = Cipher::from_password("tar-test").unwrap();
+            let mut archive = Vec::new();
+            let mut chunks = 0u64;
+            loop {
+                let encrypted = transport.receive().await.unwrap();
+                match Message::from_bytes(&cipher.decrypt(&encrypted).unwrap()).unwrap() {
+                    Message::Chunk { data, .. } => {
+                        archive.extend_from_slice(&data);
+                        chunks += 1;
+                    }
+                    Message::Complete => break,
+                    _ => panic!("Unexpected message type"),
+                }
+            }
+            (archive, chunks)
+        });
+        
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        
+        let mut transport = Transport::Direct(crate::network::connect(&"127.0.0.1".parse().unwrap(), Some(19202), true).await.unwrap());
+        let cipher = Cipher::from_password("tar-test").unwrap();
+        let sent = stream_tar_to_transport(source.path(), &[], &mut transport, &cipher, CHUNK_SIZE, |_, _| {}).await.unwrap();
+        transport.send(&cipher.encrypt(&Message::Complete.to_bytes().unwrap()).unwrap()).await.unwrap();
+        
+        let (archive, chunks) = receiver.await.unwrap();
+        assert_eq!(chunks, sent.div_ceil(CHUNK_SIZE as u64));
+        assert!(chunks < 500 / 4, "small files should be packed into shared chunks");
+        
+        let output = tempfile::TempDir::new().unwrap();
+        let mut unpacker = tar::Archive::new(archive.as_slice());
+        unpacker.set_preserve_permissions(true);
+        unpacker.set_preserve_mtime(true);
+        unpacker.unpack(output.path()).unwrap();
+        
+        for i in 0..500 {
+            let path = output.path().join(format!("file-{:03}.txt", i));
+            assert_eq!(std::fs::read(&path).unwrap(), vec![(i % 256) as u8; 1000 + i * 37]);
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+            assert_eq!(metadata.modified().unwrap(), mtime);
+        }
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_file_checksums_follow_the_archive() {
+        let source = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        for i in 0..40 {
+            // Sizes around the chunk size, so entries straddle chunk boundaries
+            let folder = if i % 2 == 0 { source.path().to_path_buf() } else { source.path().join("nested") };
+            std::fs::write(folder.join(format!("file-{:02}.bin", i)), vec![i as u8; 3000 + i * 97]).unwrap();
+        }
+        
+        let mut stream = ArchiveStream::tar(source.path(), &[], 4096);
+        let (mut archive, mut checksums) = (Vec::new(), None);
+        while let Some(piece) = stream.next().await.unwrap() {
+            match piece {
+                ArchivePiece::Data(data) => {
+                    assert!(checksums.is_none(), "checksums come after the data");
+                    archive.extend_from_slice(&data);
+                }
+                ArchivePiece::Checksums(files) => checksums = Some(files),
+                ArchivePiece::Progress { .. } => {}
+            }
+        }
+        let checksums = checksums.unwrap();
+        assert_eq!(checksums.len(), 40);
+        let nested = checksums.iter().find(|entry| entry.path == "nested/file-01.bin").unwrap();
+        assert_eq!(nested.size, 3097);
+        assert_eq!(nested.checksum, blake3::hash(&[1u8; 3097]).to_hex().to_string());
+        
+        let archive_file = NamedTempFile::new().unwrap();
+        std::fs::write(archive_file.path(), &archive).unwrap();
+        check_file_checksums(archive_file.path(), &checksums).unwrap();
+        
+        // The first entry's contents start right after its header
+        let mut damaged = archive.clone();
+        damaged[512] ^= 0xff;
+        std::fs::write(archive_file.path(), &damaged).unwrap();
+        let err = check_file_checksums(archive_file.path(), &checksums).unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>(), Some(&TransferError::FilesDamaged { count: 1, first: "file-00.bin".to_string() }));
+        
+        // A file the sender listed that never turned up counts too
+        std::fs::write(archive_file.path(), &archive).unwrap();
+        let mut listed = checksums.clone();
+        listed.push(ManifestEntry { path: "missing.bin".to_string(), ..checksums[0].clone() });
+        let err = check_file_checksums(archive_file.path(), &listed).unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>(), Some(&TransferError::FilesDamaged { count: 1, first: "missing.bin".to_string() }));
+    }
 }
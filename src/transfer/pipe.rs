@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use std::io;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::task::JoinHandle;
+
+/// How much of the end of a command's stderr is kept to explain why it failed
+const STDERR_TAIL: usize = 1024;
+
+/// How long a failed command's stderr is waited on, in case something it started still holds it open
+const STDERR_WAIT: Duration = Duration::from_secs(1);
+
+/// A shell command that received data is written into, for `--pipe-to`
+///
+/// The command's stdout is ours; its stderr is passed through as well, and
+/// the end of it kept for the error when the command fails. Writes wait for
+/// the command to take the data, so a slow one slows the transfer down
+/// rather than filling memory.
+pub struct PipeSink {
+    command: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stderr: Option<JoinHandle<()>>,
+    tail: Arc<Mutex<Vec<u8>>>,
+    bytes_written: u64,
+}
+
+impl PipeSink {
+    /// Start `command` in the platform's shell
+    pub fn spawn(command: &str) -> Result<Self> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let mut child = shell
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Couldn't start `{}`: {}", command, e))?;
+        
+        let tail = Arc::new(Mutex::new(Vec::new()));
+        let stderr = child.stderr.take().map(|mut stderr| {
+            let tail = tail.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = stderr.read(&mut buf).await {
+                    let _ = tokio::io::stderr().write_all(&buf[..n]).await;
+                    let mut tail = tail.lock().unwrap();
+                    tail.extend_from_slice(&buf[..n]);
+                    let excess = tail.len().saturating_sub(STDERR_TAIL);
+                    tail.drain(..excess);
+                }
+            })
+        });
+        Ok(Self {
+            command: command.to_string(),
+            stdin: child.stdin.take(),
+            child,
+            stderr,
+            tail,
+            bytes_written: 0,
+        })
+    }
+    
+    /// Bytes the command has taken so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+    
+    /// Hand `data` to the command, waiting until it takes it
+    ///
+    /// A command that stopped reading is waited for, and the error says how it ended.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let stdin = self.stdin.as_mut().ok_or_else(|| anyhow!("`{}` was already finished", self.command))?;
+        match stdin.write_all(data).await {
+            Ok(()) => {
+                self.bytes_written += data.len() as u64;
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.stdin = None;
+                let status = self.child.wait().await?;
+                Err(self.failure(&format!("stopped reading after {} bytes ({})", self.bytes_written, status)).await)
+            }
+            Err(e) => Err(anyhow!("Couldn't write to `{}`: {}", self.command, e)),
+        }
+    }
+    
+    /// Close the command's input and wait for it to exit, which has to be successfully
+    pub async fn finish(mut self) -> Result<()> {
+        if let Some(mut stdin) = self.stdin.take() {
+            // Already gone shows in the exit status
+            let _ = stdin.shutdown().await;
+        }
+        let status = self.child.wait().await?;
+        if !status.success() {
+            return Err(self.failure(&status.to_string()).await);
+        }
+        Ok(())
+    }
+    
+    /// Why the command failed, with the end of what it wrote to stderr
+    async fn failure(&mut self, what: &str) -> anyhow::Error {
+        // Whatever it wrote is in once the pipe closes
+        if let Some(stderr) = self.stderr.take() {
+            let _ = tokio::time::timeout(STDERR_WAIT, stderr).await;
+        }
+        let tail = self.tail.lock().unwrap();
+        let tail = String::from_utf8_lossy(&tail);
+        match tail.trim() {
+            "" => anyhow!("`{}` {}", self.command, what),
+            tail => anyhow!("`{}` {}: {}", self.command, what, tail),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[tokio::test]
+    async fn test_data_reaches_the_command() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("out.txt");
+        let mut sink = PipeSink::spawn(&format!("tr a-z A-Z > '{}'", output.display())).unwrap();
+        sink.write(b"hello ").await.unwrap();
+        sink.write(b"world").await.unwrap();
+        assert_eq!(sink.bytes_written(), 11);
+        sink.finish().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "HELLO WORLD");
+    }
+    
+    #[tokio::test]
+    async fn test_failing_command() {
+        let sink = PipeSink::spawn("cat > /dev/null; echo 'disk full' >&2; exit 3").unwrap();
+        let err = sink.finish().await.unwrap_err().to_string();
+        assert!(err.contains("exit status: 3") && err.ends_with(": disk full"), "{}", err);
+        
+        // Stops reading partway through
+        let mut sink = PipeSink::spawn("head -c 10 > /dev/null; echo 'not a tar archive' >&2; exit 2").unwrap();
+        let chunk = vec![0u8; 64 * 1024];
+        let err = loop {
+            if let Err(e) = sink.write(&chunk).await {
+                break e.to_string();
+            }
+        };
+        assert!(err.contains("stopped reading") && err.contains("not a tar archive"), "{}", err);
+        
+        assert!(PipeSink::spawn("exit 0").unwrap().finish().await.is_ok());
+    }
+}
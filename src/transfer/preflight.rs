@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Result};
+use std::fs::{self, File, FileType};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Unreadable entries listed in the error before the rest are only counted
+const LISTED_UNREADABLE: usize = 10;
+
+/// Windows' "another process has the file open and won't share it"
+#[cfg(windows)]
+const ERROR_SHARING_VIOLATION: i32 = 32;
+
+/// Windows' "another process has locked part of the file"
+#[cfg(windows)]
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// How the checks before a send treat problems they find
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PreflightOptions {
+    /// Leave unreadable files and folders out of a directory instead of refusing to send it
+    pub skip_unreadable: bool,
+}
+
+/// What a path to send is, before any link is followed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    File,
+    Directory,
+    /// A symbolic link, sent as whatever it points to
+    Symlink,
+    /// A FIFO, socket or device node, which has no contents to send
+    Special,
+}
+
+/// Something under a directory that can't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreadable {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// What the checks found out about a path before sending it
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    pub kind: PathKind,
+    /// Where a link leads
+    pub target: Option<PathBuf>,
+    /// Whether what's sent, after following any link, is a directory
+    pub is_directory: bool,
+    /// Entries under a directory that are left out, with `skip_unreadable`
+    pub unreadable: Vec<Unreadable>,
+}
+
+impl Preflight {
+    /// The left-out entries' paths, for `SendOptions::skip`
+    pub fn skipped(&self) -> Vec<PathBuf> {
+        self.unreadable.iter().map(|entry| entry.path.clone()).collect()
+    }
+}
+
+/// Check `path` can be sent before anyone is waiting for it
+///
+/// Problems that would otherwise only show up partway through a transfer,
+/// like a FIFO that never ends, a file another program has locked, or an
+/// unreadable file deep in a directory, are reported here instead.
+pub fn preflight(path: &Path, options: &PreflightOptions) -> Result<Preflight> {
+    let link = fs::symlink_metadata(path).map_err(|e| anyhow!("Can't send {}: {}", path.display(), describe(&e)))?;
+    let kind = classify(link.file_type());
+    let (target, sent) = match kind {
+        PathKind::Symlink => {
+            let target = fs::read_link(path)?;
+            let sent = fs::metadata(path)
+                .map_err(|e| anyhow!("{} is a link to {}, which can't be followed: {}", path.display(), target.display(), describe(&e)))?;
+            (Some(target), classify(sent.file_type()))
+        }
+        kind => (None, kind),
+    };
+    
+    let mut unreadable = Vec::new();
+    match sent {
+        PathKind::Special => {
+            return Err(anyhow!(
+                "{} is a {}, not a file or folder; to send what it gives, pipe it in with --stdin-passthrough",
+                path.display(),
+                special_name(&fs::metadata(path)?.file_type())
+            ));
+        }
+        PathKind::Directory => {
+            fs::read_dir(path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), describe(&e)))?;
+            unreadable = unreadable_entries(path);
+        }
+        _ => {
+            File::open(path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), describe(&e)))?;
+        }
+    }
+    if !unreadable.is_empty() && !options.skip_unreadable {
+        let mut message = format!("Can't read everything under {} (send with --skip-unreadable to leave these out):", path.display());
+        for entry in unreadable.iter().take(LISTED_UNREADABLE) {
+            message.push_str(&format!("\n  {}: {}", entry.path.display(), entry.reason));
+        }
+        if unreadable.len() > LISTED_UNREADABLE {
+            message.push_str(&format!("\n  and {} more", unreadable.len() - LISTED_UNREADABLE));
+        }
+        return Err(anyhow!(message));
+    }
+    Ok(Preflight {
+        kind,
+        target,
+        is_directory: sent == PathKind::Directory,
+        unreadable,
+    })
+}
+
+fn classify(file_type: FileType) -> PathKind {
+    if file_type.is_symlink() {
+        PathKind::Symlink
+    } else if file_type.is_dir() {
+        PathKind::Directory
+    } else if file_type.is_file() {
+        PathKind::File
+    } else {
+        PathKind::Special
+    }
+}
+
+#[cfg(unix)]
+fn special_name(file_type: &FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    
+    if file_type.is_fifo() {
+        "FIFO"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        "device node"
+    } else {
+        "special file"
+    }
+}
+
+#[cfg(not(unix))]
+fn special_name(_file_type: &FileType) -> &'static str {
+    "special file"
+}
+
+/// Everything under `dir` an archive of it would fail on, walked as archives walk it
+///
+/// Only regular files are opened; special files are archived without being read.
+fn unreadable_entries(dir: &Path) -> Vec<Unreadable> {
+    let mut unreadable = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).follow_links(true).sort_by_file_name() {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => {
+                if let Err(e) = File::open(entry.path()) {
+                    unreadable.push(Unreadable {
+                        path: entry.into_path(),
+                        reason: describe(&e),
+                    });
+                }
+            }
+            Ok(_) => {}
+            Err(e) => unreadable.push(Unreadable {
+                path: e.path().unwrap_or(dir).to_path_buf(),
+                reason: e.io_error().map_or_else(|| e.to_string(), describe),
+            }),
+        }
+    }
+    unreadable
+}
+
+/// Why opening something failed, in words
+fn describe(e: &io::Error) -> String {
+    #[cfg(windows)]
+    if matches!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)) {
+        return "another program has it open and locked; close it and try again".to_string();
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => "it doesn't exist".to_string(),
+        io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::transfer::archive_entries;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempfile::TempDir;
+    
+    const STRICT: PreflightOptions = PreflightOptions { skip_unreadable: false };
+    const SKIP: PreflightOptions = PreflightOptions { skip_unreadable: true };
+    
+    #[test]
+    fn test_regular_file_and_directory() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("report.pdf");
+        fs::write(&file, "contents").unwrap();
+        let report = preflight(&file, &STRICT).unwrap();
+        assert_eq!((report.kind, report.is_directory, report.target), (PathKind::File, false, None));
+        
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested/notes.txt"), "x").unwrap();
+        let report = preflight(dir.path(), &STRICT).unwrap();
+        assert_eq!((report.kind, report.is_directory), (PathKind::Directory, true));
+        assert!(report.unreadable.is_empty());
+        
+        let err = preflight(&dir.path().join("missing"), &STRICT).unwrap_err();
+        assert!(err.to_string().contains("doesn't exist"), "{}", err);
+    }
+    
+    #[test]
+    fn test_symlinks_are_followed() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("real.txt"), "contents").unwrap();
+        let link = dir.path().join("link.txt");
+        symlink("real.txt", &link).unwrap();
+        let report = preflight(&link, &STRICT).unwrap();
+        assert_eq!((report.kind, report.is_directory), (PathKind::Symlink, false));
+        assert_eq!(report.target, Some(PathBuf::from("real.txt")));
+        
+        symlink(dir.path(), dir.path().join("folder-link")).unwrap();
+        let report = preflight(&dir.path().join("folder-link"), &SKIP).unwrap();
+        assert_eq!((report.kind, report.is_directory), (PathKind::Symlink, true));
+        
+        let broken = dir.path().join("broken");
+        symlink("nowhere", &broken).unwrap();
+        let err = preflight(&broken, &STRICT).unwrap_err();
+        assert!(err.to_string().contains("can't be followed"), "{}", err);
+    }
+    
+    #[test]
+    fn test_special_files_refused() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.path().join("agent.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let err = preflight(&socket, &STRICT).unwrap_err();
+        assert!(err.to_string().contains("is a socket") && err.to_string().contains("--stdin-passthrough"), "{}", err);
+        
+        let err = preflight(Path::new("/dev/null"), &STRICT).unwrap_err();
+        assert!(err.to_string().contains("device node"), "{}", err);
+        
+        // Through a link too
+        symlink(&socket, dir.path().join("link")).unwrap();
+        assert!(preflight(&dir.path().join("link"), &STRICT).is_err());
+    }
+    
+    #[test]
+    fn test_unreadable_entries_in_directory() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        symlink("gone.txt", root.join("src/dangling")).unwrap();
+        let secret = root.join("secret.key");
+        fs::write(&secret, "key").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads it regardless, so only the dangling link counts then
+        let secret_readable = File::open(&secret).is_ok();
+        
+        let err = preflight(&root, &STRICT).unwrap_err().to_string();
+        assert!(err.contains("--skip-unreadable") && err.contains("dangling"), "{}", err);
+        
+        let report = preflight(&root, &SKIP).unwrap();
+        let mut expected = vec![root.join("src/dangling")];
+        if !secret_readable {
+            expected.insert(0, secret.clone());
+            assert_eq!(report.unreadable[0].reason, "permission denied");
+        }
+        assert_eq!(report.skipped(), expected);
+        
+        // Archives leave them out, where they'd otherwise fail partway through
+        assert!(archive_entries(&root, &[]).is_err());
+        let archived: Vec<PathBuf> = archive_entries(&root, &report.skipped())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.into_path())
+            .collect();
+        assert!(!archived.contains(&root.join("src/dangling")));
+        assert!(archived.contains(&root.join("src/main.rs")));
+        assert_eq!(archived.contains(&secret), secret_readable);
+    }
+}
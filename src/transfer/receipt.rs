@@ -0,0 +1,177 @@
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crypto::{self, verify_signature, ChecksumAlgorithm, IdentityKey};
+use crate::fsutil;
+use crate::protocol::{Message, ReceiptSignature};
+use crate::rng::ZapRng;
+
+use super::CHUNK_SIZE;
+
+/// Put in front of the signed fields, so a receipt signature can't pass for anything else
+const SIGNATURE_CONTEXT: &str = "zap-receipt-v1";
+
+/// The receiver's word that a file arrived whole
+///
+/// Sent back once the received file is in place and matches the sender's
+/// checksum, and kept by the sender next to the file as `<file>.zap-receipt.json`.
+/// Signed when the receiver has an identity key, which makes it proof of
+/// delivery for whoever trusts that key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Picked by the receiver, to tell apart receipts for the same file
+    pub transfer_id: String,
+    /// SHA-256 of the file, in hex, or its BLAKE3 after `blake3:` when both sides could do that
+    pub checksum: String,
+    /// Seconds since the Unix epoch
+    pub received_at: u64,
+    pub signature: Option<ReceiptSignature>,
+}
+
+impl Receipt {
+    /// A receipt for a file with `checksum` that arrived just now, signed with `identity` if there is one
+    pub fn new(checksum: &str, identity: Option<&IdentityKey>) -> Result<Self> {
+        let mut receipt = Self {
+            transfer_id: format!("{:016x}", ZapRng::new().next_u64()),
+            checksum: checksum.to_string(),
+            received_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            signature: None,
+        };
+        receipt.signature = identity.map(|identity| ReceiptSignature {
+            public_key: identity.public_key(),
+            signature: identity.sign(&receipt.signed_bytes()),
+        });
+        Ok(receipt)
+    }
+    
+    /// What the signature covers
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!("{}\n{}\n{}\n{}", SIGNATURE_CONTEXT, self.transfer_id, self.checksum, self.received_at).into_bytes()
+    }
+    
+    /// Where the receipt for sending `path` is kept
+    pub fn receipt_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".zap-receipt.json");
+        PathBuf::from(name)
+    }
+    
+    /// Write the receipt to `receipt_file` as JSON, private to the user
+    pub fn save(&self, receipt_file: &Path) -> Result<()> {
+        fsutil::write_private(receipt_file, &serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+    
+    pub fn load(receipt_file: &Path) -> Result<Self> {
+        let data = std::fs::read(receipt_file).map_err(|e| anyhow!("Couldn't read {}: {}", receipt_file.display(), e))?;
+        serde_json::from_slice(&data).map_err(|e| anyhow!("{} isn't a zap receipt: {}", receipt_file.display(), e))
+    }
+    
+    /// Check the signature, if any, returning the public key that made it
+    ///
+    /// With `trusted`, the receipt must be signed by that key.
+    pub fn check_signature(&self, trusted: Option<&str>) -> Result<Option<&str>> {
+        let Some(signature) = &self.signature else {
+            return match trusted {
+                Some(_) => Err(anyhow!("Receipt isn't signed")),
+                None => Ok(None),
+            };
+        };
+        if let Some(trusted) = trusted {
+            if !signature.public_key.eq_ignore_ascii_case(trusted.trim()) {
+                return Err(anyhow!("Receipt was signed by {}, not {}", signature.public_key, trusted.trim()));
+            }
+        }
+        verify_signature(&signature.public_key, &self.signed_bytes(), &signature.signature)
+            .map_err(|e| anyhow!("Receipt signature is invalid: {}", e))?;
+        Ok(Some(&signature.public_key))
+    }
+    
+    /// Check that `file` is the one this receipt is for and the signature holds,
+    /// returning the public key that signed it
+    pub async fn verify(&self, file: &Path, trusted: Option<&str>) -> Result<Option<&str>> {
+        let algorithm = ChecksumAlgorithm::of(&self.checksum);
+        let checksum = crypto::checksum_stream(tokio::fs::File::open(file).await?, algorithm, CHUNK_SIZE).await?;
+        if checksum != self.checksum {
+            return Err(anyhow!("{} doesn't match the receipt ({} {}, receipt says {})", file.display(), algorithm, checksum, self.checksum));
+        }
+        self.check_signature(trusted)
+    }
+}
+
+impl From<Receipt> for Message {
+    fn from(receipt: Receipt) -> Self {
+        Message::Receipt {
+            transfer_id: receipt.transfer_id,
+            checksum: receipt.checksum,
+            received_at: receipt.received_at,
+            signature: receipt.signature,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    #[tokio::test]
+    async fn test_signed_receipt_verifies() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("contract.pdf");
+        std::fs::write(&file, b"signed on the dotted line").unwrap();
+        let identity = IdentityKey::create(&dir.path().join("identity.key")).unwrap();
+        let other = IdentityKey::create(&dir.path().join("other.key")).unwrap();
+        
+        let receipt = Receipt::new(&crypto::checksum(b"signed on the dotted line"), Some(&identity)).unwrap();
+        let receipt_file = Receipt::receipt_path(&file);
+        assert_eq!(receipt_file, dir.path().join("contract.pdf.zap-receipt.json"));
+        receipt.save(&receipt_file).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&receipt_file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let receipt = Receipt::load(&receipt_file).unwrap();
+        
+        assert_eq!(receipt.verify(&file, None).await.unwrap(), Some(identity.public_key().as_str()));
+        receipt.verify(&file, Some(&identity.public_key())).await.unwrap();
+        
+        // Checked against somebody else's key
+        let err = receipt.verify(&file, Some(&other.public_key())).await.unwrap_err();
+        assert!(err.to_string().contains("not"), "{}", err);
+        
+        // A signature moved onto another key's receipt
+        let mut forged = receipt.clone();
+        forged.signature.as_mut().unwrap().public_key = other.public_key();
+        let err = forged.verify(&file, Some(&other.public_key())).await.unwrap_err();
+        assert!(err.to_string().starts_with("Receipt signature is invalid"), "{}", err);
+        
+        // The receipt itself edited
+        let mut edited = receipt.clone();
+        edited.received_at += 1;
+        assert!(edited.check_signature(None).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_tampered_file_fails() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("contract.pdf");
+        std::fs::write(&file, b"original terms").unwrap();
+        let receipt = Receipt::new(&crypto::checksum(b"original terms"), None).unwrap();
+        assert_eq!(receipt.verify(&file, None).await.unwrap(), None);
+        
+        std::fs::write(&file, b"original terms, amended").unwrap();
+        let err = receipt.verify(&file, None).await.unwrap_err();
+        assert!(err.to_string().contains("doesn't match the receipt"), "{}", err);
+        
+        // An unsigned receipt can't vouch for a key
+        std::fs::write(&file, b"original terms").unwrap();
+        let identity = IdentityKey::create(&dir.path().join("identity.key")).unwrap();
+        let err = receipt.verify(&file, Some(&identity.public_key())).await.unwrap_err();
+        assert_eq!(err.to_string(), "Receipt isn't signed");
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fsutil;
+use crate::protocol::TransferState;
+use crate::rng::ZapRng;
+
+/// Staging directories are named this followed by the transfer id
+pub const STAGING_PREFIX: &str = ".zap-staging-";
+
+/// Marker inside every staging directory; `zap clean` only removes directories that have one
+pub const MARKER_NAME: &str = "zap-staging.json";
+
+/// What a staging directory's marker records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Marker {
+    /// Always `zap`, so a stray JSON file with the same name isn't mistaken for ours
+    pub tool: String,
+    pub transfer_id: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Where the transfer was being received to
+    pub destination: PathBuf,
+}
+
+impl Marker {
+    fn read(dir: &Path) -> Option<Self> {
+        let marker: Self = serde_json::from_slice(&fs::read(dir.join(MARKER_NAME)).ok()?).ok()?;
+        let named = dir.file_name()?.to_str()? == format!("{}{}", STAGING_PREFIX, marker.transfer_id);
+        (marker.tool == "zap" && named).then_some(marker)
+    }
+}
+
+/// A directory for one transfer's temporary files, removed when dropped
+///
+/// It's created in `tmp_dir` if given, otherwise next to the destination rather
+/// than in the shared temporary directory, open only to the user. It holds a
+/// marker so a copy left behind by a crash can be found by `zap clean`.
+#[derive(Debug)]
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    pub fn create(tmp_dir: Option<&Path>, destination: &Path) -> Result<Self> {
+        let base = match tmp_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => destination.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let transfer_id = format!("{:016x}", ZapRng::new().next_u64());
+        let path = base.join(format!("{}{}", STAGING_PREFIX, transfer_id));
+        fsutil::create_private_dir(&path)?;
+        
+        // From here on, dropping the value cleans up after a failure
+        let staging = Self { path };
+        let marker = Marker {
+            tool: "zap".to_string(),
+            transfer_id,
+            created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            destination: std::path::absolute(destination).unwrap_or_else(|_| destination.to_path_buf()),
+        };
+        fsutil::write_private(&staging.path.join(MARKER_NAME), &serde_json::to_vec_pretty(&marker)?)?;
+        Ok(staging)
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Kinds of leftovers `zap clean` recognises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanKind {
+    /// A staging directory with its marker
+    Staging,
+    /// A resume record (`.zap-state`) for a transfer that never finished
+    ResumeState,
+}
+
+/// Something an abandoned transfer left behind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub path: PathBuf,
+    pub kind: OrphanKind,
+    pub age: Duration,
+    /// Disk space it takes up
+    pub bytes: u64,
+}
+
+impl Orphan {
+    pub fn remove(&self) -> Result<()> {
+        match self.kind {
+            OrphanKind::Staging => fs::remove_dir_all(&self.path)?,
+            OrphanKind::ResumeState => fs::remove_file(&self.path)?,
+        }
+        Ok(())
+    }
+}
+
+/// Find zap's leftovers under `dir` that are at least `older_than` old
+///
+/// Only staging directories with a valid marker and resume records that parse
+/// as one are reported; anything else is never touched.
+pub fn find_orphans(dir: &Path, older_than: Duration) -> Result<Vec<Orphan>> {
+    if !dir.is_dir() {
+        return Err(anyhow!("Not a directory: {}", dir.display()));
+    }
+    let now = SystemTime::now();
+    let mut orphans = Vec::new();
+    
+    let mut entries = walkdir::WalkDir::new(dir).follow_links(false).into_iter();
+    while let Some(entry) = entries.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy();
+        
+        let found = if entry.file_type().is_dir() && name.starts_with(STAGING_PREFIX) {
+            let Some(marker) = Marker::read(path) else {
+                continue;
+            };
+            // Nothing inside belongs to anyone else
+            entries.skip_current_dir();
+            let created = UNIX_EPOCH + Duration::from_secs(marker.created);
+            Some((OrphanKind::Staging, now.duration_since(created).unwrap_or_default(), dir_size(path)))
+        } else if entry.file_type().is_file() && name.ends_with(".zap-state") && is_resume_state(path) {
+            let metadata = entry.metadata()?;
+            let age = metadata.modified().ok().and_then(|modified| now.duration_since(modified).ok());
+            Some((OrphanKind::ResumeState, age.unwrap_or_default(), metadata.len()))
+        } else {
+            None
+        };
+        
+        if let Some((kind, age, bytes)) = found {
+            if age >= older_than {
+                orphans.push(Orphan { path: path.to_path_buf(), kind, age, bytes });
+            }
+        }
+    }
+    
+    Ok(orphans)
+}
+
+fn is_resume_state(path: &Path) -> bool {
+    fs::read(path).is_ok_and(|data| serde_json::from_slice::<TransferState>(&data).is_ok())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    fn set_age(path: &Path, age: Duration) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+    
+    #[test]
+    fn test_staging_dir_removed_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let staging = StagingDir::create(None, &dir.path().join("photos")).unwrap();
+        assert!(staging.path().starts_with(dir.path()));
+        let marker = Marker::read(staging.path()).unwrap();
+        assert!(marker.destination.ends_with("photos"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!((mode(staging.path()), mode(&staging.path().join(MARKER_NAME))), (0o700, 0o600));
+        }
+        
+        let path = staging.path().to_path_buf();
+        drop(staging);
+        assert!(!path.exists());
+        
+        let tmp = TempDir::new().unwrap();
+        let staging = StagingDir::create(Some(tmp.path()), &dir.path().join("photos")).unwrap();
+        assert!(staging.path().starts_with(tmp.path()));
+    }
+    
+    #[test]
+    fn test_find_and_remove_orphans() {
+        let dir = TempDir::new().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        
+        // Staging directory left by a crash two days ago
+        let staging = StagingDir::create(None, &dir.path().join("photos")).unwrap();
+        let old_staging = staging.path().to_path_buf();
+        std::mem::forget(staging);
+        fs::write(old_staging.join("archive.tar"), vec![0u8; 1000]).unwrap();
+        let mut marker = Marker::read(&old_staging).unwrap();
+        marker.created -= 2 * day.as_secs();
+        fs::write(old_staging.join(MARKER_NAME), serde_json::to_vec(&marker).unwrap()).unwrap();
+        
+        // One from a transfer that might still be running
+        let recent = StagingDir::create(None, &dir.path().join("music")).unwrap();
+        
+        // Resume records, one old enough and one in a subdirectory
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        let old_state = dir.path().join("nested/movie.mkv.zap-state");
+        TransferState::new("movie.mkv", 100, "").save(&old_state).unwrap();
+        set_age(&old_state, 3 * day);
+        let new_state = dir.path().join("song.mp3.zap-state");
+        TransferState::new("song.mp3", 100, "").save(&new_state).unwrap();
+        
+        // Look like ours but lack the marker, so they must survive
+        let unmarked = dir.path().join(format!("{}0123456789abcdef", STAGING_PREFIX));
+        fs::create_dir(&unmarked).unwrap();
+        let impostor = dir.path().join("notes.zap-state");
+        fs::write(&impostor, b"my own notes").unwrap();
+        set_age(&impostor, 3 * day);
+        let mislabelled = dir.path().join(format!("{}fedcba9876543210", STAGING_PREFIX));
+        fs::create_dir(&mislabelled).unwrap();
+        fs::write(mislabelled.join(MARKER_NAME), serde_json::to_vec(&marker).unwrap()).unwrap();
+        
+        let mut orphans = find_orphans(dir.path(), day).unwrap();
+        orphans.sort_by_key(|orphan| orphan.kind == OrphanKind::ResumeState);
+        let found: Vec<_> = orphans.iter().map(|orphan| (orphan.path.clone(), orphan.kind)).collect();
+        assert_eq!(found, vec![(old_staging.clone(), OrphanKind::Staging), (old_state.clone(), OrphanKind::ResumeState)]);
+        assert!(orphans[0].bytes >= 1000);
+        
+        // Finding them is a dry run; nothing is gone yet
+        assert!(old_staging.exists() && old_state.exists());
+        
+        for orphan in &orphans {
+            orphan.remove().unwrap();
+        }
+        assert!(!old_staging.exists() && !old_state.exists());
+        assert!(recent.path().exists() && new_state.exists());
+        assert!(unmarked.exists() && impostor.exists() && mislabelled.exists());
+        
+        // With no age threshold the recent ones count too
+        assert_eq!(find_orphans(dir.path(), Duration::ZERO).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Stdin, Stdout};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::CHUNK_SIZE;
+
+/// Chunks buffered for stdout before the tee waits for it to catch up
+pub const STDOUT_BUFFER_CHUNKS: usize = 4;
+
+/// Reads a stream of unknown length (stdin by default) in chunks
+pub struct StdinChunker<R = Stdin> {
+    reader: R,
+    chunk_size: usize,
+    bytes_read: u64,
+}
+
+impl StdinChunker {
+    /// Chunk this process's stdin
+    pub fn new() -> Self {
+        Self::from_reader(tokio::io::stdin())
+    }
+}
+
+impl Default for StdinChunker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: AsyncRead + Unpin> StdinChunker<R> {
+    /// Chunk any async reader
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            reader,
+            chunk_size: CHUNK_SIZE,
+            bytes_read: 0,
+        }
+    }
+    
+    /// Read the next chunk, filling it unless the stream ends first
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0u8; self.chunk_size];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let n = self.reader.read(&mut buffer[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        
+        if filled == 0 {
+            return Ok(None);
+        }
+        buffer.truncate(filled);
+        self.bytes_read += filled as u64;
+        Ok(Some(buffer))
+    }
+    
+    /// Change the size of subsequent chunks
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+    
+    /// Get bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+/// Copies every chunk read from a stream to stdout as well, like `tee`
+///
+/// Stdout is written by a separate task through a small buffer, so a slow
+/// consumer downstream only holds up the transfer once the buffer is full.
+pub struct TeeChunker<R = Stdin, W = Stdout> {
+    inner: StdinChunker<R>,
+    stdout: Option<mpsc::Sender<Vec<u8>>>,
+    writer: JoinHandle<std::io::Result<W>>,
+}
+
+impl<R, W> TeeChunker<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(inner: StdinChunker<R>, mut stdout: W) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(STDOUT_BUFFER_CHUNKS);
+        let writer = tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                stdout.write_all(&chunk).await?;
+            }
+            stdout.flush().await?;
+            Ok(stdout)
+        });
+        
+        Self {
+            inner,
+            stdout: Some(tx),
+            writer,
+        }
+    }
+    
+    /// Read the next chunk and queue a copy for stdout
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(chunk) = self.inner.next_chunk().await? else {
+            return Ok(None);
+        };
+        if let Some(ref stdout) = self.stdout {
+            if stdout.send(chunk.clone()).await.is_err() {
+                // The writer only stops early on an error, which `finish` reports
+                self.stdout = None;
+                return Err(anyhow!("Failed to write to stdout"));
+            }
+        }
+        Ok(Some(chunk))
+    }
+    
+    /// Change the size of subsequent chunks
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.inner.set_chunk_size(chunk_size);
+    }
+    
+    /// Get bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.inner.bytes_read()
+    }
+    
+    /// Wait until everything has been written to stdout
+    pub async fn finish(mut self) -> Result<W> {
+        self.stdout.take();
+        Ok(self.writer.await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_tee_copies_every_chunk() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut tee = TeeChunker::new(StdinChunker::from_reader(data.as_slice()), Vec::new());
+        tee.set_chunk_size(16 * 1024);
+        
+        let mut sent = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = tee.next_chunk().await.unwrap() {
+            sent.extend_from_slice(&chunk);
+            chunks += 1;
+        }
+        assert_eq!(chunks, 13);
+        assert_eq!(tee.bytes_read(), data.len() as u64);
+        
+        let stdout = tee.finish().await.unwrap();
+        assert_eq!(sent, data);
+        assert_eq!(stdout, data);
+    }
+    
+    #[tokio::test]
+    async fn test_empty_input() {
+        let mut tee = TeeChunker::new(StdinChunker::from_reader(&b""[..]), Vec::new());
+        assert!(tee.next_chunk().await.unwrap().is_none());
+        assert!(tee.finish().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::protocol::ManifestEntry;
+
+use super::conflict::SkippedFile;
+use super::hash_tree::{hash_tree, hash_tree_reusing};
+use super::manifest_cache::ManifestCache;
+
+/// What a one-shot sync has to do to make the receiver's folder match the sender's
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Files the receiver lacks or has different contents for
+    pub wanted: Vec<String>,
+    /// Files the receiver already has as they are
+    pub unchanged: usize,
+    /// Files only the receiver has
+    pub remote_only: Vec<String>,
+    /// The sender's files the receiver's folder can't hold alongside another of them
+    pub skipped: Vec<SkippedFile>,
+}
+
+/// Every file under `root`, sorted by path, hashed on `threads` threads (0 for one per core)
+///
+/// A folder that doesn't exist yet has no files. `progress` gets files done
+/// and total files as they're hashed.
+pub async fn build_manifest(root: &Path, threads: usize, progress: impl FnMut(u64, u64)) -> Result<Vec<ManifestEntry>> {
+    if !folder_exists(root).await? {
+        return Ok(Vec::new());
+    }
+    hash_tree(root, &[], threads).await?.collect(progress).await
+}
+
+/// Like `build_manifest`, reusing entries from `cache_file` for files that haven't changed, and saving the new ones there
+///
+/// Also returns how many files had to be read. See `ManifestCache` for when
+/// a cached entry is trusted. A cache that can't be written only costs the
+/// next build its time, so that isn't an error.
+pub async fn build_manifest_cached(root: &Path, threads: usize, cache_file: &Path, progress: impl FnMut(u64, u64)) -> Result<(Vec<ManifestEntry>, usize)> {
+    if !folder_exists(root).await? {
+        return Ok((Vec::new(), 0));
+    }
+    let started = SystemTime::now();
+    let (cache, folder) = (cache_file.to_path_buf(), root.to_path_buf());
+    let known = tokio::task::spawn_blocking(move || ManifestCache::load(&cache, &folder)).await?;
+    let files = hash_tree_reusing(root, &[], threads, known).await?.collect_files(progress).await?;
+    
+    let cache = ManifestCache::new(root, started, &files);
+    let cache_file = cache_file.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || cache.save(&cache_file)).await;
+    let read = files.iter().filter(|file| file.read).count();
+    Ok((files.into_iter().map(|file| file.entry).collect(), read))
+}
+
+/// Whether there's a folder at `root`; nothing there is no folder, and anything else an error
+async fn folder_exists(root: &Path) -> Result<bool> {
+    match tokio::fs::metadata(root).await {
+        Ok(metadata) if !metadata.is_dir() => Err(anyhow!("{} isn't a folder", root.display())),
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compare the sender's `source` manifest with the receiver's `destination`
+///
+/// Files count as changed when their size or checksum differ; a different
+/// modification time alone doesn't send a file again. With `fold_case`, for
+/// a receiver whose filesystem ignores case, paths that differ only in case
+/// are the same file: the receiver's `notes.txt` is the sender's `Notes.txt`
+/// rather than a file only it has, and of the sender's files that clash like
+/// that, only the first is wanted.
+pub fn plan(source: &[ManifestEntry], destination: &[ManifestEntry], fold_case: bool) -> SyncPlan {
+    let key = |path: &str| if fold_case { path.to_lowercase() } else { path.to_string() };
+    let existing: HashMap<String, &ManifestEntry> = destination.iter().map(|entry| (key(&entry.path), entry)).collect();
+    let mut offered: HashMap<String, &str> = HashMap::new();
+    let mut plan = SyncPlan::default();
+    for entry in source {
+        let key = key(&entry.path);
+        if let Some(first) = offered.get(&key) {
+            plan.skipped.push(SkippedFile {
+                path: PathBuf::from(&entry.path),
+                reason: format!("differs only in case from {}, which this filesystem can't tell apart", first),
+            });
+            continue;
+        }
+        match existing.get(&key) {
+            Some(theirs) if theirs.size == entry.size && theirs.checksum == entry.checksum => plan.unchanged += 1,
+            _ => plan.wanted.push(entry.path.clone()),
+        }
+        offered.insert(key, &entry.path);
+    }
+    plan.remote_only = destination
+        .iter()
+        .filter(|entry| !offered.contains_key(&key(&entry.path)))
+        .map(|entry| entry.path.clone())
+        .collect();
+    plan
+}
+
+/// Delete `paths` from under `root`, each of which must be one of the plan's `remote_only` files
+///
+/// Folders left empty by the deletions go too. Returns how many files were deleted.
+pub fn delete_remote_only(root: &Path, plan: &SyncPlan, paths: &[String]) -> Result<usize> {
+    let remote_only: HashSet<&str> = plan.remote_only.iter().map(String::as_str).collect();
+    if let Some(path) = paths.iter().find(|path| !remote_only.contains(path.as_str())) {
+        return Err(anyhow!("Sender asked to delete {}, which isn't only on this side", path));
+    }
+    for path in paths {
+        let file = root.join(path);
+        match fs::remove_file(&file) {
+            Ok(()) => {}
+            // Gone already is as good as deleted
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Couldn't delete {}: {}", file.display(), e)),
+        }
+        let mut parent = file.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != root) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+    Ok(paths.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    
+    fn entry(path: &str, contents: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size: contents.len() as u64,
+            mtime: 0,
+            checksum: blake3::hash(contents.as_bytes()).to_hex().to_string(),
+        }
+    }
+    
+    #[test]
+    fn test_plan() {
+        let source = vec![
+            entry("a.txt", "same"),
+            entry("b.txt", "new contents"),
+            entry("c.txt", "1234"),
+            entry("docs/new.md", "only here"),
+        ];
+        let mut touched = entry("a.txt", "same");
+        touched.mtime = 1_700_000_000;
+        let destination = vec![
+            touched,
+            entry("b.txt", "old contents"),
+            entry("c.txt", "abcd"),
+            entry("old/stale.txt", "gone from the sender"),
+        ];
+        assert_eq!(
+            plan(&source, &destination, false),
+            SyncPlan {
+                wanted: vec!["b.txt".to_string(), "c.txt".to_string(), "docs/new.md".to_string()],
+                unchanged: 1,
+                remote_only: vec!["old/stale.txt".to_string()],
+                skipped: Vec::new(),
+            }
+        );
+        
+        // Syncing into nothing sends everything
+        let first = plan(&source, &[], false);
+        assert_eq!(first.wanted.len(), 4);
+        assert_eq!((first.unchanged, first.remote_only.len()), (0, 0));
+        assert_eq!(plan(&source, &source, false).wanted, Vec::<String>::new());
+    }
+    
+    #[test]
+    fn test_plan_folding_case() {
+        let source = vec![
+            entry("Notes.txt", "same"),
+            entry("photos/IMG_1.jpg", "new"),
+            entry("photos/img_1.jpg", "another photo"),
+        ];
+        let destination = vec![entry("notes.txt", "same"), entry("PHOTOS/img_1.JPG", "old")];
+        
+        // Where case matters, these are all different files
+        let sensitive = plan(&source, &destination, false);
+        assert_eq!((sensitive.wanted.len(), sensitive.remote_only.len(), sensitive.skipped.len()), (3, 2, 0));
+        
+        let folded = plan(&source, &destination, true);
+        assert_eq!(folded.wanted, ["photos/IMG_1.jpg"]);
+        assert_eq!(folded.unchanged, 1);
+        assert!(folded.remote_only.is_empty(), "{:?}", folded.remote_only);
+        assert_eq!(folded.skipped.len(), 1);
+        assert_eq!(
+            folded.skipped[0].to_string(),
+            "photos/img_1.jpg: differs only in case from photos/IMG_1.jpg, which this filesystem can't tell apart"
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_build_manifest() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("photos");
+        assert_eq!(build_manifest(&root, 0, |_, _| {}).await.unwrap(), Vec::new());
+        
+        fs::create_dir_all(root.join("2024/summer")).unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+        fs::write(root.join("b.jpg"), "bbb").unwrap();
+        fs::write(root.join("2024/summer/a.jpg"), "aaaa").unwrap();
+        let manifest = build_manifest(&root, 2, |_, _| {}).await.unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["2024/summer/a.jpg", "b.jpg"]);
+        assert_eq!(manifest[0], ManifestEntry { mtime: manifest[0].mtime, ..entry("2024/summer/a.jpg", "aaaa") });
+        assert!(manifest[0].mtime > 0);
+        
+        assert!(build_manifest(&root.join("b.jpg"), 0, |_, _| {}).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_manifest_cache_rehashes_only_changed_files() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("photos");
+        fs::create_dir_all(root.join("2024")).unwrap();
+        let an_hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        for i in 0..6 {
+            let path = root.join(format!("2024/{}.jpg", i));
+            fs::write(&path, format!("photo {}", i)).unwrap();
+            fs::File::options().write(true).open(&path).unwrap().set_modified(an_hour_ago).unwrap();
+        }
+        let cache_file = dir.path().join("cache/photos.json");
+        let build = || async {
+            let (manifest, read) = build_manifest_cached(&root, 2, &cache_file, |_, _| {}).await.unwrap();
+            assert_eq!(manifest, build_manifest(&root, 2, |_, _| {}).await.unwrap());
+            read
+        };
+        
+        assert_eq!(build().await, 6);
+        assert_eq!(build().await, 0);
+        
+        // Same size with different contents, and a different size
+        fs::write(root.join("2024/1.jpg"), "photo X").unwrap();
+        fs::write(root.join("2024/4.jpg"), "photo 4, edited").unwrap();
+        assert_eq!(build().await, 2);
+        // Changed so recently that another write could go unnoticed, so still not trusted
+        assert_eq!(build().await, 2);
+        
+        // A cache from another version, or for another folder, is thrown away
+        let mut cache: serde_json::Value = serde_json::from_slice(&fs::read(&cache_file).unwrap()).unwrap();
+        cache["version"] = 999.into();
+        fs::write(&cache_file, serde_json::to_vec(&cache).unwrap()).unwrap();
+        assert_eq!(build().await, 6);
+        let (_, read) = build_manifest_cached(&root.join("2024"), 1, &cache_file, |_, _| {}).await.unwrap();
+        assert_eq!(read, 6);
+        assert_eq!(build().await, 6);
+        fs::write(&cache_file, "{ not json").unwrap();
+        assert_eq!(build().await, 6);
+    }
+    
+    #[test]
+    fn test_delete_remote_only() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("old/nested")).unwrap();
+        fs::write(root.join("old/nested/stale.txt"), "x").unwrap();
+        fs::write(root.join("keep.txt"), "x").unwrap();
+        let plan = SyncPlan {
+            remote_only: vec!["old/nested/stale.txt".to_string()],
+            ..SyncPlan::default()
+        };
+        
+        // Only files the plan found on this side alone
+        let err = delete_remote_only(root, &plan, &["keep.txt".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("isn't only on this side"), "{}", err);
+        assert!(delete_remote_only(root, &plan, &["../escape".to_string()]).is_err());
+        assert!(root.join("keep.txt").exists());
+        
+        assert_eq!(delete_remote_only(root, &plan, &plan.remote_only).unwrap(), 1);
+        assert!(!root.join("old").exists(), "emptied folders go too");
+        assert!(root.join("keep.txt").exists());
+    }
+}
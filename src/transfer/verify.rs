@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+use crate::crypto::{ChecksumAlgorithm, BLAKE3_PREFIX};
+
+use super::{checksum_file, Receipt};
+
+/// What a file on disk is checked against by `zap verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    /// A SHA-256 given by hand, in hex, or a BLAKE3 after `blake3:`
+    Checksum(String),
+    /// The checksum in a receipt, whose signature has to hold too
+    Receipt(Receipt),
+}
+
+impl Expected {
+    /// A checksum as typed: 64 hex digits, either case, with `blake3:` in front for a BLAKE3 one
+    pub fn checksum(typed: &str) -> Result<Self> {
+        let typed = typed.trim();
+        let (prefix, hex) = match typed.strip_prefix(BLAKE3_PREFIX) {
+            Some(hex) => (BLAKE3_PREFIX, hex),
+            None => ("", typed),
+        };
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("{:?} isn't a SHA-256 checksum (64 hex digits) or a BLAKE3 one (blake3: and 64 hex digits)", typed));
+        }
+        Ok(Self::Checksum(format!("{}{}", prefix, hex.to_ascii_lowercase())))
+    }
+    
+    /// The checksum the file should have
+    pub fn digest(&self) -> &str {
+        match self {
+            Self::Checksum(checksum) => checksum,
+            Self::Receipt(receipt) => &receipt.checksum,
+        }
+    }
+}
+
+/// How a file compared to what it was checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// Checksum of the file as it is now, by the same algorithm as `expected`
+    pub actual: String,
+    pub expected: String,
+    /// Public key that signed the receipt, if it was checked against a signed one
+    pub signer: Option<String>,
+}
+
+impl Verdict {
+    pub fn matches(&self) -> bool {
+        self.actual == self.expected
+    }
+}
+
+/// Hash the file at `path` and compare it to `expected`, telling `progress` the bytes read so far and the total
+///
+/// A receipt's signature is checked before the file is read, so a tampered
+/// receipt is an error rather than a verdict.
+pub async fn verify(path: &Path, expected: &Expected, progress: impl FnMut(u64, u64)) -> Result<Verdict> {
+    let signer = match expected {
+        Expected::Receipt(receipt) => receipt.check_signature(None)?.map(str::to_string),
+        Expected::Checksum(_) => None,
+    };
+    Ok(Verdict {
+        actual: checksum_file(path, ChecksumAlgorithm::of(expected.digest()), progress).await?,
+        expected: expected.digest().to_string(),
+        signer,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, IdentityKey};
+    use tempfile::TempDir;
+    
+    fn fixture(dir: &TempDir) -> (std::path::PathBuf, String) {
+        let path = dir.path().join("photo.jpg");
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        (path, crypto::checksum(&data))
+    }
+    
+    #[tokio::test]
+    async fn test_matches_given_checksum() {
+        let dir = TempDir::new().unwrap();
+        let (path, checksum) = fixture(&dir);
+        let mut seen = Vec::new();
+        let verdict = verify(&path, &Expected::checksum(&checksum.to_uppercase()).unwrap(), |read, total| seen.push((read, total))).await.unwrap();
+        assert!(verdict.matches());
+        assert_eq!(verdict.actual, checksum);
+        assert_eq!(seen.last(), Some(&(200_000, 200_000)));
+        assert!(Expected::checksum("abc123").is_err());
+        
+        let blake3 = format!("blake3:{}", blake3::hash(&std::fs::read(&path).unwrap()).to_hex());
+        let verdict = verify(&path, &Expected::checksum(&blake3).unwrap(), |_, _| {}).await.unwrap();
+        assert!(verdict.matches());
+        assert_eq!(verdict.actual, blake3);
+        assert!(Expected::checksum("blake3:abc123").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_matches_signed_receipt() {
+        let dir = TempDir::new().unwrap();
+        let (path, checksum) = fixture(&dir);
+        let key = IdentityKey::create(&dir.path().join("identity.key")).unwrap();
+        let receipt = Receipt::new(&checksum, Some(&key)).unwrap();
+        let verdict = verify(&path, &Expected::Receipt(receipt.clone()), |_, _| {}).await.unwrap();
+        assert!(verdict.matches());
+        assert_eq!(verdict.signer, Some(key.public_key()));
+        
+        // A receipt edited to say something else doesn't get as far as a verdict
+        let forged = Receipt { checksum: "0".repeat(64), ..receipt };
+        assert!(verify(&path, &Expected::Receipt(forged), |_, _| {}).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_changed_file_is_a_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let (path, checksum) = fixture(&dir);
+        let receipt = Receipt::new(&checksum, None).unwrap();
+        std::fs::write(&path, b"not the photo").unwrap();
+        for expected in [Expected::checksum(&checksum).unwrap(), Expected::Receipt(receipt)] {
+            let verdict = verify(&path, &expected, |_, _| {}).await.unwrap();
+            assert!(!verdict.matches());
+            assert_eq!(verdict.expected, checksum);
+            assert_eq!(verdict.actual, crypto::checksum(b"not the photo"));
+        }
+    }
+}
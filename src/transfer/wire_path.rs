@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// The name offered for what's at `path`: only its last component
+///
+/// A path ending in `.` or `..` has no name of its own, so it's resolved
+/// first; `zap send .` offers the current folder's name, not where it is.
+pub fn offered_name(path: &Path) -> Result<String> {
+    let resolved;
+    let path = match path.components().next_back() {
+        Some(Component::Normal(_)) => path,
+        Some(Component::CurDir | Component::ParentDir) => {
+            resolved = std::fs::canonicalize(path)?;
+            &resolved
+        }
+        _ => return Err(anyhow!("Invalid file path")),
+    };
+    let name = path.file_name().ok_or_else(|| anyhow!("Invalid file path"))?.to_string_lossy().to_string();
+    debug_assert!(is_bare_name(&name), "offered name {:?} isn't a bare name", name);
+    Ok(name)
+}
+
+/// `path`, found by walking `root`, as it's named in what's sent: relative to `root`
+///
+/// However `root` was given (`photos/`, `.`, a link to the folder), only the
+/// part below it is kept. Anything that doesn't lead down from `root`, or
+/// would climb out of it once there, is an error rather than sent.
+pub fn relative_to(root: &Path, path: &Path) -> Result<PathBuf> {
+    let below = path
+        .strip_prefix(root)
+        .map_err(|_| anyhow!("{} isn't in the folder being sent", path.display()))?;
+    let mut relative = PathBuf::new();
+    for component in below.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("{} leads out of the folder being sent", path.display())),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(anyhow!("{} is the folder being sent, not something in it", path.display()));
+    }
+    debug_assert!(is_contained(&relative), "{} escapes {}", relative.display(), root.display());
+    Ok(relative)
+}
+
+/// `relative` with `/` between its components, whatever the platform, for a manifest
+pub fn manifest_path(relative: &Path) -> Result<String> {
+    let parts = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str().ok_or_else(|| anyhow!("{} isn't valid UTF-8", relative.display())),
+            _ => Err(anyhow!("Unexpected path in folder: {}", relative.display())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Whether `path` stays inside whatever folder it's put in: no root, drive or `..`
+pub fn is_contained(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Whether `name` names one thing, with nothing around it to give away
+fn is_bare_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::{archive_entries, create_tar_archive, hash_tree, ZipDirectoryChunker};
+    use std::fs;
+    use tempfile::TempDir;
+    
+    fn folder() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("photos");
+        fs::create_dir_all(root.join("2024/summer")).unwrap();
+        fs::write(root.join("2024/summer/beach.jpg"), "beach").unwrap();
+        fs::write(root.join("index.md"), "index").unwrap();
+        dir
+    }
+    
+    /// Everything that names a file in what's sent for `root`: tar entries and links, ZIP entries, and the manifest
+    async fn sent_names(root: &Path, scratch: &Path) -> Vec<String> {
+        let mut names = Vec::new();
+        let tar_path = scratch.join("sent.tar");
+        create_tar_archive(root, &tar_path).unwrap();
+        let mut archive = tar::Archive::new(fs::File::open(&tar_path).unwrap());
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            names.push(entry.path().unwrap().to_string_lossy().into_owned());
+            names.extend(entry.link_name().unwrap().map(|link| link.to_string_lossy().into_owned()));
+        }
+        
+        let mut chunker = ZipDirectoryChunker::new(root, &[], 1024).unwrap();
+        let mut zip = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            zip.extend(chunk);
+        }
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+        names.extend((0..zip.len()).map(|index| zip.by_index(index).unwrap().name().to_string()));
+        
+        let manifest = hash_tree(root, &[], 1).await.unwrap().collect(|_, _| {}).await.unwrap();
+        names.extend(manifest.into_iter().map(|entry| entry.path));
+        names
+    }
+    
+    fn assert_private(names: &[String], dir: &Path) {
+        let outside = dir.to_string_lossy();
+        assert!(!names.is_empty());
+        for name in names {
+            assert!(is_contained(Path::new(name)), "{} escapes the folder", name);
+            assert!(!name.contains(outside.as_ref()) && !name.contains("photos"), "{} gives away where the folder is", name);
+        }
+    }
+    
+    #[test]
+    fn test_relative_to() {
+        let root = Path::new("/home/alice/photos");
+        assert_eq!(relative_to(root, Path::new("/home/alice/photos/2024/a.jpg")).unwrap(), Path::new("2024/a.jpg"));
+        assert_eq!(relative_to(Path::new("photos/"), Path::new("photos/a.jpg")).unwrap(), Path::new("a.jpg"));
+        assert_eq!(relative_to(Path::new("."), Path::new("./2024/./a.jpg")).unwrap(), Path::new("2024/a.jpg"));
+        
+        assert!(relative_to(root, Path::new("/home/alice/notes.txt")).is_err());
+        assert!(relative_to(root, Path::new("/home/alice/photos/../.ssh/id_ed25519")).is_err());
+        assert!(relative_to(root, root).is_err());
+        assert_eq!(manifest_path(Path::new("2024/summer/a.jpg")).unwrap(), "2024/summer/a.jpg");
+        assert!(manifest_path(Path::new("../a.jpg")).is_err());
+    }
+    
+    #[test]
+    fn test_offered_name() {
+        let dir = folder();
+        let root = dir.path().join("photos");
+        assert_eq!(offered_name(&root).unwrap(), "photos");
+        assert_eq!(offered_name(Path::new(&format!("{}/", root.display()))).unwrap(), "photos");
+        assert_eq!(offered_name(&root.join("2024/..")).unwrap(), "photos");
+        assert_eq!(offered_name(&root.join(".")).unwrap(), "photos");
+        let here = std::env::current_dir().unwrap();
+        assert_eq!(offered_name(Path::new(".")).unwrap(), here.file_name().unwrap().to_string_lossy());
+        assert!(offered_name(Path::new("/")).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_nothing_above_the_root_is_sent() {
+        let dir = folder();
+        let root = dir.path().join("photos");
+        assert_private(&sent_names(&root, dir.path()).await, dir.path());
+        
+        // With a trailing slash, and through `.`
+        let slashed = PathBuf::from(format!("{}/", root.display()));
+        assert_private(&sent_names(&slashed, dir.path()).await, dir.path());
+        assert_private(&sent_names(&root.join("."), dir.path()).await, dir.path());
+        
+        let entries = archive_entries(&root.join("."), &[]).unwrap();
+        for entry in entries {
+            assert!(!relative_to(&root.join("."), entry.path()).unwrap().starts_with("photos"));
+        }
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinked_root() {
+        let dir = folder();
+        let root = dir.path().join("photos");
+        let link = dir.path().join("shortcut");
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+        // And a link inside it, to a file outside
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("linked.txt")).unwrap();
+        
+        let names = sent_names(&link, dir.path()).await;
+        assert_private(&names, dir.path());
+        assert!(names.iter().any(|name| name == "linked.txt"), "{:?}", names);
+        assert!(!names.iter().any(|name| name.contains("secret") || name.contains("shortcut")), "{:?}", names);
+        assert_eq!(offered_name(&link).unwrap(), "shortcut");
+    }
+}
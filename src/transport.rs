@@ -1,65 +1,644 @@
-use anyhow::Result;
-use std::net::SocketAddr;
+use anyhow::{anyhow, Result};
+use futures_util::FutureExt;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
-use crate::network::Connection;
-use crate::relay::{RelayConnection, Role};
+use crate::network::{self, Connection, Endpoint, FrameReader, SocketTimeouts, TlsIdentity, TlsRole};
+use crate::relay::protocol::{CAP_KEEPALIVE, CAP_PICKUP};
+use crate::relay::{RelayConnection, RelayRoom, RelayUrl, Role, RELAY_PING_INTERVAL};
+
+/// How long `Transport::upgrade_direct` keeps trying to reach the peer before staying on the relay
+pub const DIRECT_UPGRADE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// UDP packets sent ahead of the TCP attempt to open the NAT's mapping towards the peer
+const PUNCH_PACKETS: usize = 3;
+
+/// Pause between connection attempts refused because the peer isn't trying yet
+const PUNCH_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// What both ends of an upgraded connection send first, so each knows the other switched too
+const DIRECT_HELLO: &[u8; 8] = b"zapdirct";
+
+/// The path a transport's traffic takes to the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerInfo {
+    /// Straight TCP connection to the peer
+    Direct { addr: SocketAddr },
+    /// Everything goes through a relay, which hides the peer's address
+    Relay { relay_url: String },
+    /// Met through a relay, then switched to a direct connection
+    Upgraded { addr: SocketAddr, via_relay_url: String },
+    /// Both ends live in this process
+    Memory,
+}
+
+impl PeerInfo {
+    /// The peer's address, when we talk to it directly
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            PeerInfo::Direct { addr } | PeerInfo::Upgraded { addr, .. } => Some(*addr),
+            PeerInfo::Relay { .. } | PeerInfo::Memory => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerInfo::Direct { addr } => write!(f, "{}", addr),
+            PeerInfo::Relay { relay_url } => write!(f, "relay {}", relay_url),
+            PeerInfo::Upgraded { addr, via_relay_url } => write!(f, "{} (via relay {})", addr, via_relay_url),
+            PeerInfo::Memory => write!(f, "in-memory peer"),
+        }
+    }
+}
 
 /// Transport abstraction that works with both direct TCP and relay
 pub enum Transport {
     Direct(Connection),
-    Relay(RelayConnection),
+    Relay(Box<RelayConnection>),
+    /// One room of a multi-room relay session
+    RelayRoom(RelayRoom),
+    /// Direct connection to a peer first met through a relay; see `upgrade_direct`
+    Upgraded { conn: Connection, via_relay_url: String },
+    /// In-process pipe to the other end, for tests and `zap selftest`
+    Memory(MemoryPipe),
+}
+
+/// One end of an in-process pipe, made from a `tokio::io::duplex` half
+pub struct MemoryPipe {
+    stream: DuplexStream,
+    /// What's arrived of a message a cancelled `receive` didn't finish
+    reader: FrameReader,
+}
+
+impl From<DuplexStream> for MemoryPipe {
+    fn from(stream: DuplexStream) -> Self {
+        Self { stream, reader: FrameReader::default() }
+    }
 }
 
 impl Transport {
+    /// Carry on over TLS as `role`, see `Connection::start_tls`; only direct connections can
+    pub async fn start_tls(self, role: TlsRole, identity: &TlsIdentity) -> Result<Self> {
+        match self {
+            Transport::Direct(conn) => Ok(Transport::Direct(conn.start_tls(role, identity).await?)),
+            _ => Err(anyhow!("--direct-tls is only for direct connections, not ones through a relay")),
+        }
+    }
+    
+    /// See `Connection::peer_cert_fingerprint`; `None` for anything but a direct connection over TLS
+    pub fn peer_cert_fingerprint(&self) -> Option<[u8; 32]> {
+        match self {
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => conn.peer_cert_fingerprint(),
+            _ => None,
+        }
+    }
+    
+    /// See `Connection::set_timeouts`; relay and in-memory transports aren't timed
+    pub fn set_timeouts(&mut self, timeouts: SocketTimeouts) {
+        if let Transport::Direct(conn) | Transport::Upgraded { conn, .. } = self {
+            conn.set_timeouts(timeouts);
+        }
+    }
+    
+    /// See `Connection::enable_protocol_debug`; messages through a relay or in memory aren't logged
+    #[cfg(debug_assertions)]
+    pub fn enable_protocol_debug(&mut self, log_fn: network::ProtocolLog) {
+        if let Transport::Direct(conn) | Transport::Upgraded { conn, .. } = self {
+            conn.enable_protocol_debug(log_fn);
+        }
+    }
+    
+    /// Two transports connected to each other in memory
+    pub fn memory_pair() -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(network::MEMORY_BUFFER_SIZE);
+        (Transport::Memory(a.into()), Transport::Memory(b.into()))
+    }
+    
     /// Create a transport for sending (either listen on TCP or connect to relay)
-    pub async fn new_sender(relay_addr: Option<String>, code: &str, port: Option<u16>) -> Result<Self> {
-        if let Some(relay) = relay_addr {
-            let relay_conn = RelayConnection::connect(&relay, code, Role::Sender).await?;
-            Ok(Transport::Relay(relay_conn))
+    ///
+    /// With `try_direct`, a relay connection asks for the peer's address so
+    /// `try_direct` can be called on the transport once it's matched.
+    /// `on_renew` is called whenever the relay room is registered again while
+    /// waiting for the peer (see `RelayConnection::connect_with`).
+    pub async fn new_sender(
+        relays: Option<Vec<RelayUrl>>,
+        code: &str,
+        port: Option<u16>,
+        relay_max_frame_size: usize,
+        try_direct: bool,
+        on_renew: impl Fn(),
+    ) -> Result<Self> {
+        if let Some(relays) = relays {
+            let relay_conn = Self::connect_relay(&relays, code, Role::Sender, relay_max_frame_size, try_direct, on_renew).await?;
+            Ok(Self::relay(relay_conn))
         } else {
             let conn = crate::network::listen(port).await?;
             Ok(Transport::Direct(conn))
         }
     }
     
+    /// Create a transport that uploads to the relay's mailbox for a receiver that isn't online yet
+    ///
+    /// Returns the transport and how long the relay will keep the upload.
+    pub async fn new_mailbox_sender(
+        relays: &[RelayUrl],
+        code: &str,
+        ttl: Duration,
+        relay_max_frame_size: usize,
+    ) -> Result<(Self, Duration)> {
+        let (relay_conn, ttl) = RelayConnection::store(relays, code, ttl, relay_max_frame_size).await?;
+        Ok((Self::relay(relay_conn), ttl))
+    }
+    
+    async fn connect_relay(
+        relays: &[RelayUrl],
+        code: &str,
+        role: Role,
+        max_frame_size: usize,
+        try_direct: bool,
+        on_renew: impl Fn(),
+    ) -> Result<RelayConnection> {
+        RelayConnection::connect_with(relays, code, role, max_frame_size, try_direct, on_renew).await
+    }
+    
+    /// Wrap a relay connection, watching its health when the relay answers pings
+    fn relay(relay_conn: RelayConnection) -> Self {
+        if relay_conn.supports(CAP_KEEPALIVE) {
+            // Stops by itself when the connection is dropped
+            relay_conn.spawn_health_monitor(RELAY_PING_INTERVAL);
+        }
+        Transport::Relay(Box::new(relay_conn))
+    }
+    
     /// Create a transport for receiving (either connect to TCP or connect to relay)
+    ///
+    /// `try_direct` and `on_renew` are as for `new_sender`; `happy_eyeballs`
+    /// is as for `network::connect`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_receiver(
-        relay_addr: Option<String>,
+        relays: Option<Vec<RelayUrl>>,
         code: &str,
-        host: Option<&str>,
+        host: Option<&Endpoint>,
         port: Option<u16>,
+        happy_eyeballs: bool,
+        relay_max_frame_size: usize,
+        try_direct: bool,
+        on_renew: impl Fn(),
     ) -> Result<Self> {
-        if let Some(relay) = relay_addr {
-            let relay_conn = RelayConnection::connect(&relay, code, Role::Receiver).await?;
-            Ok(Transport::Relay(relay_conn))
+        if let Some(relays) = relays {
+            let relay_conn = Self::connect_relay(&relays, code, Role::Receiver, relay_max_frame_size, try_direct, on_renew).await?;
+            Ok(Self::relay(relay_conn))
         } else {
             let host = host.ok_or_else(|| anyhow::anyhow!("Host required for direct connection"))?;
-            let conn = crate::network::connect(host, port).await?;
+            let conn = crate::network::connect(host, port, happy_eyeballs).await?;
             Ok(Transport::Direct(conn))
         }
     }
     
+    /// Create a transport for receiving through a relay that may hand over an upload left in its mailbox
+    ///
+    /// A live sender is still met as usual. `on_renew` is as for `new_sender`.
+    pub async fn new_mailbox_receiver(relays: &[RelayUrl], code: &str, relay_max_frame_size: usize, on_renew: impl Fn()) -> Result<Self> {
+        let relay_conn = RelayConnection::pickup(relays, code, relay_max_frame_size, on_renew).await?;
+        Ok(Self::relay(relay_conn))
+    }
+    
+    /// Whether this is a relay connection that asked for the relay's mailbox (see `new_mailbox_receiver`)
+    ///
+    /// Nothing else may take a mailbox upload's key, which comes from the code alone.
+    pub fn mailbox_pickup(&self) -> bool {
+        matches!(self, Transport::Relay(relay_conn) if relay_conn.supports(CAP_PICKUP))
+    }
+    
+    /// Move a relay connection onto a direct one when the relay passed on the peer's address
+    ///
+    /// Both ends must call this at the same point, before anything else is
+    /// sent. Returns whether the transport is now direct; when it isn't,
+    /// nothing has been read from or written to the relay.
+    pub async fn try_direct(&mut self) -> bool {
+        let Transport::Relay(relay_conn) = self else {
+            return false;
+        };
+        let (Some(peer), Some(local)) = (relay_conn.peer_hint(), relay_conn.local_addr()) else {
+            return false;
+        };
+        println!("Trying to reach the peer directly at {}", peer);
+        match self.upgrade_direct(peer, local.port()).await {
+            Ok(true) => true,
+            Ok(false) => {
+                println!("No direct path to the peer, staying on the relay");
+                false
+            }
+            Err(e) => {
+                println!("Couldn't try a direct connection ({}), staying on the relay", e);
+                false
+            }
+        }
+    }
+    
+    /// Replace a relay connection with a direct TCP connection to `peer_external_addr`
+    ///
+    /// A few UDP packets go out from `local_port` first to open a mapping in
+    /// our NAT, then the peer is connected to from `local_port` over TCP until
+    /// it answers or `DIRECT_UPGRADE_TIMEOUT` runs out. The peer does the same
+    /// towards us, so `local_port` should be the one the relay saw us on. The
+    /// sender also listens on `local_port`, so one of the two attempts gets
+    /// through even when the peers' packets don't cross (a simultaneous open).
+    /// Returns `Ok(false)`, still on the relay, if the peer couldn't be reached.
+    pub async fn upgrade_direct(&mut self, peer_external_addr: SocketAddr, local_port: u16) -> Result<bool> {
+        let Transport::Relay(relay_conn) = self else {
+            return Err(anyhow!("Only relay connections can be upgraded"));
+        };
+        let unspecified = match peer_external_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let local = SocketAddr::new(unspecified, local_port);
+        
+        let udp = UdpSocket::bind(local).await?;
+        for _ in 0..PUNCH_PACKETS {
+            // Only there to open our side; the peer never reads them
+            let _ = udp.send_to(DIRECT_HELLO, peer_external_addr).await;
+        }
+        drop(udp);
+        
+        let listener = match relay_conn.role() {
+            Some(Role::Sender) => {
+                let socket = network::shared_port_socket(&peer_external_addr)?;
+                socket.bind(local)?;
+                Some(socket.listen(1)?)
+            }
+            _ => None,
+        };
+        let connect = async {
+            match &listener {
+                // Connecting fails once the peer's connection takes the same addresses
+                Some(listener) => tokio::select! {
+                    Ok(stream) = punch(local, peer_external_addr, true) => Ok(stream),
+                    stream = accept_from(listener, peer_external_addr) => stream,
+                },
+                None => punch(local, peer_external_addr, false).await,
+            }
+        };
+        let Ok(stream) = tokio::time::timeout(DIRECT_UPGRADE_TIMEOUT, connect).await else {
+            return Ok(false);
+        };
+        drop(listener);
+        let mut stream = stream?;
+        
+        // A peer that gave up at the deadline as we connected would still be on the relay
+        let hello = async {
+            stream.write_all(DIRECT_HELLO).await?;
+            let mut theirs = [0u8; DIRECT_HELLO.len()];
+            stream.read_exact(&mut theirs).await?;
+            io::Result::Ok(&theirs == DIRECT_HELLO)
+        };
+        if !matches!(tokio::time::timeout(DIRECT_UPGRADE_TIMEOUT, hello).await, Ok(Ok(true))) {
+            return Ok(false);
+        }
+        
+        let via_relay_url = relay_conn.relay_url().to_string();
+        let conn = Connection::new(stream, peer_external_addr, local_port);
+        *self = Transport::Upgraded { conn, via_relay_url };
+        Ok(true)
+    }
+    
     /// Send data
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
         match self {
-            Transport::Direct(conn) => conn.send(data).await,
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => conn.send(data).await,
             Transport::Relay(conn) => conn.send(data).await,
+            Transport::RelayRoom(room) => room.send(data).await,
+            Transport::Memory(pipe) => network::write_message(&mut pipe.stream, data).await,
         }
     }
     
     /// Receive data
+    ///
+    /// Safe to cancel on every transport: part of a message that has arrived
+    /// is kept for the next call.
     pub async fn receive(&mut self) -> Result<Vec<u8>> {
         match self {
-            Transport::Direct(conn) => conn.receive().await,
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => conn.receive().await,
             Transport::Relay(conn) => conn.receive().await,
+            Transport::RelayRoom(room) => room.receive().await,
+            Transport::Memory(pipe) => pipe.reader.read(&mut pipe.stream).await,
         }
     }
     
-    /// Get peer address (only available for direct connections)
-    pub fn peer_addr(&self) -> Option<SocketAddr> {
+    /// Receive data the peer may be a long time starting to send, see `Connection::wait_for_message`
+    pub async fn wait_for_message(&mut self) -> Result<Vec<u8>> {
         match self {
-            Transport::Direct(conn) => Some(conn.peer_addr()),
-            Transport::Relay(_) => None,
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => conn.wait_for_message().await,
+            _ => self.receive().await,
+        }
+    }
+    
+    /// A message that has already arrived in full, without waiting for one
+    ///
+    /// Whatever has come in of the next message stays buffered for the next
+    /// call or `receive`, so asking early never costs a message.
+    pub fn try_receive(&mut self) -> Result<Option<Vec<u8>>> {
+        self.receive().now_or_never().transpose()
+    }
+    
+    /// Whether data goes through a relay rather than straight to the peer
+    pub fn is_relayed(&self) -> bool {
+        matches!(self, Transport::Relay(_) | Transport::RelayRoom(_))
+    }
+    
+    /// Wait for the relay to commit a mailbox upload
+    pub async fn finish_store(&mut self) -> Result<()> {
+        match self {
+            Transport::Relay(conn) => conn.finish_store().await,
+            _ => Err(anyhow::anyhow!("Only relay connections can store uploads")),
+        }
+    }
+    
+    /// Describe how this transport reaches the peer
+    pub fn peer_info(&self) -> PeerInfo {
+        match self {
+            Transport::Direct(conn) => PeerInfo::Direct { addr: conn.peer_addr() },
+            Transport::Upgraded { conn, via_relay_url } => PeerInfo::Upgraded {
+                addr: conn.peer_addr(),
+                via_relay_url: via_relay_url.clone(),
+            },
+            Transport::Relay(conn) => PeerInfo::Relay {
+                relay_url: conn.relay_url().to_string(),
+            },
+            Transport::RelayRoom(room) => PeerInfo::Relay {
+                relay_url: room.relay_url().to_string(),
+            },
+            Transport::Memory(_) => PeerInfo::Memory,
+        }
+    }
+}
+
+/// Connect from `local` to `peer` until it answers, retrying while it refuses
+///
+/// Refusals are expected until the peer's own attempt towards us is under way.
+/// With `beside_listener`, our own listener is on `local` as well.
+async fn punch(local: SocketAddr, peer: SocketAddr, beside_listener: bool) -> io::Result<TcpStream> {
+    loop {
+        let socket = if beside_listener { network::shared_port_socket(&peer)? } else { network::reusable_socket(&peer)? };
+        socket.bind(local)?;
+        match socket.connect(peer).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => tokio::time::sleep(PUNCH_RETRY_DELAY).await,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Wait for `peer` to connect, hanging up on anyone else
+async fn accept_from(listener: &TcpListener, peer: SocketAddr) -> io::Result<TcpStream> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if addr.ip() == peer.ip() {
+            return Ok(stream);
         }
     }
 }
+
+/// Raw byte stream to the peer, whichever way the transport reaches it
+///
+/// Over a relay the bytes travel as binary frames; see `RelayConnection`.
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => Pin::new(conn).poll_read(cx, buf),
+            Transport::Relay(conn) => Pin::new(conn.as_mut()).poll_read(cx, buf),
+            Transport::RelayRoom(room) => Pin::new(room).poll_read(cx, buf),
+            Transport::Memory(pipe) => {
+                if pipe.reader.partway() {
+                    return Poll::Ready(Err(io::Error::other(network::MESSAGE_PARTWAY)));
+                }
+                Pin::new(&mut pipe.stream).poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => Pin::new(conn).poll_write(cx, buf),
+            Transport::Relay(conn) => Pin::new(conn.as_mut()).poll_write(cx, buf),
+            Transport::RelayRoom(room) => Pin::new(room).poll_write(cx, buf),
+            Transport::Memory(pipe) => Pin::new(&mut pipe.stream).poll_write(cx, buf),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => Pin::new(conn).poll_flush(cx),
+            Transport::Relay(conn) => Pin::new(conn.as_mut()).poll_flush(cx),
+            Transport::RelayRoom(room) => Pin::new(room).poll_flush(cx),
+            Transport::Memory(pipe) => Pin::new(&mut pipe.stream).poll_flush(cx),
+        }
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Direct(conn) | Transport::Upgraded { conn, .. } => Pin::new(conn).poll_shutdown(cx),
+            Transport::Relay(conn) => Pin::new(conn.as_mut()).poll_shutdown(cx),
+            Transport::RelayRoom(room) => Pin::new(room).poll_shutdown(cx),
+            Transport::Memory(pipe) => Pin::new(&mut pipe.stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{serve, RelayConfig, RelaySession, RelayState, MAX_RELAY_FRAME_SIZE};
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    
+    async fn start_relay() -> RelayUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = RelayUrl::from(listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, RelayConfig::default(), Arc::new(RelayState::default())));
+        relay
+    }
+    
+    #[tokio::test]
+    async fn test_direct_peer_info() {
+        let listener = tokio::spawn(Transport::new_sender(None, "alpha-bravo-charlie", Some(19106), MAX_RELAY_FRAME_SIZE, false, || {}));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        
+        let receiver = Transport::new_receiver(None, "alpha-bravo-charlie", Some(&"127.0.0.1".parse().unwrap()), Some(19106), true, MAX_RELAY_FRAME_SIZE, false, || {})
+            .await
+            .unwrap();
+        let sender = listener.await.unwrap().unwrap();
+        
+        assert_eq!(receiver.peer_info(), PeerInfo::Direct { addr: "127.0.0.1:19106".parse().unwrap() });
+        assert!(matches!(sender.peer_info(), PeerInfo::Direct { addr } if addr.ip().is_loopback()));
+    }
+    
+    #[tokio::test]
+    async fn test_relay_peer_info() {
+        let relay = start_relay().await;
+        let (sender, receiver) = tokio::join!(
+            Transport::new_sender(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, MAX_RELAY_FRAME_SIZE, false, || {}),
+            Transport::new_receiver(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, None, true, MAX_RELAY_FRAME_SIZE, false, || {}),
+        );
+        
+        let expected = PeerInfo::Relay { relay_url: relay.to_string() };
+        assert_eq!(sender.unwrap().peer_info(), expected);
+        assert_eq!(receiver.unwrap().peer_info(), expected);
+        assert_eq!(expected.addr(), None);
+    }
+    
+    #[tokio::test]
+    async fn test_relay_room_peer_info() {
+        let relay = start_relay().await;
+        let session = RelaySession::connect(&relay, MAX_RELAY_FRAME_SIZE).await.unwrap();
+        let (sender, receiver) = tokio::join!(
+            session.open("alpha-bravo-charlie", Role::Sender),
+            session.open("alpha-bravo-charlie", Role::Receiver),
+        );
+        drop(receiver);
+        
+        let transport = Transport::RelayRoom(sender.unwrap());
+        assert_eq!(transport.peer_info(), PeerInfo::Relay { relay_url: relay.to_string() });
+    }
+    
+    async fn check_byte_stream(mut sender: Transport, mut receiver: Transport) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        sender.write_all(&data).await.unwrap();
+        sender.flush().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        receiver.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        
+        // Raw bytes carrying a length prefix read back as a message
+        sender.write_all(&[0, 0, 0, 5]).await.unwrap();
+        sender.write_all(b"hello").await.unwrap();
+        sender.flush().await.unwrap();
+        assert_eq!(receiver.receive().await.unwrap(), b"hello");
+    }
+    
+    #[tokio::test]
+    async fn test_relay_byte_stream() {
+        // Small frames so every write is split
+        let relay = start_relay().await;
+        let (sender, receiver) = tokio::join!(
+            Transport::new_sender(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, 1024, false, || {}),
+            Transport::new_receiver(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, None, true, 1024, false, || {}),
+        );
+        check_byte_stream(sender.unwrap(), receiver.unwrap()).await;
+        
+        let session = RelaySession::connect(&relay, 1024).await.unwrap();
+        let (sender, receiver) = tokio::join!(
+            session.open("delta-echo-foxtrot", Role::Sender),
+            session.open("delta-echo-foxtrot", Role::Receiver),
+        );
+        check_byte_stream(Transport::RelayRoom(sender.unwrap()), Transport::RelayRoom(receiver.unwrap())).await;
+    }
+    
+    #[tokio::test]
+    async fn test_upgrade_direct_over_loopback() {
+        let relay = start_relay().await;
+        let (sender, receiver) = tokio::join!(
+            Transport::new_sender(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, MAX_RELAY_FRAME_SIZE, true, || {}),
+            Transport::new_receiver(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, None, true, MAX_RELAY_FRAME_SIZE, true, || {}),
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        
+        let (sender_direct, receiver_direct) = tokio::join!(sender.try_direct(), receiver.try_direct());
+        assert!(sender_direct && receiver_direct);
+        for transport in [&sender, &receiver] {
+            assert!(matches!(
+                transport.peer_info(),
+                PeerInfo::Upgraded { addr, ref via_relay_url } if addr.ip().is_loopback() && *via_relay_url == relay.to_string()
+            ));
+        }
+        check_byte_stream(sender, receiver).await;
+    }
+    
+    #[tokio::test]
+    async fn test_upgrade_direct_falls_back_to_relay() {
+        // Only one side asked, so the relay gives out neither address
+        let relay = start_relay().await;
+        let (sender, receiver) = tokio::join!(
+            Transport::new_sender(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, MAX_RELAY_FRAME_SIZE, true, || {}),
+            Transport::new_receiver(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, None, true, MAX_RELAY_FRAME_SIZE, false, || {}),
+        );
+        let (mut sender, receiver) = (sender.unwrap(), receiver.unwrap());
+        assert!(!sender.try_direct().await);
+        
+        // Nothing ever answers on a port that was just closed. Ours is the one the
+        // relay connection was given, as it would be for a real attempt.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let Transport::Relay(relay_conn) = &sender else {
+            unreachable!("a sender given a relay and no port connects through it")
+        };
+        let local_port = relay_conn.local_addr().unwrap().port();
+        let started = Instant::now();
+        assert!(!sender.upgrade_direct(closed, local_port).await.unwrap());
+        assert!(started.elapsed() >= DIRECT_UPGRADE_TIMEOUT);
+        assert_eq!(sender.peer_info(), PeerInfo::Relay { relay_url: relay.to_string() });
+        check_byte_stream(sender, receiver).await;
+    }
+    
+    #[tokio::test]
+    async fn test_try_receive_keeps_a_message_that_has_partly_arrived() {
+        let listener = network::bind(Some(0)).await.unwrap();
+        let host = "127.0.0.1".parse().unwrap();
+        let (accepted, connected) = tokio::join!(network::accept(&listener), network::connect(&host, Some(listener.local_addr().unwrap().port()), true));
+        let relay = start_relay().await;
+        let (relay_sender, relay_receiver) = tokio::join!(
+            Transport::new_sender(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, MAX_RELAY_FRAME_SIZE, false, || {}),
+            Transport::new_receiver(Some(vec![relay.clone()]), "alpha-bravo-charlie", None, None, true, MAX_RELAY_FRAME_SIZE, false, || {}),
+        );
+        let (memory_sender, memory_receiver) = Transport::memory_pair();
+        let pairs = [
+            (Transport::Direct(accepted.unwrap()), Transport::Direct(connected.unwrap())),
+            (relay_sender.unwrap(), relay_receiver.unwrap()),
+            (memory_sender, memory_receiver),
+        ];
+        
+        for (mut peer, mut conn) in pairs {
+            assert!(conn.try_receive().unwrap().is_none());
+            // A Cancel on its way, its frame split across writes
+            peer.write_all(&[0, 0, 0, 6, b'c', b'a']).await.unwrap();
+            peer.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(conn.try_receive().unwrap().is_none());
+            peer.write_all(b"ncel").await.unwrap();
+            peer.flush().await.unwrap();
+            let received = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some(message) = conn.try_receive().unwrap() {
+                        break message;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            });
+            assert_eq!(received.await.unwrap(), b"cancel");
+        }
+    }
+    
+    #[test]
+    fn test_peer_info_display() {
+        let addr: SocketAddr = "192.168.1.20:9999".parse().unwrap();
+        let upgraded = PeerInfo::Upgraded {
+            addr,
+            via_relay_url: "wss://relay.example".to_string(),
+        };
+        assert_eq!(upgraded.addr(), Some(addr));
+        assert_eq!(upgraded.to_string(), "192.168.1.20:9999 (via relay wss://relay.example)");
+        assert_eq!(PeerInfo::Direct { addr }.to_string(), "192.168.1.20:9999");
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// What the console we print to can display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// How much ANSI the console takes
+    pub ansi: AnsiMode,
+    /// Characters outside ASCII, including emoji, render properly
+    pub unicode: bool,
+}
+
+impl Capabilities {
+    /// Probe stdout's console
+    pub fn detect() -> Self {
+        Self {
+            ansi: AnsiMode::detect(),
+            unicode: unicode_supported(|name| std::env::var(name).ok()),
+        }
+    }
+}
+
+/// Which ANSI escape sequences we may print
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiMode {
+    /// Colours and cursor control
+    Full,
+    /// No colour, as `NO_COLOR` asks (https://no-color.org)
+    ///
+    /// Progress lines go out as plain text; the full-screen UI still needs
+    /// cursor control, so it draws in the terminal's own colours.
+    NoColor,
+    /// None at all: not a terminal, or one that would print them literally
+    None,
+}
+
+impl AnsiMode {
+    /// Decide from `NO_COLOR`, `TERM` and whether stdout is a terminal
+    ///
+    /// On Windows this also turns on VT processing where the console allows it.
+    pub fn detect() -> Self {
+        Self::from_env(|name| std::env::var(name).ok(), || std::io::stdout().is_terminal() && console_takes_ansi())
+    }
+    
+    fn from_env(var: impl Fn(&str) -> Option<String>, terminal: impl FnOnce() -> bool) -> Self {
+        if var("TERM").is_some_and(|term| term == "dumb") || !terminal() {
+            Self::None
+        } else if var("NO_COLOR").is_some() {
+            // Set to anything, even nothing
+            Self::NoColor
+        } else {
+            Self::Full
+        }
+    }
+}
+
+#[cfg(windows)]
+fn console_takes_ansi() -> bool {
+    crossterm::ansi_support::supports_ansi()
+}
+
+#[cfg(not(windows))]
+fn console_takes_ansi() -> bool {
+    true
+}
+
+/// Guess from the environment whether the console renders Unicode
+#[cfg(windows)]
+fn unicode_supported(var: impl Fn(&str) -> Option<String>) -> bool {
+    // The legacy console only manages it with a UTF-8 code page, which we can't see
+    // from here; the terminals that replaced it announce themselves
+    var("WT_SESSION").is_some()
+        || var("TERM_PROGRAM").is_some_and(|program| program == "vscode")
+        || var("ConEmuTask").is_some()
+        || var("TERM").is_some_and(|term| term.starts_with("xterm") || term == "alacritty")
+}
+
+/// Guess from the environment whether the console renders Unicode
+#[cfg(not(windows))]
+fn unicode_supported(var: impl Fn(&str) -> Option<String>) -> bool {
+    if var("TERM").is_some_and(|term| term == "linux" || term == "dumb") {
+        return false;
+    }
+    // The first locale variable that's set decides, as it does for the C library
+    match ["LC_ALL", "LC_CTYPE", "LANG"].into_iter().find_map(|name| var(name).filter(|value| !value.is_empty())) {
+        Some(locale) => {
+            let locale = locale.to_ascii_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        }
+        None => true,
+    }
+}
+
+/// The symbols used in status output
+#[derive(Debug, PartialEq, Eq)]
+pub struct Glyphs {
+    pub bolt: &'static str,
+    pub check: &'static str,
+    pub warning: &'static str,
+    pub locked: &'static str,
+    pub unlocked: &'static str,
+    pub direct: &'static str,
+    pub relay: &'static str,
+    /// Underline for banners
+    pub rule: &'static str,
+    /// Sets off the second half of a sentence
+    pub dash: &'static str,
+}
+
+pub const UNICODE: Glyphs = Glyphs {
+    bolt: "⚡",
+    check: "✓",
+    warning: "⚠",
+    locked: "🔒",
+    unlocked: "🔓",
+    direct: "🖧",
+    relay: "🌐",
+    rule: "═══════════════════════════════════════",
+    dash: "—",
+};
+
+pub const ASCII: Glyphs = Glyphs {
+    bolt: ">>",
+    check: "[ok]",
+    warning: "[!]",
+    locked: "[E2EE]",
+    unlocked: "[plain]",
+    direct: "[lan]",
+    relay: "[relay]",
+    rule: "=======================================",
+    dash: "-",
+};
+
+impl Glyphs {
+    pub fn for_capabilities(capabilities: Capabilities) -> &'static Glyphs {
+        if capabilities.unicode {
+            &UNICODE
+        } else {
+            &ASCII
+        }
+    }
+}
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+/// This console's capabilities, probed once
+pub fn capabilities() -> Capabilities {
+    *CAPABILITIES.get_or_init(Capabilities::detect)
+}
+
+/// Symbols this console can display
+pub fn glyphs() -> &'static Glyphs {
+    Glyphs::for_capabilities(capabilities())
+}
+
+/// Bold green text, for transfer codes and ports
+pub fn highlight(text: impl Display) -> String {
+    paint(capabilities(), "1;32", text)
+}
+
+/// Bold yellow text, for warnings
+pub fn caution(text: impl Display) -> String {
+    paint(capabilities(), "1;33", text)
+}
+
+fn paint(capabilities: Capabilities, style: &str, text: impl Display) -> String {
+    if capabilities.ansi == AnsiMode::Full {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_fallbacks_follow_capabilities() {
+        let plain = Capabilities { ansi: AnsiMode::None, unicode: false };
+        assert_eq!(Glyphs::for_capabilities(plain), &ASCII);
+        assert_eq!(paint(plain, "1;32", "alpha-bravo"), "alpha-bravo");
+        assert!(ASCII.bolt.is_ascii() && ASCII.check.is_ascii() && ASCII.locked.is_ascii() && ASCII.rule.is_ascii() && ASCII.dash.is_ascii());
+        
+        let modern = Capabilities { ansi: AnsiMode::Full, unicode: true };
+        assert_eq!(Glyphs::for_capabilities(modern), &UNICODE);
+        assert_eq!(paint(modern, "1;32", "alpha-bravo"), "\x1b[1;32malpha-bravo\x1b[0m");
+        
+        // Colours and symbols are chosen independently
+        let colour_only = Capabilities { ansi: AnsiMode::Full, unicode: false };
+        assert_eq!(Glyphs::for_capabilities(colour_only), &ASCII);
+        assert!(paint(colour_only, "1;33", "careful").starts_with('\x1b'));
+        
+        let no_colour = Capabilities { ansi: AnsiMode::NoColor, unicode: true };
+        assert_eq!(paint(no_colour, "1;33", "careful"), "careful");
+    }
+    
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name: &str| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+    }
+    
+    #[test]
+    fn test_ansi_mode_from_env() {
+        let terminal = || true;
+        assert_eq!(AnsiMode::from_env(env(&[("TERM", "xterm-256color")]), terminal), AnsiMode::Full);
+        assert_eq!(AnsiMode::from_env(env(&[("TERM", "xterm-256color"), ("NO_COLOR", "1")]), terminal), AnsiMode::NoColor);
+        assert_eq!(AnsiMode::from_env(env(&[("NO_COLOR", "")]), terminal), AnsiMode::NoColor);
+        assert_eq!(AnsiMode::from_env(env(&[("TERM", "dumb")]), terminal), AnsiMode::None);
+        assert_eq!(AnsiMode::from_env(env(&[]), || false), AnsiMode::None);
+        assert_eq!(AnsiMode::from_env(env(&[("NO_COLOR", "1")]), || false), AnsiMode::None);
+    }
+    
+    #[cfg(not(windows))]
+    #[test]
+    fn test_unicode_from_locale() {
+        assert!(unicode_supported(env(&[("LANG", "en_GB.UTF-8")])));
+        assert!(unicode_supported(env(&[("LC_ALL", "C.utf8"), ("LANG", "C")])));
+        assert!(!unicode_supported(env(&[("LC_ALL", "C"), ("LANG", "en_GB.UTF-8")])));
+        assert!(!unicode_supported(env(&[("TERM", "linux"), ("LANG", "en_GB.UTF-8")])));
+        assert!(unicode_supported(env(&[])));
+    }
+}
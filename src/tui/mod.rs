@@ -1,25 +1,63 @@
-use anyhow::Result;
+pub mod glyphs;
+pub mod prompt;
+
+pub use glyphs::AnsiMode;
+
+use anyhow::{anyhow, Result};
 use crossterm::{
+    cursor::MoveToColumn,
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    execute, queue,
+    style::Print,
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
-    backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Direction, Layout, Rect},
+    backend::CrosstermBackend,
+    buffer::Buffer,
+    layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{Block, Borders, Gauge, Paragraph},
     Frame, Terminal,
 };
-use std::io;
+use std::io::{self, Write};
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::protocol::{Session, FEATURE_KEM_ML_KEM_768};
+use crate::session::TransferEvent;
+use crate::transport::{PeerInfo, Transport};
+use glyphs::Glyphs;
+
+/// How often `HeadlessUI` logs progress
+pub const HEADLESS_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct TransferUI {
     terminal: Terminal<CrosstermBackend<io::Stdout>>,
     should_quit: bool,
+    /// Fired when the user quits, to call the transfer off
+    cancel: Option<CancellationToken>,
+    /// Draw in colour, unless `NO_COLOR` is set
+    colour: bool,
+}
+
+/// How we're connected to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionType {
+    Direct,
+    Relay,
+}
+
+impl ConnectionType {
+    fn icon(&self, glyphs: &Glyphs) -> &'static str {
+        match self {
+            ConnectionType::Direct => glyphs.direct,
+            ConnectionType::Relay => glyphs.relay,
+        }
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct TransferState {
     pub code: String,
     pub filename: String,
@@ -28,11 +66,126 @@ pub struct TransferState {
     pub speed: f64, // bytes per second
     pub encrypted: bool,
     pub status: String,
+    /// Peer address, or "via relay" when the relay hides it
+    pub peer_display: String,
+    pub connection_type: ConnectionType,
+    /// Latest round-trip time estimate
+    pub rtt_ms: Option<f64>,
+}
+
+impl TransferState {
+    /// State before anything has happened
+    pub fn new(code: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            filename: String::new(),
+            total_size: 0,
+            transferred: 0,
+            speed: 0.0,
+            encrypted: true,
+            status: "Connecting".to_string(),
+            peer_display: String::new(),
+            connection_type: ConnectionType::Direct,
+            rtt_ms: None,
+        }
+    }
+    
+    /// Update from a transfer event
+    pub fn apply(&mut self, event: &TransferEvent) {
+        match event {
+            TransferEvent::Listening { port } => self.status = format!("Waiting for receiver on port {}", port),
+            TransferEvent::PortBusy { port } => self.status = format!("Waiting for port {} to clear", port),
+            TransferEvent::Reannounced { at } => {
+                let at = chrono::DateTime::<chrono::Local>::from(*at);
+                self.status = format!("Re-announced on the relay at {}", at.format("%H:%M"));
+            }
+            TransferEvent::Rejected { addr } => self.status = format!("Turned away {}; still waiting for receiver", addr.ip()),
+            TransferEvent::Hint { hint } => self.status = hint.summary.clone(),
+            TransferEvent::Connected { peer } => {
+                self.peer_display = peer.to_string();
+                self.status = "Connected".to_string();
+            }
+            TransferEvent::Handshake { session } => {
+                self.status = post_quantum_fallback(session).map_or_else(|| handshake_status(session), String::from);
+            }
+            TransferEvent::Metadata { filename, size } => {
+                self.filename = filename.clone();
+                self.total_size = *size;
+            }
+            TransferEvent::Scheduled { starts_in } => self.status = scheduled_status(*starts_in),
+            TransferEvent::Preparing { detail } => self.status = preparing_status(detail),
+            TransferEvent::Archiving { files_done, total_files } => {
+                self.status = format!("Archiving: {}/{} files", files_done, total_files);
+            }
+            TransferEvent::Hashing { files_done, total_files } => {
+                self.status = format!("Hashing: {}/{} files", files_done, total_files);
+            }
+            TransferEvent::Progress { filename, transferred, total, speed } => {
+                self.filename = filename.clone();
+                self.transferred = *transferred;
+                self.total_size = *total;
+                self.speed = *speed;
+                self.status = "Transferring".to_string();
+            }
+            TransferEvent::PathLimited { chunk_size, .. } => {
+                self.status = format!("Large chunks aren't getting through, sending {} KB ones", chunk_size / 1024);
+            }
+            TransferEvent::Stored { .. } => self.status = "Stored on the relay".to_string(),
+            TransferEvent::Deferred => self.status = "Someone looked at the offer; still waiting for receiver".to_string(),
+            TransferEvent::Complete => self.status = "Transfer complete".to_string(),
+            TransferEvent::ReceiverCancelled { transferred, total, .. } => {
+                self.status = format!("Receiver cancelled at {}", cancelled_at(*transferred, *total));
+            }
+            TransferEvent::Resuming { chunk } => self.status = format!("Resuming from chunk {}", chunk),
+            TransferEvent::Synced { sent, unchanged, .. } => {
+                self.status = format!("Synced {} files ({} already up to date)", sent, unchanged);
+            }
+            TransferEvent::Delta { reused, total } => self.status = format!("Sent only the changes ({} of {} bytes reused)", reused, total),
+            TransferEvent::Receipt { saved_to: Some(_), .. } => self.status = "Receipt saved".to_string(),
+            TransferEvent::Receipt { saved_to: None, .. } => self.status = "Receipt sent".to_string(),
+            TransferEvent::ChunkSize { .. }
+            | TransferEvent::PeerLimited { .. }
+            | TransferEvent::Conflicts { .. }
+            | TransferEvent::MetadataWarnings { .. }
+            | TransferEvent::Skipped { .. }
+            | TransferEvent::Tightened { .. }
+            | TransferEvent::Memory { .. }
+            | TransferEvent::TlsVerified { .. }
+            | TransferEvent::Timings { .. } => {}
+        }
+    }
+    
+    /// Whether the transfer is over, successfully or not
+    pub fn is_finished(&self) -> bool {
+        self.status.contains("complete") || self.status.contains("error") || self.status.contains("cancelled")
+    }
+    
+    /// Fill in the peer details from the transport carrying the transfer
+    pub fn set_transport(&mut self, transport: &Transport) {
+        match transport.peer_info() {
+            PeerInfo::Direct { addr } | PeerInfo::Upgraded { addr, .. } => {
+                self.peer_display = addr.to_string();
+                self.connection_type = ConnectionType::Direct;
+            }
+            PeerInfo::Relay { .. } => {
+                self.peer_display = "via relay".to_string();
+                self.connection_type = ConnectionType::Relay;
+            }
+            PeerInfo::Memory => {
+                self.peer_display = "in memory".to_string();
+                self.connection_type = ConnectionType::Direct;
+            }
+        }
+    }
 }
 
 impl TransferUI {
-    /// Initialize the TUI
+    /// Initialize the TUI, unless the terminal can't take escape sequences
     pub fn new() -> Result<Self> {
+        let ansi = glyphs::capabilities().ansi;
+        if ansi == AnsiMode::None {
+            return Err(anyhow!("This terminal can't show the full-screen UI"));
+        }
         enable_raw_mode()?;
         let mut stdout = io::stdout();
         execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
@@ -42,9 +195,16 @@ impl TransferUI {
         Ok(Self {
             terminal,
             should_quit: false,
+            cancel: None,
+            colour: ansi == AnsiMode::Full,
         })
     }
     
+    /// Cancel the transfer through `cancel` when the user quits with `q`
+    pub fn cancel_on_quit(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
+    }
+    
     /// Run the TUI with the given transfer state
     pub fn run<F>(&mut self, mut get_state: F) -> Result<()>
     where
@@ -52,9 +212,15 @@ impl TransferUI {
     {
         loop {
             let state = get_state();
-            self.terminal.draw(|f| Self::render_ui(f, &state))?;
+            let colour = self.colour;
+            self.terminal.draw(|f| {
+                Self::render_ui(f, &state, glyphs::glyphs());
+                if !colour {
+                    strip_colour(f.buffer_mut());
+                }
+            })?;
             
-            if self.should_quit || state.status.contains("complete") || state.status.contains("error") {
+            if self.should_quit || state.is_finished() {
                 break;
             }
             
@@ -63,6 +229,9 @@ impl TransferUI {
                 if let Event::Key(key) = event::read()? {
                     if key.code == KeyCode::Char('q') {
                         self.should_quit = true;
+                        if let Some(cancel) = &self.cancel {
+                            cancel.cancel();
+                        }
                         break;
                     }
                 }
@@ -73,7 +242,7 @@ impl TransferUI {
     }
     
     /// Render the UI
-    fn render_ui(f: &mut Frame, state: &TransferState) {
+    fn render_ui(f: &mut Frame, state: &TransferState, glyphs: &Glyphs) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .margin(2)
@@ -82,6 +251,8 @@ impl TransferUI {
                 Constraint::Length(3),
                 Constraint::Length(3),
                 Constraint::Length(5),
+                Constraint::Length(3),
+                Constraint::Length(3),
                 Constraint::Min(0),
             ])
             .split(f.area());
@@ -89,7 +260,7 @@ impl TransferUI {
         // Title
         let title = Paragraph::new(vec![
             Line::from(vec![
-                Span::styled("⚡ ", Style::default().fg(Color::Yellow)),
+                Span::styled(format!("{} ", glyphs.bolt), Style::default().fg(Color::Yellow)),
                 Span::styled("Zap Transfer", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)),
             ]),
         ])
@@ -131,12 +302,30 @@ impl TransferUI {
         f.render_widget(gauge, chunks[3]);
         
         // Status
-        let encryption_icon = if state.encrypted { "🔒" } else { "🔓" };
+        let encryption_icon = if state.encrypted { glyphs.locked } else { glyphs.unlocked };
         let status_text = format!("{} {}", encryption_icon, state.status);
         let status = Paragraph::new(status_text)
             .style(Style::default().fg(Color::Yellow))
             .block(Block::default().borders(Borders::ALL).title("Status"));
         f.render_widget(status, chunks[4]);
+        
+        // Peer
+        let rtt = match state.rtt_ms {
+            Some(rtt_ms) => format!("{:.1} ms", rtt_ms),
+            None => "--".to_string(),
+        };
+        let encryption = if state.encrypted {
+            format!("{} encrypted", glyphs.locked)
+        } else {
+            format!("{} not encrypted", glyphs.unlocked)
+        };
+        let peer_text = format!(
+            "{} {} | RTT {} | {}",
+            state.connection_type.icon(glyphs), state.peer_display, rtt, encryption
+        );
+        let peer = Paragraph::new(peer_text)
+            .block(Block::default().borders(Borders::ALL).title("Peer"));
+        f.render_widget(peer, chunks[5]);
     }
     
     /// Clean up the TUI
@@ -152,14 +341,128 @@ impl TransferUI {
     }
 }
 
+/// Put every cell back in the terminal's own colours
+fn strip_colour(buffer: &mut Buffer) {
+    for cell in &mut buffer.content {
+        cell.set_fg(Color::Reset).set_bg(Color::Reset);
+    }
+}
+
 impl Drop for TransferUI {
     fn drop(&mut self) {
         let _ = self.cleanup();
     }
 }
 
-/// Simple progress bar for non-TUI mode
-pub fn print_progress(filename: &str, transferred: u64, total: u64, speed: f64) {
+/// A `TransferUI`, or a `HeadlessUI` standing in for it, driven the same way
+pub struct TransferUIHandle(UIImpl);
+
+enum UIImpl {
+    Tui(Box<TransferUI>),
+    Headless(HeadlessUI),
+}
+
+impl TransferUI {
+    /// A UI that logs progress instead of taking over the terminal
+    pub fn new_headless() -> TransferUIHandle {
+        TransferUIHandle(UIImpl::Headless(HeadlessUI::new()))
+    }
+}
+
+impl TransferUIHandle {
+    /// The full-screen UI on a terminal that can show it, unless `no_tui`; the headless one otherwise
+    pub fn open(no_tui: bool) -> Result<Self> {
+        if no_tui || glyphs::capabilities().ansi == AnsiMode::None {
+            return Ok(TransferUI::new_headless());
+        }
+        Ok(Self(UIImpl::Tui(Box::new(TransferUI::new()?))))
+    }
+    
+    /// Whether this handle only logs
+    pub fn is_headless(&self) -> bool {
+        matches!(self.0, UIImpl::Headless(_))
+    }
+    
+    /// Cancel the transfer through `cancel` when the user quits the full-screen UI; logging can't be quit
+    pub fn cancel_on_quit(&mut self, cancel: CancellationToken) {
+        if let UIImpl::Tui(ui) = &mut self.0 {
+            ui.cancel_on_quit(cancel);
+        }
+    }
+    
+    /// Show the transfer until it finishes, as `TransferUI::run` does
+    pub fn run<F>(&mut self, get_state: F) -> Result<()>
+    where
+        F: FnMut() -> TransferState,
+    {
+        match &mut self.0 {
+            UIImpl::Tui(ui) => ui.run(get_state),
+            UIImpl::Headless(ui) => ui.run(get_state),
+        }
+    }
+}
+
+/// Progress reporter for processes without a terminal, such as daemons
+///
+/// It polls the state once per interval and writes a progress line to its
+/// log, stderr unless told otherwise.
+pub struct HeadlessUI<W = io::Stderr> {
+    log: W,
+    interval: Duration,
+}
+
+impl HeadlessUI {
+    pub fn new() -> Self {
+        Self::with_log(io::stderr())
+    }
+}
+
+impl Default for HeadlessUI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> HeadlessUI<W> {
+    /// Log to `log` instead of stderr
+    pub fn with_log(log: W) -> Self {
+        Self {
+            log,
+            interval: HEADLESS_INTERVAL,
+        }
+    }
+    
+    /// Log every `interval` instead of every second
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    
+    /// Log progress until the transfer finishes
+    pub fn run<F>(&mut self, mut get_state: F) -> Result<()>
+    where
+        F: FnMut() -> TransferState,
+    {
+        loop {
+            let state = get_state();
+            let line = progress_line(&state.filename, state.transferred, state.total_size, state.speed);
+            writeln!(self.log, "{} - {}", line, state.status)?;
+            self.log.flush()?;
+            
+            if state.is_finished() {
+                return Ok(());
+            }
+            std::thread::sleep(self.interval);
+        }
+    }
+    
+    /// Get the log back
+    pub fn into_log(self) -> W {
+        self.log
+    }
+}
+
+fn progress_line(filename: &str, transferred: u64, total: u64, speed: f64) -> String {
     let progress = if total > 0 {
         (transferred as f64 / total as f64 * 100.0).min(100.0)
     } else {
@@ -170,11 +473,204 @@ pub fn print_progress(filename: &str, transferred: u64, total: u64, speed: f64)
     let transferred_mb = transferred as f64 / 1_048_576.0;
     let total_mb = total as f64 / 1_048_576.0;
     
-    print!(
-        "\r{}: {:.1}% ({:.2}/{:.2} MB) @ {:.2} MB/s   ",
+    format!(
+        "{}: {:.1}% ({:.2}/{:.2} MB) @ {:.2} MB/s",
         filename, progress, transferred_mb, total_mb, speed_mbps
-    );
+    )
+}
+
+/// Simple progress bar for non-TUI mode
+pub fn print_progress(filename: &str, transferred: u64, total: u64, speed: f64) {
+    rewrite_line(&progress_line(filename, transferred, total, speed));
+}
+
+/// Countdown line for non-TUI mode, shown while a scheduled send holds off
+pub fn print_scheduled(starts_in: Option<Duration>) {
+    rewrite_line(&scheduled_status(starts_in));
+}
+
+/// "Handshake complete", saying so when the session key has a post-quantum secret mixed in
+pub fn handshake_status(session: &Session) -> String {
+    if session.supports(FEATURE_KEM_ML_KEM_768) {
+        "Handshake complete (post-quantum hybrid)".to_string()
+    } else {
+        "Handshake complete".to_string()
+    }
+}
+
+/// What `--verbose` says once `--direct-tls` has checked the peer's certificate, with both SHA-256 fingerprints
+pub fn tls_status(ours: &[u8; 32], peer: &[u8; 32]) -> String {
+    format!("TLS certificate checked against the code (ours {}, peer's {})", hex::encode(ours), hex::encode(peer))
+}
+
+/// A warning for when `--pq` offered the post-quantum key exchange and the other side couldn't take it
+pub fn post_quantum_fallback(session: &Session) -> Option<&'static str> {
+    (session.offered().0.contains(FEATURE_KEM_ML_KEM_768) && !session.supports(FEATURE_KEM_ML_KEM_768))
+        .then_some("The other side can't do a post-quantum key exchange, so the key rests on the transfer code alone")
+}
+
+/// What a scheduled send is waiting for
+fn scheduled_status(starts_in: Option<Duration>) -> String {
+    match starts_in {
+        Some(left) => format!("Starting in {}", humantime::format_duration(Duration::from_secs(left.as_secs()))),
+        None => "Waiting for the network to go quiet".to_string(),
+    }
+}
+
+/// Line for non-TUI mode, shown while the sender reads through what it's about to offer
+pub fn print_preparing(detail: &str) {
+    rewrite_line(&preparing_status(detail));
+}
+
+/// What the sender says it's doing before the offer
+fn preparing_status(detail: &str) -> String {
+    format!("Sender is preparing: {}", detail)
+}
+
+/// How far a transfer got when it was cancelled: a percentage, or bytes when the size isn't known
+pub fn cancelled_at(transferred: u64, total: u64) -> String {
+    match total {
+        0 => format!("{} bytes", transferred),
+        total => format!("{}%", transferred * 100 / total),
+    }
+}
+
+/// Archiving line for non-TUI mode, shown while a directory is packed and sent
+pub fn print_archiving(files_done: u64, total_files: u64) {
+    rewrite_line(&format!("Archiving: {}/{} files", files_done, total_files));
+}
+
+/// Hashing line for non-TUI mode, shown while a folder sync checksums its files
+pub fn print_hashing(files_done: u64, total_files: u64) {
+    rewrite_line(&format!("Hashing: {}/{} files", files_done, total_files));
+}
+
+/// Replace the current line of stdout
+///
+/// Goes through crossterm so legacy Windows consoles, which print escapes
+/// literally, get the equivalent console API calls instead.
+fn rewrite_line(line: &str) {
+    let mut stdout = io::stdout();
+    write_over_line(&mut stdout, line, glyphs::capabilities().ansi).unwrap();
+}
+
+fn write_over_line(out: &mut impl Write, line: &str, ansi: AnsiMode) -> io::Result<()> {
+    // Legacy Windows consoles get console API calls, so only NO_COLOR rules crossterm out there
+    if ansi == AnsiMode::Full || (cfg!(windows) && ansi == AnsiMode::None) {
+        queue!(out, MoveToColumn(0), Clear(ClearType::UntilNewLine), Print(line))?;
+    } else {
+        // A terminal without cursor control can still return to the start of the line
+        write!(out, "\r{}   ", line)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
     
-    use std::io::Write;
-    io::stdout().flush().unwrap();
+    fn state() -> TransferState {
+        TransferState {
+            code: "alpha-bravo-charlie".to_string(),
+            filename: "photo.jpg".to_string(),
+            total_size: 1_048_576,
+            transferred: 524_288,
+            speed: 1_048_576.0,
+            encrypted: true,
+            status: "Transferring".to_string(),
+            peer_display: "192.168.1.20:9999".to_string(),
+            connection_type: ConnectionType::Direct,
+            rtt_ms: Some(12.5),
+        }
+    }
+    
+    fn render(state: &TransferState) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| TransferUI::render_ui(f, state, &glyphs::UNICODE)).unwrap();
+        
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| (0..buffer.area.width).map(|x| buffer[(x, y)].symbol()).collect())
+            .collect()
+    }
+    
+    #[test]
+    fn test_peer_info_rendered() {
+        let lines = render(&state());
+        let peer_line = lines
+            .iter()
+            .find(|line| line.contains("192.168.1.20:9999"))
+            .expect("peer address should be rendered");
+        assert!(peer_line.contains("🖧"));
+        assert!(peer_line.contains("RTT 12.5 ms"));
+        assert!(peer_line.contains("encrypted"));
+        assert!(!peer_line.contains("not encrypted"));
+    }
+    
+    #[test]
+    fn test_relay_peer_info_rendered() {
+        let state = TransferState {
+            peer_display: "via relay".to_string(),
+            connection_type: ConnectionType::Relay,
+            rtt_ms: None,
+            ..state()
+        };
+        let lines = render(&state);
+        let peer_line = lines
+            .iter()
+            .find(|line| line.contains("via relay"))
+            .expect("relay connection should be rendered");
+        assert!(peer_line.contains("🌐"));
+        assert!(peer_line.contains("RTT --"));
+    }
+    
+    #[test]
+    fn test_no_color_leaves_out_escapes() {
+        let line = progress_line("photo.jpg", 524_288, 1_048_576, 1_048_576.0);
+        let written = |ansi| {
+            let mut out = Vec::new();
+            write_over_line(&mut out, &line, ansi).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        for ansi in [AnsiMode::NoColor, AnsiMode::None] {
+            let output = written(ansi);
+            assert!(!output.contains('\x1b'), "{:?}", output);
+            assert!(output.contains(&line));
+        }
+        #[cfg(not(windows))]
+        assert!(written(AnsiMode::Full).contains('\x1b'));
+        
+        // The full-screen UI keeps its layout but loses its colours
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| {
+            TransferUI::render_ui(f, &state(), &glyphs::UNICODE);
+            assert!(f.buffer_mut().content.iter().any(|cell| cell.fg != Color::Reset));
+            strip_colour(f.buffer_mut());
+        }).unwrap();
+        let buffer = terminal.backend().buffer();
+        assert!(buffer.content.iter().all(|cell| cell.fg == Color::Reset && cell.bg == Color::Reset));
+        assert!(buffer.content.iter().any(|cell| cell.symbol() == "⚡"));
+    }
+    
+    #[test]
+    fn test_headless_logs_until_finished() {
+        let mut polls = 0;
+        let mut ui = HeadlessUI::with_log(Vec::new()).interval(Duration::from_millis(1));
+        ui.run(|| {
+            polls += 1;
+            TransferState {
+                transferred: 524_288 * polls,
+                status: if polls == 2 { "Transfer complete" } else { "Transferring" }.to_string(),
+                ..state()
+            }
+        }).unwrap();
+        
+        let log = String::from_utf8(ui.into_log()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, [
+            "photo.jpg: 50.0% (0.50/1.00 MB) @ 1.00 MB/s - Transferring",
+            "photo.jpg: 100.0% (1.00/1.00 MB) @ 1.00 MB/s - Transfer complete",
+        ]);
+    }
 }
@@ -0,0 +1,321 @@
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The built-in English messages, by id
+///
+/// `yes`, `no` and `conflict-answers` are the answers accepted, separated by
+/// spaces, the first of each being the one shown. `{choices}` is filled in
+/// with them, like `[Y/n]`, the default in capitals.
+const ENGLISH: &[(&str, &str)] = &[
+    ("yes", "y yes"),
+    ("no", "n no"),
+    ("reask-yes-no", "Please answer {yes} or {no}."),
+    ("reask-choice", "Please answer one of {choices}."),
+    ("accept-executable", "Accept it anyway? {choices} (--allow-executables skips this question)"),
+    ("accept-offer", "Accept it? {choices} (--auto-accept skips this question)"),
+    ("send-anyway", "Send it? {choices} (--yes skips this question)"),
+    ("delete-remote-only", "Delete them from the receiver? {choices} (--yes skips this question)"),
+    (
+        "conflict",
+        "{path} already exists: [s]kip, [o]verwrite, [r]ename, or keep the [n]ewer one?\n\
+         Answer in capitals (S/O/R/N) to do the same for every other file.",
+    ),
+    ("conflict-answers", "s o r n"),
+];
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+/// The messages questions are asked in, and the answers they take
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn english() -> Self {
+        Self {
+            messages: ENGLISH.iter().map(|(id, text)| (id.to_string(), text.to_string())).collect(),
+        }
+    }
+    
+    /// English, with whatever a JSON object of `{"id": "text"}` in `path` replaces
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path).map_err(|e| anyhow!("Can't read messages from {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+    
+    pub fn from_json(json: &str) -> Result<Self> {
+        let replacements: HashMap<String, String> = serde_json::from_str(json)?;
+        let mut catalog = Self::english();
+        for (id, text) in replacements {
+            if !catalog.messages.contains_key(&id) {
+                return Err(anyhow!("Unknown message `{}`", id));
+            }
+            catalog.messages.insert(id, text);
+        }
+        for id in ["yes", "no", "conflict-answers"] {
+            if catalog.tokens(id).is_empty() {
+                return Err(anyhow!("`{}` needs at least one answer", id));
+            }
+        }
+        Ok(catalog)
+    }
+    
+    /// The message `id`, with each `{name}` in it replaced
+    pub fn render(&self, id: &str, args: &[(&str, &str)]) -> String {
+        let mut text = self.messages.get(id).map_or(id, String::as_str).to_string();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        text
+    }
+    
+    /// The answers listed in `id`, lowercased for comparing
+    fn tokens(&self, id: &str) -> Vec<String> {
+        self.messages.get(id).map_or(id, String::as_str).split_whitespace().map(str::to_lowercase).collect()
+    }
+}
+
+/// Use `catalog` for every question from now on; only the first call counts
+pub fn set_catalog(catalog: Catalog) {
+    let _ = CATALOG.set(catalog);
+}
+
+/// The catalog questions are asked in, English unless `set_catalog` said otherwise
+pub fn catalog() -> &'static Catalog {
+    CATALOG.get_or_init(Catalog::english)
+}
+
+/// Where answers are read from
+pub trait Answers {
+    /// The next line typed, or `None` once there's no more input
+    fn next_line(&mut self) -> io::Result<Option<String>>;
+}
+
+impl<R: BufRead> Answers for R {
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match self.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line.trim_end_matches(['\r', '\n']).to_string())),
+        }
+    }
+}
+
+/// Keys typed while the terminal is in raw mode, echoed and gathered into lines
+///
+/// Ctrl-C is an error, so it never counts as the default answer.
+struct RawKeys;
+
+impl Answers for RawKeys {
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let mut stdout = io::stdout();
+        loop {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+                }
+                KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) && line.is_empty() => return Ok(None),
+                KeyCode::Char(c) => {
+                    line.push(c);
+                    write!(stdout, "{}", c)?;
+                }
+                KeyCode::Backspace if line.pop().is_some() => write!(stdout, "\u{8} \u{8}")?,
+                KeyCode::Enter => {
+                    write!(stdout, "\r\n")?;
+                    return Ok(Some(line));
+                }
+                _ => {}
+            }
+            stdout.flush()?;
+        }
+    }
+}
+
+/// Which of a question's answers was given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Choice {
+    pub index: usize,
+    /// Typed in capitals, to do the same for every other question like it
+    pub for_all: bool,
+}
+
+/// Asks questions from a catalog and reads the answers
+///
+/// An empty answer, or none at all once input runs out, takes the default.
+/// Anything that isn't one of the answers is asked again.
+pub struct Prompt<'a> {
+    catalog: &'a Catalog,
+    answers: Box<dyn Answers + 'a>,
+    output: Box<dyn Write + 'a>,
+    /// `\r\n` while the terminal is in raw mode, which doesn't turn `\n` into one
+    newline: &'static str,
+}
+
+impl<'a> Prompt<'a> {
+    pub fn new(catalog: &'a Catalog, answers: impl Answers + 'a, output: impl Write + 'a) -> Self {
+        Self {
+            catalog,
+            answers: Box::new(answers),
+            output: Box::new(output),
+            newline: "\n",
+        }
+    }
+}
+
+impl Prompt<'static> {
+    /// Ask on the terminal, key by key if the full-screen UI has it in raw mode
+    pub fn terminal() -> Self {
+        if crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+            Self {
+                newline: "\r\n",
+                ..Self::new(catalog(), RawKeys, io::stdout())
+            }
+        } else {
+            Self::new(catalog(), io::stdin().lock(), io::stdout())
+        }
+    }
+}
+
+impl Prompt<'_> {
+    /// Ask the yes-or-no question `id`
+    pub fn confirm(&mut self, id: &str, args: &[(&str, &str)], default: bool) -> io::Result<bool> {
+        let (yes, no) = (self.catalog.tokens("yes"), self.catalog.tokens("no"));
+        let choices = match default {
+            true => format!("[{}/{}]", yes[0].to_uppercase(), no[0]),
+            false => format!("[{}/{}]", yes[0], no[0].to_uppercase()),
+        };
+        let reask = self.catalog.render("reask-yes-no", &[("yes", &yes[0]), ("no", &no[0])]);
+        let question = self.catalog.render(id, &[args, &[("choices", &choices)]].concat());
+        loop {
+            let Some(answer) = self.ask(&question)? else {
+                return Ok(default);
+            };
+            let answer = answer.to_lowercase();
+            if yes.contains(&answer) {
+                return Ok(true);
+            }
+            if no.contains(&answer) {
+                return Ok(false);
+            }
+            self.say(&reask)?;
+        }
+    }
+    
+    /// Ask question `id`, whose answers are listed in the message `answers`
+    pub fn choose(&mut self, id: &str, args: &[(&str, &str)], answers: &str, default: usize) -> io::Result<Choice> {
+        let tokens = self.catalog.tokens(answers);
+        let reask = self.catalog.render("reask-choice", &[("choices", &tokens.join(", "))]);
+        let question = self.catalog.render(id, args);
+        loop {
+            let Some(answer) = self.ask(&question)? else {
+                return Ok(Choice { index: default, for_all: false });
+            };
+            if let Some(index) = tokens.iter().position(|token| *token == answer.to_lowercase()) {
+                let for_all = answer.chars().next().is_some_and(char::is_uppercase);
+                return Ok(Choice { index, for_all });
+            }
+            self.say(&reask)?;
+        }
+    }
+    
+    /// Show `question` and read a non-empty answer, if one comes
+    fn ask(&mut self, question: &str) -> io::Result<Option<String>> {
+        self.say(question)?;
+        let answer = self.answers.next_line()?;
+        Ok(answer.map(|answer| answer.trim().to_string()).filter(|answer| !answer.is_empty()))
+    }
+    
+    fn say(&mut self, text: &str) -> io::Result<()> {
+        for line in text.lines() {
+            write!(self.output, "{}{}", line, self.newline)?;
+        }
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    
+    /// Answer the offer question with `input`, returning the answer and everything shown
+    fn confirm(catalog: &Catalog, input: &str, default: bool) -> (bool, String) {
+        let mut shown = Vec::new();
+        let answer = Prompt::new(catalog, Cursor::new(input), &mut shown).confirm("accept-offer", &[], default).unwrap();
+        (answer, String::from_utf8(shown).unwrap())
+    }
+    
+    #[test]
+    fn test_yes_no_answers() {
+        let english = Catalog::english();
+        assert!(confirm(&english, "y\n", false).0);
+        assert!(confirm(&english, "  YES \r\n", false).0);
+        assert!(!confirm(&english, "No\n", true).0);
+        
+        // Empty input and end of input both take the default
+        let (answer, shown) = confirm(&english, "\n", true);
+        assert!(answer);
+        assert_eq!(shown, "Accept it? [Y/n] (--auto-accept skips this question)\n");
+        assert!(!confirm(&english, "", false).0);
+        assert!(confirm(&english, "", true).0);
+        
+        // Anything else is asked again
+        let (answer, shown) = confirm(&english, "maybe\nja\nn\n", true);
+        assert!(!answer);
+        assert_eq!(shown.matches("Please answer y or n.").count(), 2);
+        assert_eq!(shown.matches("Accept it?").count(), 3);
+        let (answer, _) = confirm(&english, "maybe\n", false);
+        assert!(!answer, "garbage then end of input");
+    }
+    
+    #[test]
+    fn test_catalog_from_file() {
+        let german = Catalog::from_json(
+            r#"{"yes": "j ja", "no": "n nein", "accept-offer": "Annehmen? {choices}", "reask-yes-no": "Bitte mit {yes} oder {no} antworten."}"#,
+        )
+        .unwrap();
+        let (answer, shown) = confirm(&german, "y\nJa\n", false);
+        assert!(answer);
+        assert_eq!(shown, "Annehmen? [j/N]\nBitte mit j oder n antworten.\nAnnehmen? [j/N]\n");
+        assert!(!confirm(&german, "NEIN\n", true).0);
+        // What it doesn't replace stays English
+        assert_eq!(german.render("send-anyway", &[("choices", "[J/n]")]), "Send it? [J/n] (--yes skips this question)");
+        
+        let err = Catalog::from_json(r#"{"acept-offer": "typo"}"#).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown message `acept-offer`");
+        assert!(Catalog::from_json(r#"{"yes": " "}"#).is_err());
+        assert!(Catalog::from_json("[1, 2]").is_err());
+    }
+    
+    #[test]
+    fn test_choices() {
+        let english = Catalog::english();
+        let choose = |input: &str| {
+            let mut shown = Vec::new();
+            let choice = Prompt::new(&english, Cursor::new(input), &mut shown)
+                .choose("conflict", &[("path", "notes.txt")], "conflict-answers", 0)
+                .unwrap();
+            (choice, String::from_utf8(shown).unwrap())
+        };
+        assert_eq!(choose("r\n").0, Choice { index: 2, for_all: false });
+        assert_eq!(choose("N\n").0, Choice { index: 3, for_all: true });
+        assert_eq!(choose("").0, Choice { index: 0, for_all: false });
+        
+        let (choice, shown) = choose("x\nO\n");
+        assert_eq!(choice, Choice { index: 1, for_all: true });
+        assert!(shown.starts_with("notes.txt already exists: [s]kip"), "{}", shown);
+        assert!(shown.contains("Please answer one of s, o, r, n.\n"), "{}", shown);
+    }
+}
@@ -0,0 +1,457 @@
+//! End-to-end tests that drive the compiled `zap` binary
+//!
+//! Every test runs real sender and receiver processes, talking directly or
+//! through an in-process relay, and checks their exit codes and what ends up
+//! on disk. Run them with `cargo test --features e2e --test e2e`.
+
+use assert_cmd::assert::OutputAssertExt;
+use assert_cmd::cargo::CommandCargoExt;
+use std::collections::BTreeMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::{Child, Command};
+use zap::relay::{self, RelayConfig, RelayState};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A `zap` process started by a test
+struct Zap {
+    child: Child,
+}
+
+impl Zap {
+    /// Start the binary with `args`; it never reads stdin, so no prompt can hang a test
+    fn spawn(args: &[&str]) -> Self {
+        let mut command = Command::from(std::process::Command::cargo_bin("zap").unwrap());
+        command
+            .arg("--no-tui")
+            .args(args)
+            // Tests that want a history name their own
+            .env("ZAP_HISTORY", "off")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        Self {
+            child: command.spawn().unwrap(),
+        }
+    }
+    
+    /// Read the sender's output until it says which port it's listening on
+    async fn listening_port(&mut self) -> u16 {
+        let mut lines = BufReader::new(self.child.stdout.take().unwrap()).lines();
+        let port = tokio::time::timeout(TIMEOUT, async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if let Some(port) = strip_ansi(&line).strip_prefix("Listening on port: ") {
+                    return port.trim().parse().unwrap();
+                }
+            }
+            panic!("sender exited without listening");
+        }).await.expect("sender never started listening");
+        
+        // Keep draining so the sender never blocks on a full pipe
+        tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+        port
+    }
+    
+    /// Wait for the process to exit
+    async fn finish(self) -> Output {
+        tokio::time::timeout(TIMEOUT, self.child.wait_with_output())
+            .await
+            .expect("zap didn't exit in time")
+            .unwrap()
+    }
+    
+    async fn kill(&mut self) {
+        self.child.kill().await.unwrap();
+    }
+}
+
+/// Remove terminal colour codes from a line of output
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// The line `--json` ends with when something went wrong
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonError {
+    event: String,
+    kind: String,
+    message: String,
+    details: JsonErrorDetails,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonErrorDetails {
+    expected: Option<String>,
+    actual: Option<String>,
+    peer: Option<String>,
+    retryable: bool,
+}
+
+/// The error a `--json` run ended with, checking every line before it was an event too
+fn json_error(output: &Output) -> JsonError {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let lines: Vec<&str> = stdout.lines().collect();
+    let (last, events) = lines.split_last().expect("nothing on stdout");
+    for line in events {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap_or_else(|_| panic!("not JSON: {}", line));
+        assert!(event["event"].is_string(), "{}", line);
+    }
+    let error: JsonError = serde_json::from_str(last).unwrap_or_else(|e| panic!("not an error ({}): {}", e, last));
+    assert_eq!(error.event, "error");
+    // Said once, as JSON; none of it as text
+    assert!(!stderr(output).contains(&error.message), "{}", stderr(output));
+    error
+}
+
+/// Start a relay in this process and return its address
+async fn start_relay(config: RelayConfig) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(relay::serve(listener, config, Arc::new(RelayState::default())));
+    addr
+}
+
+/// A file of `len` pseudo-random bytes
+fn sized_file(dir: &Path, name: &str, len: usize) -> PathBuf {
+    let mut state = len as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    let data: Vec<u8> = (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let path = dir.join(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// A file of `len` bytes that is mostly holes, with a little data at each end
+fn sparse_file(dir: &Path, name: &str, len: u64) -> PathBuf {
+    let path = dir.join(name);
+    let mut file = std::fs::File::create(&path).unwrap();
+    file.write_all(b"start of a sparse file").unwrap();
+    file.seek(SeekFrom::Start(len - 4)).unwrap();
+    file.write_all(b"end!").unwrap();
+    path
+}
+
+/// A directory tree with nested folders, empty files and awkward names
+fn directory_tree(dir: &Path, name: &str) -> PathBuf {
+    let root = dir.join(name);
+    std::fs::create_dir_all(root.join("nested/deeper")).unwrap();
+    std::fs::create_dir_all(root.join("empty dir")).unwrap();
+    std::fs::write(root.join("readme.txt"), b"hello").unwrap();
+    std::fs::write(root.join("with spaces.txt"), b"spaces").unwrap();
+    std::fs::write(root.join("ünïcødé ✓.txt"), b"unicode").unwrap();
+    std::fs::write(root.join("-leading-dash"), b"dash").unwrap();
+    std::fs::write(root.join("nested/empty"), b"").unwrap();
+    sized_file(&root.join("nested/deeper"), "data.bin", 300_000);
+    for i in 0..50 {
+        std::fs::write(root.join(format!("nested/small-{}.txt", i)), i.to_string()).unwrap();
+    }
+    root
+}
+
+fn file_hash(path: &Path) -> blake3::Hash {
+    blake3::hash(&std::fs::read(path).unwrap())
+}
+
+/// Hash of every file under `root`, keyed by relative path; directories map to `None`
+fn tree_hashes(root: &Path) -> BTreeMap<PathBuf, Option<blake3::Hash>> {
+    let mut hashes = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let relative = path.strip_prefix(root).unwrap().to_path_buf();
+            if path.is_dir() {
+                hashes.insert(relative, None);
+                pending.push(path);
+            } else {
+                hashes.insert(relative, Some(file_hash(&path)));
+            }
+        }
+    }
+    hashes
+}
+
+/// Send `input` directly and receive it into `output`, returning both processes' output
+async fn direct_transfer(input: &Path, output: &Path, send_code: &str, receive_code: &str) -> (Output, Output) {
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", send_code, "--port", "0"]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", receive_code, "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port,
+    ]);
+    tokio::join!(sender.finish(), receiver.finish())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn direct_small_file() {
+    let dir = TempDir::new().unwrap();
+    let input = sized_file(dir.path(), "small.bin", 10_000);
+    let output = dir.path().join("received.bin");
+    
+    let (sent, received) = direct_transfer(&input, &output, "alpha-bravo-charlie", "alpha-bravo-charlie").await;
+    sent.assert().success();
+    received.assert().success();
+    assert_eq!(file_hash(&output), file_hash(&input));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relay_large_file() {
+    let relay = start_relay(RelayConfig::default()).await;
+    let dir = TempDir::new().unwrap();
+    let input = sparse_file(dir.path(), "large.img", 48 * 1024 * 1024);
+    let output = dir.path().join("received.img");
+    
+    let sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "delta-echo-foxtrot", "--relay", &relay]);
+    let receiver = Zap::spawn(&[
+        "receive", "delta-echo-foxtrot", "-o", output.to_str().unwrap(), "--relay", &relay,
+    ]);
+    let (sent, received) = tokio::join!(sender.finish(), receiver.finish());
+    sent.assert().success();
+    received.assert().success();
+    assert_eq!(file_hash(&output), file_hash(&input));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn directory_with_awkward_names() {
+    let dir = TempDir::new().unwrap();
+    let input = directory_tree(dir.path(), "photos");
+    let output = dir.path().join("received");
+    
+    let (sent, received) = direct_transfer(&input, &output, "golf-hotel-india", "golf-hotel-india").await;
+    sent.assert().success();
+    received.assert().success();
+    assert_eq!(tree_hashes(&output), tree_hashes(&input));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_then_retried() {
+    let dir = TempDir::new().unwrap();
+    let input = sparse_file(dir.path(), "big.img", 64 * 1024 * 1024);
+    let output = dir.path().join("received.img");
+    
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "juliet-kilo-lima", "--port", "0"]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", "juliet-kilo-lima", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port,
+    ]);
+    
+    // Kill the sender as soon as data starts landing
+    tokio::time::timeout(TIMEOUT, async {
+        while std::fs::metadata(&output).map_or(0, |metadata| metadata.len()) == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("transfer never started");
+    sender.kill().await;
+    
+    let received = receiver.finish().await;
+    received.assert().failure();
+    assert!(std::fs::metadata(&output).unwrap().len() < 64 * 1024 * 1024);
+    
+    // Starting over delivers the whole file
+    let (sent, received) = direct_transfer(&input, &output, "juliet-kilo-lima", "juliet-kilo-lima").await;
+    sent.assert().success();
+    received.assert().success();
+    assert_eq!(file_hash(&output), file_hash(&input));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wrong_code() {
+    let dir = TempDir::new().unwrap();
+    let input = sized_file(dir.path(), "secret.bin", 10_000);
+    let output = dir.path().join("received.bin");
+    
+    let (sent, received) = direct_transfer(&input, &output, "mike-november-oscar", "mike-november-papa").await;
+    let message = stderr(&received);
+    sent.assert().failure();
+    received.assert().failure();
+    assert!(message.contains("Wrong transfer code"), "{}", message);
+    assert!(!output.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_events() {
+    let dir = TempDir::new().unwrap();
+    let input = sized_file(dir.path(), "report.bin", 10_000);
+    let output = dir.path().join("received.bin");
+    
+    // The sender's stdout is its code, then one event per line, the port it got among them
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "quebec-romeo-sierra", "--port", "0", "--json"]);
+    let mut lines = BufReader::new(sender.child.stdout.take().unwrap()).lines();
+    let mut sent = Vec::new();
+    let port = tokio::time::timeout(TIMEOUT, async {
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap_or_else(|_| panic!("not JSON: {}", line));
+            sent.push(event["event"].as_str().unwrap().to_string());
+            if event["event"] == "listening" {
+                return event["port"].as_u64().unwrap().to_string();
+            }
+        }
+        panic!("sender exited without listening");
+    }).await.expect("sender never started listening");
+    assert_eq!(sent, ["code", "metadata", "listening"]);
+    
+    let receiver = Zap::spawn(&[
+        "receive", "quebec-romeo-sierra", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port, "--json",
+    ]);
+    let received = receiver.finish().await;
+    let stdout = String::from_utf8_lossy(&received.stdout).into_owned();
+    received.assert().success();
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|_| panic!("not JSON: {}", line)))
+        .collect();
+    let kinds: Vec<_> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+    assert!(kinds.starts_with(&["connected", "handshake"]), "{:?}", kinds);
+    assert!(kinds.ends_with(&["complete", "saved"]), "{:?}", kinds);
+    assert_eq!(events.last().unwrap()["path"], output.to_str().unwrap());
+    
+    while let Ok(Some(_)) = lines.next_line().await {}
+    sender.finish().await.assert().success();
+    assert_eq!(file_hash(&output), file_hash(&input));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_error_peer_too_old() {
+    let dir = TempDir::new().unwrap();
+    let input = sized_file(dir.path(), "old.bin", 10_000);
+    let output = dir.path().join("received.bin");
+    
+    // The sender can't go past v2 without --pq, which the receiver requires
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "whiskey-xray-yankee", "--port", "0"]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", "whiskey-xray-yankee", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port,
+        "--require-pq", "--json",
+    ]);
+    let (sent, received) = tokio::join!(sender.finish(), receiver.finish());
+    sent.assert().failure();
+    let error = json_error(&received);
+    received.assert().code(4);
+    assert_eq!(error.kind, "peer_too_old");
+    assert_eq!((error.details.expected.as_deref(), error.details.actual.as_deref()), (Some("v3"), Some("v2")));
+    assert_eq!(error.details.peer, Some(format!("127.0.0.1:{}", port)));
+    assert!(!error.details.retryable);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_error_cancelled() {
+    let dir = TempDir::new().unwrap();
+    let input = sparse_file(dir.path(), "big.img", 64 * 1024 * 1024);
+    let output = dir.path().join("received.img");
+    
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "zulu-alpha-bravo", "--port", "0"]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", "zulu-alpha-bravo", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port, "--json",
+    ]);
+    
+    // Ctrl-C the receiver as soon as data starts landing
+    tokio::time::timeout(TIMEOUT, async {
+        while std::fs::metadata(&output).map_or(0, |metadata| metadata.len()) == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("transfer never started");
+    let pid = receiver.child.id().unwrap().to_string();
+    std::process::Command::new("kill").args(["-INT", &pid]).status().unwrap();
+    
+    let received = receiver.finish().await;
+    let error = json_error(&received);
+    received.assert().code(130);
+    assert_eq!(error.kind, "cancelled");
+    assert_eq!(error.message, "Transfer cancelled");
+    assert_eq!(error.details.peer, Some(format!("127.0.0.1:{}", port)));
+    assert!(error.details.retryable);
+    sender.kill().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_error_checksum_mismatch() {
+    let dir = TempDir::new().unwrap();
+    let input = sparse_file(dir.path(), "changing.img", 16 * 1024 * 1024);
+    let output = dir.path().join("received.img");
+    
+    // With --receipt the sender hashes the file before sending it, so what's read after doesn't match
+    let mut sender = Zap::spawn(&["send", input.to_str().unwrap(), "--code", "charlie-delta-echo", "--port", "0", "--receipt"]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", "charlie-delta-echo", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port, "--json",
+    ]);
+    tokio::time::timeout(TIMEOUT, async {
+        while std::fs::metadata(&output).map_or(0, |metadata| metadata.len()) == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("transfer never started");
+    let hashed = file_hash(&input);
+    let mut file = std::fs::OpenOptions::new().write(true).open(&input).unwrap();
+    file.seek(SeekFrom::End(-4)).unwrap();
+    file.write_all(b"END!").unwrap();
+    
+    let (sent, received) = tokio::join!(sender.finish(), receiver.finish());
+    sent.assert().failure();
+    let error = json_error(&received);
+    received.assert().code(1);
+    assert_eq!(error.kind, "file_checksum_mismatch");
+    // What the sender hashed, then what it went on to read and send
+    assert_eq!(error.details.expected, Some(format!("blake3:{}", hashed.to_hex())));
+    assert_eq!(error.details.actual, Some(format!("blake3:{}", file_hash(&input).to_hex())));
+    assert!(error.details.retryable);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verify_from_history() {
+    let dir = TempDir::new().unwrap();
+    let input = sized_file(dir.path(), "contract.pdf", 20_000);
+    let output = dir.path().join("received.pdf");
+    let (sent_log, received_log) = (dir.path().join("sent.jsonl"), dir.path().join("received.jsonl"));
+    
+    let mut sender = Zap::spawn(&[
+        "send", input.to_str().unwrap(), "--code", "tango-uniform-victor", "--port", "0", "--history", sent_log.to_str().unwrap(),
+    ]);
+    let port = sender.listening_port().await.to_string();
+    let receiver = Zap::spawn(&[
+        "receive", "tango-uniform-victor", "-o", output.to_str().unwrap(), "--host", "127.0.0.1", "--port", &port,
+        "--history", received_log.to_str().unwrap(),
+    ]);
+    let (sent, received) = tokio::join!(sender.finish(), receiver.finish());
+    sent.assert().success();
+    received.assert().success();
+    
+    // Each side checks its own copy against what it logged
+    let verify = |path: &Path, log: &Path| Zap::spawn(&["verify", path.to_str().unwrap(), "--from-history", "--history", log.to_str().unwrap()]).finish();
+    verify(&input, &sent_log).await.assert().success();
+    verify(&output, &received_log).await.assert().success();
+    
+    std::fs::write(&output, b"changed since").unwrap();
+    verify(&output, &received_log).await.assert().code(3);
+    // A file that never went through zap isn't in it
+    let message = stderr(&verify(&dir.path().join("other.pdf"), &received_log).await);
+    assert!(message.contains("isn't in the history"), "{}", message);
+}
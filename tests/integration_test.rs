@@ -0,0 +1,120 @@
+//! End-to-end transfers through the public API
+//!
+//! These open real sockets and move a megabyte each, so they're ignored by
+//! default; run them with `cargo test --test integration_test -- --ignored`.
+
+use rand::RngCore;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use zap::network;
+use zap::relay::{self, RelayConfig, RelayState};
+use zap::transport::Transport;
+use zap::{CancellationToken, ReceiveOptions, SendOptions};
+
+const FILE_SIZE: usize = 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn write_random_file(dir: &TempDir) -> PathBuf {
+    let mut data = vec![0u8; FILE_SIZE];
+    rand::thread_rng().fill_bytes(&mut data);
+    let path = dir.path().join("input.bin");
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+fn file_hash(path: &Path) -> blake3::Hash {
+    blake3::hash(&std::fs::read(path).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn test_transfer_through_relay() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    let state = Arc::new(RelayState::default());
+    let config = RelayConfig {
+        port: relay_addr.port(),
+        ..Default::default()
+    };
+    tokio::spawn(relay::serve(listener, config, state.clone()));
+    
+    let dir = TempDir::new().unwrap();
+    let input = write_random_file(&dir);
+    let output = dir.path().join("output.bin");
+    
+    let send = tokio::spawn(zap::send(
+        SendOptions {
+            relay: Some(vec![relay_addr.into()]),
+            ..SendOptions::new(&input, "alpha-bravo-charlie")
+        },
+        None,
+        CancellationToken::new(),
+    ));
+    let receive = tokio::spawn(zap::receive(
+        ReceiveOptions {
+            output: Some(output.clone()),
+            relay: Some(vec![relay_addr.into()]),
+            ..ReceiveOptions::new("alpha-bravo-charlie")
+        },
+        None,
+        CancellationToken::new(),
+    ));
+    
+    let (sent, received) = tokio::time::timeout(TIMEOUT, async { tokio::join!(send, receive) })
+        .await
+        .expect("transfer timed out");
+    sent.unwrap().unwrap();
+    assert_eq!(received.unwrap().unwrap(), output);
+    assert_eq!(file_hash(&output), file_hash(&input));
+    
+    // Both clients have hung up, so the relay should be holding nothing
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let stats = state.stats().await;
+            if stats.active_connections == 0 && stats.rooms_waiting + stats.rooms_matched == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("relay still has active sessions");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn test_transfer_direct() {
+    let dir = TempDir::new().unwrap();
+    let input = write_random_file(&dir);
+    let output = dir.path().join("output.bin");
+    
+    // Listening before the receiver starts, on a port nothing else can have
+    let listener = network::bind(Some(0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let options = SendOptions::new(&input, "delta-echo-foxtrot");
+    let send = tokio::spawn(async move {
+        let conn = network::accept(&listener).await?;
+        zap::send_over(Transport::Direct(conn), options, None, CancellationToken::new()).await
+    });
+    
+    let receive = tokio::spawn(zap::receive(
+        ReceiveOptions {
+            output: Some(output.clone()),
+            host: Some("127.0.0.1".parse().unwrap()),
+            port: Some(port),
+            ..ReceiveOptions::new("delta-echo-foxtrot")
+        },
+        None,
+        CancellationToken::new(),
+    ));
+    
+    let (sent, received) = tokio::time::timeout(TIMEOUT, async { tokio::join!(send, receive) })
+        .await
+        .expect("transfer timed out");
+    sent.unwrap().unwrap();
+    assert_eq!(received.unwrap().unwrap(), output);
+    assert_eq!(file_hash(&output), file_hash(&input));
+}
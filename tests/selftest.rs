@@ -0,0 +1,21 @@
+//! `zap selftest`, run the way a user or packager would run it
+
+use assert_cmd::cargo::CommandCargoExt;
+use std::process::Command;
+
+#[test]
+fn selftest_passes() {
+    let output = Command::cargo_bin("zap")
+        .unwrap()
+        .args(["selftest", "--size", "262144", "--with-relay"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    
+    for stage in ["generate", "memory", "tcp", "relay"] {
+        let line = stdout.lines().find(|line| line.starts_with(stage)).unwrap();
+        assert!(line.contains("PASS"), "{}", line);
+    }
+    assert!(stdout.contains("Overall: PASS"));
+}